@@ -1,4 +1,4 @@
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 
 pub fn ips_parse(ips: &Vec<String>) -> Result<Vec<(u32, u32, Ipv4Addr)>, String> {
     let mut in_ips_c = vec![];
@@ -66,6 +66,67 @@ pub fn out_ips_parse(ips: &Vec<String>) -> Result<Vec<(u32, u32)>, String> {
     Ok(in_ips_c)
 }
 
+/// 解析点对网的ipv6映射，格式 ipv4/mask,ipv6，
+/// 网段内的主机位填入ipv6地址的低位，如192.168.10.0/24,fd00::100表示192.168.10.5映射到fd00::105
+pub fn out_ips6_parse(ips: &Vec<String>) -> Result<Vec<(u32, u32, Ipv6Addr)>, String> {
+    let mut list = vec![];
+    for x in ips {
+        let (net, ip) = if let Some(v) = x.split_once(",") {
+            v
+        } else {
+            return Err("ipv4/mask,ipv6".to_string());
+        };
+        let ip = if let Ok(ip) = ip.trim().parse::<Ipv6Addr>() {
+            ip
+        } else {
+            return Err("not ipv6".to_string());
+        };
+        let (dest, mask) = if let Some(v) = net.split_once("/") {
+            v
+        } else {
+            return Err("no netmask".to_string());
+        };
+        let dest = if let Ok(dest) = dest.trim().parse::<Ipv4Addr>() {
+            dest
+        } else {
+            return Err("not ipv4".to_string());
+        };
+        let mask = to_ip(mask.trim())?;
+        let dest = u32::from_be_bytes(dest.octets());
+        if u128::from(ip) & (!mask) as u128 != 0 {
+            return Err(format!(
+                "the low {} bits of {} must be 0",
+                mask.count_zeros(),
+                ip
+            ));
+        }
+        list.push((dest & mask, mask, ip));
+    }
+    Ok(list)
+}
+
+/// 解析点对点预共享密钥，格式 ip,key
+pub fn psk_parse(psk: &Vec<String>) -> Result<Vec<(Ipv4Addr, String)>, String> {
+    let mut list = vec![];
+    for x in psk {
+        let (ip, key) = if let Some(v) = x.split_once(",") {
+            v
+        } else {
+            return Err("ipv4,key".to_string());
+        };
+        let ip = if let Ok(ip) = ip.trim().parse::<Ipv4Addr>() {
+            ip
+        } else {
+            return Err("not ipv4".to_string());
+        };
+        if key.is_empty() {
+            return Err("key is empty".to_string());
+        }
+        list.push((ip, key.to_string()));
+    }
+    Ok(list)
+}
+
 pub fn to_ip(mask: &str) -> Result<u32, String> {
     if let Ok(m) = mask.parse::<u32>() {
         if m > 32 {
@@ -80,3 +141,12 @@ pub fn to_ip(mask: &str) -> Result<u32, String> {
         Err("not netmask".to_string())
     }
 }
+
+/// 子网掩码，可以是前缀长度(24)或者点分格式(255.255.255.0)
+pub fn netmask_parse(mask: &str) -> Result<Ipv4Addr, String> {
+    let mask = mask.trim().trim_start_matches('/');
+    if let Ok(mask) = mask.parse::<Ipv4Addr>() {
+        return Ok(mask);
+    }
+    to_ip(mask).map(Ipv4Addr::from)
+}
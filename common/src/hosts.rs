@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+
+/// 虚拟ip静态映射，格式同hosts文件，每行 `ip 名称 [别名...]`，'#'开头为注释
+///
+/// ```text
+/// # 办公室
+/// 10.26.0.10 office-pc
+/// 10.26.0.11 nas storage
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct HostsMap {
+    name_map: HashMap<String, Ipv4Addr>,
+    ip_map: HashMap<Ipv4Addr, String>,
+}
+
+impl HostsMap {
+    pub fn read(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Self::parse(&text)
+    }
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut name_map = HashMap::new();
+        let mut ip_map = HashMap::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(i) => &line[..i],
+                None => line,
+            };
+            let mut split = line.split_whitespace();
+            let ip = match split.next() {
+                Some(ip) => ip,
+                None => continue,
+            };
+            let ip = ip
+                .parse::<Ipv4Addr>()
+                .map_err(|_| format!("line {}: '{}' not ipv4", index + 1, ip))?;
+            let mut has_name = false;
+            for name in split {
+                if let Some(old) = name_map.insert(name.to_string(), ip) {
+                    if old != ip {
+                        return Err(format!(
+                            "line {}: '{}' is mapped to both {} and {}",
+                            index + 1,
+                            name,
+                            old,
+                            ip
+                        ));
+                    }
+                }
+                //第一个名称作为显示名称
+                if !has_name {
+                    ip_map.entry(ip).or_insert_with(|| name.to_string());
+                    has_name = true;
+                }
+            }
+            if !has_name {
+                return Err(format!("line {}: missing name", index + 1));
+            }
+        }
+        Ok(Self { name_map, ip_map })
+    }
+    pub fn ip(&self, name: &str) -> Option<Ipv4Addr> {
+        self.name_map.get(name).copied()
+    }
+    pub fn name(&self, ip: &Ipv4Addr) -> Option<&str> {
+        self.ip_map.get(ip).map(|v| v.as_str())
+    }
+    pub fn is_empty(&self) -> bool {
+        self.ip_map.is_empty()
+    }
+}
+
+#[test]
+fn test_hosts_parse() {
+    let hosts = HostsMap::parse(
+        "# 办公室\n\
+         10.26.0.10 office-pc # 注释\n\
+         \n\
+         10.26.0.11\tnas  storage\n\
+         10.26.0.11 backup\n",
+    )
+    .unwrap();
+    assert_eq!(hosts.ip("office-pc"), Some(Ipv4Addr::new(10, 26, 0, 10)));
+    assert_eq!(hosts.ip("storage"), Some(Ipv4Addr::new(10, 26, 0, 11)));
+    assert_eq!(hosts.ip("backup"), Some(Ipv4Addr::new(10, 26, 0, 11)));
+    assert_eq!(hosts.ip("#"), None);
+    // 第一个名称作为显示名称
+    assert_eq!(hosts.name(&Ipv4Addr::new(10, 26, 0, 11)), Some("nas"));
+    assert!(!hosts.is_empty());
+    assert!(HostsMap::parse("# only comment").unwrap().is_empty());
+
+    assert!(HostsMap::parse("10.26.0.300 a").is_err());
+    assert!(HostsMap::parse("fd00::1 a").is_err());
+    assert_eq!(
+        HostsMap::parse("10.26.0.2 a\n10.26.0.3").unwrap_err(),
+        "line 2: missing name"
+    );
+    assert!(HostsMap::parse("10.26.0.2 a\n10.26.0.3 a").is_err());
+}
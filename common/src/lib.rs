@@ -1,2 +1,3 @@
 pub mod args_parse;
+pub mod hosts;
 pub mod identifier;
@@ -2,11 +2,36 @@ use std::process;
 
 use console::style;
 
-use vnt::handle::callback::{ConnectInfo, ErrorType};
+use vnt::handle::callback::{CipherSelectInfo, ConnectInfo, ErrorType, RouteChangeInfo};
 use vnt::{DeviceInfo, ErrorInfo, HandshakeInfo, RegisterInfo, VntCallback};
 
+use crate::event_log::EventLog;
+
 #[derive(Clone)]
-pub struct VntHandler {}
+pub struct VntHandler {
+    #[cfg(feature = "server_encrypt")]
+    fingerprint: crate::fingerprint::FingerprintPin,
+    event_log: Option<EventLog>,
+    // 同时加入的其他网络的名称，主网络为空
+    network: Option<String>,
+}
+
+impl VntHandler {
+    #[cfg_attr(not(feature = "server_encrypt"), allow(unused_variables))]
+    pub fn new(server: String, accept_new_fingerprint: bool, event_log: Option<EventLog>) -> Self {
+        Self {
+            #[cfg(feature = "server_encrypt")]
+            fingerprint: crate::fingerprint::FingerprintPin::new(server, accept_new_fingerprint),
+            event_log,
+            network: None,
+        }
+    }
+    /// 其他网络出错或停止时不退出进程
+    pub fn network(mut self, name: String) -> Self {
+        self.network = Some(name);
+        self
+    }
+}
 
 impl VntCallback for VntHandler {
     fn success(&self) {
@@ -22,6 +47,37 @@ impl VntCallback for VntHandler {
 
     fn handshake(&self, info: HandshakeInfo) -> bool {
         println!("handshake {}", info);
+        #[cfg(feature = "server_encrypt")]
+        if let Some(finger) = &info.finger {
+            match self.fingerprint.check(finger) {
+                Ok(true) => {}
+                Ok(false) => {
+                    println!(
+                        "{}",
+                        style(format!(
+                            "服务端{}的指纹发生变化,可能被中间人攻击,确认服务端更换了密钥后使用--accept-new-fingerprint",
+                            self.fingerprint.server()
+                        ))
+                        .red()
+                    );
+                    return false;
+                }
+                Err(e) => {
+                    // 无法确认指纹时拒绝连接
+                    log::error!("server fingerprint {:?}", e);
+                    println!(
+                        "{}",
+                        style(format!(
+                            "无法校验服务端{}的指纹:{},修复文件权限或者使用--accept-new-fingerprint",
+                            self.fingerprint.server(),
+                            e
+                        ))
+                        .red()
+                    );
+                    return false;
+                }
+            }
+        }
         true
     }
 
@@ -30,23 +86,63 @@ impl VntCallback for VntHandler {
         true
     }
 
+    fn route_change(&self, info: RouteChangeInfo) {
+        log::info!("route_change {}", info)
+    }
+
+    fn cipher_select(&self, info: CipherSelectInfo) {
+        println!("cipher_select {}", info)
+    }
+
     fn error(&self, info: ErrorInfo) {
+        if let Some(network) = &self.network {
+            log::error!("network {} error {:?}", network, info);
+            println!(
+                "{}",
+                style(format!("network {} error {}", network, info)).red()
+            );
+            return;
+        }
         log::error!("error {:?}", info);
+        if let Some(event_log) = &self.event_log {
+            event_log.error(&info);
+        }
         println!("{}", style(format!("error {}", info)).red());
-        match info.code {
-            ErrorType::TokenError
-            | ErrorType::AddressExhausted
-            | ErrorType::IpAlreadyExists
-            | ErrorType::InvalidIp
-            | ErrorType::LocalIpExists => {
-                self.stop();
+        if info.code.is_fatal() {
+            if let Some(hint) = fatal_hint(info.code) {
+                println!("{}", style(hint).yellow());
             }
-            _ => {}
+            crate::daemon::save_last_error(&info.to_string());
+            // 退出码即错误码，便于脚本判断失败原因
+            let code: u8 = info.code.into();
+            println!("stopped");
+            process::exit(code as i32)
         }
     }
 
     fn stop(&self) {
+        if let Some(network) = &self.network {
+            println!("network {} stopped", network);
+            return;
+        }
         println!("stopped");
-        process::exit(0)
+        process::exit(crate::daemon::stop_exit_code())
     }
 }
+
+/// 因错误退出时给出的处理建议
+fn fatal_hint(code: ErrorType) -> Option<&'static str> {
+    let hint = match code {
+        ErrorType::TokenError => "token无效,请检查-k参数,或者服务端是否设置了token白名单",
+        ErrorType::AddressExhausted => "服务端该网段的虚拟ip已分配完,请更换token或者清理离线设备",
+        ErrorType::IpAlreadyExists => {
+            "指定的虚拟ip已被其他设备使用,请更换--ip或者不指定由服务端分配"
+        }
+        ErrorType::InvalidIp => "指定的虚拟ip不在服务端的网段内",
+        ErrorType::VersionMismatch => "服务端协议版本不兼容,请确认服务端地址或者升级服务端",
+        ErrorType::VersionTooOld => "客户端版本低于服务端要求的最低版本,请升级vnt-cli",
+        ErrorType::NameDuplicated => "设备名称已被其他设备使用,请使用-n指定不同的名称",
+        _ => return None,
+    };
+    Some(hint)
+}
@@ -0,0 +1,52 @@
+use std::io;
+use std::path::PathBuf;
+
+const TOKEN_FILE: &str = "command-token";
+
+fn token_file() -> io::Result<PathBuf> {
+    Ok(crate::app_home()?.join(TOKEN_FILE))
+}
+
+/// 每次启动生成新的命令令牌，文件只允许当前用户读写
+pub fn create_token() -> io::Result<String> {
+    let token = format!(
+        "{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    );
+    let path = token_file()?;
+    // 先删除，保证重新创建时使用下面的权限
+    let _ = std::fs::remove_file(&path);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    io::Write::write_all(&mut file, token.as_bytes())?;
+    Ok(token)
+}
+
+pub fn read_token() -> io::Result<String> {
+    Ok(std::fs::read_to_string(token_file()?)?.trim().to_string())
+}
+
+/// 请求格式为 令牌\n命令
+pub fn request(token: &str, cmd: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(token.len() + 1 + cmd.len());
+    buf.extend_from_slice(token.as_bytes());
+    buf.push(b'\n');
+    buf.extend_from_slice(cmd);
+    buf
+}
+
+/// 校验令牌，通过时返回命令
+pub fn verify<'a>(token: &str, request: &'a str) -> Option<&'a str> {
+    let (req_token, cmd) = request.split_once('\n')?;
+    if !vnt::cipher::ct_eq(req_token.as_bytes(), token.as_bytes()) {
+        return None;
+    }
+    Some(cmd)
+}
@@ -1,34 +1,185 @@
 use serde::Deserialize;
 use std::io;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+#[cfg(any(feature = "cmd_tls", not(windows)))]
+use std::net::SocketAddr;
+#[cfg(not(windows))]
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+#[cfg(any(feature = "cmd_tls", not(windows)))]
 use std::str::FromStr;
+#[cfg(feature = "cmd_tls")]
+use std::sync::OnceLock;
 use std::time::Duration;
 
-use crate::command::entity::{DeviceItem, Info, RouteItem};
+use crate::command::auth;
+use crate::command::entity::{
+    Counters, DeviceItem, DiagItem, EffectiveConfig, EventItem, Info, KeyItem, LogItem, MatrixItem,
+    PunchLogItem, RelayStatus, RouteItem, SpeedTestItem, Status,
+};
+use crate::command::server::UNAUTHORIZED;
 
 pub struct CommandClient {
     buf: [u8; 10240],
-    udp: UdpSocket,
+    token: String,
+    transport: Transport,
+}
+
+enum Transport {
+    #[cfg(not(windows))]
+    Udp(UdpSocket),
+    #[cfg(windows)]
+    Pipe { timeout: Duration },
+    #[cfg(feature = "cmd_tls")]
+    Tls {
+        addr: SocketAddr,
+        cert: String,
+        timeout: Duration,
+    },
+}
+
+/// 远程管理的地址、固定的证书和令牌
+#[cfg(feature = "cmd_tls")]
+struct Remote {
+    addr: SocketAddr,
+    cert: String,
+    token: String,
+}
+
+#[cfg(feature = "cmd_tls")]
+static REMOTE: OnceLock<Remote> = OnceLock::new();
+
+/// 设置后命令发往远程设备，而不是本机的后台进程
+#[cfg(feature = "cmd_tls")]
+pub fn set_remote(addr: &str, cert: Option<String>, token: Option<String>) -> io::Result<()> {
+    let addr = SocketAddr::from_str(addr)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{} {}", addr, e)))?;
+    let cert = cert.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--remote requires --remote-cert",
+        )
+    })?;
+    let token = match token {
+        Some(token) => token,
+        None => std::env::var("VNT_COMMAND_TOKEN").map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--remote requires --remote-token or VNT_COMMAND_TOKEN",
+            )
+        })?,
+    };
+    let _ = REMOTE.set(Remote { addr, cert, token });
+    Ok(())
+}
+
+#[cfg(not(feature = "cmd_tls"))]
+pub fn set_remote(_addr: &str, _cert: Option<String>, _token: Option<String>) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "remote requires feature cmd_tls",
+    ))
 }
 
 impl CommandClient {
     pub fn new() -> io::Result<Self> {
-        let port = read_command_port().unwrap_or_else(|e| {
-            log::warn!("read_command_port:{:?}", e);
-            39271
+        #[cfg(feature = "cmd_tls")]
+        if let Some(remote) = REMOTE.get() {
+            return Ok(Self {
+                buf: [0; 10240],
+                token: remote.token.clone(),
+                transport: Transport::Tls {
+                    addr: remote.addr,
+                    cert: remote.cert.clone(),
+                    timeout: Duration::from_secs(5),
+                },
+            });
+        }
+        let token = auth::read_token().unwrap_or_else(|e| {
+            log::warn!("read_command_token:{:?}", e);
+            String::new()
         });
-        let udp = UdpSocket::bind("127.0.0.1:0")?;
-        udp.set_read_timeout(Some(Duration::from_secs(5)))?;
-        udp.connect(SocketAddr::V4(SocketAddrV4::new(
-            Ipv4Addr::new(127, 0, 0, 1),
-            port,
-        )))?;
+        // windows上使用命名管道
+        #[cfg(windows)]
+        let transport = Transport::Pipe {
+            timeout: Duration::from_secs(5),
+        };
+        #[cfg(not(windows))]
+        let transport = {
+            let port = read_command_port().unwrap_or_else(|e| {
+                log::warn!("read_command_port:{:?}", e);
+                39271
+            });
+            let udp = UdpSocket::bind("127.0.0.1:0")?;
+            udp.set_read_timeout(Some(Duration::from_secs(5)))?;
+            udp.connect(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::new(127, 0, 0, 1),
+                port,
+            )))?;
+            Transport::Udp(udp)
+        };
         Ok(Self {
-            udp,
             buf: [0; 10240],
+            token,
+            transport,
         })
     }
+    fn set_timeout(&mut self, dur: Duration) -> io::Result<()> {
+        match &mut self.transport {
+            #[cfg(not(windows))]
+            Transport::Udp(udp) => udp.set_read_timeout(Some(dur)),
+            #[cfg(windows)]
+            Transport::Pipe { timeout } => {
+                *timeout = dur;
+                Ok(())
+            }
+            #[cfg(feature = "cmd_tls")]
+            Transport::Tls { timeout, .. } => {
+                *timeout = dur;
+                Ok(())
+            }
+        }
+    }
+    /// 发送命令，响应写入buf，返回长度
+    fn exchange(&mut self, cmd: &[u8]) -> io::Result<usize> {
+        let request = auth::request(&self.token, cmd);
+        let len = match &self.transport {
+            #[cfg(not(windows))]
+            Transport::Udp(udp) => {
+                udp.send(&request)?;
+                udp.recv(&mut self.buf)?
+            }
+            #[cfg(windows)]
+            Transport::Pipe { timeout } => {
+                let out = crate::command::pipe::request(&request, *timeout)?;
+                if out.len() > self.buf.len() {
+                    return Err(io::Error::new(io::ErrorKind::Other, "response too long"));
+                }
+                self.buf[..out.len()].copy_from_slice(&out);
+                out.len()
+            }
+            #[cfg(feature = "cmd_tls")]
+            Transport::Tls {
+                addr,
+                cert,
+                timeout,
+            } => {
+                let out = crate::command::tls::request(*addr, cert, &request, *timeout)?;
+                if out.len() > self.buf.len() {
+                    return Err(io::Error::new(io::ErrorKind::Other, "response too long"));
+                }
+                self.buf[..out.len()].copy_from_slice(&out);
+                out.len()
+            }
+        };
+        if &self.buf[..len] == UNAUTHORIZED.as_bytes() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "unauthorized, check the command token",
+            ));
+        }
+        Ok(len)
+    }
 }
+#[cfg(not(windows))]
 fn read_command_port() -> io::Result<u16> {
     let path_buf = crate::app_home()?.join("command-port");
     let port = std::fs::read_to_string(path_buf)?;
@@ -53,9 +204,57 @@ impl CommandClient {
     pub fn info(&mut self) -> io::Result<Info> {
         self.send_cmd(b"info")
     }
+    pub fn effective_config(&mut self) -> io::Result<EffectiveConfig> {
+        self.send_cmd(b"effective-config")
+    }
+    pub fn events(&mut self, seq: u64) -> io::Result<Vec<EventItem>> {
+        self.send_cmd(format!("events {}", seq).as_bytes())
+    }
+    pub fn matrix(&mut self) -> io::Result<Vec<MatrixItem>> {
+        self.send_cmd(b"matrix")
+    }
+    pub fn keys(&mut self) -> io::Result<Vec<KeyItem>> {
+        self.send_cmd(b"keys")
+    }
+    pub fn relay(&mut self) -> io::Result<RelayStatus> {
+        self.send_cmd(b"relay")
+    }
+    pub fn counters(&mut self) -> io::Result<Counters> {
+        self.send_cmd(b"counters")
+    }
+    pub fn diag(&mut self, ip: &str) -> io::Result<DiagItem> {
+        self.send_cmd(format!("diag {}", ip).as_bytes())
+    }
+    pub fn punch_log(&mut self, ip: &str) -> io::Result<Vec<PunchLogItem>> {
+        self.send_cmd(format!("punch-log {}", ip).as_bytes())
+    }
+    pub fn speed_test(&mut self, ip: &str, secs: u64, tcp: bool) -> io::Result<SpeedTestItem> {
+        // 测速结束才有回应
+        self.set_timeout(Duration::from_secs(secs + 10))?;
+        self.send_cmd(
+            format!(
+                "speedtest {} {} {}",
+                ip,
+                secs,
+                if tcp { "tcp" } else { "udp" }
+            )
+            .as_bytes(),
+        )
+    }
+    pub fn logs(&mut self, seq: u64, level: &str) -> io::Result<Vec<LogItem>> {
+        self.send_cmd(format!("logs {} {}", seq, level).as_bytes())
+    }
+    pub fn log_filter(&mut self, filter: &str) -> io::Result<String> {
+        self.send_cmd(format!("log {}", filter).as_bytes())
+    }
+    pub fn wan_sim(&mut self, cmd: &str) -> io::Result<String> {
+        self.send_cmd(format!("wan {}", cmd).as_bytes())
+    }
+    pub fn status(&mut self) -> io::Result<Status> {
+        self.send_cmd(b"status")
+    }
     fn send_cmd<'a, V: Deserialize<'a>>(&'a mut self, cmd: &[u8]) -> io::Result<V> {
-        self.udp.send(cmd)?;
-        let len = self.udp.recv(&mut self.buf)?;
+        let len = self.exchange(cmd)?;
         match serde_yaml::from_slice::<V>(&self.buf[..len]) {
             Ok(val) => Ok(val),
             Err(e) => {
@@ -64,10 +263,8 @@ impl CommandClient {
             }
         }
     }
-    pub fn stop(&self) -> io::Result<String> {
-        self.udp.send(b"stop")?;
-        let mut buf = [0; 10240];
-        let len = self.udp.recv(&mut buf)?;
-        Ok(String::from_utf8(buf[..len].to_vec()).unwrap())
+    pub fn stop(&mut self) -> io::Result<String> {
+        let len = self.exchange(b"stop")?;
+        Ok(String::from_utf8_lossy(&self.buf[..len]).to_string())
     }
 }
@@ -13,15 +13,22 @@ pub struct Info {
     pub ipv6_addr: String,
     pub up: u64,
     pub down: u64,
+    #[serde(default)]
+    pub replay_drop: u64,
+    #[serde(default)]
+    pub tcp_proxy_evict: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RouteItem {
     pub destination: String,
     pub next_hop: String,
     pub metric: String,
     pub rt: String,
     pub interface: String,
+    // 本地设置的别名
+    #[serde(default)]
+    pub name: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -39,4 +46,161 @@ pub struct DeviceItem {
     pub client_secret_hash: Vec<u8>,
     pub current_client_secret: bool,
     pub current_client_secret_hash: Vec<u8>,
+    // 本地设置的标签
+    #[serde(default)]
+    pub tags: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EventItem {
+    pub seq: u64,
+    pub time: u64,
+    pub ip: String,
+    pub kind: String,
+    pub detail: String,
+}
+
+/// 后台服务内存中保留的一行日志
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LogItem {
+    pub seq: u64,
+    pub time: u64,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+}
+
+/// 后台运行时工作进程的异常退出记录
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CrashItem {
+    pub time: u64,
+    // 运行了多少秒
+    pub uptime: u64,
+    // 被信号结束时没有退出码
+    pub code: Option<i32>,
+    pub reason: String,
+    // 多少秒后重启，不再重启时为空
+    pub restart_in: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PunchLogItem {
+    pub seq: u64,
+    pub time: u64,
+    pub end_time: u64,
+    pub nat_type: String,
+    // 使用的方式和发送的包数，如local:2,cone:4
+    pub strategies: String,
+    pub packets: u32,
+    pub outcome: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MatrixItem {
+    pub virtual_ip: String,
+    pub routes: Vec<MatrixRoute>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MatrixRoute {
+    pub virtual_ip: String,
+    pub p2p: bool,
+    pub rt: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeyItem {
+    pub name: String,
+    pub virtual_ip: String,
+    pub public_key: String,
+    pub local: bool,
+}
+
+/// 当前生效的配置，accept为允许服务端下发的配置项
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EffectiveConfig {
+    pub accept: String,
+    pub items: Vec<ConfigItem>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConfigItem {
+    pub field: String,
+    pub value: String,
+    pub source: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DiagItem {
+    pub virtual_ip: String,
+    pub status: String,
+    pub version: String,
+    pub nat_type: String,
+    pub public_ips: String,
+    pub local_ip: String,
+    pub ipv6: String,
+    pub cipher_model: String,
+    pub route: String,
+    pub rt: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpeedTestItem {
+    pub virtual_ip: String,
+    pub mode: String,
+    pub status: String,
+    pub upload: Option<SpeedItem>,
+    pub download: Option<SpeedItem>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpeedItem {
+    // bit/s
+    pub goodput: u64,
+    pub packets: u64,
+    pub loss_rate: f64,
+    pub jitter_us: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Counters {
+    pub up: u64,
+    pub down: u64,
+    pub tx_packets: u64,
+    pub rx_packets: u64,
+    pub p2p_tx: u64,
+    pub p2p_rx: u64,
+    pub relay_tx: u64,
+    pub relay_rx: u64,
+    pub cipher_error: u64,
+    pub tcp_dropped: u64,
+    #[serde(default)]
+    pub route_retry: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RelayStatus {
+    // KB/s，0表示不限
+    pub budget: u64,
+    pub relayed: u64,
+    pub terminal: u64,
+    pub dropped: u64,
+    pub pairs: Vec<RelayPairItem>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RelayPairItem {
+    pub source: String,
+    pub destination: String,
+    pub bytes: u64,
+    pub packets: u64,
+    pub dropped: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Status {
+    pub version: String,
+    pub pid: u32,
+    pub uptime: u64,
+    pub connect_status: String,
 }
@@ -0,0 +1,26 @@
+use std::io;
+use std::io::{Read, Write};
+
+/// 单个请求或响应的最大长度
+const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+/// 流式传输时每个请求和响应前带4字节长度
+pub fn read_frame<R: Read>(stream: &mut R) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too long"));
+    }
+    let mut buf = vec![0; len];
+    stream.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+pub fn write_frame<W: Write>(stream: &mut W, buf: &[u8]) -> io::Result<()> {
+    let mut data = Vec::with_capacity(4 + buf.len());
+    data.extend_from_slice(&(buf.len() as u32).to_be_bytes());
+    data.extend_from_slice(buf);
+    stream.write_all(&data)?;
+    stream.flush()
+}
@@ -1,34 +1,127 @@
 use std::io;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use common::hosts::HostsMap;
+use vnt::channel::netem::{WanRule, WanSchedule};
+use vnt::channel::punch::NatFiltering;
 use vnt::core::Vnt;
 
-use crate::command::entity::{DeviceItem, Info, RouteItem};
+use crate::command::entity::{
+    ConfigItem, Counters, DeviceItem, DiagItem, EffectiveConfig, EventItem, Info, KeyItem, LogItem,
+    MatrixItem, MatrixRoute, PunchLogItem, RelayPairItem, RelayStatus, RouteItem, SpeedItem,
+    SpeedTestItem, Status,
+};
 use crate::console_out;
+use crate::peer_tag::PeerTags;
 
+mod auth;
 pub mod client;
 pub mod entity;
+#[cfg(any(feature = "cmd_tls", windows))]
+mod frame;
+#[cfg(windows)]
+mod pipe;
 pub mod server;
+#[cfg(feature = "cmd_tls")]
+mod tls;
+
+static HOSTS: OnceLock<HostsMap> = OnceLock::new();
+
+/// 加载虚拟ip映射文件，返回当前设备期望使用的ip
+pub fn init_hosts(path: &str, name: &str) -> io::Result<Option<Ipv4Addr>> {
+    let hosts = HostsMap::read(path).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let ip = hosts.ip(name);
+    let _ = HOSTS.set(hosts);
+    Ok(ip)
+}
+
+/// 映射文件中的设备名称
+fn hosts_name(ip: &Ipv4Addr) -> Option<String> {
+    HOSTS.get().and_then(|v| v.name(ip)).map(|v| v.to_string())
+}
 
 pub enum CommandEnum {
     Route,
+    // 按间隔秒数持续查看路由变化
+    RouteWatch(u64),
     List,
     All,
     Info,
+    EffectiveConfig,
+    Status,
+    Events(bool),
+    LogFilter(String),
+    WanSim(String),
+    // 是否持续输出，最低级别
+    Logs(bool, String),
+    Matrix,
+    Keys,
+    Relay,
+    // 按间隔秒数输出速率
+    Stats(u64),
+    Diag(String),
+    PunchLog(String),
+    SpeedTest(String, u64, bool),
+    LastErrors,
     Stop,
 }
 
+/// 连不上后台服务时的退出码，和vnt的错误码(1-255以内的小值)区分开
+pub const EXIT_UNREACHABLE: i32 = 100;
+/// 命令执行失败的退出码
+pub const EXIT_FAILED: i32 = 101;
+
 pub fn command(cmd: CommandEnum) {
+    let stop = matches!(cmd, CommandEnum::Stop);
     if let Err(e) = command_(cmd) {
+        if stop && unreachable(&e) && crate::daemon::cancel().unwrap_or(false) {
+            // 工作进程正在等待重启
+            println!("stopped");
+            return;
+        }
         println!("cmd: {:?}", e);
+        let code = if unreachable(&e) {
+            EXIT_UNREACHABLE
+        } else {
+            EXIT_FAILED
+        };
+        std::process::exit(code);
     }
 }
 
+fn unreachable(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::NotFound | io::ErrorKind::TimedOut
+    )
+}
+
 fn command_(cmd: CommandEnum) -> io::Result<()> {
+    if let CommandEnum::LastErrors = cmd {
+        // 记录在本地文件中，工作进程没有运行时也能查看
+        console_out::console_crash_list(crate::daemon::crash_list()?);
+        return Ok(());
+    }
     let mut command_client = client::CommandClient::new()?;
     match cmd {
         CommandEnum::Route => {
             let list = command_client.route()?;
             console_out::console_route_table(list);
         }
+        CommandEnum::RouteWatch(secs) => {
+            let mut snapshot = console_out::route_diff::RouteSnapshot::default();
+            let list = command_client.route()?;
+            snapshot.update(list.clone());
+            console_out::console_route_table(list);
+            loop {
+                std::thread::sleep(std::time::Duration::from_secs(secs));
+                let changes = snapshot.update(command_client.route()?);
+                console_out::route_diff::console_route_changes(changes);
+            }
+        }
         CommandEnum::List => {
             let list = command_client.list()?;
             console_out::console_device_list(list);
@@ -41,6 +134,93 @@ fn command_(cmd: CommandEnum) -> io::Result<()> {
             let info = command_client.info()?;
             console_out::console_info(info);
         }
+        CommandEnum::EffectiveConfig => {
+            let config = command_client.effective_config()?;
+            console_out::console_effective_config(config);
+        }
+        CommandEnum::Status => {
+            let status = command_client.status()?;
+            console_out::console_status(status);
+        }
+        CommandEnum::Events(follow) => {
+            let mut seq = 0;
+            loop {
+                let list = command_client.events(seq)?;
+                if let Some(last) = list.last() {
+                    seq = last.seq;
+                }
+                let empty = list.is_empty();
+                console_out::console_events(list);
+                if empty {
+                    if !follow {
+                        break;
+                    }
+                    std::thread::sleep(std::time::Duration::from_secs(1));
+                }
+            }
+        }
+        CommandEnum::Matrix => {
+            let list = command_client.matrix()?;
+            console_out::console_matrix(list);
+        }
+        CommandEnum::Keys => {
+            let list = command_client.keys()?;
+            console_out::console_keys(list);
+        }
+        CommandEnum::Relay => {
+            let status = command_client.relay()?;
+            console_out::console_relay(status);
+        }
+        CommandEnum::Stats(secs) => {
+            let mut last = command_client.counters()?;
+            let mut time = Instant::now();
+            loop {
+                std::thread::sleep(std::time::Duration::from_secs(secs));
+                let counters = command_client.counters()?;
+                let now = Instant::now();
+                console_out::console_stats(&last, &counters, now - time);
+                last = counters;
+                time = now;
+            }
+        }
+        CommandEnum::Diag(ip) => {
+            let diag = command_client.diag(&ip)?;
+            console_out::console_diag(diag);
+        }
+        CommandEnum::PunchLog(ip) => {
+            let list = command_client.punch_log(&ip)?;
+            console_out::console_punch_log(list);
+        }
+        CommandEnum::SpeedTest(ip, secs, tcp) => {
+            let item = command_client.speed_test(&ip, secs, tcp)?;
+            console_out::console_speed_test(item);
+        }
+        CommandEnum::LogFilter(filter) => {
+            let out = command_client.log_filter(&filter)?;
+            println!("{}", out);
+        }
+        CommandEnum::WanSim(cmd) => {
+            let out = command_client.wan_sim(&cmd)?;
+            println!("{}", out);
+        }
+        CommandEnum::Logs(follow, level) => {
+            let mut seq = 0;
+            loop {
+                let list = command_client.logs(seq, &level)?;
+                if let Some(last) = list.last() {
+                    seq = last.seq;
+                }
+                let empty = list.is_empty();
+                console_out::console_logs(list);
+                if empty {
+                    if !follow {
+                        break;
+                    }
+                    std::thread::sleep(std::time::Duration::from_secs(1));
+                }
+            }
+        }
+        CommandEnum::LastErrors => {}
         CommandEnum::Stop => {
             command_client.stop()?;
         }
@@ -50,6 +230,7 @@ fn command_(cmd: CommandEnum) -> io::Result<()> {
 
 pub fn command_route(vnt: &Vnt) -> Vec<RouteItem> {
     let route_table = vnt.route_table();
+    let tags = PeerTags::load();
     let mut route_list = Vec::with_capacity(route_table.len());
     for (destination, routes) in route_table {
         for route in routes {
@@ -64,6 +245,8 @@ pub fn command_route(vnt: &Vnt) -> Vec<RouteItem> {
             };
             let interface = if route.is_tcp {
                 format!("tcp@{}", route.addr)
+            } else if route.is_uds() {
+                "uds".to_string()
             } else {
                 route.addr.to_string()
             };
@@ -73,6 +256,7 @@ pub fn command_route(vnt: &Vnt) -> Vec<RouteItem> {
                 metric,
                 rt,
                 interface,
+                name: tags.name(&destination).unwrap_or_default(),
             };
             route_list.push(item);
         }
@@ -86,12 +270,20 @@ pub fn command_list(vnt: &Vnt) -> Vec<DeviceItem> {
     let mut list = Vec::new();
     let current_client_secret = vnt.client_encrypt();
     let client_encrypt_hash = vnt.client_encrypt_hash().unwrap_or(&[]);
+    let tags = PeerTags::load();
     for peer in device_list {
-        let name = peer.name;
+        let name = tags
+            .name(&peer.virtual_ip)
+            .or_else(|| hosts_name(&peer.virtual_ip))
+            .unwrap_or(peer.name);
         let virtual_ip = peer.virtual_ip.to_string();
         let (nat_type, public_ips, local_ip, ipv6) =
             if let Some(nat_info) = vnt.peer_nat_info(&peer.virtual_ip) {
-                let nat_type = format!("{:?}", nat_info.nat_type);
+                let nat_type = if nat_info.same_nat() {
+                    format!("{:?}(same NAT)", nat_info.nat_type)
+                } else {
+                    format!("{:?}", nat_info.nat_type)
+                };
                 let public_ips: Vec<String> =
                     nat_info.public_ips.iter().map(|v| v.to_string()).collect();
                 let public_ips = public_ips.join(",");
@@ -116,6 +308,8 @@ pub fn command_list(vnt: &Vnt) -> Vec<DeviceItem> {
             let nat_traversal_type = if route.metric == 1 {
                 if route.is_tcp {
                     "tcp-p2p"
+                } else if route.is_uds() {
+                    "uds-p2p"
                 } else {
                     "p2p"
                 }
@@ -157,6 +351,7 @@ pub fn command_list(vnt: &Vnt) -> Vec<DeviceItem> {
             client_secret_hash: peer.client_secret_hash,
             current_client_secret,
             current_client_secret_hash: client_encrypt_hash.to_vec(),
+            tags: tags.tags(&peer.virtual_ip),
         };
         list.push(item);
     }
@@ -172,7 +367,10 @@ pub fn command_info(vnt: &Vnt) -> Info {
     let virtual_netmask = current_device.virtual_netmask.to_string();
     let connect_status = format!("{:?}", vnt.connection_status());
     let relay_server = current_device.connect_server.to_string();
-    let nat_type = format!("{:?}", nat_info.nat_type);
+    let nat_type = match nat_info.filtering() {
+        NatFiltering::Unknown => format!("{:?}", nat_info.nat_type),
+        filtering => format!("{:?}({:?})", nat_info.nat_type, filtering),
+    };
     let public_ips: Vec<String> = nat_info.public_ips.iter().map(|v| v.to_string()).collect();
     let public_ips = public_ips.join(",");
     let local_addr = nat_info
@@ -185,6 +383,11 @@ pub fn command_info(vnt: &Vnt) -> Info {
         .unwrap_or("None".to_string());
     let up = vnt.up_stream();
     let down = vnt.down_stream();
+    let replay_drop = vnt.replay_drop_count();
+    #[cfg(feature = "ip_proxy")]
+    let tcp_proxy_evict = vnt.tcp_proxy_evict_count();
+    #[cfg(not(feature = "ip_proxy"))]
+    let tcp_proxy_evict = 0;
     Info {
         name,
         virtual_ip,
@@ -198,5 +401,378 @@ pub fn command_info(vnt: &Vnt) -> Info {
         ipv6_addr,
         up,
         down,
+        replay_drop,
+        tcp_proxy_evict,
+    }
+}
+
+/// 当前生效的配置，包括服务端下发的和被本地拒绝的配置项
+pub fn command_effective_config(vnt: &Vnt) -> EffectiveConfig {
+    EffectiveConfig {
+        accept: vnt
+            .accept_server_config()
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(","),
+        items: vnt
+            .effective_config()
+            .into_iter()
+            .map(|v| ConfigItem {
+                field: v.field.to_string(),
+                value: v.value,
+                source: v.source.to_string(),
+            })
+            .collect(),
+    }
+}
+
+pub fn command_status(vnt: &Vnt, start_time: Instant) -> Status {
+    Status {
+        version: vnt::VNT_VERSION.to_string(),
+        pid: std::process::id(),
+        uptime: start_time.elapsed().as_secs(),
+        connect_status: format!("{:?}", vnt.connection_status()),
+    }
+}
+
+/// 单次返回的日志的总字节数上限，避免超出udp缓冲区
+const LOG_BUDGET: usize = 8000;
+
+/// 修改模拟弱网，计划的多行内容用'|'连接成一行传输
+pub fn command_wan_sim(vnt: &Vnt, cmd: &str) -> String {
+    let (action, arg) = match cmd.split_once(' ') {
+        Some((action, arg)) => (action, arg.trim()),
+        None => (cmd, ""),
+    };
+    match action {
+        "status" => {
+            let status = vnt.wan_sim_status();
+            let rules: Vec<String> = status.rules.iter().map(|v| v.to_string()).collect();
+            let mut out = if rules.is_empty() {
+                "rules: clean".to_string()
+            } else {
+                format!("rules: {}", rules.join(";"))
+            };
+            if let Some((index, total, remaining, repeat)) = status.phase {
+                out.push_str(&format!(
+                    "\nphase: {}/{} remaining {}s{}",
+                    index + 1,
+                    total,
+                    remaining.as_secs(),
+                    if repeat { " repeat" } else { "" }
+                ));
+            }
+            out
+        }
+        "off" => {
+            vnt.set_wan_sim(Vec::new());
+            "ok".to_string()
+        }
+        "rules" => {
+            let rules = arg
+                .split(';')
+                .map(|v| v.trim())
+                .filter(|v| !v.is_empty())
+                .map(WanRule::from_str)
+                .collect::<Result<Vec<_>, _>>();
+            match rules {
+                Ok(rules) => {
+                    vnt.set_wan_sim(rules);
+                    "ok".to_string()
+                }
+                Err(e) => format!("error {}", e),
+            }
+        }
+        "schedule" => match WanSchedule::from_str(&arg.replace('|', "\n")) {
+            Ok(schedule) => {
+                vnt.set_wan_schedule(schedule);
+                "ok".to_string()
+            }
+            Err(e) => format!("error {}", e),
+        },
+        _ => format!(
+            "unknown '{}', supported: status/off/rules <rules>/schedule <file>",
+            action
+        ),
+    }
+}
+
+pub fn command_logs(vnt: &Vnt, seq: u64, level: &str) -> io::Result<Vec<LogItem>> {
+    let level = level
+        .parse::<log::LevelFilter>()
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, format!("level error '{}'", level)))?;
+    let mut budget = LOG_BUDGET;
+    let mut list = Vec::new();
+    for line in vnt.recent_logs(seq, level, 200) {
+        // 序列化后每行的字段名等额外占用按64字节估算
+        let len = line.target.len() + line.message.len() + 64;
+        if len > budget && !list.is_empty() {
+            break;
+        }
+        budget = budget.saturating_sub(len);
+        list.push(LogItem {
+            seq: line.seq,
+            time: line.time,
+            level: line.level.to_string(),
+            target: line.target,
+            message: line.message,
+        });
+    }
+    Ok(list)
+}
+
+/// 单次最多返回的事件数，避免超出udp缓冲区
+const EVENT_LIMIT: usize = 40;
+
+pub fn command_events(vnt: &Vnt, seq: u64) -> Vec<EventItem> {
+    vnt.route_events(seq, EVENT_LIMIT)
+        .into_iter()
+        .map(|v| EventItem {
+            seq: v.seq,
+            time: v.time,
+            ip: v.ip.to_string(),
+            kind: v.kind.to_string(),
+            detail: v.detail,
+        })
+        .collect()
+}
+
+/// 向对端打洞的记录
+pub fn command_punch_log(vnt: &Vnt, ip: &str) -> io::Result<Vec<PunchLogItem>> {
+    let ip = ip
+        .parse::<Ipv4Addr>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{} {}", ip, e)))?;
+    Ok(vnt
+        .punch_log(&ip)
+        .into_iter()
+        .map(|v| PunchLogItem {
+            seq: v.seq,
+            time: v.time,
+            end_time: v.end_time,
+            nat_type: format!("{:?}", v.nat_type),
+            strategies: v
+                .strategies
+                .iter()
+                .map(|(strategy, num)| format!("{}:{}", strategy, num))
+                .collect::<Vec<_>>()
+                .join(","),
+            packets: v.packets(),
+            outcome: v.outcome.to_string(),
+        })
+        .collect())
+}
+
+/// 请求其他设备上报路由，等待一段时间后汇总成可达矩阵
+pub fn command_matrix(vnt: &Vnt) -> Vec<MatrixItem> {
+    if let Err(e) = vnt.request_route_matrix() {
+        log::warn!("request_route_matrix {:?}", e);
+    }
+    std::thread::sleep(std::time::Duration::from_millis(1500));
+    vnt.route_matrix(std::time::Duration::from_secs(5))
+        .into_iter()
+        .map(|(ip, routes)| MatrixItem {
+            virtual_ip: ip.to_string(),
+            routes: routes
+                .into_iter()
+                .map(|v| MatrixRoute {
+                    virtual_ip: v.ip.to_string(),
+                    p2p: v.p2p,
+                    rt: v.rt,
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+/// 当前设备和其他设备登记的公钥，当前设备排在第一个
+pub fn command_keys(vnt: &Vnt) -> Vec<KeyItem> {
+    let mut list = Vec::new();
+    if let Some(key) = vnt.device_public_key() {
+        let info = vnt.current_device();
+        list.push(KeyItem {
+            name: vnt.name().to_string(),
+            virtual_ip: info.virtual_ip.to_string(),
+            public_key: hex(&key),
+            local: true,
+        });
+    }
+    let device_list = vnt.device_list();
+    for (ip, key) in vnt.peer_public_keys() {
+        let name = hosts_name(&ip)
+            .or_else(|| {
+                device_list
+                    .iter()
+                    .find(|v| v.virtual_ip == ip)
+                    .map(|v| v.name.clone())
+            })
+            .unwrap_or_default();
+        list.push(KeyItem {
+            name,
+            virtual_ip: ip.to_string(),
+            public_key: hex(&key),
+            local: false,
+        });
     }
+    list
+}
+
+/// 为其他设备中转的流量，按中转量从大到小排列
+/// 累计计数，由调用方采样两次求速率
+pub fn command_counters(vnt: &Vnt) -> Counters {
+    let stats = vnt.stats_handle();
+    let traffic = stats.traffic();
+    Counters {
+        up: stats.up_stream(),
+        down: stats.down_stream(),
+        tx_packets: traffic.tx_packets,
+        rx_packets: traffic.rx_packets,
+        p2p_tx: traffic.p2p_tx,
+        p2p_rx: traffic.p2p_rx,
+        relay_tx: traffic.relay_tx,
+        relay_rx: traffic.relay_rx,
+        cipher_error: traffic.cipher_error,
+        tcp_dropped: stats.tcp_queue_dropped(),
+        route_retry: traffic.route_retry,
+    }
+}
+
+pub fn command_relay(vnt: &Vnt) -> RelayStatus {
+    let stats = vnt.relay_stats();
+    let name = |ip: &Ipv4Addr| match hosts_name(ip) {
+        Some(name) => format!("{}({})", ip, name),
+        None => ip.to_string(),
+    };
+    RelayStatus {
+        budget: stats.budget / 1024,
+        relayed: stats.relayed,
+        terminal: stats.terminal,
+        dropped: stats.dropped,
+        pairs: stats
+            .pairs
+            .iter()
+            .map(|pair| RelayPairItem {
+                source: name(&pair.source),
+                destination: name(&pair.destination),
+                bytes: pair.bytes,
+                packets: pair.packets,
+                dropped: pair.dropped,
+            })
+            .collect(),
+    }
+}
+
+pub fn hex(buf: &[u8]) -> String {
+    buf.iter().map(|v| format!("{:02x}", v)).collect()
+}
+
+/// 请求对端的诊断信息，最多等待2秒
+pub fn command_diag(vnt: &Vnt, ip: &str) -> DiagItem {
+    let mut item = DiagItem {
+        virtual_ip: ip.to_string(),
+        status: String::new(),
+        version: String::new(),
+        nat_type: String::new(),
+        public_ips: String::new(),
+        local_ip: String::new(),
+        ipv6: String::new(),
+        cipher_model: String::new(),
+        route: String::new(),
+        rt: String::new(),
+    };
+    let ip = match ip.parse::<Ipv4Addr>() {
+        Ok(ip) => ip,
+        Err(e) => {
+            item.status = format!("error {}", e);
+            return item;
+        }
+    };
+    if let Err(e) = vnt.request_diag(&ip) {
+        item.status = format!("error {}", e);
+        return item;
+    }
+    let mut diag = None;
+    for _ in 0..20 {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        diag = vnt.peer_diag(&ip);
+        if diag.is_some() {
+            break;
+        }
+    }
+    let diag = match diag {
+        Some(diag) => diag,
+        None => {
+            item.status = "timeout".to_string();
+            return item;
+        }
+    };
+    if !diag.allow {
+        item.status = "refused".to_string();
+        return item;
+    }
+    item.status = "ok".to_string();
+    item.version = diag.version;
+    item.nat_type = format!("{:?}", diag.nat_type);
+    item.public_ips = diag
+        .public_ips
+        .iter()
+        .zip(diag.public_ports.iter().chain(std::iter::repeat(&0)))
+        .map(|(ip, port)| format!("{}:{}", ip, port))
+        .collect::<Vec<String>>()
+        .join(",");
+    item.local_ip = diag.local_ip.to_string();
+    item.ipv6 = diag.ipv6.map(|v| v.to_string()).unwrap_or_default();
+    item.cipher_model = diag.cipher_model;
+    item.route = if diag.metric == 0 {
+        "none".to_string()
+    } else if diag.p2p {
+        "p2p".to_string()
+    } else {
+        format!("relay({})", diag.metric)
+    };
+    item.rt = if diag.rt < 0 {
+        String::new()
+    } else {
+        diag.rt.to_string()
+    };
+    item
+}
+
+/// 和对端双向测速
+pub fn command_speed_test(vnt: &Vnt, ip: &str, secs: u64, tcp: bool) -> SpeedTestItem {
+    let mut item = SpeedTestItem {
+        virtual_ip: ip.to_string(),
+        mode: if tcp { "tcp" } else { "udp" }.to_string(),
+        status: String::new(),
+        upload: None,
+        download: None,
+    };
+    let ip = match ip.parse::<Ipv4Addr>() {
+        Ok(ip) => ip,
+        Err(e) => {
+            item.status = format!("error {}", e);
+            return item;
+        }
+    };
+    let result = match vnt.speed_test(&ip, std::time::Duration::from_secs(secs), tcp) {
+        Ok(result) => result,
+        Err(e) => {
+            item.status = format!("error {}", e);
+            return item;
+        }
+    };
+    let convert = |stats: vnt::handle::speed_test::SpeedStats| SpeedItem {
+        goodput: stats.goodput(),
+        packets: stats.packets,
+        loss_rate: stats.loss_rate(),
+        jitter_us: stats.jitter_us,
+    };
+    item.status = if result.upload.is_some() {
+        "ok".to_string()
+    } else {
+        "timeout".to_string()
+    };
+    item.upload = result.upload.map(convert);
+    item.download = Some(convert(result.download));
+    item
 }
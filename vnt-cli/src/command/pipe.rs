@@ -0,0 +1,256 @@
+use std::io;
+use std::io::{Read, Write};
+use std::os::windows::ffi::OsStrExt;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use winapi::shared::minwindef::{DWORD, FALSE};
+use winapi::shared::sddl::{ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1};
+use winapi::shared::winerror::{
+    ERROR_ACCESS_DENIED, ERROR_BROKEN_PIPE, ERROR_PIPE_BUSY, ERROR_PIPE_CONNECTED,
+};
+use winapi::um::fileapi::{FlushFileBuffers, ReadFile, WriteFile};
+use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+use winapi::um::minwinbase::SECURITY_ATTRIBUTES;
+use winapi::um::namedpipeapi::{ConnectNamedPipe, CreateNamedPipeW, DisconnectNamedPipe};
+use winapi::um::winbase::{
+    LocalFree, FILE_FLAG_FIRST_PIPE_INSTANCE, PIPE_ACCESS_DUPLEX, PIPE_READMODE_BYTE,
+    PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+};
+use winapi::um::winnt::{HANDLE, PSECURITY_DESCRIPTOR};
+
+use crate::command::frame::{read_frame, write_frame};
+
+const PIPE_FILE: &str = "command-pipe";
+const DEFAULT_PIPE: &str = "vnt-command";
+/// 只允许管理员和SYSTEM访问
+const PIPE_SDDL: &str = "D:P(A;;GA;;;BA)(A;;GA;;;SY)";
+
+fn pipe_path(name: &str) -> String {
+    format!(r"\\.\pipe\{}", name)
+}
+
+fn to_wide(s: &str) -> Vec<u16> {
+    std::ffi::OsStr::new(s)
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect()
+}
+
+/// 管道的安全描述符
+struct AdminOnly(PSECURITY_DESCRIPTOR);
+
+impl AdminOnly {
+    fn new() -> io::Result<Self> {
+        let sddl = to_wide(PIPE_SDDL);
+        let mut descriptor: PSECURITY_DESCRIPTOR = ptr::null_mut();
+        let rs = unsafe {
+            ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                sddl.as_ptr(),
+                SDDL_REVISION_1,
+                &mut descriptor,
+                ptr::null_mut(),
+            )
+        };
+        if rs == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(descriptor))
+    }
+    fn attributes(&self) -> SECURITY_ATTRIBUTES {
+        SECURITY_ATTRIBUTES {
+            nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as DWORD,
+            lpSecurityDescriptor: self.0,
+            bInheritHandle: FALSE,
+        }
+    }
+}
+
+impl Drop for AdminOnly {
+    fn drop(&mut self) {
+        unsafe { LocalFree(self.0) };
+    }
+}
+
+/// 服务端的管道实例
+struct PipeInstance(HANDLE);
+
+impl PipeInstance {
+    fn create(path: &[u16], security: &AdminOnly, first: bool) -> io::Result<Self> {
+        let mut attributes = security.attributes();
+        let mut open_mode = PIPE_ACCESS_DUPLEX;
+        if first {
+            // 名称已被其他进程占用时失败，避免连上伪造的服务端
+            open_mode |= FILE_FLAG_FIRST_PIPE_INSTANCE;
+        }
+        let handle = unsafe {
+            CreateNamedPipeW(
+                path.as_ptr(),
+                open_mode,
+                PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                PIPE_UNLIMITED_INSTANCES,
+                64 * 1024,
+                64 * 1024,
+                0,
+                &mut attributes,
+            )
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(handle))
+    }
+    fn connect(&self) -> io::Result<()> {
+        if unsafe { ConnectNamedPipe(self.0, ptr::null_mut()) } == 0 {
+            let e = io::Error::last_os_error();
+            // 客户端在调用之前已经连上
+            if e.raw_os_error() != Some(ERROR_PIPE_CONNECTED as i32) {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for PipeInstance {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut len: DWORD = 0;
+        let rs = unsafe {
+            ReadFile(
+                self.0,
+                buf.as_mut_ptr() as _,
+                buf.len().min(DWORD::MAX as usize) as DWORD,
+                &mut len,
+                ptr::null_mut(),
+            )
+        };
+        if rs == 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(ERROR_BROKEN_PIPE as i32) {
+                return Ok(0);
+            }
+            return Err(e);
+        }
+        Ok(len as usize)
+    }
+}
+
+impl Write for PipeInstance {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut len: DWORD = 0;
+        let rs = unsafe {
+            WriteFile(
+                self.0,
+                buf.as_ptr() as _,
+                buf.len().min(DWORD::MAX as usize) as DWORD,
+                &mut len,
+                ptr::null_mut(),
+            )
+        };
+        if rs == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(len as usize)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        if unsafe { FlushFileBuffers(self.0) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PipeInstance {
+    fn drop(&mut self) {
+        unsafe {
+            DisconnectNamedPipe(self.0);
+            CloseHandle(self.0);
+        }
+    }
+}
+
+/// 命名管道上的命令服务，每个连接处理一个请求，stopped为true后退出
+pub fn serve<F: Fn(&[u8]) -> String>(stopped: &AtomicBool, handler: F) -> io::Result<()> {
+    let security = AdminOnly::new()?;
+    let (name, mut instance) =
+        match PipeInstance::create(&to_wide(&pipe_path(DEFAULT_PIPE)), &security, true) {
+            Ok(instance) => (DEFAULT_PIPE.to_string(), instance),
+            Err(e) if e.raw_os_error() == Some(ERROR_ACCESS_DENIED as i32) => {
+                // 已有其他实例在运行
+                let name = format!("{}-{}", DEFAULT_PIPE, std::process::id());
+                let instance = PipeInstance::create(&to_wide(&pipe_path(&name)), &security, true)?;
+                (name, instance)
+            }
+            Err(e) => return Err(e),
+        };
+    let path = to_wide(&pipe_path(&name));
+    log::info!("启动后台cmd:{}", pipe_path(&name));
+    if let Err(e) = save_name(&name) {
+        log::warn!("保存后台命令管道失败：{:?}", e);
+    }
+    loop {
+        let connected = instance.connect();
+        // 处理请求前先创建下一个实例，始终占用管道名称
+        let next = PipeInstance::create(&path, &security, false)?;
+        let mut current = std::mem::replace(&mut instance, next);
+        if let Err(e) = connected {
+            log::warn!("{:?}", e);
+            continue;
+        }
+        let out = match read_frame(&mut current) {
+            Ok(request) => handler(&request),
+            Err(e) => {
+                log::warn!("{:?}", e);
+                continue;
+            }
+        };
+        if let Err(e) = write_frame(&mut current, out.as_bytes()) {
+            log::warn!("{:?}", e);
+        }
+        if stopped.load(Ordering::Acquire) {
+            return Ok(());
+        }
+    }
+}
+
+fn save_name(name: &str) -> io::Result<()> {
+    let path_buf = crate::app_home()?.join(PIPE_FILE);
+    let mut file = std::fs::File::create(path_buf)?;
+    file.write_all(name.as_bytes())?;
+    file.sync_all()
+}
+
+fn read_name() -> String {
+    match crate::app_home().and_then(|home| std::fs::read_to_string(home.join(PIPE_FILE))) {
+        Ok(name) => name.trim().to_string(),
+        Err(e) => {
+            log::warn!("read_command_pipe:{:?}", e);
+            DEFAULT_PIPE.to_string()
+        }
+    }
+}
+
+/// 发送一个请求，管道忙时在timeout内重试
+pub fn request(request: &[u8], timeout: Duration) -> io::Result<Vec<u8>> {
+    let path = pipe_path(&read_name());
+    let start = Instant::now();
+    let mut pipe = loop {
+        match std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+        {
+            Ok(pipe) => break pipe,
+            Err(e) => {
+                if e.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) && start.elapsed() < timeout {
+                    std::thread::sleep(Duration::from_millis(20));
+                    continue;
+                }
+                return Err(e);
+            }
+        }
+    };
+    write_frame(&mut pipe, request)?;
+    read_frame(&mut pipe)
+}
@@ -1,18 +1,63 @@
 use std::io;
+#[cfg(not(windows))]
 use std::io::Write;
+use std::net::SocketAddr;
+#[cfg(not(windows))]
 use std::net::UdpSocket;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+#[cfg(not(windows))]
+use std::time::Duration;
+use std::time::Instant;
 
 use vnt::core::Vnt;
 
-pub struct CommandServer {}
+use crate::command::auth;
+
+/// 远程管理的监听地址和tls证书
+#[derive(Clone)]
+#[cfg_attr(not(feature = "cmd_tls"), allow(dead_code))]
+pub struct CommandTls {
+    pub addr: SocketAddr,
+    pub cert: String,
+    pub key: String,
+}
+
+/// 本地命令服务检查是否已停止的间隔
+#[cfg(not(windows))]
+const STOP_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
+pub struct CommandServer {
+    start_time: Instant,
+    token: String,
+    // 任一监听收到stop后，本地和远程的监听都退出
+    stopped: Arc<AtomicBool>,
+}
 
 impl CommandServer {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            start_time: Instant::now(),
+            token: auth::create_token()?,
+            stopped: Arc::new(AtomicBool::new(false)),
+        })
     }
 }
 
 impl CommandServer {
+    /// windows上使用只允许管理员访问的命名管道，不占用本地端口
+    #[cfg(windows)]
+    pub fn start(self, vnt: Vnt) -> io::Result<()> {
+        let stopped = self.stopped.clone();
+        crate::command::pipe::serve(&stopped, move |request| {
+            match std::str::from_utf8(request) {
+                Ok(request) => self.handle(request, &vnt),
+                Err(e) => format!("error {:?}", e),
+            }
+        })
+    }
+    #[cfg(not(windows))]
     pub fn start(self, vnt: Vnt) -> io::Result<()> {
         let udp = if let Ok(udp) = UdpSocket::bind("127.0.0.1:39271") {
             udp
@@ -25,18 +70,25 @@ impl CommandServer {
             log::warn!("保存后台命令端口失败：{:?}", e);
         }
 
-        let mut buf = [0u8; 64];
-        loop {
-            let (len, addr) = udp.recv_from(&mut buf)?;
+        // 远程管理收到stop时这里也要退出
+        udp.set_read_timeout(Some(STOP_CHECK_INTERVAL))?;
+        let mut buf = [0u8; 1024];
+        while !self.is_stopped() {
+            let (len, addr) = match udp.recv_from(&mut buf) {
+                Ok(rs) => rs,
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
             match std::str::from_utf8(&buf[..len]) {
-                Ok(cmd) => {
-                    if let Ok(out) = command(cmd, &vnt) {
-                        if let Err(e) = udp.send_to(out.as_bytes(), addr) {
-                            log::warn!("cmd={},err={:?}", cmd, e);
-                        }
-                        if "stopped" == &out {
-                            break;
-                        }
+                Ok(request) => {
+                    let out = self.handle(request, &vnt);
+                    if let Err(e) = udp.send_to(out.as_bytes(), addr) {
+                        log::warn!("addr={},err={:?}", addr, e);
                     }
                 }
                 Err(e) => {
@@ -46,7 +98,53 @@ impl CommandServer {
         }
         Ok(())
     }
+    /// 通过tcp+tls远程管理，使用和本地相同的令牌
+    #[cfg(feature = "cmd_tls")]
+    pub fn start_tls(self, vnt: Vnt, tls: CommandTls) -> io::Result<()> {
+        let stopped = self.stopped.clone();
+        crate::command::tls::serve(tls, stopped, move |request| {
+            let out = match std::str::from_utf8(request) {
+                Ok(request) => self.handle(request, &vnt),
+                Err(e) => format!("error {:?}", e),
+            };
+            // 命名管道阻塞在等待连接，需要连一次才能检查到已停止
+            #[cfg(windows)]
+            if self.is_stopped() {
+                let _ = crate::command::pipe::request(b"", std::time::Duration::from_secs(1));
+            }
+            out
+        })
+    }
+    #[cfg(not(feature = "cmd_tls"))]
+    pub fn start_tls(self, _vnt: Vnt, _tls: CommandTls) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "cmd tls requires feature cmd_tls",
+        ))
+    }
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Acquire)
+    }
+    fn handle(&self, request: &str, vnt: &Vnt) -> String {
+        match auth::verify(&self.token, request) {
+            Some(cmd) => {
+                let out =
+                    command(cmd, vnt, self.start_time).unwrap_or_else(|e| format!("error {:?}", e));
+                if "stopped" == out {
+                    self.stopped.store(true, Ordering::Release);
+                }
+                out
+            }
+            None => {
+                log::warn!("命令令牌错误");
+                UNAUTHORIZED.to_string()
+            }
+        }
+    }
 }
+
+pub const UNAUTHORIZED: &str = "error unauthorized";
+#[cfg(not(windows))]
 fn save_port(port: u16) -> io::Result<()> {
     let path_buf = crate::app_home()?.join("command-port");
     let mut file = std::fs::File::create(path_buf)?;
@@ -54,7 +152,7 @@ fn save_port(port: u16) -> io::Result<()> {
     file.sync_all()
 }
 
-fn command(cmd: &str, vnt: &Vnt) -> io::Result<String> {
+fn command(cmd: &str, vnt: &Vnt, start_time: Instant) -> io::Result<String> {
     let cmd = cmd.trim();
     let out_str = match cmd {
         "route" => serde_yaml::to_string(&crate::command::command_route(vnt))
@@ -63,13 +161,69 @@ fn command(cmd: &str, vnt: &Vnt) -> io::Result<String> {
             .unwrap_or_else(|e| format!("error {:?}", e)),
         "info" => serde_yaml::to_string(&crate::command::command_info(vnt))
             .unwrap_or_else(|e| format!("error {:?}", e)),
+        "effective-config" => serde_yaml::to_string(&crate::command::command_effective_config(vnt))
+            .unwrap_or_else(|e| format!("error {:?}", e)),
+        "events" => serde_yaml::to_string(&crate::command::command_events(vnt, 0))
+            .unwrap_or_else(|e| format!("error {:?}", e)),
+        "matrix" => serde_yaml::to_string(&crate::command::command_matrix(vnt))
+            .unwrap_or_else(|e| format!("error {:?}", e)),
+        "relay" => serde_yaml::to_string(&crate::command::command_relay(vnt))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?,
+        "counters" => serde_yaml::to_string(&crate::command::command_counters(vnt))
+            .unwrap_or_else(|e| format!("error {:?}", e)),
+        "keys" => serde_yaml::to_string(&crate::command::command_keys(vnt))
+            .unwrap_or_else(|e| format!("error {:?}", e)),
+        "status" => serde_yaml::to_string(&crate::command::command_status(vnt, start_time))
+            .unwrap_or_else(|e| format!("error {:?}", e)),
         "stop" => {
+            crate::daemon::request_stop();
             vnt.stop();
             "stopped".to_string()
         }
+        _ if cmd.starts_with("events ") => {
+            let seq = cmd["events ".len()..].trim().parse::<u64>().unwrap_or(0);
+            serde_yaml::to_string(&crate::command::command_events(vnt, seq))
+                .unwrap_or_else(|e| format!("error {:?}", e))
+        }
+        _ if cmd.starts_with("diag ") => {
+            let ip = cmd["diag ".len()..].trim();
+            serde_yaml::to_string(&crate::command::command_diag(vnt, ip))
+                .unwrap_or_else(|e| format!("error {:?}", e))
+        }
+        _ if cmd.starts_with("punch-log ") => {
+            let ip = cmd["punch-log ".len()..].trim();
+            serde_yaml::to_string(&crate::command::command_punch_log(vnt, ip)?)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?
+        }
+        _ if cmd.starts_with("speedtest ") => {
+            let mut args = cmd["speedtest ".len()..].split_whitespace();
+            let ip = args.next().unwrap_or_default();
+            let secs = args.next().and_then(|v| v.parse().ok()).unwrap_or(10);
+            let tcp = args.next() == Some("tcp");
+            serde_yaml::to_string(&crate::command::command_speed_test(vnt, ip, secs, tcp))
+                .unwrap_or_else(|e| format!("error {:?}", e))
+        }
+        _ if cmd.starts_with("logs ") => {
+            let mut args = cmd["logs ".len()..].split_whitespace();
+            let seq = args.next().and_then(|v| v.parse().ok()).unwrap_or(0);
+            let level = args.next().unwrap_or("trace");
+            serde_yaml::to_string(&crate::command::command_logs(vnt, seq, level)?)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?
+        }
+        _ if cmd.starts_with("wan ") => {
+            let out = crate::command::command_wan_sim(vnt, cmd["wan ".len()..].trim());
+            serde_yaml::to_string(&out).unwrap_or_else(|e| format!("error {:?}", e))
+        }
+        _ if cmd.starts_with("log ") => {
+            let out = match vnt.set_log_filter(cmd["log ".len()..].trim()) {
+                Ok(_) => "ok".to_string(),
+                Err(e) => format!("error {}", e),
+            };
+            serde_yaml::to_string(&out).unwrap_or_else(|e| format!("error {:?}", e))
+        }
         _ => {
             format!(
-                "command '{}' not found.  Try to enter: 'route'/'list'/'stop' \n",
+                "command '{}' not found.  Try to enter: 'route'/'list'/'info'/'effective-config'/'events'/'matrix'/'keys'/'relay'/'diag <ip>'/'punch-log <ip>'/'speedtest <ip> [secs] [udp|tcp]'/'status'/'log <filter>'/'logs <seq> [level]'/'wan <cmd>'/'stop' \n",
                 cmd
             )
         }
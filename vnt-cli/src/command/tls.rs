@@ -0,0 +1,177 @@
+use std::io;
+use std::io::Write;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, PrivateKey, ServerName};
+
+use crate::command::frame::{read_frame, write_frame};
+use crate::command::server::CommandTls;
+
+/// 同时处理的连接数，超出的连接直接关闭
+const MAX_CONNECTIONS: usize = 4;
+/// 握手和读取请求的超时时间
+const READ_TIMEOUT: Duration = Duration::from_secs(3);
+/// 没有新连接时检查是否已停止的间隔
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(500);
+
+fn to_io<E: std::fmt::Debug>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("{:?}", e))
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<Certificate>> {
+    let mut reader = io::BufReader::new(std::fs::File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no certificate in {}", path),
+        ));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &str) -> io::Result<PrivateKey> {
+    let mut reader = io::BufReader::new(std::fs::File::open(path)?);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => {}
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("no private key in {}", path),
+                ))
+            }
+        }
+    }
+}
+
+/// 监听tcp，每个连接处理一个请求，请求和响应都带4字节长度，stopped为true后退出
+pub fn serve<F>(tls: CommandTls, stopped: Arc<AtomicBool>, handler: F) -> io::Result<()>
+where
+    F: Fn(&[u8]) -> String + Send + Sync + 'static,
+{
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(load_certs(&tls.cert)?, load_key(&tls.key)?)
+        .map_err(to_io)?;
+    let config = Arc::new(config);
+    let handler = Arc::new(handler);
+    let listener = TcpListener::bind(tls.addr)?;
+    // 非阻塞接收，本地命令收到stop时能及时退出
+    listener.set_nonblocking(true)?;
+    let connections = Arc::new(AtomicUsize::new(0));
+    log::info!("启动远程管理:{:?}", tls.addr);
+    while !stopped.load(Ordering::Acquire) {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                std::thread::sleep(ACCEPT_INTERVAL);
+                continue;
+            }
+            Err(e) => {
+                log::warn!("{:?}", e);
+                continue;
+            }
+        };
+        if let Err(e) = stream.set_nonblocking(false) {
+            log::warn!("{:?}", e);
+            continue;
+        }
+        if connections.fetch_add(1, Ordering::AcqRel) >= MAX_CONNECTIONS {
+            connections.fetch_sub(1, Ordering::AcqRel);
+            log::warn!("远程管理连接数过多，关闭 {:?}", stream.peer_addr());
+            continue;
+        }
+        let guard = ConnectionGuard(connections.clone());
+        let config = config.clone();
+        let handler = handler.clone();
+        let rs = std::thread::Builder::new()
+            .name("CommandTls".into())
+            .spawn(move || {
+                let _guard = guard;
+                let peer = stream.peer_addr();
+                if let Err(e) = handle(config, stream, handler.as_ref()) {
+                    log::warn!("远程管理 {:?} {:?}", peer, e);
+                }
+            });
+        if let Err(e) = rs {
+            log::warn!("远程管理 {:?}", e);
+        }
+    }
+    Ok(())
+}
+
+/// 连接处理结束时释放计数
+struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+fn handle<F: Fn(&[u8]) -> String>(
+    config: Arc<rustls::ServerConfig>,
+    stream: TcpStream,
+    handler: &F,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.set_write_timeout(Some(READ_TIMEOUT))?;
+    let conn = rustls::ServerConnection::new(config).map_err(to_io)?;
+    let mut stream = rustls::StreamOwned::new(conn, stream);
+    let request = read_frame(&mut stream)?;
+    let out = handler(&request);
+    write_frame(&mut stream, out.as_bytes())?;
+    stream.conn.send_close_notify();
+    stream.flush()
+}
+
+/// 发送一个请求，证书和本地保存的完全一致才信任
+pub fn request(
+    addr: SocketAddr,
+    cert: &str,
+    request: &[u8],
+    timeout: Duration,
+) -> io::Result<Vec<u8>> {
+    let pinned = load_certs(cert)?.remove(0);
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(PinnedCert(pinned)))
+        .with_no_client_auth();
+    let tcp = TcpStream::connect_timeout(&addr, Duration::from_secs(5))?;
+    tcp.set_read_timeout(Some(timeout))?;
+    // 证书固定后不校验名称
+    let name = ServerName::try_from("vnt").map_err(to_io)?;
+    let conn = rustls::ClientConnection::new(Arc::new(config), name).map_err(to_io)?;
+    let mut stream = rustls::StreamOwned::new(conn, tcp);
+    write_frame(&mut stream, request)?;
+    read_frame(&mut stream)
+}
+
+struct PinnedCert(Certificate);
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if end_entity == &self.0 {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General("certificate mismatch".into()))
+        }
+    }
+}
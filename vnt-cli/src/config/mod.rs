@@ -1,13 +1,21 @@
+use std::collections::BTreeMap;
 use std::io;
 use std::net::Ipv4Addr;
 use std::str::FromStr;
 
 use serde::{Deserialize, Serialize};
 
+use vnt::channel::acl::{AclBundle, AclGroup};
+use vnt::channel::peer_filter::PeerMatch;
 use vnt::channel::punch::PunchModel;
-use vnt::channel::UseChannelType;
-use vnt::cipher::CipherModel;
+use vnt::channel::send_queue::SendQueueConfig;
+use vnt::channel::sock_buf::SocketBuf;
+use vnt::channel::{LoadBalanceModel, UseChannelType};
+use vnt::cipher::{CipherModel, KeyDerivation};
 use vnt::core::Config;
+use vnt::tun_tap_device::dhcp_pool::DhcpPool;
+
+pub mod wizard;
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(default)]
@@ -29,6 +37,12 @@ pub struct FileConfig {
     pub use_channel: String,
     #[cfg(feature = "ip_proxy")]
     pub no_proxy: bool,
+    #[cfg(feature = "ip_proxy")]
+    pub tcp_proxy_keepalive: u32,
+    #[cfg(feature = "ip_proxy")]
+    pub tcp_proxy_idle: u32,
+    #[cfg(feature = "ip_proxy")]
+    pub tcp_proxy_max_conn: u32,
     pub server_encrypt: bool,
     pub parallel: usize,
     pub cipher_model: String,
@@ -40,6 +54,67 @@ pub struct FileConfig {
     pub device_name: Option<String>,
     pub packet_loss: Option<f64>,
     pub packet_delay: u32,
+    pub load_balance: String,
+    pub hosts: Option<String>,
+    pub anti_replay: bool,
+    pub prefer_ipv6_server: bool,
+    pub power_save: u32,
+    pub power_save_multiple: u32,
+    pub psk: Vec<String>,
+    pub knock_key: Option<String>,
+    pub knock_port: Option<u16>,
+    pub coalesce: u32,
+    pub allow_diag: bool,
+    pub allow_speed_test: bool,
+    pub server_proxy: Option<String>,
+    pub route_hysteresis: u32,
+    pub route_hold_down: u32,
+    pub device_key: Option<String>,
+    pub reverse_tunnel: usize,
+    pub header_auth: bool,
+    pub listen: Option<String>,
+    pub dscp: Option<u8>,
+    pub so_priority: Option<u32>,
+    pub dscp_copy: bool,
+    pub hold_punch: u32,
+    pub wan_sim: Vec<String>,
+    pub relay_budget: u32,
+    pub kdf: String,
+    pub allow_peer: Vec<String>,
+    pub deny_peer: Vec<String>,
+    #[cfg(target_os = "linux")]
+    pub no_multi_queue: bool,
+    #[cfg(feature = "ip_proxy")]
+    pub out_ips6: Vec<String>,
+    pub advertise_subnets: bool,
+    pub accept_subnets: bool,
+    pub padding: bool,
+    pub cover_traffic: u32,
+    pub accept_server_config: Vec<String>,
+    pub server_ports: Vec<u16>,
+    pub vlan: Vec<u16>,
+    pub ip_conflict_reassign: bool,
+    pub xdp: Option<String>,
+    pub send_queue: Option<String>,
+    pub acl_bundles: BTreeMap<String, Vec<String>>,
+    pub acl_groups: Vec<AclGroupConfig>,
+    pub control_dscp: Option<u8>,
+    pub parallel_crypto: usize,
+    #[cfg(feature = "ip_proxy")]
+    pub gateway: Option<String>,
+    pub broadcast_mask: Option<String>,
+    pub user: Option<String>,
+    pub wan_schedule: Option<String>,
+    pub udp_buf: Option<String>,
+    pub tap_dhcp: Option<String>,
+    pub networks: BTreeMap<String, serde_yaml::Value>,
+}
+
+/// 把规则集分配给一组对端，bundle为acl_bundles中定义的或者内置的规则集
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AclGroupConfig {
+    pub bundle: String,
+    pub peers: Vec<String>,
 }
 
 impl Default for FileConfig {
@@ -66,6 +141,12 @@ impl Default for FileConfig {
             use_channel: "all".to_string(),
             #[cfg(feature = "ip_proxy")]
             no_proxy: false,
+            #[cfg(feature = "ip_proxy")]
+            tcp_proxy_keepalive: 120,
+            #[cfg(feature = "ip_proxy")]
+            tcp_proxy_idle: 0,
+            #[cfg(feature = "ip_proxy")]
+            tcp_proxy_max_conn: 0,
             server_encrypt: false,
             parallel: 1,
             cipher_model: "aes_gcm".to_string(),
@@ -77,19 +158,174 @@ impl Default for FileConfig {
             device_name: None,
             packet_loss: None,
             packet_delay: 0,
+            load_balance: "none".to_string(),
+            hosts: None,
+            anti_replay: false,
+            prefer_ipv6_server: false,
+            power_save: 0,
+            power_save_multiple: 5,
+            psk: vec![],
+            knock_key: None,
+            knock_port: None,
+            coalesce: 0,
+            allow_diag: false,
+            allow_speed_test: false,
+            server_proxy: None,
+            route_hysteresis: 0,
+            route_hold_down: 0,
+            device_key: None,
+            reverse_tunnel: 0,
+            header_auth: false,
+            listen: None,
+            dscp: None,
+            so_priority: None,
+            dscp_copy: false,
+            hold_punch: 0,
+            wan_sim: vec![],
+            relay_budget: 0,
+            kdf: "v1".to_string(),
+            allow_peer: vec![],
+            deny_peer: vec![],
+            #[cfg(target_os = "linux")]
+            no_multi_queue: false,
+            #[cfg(feature = "ip_proxy")]
+            out_ips6: vec![],
+            advertise_subnets: false,
+            accept_subnets: false,
+            padding: false,
+            cover_traffic: 0,
+            accept_server_config: vec![],
+            server_ports: vec![],
+            vlan: vec![],
+            ip_conflict_reassign: false,
+            xdp: None,
+            send_queue: None,
+            acl_bundles: BTreeMap::new(),
+            acl_groups: vec![],
+            control_dscp: None,
+            parallel_crypto: 0,
+            #[cfg(feature = "ip_proxy")]
+            gateway: None,
+            broadcast_mask: None,
+            user: None,
+            wan_schedule: None,
+            udp_buf: None,
+            tap_dhcp: None,
+            networks: BTreeMap::new(),
         }
     }
 }
 
 pub fn read_config(file_path: &str) -> io::Result<(Config, bool)> {
+    to_config(load_file_config(file_path)?)
+}
+
+/// 同时加入的其他网络不继承的配置，端口和网卡名称不能重复，
+/// 路由、网关、vlan、dhcp和监听相关的配置只对所在的网络有效
+const NOT_INHERITED: [&str; 15] = [
+    "token",
+    "ip",
+    "device_name",
+    "ports",
+    "user",
+    "networks",
+    "in_ips",
+    "out_ips",
+    "out_ips6",
+    "advertise_subnets",
+    "gateway",
+    "vlan",
+    "tap_dhcp",
+    "knock_port",
+    "xdp",
+];
+
+/// 配置文件中networks下的其他网络，每个网络使用单独的虚拟网卡、路由表和密钥，
+/// 未指定的配置继承顶层配置，网卡名称默认为vnt-<网络名>
+pub fn read_networks(file_path: &str) -> io::Result<Vec<(String, Config)>> {
+    let conf = std::fs::read_to_string(file_path)?;
+    let root = serde_yaml::from_str::<serde_yaml::Mapping>(&conf)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e)))?;
+    let networks = match root.get("networks") {
+        Some(serde_yaml::Value::Mapping(networks)) => networks.clone(),
+        Some(serde_yaml::Value::Null) | None => return Ok(Vec::new()),
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "networks must be a mapping of name to config",
+            ))
+        }
+    };
+    if root.get("user").is_some_and(|v| !v.is_null()) {
+        // 降权后无法再创建其他网络的虚拟网卡
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "user is not supported with networks",
+        ));
+    }
+    let mut list = Vec::with_capacity(networks.len());
+    for (name, network) in networks {
+        let name = match name.as_str() {
+            Some(name) => name.to_string(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("network name {:?}", name),
+                ))
+            }
+        };
+        let mut mapping = root.clone();
+        for key in NOT_INHERITED {
+            mapping.remove(key);
+        }
+        match network {
+            serde_yaml::Value::Mapping(network) => {
+                for (key, value) in network {
+                    mapping.insert(key, value);
+                }
+            }
+            serde_yaml::Value::Null => {}
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("network {} must be a mapping", name),
+                ))
+            }
+        }
+        if mapping.get("user").is_some_and(|v| !v.is_null()) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("network {}: user is not supported with networks", name),
+            ));
+        }
+        if !mapping.contains_key("device_name") {
+            // linux网卡名称最长15个字符
+            let device_name: String = format!("vnt-{}", name).chars().take(15).collect();
+            mapping.insert("device_name".into(), device_name.into());
+        }
+        let file_conf = serde_yaml::from_value::<FileConfig>(serde_yaml::Value::Mapping(mapping))
+            .map_err(|e| {
+            io::Error::new(io::ErrorKind::Other, format!("network {} {}", name, e))
+        })?;
+        let (config, _) = to_config(file_conf)
+            .map_err(|e| io::Error::new(e.kind(), format!("network {} {}", name, e)))?;
+        list.push((name, config));
+    }
+    Ok(list)
+}
+
+pub fn load_file_config(file_path: &str) -> io::Result<FileConfig> {
     let conf = std::fs::read_to_string(file_path)?;
-    let file_conf = match serde_yaml::from_str::<FileConfig>(&conf) {
-        Ok(val) => val,
+    match serde_yaml::from_str::<FileConfig>(&conf) {
+        Ok(val) => Ok(val),
         Err(e) => {
             log::error!("{:?}", e);
-            return Err(io::Error::new(io::ErrorKind::Other, format!("{}", e)));
+            Err(io::Error::new(io::ErrorKind::Other, format!("{}", e)))
         }
-    };
+    }
+}
+
+pub fn to_config(file_conf: FileConfig) -> io::Result<(Config, bool)> {
     if file_conf.token.is_empty() {
         return Err(io::Error::new(io::ErrorKind::Other, "token is_empty"));
     }
@@ -112,6 +348,25 @@ pub fn read_config(file_path: &str) -> io::Result<(Config, bool)> {
             ));
         }
     };
+    #[cfg(feature = "ip_proxy")]
+    let out_ips6 = match common::args_parse::out_ips6_parse(&file_conf.out_ips6) {
+        Ok(out_ips6) => out_ips6,
+        Err(e) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("out_ips6 {:?} error:{}", &file_conf.out_ips6, e),
+            ));
+        }
+    };
+    let psk = match common::args_parse::psk_parse(&file_conf.psk) {
+        Ok(psk) => psk,
+        Err(e) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("psk error:{}", e),
+            ));
+        }
+    };
     let virtual_ip = match file_conf.ip.clone().map(|v| Ipv4Addr::from_str(&v)) {
         None => None,
         Some(r) => Some(r.map_err(|e| {
@@ -122,13 +377,67 @@ pub fn read_config(file_path: &str) -> io::Result<(Config, bool)> {
         })?),
     };
 
+    let virtual_ip = match &file_conf.hosts {
+        Some(hosts) => {
+            let hosts_ip = crate::command::init_hosts(hosts, &file_conf.name)?;
+            virtual_ip.or(hosts_ip)
+        }
+        None => virtual_ip,
+    };
+
     let cipher_model = CipherModel::from_str(&file_conf.cipher_model)
         .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
 
+    let kdf = KeyDerivation::from_str(&file_conf.kdf)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
     let punch_model = PunchModel::from_str(&file_conf.punch_model)
         .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let send_queue = match &file_conf.send_queue {
+        Some(send_queue) => SendQueueConfig::from_str(send_queue)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?,
+        None => SendQueueConfig::default(),
+    };
+    let socket_buf = match &file_conf.udp_buf {
+        Some(udp_buf) => {
+            SocketBuf::from_str(udp_buf).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+        }
+        None => SocketBuf::default(),
+    };
+    let dhcp_pool = match &file_conf.tap_dhcp {
+        Some(tap_dhcp) => Some(
+            DhcpPool::from_str(tap_dhcp).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?,
+        ),
+        None => None,
+    };
+    let wan_schedule = match &file_conf.wan_schedule {
+        Some(path) => Some(
+            std::fs::read_to_string(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("wan_schedule {} {}", path, e)))?,
+        ),
+        None => None,
+    };
+    #[cfg(feature = "ip_proxy")]
+    let gateway = match &file_conf.gateway {
+        Some(gateway) => Some(
+            vnt::ip_proxy::gateway::GatewayConfig::from_str(gateway)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?,
+        ),
+        None => None,
+    };
+    let broadcast_mask =
+        match &file_conf.broadcast_mask {
+            Some(mask) => Some(common::args_parse::netmask_parse(mask).map_err(|e| {
+                io::Error::new(io::ErrorKind::Other, format!("broadcast_mask {}", e))
+            })?),
+            None => None,
+        };
+    let acl = acl_groups(&file_conf.acl_bundles, &file_conf.acl_groups)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
     let use_channel_type = UseChannelType::from_str(&file_conf.use_channel)
         .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let load_balance = LoadBalanceModel::from_str(&file_conf.load_balance)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
     let config = Config::new(
         #[cfg(any(target_os = "windows", target_os = "linux"))]
         file_conf.tap,
@@ -146,6 +455,12 @@ pub fn read_config(file_path: &str) -> io::Result<(Config, bool)> {
         virtual_ip,
         #[cfg(feature = "ip_proxy")]
         file_conf.no_proxy,
+        #[cfg(feature = "ip_proxy")]
+        file_conf.tcp_proxy_keepalive,
+        #[cfg(feature = "ip_proxy")]
+        file_conf.tcp_proxy_idle,
+        #[cfg(feature = "ip_proxy")]
+        file_conf.tcp_proxy_max_conn,
         file_conf.server_encrypt,
         file_conf.parallel,
         cipher_model,
@@ -157,11 +472,86 @@ pub fn read_config(file_path: &str) -> io::Result<(Config, bool)> {
         use_channel_type,
         file_conf.packet_loss,
         file_conf.packet_delay,
+        load_balance,
+        file_conf.anti_replay,
+        file_conf.prefer_ipv6_server,
+        file_conf.power_save,
+        file_conf.power_save_multiple,
+        psk,
+        file_conf.knock_key,
+        file_conf.knock_port,
+        file_conf.coalesce,
+        file_conf.allow_diag,
+        file_conf.allow_speed_test,
+        file_conf.server_proxy,
+        file_conf.route_hysteresis,
+        file_conf.route_hold_down,
+        file_conf.device_key,
+        file_conf.reverse_tunnel,
+        file_conf.header_auth,
+        file_conf.listen,
+        file_conf.dscp,
+        file_conf.so_priority,
+        file_conf.dscp_copy,
+        file_conf.hold_punch,
+        file_conf.wan_sim,
+        file_conf.relay_budget,
+        kdf,
+        file_conf.allow_peer,
+        file_conf.deny_peer,
+        #[cfg(target_os = "linux")]
+        file_conf.no_multi_queue,
+        #[cfg(feature = "ip_proxy")]
+        out_ips6,
+        file_conf.advertise_subnets,
+        file_conf.accept_subnets,
+        file_conf.padding,
+        file_conf.cover_traffic,
+        file_conf.accept_server_config,
+        file_conf.server_ports,
+        #[cfg(any(target_os = "windows", target_os = "linux"))]
+        file_conf.vlan,
+        file_conf.ip_conflict_reassign,
+        file_conf.xdp,
+        send_queue,
+        acl,
+        file_conf.control_dscp,
+        file_conf.parallel_crypto,
+        #[cfg(feature = "ip_proxy")]
+        gateway,
+        broadcast_mask,
+        file_conf.user,
+        wan_schedule,
+        socket_buf,
+        dhcp_pool,
     )
-    .unwrap();
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e)))?;
     Ok((config, file_conf.cmd))
 }
 
+fn acl_groups(
+    bundles: &BTreeMap<String, Vec<String>>,
+    groups: &[AclGroupConfig],
+) -> Result<Vec<AclGroup>, String> {
+    let mut list = Vec::with_capacity(groups.len());
+    for group in groups {
+        // 自定义的规则集可以覆盖同名的内置规则集
+        let bundle = match bundles.get(&group.bundle) {
+            Some(rules) => AclBundle::new(&group.bundle, rules)?,
+            None => match AclBundle::builtin(&group.bundle) {
+                Some(bundle) => bundle,
+                None => return Err(format!("acl bundle '{}' not found", group.bundle)),
+            },
+        };
+        let mut peers = Vec::with_capacity(group.peers.len());
+        for peer in &group.peers {
+            peers.push(PeerMatch::from_str(peer)?);
+        }
+        list.push(AclGroup { bundle, peers });
+    }
+    Ok(list)
+}
+
 pub fn get_device_id() -> String {
     if let Some(id) = common::identifier::get_unique_identifier() {
         id
@@ -182,3 +572,53 @@ pub fn get_device_id() -> String {
         }
     }
 }
+
+#[test]
+fn test_read_networks() {
+    let path = std::env::temp_dir().join(format!("vnt-networks-{}.yaml", std::process::id()));
+    std::fs::write(
+        &path,
+        r#"
+token: family-token
+device_id: test-device
+server_address: 127.0.0.1:29872
+password: secret
+in_ips:
+  - 192.168.10.0/24,10.26.0.3
+out_ips:
+  - 0.0.0.0/0
+advertise_subnets: true
+knock_port: 29873
+xdp: eth0
+networks:
+  work:
+    token: work-token
+    server_address: 127.0.0.2:29872
+  lab:
+    token: lab-token
+    out_ips:
+      - 192.168.20.0/24
+"#,
+    )
+    .unwrap();
+    let rs = read_networks(path.to_str().unwrap());
+    let _ = std::fs::remove_file(&path);
+    let networks = rs.unwrap();
+    assert_eq!(networks.len(), 2);
+    let (name, work) = &networks[0];
+    assert_eq!(name, "work");
+    assert_eq!(work.token, "work-token");
+    assert_eq!(work.device_name.as_deref(), Some("vnt-work"));
+    // 继承顶层的密码和设备id
+    assert!(work.password.is_some());
+    assert_eq!(work.device_id, "test-device");
+    // 路由和监听相关的配置不继承
+    assert!(work.in_ips.is_empty());
+    assert!(work.out_ips.is_empty());
+    assert!(!work.advertise_subnets);
+    assert_eq!(work.knock_port, None);
+    assert_eq!(work.xdp, None);
+    let (name, lab) = &networks[1];
+    assert_eq!(name, "lab");
+    assert_eq!(lab.out_ips.len(), 1);
+}
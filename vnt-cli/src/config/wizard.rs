@@ -0,0 +1,181 @@
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+use console::style;
+use serde_yaml::{Mapping, Value};
+
+use crate::config::{load_file_config, read_networks, to_config, FileConfig};
+
+/// 输出时隐藏的字段
+const SECRET_KEYS: [&str; 3] = ["password", "psk", "knock_key"];
+
+/// 交互式生成配置文件，完成后检查一遍
+pub fn init(path: &str) -> io::Result<()> {
+    if Path::new(path).exists() && !confirm(&format!("{} 已存在,是否覆盖", path), false)? {
+        return Ok(());
+    }
+    let default = FileConfig::default();
+    let mut mapping = Mapping::new();
+    let token = loop {
+        let token = prompt("组网标识(token)", None)?;
+        if !token.is_empty() {
+            break token;
+        }
+        println!("{}", style("token不能为空").red());
+    };
+    mapping.insert("token".into(), token.into());
+    let name = prompt("设备名称", Some(&default.name))?;
+    mapping.insert("name".into(), name.into());
+    let server_address = prompt("服务器地址", Some(&default.server_address))?;
+    mapping.insert("server_address".into(), server_address.into());
+    if confirm("使用tcp连接服务器", false)? {
+        mapping.insert("tcp".into(), true.into());
+    }
+    let ip = prompt("虚拟ip(留空由服务端分配)", Some(""))?;
+    if !ip.is_empty() {
+        mapping.insert("ip".into(), ip.into());
+    }
+    let password = prompt("客户端加密密码(留空不加密)", Some(""))?;
+    if !password.is_empty() {
+        mapping.insert("password".into(), password.into());
+        let cipher_model = prompt("加密模式", Some(&default.cipher_model))?;
+        mapping.insert("cipher_model".into(), cipher_model.into());
+    }
+    let in_ips = prompt(
+        "点对网入站规则(如192.168.1.0/24,10.26.0.3,多个用空格分隔)",
+        Some(""),
+    )?;
+    let in_ips: Vec<Value> = in_ips.split_whitespace().map(|v| v.into()).collect();
+    if !in_ips.is_empty() {
+        mapping.insert("in_ips".into(), in_ips.into());
+    }
+    let out_ips = prompt("点对网出站规则(如0.0.0.0/0,多个用空格分隔)", Some(""))?;
+    let out_ips: Vec<Value> = out_ips.split_whitespace().map(|v| v.into()).collect();
+    if !out_ips.is_empty() {
+        mapping.insert("out_ips".into(), out_ips.into());
+    }
+    let yaml = serde_yaml::to_string(&mapping)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    std::fs::write(path, yaml)?;
+    println!("{} {}", style("已生成").green(), path);
+    check(path);
+    Ok(())
+}
+
+/// 检查配置文件，输出错误和生效的完整配置，返回是否有错误
+pub fn check(path: &str) -> bool {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            println!("[{}] {} {}", style("FAIL").red(), path, e);
+            return false;
+        }
+    };
+    // 未知的字段会被忽略，多数是拼写错误或者当前版本未启用对应功能
+    let known = match serde_yaml::to_value(FileConfig::default()) {
+        Ok(Value::Mapping(known)) => known,
+        _ => Mapping::new(),
+    };
+    match serde_yaml::from_str::<Value>(&text) {
+        Ok(Value::Mapping(mapping)) => {
+            for key in mapping.keys() {
+                if !known.contains_key(key) {
+                    println!(
+                        "[{}] unknown field {}, misspelled or not supported in this build",
+                        style("WARN").yellow(),
+                        serde_yaml::to_string(key).unwrap_or_default().trim()
+                    );
+                }
+            }
+        }
+        Ok(Value::Null) => {}
+        Ok(_) => {
+            println!("[{}] expected a mapping at top level", style("FAIL").red());
+            return false;
+        }
+        Err(e) => {
+            println!("[{}] {}", style("FAIL").red(), e);
+            return false;
+        }
+    }
+    let file_conf = match load_file_config(path) {
+        Ok(file_conf) => file_conf,
+        Err(e) => {
+            println!("[{}] {}", style("FAIL").red(), e);
+            return false;
+        }
+    };
+    let effective = effective_yaml(&file_conf);
+    if let Err(e) = to_config(file_conf) {
+        println!("[{}] {}", style("FAIL").red(), e);
+        return false;
+    }
+    if let Err(e) = read_networks(path) {
+        println!("[{}] {}", style("FAIL").red(), e);
+        return false;
+    }
+    println!("[{}] {}", style("PASS").green(), path);
+    println!("{}", style("# 生效的配置").dim());
+    print!("{}", effective);
+    true
+}
+
+/// 合并默认值后的配置，隐藏密码等字段
+fn effective_yaml(file_conf: &FileConfig) -> String {
+    let mut value = match serde_yaml::to_value(file_conf) {
+        Ok(value) => value,
+        Err(e) => return format!("# {}\n", e),
+    };
+    if let Value::Mapping(mapping) = &mut value {
+        hide_secrets(mapping);
+        if let Some(Value::Mapping(networks)) = mapping.get_mut("networks") {
+            for (_, network) in networks.iter_mut() {
+                if let Value::Mapping(network) = network {
+                    hide_secrets(network);
+                }
+            }
+        }
+    }
+    serde_yaml::to_string(&value).unwrap_or_default()
+}
+
+fn hide_secrets(mapping: &mut Mapping) {
+    for key in SECRET_KEYS {
+        if let Some(v) = mapping.get_mut(key) {
+            match v {
+                Value::Null => {}
+                Value::Sequence(list) if list.is_empty() => {}
+                _ => *v = "******".into(),
+            }
+        }
+    }
+}
+
+fn prompt(label: &str, default: Option<&str>) -> io::Result<String> {
+    match default {
+        Some(default) if !default.is_empty() => print!("{} [{}]: ", label, default),
+        _ => print!("{}: ", label),
+    }
+    io::stdout().flush()?;
+    let mut line = String::new();
+    if io::stdin().read_line(&mut line)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "input closed"));
+    }
+    let line = line.trim();
+    if line.is_empty() {
+        Ok(default.unwrap_or_default().to_string())
+    } else {
+        Ok(line.to_string())
+    }
+}
+
+fn confirm(label: &str, default: bool) -> io::Result<bool> {
+    let hint = if default { "Y/n" } else { "y/N" };
+    let answer = prompt(&format!("{}({})", label, hint), Some(""))?;
+    Ok(match answer.to_lowercase().as_str() {
+        "y" | "yes" => true,
+        "n" | "no" => false,
+        _ => default,
+    })
+}
@@ -1,7 +1,11 @@
 use console::{style, Style};
 
-use crate::command::entity::{DeviceItem, Info, RouteItem};
+use crate::command::entity::{
+    Counters, CrashItem, DeviceItem, DiagItem, EffectiveConfig, EventItem, Info, KeyItem, LogItem,
+    MatrixItem, PunchLogItem, RelayStatus, RouteItem, SpeedItem, SpeedTestItem, Status,
+};
 
+pub mod route_diff;
 pub mod table;
 
 pub fn console_info(status: Info) {
@@ -20,9 +24,473 @@ pub fn console_info(status: Info) {
     println!("IPv6: {}", style(status.ipv6_addr).green());
     println!("Up: {}", style(convert(status.up)).green());
     println!("Down: {}", style(convert(status.down)).green());
+    if status.replay_drop > 0 {
+        println!("Replay drop: {}", style(status.replay_drop).red());
+    }
+    if status.tcp_proxy_evict > 0 {
+        println!(
+            "TCP proxy evict: {}",
+            style(status.tcp_proxy_evict).yellow()
+        );
+    }
+}
+
+pub fn console_status(status: Status) {
+    println!("Version: {}", style(status.version).green());
+    println!("Pid: {}", style(status.pid).green());
+    let uptime = status.uptime;
+    println!(
+        "Uptime: {}",
+        style(format!(
+            "{}d {}h {}m {}s",
+            uptime / 86400,
+            uptime % 86400 / 3600,
+            uptime % 3600 / 60,
+            uptime % 60
+        ))
+        .green()
+    );
+    println!(
+        "Connection status: {}",
+        style(status.connect_status).green()
+    );
+}
+
+pub fn console_logs(list: Vec<LogItem>) {
+    for item in list {
+        // 时间按UTC显示
+        let secs = item.time / 1000;
+        let time = format!(
+            "{:02}:{:02}:{:02}.{:03}",
+            secs % 86400 / 3600,
+            secs % 3600 / 60,
+            secs % 60,
+            item.time % 1000
+        );
+        let level = match item.level.as_str() {
+            "ERROR" => style(format!("{:<5}", item.level)).red(),
+            "WARN" => style(format!("{:<5}", item.level)).yellow(),
+            "INFO" => style(format!("{:<5}", item.level)).green(),
+            _ => style(format!("{:<5}", item.level)).color256(102),
+        };
+        println!(
+            "{} {} {} {}",
+            style(time).color256(102),
+            level,
+            style(item.target).color256(102),
+            item.message
+        );
+    }
+}
+
+pub fn console_events(list: Vec<EventItem>) {
+    for item in list {
+        // 时间按UTC显示
+        let secs = item.time / 1000;
+        let time = format!(
+            "{:02}:{:02}:{:02}.{:03}",
+            secs % 86400 / 3600,
+            secs % 3600 / 60,
+            secs % 60,
+            item.time % 1000
+        );
+        let kind = match item.kind.as_str() {
+            "add" | "punch-success" => style(format!("{:<13}", item.kind)).green(),
+            "remove" | "punch-fail" | "punch-timeout" => style(format!("{:<13}", item.kind)).red(),
+            _ => style(format!("{:<13}", item.kind)).yellow(),
+        };
+        println!(
+            "{} {:<15} {} {}",
+            style(time).color256(102),
+            item.ip,
+            kind,
+            item.detail
+        );
+    }
+}
+
+/// 后台运行的异常退出记录，时间按UTC显示
+pub fn console_crash_list(list: Vec<CrashItem>) {
+    if list.is_empty() {
+        println!("No errors");
+        return;
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or(0);
+    for item in list {
+        let secs = item.time / 1000;
+        let ago = now.saturating_sub(secs);
+        let time = format!(
+            "{:02}:{:02}:{:02} ({}d {}h {}m ago)",
+            secs % 86400 / 3600,
+            secs % 3600 / 60,
+            secs % 60,
+            ago / 86400,
+            ago % 86400 / 3600,
+            ago % 3600 / 60
+        );
+        let restart = match item.restart_in {
+            Some(secs) => style(format!("restart in {}s", secs)).yellow(),
+            None => style("gave up".to_string()).red(),
+        };
+        println!(
+            "{} uptime {}s {} {}",
+            style(time).color256(102),
+            item.uptime,
+            restart,
+            style(item.reason.trim()).red()
+        );
+    }
+}
+
+/// 打洞记录，时间按UTC显示
+pub fn console_punch_log(list: Vec<PunchLogItem>) {
+    if list.is_empty() {
+        println!("No punch attempts");
+        return;
+    }
+    let hms = |time: u64| {
+        let secs = time / 1000;
+        format!(
+            "{:02}:{:02}:{:02}",
+            secs % 86400 / 3600,
+            secs % 3600 / 60,
+            secs % 60
+        )
+    };
+    let mut out_list = Vec::with_capacity(list.len() + 1);
+    out_list.push(vec![
+        ("Time".to_string(), Style::new()),
+        ("Duration".to_string(), Style::new()),
+        ("NAT".to_string(), Style::new()),
+        ("Strategies".to_string(), Style::new()),
+        ("Packets".to_string(), Style::new()),
+        ("Result".to_string(), Style::new()),
+    ]);
+    for item in list {
+        let duration = if item.end_time == 0 {
+            String::new()
+        } else {
+            format!("{}ms", item.end_time.saturating_sub(item.time))
+        };
+        let style = if item.outcome.starts_with("established") {
+            Style::new().green()
+        } else if item.outcome == "pending" {
+            Style::new().yellow()
+        } else {
+            Style::new().red()
+        };
+        out_list.push(vec![
+            (hms(item.time), Style::new()),
+            (duration, Style::new()),
+            (item.nat_type, Style::new()),
+            (item.strategies, Style::new()),
+            (item.packets.to_string(), Style::new()),
+            (item.outcome, style),
+        ]);
+    }
+    table::println_table(out_list);
+}
+
+/// 可达矩阵，行是上报的设备，列是目标设备
+pub fn console_matrix(mut list: Vec<MatrixItem>) {
+    if list.len() <= 1 && list.iter().all(|v| v.routes.is_empty()) {
+        println!("No other devices found");
+        return;
+    }
+    let local = list[0].virtual_ip.clone();
+    list.sort_by(|t1, t2| t1.virtual_ip.cmp(&t2.virtual_ip));
+    let mut ips: Vec<String> = list
+        .iter()
+        .flat_map(|v| {
+            std::iter::once(v.virtual_ip.clone())
+                .chain(v.routes.iter().map(|r| r.virtual_ip.clone()))
+        })
+        .collect();
+    ips.sort();
+    ips.dedup();
+    let mut out_list = Vec::with_capacity(list.len() + 1);
+    let mut head = vec![("From\\To".to_string(), Style::new())];
+    for ip in &ips {
+        head.push((ip.clone(), Style::new()));
+    }
+    out_list.push(head);
+    for item in &list {
+        let name = if item.virtual_ip == local {
+            format!("{}*", item.virtual_ip)
+        } else {
+            item.virtual_ip.clone()
+        };
+        let mut row = vec![(name, Style::new())];
+        for ip in &ips {
+            if ip == &item.virtual_ip {
+                row.push(("-".to_string(), Style::new()));
+                continue;
+            }
+            match item.routes.iter().find(|r| &r.virtual_ip == ip) {
+                Some(route) => {
+                    let rt = if route.rt < 0 {
+                        String::new()
+                    } else {
+                        format!(" {}", route.rt)
+                    };
+                    if route.p2p {
+                        row.push((format!("p2p{}", rt), Style::new().green()));
+                    } else {
+                        row.push((format!("relay{}", rt), Style::new().yellow()));
+                    }
+                }
+                None => row.push(("x".to_string(), Style::new().red())),
+            }
+        }
+        out_list.push(row);
+    }
+    table::println_table(out_list);
+    let missing: Vec<&String> = ips
+        .iter()
+        .filter(|ip| !list.iter().any(|v| &v.virtual_ip == *ip))
+        .collect();
+    if !missing.is_empty() {
+        println!(
+            "{}",
+            style(format!("No report from: {:?}", missing)).yellow()
+        );
+    }
+}
+
+pub fn console_effective_config(config: EffectiveConfig) {
+    if config.accept.is_empty() {
+        println!("Accept server config: {}", style("none").yellow());
+    } else {
+        println!("Accept server config: {}", style(config.accept).green());
+    }
+    let mut out_list = Vec::with_capacity(config.items.len() + 1);
+    out_list.push(vec![
+        ("Field".to_string(), Style::new()),
+        ("Value".to_string(), Style::new()),
+        ("Source".to_string(), Style::new()),
+    ]);
+    for item in config.items {
+        let style = match item.source.as_str() {
+            "server" => Style::new().green(),
+            "vetoed" => Style::new().red(),
+            _ => Style::new(),
+        };
+        out_list.push(vec![
+            (item.field, style.clone()),
+            (item.value, style.clone()),
+            (item.source, style),
+        ]);
+    }
+    table::println_table(out_list);
 }
 
-fn convert(num: u64) -> String {
+pub fn console_keys(list: Vec<KeyItem>) {
+    if list.is_empty() {
+        println!("No device keys, please enable --device-key");
+        return;
+    }
+    let mut out_list = Vec::with_capacity(list.len() + 1);
+    out_list.push(vec![
+        ("Name".to_string(), Style::new()),
+        ("Virtual Ip".to_string(), Style::new()),
+        ("Public Key".to_string(), Style::new()),
+    ]);
+    for item in list {
+        let style = if item.local {
+            Style::new().green()
+        } else {
+            Style::new()
+        };
+        out_list.push(vec![
+            (item.name, style.clone()),
+            (item.virtual_ip, style.clone()),
+            (item.public_key, style),
+        ]);
+    }
+    table::println_table(out_list);
+}
+
+pub fn console_relay(status: RelayStatus) {
+    let budget = if status.budget == 0 {
+        "unlimited".to_string()
+    } else {
+        format!("{} KB/s", status.budget)
+    };
+    println!("Relay budget: {}", style(budget).green());
+    println!("Relayed: {}", style(convert(status.relayed)).green());
+    println!("Terminal: {}", style(convert(status.terminal)).green());
+    println!("Dropped: {}", style(convert(status.dropped)).red());
+    if status.pairs.is_empty() {
+        return;
+    }
+    let mut out_list = Vec::with_capacity(status.pairs.len() + 1);
+    out_list.push(vec![
+        ("Source".to_string(), Style::new()),
+        ("Destination".to_string(), Style::new()),
+        ("Bytes".to_string(), Style::new()),
+        ("Packets".to_string(), Style::new()),
+        ("Dropped".to_string(), Style::new()),
+    ]);
+    for item in status.pairs {
+        let style = if item.dropped > 0 {
+            Style::new().yellow()
+        } else {
+            Style::new()
+        };
+        out_list.push(vec![
+            (item.source, style.clone()),
+            (item.destination, style.clone()),
+            (convert(item.bytes), style.clone()),
+            (item.packets.to_string(), style.clone()),
+            (convert(item.dropped), style),
+        ]);
+    }
+    table::println_table(out_list);
+}
+
+pub fn console_bench(list: Vec<vnt::cipher::CipherBench>) {
+    if list.is_empty() {
+        println!("Encryption not supported");
+        return;
+    }
+    let fastest = vnt::cipher::CipherBench::fastest_aead(&list);
+    let mut out_list = Vec::with_capacity(list.len() + 1);
+    out_list.push(vec![
+        ("Model".to_string(), Style::new()),
+        ("AEAD".to_string(), Style::new()),
+        ("Speed".to_string(), Style::new()),
+    ]);
+    for item in list {
+        // auto会选择的模式
+        let style = if Some(item.model) == fastest {
+            Style::new().green()
+        } else {
+            Style::new()
+        };
+        out_list.push(vec![
+            (item.model.to_string(), style.clone()),
+            (item.model.is_aead().to_string(), style.clone()),
+            (
+                format!("{:.1}MB/s", item.bytes_per_sec as f64 / 1024.0 / 1024.0),
+                style,
+            ),
+        ]);
+    }
+    table::println_table(out_list);
+}
+
+pub fn console_doctor(list: Vec<vnt::doctor::CheckResult>) {
+    use vnt::doctor::CheckStatus;
+    for item in list {
+        let status = match item.status {
+            CheckStatus::Pass => style(item.status).green(),
+            CheckStatus::Warn => style(item.status).yellow(),
+            CheckStatus::Fail => style(item.status).red(),
+            CheckStatus::Skip => style(item.status).dim(),
+        };
+        println!("[{}] {:<10} {}", status, item.name, item.detail);
+        if let Some(hint) = item.hint {
+            println!("       {:<10} {}", "", style(hint).yellow());
+        }
+    }
+}
+
+pub fn console_diag(diag: DiagItem) {
+    match diag.status.as_str() {
+        "ok" => {}
+        "refused" => {
+            println!(
+                "{}",
+                style(format!(
+                    "{} refused, please enable --allow-diag",
+                    diag.virtual_ip
+                ))
+                .red()
+            );
+            return;
+        }
+        _ => {
+            println!("{} {}", diag.virtual_ip, style(diag.status).red());
+            return;
+        }
+    }
+    println!("Virtual ip: {}", style(diag.virtual_ip).green());
+    println!("Version: {}", style(diag.version).green());
+    println!("NAT type: {}", style(diag.nat_type).green());
+    println!("Public addr: {}", style(diag.public_ips).green());
+    println!("Local ip: {}", style(diag.local_ip).green());
+    println!("IPv6: {}", style(diag.ipv6).green());
+    println!("Cipher model: {}", style(diag.cipher_model).green());
+    println!("Route to me: {}", style(diag.route).green());
+    println!("Rt: {}", style(diag.rt).green());
+}
+
+pub fn console_speed_test(item: SpeedTestItem) {
+    if item.status.starts_with("error") {
+        println!("{} {}", item.virtual_ip, style(item.status).red());
+        return;
+    }
+    println!(
+        "Speed test {} ({})",
+        style(&item.virtual_ip).green(),
+        item.mode
+    );
+    let speed = |name: &str, speed: Option<SpeedItem>| match speed {
+        Some(speed) => println!(
+            "{}: {} packets={} loss={:.2}% jitter={:.2}ms",
+            name,
+            style(format!("{:.2} Mbit/s", speed.goodput as f64 / 1_000_000.0)).green(),
+            speed.packets,
+            speed.loss_rate * 100.0,
+            speed.jitter_us as f64 / 1000.0
+        ),
+        None => println!("{}: {}", name, style("no report").red()),
+    };
+    speed("Upload", item.upload);
+    speed("Download", item.download);
+}
+
+/// 两次采样之间的速率
+pub fn console_stats(last: &Counters, now: &Counters, elapsed: std::time::Duration) {
+    let secs = elapsed.as_secs_f64().max(0.001);
+    let rate = |now: u64, last: u64| now.saturating_sub(last) as f64 / secs;
+    let bits = |now: u64, last: u64| format!("{:.2} Mbit/s", rate(now, last) * 8.0 / 1_000_000.0);
+    let p2p = now.p2p_tx.saturating_sub(last.p2p_tx) + now.p2p_rx.saturating_sub(last.p2p_rx);
+    let relay =
+        now.relay_tx.saturating_sub(last.relay_tx) + now.relay_rx.saturating_sub(last.relay_rx);
+    let p2p_percent = if p2p + relay == 0 {
+        0.0
+    } else {
+        p2p as f64 * 100.0 / (p2p + relay) as f64
+    };
+    let cipher_error = now.cipher_error.saturating_sub(last.cipher_error);
+    let tcp_dropped = now.tcp_dropped.saturating_sub(last.tcp_dropped);
+    let route_retry = now.route_retry.saturating_sub(last.route_retry);
+    let error_style = |num: u64| {
+        if num > 0 {
+            style(num).red()
+        } else {
+            style(num).green()
+        }
+    };
+    println!(
+        "up {} {:.0} pps | down {} {:.0} pps | p2p {:.1}% relay {} | cipher errors {} tcp dropped {} route retries {}",
+        style(bits(now.up, last.up)).green(),
+        rate(now.tx_packets, last.tx_packets),
+        style(bits(now.down, last.down)).green(),
+        rate(now.rx_packets, last.rx_packets),
+        p2p_percent,
+        convert(relay),
+        error_style(cipher_error),
+        error_style(tcp_dropped),
+        error_style(route_retry),
+    );
+}
+
+pub(crate) fn convert(num: u64) -> String {
     let gigabytes = num / (1024 * 1024 * 1024);
     let remaining_bytes = num % (1024 * 1024 * 1024);
     let megabytes = remaining_bytes / (1024 * 1024);
@@ -62,7 +530,10 @@ pub fn console_route_table(mut list: Vec<RouteItem>) {
     ]);
     for item in list {
         out_list.push(vec![
-            (item.destination, Style::new().green()),
+            (
+                route_destination(item.destination, item.name),
+                Style::new().green(),
+            ),
             (item.next_hop, Style::new().green()),
             (item.metric, Style::new().green()),
             (item.rt, Style::new().green()),
@@ -73,6 +544,15 @@ pub fn console_route_table(mut list: Vec<RouteItem>) {
     table::println_table(out_list)
 }
 
+/// 设置了别名时显示为ip(别名)
+pub fn route_destination(destination: String, name: String) -> String {
+    if name.is_empty() {
+        destination
+    } else {
+        format!("{}({})", destination, name)
+    }
+}
+
 pub fn console_device_list(mut list: Vec<DeviceItem>) {
     if list.is_empty() {
         println!("No other devices found");
@@ -89,6 +569,7 @@ pub fn console_device_list(mut list: Vec<DeviceItem>) {
         ("P2P/Relay".to_string(), Style::new()),
         ("Rt".to_string(), Style::new()),
     ]);
+    let tags: Vec<String> = list.iter().map(|v| v.tags.clone()).collect();
     for item in list {
         if &item.status == "Online" {
             if item.client_secret != item.current_client_secret
@@ -133,9 +614,25 @@ pub fn console_device_list(mut list: Vec<DeviceItem>) {
             ]);
         }
     }
+    append_tags(&mut out_list, tags);
     table::println_table(out_list)
 }
 
+/// 有设备设置了标签时在最后增加一列
+fn append_tags(out_list: &mut [Vec<(String, Style)>], tags: Vec<String>) {
+    if tags.iter().all(|v| v.is_empty()) {
+        return;
+    }
+    out_list[0].push(("Tags".to_string(), Style::new()));
+    for (row, tags) in out_list[1..].iter_mut().zip(tags) {
+        let style = row
+            .last()
+            .map(|(_, style)| style.clone())
+            .unwrap_or_default();
+        row.push((tags, style));
+    }
+}
+
 pub fn console_device_list_all(mut list: Vec<DeviceItem>) {
     if list.is_empty() {
         println!("No other devices found");
@@ -156,6 +653,7 @@ pub fn console_device_list_all(mut list: Vec<DeviceItem>) {
         ("Local Ip".to_string(), Style::new()),
         ("IPv6".to_string(), Style::new()),
     ]);
+    let tags: Vec<String> = list.iter().map(|v| v.tags.clone()).collect();
     for item in list {
         if &item.status == "Online" {
             if &item.nat_traversal_type == "p2p" {
@@ -197,5 +695,6 @@ pub fn console_device_list_all(mut list: Vec<DeviceItem>) {
             ]);
         }
     }
+    append_tags(&mut out_list, tags);
     table::println_table(out_list)
 }
@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+
+use console::style;
+
+use crate::command::entity::RouteItem;
+
+pub enum RouteChange {
+    Add(RouteItem),
+    Remove(RouteItem),
+    Metric(RouteItem, String),
+}
+
+/// 路由表快照，按目标和通道区分每一行
+#[derive(Default)]
+pub struct RouteSnapshot {
+    map: HashMap<(String, String), RouteItem>,
+}
+
+impl RouteSnapshot {
+    /// 更新快照，返回和上一次相比变化的行
+    pub fn update(&mut self, list: Vec<RouteItem>) -> Vec<RouteChange> {
+        let mut map = HashMap::with_capacity(list.len());
+        for item in list {
+            map.insert((item.destination.clone(), item.interface.clone()), item);
+        }
+        let mut changes = Vec::new();
+        for (key, item) in map.iter() {
+            match self.map.get(key) {
+                None => changes.push(RouteChange::Add(item.clone())),
+                Some(old) => {
+                    if old.metric != item.metric {
+                        changes.push(RouteChange::Metric(item.clone(), old.metric.clone()));
+                    }
+                }
+            }
+        }
+        for (key, item) in self.map.drain() {
+            if !map.contains_key(&key) {
+                changes.push(RouteChange::Remove(item));
+            }
+        }
+        changes.sort_by(|a, b| a.item().destination.cmp(&b.item().destination));
+        self.map = map;
+        changes
+    }
+}
+
+impl RouteChange {
+    fn item(&self) -> &RouteItem {
+        match self {
+            RouteChange::Add(item) => item,
+            RouteChange::Remove(item) => item,
+            RouteChange::Metric(item, _) => item,
+        }
+    }
+}
+
+pub fn console_route_changes(changes: Vec<RouteChange>) {
+    if changes.is_empty() {
+        return;
+    }
+    // 时间按UTC显示
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or_default();
+    let time = format!(
+        "{:02}:{:02}:{:02}",
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    );
+    for change in changes {
+        let (mark, metric) = match &change {
+            RouteChange::Add(item) => (style("+").green(), style(item.metric.clone()).green()),
+            RouteChange::Remove(item) => (style("-").red(), style(item.metric.clone()).red()),
+            RouteChange::Metric(item, old) => (
+                style("~").yellow(),
+                style(format!("{} -> {}", old, item.metric)).yellow(),
+            ),
+        };
+        let item = change.item();
+        println!(
+            "{} {} {:<15} next_hop={} metric={} rt={} {}",
+            style(&time).color256(102),
+            mark,
+            super::route_destination(item.destination.clone(), item.name.clone()),
+            item.next_hop,
+            metric,
+            item.rt,
+            item.interface
+        );
+    }
+}
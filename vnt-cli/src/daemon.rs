@@ -0,0 +1,252 @@
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::command::entity::CrashItem;
+
+const PID_FILE: &str = "vnt-cli.pid";
+const LOG_FILE: &str = "vnt-cli.out";
+/// 异常退出的记录
+const CRASH_FILE: &str = "vnt-cli.crash";
+/// 工作进程退出前写入的错误原因，由守护进程读取后删除
+const LAST_ERROR_FILE: &str = "vnt-cli.last-error";
+/// 守护进程的标记，工作进程的标记
+const SUPERVISOR_ENV: &str = "VNT_CLI_SUPERVISOR";
+const SUPERVISED_ENV: &str = "VNT_CLI_SUPERVISED";
+/// 保留的异常记录数
+const CRASH_CAPACITY: usize = 20;
+/// 重启等待时间，连续异常时翻倍
+const BACKOFF_MIN: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+/// 运行超过这个时间再退出，则等待时间重新计算
+const STABLE_TIME: Duration = Duration::from_secs(60);
+/// 时间窗口内重启次数超过上限则不再重启
+const RESTART_WINDOW: Duration = Duration::from_secs(600);
+const RESTART_LIMIT: usize = 5;
+/// 没有收到停止命令却停止了(如网卡读写出错)的退出码
+const EXIT_UNEXPECTED_STOP: i32 = 102;
+
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+pub fn pid_file() -> io::Result<PathBuf> {
+    Ok(crate::app_home()?.join(PID_FILE))
+}
+
+/// 以后台方式重新启动当前程序，参数中去除--daemon，标准输出和错误输出重定向到app_home()下的文件。
+/// 后台进程只负责守护，由它再启动实际运行的工作进程
+pub fn start(args: &[String]) -> io::Result<u32> {
+    let exe = std::env::current_exe()?;
+    let home = crate::app_home()?;
+    let out = File::create(home.join(LOG_FILE))?;
+    let err = out.try_clone()?;
+    let args: Vec<&String> = args
+        .iter()
+        .skip(1)
+        .filter(|v| v.as_str() != "--daemon")
+        .collect();
+    let mut command = Command::new(exe);
+    command
+        .args(args)
+        .env(SUPERVISOR_ENV, "1")
+        .stdin(Stdio::null())
+        .stdout(Stdio::from(out))
+        .stderr(Stdio::from(err));
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        // 脱离当前终端的进程组，关闭终端时不会被一起结束
+        command.process_group(0);
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        const DETACHED_PROCESS: u32 = 0x00000008;
+        const CREATE_NEW_PROCESS_GROUP: u32 = 0x00000200;
+        command.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP);
+    }
+    let child = command.spawn()?;
+    let pid = child.id();
+    std::fs::write(home.join(PID_FILE), pid.to_string())?;
+    Ok(pid)
+}
+
+/// 进程退出时删除pid文件，只删除属于自己的
+pub fn remove_pid_file() {
+    let path = match pid_file() {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    if let Ok(pid) = std::fs::read_to_string(&path) {
+        if pid.trim() == std::process::id().to_string() {
+            if let Err(e) = std::fs::remove_file(&path) {
+                log::warn!("删除pid文件失败:{:?}", e);
+            }
+        }
+    }
+}
+
+/// 当前进程是否为后台守护进程
+pub fn is_supervisor() -> bool {
+    std::env::var_os(SUPERVISOR_ENV).is_some()
+}
+
+/// 守护工作进程，异常退出时等待一段时间后重启，正常停止(退出码0)时一起退出
+pub fn supervise(args: &[String]) {
+    let mut backoff = BACKOFF_MIN;
+    let mut restarts: Vec<Instant> = Vec::new();
+    loop {
+        let start = Instant::now();
+        let status = match run_worker(args) {
+            Ok(status) => status,
+            Err(e) => {
+                log::error!("启动工作进程失败:{:?}", e);
+                break;
+            }
+        };
+        if status.success() {
+            break;
+        }
+        let uptime = start.elapsed();
+        if uptime >= STABLE_TIME {
+            backoff = BACKOFF_MIN;
+        }
+        restarts.retain(|time| time.elapsed() < RESTART_WINDOW);
+        let give_up = restarts.len() >= RESTART_LIMIT;
+        let reason = match take_last_error() {
+            Some(reason) => reason,
+            None => exit_reason(&status),
+        };
+        log::warn!("工作进程异常退出:{},{:?}", reason, status);
+        let item = CrashItem {
+            time: now_millis(),
+            uptime: uptime.as_secs(),
+            code: status.code(),
+            reason,
+            restart_in: if give_up {
+                None
+            } else {
+                Some(backoff.as_secs())
+            },
+        };
+        if let Err(e) = save_crash(item) {
+            log::warn!("保存异常记录失败:{:?}", e);
+        }
+        if give_up {
+            log::error!(
+                "{}秒内重启超过{}次,不再重启",
+                RESTART_WINDOW.as_secs(),
+                RESTART_LIMIT
+            );
+            break;
+        }
+        // 等待期间删除了pid文件(--stop)则不再重启
+        let deadline = Instant::now() + backoff;
+        while Instant::now() < deadline {
+            if !pid_file().map(|path| path.exists()).unwrap_or(false) {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(200));
+        }
+        restarts.push(Instant::now());
+        backoff = (backoff * 2).min(BACKOFF_MAX);
+    }
+    remove_pid_file();
+}
+
+fn run_worker(args: &[String]) -> io::Result<ExitStatus> {
+    let exe = std::env::current_exe()?;
+    let _ = std::fs::remove_file(crate::app_home()?.join(LAST_ERROR_FILE));
+    Command::new(exe)
+        .args(args.iter().skip(1))
+        .env_remove(SUPERVISOR_ENV)
+        .env(SUPERVISED_ENV, "1")
+        .status()
+}
+
+fn exit_reason(status: &ExitStatus) -> String {
+    match status.code() {
+        Some(code) => format!("exit code {}", code),
+        None => "terminated by signal".to_string(),
+    }
+}
+
+/// 通过命令停止，之后的退出不需要重启
+pub fn request_stop() {
+    STOP_REQUESTED.store(true, Ordering::Release);
+}
+
+/// 停止时的退出码，守护进程运行时意外停止返回非0，以便重启
+pub fn stop_exit_code() -> i32 {
+    if STOP_REQUESTED.load(Ordering::Acquire) || std::env::var_os(SUPERVISED_ENV).is_none() {
+        return 0;
+    }
+    save_last_error("stopped unexpectedly");
+    EXIT_UNEXPECTED_STOP
+}
+
+/// 守护进程运行时，记录工作进程退出的原因
+pub fn save_last_error(reason: &str) {
+    if std::env::var_os(SUPERVISED_ENV).is_none() {
+        return;
+    }
+    match crate::app_home() {
+        Ok(home) => {
+            if let Err(e) = std::fs::write(home.join(LAST_ERROR_FILE), reason) {
+                log::warn!("{:?}", e);
+            }
+        }
+        Err(e) => log::warn!("{:?}", e),
+    }
+}
+
+fn take_last_error() -> Option<String> {
+    let path = crate::app_home().ok()?.join(LAST_ERROR_FILE);
+    let reason = std::fs::read_to_string(&path).ok()?;
+    let _ = std::fs::remove_file(&path);
+    // 两个线程同时退出时可能只截断了文件还没写入
+    if reason.trim().is_empty() {
+        return None;
+    }
+    Some(reason)
+}
+
+/// 工作进程正在等待重启时，停止守护进程
+pub fn cancel() -> io::Result<bool> {
+    let path = pid_file()?;
+    if !path.exists() {
+        return Ok(false);
+    }
+    std::fs::remove_file(&path)?;
+    Ok(true)
+}
+
+/// 最近的异常退出记录，从旧到新
+pub fn crash_list() -> io::Result<Vec<CrashItem>> {
+    let path = crate::app_home()?.join(CRASH_FILE);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = std::fs::read_to_string(path)?;
+    serde_yaml::from_str(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn save_crash(item: CrashItem) -> io::Result<()> {
+    let mut list = crash_list().unwrap_or_default();
+    list.push(item);
+    if list.len() > CRASH_CAPACITY {
+        list.drain(..list.len() - CRASH_CAPACITY);
+    }
+    let content =
+        serde_yaml::to_string(&list).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    std::fs::write(crate::app_home()?.join(CRASH_FILE), content)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_millis() as u64)
+        .unwrap_or(0)
+}
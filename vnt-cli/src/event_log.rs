@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use vnt::channel::event::{RouteEventKind, StateEvent};
+use vnt::core::Vnt;
+use vnt::ErrorInfo;
+
+use crate::web::json::JsonWriter;
+
+/// 单个文件的大小上限，超过后轮转
+const MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+/// 保留的历史文件数，分别为xxx.1 ~ xxx.5
+const MAX_BACKUPS: usize = 5;
+
+/// 以每行一个json对象(NDJSON)的格式记录连接、路由、打洞和错误事件，
+/// 和log4rs的日志分开，方便vector、filebeat等直接采集
+#[derive(Clone)]
+pub struct EventLog {
+    inner: Arc<Mutex<RotateFile>>,
+}
+
+struct RotateFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl RotateFile {
+    fn open(path: PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, file, size })
+    }
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > MAX_FILE_SIZE {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+    fn rotate(&mut self) -> io::Result<()> {
+        for i in (1..MAX_BACKUPS).rev() {
+            let from = backup_path(&self.path, i);
+            if from.exists() {
+                std::fs::rename(from, backup_path(&self.path, i + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, backup_path(&self.path, 1))?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn backup_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+impl EventLog {
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        Ok(Self {
+            inner: Arc::new(Mutex::new(RotateFile::open(path)?)),
+        })
+    }
+    /// 写入一个事件，公共字段为毫秒时间戳和事件类型
+    fn write<F: FnOnce(&mut JsonWriter)>(&self, event: &str, f: F) {
+        let mut json = JsonWriter::default();
+        json.begin_object();
+        json.field_u64("time", now_millis());
+        json.field_str("event", event);
+        f(&mut json);
+        json.end_object();
+        let mut line = json.finish();
+        line.push('\n');
+        let mut guard = match self.inner.lock() {
+            Ok(guard) => guard,
+            Err(e) => e.into_inner(),
+        };
+        if let Err(e) = guard.write_line(&line) {
+            log::warn!("event log {:?}", e);
+        }
+    }
+    pub fn error(&self, info: &ErrorInfo) {
+        self.write("error", |json| {
+            json.field_str("code", &format!("{:?}", info.code));
+            json.field_bool("fatal", info.code.is_fatal());
+            json.field_str("message", &info.to_string());
+        });
+    }
+    /// 订阅vnt的状态变化，在后台线程中写入
+    pub fn start(&self, vnt: &Vnt) {
+        let receiver = vnt.subscribe();
+        let event_log = self.clone();
+        let rs = std::thread::Builder::new()
+            .name("eventLog".into())
+            .spawn(move || {
+                // 对端虚拟ip -> 名称，用于生成上下线事件
+                let mut online: HashMap<Ipv4Addr, String> = HashMap::new();
+                while let Ok(event) = receiver.recv() {
+                    event_log.state_event(event, &mut online);
+                }
+            });
+        if let Err(e) = rs {
+            log::warn!("event log {:?}", e);
+        }
+    }
+    fn state_event(&self, event: StateEvent, online: &mut HashMap<Ipv4Addr, String>) {
+        match event {
+            StateEvent::Status(status) => {
+                self.write("connection", |json| {
+                    json.field_str("status", &format!("{:?}", status));
+                });
+            }
+            StateEvent::Route(event) => {
+                let name = match event.kind {
+                    RouteEventKind::PunchRequest
+                    | RouteEventKind::Punch
+                    | RouteEventKind::PunchFail
+                    | RouteEventKind::PunchSuccess
+                    | RouteEventKind::PunchTimeout => "punch",
+                    _ => "route",
+                };
+                self.write(name, |json| {
+                    json.field_str("ip", &event.ip.to_string());
+                    json.field_str("kind", &event.kind.to_string());
+                    json.field_str("detail", &event.detail);
+                });
+            }
+            StateEvent::PeerList { peers, .. } => {
+                let current: HashMap<Ipv4Addr, String> = peers
+                    .into_iter()
+                    .filter(|peer| peer.status.is_online())
+                    .map(|peer| (peer.virtual_ip, peer.name))
+                    .collect();
+                for (ip, name) in &current {
+                    if !online.contains_key(ip) {
+                        self.peer_event(*ip, name, true);
+                    }
+                }
+                for (ip, name) in online.iter() {
+                    if !current.contains_key(ip) {
+                        self.peer_event(*ip, name, false);
+                    }
+                }
+                *online = current;
+            }
+            StateEvent::Presence(event) => {
+                self.write("presence", |json| {
+                    json.field_str("ip", &event.ip.to_string());
+                    json.field_str("name", &event.name);
+                    json.field_str("kind", &event.kind.to_string());
+                });
+            }
+            StateEvent::Stats { .. } => {}
+        }
+    }
+    fn peer_event(&self, ip: Ipv4Addr, name: &str, online: bool) {
+        self.write("peer", |json| {
+            json.field_str("ip", &ip.to_string());
+            json.field_str("name", name);
+            json.field_bool("online", online);
+        });
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_millis() as u64)
+        .unwrap_or_default()
+}
@@ -0,0 +1,93 @@
+use std::io;
+use std::io::Write;
+
+const FINGERPRINT_FILE: &str = "server-fingerprint";
+
+/// 服务端指纹的首次使用信任，首次连接时保存，之后指纹变化则拒绝连接
+#[derive(Clone)]
+pub struct FingerprintPin {
+    server: String,
+    accept_new: bool,
+}
+
+impl FingerprintPin {
+    pub fn new(server: String, accept_new: bool) -> Self {
+        Self { server, accept_new }
+    }
+    /// 检查服务端指纹，返回是否信任。
+    /// 指纹文件存在但无法读取时返回错误，不能当作首次连接，除非指定了接受新指纹
+    pub fn check(&self, finger: &str) -> io::Result<bool> {
+        let path = crate::app_home()?.join(FINGERPRINT_FILE);
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                if self.accept_new {
+                    // 读不到其他服务端的指纹，不覆盖文件
+                    log::warn!("读取指纹文件失败,接受服务端指纹 {:?} {:?}", path, e);
+                    return Ok(true);
+                }
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("无法读取指纹文件{:?}: {}", path, e),
+                ));
+            }
+        };
+        let mut entries = parse(&content);
+        match entries
+            .iter_mut()
+            .find(|(server, _)| server == &self.server)
+        {
+            Some((_, saved)) => {
+                if saved == finger {
+                    return Ok(true);
+                }
+                if !self.accept_new {
+                    return Ok(false);
+                }
+                log::warn!(
+                    "接受新的服务端指纹 server={} old={} new={}",
+                    self.server,
+                    saved,
+                    finger
+                );
+                *saved = finger.to_string();
+            }
+            None => {
+                log::info!(
+                    "首次连接，保存服务端指纹 server={} finger={}",
+                    self.server,
+                    finger
+                );
+                entries.push((self.server.clone(), finger.to_string()));
+            }
+        }
+        // 指纹已经校验通过，保存失败只影响下次校验
+        if let Err(e) = save(&path, &entries) {
+            log::warn!("保存指纹文件失败 {:?} {:?}", path, e);
+        }
+        Ok(true)
+    }
+    pub fn server(&self) -> &str {
+        &self.server
+    }
+}
+
+fn save(path: &std::path::Path, entries: &[(String, String)]) -> io::Result<()> {
+    let mut file = std::fs::File::create(path)?;
+    for (server, finger) in entries {
+        writeln!(file, "{} {}", server, finger)?;
+    }
+    file.sync_all()
+}
+
+/// 每行一个服务端：地址 指纹
+fn parse(content: &str) -> Vec<(String, String)> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let (server, finger) = line.trim().rsplit_once(' ')?;
+            Some((server.trim().to_string(), finger.to_string()))
+        })
+        .collect()
+}
@@ -0,0 +1,354 @@
+#[cfg(feature = "history")]
+use std::collections::HashSet;
+use std::io;
+#[cfg(feature = "history")]
+use std::net::Ipv4Addr;
+#[cfg(feature = "history")]
+use std::time::Duration;
+
+#[cfg(feature = "history")]
+use console::style;
+#[cfg(feature = "history")]
+use rusqlite::{params, Connection};
+#[cfg(feature = "history")]
+use vnt::channel::event::RouteEventKind;
+use vnt::core::Vnt;
+
+#[cfg(feature = "history")]
+use crate::console_out::convert;
+
+#[cfg(feature = "history")]
+const DB_FILE: &str = "history.db";
+/// 采样间隔
+#[cfg(feature = "history")]
+const INTERVAL: Duration = Duration::from_secs(30);
+/// 只保留90天内的记录
+#[cfg(feature = "history")]
+const RETENTION_SECS: u64 = 90 * 86400;
+
+/// 后台记录设备上下线、路由变化和每小时流量，保存在app_home()下的history.db
+#[cfg(feature = "history")]
+pub fn start(vnt: Vnt) {
+    let rs = std::thread::Builder::new()
+        .name("history".into())
+        .spawn(move || {
+            if let Err(e) = record(&vnt) {
+                log::warn!("history {:?}", e);
+            }
+        });
+    if let Err(e) = rs {
+        log::warn!("history {:?}", e);
+    }
+}
+
+#[cfg(not(feature = "history"))]
+pub fn start(_vnt: Vnt) {}
+
+/// 程序停止时结束所有在线记录
+#[cfg(feature = "history")]
+pub fn stop() {
+    let rs = open().and_then(|conn| {
+        conn.execute(
+            "UPDATE peer_session SET offline=?1 WHERE offline IS NULL",
+            [now_secs()],
+        )
+        .map_err(to_io)
+    });
+    if let Err(e) = rs {
+        log::warn!("history {:?}", e);
+    }
+}
+
+#[cfg(not(feature = "history"))]
+pub fn stop() {}
+
+/// 查看指定设备的上下线和路由变化记录，参数为traffic时查看每小时流量
+#[cfg(feature = "history")]
+pub fn print(arg: &str) -> io::Result<()> {
+    let conn = open()?;
+    if arg == "traffic" {
+        return print_traffic(&conn);
+    }
+    let ip = arg
+        .parse::<Ipv4Addr>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{} {}", arg, e)))?;
+    print_peer(&conn, ip)
+}
+
+#[cfg(not(feature = "history"))]
+pub fn print(_arg: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "history requires feature history",
+    ))
+}
+
+#[cfg(feature = "history")]
+fn open() -> io::Result<Connection> {
+    let path = crate::app_home()?.join(DB_FILE);
+    let conn = Connection::open(path).map_err(to_io)?;
+    conn.busy_timeout(Duration::from_secs(3)).map_err(to_io)?;
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS peer_session(
+            ip TEXT NOT NULL,
+            name TEXT NOT NULL,
+            online INTEGER NOT NULL,
+            offline INTEGER
+        );
+        CREATE INDEX IF NOT EXISTS peer_session_ip ON peer_session(ip, online);
+        CREATE TABLE IF NOT EXISTS route_event(
+            time INTEGER NOT NULL,
+            ip TEXT NOT NULL,
+            kind TEXT NOT NULL,
+            detail TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS route_event_ip ON route_event(ip, time);
+        CREATE TABLE IF NOT EXISTS traffic(
+            hour INTEGER PRIMARY KEY,
+            up INTEGER NOT NULL,
+            down INTEGER NOT NULL
+        );",
+    )
+    .map_err(to_io)?;
+    Ok(conn)
+}
+
+#[cfg(feature = "history")]
+fn record(vnt: &Vnt) -> io::Result<()> {
+    let conn = open()?;
+    let now = now_secs();
+    // 上次异常退出时没有结束的记录，以本次启动时间结束
+    conn.execute(
+        "UPDATE peer_session SET offline=?1 WHERE offline IS NULL",
+        [now],
+    )
+    .map_err(to_io)?;
+    let expire = now.saturating_sub(RETENTION_SECS);
+    conn.execute("DELETE FROM peer_session WHERE online<?1", [expire])
+        .map_err(to_io)?;
+    conn.execute("DELETE FROM route_event WHERE time<?1", [expire])
+        .map_err(to_io)?;
+    conn.execute("DELETE FROM traffic WHERE hour<?1", [expire])
+        .map_err(to_io)?;
+
+    let mut online: HashSet<Ipv4Addr> = HashSet::new();
+    let mut seq = 0;
+    let mut up = 0;
+    let mut down = 0;
+    loop {
+        let now = now_secs();
+        let mut current = HashSet::new();
+        for peer in vnt.device_list() {
+            if !peer.status.is_online() {
+                continue;
+            }
+            current.insert(peer.virtual_ip);
+            if !online.contains(&peer.virtual_ip) {
+                conn.execute(
+                    "INSERT INTO peer_session(ip, name, online) VALUES (?1, ?2, ?3)",
+                    params![peer.virtual_ip.to_string(), peer.name, now],
+                )
+                .map_err(to_io)?;
+            }
+        }
+        for ip in online.difference(&current) {
+            conn.execute(
+                "UPDATE peer_session SET offline=?1 WHERE ip=?2 AND offline IS NULL",
+                params![now, ip.to_string()],
+            )
+            .map_err(to_io)?;
+        }
+        online = current;
+
+        loop {
+            let events = vnt.route_events(seq, 100);
+            let last = match events.last() {
+                Some(last) => last.seq,
+                None => break,
+            };
+            seq = last;
+            for event in events {
+                // 打洞过程的事件太多，只记录路由变化
+                match event.kind {
+                    RouteEventKind::PunchRequest
+                    | RouteEventKind::Punch
+                    | RouteEventKind::PunchFail
+                    | RouteEventKind::PunchSuccess
+                    | RouteEventKind::PunchTimeout => continue,
+                    _ => {}
+                }
+                conn.execute(
+                    "INSERT INTO route_event(time, ip, kind, detail) VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        event.time / 1000,
+                        event.ip.to_string(),
+                        event.kind.to_string(),
+                        event.detail
+                    ],
+                )
+                .map_err(to_io)?;
+            }
+        }
+
+        let (cur_up, cur_down) = (vnt.up_stream(), vnt.down_stream());
+        let (up_delta, down_delta) = (cur_up.saturating_sub(up), cur_down.saturating_sub(down));
+        (up, down) = (cur_up, cur_down);
+        if up_delta > 0 || down_delta > 0 {
+            conn.execute(
+                "INSERT INTO traffic(hour, up, down) VALUES (?1, ?2, ?3)
+                ON CONFLICT(hour) DO UPDATE SET up=up+excluded.up, down=down+excluded.down",
+                params![now / 3600 * 3600, up_delta, down_delta],
+            )
+            .map_err(to_io)?;
+        }
+        std::thread::sleep(INTERVAL);
+    }
+}
+
+#[cfg(feature = "history")]
+fn print_peer(conn: &Connection, ip: Ipv4Addr) -> io::Result<()> {
+    let ip = ip.to_string();
+    let mut stmt = conn
+        .prepare(
+            "SELECT name, online, offline FROM peer_session WHERE ip=?1 ORDER BY online DESC LIMIT 50",
+        )
+        .map_err(to_io)?;
+    let rows = stmt
+        .query_map([&ip], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, u64>(1)?,
+                row.get::<_, Option<u64>>(2)?,
+            ))
+        })
+        .map_err(to_io)?;
+    println!("{}", style(format!("{} sessions (UTC)", ip)).green());
+    let mut empty = true;
+    for row in rows {
+        let (name, online, offline) = row.map_err(to_io)?;
+        empty = false;
+        let (end, secs) = match offline {
+            Some(offline) => (format_time(offline), offline.saturating_sub(online)),
+            None => (
+                style("online").green().to_string(),
+                now_secs().saturating_sub(online),
+            ),
+        };
+        println!(
+            "  {} ~ {:<19} {:>10} {}",
+            format_time(online),
+            end,
+            format_duration(secs),
+            name
+        );
+    }
+    if empty {
+        println!("  No records");
+    }
+    let mut stmt = conn
+        .prepare(
+            "SELECT time, kind, detail FROM route_event WHERE ip=?1 ORDER BY time DESC LIMIT 50",
+        )
+        .map_err(to_io)?;
+    let rows = stmt
+        .query_map([&ip], |row| {
+            Ok((
+                row.get::<_, u64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })
+        .map_err(to_io)?;
+    println!("{}", style(format!("{} route changes (UTC)", ip)).green());
+    let mut empty = true;
+    for row in rows {
+        let (time, kind, detail) = row.map_err(to_io)?;
+        empty = false;
+        println!("  {} {:<8} {}", format_time(time), kind, detail);
+    }
+    if empty {
+        println!("  No records");
+    }
+    Ok(())
+}
+
+#[cfg(feature = "history")]
+fn print_traffic(conn: &Connection) -> io::Result<()> {
+    let mut stmt = conn
+        .prepare("SELECT hour, up, down FROM traffic ORDER BY hour DESC LIMIT 48")
+        .map_err(to_io)?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, u64>(0)?,
+                row.get::<_, u64>(1)?,
+                row.get::<_, u64>(2)?,
+            ))
+        })
+        .map_err(to_io)?;
+    println!("{}", style("hourly traffic (UTC)").green());
+    let mut empty = true;
+    for row in rows {
+        let (hour, up, down) = row.map_err(to_io)?;
+        empty = false;
+        println!(
+            "  {} up={:<24} down={}",
+            format_time(hour),
+            convert(up),
+            convert(down)
+        );
+    }
+    if empty {
+        println!("  No records");
+    }
+    Ok(())
+}
+
+#[cfg(feature = "history")]
+fn to_io(e: rusqlite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("sqlite {}", e))
+}
+
+#[cfg(feature = "history")]
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or_default()
+}
+
+/// UTC时间，yyyy-mm-dd hh:mm:ss
+#[cfg(feature = "history")]
+fn format_time(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    // 按公历从1970-01-01推算日期
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(feature = "history")]
+fn format_duration(secs: u64) -> String {
+    if secs >= 86400 {
+        format!("{}d{}h", secs / 86400, secs % 86400 / 3600)
+    } else if secs >= 3600 {
+        format!("{}h{}m", secs / 3600, secs % 3600 / 60)
+    } else {
+        format!("{}m{}s", secs / 60, secs % 60)
+    }
+}
@@ -0,0 +1,92 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::net::Ipv4Addr;
+
+const TAG_FILE: &str = "peer_tags.yaml";
+/// 别名使用的标签
+const NAME_TAG: &str = "name";
+
+/// 本地给其他设备设置的别名和标签，保存在app_home()下的peer_tags.yaml，
+/// 服务端分配的名称往往没有意义，列表中优先显示别名
+#[derive(Default)]
+pub struct PeerTags {
+    map: BTreeMap<Ipv4Addr, BTreeMap<String, String>>,
+}
+
+impl PeerTags {
+    /// 每次查看列表时重新读取，修改后不需要重启
+    pub fn load() -> Self {
+        match read() {
+            Ok(map) => Self { map },
+            Err(e) => {
+                log::warn!("{} {:?}", TAG_FILE, e);
+                Self::default()
+            }
+        }
+    }
+    pub fn name(&self, ip: &Ipv4Addr) -> Option<String> {
+        self.map.get(ip).and_then(|v| v.get(NAME_TAG)).cloned()
+    }
+    /// 别名以外的标签，格式为k=v,k=v
+    pub fn tags(&self, ip: &Ipv4Addr) -> String {
+        match self.map.get(ip) {
+            Some(tags) => tags
+                .iter()
+                .filter(|(k, _)| k.as_str() != NAME_TAG)
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect::<Vec<_>>()
+                .join(","),
+            None => String::new(),
+        }
+    }
+}
+
+fn read() -> io::Result<BTreeMap<Ipv4Addr, BTreeMap<String, String>>> {
+    let path = crate::app_home()?.join(TAG_FILE);
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let text = std::fs::read_to_string(path)?;
+    if text.trim().is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    serde_yaml::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// 修改设备的标签，参数为k=v，v为空时删除该标签，没有参数时查看标签
+pub fn set(ip: &str, args: &[String]) -> io::Result<()> {
+    let ip = ip
+        .parse::<Ipv4Addr>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{} {}", ip, e)))?;
+    let mut map = read()?;
+    if args.is_empty() {
+        if let Some(tags) = map.get(&ip) {
+            for (k, v) in tags {
+                println!("{}={}", k, v);
+            }
+        }
+        return Ok(());
+    }
+    let tags = map.entry(ip).or_default();
+    for arg in args {
+        let (k, v) = match arg.split_once('=') {
+            Some((k, v)) if !k.trim().is_empty() => (k.trim(), v.trim()),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("'{}' not k=v", arg),
+                ))
+            }
+        };
+        if v.is_empty() {
+            tags.remove(k);
+        } else {
+            tags.insert(k.to_string(), v.to_string());
+        }
+    }
+    if tags.is_empty() {
+        map.remove(&ip);
+    }
+    let text = serde_yaml::to_string(&map).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    std::fs::write(crate::app_home()?.join(TAG_FILE), text)
+}
@@ -0,0 +1,80 @@
+/// 简单的json拼接，状态页只需要输出字符串、数字和布尔值
+#[derive(Default)]
+pub struct JsonWriter {
+    out: String,
+    // 当前层是否需要在下一个元素前加逗号
+    stack: Vec<bool>,
+}
+
+impl JsonWriter {
+    fn separator(&mut self) {
+        if let Some(need_comma) = self.stack.last_mut() {
+            if *need_comma {
+                self.out.push(',');
+            }
+            *need_comma = true;
+        }
+    }
+    pub fn begin_object(&mut self) {
+        self.separator();
+        self.out.push('{');
+        self.stack.push(false);
+    }
+    pub fn end_object(&mut self) {
+        self.stack.pop();
+        self.out.push('}');
+    }
+    pub fn begin_array(&mut self) {
+        self.separator();
+        self.out.push('[');
+        self.stack.push(false);
+    }
+    pub fn end_array(&mut self) {
+        self.stack.pop();
+        self.out.push(']');
+    }
+    /// 写入对象的键，紧跟的值不再加逗号
+    pub fn key(&mut self, key: &str) {
+        self.separator();
+        self.push_str(key);
+        self.out.push(':');
+        if let Some(need_comma) = self.stack.last_mut() {
+            *need_comma = false;
+        }
+    }
+    pub fn field_str(&mut self, key: &str, value: &str) {
+        self.key(key);
+        self.separator();
+        self.push_str(value);
+    }
+    pub fn field_u64(&mut self, key: &str, value: u64) {
+        self.key(key);
+        self.separator();
+        self.out.push_str(&value.to_string());
+    }
+    pub fn field_bool(&mut self, key: &str, value: bool) {
+        self.key(key);
+        self.separator();
+        self.out.push_str(if value { "true" } else { "false" });
+    }
+    pub fn finish(self) -> String {
+        self.out
+    }
+    fn push_str(&mut self, value: &str) {
+        self.out.push('"');
+        for c in value.chars() {
+            match c {
+                '"' => self.out.push_str("\\\""),
+                '\\' => self.out.push_str("\\\\"),
+                '\n' => self.out.push_str("\\n"),
+                '\r' => self.out.push_str("\\r"),
+                '\t' => self.out.push_str("\\t"),
+                // 避免在html中被当作标签结束
+                '<' => self.out.push_str("\\u003c"),
+                c if (c as u32) < 0x20 => self.out.push_str(&format!("\\u{:04x}", c as u32)),
+                c => self.out.push(c),
+            }
+        }
+        self.out.push('"');
+    }
+}
@@ -0,0 +1,205 @@
+use std::io;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+use vnt::core::Vnt;
+
+use crate::command::entity::{DeviceItem, Info, RouteItem, Status};
+
+pub mod json;
+
+use json::JsonWriter;
+
+const INDEX_HTML: &str = include_str!("index.html");
+/// 请求头的最大长度
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// 本地状态页，只读，展示设备、路由、nat信息和流量曲线
+pub fn start(addr: SocketAddr, vnt: Vnt, start_time: Instant) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    if !addr.ip().is_loopback() {
+        log::warn!("状态页监听在非本地地址{:?}，没有访问控制", addr);
+    }
+    log::info!("启动状态页:http://{}", listener.local_addr()?);
+    // 请求很轻，顺序处理即可
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("{:?}", e);
+                continue;
+            }
+        };
+        if let Err(e) = handle(stream, &vnt, start_time) {
+            log::debug!("状态页 {:?}", e);
+        }
+    }
+    Ok(())
+}
+
+fn handle(mut stream: TcpStream, vnt: &Vnt, start_time: Instant) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|v| v == b"\r\n\r\n") {
+        let len = stream.read(&mut chunk)?;
+        if len == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..len]);
+        if buf.len() > MAX_REQUEST {
+            return response(
+                &mut stream,
+                "431 Request Header Fields Too Large",
+                "text/plain",
+                b"",
+            );
+        }
+    }
+    let request = String::from_utf8_lossy(&buf);
+    let mut lines = request.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+    // 防止dns重绑定，只接受ip或localhost作为Host
+    let host_ok = lines
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            if name.trim().eq_ignore_ascii_case("host") {
+                Some(allowed_host(value.trim()))
+            } else {
+                None
+            }
+        })
+        .unwrap_or(true);
+    if !host_ok {
+        return response(&mut stream, "403 Forbidden", "text/plain", b"forbidden");
+    }
+    if method != "GET" {
+        return response(&mut stream, "405 Method Not Allowed", "text/plain", b"");
+    }
+    match path.split('?').next().unwrap_or_default() {
+        "/" | "/index.html" => response(
+            &mut stream,
+            "200 OK",
+            "text/html; charset=utf-8",
+            INDEX_HTML.as_bytes(),
+        ),
+        "/api/status" => {
+            let body = status_json(vnt, start_time);
+            response(&mut stream, "200 OK", "application/json", body.as_bytes())
+        }
+        _ => response(&mut stream, "404 Not Found", "text/plain", b"not found"),
+    }
+}
+
+fn allowed_host(host: &str) -> bool {
+    let name = match host.rsplit_once(':') {
+        // ipv6地址带方括号
+        Some((name, port)) if !name.is_empty() && port.bytes().all(|v| v.is_ascii_digit()) => name,
+        _ => host,
+    };
+    let name = name.trim_start_matches('[').trim_end_matches(']');
+    name.eq_ignore_ascii_case("localhost") || name.parse::<std::net::IpAddr>().is_ok()
+}
+
+fn response(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nX-Content-Type-Options: nosniff\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+fn status_json(vnt: &Vnt, start_time: Instant) -> String {
+    let info = crate::command::command_info(vnt);
+    let status = crate::command::command_status(vnt, start_time);
+    let peers = crate::command::command_list(vnt);
+    let routes = crate::command::command_route(vnt);
+    let mut json = JsonWriter::default();
+    json.begin_object();
+    json.key("status");
+    write_status(&mut json, &status);
+    json.key("info");
+    write_info(&mut json, &info);
+    json.key("peers");
+    json.begin_array();
+    for peer in &peers {
+        write_peer(&mut json, peer);
+    }
+    json.end_array();
+    json.key("routes");
+    json.begin_array();
+    for route in &routes {
+        write_route(&mut json, route);
+    }
+    json.end_array();
+    json.end_object();
+    json.finish()
+}
+
+fn write_status(json: &mut JsonWriter, status: &Status) {
+    json.begin_object();
+    json.field_str("version", &status.version);
+    json.field_u64("pid", status.pid as u64);
+    json.field_u64("uptime", status.uptime);
+    json.field_str("connect_status", &status.connect_status);
+    json.end_object();
+}
+
+fn write_info(json: &mut JsonWriter, info: &Info) {
+    json.begin_object();
+    json.field_str("name", &info.name);
+    json.field_str("virtual_ip", &info.virtual_ip);
+    json.field_str("virtual_gateway", &info.virtual_gateway);
+    json.field_str("virtual_netmask", &info.virtual_netmask);
+    json.field_str("connect_status", &info.connect_status);
+    json.field_str("relay_server", &info.relay_server);
+    json.field_str("nat_type", &info.nat_type);
+    json.field_str("public_ips", &info.public_ips);
+    json.field_str("local_addr", &info.local_addr);
+    json.field_str("ipv6_addr", &info.ipv6_addr);
+    json.field_u64("up", info.up);
+    json.field_u64("down", info.down);
+    json.field_u64("replay_drop", info.replay_drop);
+    json.field_u64("tcp_proxy_evict", info.tcp_proxy_evict);
+    json.end_object();
+}
+
+fn write_peer(json: &mut JsonWriter, peer: &DeviceItem) {
+    json.begin_object();
+    json.field_str("name", &peer.name);
+    json.field_str("virtual_ip", &peer.virtual_ip);
+    json.field_str("nat_type", &peer.nat_type);
+    json.field_str("public_ips", &peer.public_ips);
+    json.field_str("local_ip", &peer.local_ip);
+    json.field_str("ipv6", &peer.ipv6);
+    json.field_str("nat_traversal_type", &peer.nat_traversal_type);
+    json.field_str("rt", &peer.rt);
+    json.field_str("status", &peer.status);
+    json.field_bool("client_secret", peer.client_secret);
+    json.field_str("tags", &peer.tags);
+    json.end_object();
+}
+
+fn write_route(json: &mut JsonWriter, route: &RouteItem) {
+    json.begin_object();
+    json.field_str("destination", &route.destination);
+    json.field_str("next_hop", &route.next_hop);
+    json.field_str("metric", &route.metric);
+    json.field_str("rt", &route.rt);
+    json.field_str("interface", &route.interface);
+    json.field_str("name", &route.name);
+    json.end_object();
+}
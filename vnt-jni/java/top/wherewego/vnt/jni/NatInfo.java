@@ -0,0 +1,93 @@
+package top.wherewego.vnt.jni;
+
+/**
+ * nat信息
+ *
+ * @author https://github.com/lbl8603/vnt
+ */
+public class NatInfo {
+    /**
+     * Cone或Symmetric
+     */
+    private final String natType;
+    /**
+     * 公网ip，逗号分隔
+     */
+    private final String publicIps;
+    /**
+     * 公网端口，逗号分隔
+     */
+    private final String publicPorts;
+    private final int publicPortRange;
+    /**
+     * 本地ipv4地址，没有时为空字符串
+     */
+    private final String localAddr;
+    /**
+     * ipv6地址，没有时为空字符串
+     */
+    private final String ipv6Addr;
+    private final int tcpPort;
+    /**
+     * 对端和本机在同一个NAT后面(公网ip相同)，只对对端的nat信息有效
+     */
+    private final boolean sameNat;
+
+    public NatInfo(String natType, String publicIps, String publicPorts, int publicPortRange,
+                   String localAddr, String ipv6Addr, int tcpPort, boolean sameNat) {
+        this.natType = natType;
+        this.publicIps = publicIps;
+        this.publicPorts = publicPorts;
+        this.publicPortRange = publicPortRange;
+        this.localAddr = localAddr;
+        this.ipv6Addr = ipv6Addr;
+        this.tcpPort = tcpPort;
+        this.sameNat = sameNat;
+    }
+
+    public String getNatType() {
+        return natType;
+    }
+
+    public String getPublicIps() {
+        return publicIps;
+    }
+
+    public String getPublicPorts() {
+        return publicPorts;
+    }
+
+    public int getPublicPortRange() {
+        return publicPortRange;
+    }
+
+    public String getLocalAddr() {
+        return localAddr;
+    }
+
+    public String getIpv6Addr() {
+        return ipv6Addr;
+    }
+
+    public int getTcpPort() {
+        return tcpPort;
+    }
+
+    public boolean isSameNat() {
+        return sameNat;
+    }
+
+    @Override
+    public String toString() {
+        return "NatInfo{" +
+                "natType='" + natType + '\'' +
+                ", publicIps='" + publicIps + '\'' +
+                ", publicPorts='" + publicPorts + '\'' +
+                ", publicPortRange=" + publicPortRange +
+                ", localAddr='" + localAddr + '\'' +
+                ", ipv6Addr='" + ipv6Addr + '\'' +
+                ", tcpPort=" + tcpPort +
+                ", sameNat=" + sameNat +
+                '}';
+    }
+}
@@ -1,5 +1,7 @@
 package top.wherewego.vnt.jni;
 
+import top.wherewego.vnt.jni.param.ErrorInfo;
+
 import java.io.Closeable;
 import java.io.IOException;
 
@@ -11,7 +13,7 @@ import java.io.IOException;
 public class Vnt implements Closeable {
     private final long raw;
 
-    public Vnt(Config config, CallBack callBack) throws Exception{
+    public Vnt(Config config, CallBack callBack) throws VntException {
         this.raw = new0(config, callBack);
         if (this.raw == 0) {
             throw new RuntimeException();
@@ -26,11 +28,63 @@ public class Vnt implements Closeable {
         wait0(raw);
     }
 
+    /**
+     * 进入或退出低功耗状态，在Doze(ACTION_DEVICE_IDLE_MODE_CHANGED)变化时调用。
+     * 低功耗时放大客户端间的心跳间隔并暂停打洞，和服务端的心跳不变
+     */
+    public void setLowPower(boolean lowPower) {
+        setLowPower0(raw, lowPower);
+    }
+
+    /**
+     * 网络变化时调用，如ConnectivityManager.NetworkCallback的onAvailable、onLost，
+     * 立即重新绑定并迁移连接，不用等待超时
+     *
+     * @return false表示还没有启动完成
+     */
+    public boolean onNetworkChanged() {
+        return onNetworkChanged0(raw);
+    }
+
     public PeerRouteInfo[] list() {
         return list0(raw);
     }
 
-    private native long new0(Config config, CallBack callBack) throws Exception;
+    /**
+     * 当前设备信息，和命令行的--info一致
+     */
+    public VntInfo info() {
+        return info0(raw);
+    }
+
+    public NatInfo natInfo() {
+        return natInfo0(raw);
+    }
+
+    /**
+     * 对端的nat信息，还没有收到时返回null
+     *
+     * @param virtualIp 对端虚拟ip
+     */
+    public NatInfo peerNatInfo(int virtualIp) {
+        return peerNatInfo0(raw, virtualIp);
+    }
+
+    /**
+     * @return [上行字节数, 下行字节数]
+     */
+    public long[] upDownStream() {
+        return upDownStream0(raw);
+    }
+
+    /**
+     * 因错误停止时的错误信息，没有发生导致停止的错误时返回null
+     */
+    public ErrorInfo stopReason() {
+        return stopReason0(raw);
+    }
+
+    private native long new0(Config config, CallBack callBack) throws VntException;
 
     private native void stop0(long raw);
 
@@ -38,8 +92,22 @@ public class Vnt implements Closeable {
 
     private native void drop0(long raw);
 
+    private native void setLowPower0(long raw, boolean lowPower);
+
+    private native boolean onNetworkChanged0(long raw);
+
     private native PeerRouteInfo[] list0(long raw);
 
+    private native VntInfo info0(long raw);
+
+    private native NatInfo natInfo0(long raw);
+
+    private native NatInfo peerNatInfo0(long raw, int virtualIp);
+
+    private native long[] upDownStream0(long raw);
+
+    private native ErrorInfo stopReason0(long raw);
+
     @Override
     public void close() throws IOException {
         drop0(raw);
@@ -0,0 +1,24 @@
+package top.wherewego.vnt.jni;
+
+import top.wherewego.vnt.jni.param.ErrorInfo;
+
+/**
+ * vnt启动失败的异常，错误码和回调中的ErrorInfo一致
+ *
+ * @author https://github.com/lbl8603/vnt
+ */
+public class VntException extends Exception {
+    /**
+     * 错误码
+     */
+    private final ErrorInfo.ErrorCodeEnum code;
+
+    public VntException(int code, String msg) {
+        super(msg);
+        this.code = ErrorInfo.ErrorCodeEnum.of(code);
+    }
+
+    public ErrorInfo.ErrorCodeEnum getCode() {
+        return code;
+    }
+}
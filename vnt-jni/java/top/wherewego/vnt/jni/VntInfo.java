@@ -0,0 +1,91 @@
+package top.wherewego.vnt.jni;
+
+/**
+ * 当前设备信息
+ *
+ * @author https://github.com/lbl8603/vnt
+ */
+public class VntInfo {
+    private final String name;
+    private final int virtualIp;
+    private final int virtualGateway;
+    private final int virtualNetmask;
+    /**
+     * 和服务端的连接状态
+     */
+    private final String connectStatus;
+    private final String relayServer;
+    private final NatInfo natInfo;
+    /**
+     * 上行字节数
+     */
+    private final long up;
+    /**
+     * 下行字节数
+     */
+    private final long down;
+
+    public VntInfo(String name, int virtualIp, int virtualGateway, int virtualNetmask,
+                   String connectStatus, String relayServer, NatInfo natInfo, long up, long down) {
+        this.name = name;
+        this.virtualIp = virtualIp;
+        this.virtualGateway = virtualGateway;
+        this.virtualNetmask = virtualNetmask;
+        this.connectStatus = connectStatus;
+        this.relayServer = relayServer;
+        this.natInfo = natInfo;
+        this.up = up;
+        this.down = down;
+    }
+
+    public String getName() {
+        return name;
+    }
+
+    public int getVirtualIp() {
+        return virtualIp;
+    }
+
+    public int getVirtualGateway() {
+        return virtualGateway;
+    }
+
+    public int getVirtualNetmask() {
+        return virtualNetmask;
+    }
+
+    public String getConnectStatus() {
+        return connectStatus;
+    }
+
+    public String getRelayServer() {
+        return relayServer;
+    }
+
+    public NatInfo getNatInfo() {
+        return natInfo;
+    }
+
+    public long getUp() {
+        return up;
+    }
+
+    public long getDown() {
+        return down;
+    }
+
+    @Override
+    public String toString() {
+        return "VntInfo{" +
+                "name='" + name + '\'' +
+                ", virtualIp=" + IpUtils.intToIpAddress(virtualIp) +
+                ", virtualGateway=" + IpUtils.intToIpAddress(virtualGateway) +
+                ", virtualNetmask=" + IpUtils.intToIpAddress(virtualNetmask) +
+                ", connectStatus='" + connectStatus + '\'' +
+                ", relayServer='" + relayServer + '\'' +
+                ", natInfo=" + natInfo +
+                ", up=" + up +
+                ", down=" + down +
+                '}';
+    }
+}
@@ -16,25 +16,7 @@ public class ErrorInfo {
     public final String msg;
 
     public ErrorInfo(int code, String msg) {
-        switch (code) {
-            case 1:
-                this.code = ErrorCodeEnum.TokenError;
-                break;
-            case 2:
-                this.code = ErrorCodeEnum.Disconnect;
-                break;
-            case 3:
-                this.code = ErrorCodeEnum.AddressExhausted;
-                break;
-            case 4:
-                this.code = ErrorCodeEnum.IpAlreadyExists;
-                break;
-            case 5:
-                this.code = ErrorCodeEnum.InvalidIp;
-                break;
-            default:
-                this.code = ErrorCodeEnum.Unknown;
-        }
+        this.code = ErrorCodeEnum.of(code);
         this.msg = msg;
     }
 
@@ -47,12 +29,42 @@ public class ErrorInfo {
     }
 
     public enum ErrorCodeEnum {
-        TokenError,
-        Disconnect,
-        AddressExhausted,
-        IpAlreadyExists,
-        InvalidIp,
-        Unknown,
+        TokenError(1),
+        Disconnect(2),
+        AddressExhausted(3),
+        IpAlreadyExists(4),
+        InvalidIp(5),
+        LocalIpExists(6),
+        HandshakeRejected(7),
+        VersionMismatch(8),
+        PunchFailed(9),
+        CipherError(10),
+        TunCreateFailed(11),
+        IoError(12),
+        KdfMismatch(13),
+        ServerUnreachable(14),
+        IpConflictDetected(15),
+        VersionTooOld(16),
+        NameDuplicated(17),
+        Unknown(255);
+
+        /**
+         * 错误码，和命令行的退出码一致
+         */
+        public final int code;
+
+        ErrorCodeEnum(int code) {
+            this.code = code;
+        }
+
+        public static ErrorCodeEnum of(int code) {
+            for (ErrorCodeEnum value : values()) {
+                if (value.code == code) {
+                    return value;
+                }
+            }
+            return Unknown;
+        }
     }
 
     @Override
@@ -2,6 +2,7 @@ use std::sync::Arc;
 
 use jni::objects::{GlobalRef, JClass, JObject, JString, JValue};
 use jni::{JNIEnv, JavaVM};
+use parking_lot::Mutex;
 use spki::der::pem::LineEnding;
 use spki::EncodePublicKey;
 
@@ -12,9 +13,13 @@ use vnt::handle::callback::DeviceConfig;
 use vnt::DeviceInfo;
 use vnt::{ErrorInfo, HandshakeInfo, PeerClientInfo, RegisterInfo, VntCallback};
 
+/// 导致停止的错误码和错误信息
+pub type StopReason = Arc<Mutex<Option<(u8, Option<String>)>>>;
+
 #[derive(Clone)]
 pub struct CallBack {
     jvm: Arc<JavaVM>,
+    stop_reason: StopReason,
     this: GlobalRef,
     connect_info_class: GlobalRef,
     handshake_info_class: GlobalRef,
@@ -56,6 +61,7 @@ impl CallBack {
             find_class_global_ref(&mut env, "top/wherewego/vnt/jni/param/DeviceInfo")?;
         Ok(Self {
             jvm: Arc::new(jvm),
+            stop_reason: StopReason::default(),
             this,
             connect_info_class,
             handshake_info_class,
@@ -71,6 +77,9 @@ impl CallBack {
 }
 
 impl CallBack {
+    pub fn stop_reason(&self) -> StopReason {
+        self.stop_reason.clone()
+    }
     fn success0(&self) -> jni::errors::Result<()> {
         let mut env = self.jvm.attach_current_thread_as_daemon()?;
         env.call_method(&self.this, "success", "()V", &[])?;
@@ -309,6 +318,11 @@ impl VntCallback for CallBack {
     }
 
     fn error(&self, info: ErrorInfo) {
+        if info.code.is_fatal() {
+            self.stop_reason
+                .lock()
+                .replace((info.code.into(), info.msg.clone()));
+        }
         if let Err(e) = self.error0(info) {
             log::warn!("error {:?}", e);
         }
@@ -5,8 +5,10 @@ use jni::objects::JObject;
 use jni::JNIEnv;
 
 use vnt::channel::punch::PunchModel;
-use vnt::channel::UseChannelType;
-use vnt::cipher::CipherModel;
+use vnt::channel::send_queue::SendQueueConfig;
+use vnt::channel::sock_buf::SocketBuf;
+use vnt::channel::{LoadBalanceModel, UseChannelType};
+use vnt::cipher::{CipherModel, KeyDerivation};
 use vnt::core::Config;
 
 use crate::utils::*;
@@ -14,6 +16,8 @@ use crate::utils::*;
 pub fn new_config(env: &mut JNIEnv, config: JObject) -> Result<Config, Error> {
     #[cfg(any(target_os = "windows", target_os = "linux"))]
     let tap = env.get_field(&config, "tap", "Z")?.z()?;
+    #[cfg(target_os = "linux")]
+    let no_multi_queue = env.get_field(&config, "noMultiQueue", "Z")?.z()?;
     let token = to_string_not_null(env, &config, "token")?;
     let name = to_string_not_null(env, &config, "name")?;
     let device_id = to_string_not_null(env, &config, "deviceId")?;
@@ -33,9 +37,121 @@ pub fn new_config(env: &mut JNIEnv, config: JObject) -> Result<Config, Error> {
         .map(|v| v as u32)
         .unwrap_or_default();
     let packet_loss_rate = to_double(env, &config, "packetLossRate")?;
+    let load_balance = to_string(env, &config, "loadBalance")?;
+    let anti_replay = env.get_field(&config, "antiReplay", "Z")?.z()?;
+    let prefer_ipv6_server = env.get_field(&config, "preferIpv6Server", "Z")?.z()?;
+    let power_save = to_integer(env, &config, "powerSave")?
+        .map(|v| v as u32)
+        .unwrap_or_default();
+    let power_save_multiple = to_integer(env, &config, "powerSaveMultiple")?
+        .map(|v| v as u32)
+        .unwrap_or(5);
 
     let in_ips = to_string_array(env, &config, "inIps")?;
     let out_ips = to_string_array(env, &config, "outIps")?;
+    let out_ips6 = to_string_array(env, &config, "outIps6")?;
+    let psk = to_string_array(env, &config, "psk")?;
+    let knock_key = to_string(env, &config, "knockKey")?;
+    let knock_port = to_integer(env, &config, "knockPort")?.map(|v| v as u16);
+    let server_proxy = to_string(env, &config, "serverProxy")?;
+    let route_hysteresis = to_integer(env, &config, "routeHysteresis")?
+        .map(|v| v as u32)
+        .unwrap_or_default();
+    let route_hold_down = to_integer(env, &config, "routeHoldDown")?
+        .map(|v| v as u32)
+        .unwrap_or_default();
+    let device_key = to_string(env, &config, "deviceKey")?;
+    let listen = to_string(env, &config, "listen")?;
+    // 超出范围的值交给Config::new报错
+    let dscp = to_integer(env, &config, "dscp")?.map(|v| u8::try_from(v).unwrap_or(u8::MAX));
+    let so_priority = to_integer(env, &config, "soPriority")?.map(|v| v as u32);
+    let dscp_copy = env.get_field(&config, "dscpCopy", "Z")?.z()?;
+    let control_dscp =
+        to_integer(env, &config, "controlDscp")?.map(|v| u8::try_from(v).unwrap_or(u8::MAX));
+    let parallel_crypto = to_integer(env, &config, "parallelCrypto")?
+        .map(|v| v.max(0) as usize)
+        .unwrap_or_default();
+    let gateway = match to_string(env, &config, "gateway")? {
+        Some(gateway) => match vnt::ip_proxy::gateway::GatewayConfig::from_str(&gateway) {
+            Ok(gateway) => Some(gateway),
+            Err(e) => {
+                env.throw_new("java/lang/RuntimeException", format!("gateway {}", e))
+                    .expect("throw");
+                return Err(Error::JavaException);
+            }
+        },
+        None => None,
+    };
+    let broadcast_mask = match to_string(env, &config, "broadcastMask")? {
+        Some(mask) => match common::args_parse::netmask_parse(&mask) {
+            Ok(mask) => Some(mask),
+            Err(e) => {
+                env.throw_new(
+                    "java/lang/RuntimeException",
+                    format!("broadcast_mask {}", e),
+                )
+                .expect("throw");
+                return Err(Error::JavaException);
+            }
+        },
+        None => None,
+    };
+    let hold_punch = to_integer(env, &config, "holdPunch")?
+        .map(|v| v as u32)
+        .unwrap_or_default();
+    let wan_sim = to_string_array(env, &config, "wanSim")?.unwrap_or_default();
+    let relay_budget = to_integer(env, &config, "relayBudget")?
+        .map(|v| v as u32)
+        .unwrap_or_default();
+    let kdf = to_string(env, &config, "kdf")?;
+    let allow_peer = to_string_array(env, &config, "allowPeer")?.unwrap_or_default();
+    let deny_peer = to_string_array(env, &config, "denyPeer")?.unwrap_or_default();
+    let reverse_tunnel = to_integer(env, &config, "reverseTunnel")?
+        .map(|v| v as usize)
+        .unwrap_or_default();
+    let allow_diag = env.get_field(&config, "allowDiag", "Z")?.z()?;
+    let allow_speed_test = env.get_field(&config, "allowSpeedTest", "Z")?.z()?;
+    let advertise_subnets = env.get_field(&config, "advertiseSubnets", "Z")?.z()?;
+    let accept_subnets = env.get_field(&config, "acceptSubnets", "Z")?.z()?;
+    let padding = env.get_field(&config, "padding", "Z")?.z()?;
+    let cover_traffic = to_integer(env, &config, "coverTraffic")?
+        .map(|v| v as u32)
+        .unwrap_or_default();
+    let accept_server_config =
+        to_string_array(env, &config, "acceptServerConfig")?.unwrap_or_default();
+    let server_ports = to_i32_array(env, &config, "serverPorts")?
+        .map(|v| v.into_iter().map(|v| v as u16).collect())
+        .unwrap_or_default();
+    #[cfg(any(target_os = "windows", target_os = "linux"))]
+    let vlan = to_i32_array(env, &config, "vlan")?
+        .map(|v| v.into_iter().map(|v| v as u16).collect())
+        .unwrap_or_default();
+    let ip_conflict_reassign = env.get_field(&config, "ipConflictReassign", "Z")?.z()?;
+    let send_queue = to_string(env, &config, "sendQueue")?;
+    let udp_buf = to_string(env, &config, "udpBuf")?;
+    let header_auth = env.get_field(&config, "headerAuth", "Z")?.z()?;
+    let coalesce = to_integer(env, &config, "coalesce")?
+        .map(|v| v as u32)
+        .unwrap_or_default();
+    let no_proxy = env.get_field(&config, "noProxy", "Z")?.z()?;
+    let tcp_proxy_keepalive = to_integer(env, &config, "tcpProxyKeepalive")?
+        .map(|v| v as u32)
+        .unwrap_or(120);
+    let tcp_proxy_idle = to_integer(env, &config, "tcpProxyIdle")?
+        .map(|v| v as u32)
+        .unwrap_or_default();
+    let tcp_proxy_max_conn = to_integer(env, &config, "tcpProxyMaxConn")?
+        .map(|v| v as u32)
+        .unwrap_or_default();
+    let parallel = to_integer(env, &config, "parallel")?.unwrap_or(1);
+    if parallel <= 0 {
+        env.throw_new(
+            "java/lang/RuntimeException",
+            format!("parallel {} invalid", parallel),
+        )
+        .expect("throw");
+        return Err(Error::JavaException);
+    }
     let ports =
         to_i32_array(env, &config, "ports")?.map(|v| v.into_iter().map(|v| v as u16).collect());
     let ip = if let Some(ip) = to_string(env, &config, "ip")? {
@@ -77,6 +193,30 @@ pub fn new_config(env: &mut JNIEnv, config: JObject) -> Result<Config, Error> {
     } else {
         vec![]
     };
+    let out_ips6 = if let Some(out_ips6) = out_ips6 {
+        match common::args_parse::out_ips6_parse(&out_ips6) {
+            Ok(out_ips6) => out_ips6,
+            Err(e) => {
+                env.throw_new("java/lang/RuntimeException", format!("out_ips6 {}", e))
+                    .expect("throw");
+                return Err(Error::JavaException);
+            }
+        }
+    } else {
+        vec![]
+    };
+    let psk = if let Some(psk) = psk {
+        match common::args_parse::psk_parse(&psk) {
+            Ok(psk) => psk,
+            Err(e) => {
+                env.throw_new("java/lang/RuntimeException", format!("psk {}", e))
+                    .expect("throw");
+                return Err(Error::JavaException);
+            }
+        }
+    } else {
+        vec![]
+    };
 
     let cipher_model = match CipherModel::from_str(&cipher_model) {
         Ok(cipher_model) => cipher_model,
@@ -86,6 +226,12 @@ pub fn new_config(env: &mut JNIEnv, config: JObject) -> Result<Config, Error> {
             return Err(Error::JavaException);
         }
     };
+    let punch_model: PunchModel = parse_or_default(env, "punch_model", punch_model)?;
+    let use_channel: UseChannelType = parse_or_default(env, "use_channel", use_channel)?;
+    let load_balance: LoadBalanceModel = parse_or_default(env, "load_balance", load_balance)?;
+    let kdf: KeyDerivation = parse_or_default(env, "kdf", kdf)?;
+    let send_queue: SendQueueConfig = parse_or_default(env, "send_queue", send_queue)?;
+    let socket_buf: SocketBuf = parse_or_default(env, "udp_buf", udp_buf)?;
     #[cfg(not(target_os = "android"))]
     let device_name = to_string(env, &config, "deviceName")?;
     let config = match Config::new(
@@ -103,19 +249,72 @@ pub fn new_config(env: &mut JNIEnv, config: JObject) -> Result<Config, Error> {
         mtu,
         tcp,
         ip,
-        false,
+        no_proxy,
+        tcp_proxy_keepalive,
+        tcp_proxy_idle,
+        tcp_proxy_max_conn,
         server_encrypt,
-        1,
+        parallel as usize,
         cipher_model,
         finger,
-        PunchModel::from_str(&punch_model.unwrap_or_default()).unwrap_or_default(),
+        punch_model,
         ports,
         first_latency,
         #[cfg(not(target_os = "android"))]
         device_name,
-        UseChannelType::from_str(&use_channel.unwrap_or_default()).unwrap_or_default(),
+        use_channel,
         packet_loss_rate,
         packet_delay,
+        load_balance,
+        anti_replay,
+        prefer_ipv6_server,
+        power_save,
+        power_save_multiple,
+        psk,
+        knock_key,
+        knock_port,
+        coalesce,
+        allow_diag,
+        allow_speed_test,
+        server_proxy,
+        route_hysteresis,
+        route_hold_down,
+        device_key,
+        reverse_tunnel,
+        header_auth,
+        listen,
+        dscp,
+        so_priority,
+        dscp_copy,
+        hold_punch,
+        wan_sim,
+        relay_budget,
+        kdf,
+        allow_peer,
+        deny_peer,
+        #[cfg(target_os = "linux")]
+        no_multi_queue,
+        out_ips6,
+        advertise_subnets,
+        accept_subnets,
+        padding,
+        cover_traffic,
+        accept_server_config,
+        server_ports,
+        #[cfg(any(target_os = "windows", target_os = "linux"))]
+        vlan,
+        ip_conflict_reassign,
+        None,
+        send_queue,
+        Vec::new(),
+        control_dscp,
+        parallel_crypto,
+        gateway,
+        broadcast_mask,
+        None,
+        None,
+        socket_buf,
+        None,
     ) {
         Ok(config) => config,
         Err(e) => {
@@ -129,3 +328,21 @@ pub fn new_config(env: &mut JNIEnv, config: JObject) -> Result<Config, Error> {
     };
     Ok(config)
 }
+
+/// 未设置时使用默认值，设置了错误的值则抛出异常，和命令行保持一致
+fn parse_or_default<T>(env: &mut JNIEnv, name: &str, value: Option<String>) -> Result<T, Error>
+where
+    T: FromStr<Err = String> + Default,
+{
+    match value.filter(|v| !v.trim().is_empty()) {
+        None => Ok(T::default()),
+        Some(value) => match T::from_str(&value) {
+            Ok(v) => Ok(v),
+            Err(e) => {
+                env.throw_new("java/lang/RuntimeException", format!("{} {}", name, e))
+                    .expect("throw");
+                Err(Error::JavaException)
+            }
+        },
+    }
+}
@@ -1,15 +1,24 @@
 use std::ptr;
 
 use jni::errors::Error;
-use jni::objects::{JClass, JObject, JValue};
-use jni::sys::{jint, jlong, jobject, jobjectArray, jsize};
+use jni::objects::{JClass, JObject, JThrowable, JValue};
+use std::net::Ipv4Addr;
+
+use jni::sys::{jboolean, jint, jlong, jlongArray, jobject, jobjectArray, jsize};
 use jni::JNIEnv;
 
+use vnt::channel::punch::NatInfo;
 use vnt::channel::Route;
 use vnt::core::Vnt;
 use vnt::handle::PeerDeviceInfo;
 
-use crate::callback::CallBack;
+use crate::callback::{CallBack, StopReason};
+
+/// Java持有的vnt实例
+pub struct VntHolder {
+    vnt: Vnt,
+    stop_reason: StopReason,
+}
 
 #[no_mangle]
 pub unsafe extern "C" fn Java_top_wherewego_vnt_jni_Vnt_new0(
@@ -36,18 +45,20 @@ pub unsafe extern "C" fn Java_top_wherewego_vnt_jni_Vnt_new0(
                     return 0;
                 }
             };
+            let stop_reason = call_back.stop_reason();
             let vnt_util = match Vnt::new(config, call_back) {
                 Ok(vnt_util) => vnt_util,
                 Err(e) => {
-                    env.throw_new(
-                        "java/lang/RuntimeException",
-                        format!("vnt start error {}", e),
-                    )
-                    .expect("throw");
+                    if let Err(err) = throw_vnt_exception(&mut env, e.code(), e.to_string()) {
+                        log::warn!("throw {:?} {:?}", e, err);
+                    }
                     return 0;
                 }
             };
-            let ptr = Box::into_raw(Box::new(vnt_util));
+            let ptr = Box::into_raw(Box::new(VntHolder {
+                vnt: vnt_util,
+                stop_reason,
+            }));
             return ptr as jlong;
         }
         Err(_) => {}
@@ -55,14 +66,25 @@ pub unsafe extern "C" fn Java_top_wherewego_vnt_jni_Vnt_new0(
     return 0;
 }
 
+/// 抛出带错误码的VntException
+fn throw_vnt_exception(env: &mut JNIEnv, code: u8, msg: String) -> jni::errors::Result<()> {
+    let msg = env.new_string(msg)?;
+    let exception = env.new_object(
+        "top/wherewego/vnt/jni/VntException",
+        "(ILjava/lang/String;)V",
+        &[JValue::Int(code as _), JValue::Object(&msg.into())],
+    )?;
+    env.throw(JThrowable::from(exception))
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn Java_top_wherewego_vnt_jni_Vnt_stop0(
     _env: JNIEnv,
     _class: JClass,
     raw_vnt: jlong,
 ) {
-    let vnt = raw_vnt as *mut Vnt;
-    let _ = (&*vnt).stop();
+    let vnt = raw_vnt as *mut VntHolder;
+    let _ = (&*vnt).vnt.stop();
 }
 
 #[no_mangle]
@@ -71,8 +93,8 @@ pub unsafe extern "C" fn Java_top_wherewego_vnt_jni_Vnt_wait0(
     _class: JClass,
     raw_vnt: jlong,
 ) {
-    let vnt = raw_vnt as *mut Vnt;
-    let _ = (&*vnt).wait();
+    let vnt = raw_vnt as *mut VntHolder;
+    let _ = (&*vnt).vnt.wait();
 }
 
 #[no_mangle]
@@ -81,8 +103,31 @@ pub unsafe extern "C" fn Java_top_wherewego_vnt_jni_Vnt_drop0(
     _class: JClass,
     raw_vnt: jlong,
 ) {
-    let vnt = raw_vnt as *mut Vnt;
-    let _ = Box::from_raw(vnt).stop();
+    let vnt = raw_vnt as *mut VntHolder;
+    let _ = Box::from_raw(vnt).vnt.stop();
+}
+
+// Doze等低功耗状态变化时调用
+#[no_mangle]
+pub unsafe extern "C" fn Java_top_wherewego_vnt_jni_Vnt_setLowPower0(
+    _env: JNIEnv,
+    _class: JClass,
+    raw_vnt: jlong,
+    low_power: jboolean,
+) {
+    let vnt = raw_vnt as *mut VntHolder;
+    (&*vnt).vnt.set_low_power(low_power != 0);
+}
+
+// ConnectivityManager回调中调用，立即重新绑定
+#[no_mangle]
+pub unsafe extern "C" fn Java_top_wherewego_vnt_jni_Vnt_onNetworkChanged0(
+    _env: JNIEnv,
+    _class: JClass,
+    raw_vnt: jlong,
+) -> jboolean {
+    let vnt = raw_vnt as *mut VntHolder;
+    (&*vnt).vnt.network_changed() as jboolean
 }
 
 #[no_mangle]
@@ -91,8 +136,8 @@ pub unsafe extern "C" fn Java_top_wherewego_vnt_jni_Vnt_list0(
     _class: JClass,
     raw_vnt: jlong,
 ) -> jobjectArray {
-    let vnt = raw_vnt as *mut Vnt;
-    let vnt = &mut *vnt;
+    let vnt = raw_vnt as *mut VntHolder;
+    let vnt = &(*vnt).vnt;
     let list = vnt.device_list();
 
     let arr = match env.new_object_array(
@@ -137,6 +182,180 @@ pub unsafe extern "C" fn Java_top_wherewego_vnt_jni_Vnt_list0(
     arr.as_raw()
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn Java_top_wherewego_vnt_jni_Vnt_info0(
+    mut env: JNIEnv,
+    _class: JClass,
+    raw_vnt: jlong,
+) -> jobject {
+    let vnt = raw_vnt as *mut VntHolder;
+    let vnt = &(*vnt).vnt;
+    match info_parse(&mut env, vnt) {
+        Ok(info) => info,
+        Err(e) => {
+            env.throw_new("java/lang/RuntimeException", format!("error:{:?}", e))
+                .expect("throw");
+            ptr::null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn Java_top_wherewego_vnt_jni_Vnt_natInfo0(
+    mut env: JNIEnv,
+    _class: JClass,
+    raw_vnt: jlong,
+) -> jobject {
+    let vnt = raw_vnt as *mut VntHolder;
+    let vnt = &(*vnt).vnt;
+    match nat_info_parse(&mut env, vnt.nat_info()) {
+        Ok(info) => info,
+        Err(e) => {
+            env.throw_new("java/lang/RuntimeException", format!("error:{:?}", e))
+                .expect("throw");
+            ptr::null_mut()
+        }
+    }
+}
+
+// 对端的nat信息，还没有收到时返回null
+#[no_mangle]
+pub unsafe extern "C" fn Java_top_wherewego_vnt_jni_Vnt_peerNatInfo0(
+    mut env: JNIEnv,
+    _class: JClass,
+    raw_vnt: jlong,
+    ip: jint,
+) -> jobject {
+    let vnt = raw_vnt as *mut VntHolder;
+    let vnt = &(*vnt).vnt;
+    let nat_info = match vnt.peer_nat_info(&Ipv4Addr::from(ip as u32)) {
+        Some(nat_info) => nat_info,
+        None => return ptr::null_mut(),
+    };
+    match nat_info_parse(&mut env, nat_info) {
+        Ok(info) => info,
+        Err(e) => {
+            env.throw_new("java/lang/RuntimeException", format!("error:{:?}", e))
+                .expect("throw");
+            ptr::null_mut()
+        }
+    }
+}
+
+// [上行字节数，下行字节数]
+#[no_mangle]
+pub unsafe extern "C" fn Java_top_wherewego_vnt_jni_Vnt_upDownStream0(
+    mut env: JNIEnv,
+    _class: JClass,
+    raw_vnt: jlong,
+) -> jlongArray {
+    let vnt = raw_vnt as *mut VntHolder;
+    let vnt = &(*vnt).vnt;
+    let stream = [vnt.up_stream() as jlong, vnt.down_stream() as jlong];
+    let rs = env.new_long_array(2).and_then(|arr| {
+        env.set_long_array_region(&arr, 0, &stream)?;
+        Ok(arr)
+    });
+    match rs {
+        Ok(arr) => arr.as_raw(),
+        Err(e) => {
+            env.throw_new("java/lang/RuntimeException", format!("error:{:?}", e))
+                .expect("throw");
+            ptr::null_mut()
+        }
+    }
+}
+
+// 因错误停止时的错误信息，没有发生导致停止的错误时返回null
+#[no_mangle]
+pub unsafe extern "C" fn Java_top_wherewego_vnt_jni_Vnt_stopReason0(
+    mut env: JNIEnv,
+    _class: JClass,
+    raw_vnt: jlong,
+) -> jobject {
+    let vnt = raw_vnt as *mut VntHolder;
+    let (code, msg) = match (*vnt).stop_reason.lock().clone() {
+        Some(reason) => reason,
+        None => return ptr::null_mut(),
+    };
+    let rs = env.new_string(msg.unwrap_or_default()).and_then(|msg| {
+        env.new_object(
+            "top/wherewego/vnt/jni/param/ErrorInfo",
+            "(ILjava/lang/String;)V",
+            &[JValue::Int(code as _), JValue::Object(&msg.into())],
+        )
+    });
+    match rs {
+        Ok(info) => info.as_raw(),
+        Err(e) => {
+            env.throw_new("java/lang/RuntimeException", format!("error:{:?}", e))
+                .expect("throw");
+            ptr::null_mut()
+        }
+    }
+}
+
+fn info_parse(env: &mut JNIEnv, vnt: &Vnt) -> Result<jobject, Error> {
+    let current_device = vnt.current_device();
+    let nat_info = unsafe { JObject::from_raw(nat_info_parse(env, vnt.nat_info())?) };
+    let name = env.new_string(vnt.name())?;
+    let connect_status = env.new_string(format!("{:?}", vnt.connection_status()))?;
+    let relay_server = env.new_string(current_device.connect_server.to_string())?;
+    let rs = env.new_object(
+        "top/wherewego/vnt/jni/VntInfo",
+        "(Ljava/lang/String;IIILjava/lang/String;Ljava/lang/String;Ltop/wherewego/vnt/jni/NatInfo;JJ)V",
+        &[
+            JValue::Object(&name.into()),
+            JValue::Int(u32::from(current_device.virtual_ip) as jint),
+            JValue::Int(u32::from(current_device.virtual_gateway) as jint),
+            JValue::Int(u32::from(current_device.virtual_netmask) as jint),
+            JValue::Object(&connect_status.into()),
+            JValue::Object(&relay_server.into()),
+            JValue::Object(&nat_info),
+            JValue::Long(vnt.up_stream() as jlong),
+            JValue::Long(vnt.down_stream() as jlong),
+        ],
+    )?;
+    Ok(rs.as_raw())
+}
+
+fn nat_info_parse(env: &mut JNIEnv, nat_info: NatInfo) -> Result<jobject, Error> {
+    let join = |list: Vec<String>| list.join(",");
+    let nat_type = env.new_string(format!("{:?}", nat_info.nat_type))?;
+    let public_ips = env.new_string(join(
+        nat_info.public_ips.iter().map(|v| v.to_string()).collect(),
+    ))?;
+    let public_ports = env.new_string(join(
+        nat_info
+            .public_ports
+            .iter()
+            .map(|v| v.to_string())
+            .collect(),
+    ))?;
+    let local_addr = env.new_string(
+        nat_info
+            .local_ipv4()
+            .map(|v| v.to_string())
+            .unwrap_or_default(),
+    )?;
+    let ipv6_addr = env.new_string(nat_info.ipv6().map(|v| v.to_string()).unwrap_or_default())?;
+    let rs = env.new_object(
+        "top/wherewego/vnt/jni/NatInfo",
+        "(Ljava/lang/String;Ljava/lang/String;Ljava/lang/String;ILjava/lang/String;Ljava/lang/String;IZ)V",
+        &[
+            JValue::Object(&nat_type.into()),
+            JValue::Object(&public_ips.into()),
+            JValue::Object(&public_ports.into()),
+            JValue::Int(nat_info.public_port_range as jint),
+            JValue::Object(&local_addr.into()),
+            JValue::Object(&ipv6_addr.into()),
+            JValue::Int(nat_info.tcp_port as jint),
+            JValue::Bool(nat_info.same_nat() as _),
+        ],
+    )?;
+    Ok(rs.as_raw())
+}
+
 fn route_parse(env: &mut JNIEnv, route: Route) -> Result<jobject, Error> {
     let rs = env.new_object(
         "top/wherewego/vnt/jni/Route",
@@ -0,0 +1,154 @@
+#![no_main]
+
+//! 解析从网络收到的数据包，任何输入都不能panic或越界读取
+//!
+//! cargo +nightly fuzz run net_packet
+
+use libfuzzer_sys::fuzz_target;
+use packet::icmp::icmp::IcmpPacket;
+use packet::ip::ipv4::packet::IpV4Packet;
+use packet::ip::ipv4::protocol::Protocol as Ipv4Protocol;
+use packet::tcp::tcp::TcpPacket;
+use packet::udp::udp::UdpPacket;
+use protobuf::Message;
+use vnt::channel::{coalesce, fragment, padding};
+use vnt::proto::message::{
+    DeviceList, DiagResponse, HandshakeResponse, PeerRouteReport, PunchInfo, RegistrationResponse,
+    SubnetAdvert,
+};
+use vnt::protocol::control_packet::ControlPacket;
+use vnt::protocol::error_packet::InErrorPacket;
+use vnt::protocol::ip_turn_packet::{BroadcastPacket, FragmentPacket};
+use vnt::protocol::{ip_turn_packet, other_turn_packet, NetPacket, Protocol};
+
+fuzz_target!(|data: &[u8]| {
+    let packet = match NetPacket::new(data) {
+        Ok(packet) => packet,
+        Err(_) => return,
+    };
+    let _ = (
+        packet.is_encrypt(),
+        packet.is_gateway(),
+        packet.version(),
+        packet.ttl(),
+        packet.source_ttl(),
+        packet.source(),
+        packet.destination(),
+    );
+    let payload = packet.payload();
+    match packet.protocol() {
+        Protocol::Service => {
+            let _ = HandshakeResponse::parse_from_bytes(payload);
+            let _ = RegistrationResponse::parse_from_bytes(payload);
+            let _ = DeviceList::parse_from_bytes(payload);
+        }
+        Protocol::Error => {
+            if let Ok(InErrorPacket::OtherError(e)) =
+                InErrorPacket::new(packet.transport_protocol(), payload)
+            {
+                let _ = e.message();
+            }
+        }
+        Protocol::Control => {
+            if let Ok(control) = ControlPacket::new(packet.transport_protocol(), payload) {
+                match control {
+                    ControlPacket::PingPacket(ping) | ControlPacket::PongPacket(ping) => {
+                        let _ = (ping.time(), ping.epoch(), ping.flags(), ping.time32());
+                        let _ = (ping.signed_data(), ping.signature());
+                    }
+                    ControlPacket::AddrResponse(addr) => {
+                        let _ = (addr.ipv4(), addr.port());
+                    }
+                    ControlPacket::Congestion(congestion) => {
+                        let _ = (
+                            congestion.destination(),
+                            congestion.hold(),
+                            congestion.rate(),
+                        );
+                    }
+                    ControlPacket::MtuProbe(probe) => {
+                        let _ = (probe.size(), probe.is_reply());
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Protocol::IpTurn => match ip_turn_packet::Protocol::from(packet.transport_protocol()) {
+            ip_turn_packet::Protocol::Ipv4 => ipv4(payload),
+            ip_turn_packet::Protocol::Ipv4Broadcast => {
+                if let Ok(broadcast) = BroadcastPacket::new(payload) {
+                    let _ = broadcast.addresses();
+                    if let Ok(data) = broadcast.data() {
+                        ipv4(data);
+                    }
+                }
+            }
+            ip_turn_packet::Protocol::Ipv4Batch => {
+                if let Ok(list) = coalesce::split(payload) {
+                    list.into_iter().for_each(ipv4);
+                }
+            }
+            ip_turn_packet::Protocol::Ipv4Fragment => {
+                if let Ok(fragment) = FragmentPacket::new(payload) {
+                    let _ = (fragment.id(), fragment.index(), fragment.count());
+                    let _ = fragment.data();
+                }
+            }
+            ip_turn_packet::Protocol::Ipv4Padded => {
+                if let Some(len) = padding::unpad(payload) {
+                    ipv4(&payload[..len]);
+                }
+            }
+            ip_turn_packet::Protocol::Unknown(_) => {}
+        },
+        Protocol::OtherTurn => match other_turn_packet::Protocol::from(packet.transport_protocol())
+        {
+            other_turn_packet::Protocol::Punch => {
+                let _ = PunchInfo::parse_from_bytes(payload);
+            }
+            other_turn_packet::Protocol::RouteReport => {
+                let _ = PeerRouteReport::parse_from_bytes(payload);
+            }
+            other_turn_packet::Protocol::DiagResponse => {
+                let _ = DiagResponse::parse_from_bytes(payload);
+            }
+            other_turn_packet::Protocol::SubnetAdvert => {
+                let _ = SubnetAdvert::parse_from_bytes(payload);
+            }
+            _ => {}
+        },
+        Protocol::Unknown(_) => {}
+    }
+});
+
+fn ipv4(data: &[u8]) {
+    let mut buf = data.to_vec();
+    fragment::clamp_mss(&mut buf, 1400);
+    let ipv4 = match IpV4Packet::new(data) {
+        Ok(ipv4) => ipv4,
+        Err(_) => return,
+    };
+    if !ipv4.is_consistent() {
+        return;
+    }
+    let _ = format!("{:?}", ipv4);
+    let (source, destination) = (ipv4.source_ip(), ipv4.destination_ip());
+    match ipv4.protocol() {
+        Ipv4Protocol::Tcp => {
+            if let Ok(tcp) = TcpPacket::new(source, destination, ipv4.payload()) {
+                let _ = format!("{:?}", tcp);
+            }
+        }
+        Ipv4Protocol::Udp => {
+            if let Ok(udp) = UdpPacket::new(source, destination, ipv4.payload()) {
+                let _ = format!("{:?}", udp);
+            }
+        }
+        Ipv4Protocol::Icmp => {
+            if let Ok(icmp) = IcmpPacket::new(ipv4.payload()) {
+                let _ = format!("{:?}", icmp.description());
+            }
+        }
+        _ => {}
+    }
+}
@@ -44,11 +44,20 @@ impl<B: AsRef<[u8]>> IpV4Packet<B> {
             Err(io::Error::new(io::ErrorKind::InvalidData, "not ipv4"))?;
         }
         let packet = Self::unchecked(buffer);
-        if packet.buffer.as_ref().len() < packet.header_len() as usize * 4 {
+        // 头部至少20字节，否则按头部长度切片会越界
+        if packet.header_len() < 5
+            || packet.buffer.as_ref().len() < packet.header_len() as usize * 4
+        {
             Err(io::Error::new(io::ErrorKind::InvalidData, "head_len err"))?;
         }
         Ok(packet)
     }
+    /// 总字节数和头部长度、缓冲区长度一致，
+    /// 缓冲区可以比总字节数长(如以太网填充)，但不能更短
+    pub fn is_consistent(&self) -> bool {
+        let length = self.length() as usize;
+        length >= self.header_len() as usize * 4 && length <= self.buffer.as_ref().len()
+    }
 }
 
 impl<B: AsRef<[u8]>> IpV4Packet<B> {
@@ -143,4 +143,28 @@ mod tests {
         let sum = cal_checksum(&[255, 255]);
         println!("{:?}", sum);
     }
+
+    #[test]
+    fn malformed() {
+        use std::net::Ipv4Addr;
+        let mut buf = [0u8; 40];
+        // 头部长度为0
+        buf[0] = 0x40;
+        assert!(ip::ipv4::packet::IpV4Packet::new(&buf[..]).is_err());
+        buf[0] = 0x45;
+        buf[2..4].copy_from_slice(&60u16.to_be_bytes());
+        let ipv4 = ip::ipv4::packet::IpV4Packet::new(&buf[..]).unwrap();
+        assert!(!ipv4.is_consistent());
+        // tcp数据偏移为0
+        let ip = Ipv4Addr::UNSPECIFIED;
+        assert!(tcp::tcp::TcpPacket::new(ip, ip, &buf[20..]).is_err());
+        // 时间戳请求不足12字节
+        let mut icmp = [0u8; 10];
+        icmp[0] = 13;
+        let icmp = icmp::icmp::IcmpPacket::new(&icmp[..]).unwrap();
+        assert!(matches!(
+            icmp.description(),
+            icmp::icmp::Description::Other(_)
+        ));
+    }
 }
@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use packet::ip::ipv4::protocol::Protocol;
+
+use crate::channel::peer_filter::PeerMatch;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AclProtocol {
+    Any,
+    Tcp,
+    Udp,
+    Icmp,
+}
+
+/// 一条访问规则，格式为 allow|deny any|tcp|udp|icmp [端口]，
+/// 端口可以是多个，逗号分隔，支持范围，如 allow tcp 80,443,8000-8100
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AclRule {
+    pub allow: bool,
+    pub protocol: AclProtocol,
+    // 为空表示所有端口
+    pub ports: Vec<(u16, u16)>,
+}
+
+impl FromStr for AclRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut split = s.split_whitespace();
+        let allow = match split.next() {
+            Some("allow") => true,
+            Some("deny") => false,
+            _ => return Err(format!("acl rule '{}', e.g. allow tcp 80,443", s)),
+        };
+        let protocol = match split.next() {
+            Some("any") => AclProtocol::Any,
+            Some("tcp") => AclProtocol::Tcp,
+            Some("udp") => AclProtocol::Udp,
+            Some("icmp") => AclProtocol::Icmp,
+            _ => return Err(format!("acl rule '{}', enum: any/tcp/udp/icmp", s)),
+        };
+        let mut ports = Vec::new();
+        if let Some(port_str) = split.next() {
+            if protocol == AclProtocol::Icmp {
+                return Err(format!("acl rule '{}', icmp has no port", s));
+            }
+            for item in port_str.split(',') {
+                let range = match item.split_once('-') {
+                    Some((start, end)) => (start.trim().parse(), end.trim().parse()),
+                    None => (item.trim().parse(), item.trim().parse()),
+                };
+                match range {
+                    (Ok(start), Ok(end)) if start <= end => ports.push((start, end)),
+                    _ => return Err(format!("acl rule '{}', invalid port '{}'", s, item)),
+                }
+            }
+        }
+        if split.next().is_some() {
+            return Err(format!("acl rule '{}'", s));
+        }
+        Ok(Self {
+            allow,
+            protocol,
+            ports,
+        })
+    }
+}
+
+impl AclRule {
+    fn is_match_protocol(&self, protocol: AclProtocol) -> bool {
+        self.protocol == AclProtocol::Any || self.protocol == protocol
+    }
+    fn is_match_port(&self, port: u16) -> bool {
+        self.ports.is_empty()
+            || self
+                .ports
+                .iter()
+                .any(|(start, end)| *start <= port && port <= *end)
+    }
+}
+
+/// 命名的规则集，按顺序匹配，都不匹配时拒绝
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AclBundle {
+    pub name: String,
+    pub rules: Vec<AclRule>,
+}
+
+impl AclBundle {
+    pub fn new(name: &str, rules: &[String]) -> Result<Self, String> {
+        let mut list = Vec::with_capacity(rules.len());
+        for rule in rules {
+            list.push(AclRule::from_str(rule).map_err(|e| format!("{}: {}", name, e))?);
+        }
+        Ok(Self {
+            name: name.to_string(),
+            rules: list,
+        })
+    }
+    /// 内置的规则集
+    /// - admin: 不限制
+    /// - web-only: 只能访问本机的web服务和dns，可以ping
+    /// - iot-isolated: 不能主动访问本机，本机仍可以主动连接它(tcp)
+    pub fn builtin(name: &str) -> Option<Self> {
+        let rules: &[&str] = match name {
+            "admin" => &["allow any"],
+            "web-only" => &["allow tcp 80,443", "allow udp 53", "allow icmp"],
+            "iot-isolated" => &["deny any"],
+            _ => return None,
+        };
+        let rules: Vec<String> = rules.iter().map(|v| v.to_string()).collect();
+        Self::new(name, &rules).ok()
+    }
+}
+
+/// 把规则集分配给一组对端
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AclGroup {
+    pub bundle: AclBundle,
+    pub peers: Vec<PeerMatch>,
+}
+
+/// 编译后的端口表，覆盖0~65535，按起始端口排序，二分查找
+#[derive(Debug)]
+struct PortTable {
+    segments: Vec<(u16, bool)>,
+}
+
+impl PortTable {
+    fn new(rules: &[AclRule], protocol: AclProtocol) -> Self {
+        let rules: Vec<&AclRule> = rules
+            .iter()
+            .filter(|rule| rule.is_match_protocol(protocol))
+            .collect();
+        let mut points: Vec<u32> = vec![0];
+        for rule in &rules {
+            for (start, end) in &rule.ports {
+                points.push(*start as u32);
+                points.push(*end as u32 + 1);
+            }
+        }
+        points.retain(|v| *v <= u16::MAX as u32);
+        points.sort_unstable();
+        points.dedup();
+        let mut segments: Vec<(u16, bool)> = Vec::with_capacity(points.len());
+        for point in points {
+            let port = point as u16;
+            // 每一段内的端口匹配结果相同，取段首端口按顺序匹配
+            let allow = rules
+                .iter()
+                .find(|rule| rule.is_match_port(port))
+                .is_some_and(|rule| rule.allow);
+            match segments.last() {
+                Some((_, last)) if *last == allow => {}
+                _ => segments.push((port, allow)),
+            }
+        }
+        Self { segments }
+    }
+    fn is_allowed(&self, port: u16) -> bool {
+        let index = self.segments.partition_point(|(start, _)| *start <= port);
+        self.segments[index - 1].1
+    }
+}
+
+#[derive(Debug)]
+struct CompiledBundle {
+    tcp: PortTable,
+    udp: PortTable,
+    icmp: bool,
+    other: bool,
+}
+
+impl CompiledBundle {
+    fn new(bundle: &AclBundle) -> Self {
+        let first = |protocol: AclProtocol| {
+            bundle
+                .rules
+                .iter()
+                .find(|rule| rule.is_match_protocol(protocol) && rule.ports.is_empty())
+                .is_some_and(|rule| rule.allow)
+        };
+        Self {
+            tcp: PortTable::new(&bundle.rules, AclProtocol::Tcp),
+            udp: PortTable::new(&bundle.rules, AclProtocol::Udp),
+            icmp: first(AclProtocol::Icmp),
+            other: first(AclProtocol::Any),
+        }
+    }
+    /// offset为ip分片偏移，非首个分片没有端口，不做限制
+    fn is_allowed(&self, protocol: Protocol, offset: u16, payload: &[u8]) -> bool {
+        match protocol {
+            Protocol::Tcp => {
+                if offset != 0 {
+                    return true;
+                }
+                if payload.len() < 14 {
+                    return false;
+                }
+                // 只限制发起连接的syn，本机主动发起的连接的回包不受影响
+                let flags = payload[13];
+                if flags & 0x02 == 0 || flags & 0x10 != 0 {
+                    return true;
+                }
+                self.tcp
+                    .is_allowed(u16::from_be_bytes([payload[2], payload[3]]))
+            }
+            Protocol::Udp => {
+                if offset != 0 {
+                    return true;
+                }
+                if payload.len() < 4 {
+                    return false;
+                }
+                self.udp
+                    .is_allowed(u16::from_be_bytes([payload[2], payload[3]]))
+            }
+            Protocol::Icmp => self.icmp,
+            _ => self.other,
+        }
+    }
+}
+
+/// 按对端应用的访问控制，只限制对端发给本机(包括经本机代理)的ip数据，
+/// 不在任何分组内的对端不受限制，一个对端属于多个分组时使用第一个匹配的分组，ip优先于名称
+#[derive(Default, Debug)]
+pub struct Acl {
+    bundles: Vec<CompiledBundle>,
+    by_ip: HashMap<Ipv4Addr, usize>,
+    by_name: HashMap<String, usize>,
+}
+
+impl Acl {
+    pub fn new(groups: &[AclGroup]) -> Self {
+        let mut acl = Acl::default();
+        for (index, group) in groups.iter().enumerate() {
+            acl.bundles.push(CompiledBundle::new(&group.bundle));
+            for peer in &group.peers {
+                match peer {
+                    PeerMatch::Ip(ip) => {
+                        acl.by_ip.entry(*ip).or_insert(index);
+                    }
+                    PeerMatch::Name(name) => {
+                        acl.by_name.entry(name.clone()).or_insert(index);
+                    }
+                }
+            }
+        }
+        acl
+    }
+    pub fn is_empty(&self) -> bool {
+        self.bundles.is_empty()
+    }
+    pub fn has_name(&self) -> bool {
+        !self.by_name.is_empty()
+    }
+    pub fn is_allowed(
+        &self,
+        ip: &Ipv4Addr,
+        name: Option<&String>,
+        protocol: Protocol,
+        offset: u16,
+        payload: &[u8],
+    ) -> bool {
+        let index = match self
+            .by_ip
+            .get(ip)
+            .or_else(|| name.and_then(|name| self.by_name.get(name)))
+        {
+            Some(index) => *index,
+            None => return true,
+        };
+        self.bundles[index].is_allowed(protocol, offset, payload)
+    }
+}
+
+#[test]
+fn test_acl() {
+    let syn = |port: u16| {
+        let mut tcp = [0u8; 20];
+        tcp[2..4].copy_from_slice(&port.to_be_bytes());
+        tcp[13] = 0x02;
+        tcp
+    };
+    let udp = |port: u16| {
+        let mut udp = [0u8; 8];
+        udp[2..4].copy_from_slice(&port.to_be_bytes());
+        udp
+    };
+    let a = Ipv4Addr::new(10, 26, 0, 2);
+    let b = Ipv4Addr::new(10, 26, 0, 3);
+    let c = Ipv4Addr::new(10, 26, 0, 4);
+    let custom = AclBundle::new(
+        "custom",
+        &[
+            "deny tcp 8080".to_string(),
+            "allow tcp 8000-8100,22".to_string(),
+            "allow udp".to_string(),
+        ],
+    )
+    .unwrap();
+    let acl = Acl::new(&[
+        AclGroup {
+            bundle: AclBundle::builtin("web-only").unwrap(),
+            peers: vec![PeerMatch::Ip(a), PeerMatch::Name("tv".to_string())],
+        },
+        AclGroup {
+            bundle: custom,
+            peers: vec![PeerMatch::Ip(a), PeerMatch::Ip(b)],
+        },
+    ]);
+    let tv = "tv".to_string();
+    assert!(acl.is_allowed(&a, None, Protocol::Tcp, 0, &syn(443)));
+    assert!(!acl.is_allowed(&a, None, Protocol::Tcp, 0, &syn(22)));
+    assert!(acl.is_allowed(&a, None, Protocol::Udp, 0, &udp(53)));
+    assert!(acl.is_allowed(&a, None, Protocol::Icmp, 0, &[]));
+    assert!(!acl.is_allowed(&c, Some(&tv), Protocol::Udp, 0, &udp(54)));
+    // 不在分组内的对端不受限制
+    assert!(acl.is_allowed(&c, None, Protocol::Tcp, 0, &syn(22)));
+    // 已建立连接的包和后续分片不受限制
+    let mut ack = syn(22);
+    ack[13] = 0x10;
+    assert!(acl.is_allowed(&a, None, Protocol::Tcp, 0, &ack));
+    assert!(acl.is_allowed(&a, None, Protocol::Udp, 100, &udp(54)));
+
+    assert!(!acl.is_allowed(&b, None, Protocol::Tcp, 0, &syn(8080)));
+    assert!(acl.is_allowed(&b, None, Protocol::Tcp, 0, &syn(8081)));
+    assert!(acl.is_allowed(&b, None, Protocol::Tcp, 0, &syn(8100)));
+    assert!(!acl.is_allowed(&b, None, Protocol::Tcp, 0, &syn(8101)));
+    assert!(acl.is_allowed(&b, None, Protocol::Tcp, 0, &syn(22)));
+    assert!(acl.is_allowed(&b, None, Protocol::Udp, 0, &udp(65535)));
+    assert!(!acl.is_allowed(&b, None, Protocol::Icmp, 0, &[]));
+
+    assert!(AclRule::from_str("allow icmp 80").is_err());
+    assert!(AclRule::from_str("allow tcp 90-80").is_err());
+    assert!(AclRule::from_str("permit tcp").is_err());
+}
@@ -0,0 +1,151 @@
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::channel::RouteKey;
+
+/// 收到对端直连的包但自己发不过去时，经服务端通知对端的间隔
+const NOTICE_INTERVAL: Duration = Duration::from_secs(20);
+/// 收到通知后探测的最小间隔
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+/// 探测发出后等待确认的时间
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// 单向路由没有再次确认时的有效期，超过后按普通路由超时删除
+const SEND_ONLY_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct State {
+    // 对端 -> 上次发出通知的时间
+    notified: HashMap<Ipv4Addr, Instant>,
+    // 对端 -> (探测的随机数，发出时间)
+    probing: HashMap<Ipv4Addr, (u32, Instant)>,
+    // 只用于发送的直连路由 -> 最近确认的时间
+    send_only: HashMap<(Ipv4Addr, RouteKey), Instant>,
+}
+
+/// 单向可达的直连通道。
+/// 有的NAT组合只有一个方向能直连，收到直连包的一方经服务端通知对方，
+/// 对方向候选地址发送探测，收到的一方再经服务端确认，对方据此添加只用于发送的路由，
+/// 回来的数据仍然走中转
+#[derive(Default)]
+pub struct AsymPaths {
+    state: Mutex<State>,
+}
+
+impl AsymPaths {
+    /// 收到对端的直连包，自己没有可用的直连路由时返回是否需要通知对端
+    pub fn on_inbound(&self, peer: Ipv4Addr) -> bool {
+        self.on_inbound_at(peer, Instant::now())
+    }
+    fn on_inbound_at(&self, peer: Ipv4Addr, now: Instant) -> bool {
+        let mut guard = self.state.lock();
+        match guard.notified.get(&peer) {
+            Some(last) if now.saturating_duration_since(*last) < NOTICE_INTERVAL => false,
+            _ => {
+                guard.notified.insert(peer, now);
+                true
+            }
+        }
+    }
+    /// 收到对端的通知，返回本次探测使用的随机数，间隔太短时不探测
+    pub fn start_probe(&self, peer: Ipv4Addr, nonce: u32) -> Option<u32> {
+        self.start_probe_at(peer, nonce, Instant::now())
+    }
+    fn start_probe_at(&self, peer: Ipv4Addr, nonce: u32, now: Instant) -> Option<u32> {
+        let mut guard = self.state.lock();
+        if let Some((_, last)) = guard.probing.get(&peer) {
+            if now.saturating_duration_since(*last) < PROBE_INTERVAL {
+                return None;
+            }
+        }
+        guard.probing.insert(peer, (nonce, now));
+        Some(nonce)
+    }
+    /// 对端确认收到探测，随机数匹配时记录单向路由，返回探测到确认的耗时
+    pub fn confirm(&self, peer: Ipv4Addr, nonce: u32, route_key: RouteKey) -> Option<Duration> {
+        self.confirm_at(peer, nonce, route_key, Instant::now())
+    }
+    fn confirm_at(
+        &self,
+        peer: Ipv4Addr,
+        nonce: u32,
+        route_key: RouteKey,
+        now: Instant,
+    ) -> Option<Duration> {
+        let mut guard = self.state.lock();
+        let elapsed = match guard.probing.get(&peer) {
+            Some((expect, time)) if *expect == nonce => now.saturating_duration_since(*time),
+            _ => return None,
+        };
+        if elapsed >= PROBE_TIMEOUT {
+            return None;
+        }
+        guard.send_only.insert((peer, route_key), now);
+        Some(elapsed)
+    }
+    /// 是否为最近确认过的单向路由，这类路由收不到数据，不按读超时删除
+    pub fn is_send_only(&self, peer: &Ipv4Addr, route_key: &RouteKey) -> bool {
+        self.is_send_only_at(peer, route_key, Instant::now())
+    }
+    fn is_send_only_at(&self, peer: &Ipv4Addr, route_key: &RouteKey, now: Instant) -> bool {
+        match self.state.lock().send_only.get(&(*peer, *route_key)) {
+            Some(time) => now.saturating_duration_since(*time) < SEND_ONLY_TTL,
+            None => false,
+        }
+    }
+    /// 当前的单向路由
+    pub fn send_only(&self) -> Vec<(Ipv4Addr, RouteKey)> {
+        let now = Instant::now();
+        self.state
+            .lock()
+            .send_only
+            .iter()
+            .filter(|(_, time)| now.saturating_duration_since(**time) < SEND_ONLY_TTL)
+            .map(|(key, _)| *key)
+            .collect()
+    }
+    /// 去掉已经不在设备列表中的对端和过期的记录
+    pub fn retain(&self, ips: &HashSet<Ipv4Addr>) {
+        let now = Instant::now();
+        let mut guard = self.state.lock();
+        guard.notified.retain(|ip, _| ips.contains(ip));
+        guard.probing.retain(|ip, _| ips.contains(ip));
+        guard.send_only.retain(|(ip, _), time| {
+            ips.contains(ip) && now.saturating_duration_since(*time) < SEND_ONLY_TTL
+        });
+    }
+}
+
+#[test]
+fn test_asym_paths() {
+    let paths = AsymPaths::default();
+    let peer = Ipv4Addr::new(10, 26, 0, 2);
+    let key = RouteKey::new(false, 0, "1.1.1.1:1000".parse().unwrap());
+    let now = Instant::now();
+    assert!(paths.on_inbound_at(peer, now));
+    assert!(!paths.on_inbound_at(peer, now + Duration::from_secs(1)));
+    assert!(paths.on_inbound_at(peer, now + NOTICE_INTERVAL));
+
+    assert_eq!(paths.start_probe_at(peer, 7, now), Some(7));
+    assert_eq!(
+        paths.start_probe_at(peer, 8, now + Duration::from_secs(1)),
+        None
+    );
+    // 随机数不匹配或超时的确认不接受
+    assert!(paths
+        .confirm_at(peer, 8, key, now + Duration::from_secs(1))
+        .is_none());
+    assert!(paths
+        .confirm_at(peer, 7, key, now + PROBE_TIMEOUT)
+        .is_none());
+    assert_eq!(
+        paths.confirm_at(peer, 7, key, now + Duration::from_millis(80)),
+        Some(Duration::from_millis(80))
+    );
+    assert!(paths.is_send_only_at(&peer, &key, now + Duration::from_secs(1)));
+    assert!(!paths.is_send_only_at(&peer, &key, now + SEND_ONLY_TTL + Duration::from_secs(1)));
+    paths.retain(&HashSet::new());
+    assert!(!paths.is_send_only(&peer, &key));
+}
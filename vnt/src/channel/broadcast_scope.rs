@@ -0,0 +1,88 @@
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// 客户端覆盖的广播范围，把服务端分配的大网段(如/16)划分成多个互相隔离的广播组(如/24)，
+/// 广播只发给同组的设备，也只接收同组设备的广播
+pub struct BroadcastScope {
+    // 配置的掩码
+    mask: Option<u32>,
+    // 按服务端网段校验后生效的掩码，0为不限制
+    active: AtomicU32,
+}
+
+impl BroadcastScope {
+    pub fn new(mask: Option<Ipv4Addr>) -> Result<Self, String> {
+        let mask = mask.map(u32::from);
+        if let Some(mask) = mask {
+            if mask == 0 || mask.leading_ones() + mask.trailing_zeros() != 32 {
+                return Err(format!("broadcast mask {}", Ipv4Addr::from(mask)));
+            }
+        }
+        Ok(Self {
+            mask,
+            active: AtomicU32::new(0),
+        })
+    }
+    /// 注册成功后按服务端的掩码校验，广播组不能比服务端的网段大，不符合时不生效
+    pub(crate) fn apply(&self, server_mask: Ipv4Addr) -> Result<(), String> {
+        let mask = match self.mask {
+            Some(mask) => mask,
+            None => return Ok(()),
+        };
+        let server_mask = u32::from(server_mask);
+        if mask & server_mask != server_mask {
+            self.active.store(0, Ordering::Relaxed);
+            return Err(format!(
+                "broadcast mask {} is wider than the server netmask {}",
+                Ipv4Addr::from(mask),
+                Ipv4Addr::from(server_mask)
+            ));
+        }
+        // 和服务端网段一致时无需过滤
+        let active = if mask == server_mask { 0 } else { mask };
+        self.active.store(active, Ordering::Relaxed);
+        Ok(())
+    }
+    /// 当前生效的广播组掩码
+    pub fn mask(&self) -> Option<Ipv4Addr> {
+        match self.active.load(Ordering::Relaxed) {
+            0 => None,
+            mask => Some(Ipv4Addr::from(mask)),
+        }
+    }
+    /// 对端是否和本机在同一个广播组
+    pub fn is_member(&self, local: Ipv4Addr, peer: Ipv4Addr) -> bool {
+        let mask = self.active.load(Ordering::Relaxed);
+        u32::from(local) & mask == u32::from(peer) & mask
+    }
+    /// 是否为本机所在广播组的广播地址
+    pub fn is_group_broadcast(&self, local: Ipv4Addr, dest: Ipv4Addr) -> bool {
+        let mask = self.active.load(Ordering::Relaxed);
+        mask != 0 && u32::from(local) | !mask == u32::from(dest)
+    }
+}
+
+#[test]
+fn test_broadcast_scope() {
+    let local = Ipv4Addr::new(10, 26, 1, 2);
+    assert!(BroadcastScope::new(Some(Ipv4Addr::new(255, 0, 255, 0))).is_err());
+    assert!(BroadcastScope::new(Some(Ipv4Addr::UNSPECIFIED)).is_err());
+    let scope = BroadcastScope::new(Some(Ipv4Addr::new(255, 255, 255, 0))).unwrap();
+    // 校验前不限制
+    assert!(scope.is_member(local, Ipv4Addr::new(10, 26, 2, 2)));
+    assert!(scope.apply(Ipv4Addr::new(255, 255, 0, 0)).is_ok());
+    assert_eq!(scope.mask(), Some(Ipv4Addr::new(255, 255, 255, 0)));
+    assert!(scope.is_member(local, Ipv4Addr::new(10, 26, 1, 200)));
+    assert!(!scope.is_member(local, Ipv4Addr::new(10, 26, 2, 2)));
+    assert!(scope.is_group_broadcast(local, Ipv4Addr::new(10, 26, 1, 255)));
+    assert!(!scope.is_group_broadcast(local, Ipv4Addr::new(10, 26, 2, 255)));
+    // 服务端网段更小时不生效
+    assert!(scope.apply(Ipv4Addr::new(255, 255, 255, 128)).is_err());
+    assert_eq!(scope.mask(), None);
+    assert!(scope.is_member(local, Ipv4Addr::new(10, 26, 2, 2)));
+    assert!(scope.apply(Ipv4Addr::new(255, 255, 255, 0)).is_ok());
+    assert_eq!(scope.mask(), None);
+    let scope = BroadcastScope::new(None).unwrap();
+    assert!(scope.apply(Ipv4Addr::new(255, 255, 255, 0)).is_ok());
+    assert!(!scope.is_group_broadcast(local, Ipv4Addr::new(10, 26, 1, 255)));
+}
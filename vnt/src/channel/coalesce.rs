@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::{Condvar, Mutex};
+
+/// 只合并小于此长度的ip包
+pub const COALESCE_MAX_PACKET: usize = 512;
+/// 合并后的最大长度，避免超出mtu
+const COALESCE_MAX_BATCH: usize = 1200;
+/// 对端能力的有效期，超过这个时间没收到心跳则认为对端不支持
+const PEER_EXPIRE: Duration = Duration::from_secs(60);
+
+/// 小包合并，把发往同一目标的多个小包合并成一个udp包发送，减少加密和系统调用次数。
+/// 合并后的数据格式: |长度(2)|ip包|长度(2)|ip包|...
+#[derive(Clone)]
+pub struct Coalesce {
+    inner: Arc<CoalesceInner>,
+}
+
+struct CoalesceInner {
+    // 聚合等待时间，为0表示不开启
+    delay: Duration,
+    // 通过心跳协商出的支持合并的对端
+    peers: Mutex<HashMap<Ipv4Addr, Instant>>,
+    // 待发送的数据 目标ip -> (第一个包的时间,数据)
+    pending: Mutex<HashMap<Ipv4Addr, (Instant, Vec<u8>)>>,
+    cond: Condvar,
+}
+
+impl Coalesce {
+    pub fn new(delay_us: u32) -> Self {
+        Self {
+            inner: Arc::new(CoalesceInner {
+                delay: Duration::from_micros(delay_us as u64),
+                peers: Mutex::new(HashMap::new()),
+                pending: Mutex::new(HashMap::new()),
+                cond: Condvar::new(),
+            }),
+        }
+    }
+    pub fn is_enable(&self) -> bool {
+        !self.inner.delay.is_zero()
+    }
+    pub fn set_peer_support(&self, ip: Ipv4Addr, support: bool) {
+        if !self.is_enable() {
+            return;
+        }
+        let mut guard = self.inner.peers.lock();
+        if support {
+            if guard.insert(ip, Instant::now()).is_none() {
+                log::info!("对端支持小包合并 peer={}", ip);
+            }
+        } else {
+            guard.remove(&ip);
+        }
+    }
+    pub fn is_peer_support(&self, ip: &Ipv4Addr) -> bool {
+        self.inner
+            .peers
+            .lock()
+            .get(ip)
+            .map(|time| time.elapsed() < PEER_EXPIRE)
+            .unwrap_or(false)
+    }
+    /// 加入待发送数据，放不下时返回之前积累的数据，需要立即发送
+    pub fn push(&self, dest: Ipv4Addr, packet: &[u8]) -> Option<Vec<u8>> {
+        let mut guard = self.inner.pending.lock();
+        let notify = guard.is_empty();
+        let (time, buf) = guard
+            .entry(dest)
+            .or_insert_with(|| (Instant::now(), Vec::with_capacity(COALESCE_MAX_BATCH)));
+        let full = if buf.len() + 2 + packet.len() > COALESCE_MAX_BATCH {
+            *time = Instant::now();
+            Some(std::mem::replace(
+                buf,
+                Vec::with_capacity(COALESCE_MAX_BATCH),
+            ))
+        } else {
+            None
+        };
+        buf.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+        buf.extend_from_slice(packet);
+        drop(guard);
+        if notify {
+            self.inner.cond.notify_one();
+        }
+        full
+    }
+    /// 取出发往dest的待发送数据，发送不合并的包之前先发送，避免乱序
+    pub fn take(&self, dest: &Ipv4Addr) -> Option<Vec<u8>> {
+        let mut guard = self.inner.pending.lock();
+        if guard.is_empty() {
+            return None;
+        }
+        guard.remove(dest).map(|(_, buf)| buf)
+    }
+    /// 等待并取出超过聚合时间的数据，timeout内没有数据则返回空
+    pub fn wait_expired(&self, timeout: Duration) -> Vec<(Ipv4Addr, Vec<u8>)> {
+        let mut guard = self.inner.pending.lock();
+        if guard.is_empty() {
+            self.inner.cond.wait_for(&mut guard, timeout);
+        }
+        let oldest = guard.values().map(|(time, _)| time.elapsed()).max();
+        if let Some(oldest) = oldest {
+            if oldest < self.inner.delay {
+                // 等待期间可能有新的数据放入，不影响最早的数据
+                let _ = self
+                    .inner
+                    .cond
+                    .wait_for(&mut guard, self.inner.delay - oldest);
+            }
+        }
+        let delay = self.inner.delay;
+        let expired: Vec<Ipv4Addr> = guard
+            .iter()
+            .filter(|(_, (time, _))| time.elapsed() >= delay)
+            .map(|(ip, _)| *ip)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|ip| guard.remove(&ip).map(|(_, buf)| (ip, buf)))
+            .collect()
+    }
+}
+
+/// 拆分合并的数据
+pub fn split(mut buf: &[u8]) -> io::Result<Vec<&[u8]>> {
+    let mut list = Vec::new();
+    while !buf.is_empty() {
+        if buf.len() < 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "batch len"));
+        }
+        let len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+        if buf.len() < 2 + len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "batch len"));
+        }
+        list.push(&buf[2..2 + len]);
+        buf = &buf[2 + len..];
+    }
+    Ok(list)
+}
+
+#[test]
+fn test_coalesce_split() {
+    let coalesce = Coalesce::new(500);
+    let dest = Ipv4Addr::new(10, 26, 0, 2);
+    assert!(coalesce.push(dest, &[1, 2, 3]).is_none());
+    assert!(coalesce.push(dest, &[4]).is_none());
+    let list = coalesce.wait_expired(Duration::from_millis(10));
+    assert_eq!(list.len(), 1);
+    assert_eq!(
+        split(&list[0].1).unwrap(),
+        vec![&[1u8, 2, 3][..], &[4u8][..]]
+    );
+    assert!(split(&[0, 5, 1]).is_err());
+    // 不合并的包发送前取出积累的数据
+    assert!(coalesce.push(dest, &[5]).is_none());
+    assert_eq!(coalesce.take(&dest), Some(vec![0, 1, 5]));
+    assert_eq!(coalesce.take(&dest), None);
+    assert!(coalesce.wait_expired(Duration::from_millis(1)).is_empty());
+}
@@ -0,0 +1,112 @@
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+
+use parking_lot::RwLock;
+use rand::Rng;
+
+/// 中转包携带的连接id，标识目标设备而不是虚拟ip，
+/// 本机的id随机生成，通过打洞信息告知对端，中转时优先按id查找路由
+pub struct ConnIds {
+    local: u64,
+    // 对端虚拟ip -> 对端的连接id
+    peers: RwLock<HashMap<Ipv4Addr, u64>>,
+    // 连接id -> 对端虚拟ip
+    index: RwLock<HashMap<u64, Ipv4Addr>>,
+}
+
+impl Default for ConnIds {
+    fn default() -> Self {
+        let mut rng = rand::thread_rng();
+        let mut local = 0;
+        while local == 0 {
+            local = rng.gen();
+        }
+        Self::new(local)
+    }
+}
+
+impl ConnIds {
+    pub fn new(local: u64) -> Self {
+        Self {
+            local,
+            peers: RwLock::new(HashMap::with_capacity(16)),
+            index: RwLock::new(HashMap::with_capacity(16)),
+        }
+    }
+    /// 本机的连接id
+    pub fn local(&self) -> u64 {
+        self.local
+    }
+    /// 记录对端的连接id，0表示对端不支持
+    pub fn set_peer(&self, ip: Ipv4Addr, id: u64) {
+        let mut peers = self.peers.write();
+        let mut index = self.index.write();
+        if let Some(old) = peers.remove(&ip) {
+            index.remove(&old);
+        }
+        if id == 0 || id == self.local {
+            return;
+        }
+        // 其他设备已经使用了这个id时以后来的为准
+        if let Some(other) = index.insert(id, ip) {
+            peers.remove(&other);
+        }
+        peers.insert(ip, id);
+    }
+    pub fn peer(&self, ip: &Ipv4Addr) -> Option<u64> {
+        self.peers.read().get(ip).copied()
+    }
+    /// 按连接id查找目标设备
+    pub fn lookup(&self, id: u64) -> Option<Ipv4Addr> {
+        self.index.read().get(&id).copied()
+    }
+    /// 去掉已经不在设备列表中的对端
+    pub fn retain(&self, ips: &HashSet<Ipv4Addr>) {
+        let mut peers = self.peers.write();
+        let mut index = self.index.write();
+        peers.retain(|ip, _| ips.contains(ip));
+        index.retain(|_, ip| ips.contains(ip));
+    }
+}
+
+#[test]
+fn test_conn_ids() {
+    let ids = ConnIds::new(1);
+    let a = Ipv4Addr::new(10, 26, 0, 2);
+    let b = Ipv4Addr::new(10, 26, 0, 3);
+    ids.set_peer(a, 100);
+    assert_eq!(ids.peer(&a), Some(100));
+    assert_eq!(ids.lookup(100), Some(a));
+    // 对端重启后id变化
+    ids.set_peer(a, 101);
+    assert_eq!(ids.lookup(100), None);
+    assert_eq!(ids.lookup(101), Some(a));
+    // 和本机相同的id不使用
+    ids.set_peer(b, 1);
+    assert_eq!(ids.peer(&b), None);
+    ids.set_peer(b, 101);
+    assert_eq!(ids.peer(&a), None);
+    assert_eq!(ids.lookup(101), Some(b));
+    ids.retain(&HashSet::new());
+    assert_eq!(ids.lookup(101), None);
+}
+
+#[test]
+fn test_conn_id_extension() {
+    use crate::protocol::{NetPacket, CONN_ID_LEN};
+    let mut packet = NetPacket::new0(16, vec![0u8; 16 + CONN_ID_LEN]).unwrap();
+    packet.set_default_version();
+    packet.set_encrypt_flag(true);
+    packet.payload_mut().copy_from_slice(&[1, 2, 3, 4]);
+    assert_eq!(packet.conn_id(), None);
+    packet.append_conn_id(0x0102_0304_0506_0708).unwrap();
+    assert!(packet.is_encrypt());
+    assert_eq!(packet.data_len(), 16 + CONN_ID_LEN);
+    assert_eq!(packet.conn_id(), Some(0x0102_0304_0506_0708));
+    assert_eq!(packet.strip_conn_id(), Some(0x0102_0304_0506_0708));
+    assert!(!packet.has_conn_id());
+    assert_eq!(packet.payload(), &[1, 2, 3, 4]);
+    // 空间不够时不能追加
+    let mut packet = NetPacket::new(vec![0u8; 16]).unwrap();
+    assert!(packet.append_conn_id(1).is_err());
+}
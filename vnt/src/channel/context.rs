@@ -1,18 +1,47 @@
 use std::collections::HashMap;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV6, UdpSocket};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV6, UdpSocket};
 use std::ops::Deref;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::sync::{Arc, OnceLock, Weak};
+use std::time::Duration;
 use std::{io, thread};
 
-use crossbeam_utils::atomic::AtomicCell;
 use parking_lot::RwLock;
-use rand::Rng;
+use socket2::SockRef;
 
+use crate::channel::asym_path::AsymPaths;
+use crate::channel::broadcast_scope::BroadcastScope;
+use crate::channel::coalesce::Coalesce;
+use crate::channel::conn_id::ConnIds;
+use crate::channel::fragment::PathMtu;
+use crate::channel::hold_punch::HoldPunch;
+use crate::channel::hysteresis::RouteHysteresis;
+use crate::channel::idle::PowerSave;
+use crate::channel::ip_conflict::IpConflict;
+use crate::channel::netem::{DelayedPacket, Verdict, WanRule, WanSchedule, WanSim, WanSimStatus};
+use crate::channel::pacing::RelayPacer;
+use crate::channel::padding::Padding;
+use crate::channel::peer_auth::PeerAuth;
+use crate::channel::peer_filter::PeerFilter;
 use crate::channel::punch::NatType;
+use crate::channel::qos::Qos;
+use crate::channel::queue_depth::QueueDepth;
+use crate::channel::relay_meter::RelayMeter;
+pub use crate::channel::route_table::RouteTable;
+use crate::channel::send_queue::SendQueueConfig;
 use crate::channel::sender::{AcceptSocketSender, ChannelSender, PacketSender};
-use crate::channel::{Route, RouteKey, UseChannelType, DEFAULT_RT};
+use crate::channel::server_config::ServerConfig;
+use crate::channel::sock_buf::SocketBuf;
+use crate::channel::subnet::SubnetRoutes;
+use crate::channel::traffic::Traffic;
+#[cfg(unix)]
+use crate::channel::uds_channel::{uds_id, UdsChannel};
+use crate::channel::unreachable::UnreachableLimiter;
+use crate::channel::vlan::VlanTable;
+use crate::channel::{LoadBalanceModel, RouteKey, UseChannelType, UDS_INDEX};
+use crate::cipher::{PairwiseCipher, ReplayGuard};
+use crate::protocol::capability::{Capabilities, PeerCapabilities};
+use crate::protocol::{NetPacket, CONN_ID_LEN};
 
 /// 传输通道上下文，持有udp socket、tcp socket和路由信息
 #[derive(Clone)]
@@ -29,34 +58,115 @@ impl ChannelContext {
         packet_loss_rate: Option<f64>,
         packet_delay: u32,
         use_ipv6: bool,
+        load_balance: LoadBalanceModel,
+        anti_replay: bool,
+        power_save: PowerSave,
+        pairwise_cipher: PairwiseCipher,
+        coalesce: Coalesce,
+        route_hysteresis: RouteHysteresis,
+        peer_auth: PeerAuth,
+        listen: Option<IpAddr>,
+        mut qos: Qos,
+        hold_punch: HoldPunch,
+        wan_rules: Vec<WanRule>,
+        relay_meter: RelayMeter,
+        peer_filter: PeerFilter,
+        subnet_routes: SubnetRoutes,
+        padding: Padding,
+        server_config: ServerConfig,
+        vlan: VlanTable,
+        ip_conflict: IpConflict,
+        send_queue: SendQueueConfig,
+        broadcast_scope: BroadcastScope,
+        socket_buf: SocketBuf,
     ) -> Self {
         let channel_num = main_udp_socket.len();
         assert_ne!(channel_num, 0, "not channel");
-        let packet_loss_rate = packet_loss_rate
-            .map(|v| {
-                let v = (v * PACKET_LOSS_RATE_DENOMINATOR as f64) as u32;
-                if v > PACKET_LOSS_RATE_DENOMINATOR {
-                    PACKET_LOSS_RATE_DENOMINATOR
-                } else {
-                    v
-                }
-            })
-            .unwrap_or(0);
+        qos.init_main(channel_num);
+        let wan_sim = Arc::new(WanSim::new(packet_loss_rate, packet_delay, wan_rules));
         let inner = ContextInner {
             main_udp_socket,
             sub_udp_socket: RwLock::new(Vec::with_capacity(64)),
             tcp_map: RwLock::new(HashMap::with_capacity(64)),
-            route_table: RouteTable::new(use_channel_type, first_latency, channel_num),
+            route_table: RouteTable::new(
+                use_channel_type,
+                first_latency,
+                channel_num,
+                load_balance,
+                route_hysteresis,
+            ),
             is_tcp,
             state: AtomicBool::new(true),
-            packet_loss_rate,
-            packet_delay,
+            wan_sim,
             main_index: AtomicUsize::new(0),
             use_ipv6,
+            replay_guard: if anti_replay {
+                Some(ReplayGuard::new())
+            } else {
+                None
+            },
+            power_save,
+            pairwise_cipher,
+            coalesce,
+            peer_auth,
+            listen,
+            qos,
+            relay_pacer: RelayPacer::default(),
+            path_mtu: PathMtu::default(),
+            hold_punch,
+            relay_meter,
+            peer_filter,
+            capabilities: PeerCapabilities::default(),
+            conn_ids: ConnIds::default(),
+            asym_paths: AsymPaths::default(),
+            queue_depth: Arc::new(QueueDepth::default()),
+            traffic: Arc::new(Traffic::default()),
+            unreachable_limiter: UnreachableLimiter::default(),
+            subnet_routes,
+            padding,
+            server_config,
+            vlan,
+            ip_conflict,
+            send_queue,
+            broadcast_scope,
+            socket_buf,
+            #[cfg(unix)]
+            uds: OnceLock::new(),
         };
-        Self {
+        let context = Self {
             inner: Arc::new(inner),
+        };
+        if context.wan_sim.is_enable() {
+            context.start_wan_sim();
         }
+        context
+    }
+    /// 没有开启模拟弱网时不启动发送线程，运行中开启时再启动
+    fn start_wan_sim(&self) {
+        if !self.wan_sim.start() {
+            return;
+        }
+        let weak = Arc::downgrade(&self.inner);
+        let wan_sim = self.wan_sim.clone();
+        if let Err(e) = thread::Builder::new()
+            .name("wanSim".into())
+            .spawn(move || wan_sim_loop(weak, wan_sim))
+        {
+            log::error!("模拟弱网线程启动失败 {:?}", e);
+        }
+    }
+    /// 运行中替换模拟弱网的规则，为空时关闭
+    pub fn set_wan_rules(&self, rules: Vec<WanRule>) {
+        self.wan_sim.set_rules(rules);
+        self.start_wan_sim();
+    }
+    /// 按计划分阶段模拟弱网
+    pub fn set_wan_schedule(&self, schedule: WanSchedule) {
+        self.wan_sim.set_schedule(schedule);
+        self.start_wan_sim();
+    }
+    pub fn wan_sim_status(&self) -> WanSimStatus {
+        self.wan_sim.status()
     }
     pub fn sender(&self) -> ChannelSender {
         ChannelSender::new(self.clone())
@@ -73,7 +183,6 @@ impl Deref for ChannelContext {
 
 /// 对称网络增加的udp socket数目，有助于增加打洞成功率
 pub const SYMMETRIC_CHANNEL_NUM: usize = 100;
-const PACKET_LOSS_RATE_DENOMINATOR: u32 = 100_0000;
 
 pub struct ContextInner {
     // 核心udp socket
@@ -88,12 +197,65 @@ pub struct ContextInner {
     is_tcp: bool,
     //状态
     state: AtomicBool,
-    //控制丢包率，取值v=[0,100_0000] 丢包率r=v/100_0000
-    packet_loss_rate: u32,
-    //控制延迟
-    packet_delay: u32,
+    //模拟丢包、延迟、抖动和乱序
+    wan_sim: Arc<WanSim>,
     main_index: AtomicUsize,
     use_ipv6: bool,
+    //防重放，开启后客户端间的ip数据会带上序号
+    pub(crate) replay_guard: Option<ReplayGuard>,
+    //省电模式
+    pub power_save: PowerSave,
+    //点对点预共享密钥
+    pub(crate) pairwise_cipher: PairwiseCipher,
+    //小包合并
+    pub(crate) coalesce: Coalesce,
+    //设备身份校验
+    pub(crate) peer_auth: PeerAuth,
+    //通道socket绑定的本地地址
+    listen: Option<IpAddr>,
+    //DSCP和SO_PRIORITY标记
+    pub(crate) qos: Qos,
+    //中转拥塞限速
+    pub(crate) relay_pacer: RelayPacer,
+    //对端路径mtu和分片重组
+    pub(crate) path_mtu: PathMtu,
+    //p2p路由保活
+    pub(crate) hold_punch: HoldPunch,
+    //中转流量统计和限额
+    pub(crate) relay_meter: RelayMeter,
+    //对端黑白名单
+    pub(crate) peer_filter: PeerFilter,
+    //服务端和对端的能力
+    pub(crate) capabilities: PeerCapabilities,
+    //中转包携带的连接id
+    pub(crate) conn_ids: ConnIds,
+    //单向可达的直连通道
+    pub(crate) asym_paths: AsymPaths,
+    //各个队列中等待的包数
+    pub(crate) queue_depth: Arc<QueueDepth>,
+    //和对端之间的数据计数
+    pub(crate) traffic: Arc<Traffic>,
+    //生成icmp不可达报文的速率限制
+    pub(crate) unreachable_limiter: UnreachableLimiter,
+    //从其他网关学习到的网段
+    pub(crate) subnet_routes: SubnetRoutes,
+    //包长填充和掩护流量
+    pub(crate) padding: Padding,
+    //服务端下发的配置
+    pub(crate) server_config: ServerConfig,
+    //tap模式下的vlan划分
+    pub(crate) vlan: VlanTable,
+    //虚拟ip冲突检测
+    pub(crate) ip_conflict: IpConflict,
+    //tcp连接发送队列的水位和丢包策略
+    pub(crate) send_queue: SendQueueConfig,
+    //客户端划分的广播组
+    pub(crate) broadcast_scope: BroadcastScope,
+    //udp socket的收发缓冲区
+    pub(crate) socket_buf: SocketBuf,
+    //同一台主机上的实例之间的unix域套接字
+    #[cfg(unix)]
+    uds: OnceLock<UdsChannel>,
 }
 
 impl ContextInner {
@@ -113,12 +275,47 @@ impl ContextInner {
     pub fn is_main_tcp(&self) -> bool {
         self.is_tcp
     }
+    #[cfg(unix)]
+    pub(crate) fn set_uds(&self, uds: UdsChannel) -> bool {
+        self.uds.set(uds).is_ok()
+    }
+    /// 本机unix域套接字的名称，没有启用时为0
+    pub fn uds_id(&self) -> u64 {
+        #[cfg(unix)]
+        if let Some(uds) = self.uds.get() {
+            return uds.id();
+        }
+        0
+    }
+    /// 发送到同一台主机上的实例，对端的套接字不存在时返回错误
+    pub fn send_uds(&self, buf: &[u8], id: u64) -> io::Result<()> {
+        #[cfg(unix)]
+        if let Some(uds) = self.uds.get() {
+            return uds.send_to(buf, id);
+        }
+        let _ = (buf, id);
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
     pub fn is_udp_main(&self, route_key: &RouteKey) -> bool {
         !route_key.is_tcp() && route_key.index < self.main_udp_socket.len()
     }
     pub fn first_latency(&self) -> bool {
         self.route_table.first_latency
     }
+    pub fn load_balance(&self) -> LoadBalanceModel {
+        self.route_table.load_balance
+    }
+    /// 指定的监听地址，主动发起的连接也从这个地址出去
+    pub fn listen_ip(&self) -> Option<IpAddr> {
+        self.listen
+    }
+    /// 防重放丢弃的包数量
+    pub fn replay_drop_count(&self) -> u64 {
+        self.replay_guard
+            .as_ref()
+            .map(|v| v.drop_count())
+            .unwrap_or(0)
+    }
     /// 切换NAT类型，不同的nat打洞模式会有不同
     pub fn switch(
         &self,
@@ -133,7 +330,12 @@ impl ContextInner {
                 }
                 let mut vec = Vec::with_capacity(SYMMETRIC_CHANNEL_NUM);
                 for _ in 0..SYMMETRIC_CHANNEL_NUM {
-                    let udp = UdpSocket::bind("0.0.0.0:0")?;
+                    let udp = match self.listen {
+                        Some(IpAddr::V4(ip)) => UdpSocket::bind((ip, 0))?,
+                        _ => UdpSocket::bind("0.0.0.0:0")?,
+                    };
+                    self.qos.mark(SockRef::from(&udp));
+                    self.socket_buf.apply(SockRef::from(&udp));
                     //副通道使用异步io
                     udp.set_nonblocking(true)?;
                     vec.push(udp);
@@ -160,6 +362,10 @@ impl ContextInner {
     pub fn channel_num(&self) -> usize {
         self.main_udp_socket.len()
     }
+    /// 核心udp socket是否为v6+v4双栈
+    pub fn use_ipv6(&self) -> bool {
+        self.use_ipv6
+    }
     /// 获取核心udp监听的端口，用于其他客户端连接
     pub fn main_local_udp_port(&self) -> io::Result<Vec<u16>> {
         let mut ports = Vec::new();
@@ -187,7 +393,11 @@ impl ContextInner {
                 ));
             }
         }
-        self.main_udp_socket[index].send_to(buf, addr)?;
+        let main_udp = &self.main_udp_socket[index];
+        if let Some(dscp) = self.qos.send_dscp(buf, None) {
+            self.qos.copy_dscp(index, main_udp, dscp);
+        }
+        main_udp.send_to(buf, addr)?;
         Ok(())
     }
     /// 将数据发送到默认通道，一般发往服务器才用此方法
@@ -205,21 +415,29 @@ impl ContextInner {
         self.main_index.store(index, Ordering::Relaxed);
     }
     /// 此方法仅用于对称网络打洞
-    pub fn try_send_all(&self, buf: &[u8], addr: SocketAddr) {
-        self.try_send_all_main(buf, addr);
+    /// 使用全部udp socket发送，返回发送成功的数量
+    pub fn try_send_all(&self, buf: &[u8], addr: SocketAddr) -> usize {
+        let mut count = self.try_send_all_main(buf, addr);
         for udp in self.sub_udp_socket.read().iter() {
             if let Err(e) = udp.send_to(buf, addr) {
                 log::warn!("{:?},add={:?}", e, addr);
+            } else {
+                count += 1;
             }
             thread::sleep(Duration::from_millis(1));
         }
+        count
     }
-    pub fn try_send_all_main(&self, buf: &[u8], addr: SocketAddr) {
+    pub fn try_send_all_main(&self, buf: &[u8], addr: SocketAddr) -> usize {
+        let mut count = 0;
         for index in 0..self.channel_num() {
             if let Err(e) = self.send_main_udp(index, buf, addr) {
                 log::warn!("{:?},add={:?}", e, addr);
+            } else {
+                count += 1;
             }
         }
+        count
     }
     /// 发送网络数据
     pub fn send_ipv4_by_id(
@@ -229,32 +447,96 @@ impl ContextInner {
         server_addr: SocketAddr,
         send_default: bool,
     ) -> io::Result<()> {
-        if self.packet_loss_rate > 0 {
-            if rand::thread_rng().gen_ratio(self.packet_loss_rate, PACKET_LOSS_RATE_DENOMINATOR) {
-                return Ok(());
+        self.send_ipv4_by_flow(buf, id, 0, None, server_addr, send_default)
+    }
+    /// 发送网络数据，flow为数据流的哈希值，开启负载均衡时同一条流使用同一个通道，
+    /// dscp为内层ip包的DSCP，开启复制时用于标记外层udp
+    pub fn send_ipv4_by_flow(
+        &self,
+        buf: &[u8],
+        id: &Ipv4Addr,
+        flow: u32,
+        dscp: Option<u8>,
+        server_addr: SocketAddr,
+        send_default: bool,
+    ) -> io::Result<()> {
+        if self.wan_sim.is_enable() {
+            match self.wan_sim.judge(id) {
+                Verdict::Send => {}
+                Verdict::Drop => return Ok(()),
+                Verdict::Delay(delay) => {
+                    let packet = DelayedPacket {
+                        buf: buf.to_vec(),
+                        id: *id,
+                        flow,
+                        dscp,
+                        server_addr,
+                        send_default,
+                    };
+                    if !self.wan_sim.delay(delay, packet) {
+                        log::warn!("模拟延迟队列已满 peer={}", id);
+                    }
+                    return Ok(());
+                }
             }
         }
-        if self.packet_delay > 0 {
-            thread::sleep(Duration::from_millis(self.packet_delay as _));
+        self.send_ipv4_by_flow0(buf, id, flow, dscp, server_addr, send_default)
+    }
+    fn send_ipv4_by_flow0(
+        &self,
+        buf: &[u8],
+        id: &Ipv4Addr,
+        flow: u32,
+        dscp: Option<u8>,
+        server_addr: SocketAddr,
+        send_default: bool,
+    ) -> io::Result<()> {
+        //中转拥塞时限速，有直连通道的不受影响
+        if !self.relay_pacer.is_idle()
+            && self.route_table.route_one_p2p(id).is_none()
+            && !self.relay_pacer.check(id, buf.len())
+        {
+            return Ok(());
         }
         //优先发到直连到地址
-        if let Err(e) = self.send_by_id(buf, id) {
-            if e.kind() != io::ErrorKind::NotFound {
-                log::warn!("{}:{:?}", id, e);
-            }
-            if !self.route_table.use_channel_type.is_only_p2p() && send_default {
-                //符合条件再发到服务器转发
-                self.send_default(buf, server_addr)?;
+        let rs = if self.route_table.load_balance.is_none() {
+            self.send_by_id_(buf, id, dscp)
+        } else {
+            self.send_by_flow(buf, id, flow, dscp)
+        };
+        match rs {
+            Ok(metric) => self.traffic.tx(buf.len(), metric == 1),
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    log::warn!("发送失败 peer={} err={:?}", id, e);
+                }
+                if !self.route_table.use_channel_type.is_only_p2p() && send_default {
+                    //符合条件再发到服务器转发
+                    let tagged = self.with_conn_id(buf, id, None);
+                    self.send_default(tagged.as_deref().unwrap_or(buf), server_addr)?;
+                    self.traffic.tx(buf.len(), false);
+                }
             }
         }
         Ok(())
     }
     /// 将数据发到指定id
     pub fn send_by_id(&self, buf: &[u8], id: &Ipv4Addr) -> io::Result<()> {
+        self.send_by_id_(buf, id, None).map(|_| ())
+    }
+    /// 返回所用路由的跳数
+    fn send_by_id_(&self, buf: &[u8], id: &Ipv4Addr, dscp: Option<u8>) -> io::Result<u8> {
         let mut c = 0;
+        let mut retried = false;
         loop {
             let route = self.route_table.get_route_by_id(c, id)?;
-            return if let Err(e) = self.send_by_key(buf, route.route_key()) {
+            let tagged = if route.metric > 1 {
+                self.with_conn_id(buf, id, Some(&route.route_key()))
+            } else {
+                None
+            };
+            let buf0 = tagged.as_deref().unwrap_or(buf);
+            return if let Err(e) = self.send_by_key_(buf0, route.route_key(), dscp) {
                 //降低发送速率
                 if e.kind() == io::ErrorKind::WouldBlock {
                     c += 1;
@@ -263,18 +545,117 @@ impl ContextInner {
                         continue;
                     }
                 }
+                //路由刚失效，移除后重新选择一次，没有其他路由时由调用方走服务端中转
+                if !retried && self.stale_route(id, route.route_key(), &e) {
+                    retried = true;
+                    continue;
+                }
                 Err(e)
             } else {
-                Ok(())
+                Ok(route.metric)
+            };
+        }
+    }
+    /// 路由对应的连接或socket已经不存在(如tcp断开、副通道关闭)时移除路由并计数
+    fn stale_route(&self, id: &Ipv4Addr, route_key: RouteKey, e: &io::Error) -> bool {
+        match e.kind() {
+            io::ErrorKind::NotFound
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::AddrNotAvailable => {}
+            _ => return false,
+        }
+        log::info!(
+            "路由已失效,重新选择 peer={} route={:?} err={:?}",
+            id,
+            route_key,
+            e
+        );
+        // 重试前新的路由快照必须已经可见，否则会再次选到失效的路由
+        self.route_table.remove_route_sync(id, route_key);
+        self.traffic.route_retry_add();
+        true
+    }
+    /// 按负载均衡策略选择通道发送，失败时依次尝试其余通道
+    fn send_by_flow(
+        &self,
+        buf: &[u8],
+        id: &Ipv4Addr,
+        flow: u32,
+        dscp: Option<u8>,
+    ) -> io::Result<u8> {
+        let routes = self.route_table.get_route_by_flow(id, flow);
+        if routes.is_empty() {
+            return self.send_by_id_(buf, id, dscp);
+        }
+        let mut last_err = None;
+        for route in routes {
+            let tagged = if route.metric > 1 {
+                self.with_conn_id(buf, id, Some(&route.route_key()))
+            } else {
+                None
             };
+            match self.send_by_key_(tagged.as_deref().unwrap_or(buf), route.route_key(), dscp) {
+                Ok(_) => return Ok(route.metric),
+                Err(e) => {
+                    if !self.stale_route(id, route.route_key(), &e) {
+                        log::warn!(
+                            "发送失败 peer={} route={:?} err={:?}",
+                            id,
+                            route.route_key(),
+                            e
+                        );
+                    }
+                    last_err = Some(e);
+                }
+            }
         }
+        Err(last_err.unwrap())
+    }
+    /// 经中转发送时在末尾带上目标的连接id，目标和中转方(relay为空时是服务端)都支持时才带上
+    fn with_conn_id(&self, buf: &[u8], id: &Ipv4Addr, relay: Option<&RouteKey>) -> Option<Vec<u8>> {
+        if !self.capabilities.supports(id, Capabilities::CONN_ID) {
+            return None;
+        }
+        let conn_id = self.conn_ids.peer(id)?;
+        let relay_support = match relay {
+            Some(route_key) => self
+                .route_table
+                .route_to_id(route_key)
+                .is_some_and(|relay| self.capabilities.supports(&relay, Capabilities::CONN_ID)),
+            None => self.capabilities.server().contains(Capabilities::CONN_ID),
+        };
+        if !relay_support {
+            return None;
+        }
+        let mut packet = NetPacket::new0(buf.len(), vec![0u8; buf.len() + CONN_ID_LEN]).ok()?;
+        packet.buffer_mut().copy_from_slice(buf);
+        if packet.has_conn_id() {
+            return None;
+        }
+        packet.append_conn_id(conn_id).ok()?;
+        Some(packet.into_buffer())
     }
     /// 将数据发到指定路由
     pub fn send_by_key(&self, buf: &[u8], route_key: RouteKey) -> io::Result<()> {
+        self.send_by_key_(buf, route_key, None)
+    }
+    fn send_by_key_(&self, buf: &[u8], route_key: RouteKey, dscp: Option<u8>) -> io::Result<()> {
         if route_key.is_tcp {
             self.send_tcp(buf, route_key.addr)
+        } else if route_key.index == UDS_INDEX {
+            #[cfg(unix)]
+            if let Some(id) = uds_id(&route_key.addr) {
+                return self.send_uds(buf, id);
+            }
+            Err(io::Error::from(io::ErrorKind::NotFound))
         } else {
             if let Some(main_udp) = self.main_udp_socket.get(route_key.index) {
+                if let Some(dscp) = self.qos.send_dscp(buf, dscp) {
+                    self.qos.copy_dscp(route_key.index, main_udp, dscp);
+                }
                 main_udp.send_to(buf, route_key.addr)?;
             } else {
                 if let Some(udp) = self
@@ -295,246 +676,28 @@ impl ContextInner {
     }
 }
 
-pub struct RouteTable {
-    pub(crate) route_table:
-        RwLock<HashMap<Ipv4Addr, (AtomicUsize, Vec<(Route, AtomicCell<Instant>)>)>>,
-    first_latency: bool,
-    channel_num: usize,
-    use_channel_type: UseChannelType,
-}
-
-impl RouteTable {
-    fn new(use_channel_type: UseChannelType, first_latency: bool, channel_num: usize) -> Self {
-        Self {
-            route_table: RwLock::new(HashMap::with_capacity(64)),
-            use_channel_type,
-            first_latency,
-            channel_num,
-        }
-    }
-}
-
-impl RouteTable {
-    fn get_route_by_id(&self, index: usize, id: &Ipv4Addr) -> io::Result<Route> {
-        if let Some((_count, v)) = self.route_table.read().get(id) {
-            if self.first_latency {
-                if let Some((route, _)) = v.first() {
-                    return Ok(*route);
-                }
-            } else {
-                let len = v.len();
-                if len != 0 {
-                    let route = &v[index % len].0;
-                    // 跳过默认rt的路由(一般是刚加入的)，这有助于提升稳定性
-                    if route.rt != DEFAULT_RT {
-                        return Ok(*route);
-                    }
-                    for (route, _) in v {
-                        if route.rt != DEFAULT_RT {
-                            return Ok(*route);
-                        }
-                    }
-                }
-            }
-        }
-        Err(io::Error::new(io::ErrorKind::NotFound, "route not found"))
-    }
-    pub fn add_route_if_absent(&self, id: Ipv4Addr, route: Route) {
-        self.add_route_(id, route, true)
-    }
-    pub fn add_route(&self, id: Ipv4Addr, route: Route) {
-        self.add_route_(id, route, false)
-    }
-    fn add_route_(&self, id: Ipv4Addr, route: Route, only_if_absent: bool) {
-        // 限制通道类型
-        match self.use_channel_type {
-            UseChannelType::P2p => {
-                if !route.is_p2p() {
-                    return;
-                }
-            }
-            _ => {}
-        }
-        let key = route.route_key();
-        if only_if_absent {
-            if let Some((_, list)) = self.route_table.read().get(&id) {
-                for (x, _) in list {
-                    if x.route_key() == key {
-                        return;
-                    }
-                }
-            }
-        }
-        let mut route_table = self.route_table.write();
-        let (_, list) = route_table
-            .entry(id)
-            .or_insert_with(|| (AtomicUsize::new(0), Vec::with_capacity(4)));
-        let mut exist = false;
-        for (x, time) in list.iter_mut() {
-            if x.metric < route.metric && !self.first_latency {
-                //非优先延迟的情况下 不能比当前的路径更长
-                return;
-            }
-            if x.route_key() == key {
-                if only_if_absent {
-                    return;
-                }
-                x.metric = route.metric;
-                x.rt = route.rt;
-                exist = true;
-                time.store(Instant::now());
-                break;
-            }
-        }
-        if exist {
-            // 这个排序还有待优化，因为后加入的大概率排最后，被直接淘汰的概率也大，可能导致更好的通道被移除了
-            list.sort_by_key(|(k, _)| k.rt);
-            //如果延迟都稳定了，则去除多余通道
-            for (route, _) in list.iter() {
-                if route.rt == DEFAULT_RT {
-                    return;
-                }
-            }
-            //延迟优先模式需要更多的通道探测延迟最低的路线
-            let limit_len = if self.first_latency {
-                self.channel_num + 2
-            } else {
-                self.channel_num
-            };
-            self.truncate_(list, limit_len);
-        } else {
-            if !self.first_latency {
-                if route.is_p2p() {
-                    //非优先延迟的情况下 添加了直连的则排除非直连的
-                    list.retain(|(k, _)| k.is_p2p());
-                }
-            };
-            //增加路由表容量，避免波动
-            let limit_len = self.channel_num * 2;
-            list.sort_by_key(|(k, _)| k.rt);
-            self.truncate_(list, limit_len);
-            list.push((route, AtomicCell::new(Instant::now())));
-        }
-    }
-    fn truncate_(&self, list: &mut Vec<(Route, AtomicCell<Instant>)>, len: usize) {
-        if list.len() <= len {
+/// 到期后发送模拟延迟的包，上下文释放或停止后退出
+fn wan_sim_loop(context: Weak<ContextInner>, wan_sim: Arc<WanSim>) {
+    loop {
+        wan_sim.tick();
+        let packet = wan_sim.next(Duration::from_secs(1));
+        let context = match context.upgrade() {
+            Some(context) => context,
+            None => return,
+        };
+        if context.is_stop() {
             return;
         }
-        if self.first_latency {
-            //找到第一个p2p通道
-            if let Some(index) =
-                list.iter()
-                    .enumerate()
-                    .find_map(|(index, (route, _))| if route.is_p2p() { Some(index) } else { None })
-            {
-                if index >= len {
-                    //保留第一个p2p通道
-                    let route = list.remove(index);
-                    list.truncate(len - 1);
-                    list.push(route);
-                    return;
-                }
-            }
-        }
-        list.truncate(len);
-    }
-    pub fn route(&self, id: &Ipv4Addr) -> Option<Vec<Route>> {
-        if let Some((_, v)) = self.route_table.read().get(id) {
-            Some(v.iter().map(|(i, _)| *i).collect())
-        } else {
-            None
-        }
-    }
-    pub fn route_one(&self, id: &Ipv4Addr) -> Option<Route> {
-        if let Some((_, v)) = self.route_table.read().get(id) {
-            v.first().map(|(i, _)| *i)
-        } else {
-            None
-        }
-    }
-    pub fn route_one_p2p(&self, id: &Ipv4Addr) -> Option<Route> {
-        if let Some((_, v)) = self.route_table.read().get(id) {
-            for (i, _) in v {
-                if i.is_p2p() {
-                    return Some(*i);
-                }
-            }
-        }
-        None
-    }
-    pub fn route_to_id(&self, route_key: &RouteKey) -> Option<Ipv4Addr> {
-        let table = self.route_table.read();
-        for (k, (_, v)) in table.iter() {
-            for (route, _) in v {
-                if &route.route_key() == route_key && route.is_p2p() {
-                    return Some(*k);
-                }
-            }
-        }
-        None
-    }
-    pub fn no_need_punch(&self, id: &Ipv4Addr) -> bool {
-        if let Some((_, v)) = self.route_table.read().get(id) {
-            //p2p的通道数符合要求
-            return v.iter().filter(|(k, _)| k.is_p2p()).count() >= self.channel_num;
-        }
-        false
-    }
-    pub fn p2p_num(&self, id: &Ipv4Addr) -> usize {
-        if let Some((_, v)) = self.route_table.read().get(id) {
-            v.iter().filter(|(k, _)| k.is_p2p()).count()
-        } else {
-            0
-        }
-    }
-    /// 返回所有路由
-    pub fn route_table(&self) -> Vec<(Ipv4Addr, Vec<Route>)> {
-        let table = self.route_table.read();
-        table
-            .iter()
-            .map(|(k, (_, v))| (k.clone(), v.iter().map(|(i, _)| *i).collect()))
-            .collect()
-    }
-    pub fn route_table_p2p(&self) -> Vec<(Ipv4Addr, Route)> {
-        let table = self.route_table.read();
-        let mut list = Vec::with_capacity(8);
-        for (ip, (_, routes)) in table.iter() {
-            for (route, _) in routes.iter() {
-                if route.is_p2p() {
-                    list.push((*ip, *route));
-                    break;
-                }
-            }
-        }
-        list
-    }
-    pub fn route_table_one(&self) -> Vec<(Ipv4Addr, Route)> {
-        let mut list = Vec::with_capacity(8);
-        let table = self.route_table.read();
-        for (k, (_, v)) in table.iter() {
-            if let Some((route, _)) = v.first() {
-                list.push((*k, *route));
-            }
-        }
-        list
-    }
-    pub fn remove_route(&self, id: &Ipv4Addr, route_key: RouteKey) {
-        let mut write_guard = self.route_table.write();
-        if let Some((_, routes)) = write_guard.get_mut(id) {
-            routes.retain(|(x, _)| x.route_key() != route_key);
-            if routes.is_empty() {
-                write_guard.remove(id);
-            }
-        }
-    }
-    /// 更新路由入栈包的时刻，长时间没有收到数据的路由将会被剔除
-    pub fn update_read_time(&self, id: &Ipv4Addr, route_key: &RouteKey) {
-        if let Some((_, routes)) = self.route_table.read().get(id) {
-            for (route, time) in routes {
-                if &route.route_key() == route_key {
-                    time.store(Instant::now());
-                    break;
-                }
+        if let Some(p) = packet {
+            if let Err(e) = context.send_ipv4_by_flow0(
+                &p.buf,
+                &p.id,
+                p.flow,
+                p.dscp,
+                p.server_addr,
+                p.send_default,
+            ) {
+                log::warn!("模拟延迟发送失败 peer={} {:?}", p.id, e);
             }
         }
     }
@@ -0,0 +1,192 @@
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+
+use parking_lot::Mutex;
+
+use crate::handle::presence::PresenceEvent;
+use crate::handle::{now_time, ConnectStatus, PeerDeviceInfo};
+
+/// 保留的事件数
+const EVENT_CAPACITY: usize = 512;
+/// 每个订阅者未读取的状态事件上限，超出后丢弃新的事件
+const SUBSCRIBER_CAPACITY: usize = 256;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RouteEventKind {
+    // 新增路由
+    Add,
+    // 移除路由
+    Remove,
+    // 路由跳数变化
+    MetricChange,
+    // 发起打洞协商
+    PunchRequest,
+    // 开始打洞
+    Punch,
+    // 打洞失败
+    PunchFail,
+    // 打洞建立了直连
+    PunchSuccess,
+    // 打洞后没有建立直连
+    PunchTimeout,
+    // 首选通道切换
+    Switch,
+    // 本地地址变化，连接迁移
+    Migrate,
+    // 公网地址变化，通知对端重新打洞
+    NatChange,
+}
+
+impl Display for RouteEventKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            RouteEventKind::Add => "add",
+            RouteEventKind::Remove => "remove",
+            RouteEventKind::MetricChange => "metric",
+            RouteEventKind::PunchRequest => "punch-request",
+            RouteEventKind::Punch => "punch",
+            RouteEventKind::PunchFail => "punch-fail",
+            RouteEventKind::PunchSuccess => "punch-success",
+            RouteEventKind::PunchTimeout => "punch-timeout",
+            RouteEventKind::Switch => "switch",
+            RouteEventKind::Migrate => "migrate",
+            RouteEventKind::NatChange => "nat-change",
+        };
+        f.write_str(str)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RouteEvent {
+    // 递增序号，用于增量拉取
+    pub seq: u64,
+    // 毫秒时间戳
+    pub time: u64,
+    pub ip: Ipv4Addr,
+    pub kind: RouteEventKind,
+    pub detail: String,
+}
+
+/// 推送给订阅者的状态变化
+#[derive(Clone, Debug)]
+pub enum StateEvent {
+    // 设备列表更新，revision为服务端下发的版本号
+    PeerList {
+        revision: u16,
+        peers: Vec<PeerDeviceInfo>,
+    },
+    // 路由变化
+    Route(RouteEvent),
+    // 每秒一次的流量统计，为累计值
+    Stats {
+        up_stream: u64,
+        down_stream: u64,
+    },
+    // 连接状态变化
+    Status(ConnectStatus),
+    // 单个对端的上下线、ip和名称变化
+    Presence(PresenceEvent),
+}
+
+/// 状态事件的订阅者，接收端被丢弃后自动移除
+#[derive(Default)]
+pub struct StateSubscribers {
+    num: AtomicUsize,
+    senders: Mutex<Vec<SyncSender<StateEvent>>>,
+}
+
+impl StateSubscribers {
+    pub fn subscribe(&self) -> (SyncSender<StateEvent>, Receiver<StateEvent>) {
+        let (sender, receiver) = sync_channel(SUBSCRIBER_CAPACITY);
+        let mut senders = self.senders.lock();
+        senders.push(sender.clone());
+        self.num.store(senders.len(), Ordering::Release);
+        (sender, receiver)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.num.load(Ordering::Acquire) == 0
+    }
+    pub fn publish(&self, event: StateEvent) {
+        if self.is_empty() {
+            return;
+        }
+        let mut senders = self.senders.lock();
+        senders.retain(|sender| match sender.try_send(event.clone()) {
+            Ok(_) => true,
+            Err(TrySendError::Full(_)) => {
+                log::warn!("状态事件订阅者处理过慢，丢弃事件");
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+        self.num.store(senders.len(), Ordering::Release);
+    }
+}
+
+/// 路由事件记录，环形缓冲，超出容量后丢弃最早的事件
+pub struct RouteEventLog {
+    inner: Mutex<(u64, VecDeque<RouteEvent>)>,
+    // 状态事件的订阅者，路由事件同时推送给订阅者
+    pub subscribers: StateSubscribers,
+}
+
+impl RouteEventLog {
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new((0, VecDeque::with_capacity(EVENT_CAPACITY))),
+            subscribers: StateSubscribers::default(),
+        }
+    }
+    pub fn push(&self, ip: Ipv4Addr, kind: RouteEventKind, detail: String) {
+        let mut guard = self.inner.lock();
+        guard.0 += 1;
+        let seq = guard.0;
+        if guard.1.len() >= EVENT_CAPACITY {
+            guard.1.pop_front();
+        }
+        let event = RouteEvent {
+            seq,
+            time: now_time(),
+            ip,
+            kind,
+            detail,
+        };
+        if !self.subscribers.is_empty() {
+            self.subscribers.publish(StateEvent::Route(event.clone()));
+        }
+        guard.1.push_back(event);
+    }
+    /// 返回序号大于seq的事件，最多limit条
+    pub fn since(&self, seq: u64, limit: usize) -> Vec<RouteEvent> {
+        let guard = self.inner.lock();
+        guard
+            .1
+            .iter()
+            .filter(|v| v.seq > seq)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+#[test]
+fn test_state_subscribers() {
+    let log = RouteEventLog::new();
+    let (_, receiver) = log.subscribers.subscribe();
+    log.push(
+        Ipv4Addr::new(10, 26, 0, 2),
+        RouteEventKind::Add,
+        String::new(),
+    );
+    match receiver.try_recv() {
+        Ok(StateEvent::Route(event)) => assert_eq!(event.kind, RouteEventKind::Add),
+        e => panic!("{:?}", e),
+    }
+    drop(receiver);
+    log.subscribers
+        .publish(StateEvent::Status(ConnectStatus::Connected));
+    assert!(log.subscribers.is_empty());
+}
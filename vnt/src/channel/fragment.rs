@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// 对端能力的有效期，超过这个时间没收到心跳则认为对端不支持
+const PEER_EXPIRE: Duration = Duration::from_secs(60);
+/// 连续多少次探测没有回应就降低探测长度
+const MAX_MISS: u8 = 3;
+/// 确定路径mtu后重新探测的间隔，路径可能已经变化
+const REPROBE_INTERVAL: Duration = Duration::from_secs(600);
+/// 探测失败后依次尝试的长度
+const PROBE_STEPS: [u16; 5] = [1400, 1280, 1024, 768, 548];
+/// 分片重组的超时时间
+const REASSEMBLE_TIMEOUT: Duration = Duration::from_secs(5);
+/// 同时重组的包数量上限
+const MAX_PENDING: usize = 256;
+/// 一个ip包最多的分片数
+pub const MAX_FRAGMENTS: usize = 16;
+
+struct PeerMtu {
+    // 最近一次收到对端支持分片的心跳
+    support: Instant,
+    // 可以不分片发送的ip包长度，None表示不受限
+    limit: Option<u16>,
+    // 探测的目标长度，即本地mtu
+    target: u16,
+    // 正在探测的长度和没有回应的次数
+    probing: Option<(u16, u8)>,
+    next_probe: Instant,
+}
+
+struct Partial {
+    time: Instant,
+    parts: Vec<Option<Vec<u8>>>,
+    received: usize,
+}
+
+/// 对端路径mtu和分片重组。
+/// 通过心跳协商出支持分片的对端后，定时发送填充到本地mtu长度的探测包，没有回应则逐步降低长度，
+/// 得到路径上能通过的最大长度。发往路径mtu较小的对端时，tcp调整mss，其他超长的包分片发送
+#[derive(Default)]
+pub struct PathMtu {
+    // 受限的对端数量，为0时发送路径不加锁
+    limited: AtomicUsize,
+    peers: Mutex<HashMap<Ipv4Addr, PeerMtu>>,
+    id: AtomicU16,
+    pending: Mutex<HashMap<(Ipv4Addr, u16), Partial>>,
+}
+
+impl PathMtu {
+    pub fn set_peer_support(&self, ip: Ipv4Addr, support: bool) {
+        let mut peers = self.peers.lock();
+        if support {
+            let now = Instant::now();
+            peers
+                .entry(ip)
+                .and_modify(|peer| peer.support = now)
+                .or_insert_with(|| PeerMtu {
+                    support: now,
+                    limit: None,
+                    target: 0,
+                    probing: None,
+                    next_probe: now,
+                });
+        } else if peers.remove(&ip).is_some() {
+            self.update_limited(&peers);
+        }
+    }
+    fn update_limited(&self, peers: &HashMap<Ipv4Addr, PeerMtu>) {
+        let count = peers.values().filter(|peer| peer.limit.is_some()).count();
+        self.limited.store(count, Ordering::Relaxed);
+    }
+    /// 发往ip的包不分片时的最大长度
+    pub fn limit(&self, ip: &Ipv4Addr) -> Option<usize> {
+        if self.limited.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        self.peers
+            .lock()
+            .get(ip)
+            .and_then(|peer| peer.limit)
+            .map(|v| v as usize)
+    }
+    pub fn next_id(&self) -> u16 {
+        self.id.fetch_add(1, Ordering::Relaxed)
+    }
+    /// 本轮需要发送探测的对端和探测长度
+    pub fn probe_targets(&self, mtu: u16) -> Vec<(Ipv4Addr, u16)> {
+        let now = Instant::now();
+        let mut peers = self.peers.lock();
+        peers.retain(|_, peer| now.saturating_duration_since(peer.support) < PEER_EXPIRE);
+        let mut list = Vec::new();
+        for (ip, peer) in peers.iter_mut() {
+            match peer.probing {
+                Some((size, miss)) => {
+                    if miss + 1 < MAX_MISS {
+                        peer.probing = Some((size, miss + 1));
+                        list.push((*ip, size));
+                        continue;
+                    }
+                    match PROBE_STEPS.iter().find(|step| **step < size) {
+                        Some(step) => {
+                            peer.probing = Some((*step, 0));
+                            list.push((*ip, *step));
+                        }
+                        None => {
+                            // 最小的长度也不通，可能是对端离线，按最小长度分片
+                            peer.probing = None;
+                            peer.limit = Some(size);
+                            peer.next_probe = now + REPROBE_INTERVAL;
+                        }
+                    }
+                }
+                None => {
+                    if now >= peer.next_probe || peer.target != mtu {
+                        peer.target = mtu;
+                        peer.probing = Some((mtu, 0));
+                        list.push((*ip, mtu));
+                    }
+                }
+            }
+        }
+        self.update_limited(&peers);
+        list
+    }
+    /// 收到探测回应
+    pub fn on_reply(&self, ip: Ipv4Addr, size: u16) {
+        let mut peers = self.peers.lock();
+        if let Some(peer) = peers.get_mut(&ip) {
+            if peer.probing.map(|(v, _)| v) != Some(size) {
+                return;
+            }
+            peer.probing = None;
+            peer.next_probe = Instant::now() + REPROBE_INTERVAL;
+            let limit = if size >= peer.target {
+                None
+            } else {
+                Some(size)
+            };
+            if peer.limit != limit {
+                log::info!("路径mtu peer={} limit={:?}", ip, limit);
+                peer.limit = limit;
+            }
+            self.update_limited(&peers);
+        }
+    }
+    /// 放入一个分片，收齐时返回重组后的ip包
+    pub fn reassemble(
+        &self,
+        source: Ipv4Addr,
+        id: u16,
+        index: u8,
+        count: u8,
+        data: &[u8],
+    ) -> Option<Vec<u8>> {
+        let (index, count) = (index as usize, count as usize);
+        if count > MAX_FRAGMENTS || index >= count {
+            return None;
+        }
+        let now = Instant::now();
+        let mut pending = self.pending.lock();
+        let key = (source, id);
+        if !pending.contains_key(&key) {
+            if pending.len() >= MAX_PENDING {
+                pending.retain(|_, v| now.saturating_duration_since(v.time) < REASSEMBLE_TIMEOUT);
+                if pending.len() >= MAX_PENDING {
+                    return None;
+                }
+            }
+            pending.insert(
+                key,
+                Partial {
+                    time: now,
+                    parts: vec![None; count],
+                    received: 0,
+                },
+            );
+        }
+        let partial = pending.get_mut(&key)?;
+        if partial.parts.len() != count
+            || now.saturating_duration_since(partial.time) >= REASSEMBLE_TIMEOUT
+        {
+            // id回绕后的旧数据
+            *partial = Partial {
+                time: now,
+                parts: vec![None; count],
+                received: 0,
+            };
+        }
+        if partial.parts[index].is_none() {
+            partial.parts[index] = Some(data.to_vec());
+            partial.received += 1;
+        }
+        if partial.received < count {
+            return None;
+        }
+        let partial = pending.remove(&key)?;
+        let mut packet = Vec::with_capacity(partial.parts.iter().flatten().map(|v| v.len()).sum());
+        for part in partial.parts.into_iter().flatten() {
+            packet.extend_from_slice(&part);
+        }
+        Some(packet)
+    }
+}
+
+/// 调整tcp syn包的mss选项，使按这个mss发送的tcp包不超过limit，返回是否修改
+pub fn clamp_mss(ip_packet: &mut [u8], limit: usize) -> bool {
+    if ip_packet.len() < 20 || ip_packet[9] != 6 {
+        return false;
+    }
+    let ihl = (ip_packet[0] & 0x0F) as usize * 4;
+    if ihl < 20 {
+        return false;
+    }
+    // 只处理第一个分片
+    if u16::from_be_bytes([ip_packet[6], ip_packet[7]]) & 0x1FFF != 0 {
+        return false;
+    }
+    let tcp = match ip_packet.get_mut(ihl..) {
+        Some(tcp) if tcp.len() >= 20 => tcp,
+        _ => return false,
+    };
+    if tcp[13] & 0x02 == 0 {
+        return false;
+    }
+    let max_mss = match limit.checked_sub(ihl + 20) {
+        Some(v) => v.min(u16::MAX as usize) as u16,
+        None => return false,
+    };
+    let data_offset = ((tcp[12] >> 4) as usize * 4).min(tcp.len());
+    let mut i = 20;
+    while i + 1 < data_offset {
+        match tcp[i] {
+            0 => break,
+            1 => i += 1,
+            kind => {
+                let len = tcp[i + 1] as usize;
+                if len < 2 || i + len > data_offset {
+                    break;
+                }
+                if kind == 2 && len == 4 {
+                    let mss = u16::from_be_bytes([tcp[i + 2], tcp[i + 3]]);
+                    if mss <= max_mss {
+                        return false;
+                    }
+                    tcp[i + 2..i + 4].copy_from_slice(&max_mss.to_be_bytes());
+                    // 增量更新校验和 RFC1624
+                    let checksum = u16::from_be_bytes([tcp[16], tcp[17]]);
+                    let mut sum = (!checksum) as u32 + (!mss) as u32 + max_mss as u32;
+                    while sum >> 16 != 0 {
+                        sum = (sum & 0xFFFF) + (sum >> 16);
+                    }
+                    tcp[16..18].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+                    return true;
+                }
+                i += len;
+            }
+        }
+    }
+    false
+}
+
+#[test]
+fn test_reassemble() {
+    let path_mtu = PathMtu::default();
+    let source = Ipv4Addr::new(10, 26, 0, 2);
+    assert!(path_mtu.reassemble(source, 1, 1, 2, &[3, 4]).is_none());
+    assert!(path_mtu.reassemble(source, 2, 0, 2, &[9]).is_none());
+    assert_eq!(
+        path_mtu.reassemble(source, 1, 0, 2, &[1, 2]),
+        Some(vec![1, 2, 3, 4])
+    );
+    assert!(path_mtu
+        .reassemble(source, 3, 0, MAX_FRAGMENTS as u8 + 1, &[1])
+        .is_none());
+}
+
+#[test]
+fn test_probe() {
+    let path_mtu = PathMtu::default();
+    let peer = Ipv4Addr::new(10, 26, 0, 3);
+    path_mtu.set_peer_support(peer, true);
+    assert_eq!(path_mtu.probe_targets(1410), vec![(peer, 1410)]);
+    for _ in 1..MAX_MISS {
+        assert_eq!(path_mtu.probe_targets(1410), vec![(peer, 1410)]);
+    }
+    // 没有回应，降低长度
+    assert_eq!(path_mtu.probe_targets(1410), vec![(peer, 1400)]);
+    path_mtu.on_reply(peer, 1400);
+    assert_eq!(path_mtu.limit(&peer), Some(1400));
+    assert!(path_mtu.probe_targets(1410).is_empty());
+}
+
+#[test]
+fn test_clamp_mss() {
+    let mut packet = [0u8; 44];
+    packet[0] = 0x45;
+    packet[9] = 6;
+    packet[20 + 12] = 6 << 4;
+    packet[20 + 13] = 0x02;
+    packet[40..44].copy_from_slice(&[2, 4, 0x05, 0xB4]);
+    let checksum = 0x1234u16;
+    packet[36..38].copy_from_slice(&checksum.to_be_bytes());
+    assert!(clamp_mss(&mut packet, 1300));
+    assert_eq!(u16::from_be_bytes([packet[42], packet[43]]), 1260);
+    // 校验和的变化和mss的变化相抵
+    let new_checksum = u16::from_be_bytes([packet[36], packet[37]]);
+    assert_eq!(
+        ((!new_checksum) as u32 + 1460) % 0xFFFF,
+        ((!checksum) as u32 + 1260) % 0xFFFF
+    );
+    assert!(!clamp_mss(&mut packet, 1300));
+}
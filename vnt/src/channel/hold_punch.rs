@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::channel::RouteKey;
+
+/// 保活间隔的下限，心跳是3秒，更短没有意义
+const MIN_INTERVAL: Duration = Duration::from_secs(5);
+/// 学习时间隔的上限
+const MAX_INTERVAL: Duration = Duration::from_secs(180);
+/// 每次调整的步长
+const STEP: Duration = Duration::from_secs(5);
+/// 保持多少个间隔没有路由超时才延长间隔
+const STABLE_CYCLES: u32 = 3;
+
+/// 打洞成功后的保活。
+/// 省电模式下客户端间的心跳变稀疏，nat上的udp映射可能超时，之后再有数据时需要重新打洞。
+/// 在p2p路由上按间隔发送心跳，间隔从配置值开始逐步延长，路由超时后回退，学习nat的超时时间
+#[derive(Clone)]
+pub struct HoldPunch {
+    inner: Arc<HoldPunchInner>,
+}
+
+struct HoldPunchInner {
+    // 配置的间隔，为0则不开启
+    base: Duration,
+    state: Mutex<HoldState>,
+}
+
+struct HoldState {
+    interval: Duration,
+    // 学习到的上限，超过后路由会超时
+    ceiling: Duration,
+    last_change: Instant,
+    // 本轮调整后是否只靠保活维持了路由
+    kept: bool,
+    // 路由最近一次发送的时间，以及是否是保活包
+    last_send: HashMap<RouteKey, (Instant, bool)>,
+}
+
+impl HoldPunch {
+    pub fn new(secs: u32) -> Self {
+        let base = if secs == 0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs(secs as u64).clamp(MIN_INTERVAL, MAX_INTERVAL)
+        };
+        Self {
+            inner: Arc::new(HoldPunchInner {
+                base,
+                state: Mutex::new(HoldState {
+                    interval: base,
+                    ceiling: MAX_INTERVAL,
+                    last_change: Instant::now(),
+                    kept: false,
+                    last_send: HashMap::new(),
+                }),
+            }),
+        }
+    }
+    pub fn is_enable(&self) -> bool {
+        !self.inner.base.is_zero()
+    }
+    pub fn interval(&self) -> Duration {
+        self.inner.state.lock().interval
+    }
+    /// 在路由上发送了数据，keepalive表示是保活包
+    pub fn sent(&self, route_key: RouteKey, keepalive: bool) {
+        if !self.is_enable() {
+            return;
+        }
+        let mut state = self.inner.state.lock();
+        state
+            .last_send
+            .insert(route_key, (Instant::now(), keepalive));
+        if keepalive {
+            state.kept = true;
+        }
+    }
+    /// 从当前的p2p路由中选出需要保活的，同时按路由存活情况延长间隔
+    pub fn due(&self, routes: &[RouteKey]) -> Vec<RouteKey> {
+        if !self.is_enable() {
+            return Vec::new();
+        }
+        let now = Instant::now();
+        let mut state = self.inner.state.lock();
+        state.last_send.retain(|key, _| routes.contains(key));
+        let interval = state.interval;
+        if state.kept
+            && now.saturating_duration_since(state.last_change) >= interval * STABLE_CYCLES
+            && interval + STEP <= state.ceiling
+        {
+            state.interval = interval + STEP;
+            state.last_change = now;
+            state.kept = false;
+            log::info!("保活间隔延长到{:?}", state.interval);
+        }
+        let interval = state.interval;
+        let mut list = Vec::new();
+        for key in routes {
+            match state.last_send.get(key) {
+                Some((time, _)) => {
+                    if now.saturating_duration_since(*time) >= interval {
+                        list.push(*key);
+                    }
+                }
+                None => {
+                    // 新路由刚打洞成功，从现在开始计时
+                    state.last_send.insert(*key, (now, false));
+                }
+            }
+        }
+        list
+    }
+    /// 路由超时，如果是只靠保活维持的路由，说明间隔超过了nat的超时时间
+    pub fn on_timeout(&self, route_key: &RouteKey) {
+        if !self.is_enable() {
+            return;
+        }
+        let mut state = self.inner.state.lock();
+        let keepalive = match state.last_send.remove(route_key) {
+            Some((_, keepalive)) => keepalive,
+            None => return,
+        };
+        if !keepalive || state.interval <= self.inner.base {
+            return;
+        }
+        let interval = state.interval;
+        state.ceiling = interval.saturating_sub(STEP).max(self.inner.base);
+        state.interval = state.ceiling;
+        state.last_change = Instant::now();
+        state.kept = false;
+        log::info!(
+            "保活间隔{:?}时路由超时，回退到{:?}",
+            interval,
+            state.interval
+        );
+    }
+}
+
+#[test]
+fn test_hold_punch() {
+    let hold_punch = HoldPunch::new(10);
+    let key = RouteKey::new(false, 0, "192.168.1.2:1000".parse().unwrap());
+    // 新路由不需要立即保活
+    assert!(hold_punch.due(&[key]).is_empty());
+    {
+        let mut state = hold_punch.inner.state.lock();
+        state
+            .last_send
+            .insert(key, (Instant::now() - STEP * 3, true));
+        state.last_change = Instant::now() - Duration::from_secs(60);
+        state.kept = true;
+    }
+    assert_eq!(hold_punch.due(&[key]), vec![key]);
+    assert_eq!(hold_punch.interval(), Duration::from_secs(15));
+    hold_punch.on_timeout(&key);
+    assert_eq!(hold_punch.interval(), Duration::from_secs(10));
+    assert_eq!(
+        hold_punch.inner.state.lock().ceiling,
+        Duration::from_secs(10)
+    );
+    assert!(!HoldPunch::new(0).is_enable());
+}
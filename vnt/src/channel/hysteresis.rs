@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::channel::{Route, DEFAULT_RT};
+
+/// 最多保留的未通知的切换记录
+const CHANGE_CAPACITY: usize = 256;
+
+/// 路由迟滞，延迟优势不明显或者刚切换过时保持当前通道，避免在延迟相近的通道间来回切换
+pub struct RouteHysteresis {
+    // 新通道至少要比当前通道低多少延迟(ms)才切换
+    delta: i64,
+    // 两次切换的最小间隔
+    hold_down: Duration,
+    last_switch: Mutex<HashMap<Ipv4Addr, Instant>>,
+    // 首选通道的变化，等待回调通知
+    changes: Mutex<Vec<(Ipv4Addr, Route)>>,
+}
+
+impl RouteHysteresis {
+    pub fn new(delta_ms: u32, hold_down_secs: u32) -> Self {
+        Self {
+            delta: delta_ms as i64,
+            hold_down: Duration::from_secs(hold_down_secs as u64),
+            last_switch: Mutex::new(HashMap::with_capacity(16)),
+            changes: Mutex::new(Vec::with_capacity(16)),
+        }
+    }
+    /// 是否保持当前通道，不切换到best
+    pub fn keep(&self, id: &Ipv4Addr, current: &Route, best: &Route) -> bool {
+        if current.rt == DEFAULT_RT {
+            return false;
+        }
+        if current.rt - best.rt < self.delta {
+            return true;
+        }
+        if let Some(time) = self.last_switch.lock().get(id) {
+            if time.elapsed() < self.hold_down {
+                return true;
+            }
+        }
+        false
+    }
+    /// 记录首选通道的变化
+    pub fn switch(&self, id: Ipv4Addr, route: Route) {
+        self.last_switch.lock().insert(id, Instant::now());
+        let mut changes = self.changes.lock();
+        if changes.len() >= CHANGE_CAPACITY {
+            changes.remove(0);
+        }
+        changes.push((id, route));
+    }
+    pub fn take_changes(&self) -> Vec<(Ipv4Addr, Route)> {
+        std::mem::take(&mut *self.changes.lock())
+    }
+}
+
+#[test]
+fn test_route_hysteresis() {
+    use crate::channel::RouteKey;
+    let id = Ipv4Addr::new(10, 26, 0, 2);
+    let key = RouteKey::new(false, 0, "127.0.0.1:1000".parse().unwrap());
+    let current = Route::from(key, 1, 50);
+    let hysteresis = RouteHysteresis::new(10, 0);
+    assert!(hysteresis.keep(&id, &current, &Route::from(key, 2, 45)));
+    assert!(!hysteresis.keep(&id, &current, &Route::from(key, 2, 30)));
+    let hysteresis = RouteHysteresis::new(0, 60);
+    hysteresis.switch(id, current);
+    assert!(hysteresis.keep(&id, &current, &Route::from(key, 2, 10)));
+    assert_eq!(hysteresis.take_changes().len(), 1);
+    assert!(hysteresis.take_changes().is_empty());
+}
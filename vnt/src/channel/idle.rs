@@ -1,5 +1,9 @@
 use std::net::Ipv4Addr;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossbeam_utils::atomic::AtomicCell;
 
 use crate::channel::context::ChannelContext;
 use crate::channel::Route;
@@ -9,6 +13,89 @@ pub struct Idle {
     context: ChannelContext,
 }
 
+/// 省电模式，一段时间没有用户数据时放大心跳、打洞等定时任务的间隔，
+/// 和服务端的心跳不受影响，有数据后立即恢复
+#[derive(Clone)]
+pub struct PowerSave {
+    inner: Arc<PowerSaveInner>,
+}
+
+struct PowerSaveInner {
+    // 无数据多久后进入省电模式，为0则不开启
+    idle_time: Duration,
+    // 省电模式下间隔放大的倍数
+    multiple: usize,
+    last_active: AtomicCell<Instant>,
+    saving: AtomicBool,
+    // 由外部设置的低功耗状态(如Android的Doze)，不受数据收发影响
+    low_power: AtomicBool,
+}
+
+impl PowerSave {
+    pub fn new(idle_minutes: u32, multiple: u32) -> Self {
+        Self {
+            inner: Arc::new(PowerSaveInner {
+                idle_time: Duration::from_secs(idle_minutes as u64 * 60),
+                multiple: multiple.max(1) as usize,
+                last_active: AtomicCell::new(Instant::now()),
+                saving: AtomicBool::new(false),
+                low_power: AtomicBool::new(false),
+            }),
+        }
+    }
+    pub fn is_enable(&self) -> bool {
+        !self.inner.idle_time.is_zero()
+    }
+    /// 有用户数据收发
+    pub fn active(&self) {
+        if self.is_enable() {
+            self.inner.last_active.store(Instant::now());
+        }
+    }
+    /// 进入或退出低功耗状态，低功耗时按省电模式放大间隔，并暂停打洞
+    pub fn set_low_power(&self, low_power: bool) {
+        if self.inner.low_power.swap(low_power, Ordering::Relaxed) != low_power {
+            if low_power {
+                log::info!("进入低功耗模式");
+            } else {
+                log::info!("退出低功耗模式");
+            }
+        }
+    }
+    pub fn is_low_power(&self) -> bool {
+        self.inner.low_power.load(Ordering::Relaxed)
+    }
+    pub fn is_saving(&self) -> bool {
+        if self.is_low_power() {
+            return true;
+        }
+        if !self.is_enable() {
+            return false;
+        }
+        let saving = self.inner.last_active.load().elapsed() >= self.inner.idle_time;
+        if self.inner.saving.swap(saving, Ordering::Relaxed) != saving {
+            if saving {
+                log::info!("{:?}无数据,进入省电模式", self.inner.idle_time);
+            } else {
+                log::info!("退出省电模式");
+            }
+        }
+        saving
+    }
+    /// 第count次执行的定时任务是否跳过
+    pub fn skip(&self, count: usize) -> bool {
+        self.is_saving() && count % self.inner.multiple != 0
+    }
+    /// 省电模式下放大空闲时间
+    pub fn idle_time(&self, duration: Duration) -> Duration {
+        if self.is_saving() {
+            duration * self.inner.multiple as u32
+        } else {
+            duration
+        }
+    }
+}
+
 impl Idle {
     pub fn new(read_idle: Duration, context: ChannelContext) -> Self {
         Self { read_idle, context }
@@ -25,21 +112,29 @@ impl Idle {
     /// 获取空闲路由
     pub fn next_idle(&self) -> IdleType {
         let mut max = Duration::from_secs(0);
-        let read_guard = self.context.route_table.route_table.read();
-        if read_guard.is_empty() {
+        // 省电模式下心跳间隔变长，相应的延长超时时间
+        let read_idle = self.context.power_save.idle_time(self.read_idle);
+        let routes = self.context.route_table.route_read_time();
+        if routes.is_empty() {
             return IdleType::None;
         }
-        for (ip, (_, routes)) in read_guard.iter() {
-            for (route, time) in routes {
-                let last_read = time.load().elapsed();
-                if last_read >= self.read_idle {
-                    return IdleType::Timeout(*ip, *route);
-                } else if max < last_read {
-                    max = last_read;
-                }
+        for (ip, route, time) in routes {
+            // 单向路由收不到数据，由对端定期确认
+            if self
+                .context
+                .asym_paths
+                .is_send_only(&ip, &route.route_key())
+            {
+                continue;
+            }
+            let last_read = time.elapsed();
+            if last_read >= read_idle {
+                return IdleType::Timeout(ip, route);
+            } else if max < last_read {
+                max = last_read;
             }
         }
-        let sleep_time = self.read_idle - max;
+        let sleep_time = read_idle - max;
         return IdleType::Sleep(sleep_time);
     }
 }
@@ -0,0 +1,140 @@
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crossbeam_utils::atomic::AtomicCell;
+use parking_lot::Mutex;
+
+use crate::channel::RouteKey;
+
+/// 同一个ip的两个实例交替出现的时间窗口(ms)，超过时视为对端重启
+const FLIP_WINDOW: u64 = 120_000;
+
+// 对端ip最近看到的实例
+struct Seen {
+    current: (u64, RouteKey),
+    previous: Option<(u64, RouteKey)>,
+    changed: u64,
+}
+
+/// 虚拟ip冲突检测。每个实例启动时生成随机的实例id，定时向对端和自己的ip通告，
+/// 收到自己的ip但实例id不同，或者对端发现同一个ip的两个实例交替出现并通知时，认为发生了冲突。
+/// 克隆了配置(相同的设备id)的两台设备会拿到同一个ip，表现为时断时通
+pub struct IpConflict {
+    instance_id: u64,
+    // 冲突时是否重新申请ip
+    reassign: bool,
+    reassigned: AtomicBool,
+    // 检测到冲突的对方实例id，等待定时任务处理
+    detected: AtomicCell<Option<u64>>,
+    peers: Mutex<HashMap<Ipv4Addr, Seen>>,
+}
+
+impl IpConflict {
+    pub fn new(reassign: bool) -> Self {
+        Self {
+            instance_id: rand::random(),
+            reassign,
+            reassigned: AtomicBool::new(false),
+            detected: AtomicCell::new(None),
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+    pub fn instance_id(&self) -> u64 {
+        self.instance_id
+    }
+    pub fn is_reassign(&self) -> bool {
+        self.reassign
+    }
+    /// 检测到和另一个实例冲突
+    pub(crate) fn detect(&self, other: u64) {
+        if other != self.instance_id {
+            self.detected.store(Some(other));
+        }
+    }
+    pub(crate) fn take_detected(&self) -> Option<u64> {
+        self.detected.take()
+    }
+    /// 冲突双方中实例id较大的一方重新申请ip，避免两边同时更换
+    pub(crate) fn should_reassign(&self, other: u64) -> bool {
+        self.reassign && self.instance_id > other && !self.is_reassigned()
+    }
+    pub(crate) fn set_reassigned(&self) {
+        self.reassigned.store(true, Ordering::Relaxed);
+    }
+    pub fn is_reassigned(&self) -> bool {
+        self.reassigned.load(Ordering::Relaxed)
+    }
+    /// 重新申请ip时使用的设备id，服务端按设备id分配ip
+    pub fn device_id(&self, device_id: &str) -> String {
+        if self.is_reassigned() {
+            format!("{}-{:08x}", device_id, self.instance_id as u32)
+        } else {
+            device_id.to_string()
+        }
+    }
+    /// 记录对端ip的通告，同一个ip的两个实例交替出现时，
+    /// 返回需要通知的(路由，对方的实例id)
+    pub(crate) fn on_announce(
+        &self,
+        ip: Ipv4Addr,
+        instance_id: u64,
+        route_key: RouteKey,
+        now: u64,
+    ) -> Option<[(RouteKey, u64); 2]> {
+        let mut guard = self.peers.lock();
+        let seen = guard.entry(ip).or_insert(Seen {
+            current: (instance_id, route_key),
+            previous: None,
+            changed: now,
+        });
+        if seen.current.0 == instance_id {
+            seen.current.1 = route_key;
+            return None;
+        }
+        let flip = matches!(seen.previous, Some((id, _)) if id == instance_id)
+            && now.saturating_sub(seen.changed) < FLIP_WINDOW;
+        let old = seen.current;
+        seen.previous = Some(old);
+        seen.current = (instance_id, route_key);
+        seen.changed = now;
+        if flip {
+            Some([(route_key, old.0), (old.1, instance_id)])
+        } else {
+            None
+        }
+    }
+    /// 去掉已经不在设备列表中的对端
+    pub(crate) fn retain(&self, ips: &HashSet<Ipv4Addr>) {
+        self.peers.lock().retain(|ip, _| ips.contains(ip));
+    }
+}
+
+#[test]
+fn test_ip_conflict() {
+    let conflict = IpConflict::new(true);
+    let ip = Ipv4Addr::new(10, 26, 0, 2);
+    let a = RouteKey::new(false, 0, "1.1.1.1:1000".parse().unwrap());
+    let b = RouteKey::new(false, 0, "2.2.2.2:2000".parse().unwrap());
+    assert!(conflict.on_announce(ip, 1, a, 0).is_none());
+    assert!(conflict.on_announce(ip, 1, a, 1000).is_none());
+    // 对端重启，实例id变化一次不算冲突
+    assert!(conflict.on_announce(ip, 2, b, 2000).is_none());
+    // 旧的实例再次出现
+    let notify = conflict.on_announce(ip, 1, a, 3000).unwrap();
+    assert_eq!(notify, [(a, 2), (b, 1)]);
+    // 超出时间窗口
+    assert!(conflict.on_announce(ip, 2, b, 3000 + FLIP_WINDOW).is_none());
+
+    conflict.detect(conflict.instance_id());
+    assert!(conflict.take_detected().is_none());
+    conflict.detect(0);
+    assert_eq!(conflict.take_detected(), Some(0));
+    assert!(conflict.take_detected().is_none());
+    assert_eq!(conflict.device_id("dev"), "dev");
+    if conflict.should_reassign(0) {
+        conflict.set_reassigned();
+        assert!(conflict.device_id("dev").starts_with("dev-"));
+        assert!(!conflict.should_reassign(0));
+    }
+}
@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// 某个设备到另一个设备的路由情况
+#[derive(Copy, Clone, Debug)]
+pub struct PeerRoute {
+    pub ip: Ipv4Addr,
+    pub p2p: bool,
+    pub metric: u8,
+    pub rt: i64,
+}
+
+/// 可达矩阵，记录其他设备上报的到各设备的路由
+pub struct RouteMatrix {
+    reports: Mutex<HashMap<Ipv4Addr, (Instant, Vec<PeerRoute>)>>,
+}
+
+impl RouteMatrix {
+    pub fn new() -> Self {
+        Self {
+            reports: Mutex::new(HashMap::with_capacity(16)),
+        }
+    }
+    pub fn update(&self, ip: Ipv4Addr, list: Vec<PeerRoute>) {
+        self.reports.lock().insert(ip, (Instant::now(), list));
+    }
+    /// 经其他设备中转到target时延迟最低的设备，local为本机直连的设备和延迟。
+    /// 延迟按 本机到中转设备 + 中转设备上报的到target的直连延迟 计算，
+    /// 超过max_age的上报和没有直连target的设备不参与
+    pub fn best_relay(
+        &self,
+        local: &[(Ipv4Addr, i64)],
+        target: Ipv4Addr,
+        max_age: Duration,
+    ) -> Option<Ipv4Addr> {
+        self.best_relay_at(local, target, max_age, Instant::now())
+    }
+    fn best_relay_at(
+        &self,
+        local: &[(Ipv4Addr, i64)],
+        target: Ipv4Addr,
+        max_age: Duration,
+        now: Instant,
+    ) -> Option<Ipv4Addr> {
+        let guard = self.reports.lock();
+        local
+            .iter()
+            .filter(|(ip, _)| *ip != target)
+            .filter_map(|(ip, rt)| {
+                let (time, list) = guard.get(ip)?;
+                if now.saturating_duration_since(*time) >= max_age {
+                    return None;
+                }
+                let route = list.iter().find(|v| v.ip == target && v.p2p)?;
+                Some((*ip, rt + route.rt))
+            })
+            .min_by_key(|(_, rt)| *rt)
+            .map(|(ip, _)| ip)
+    }
+    /// 返回最近max_age内收到的上报，过期的直接清除
+    pub fn reports(&self, max_age: Duration) -> Vec<(Ipv4Addr, Vec<PeerRoute>)> {
+        let mut guard = self.reports.lock();
+        guard.retain(|_, (time, _)| time.elapsed() < max_age);
+        guard
+            .iter()
+            .map(|(ip, (_, list))| (*ip, list.clone()))
+            .collect()
+    }
+}
+
+#[test]
+fn test_best_relay() {
+    let matrix = RouteMatrix::new();
+    let target = Ipv4Addr::new(10, 26, 0, 9);
+    let (a, b, c) = (
+        Ipv4Addr::new(10, 26, 0, 2),
+        Ipv4Addr::new(10, 26, 0, 3),
+        Ipv4Addr::new(10, 26, 0, 4),
+    );
+    let route = |p2p: bool, rt: i64| PeerRoute {
+        ip: target,
+        p2p,
+        metric: if p2p { 1 } else { 2 },
+        rt,
+    };
+    matrix.update(a, vec![route(true, 50)]);
+    matrix.update(b, vec![route(true, 10)]);
+    // c只能经服务端到达target
+    matrix.update(c, vec![route(false, 5)]);
+    let local = [(a, 10), (b, 30), (c, 1)];
+    let max_age = Duration::from_secs(60);
+    let now = Instant::now();
+    // 30+10 < 10+50
+    assert_eq!(matrix.best_relay_at(&local, target, max_age, now), Some(b));
+    // 本机到b的延迟变高后改用a
+    let local = [(a, 10), (b, 80), (c, 1)];
+    assert_eq!(matrix.best_relay_at(&local, target, max_age, now), Some(a));
+    // 没有a的上报时只剩b
+    let local = [(b, 80), (c, 1), (Ipv4Addr::new(10, 26, 0, 5), 1)];
+    assert_eq!(matrix.best_relay_at(&local, target, max_age, now), Some(b));
+    // 上报过期后都不可用
+    let later = now + max_age + Duration::from_secs(1);
+    assert_eq!(matrix.best_relay_at(&local, target, max_age, later), None);
+    // 较新的上报覆盖旧的
+    matrix.update(a, vec![route(true, 1)]);
+    let local = [(a, 10), (b, 30)];
+    assert_eq!(matrix.best_relay_at(&local, target, max_age, now), Some(a));
+}
@@ -1,24 +1,76 @@
 use std::io;
-use std::net::{SocketAddr, UdpSocket};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
 use std::str::FromStr;
 
+use crate::channel::broadcast_scope::BroadcastScope;
+use crate::channel::coalesce::Coalesce;
 use crate::channel::context::ChannelContext;
 use crate::channel::handler::RecvChannelHandler;
+use crate::channel::hold_punch::HoldPunch;
+use crate::channel::hysteresis::RouteHysteresis;
+use crate::channel::idle::PowerSave;
+use crate::channel::ip_conflict::IpConflict;
+use crate::channel::netem::WanRule;
+use crate::channel::padding::Padding;
+use crate::channel::peer_auth::PeerAuth;
+use crate::channel::peer_filter::PeerFilter;
+use crate::channel::qos::Qos;
+use crate::channel::relay_meter::RelayMeter;
+use crate::channel::send_queue::SendQueueConfig;
 use crate::channel::sender::AcceptSocketSender;
+use crate::channel::server_config::ServerConfig;
+use crate::channel::sock_buf::SocketBuf;
+use crate::channel::subnet::SubnetRoutes;
 use crate::channel::tcp_channel::tcp_listen;
 use crate::channel::udp_channel::udp_listen;
+use crate::channel::vlan::VlanTable;
+use crate::cipher::PairwiseCipher;
 use crate::util::{io_convert, StopManager};
 
+pub mod acl;
+pub mod asym_path;
+pub mod broadcast_scope;
+pub mod coalesce;
+pub mod conn_id;
 pub mod context;
+pub mod event;
+pub mod fragment;
 pub mod handler;
+pub mod hold_punch;
+pub mod hysteresis;
 pub mod idle;
+pub mod ip_conflict;
+pub mod matrix;
+pub mod netem;
 pub mod notify;
+pub mod pacing;
+pub mod padding;
+pub mod peer_auth;
+pub mod peer_filter;
 pub mod punch;
+pub mod punch_log;
+pub mod qos;
+pub mod queue_depth;
+pub mod relay_meter;
+pub mod route_table;
+pub mod send_queue;
 pub mod sender;
+pub mod server_config;
+pub mod sock_buf;
+pub mod subnet;
 pub mod tcp_channel;
+pub mod traffic;
 pub mod udp_channel;
+#[cfg(unix)]
+pub mod uds_channel;
+pub mod unreachable;
+pub mod vlan;
+#[cfg(all(target_os = "linux", feature = "xdp"))]
+pub mod xdp;
 
 const BUFFER_SIZE: usize = 1024 * 16;
+/// unix域套接字通道的路由序号
+pub(crate) const UDS_INDEX: usize = usize::MAX;
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum UseChannelType {
     Relay,
@@ -54,6 +106,39 @@ impl Default for UseChannelType {
     }
 }
 
+/// 存在多条p2p通道时的使用方式
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LoadBalanceModel {
+    /// 只使用最优的通道
+    None,
+    /// 按数据流哈希分散到延迟相近的多条通道，同一条流(如tcp连接)固定走一条通道
+    Flow,
+    /// 只使用最优的通道，发送失败时立即切换到下一条通道
+    Failover,
+}
+impl LoadBalanceModel {
+    pub fn is_none(&self) -> bool {
+        self == &LoadBalanceModel::None
+    }
+}
+impl FromStr for LoadBalanceModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().trim() {
+            "none" => Ok(LoadBalanceModel::None),
+            "flow" => Ok(LoadBalanceModel::Flow),
+            "failover" => Ok(LoadBalanceModel::Failover),
+            _ => Err(format!("not match '{}', enum: none/flow/failover", s)),
+        }
+    }
+}
+impl Default for LoadBalanceModel {
+    fn default() -> Self {
+        LoadBalanceModel::None
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct Route {
     pub is_tcp: bool,
@@ -69,6 +154,8 @@ pub struct RouteSortKey {
     pub rt: i64,
 }
 const DEFAULT_RT: i64 = 9999;
+/// 新样本占1/4
+const RT_SMOOTH: i64 = 4;
 impl Route {
     pub fn new(is_tcp: bool, index: usize, addr: SocketAddr, metric: u8, rt: i64) -> Self {
         Self {
@@ -113,6 +200,18 @@ impl Route {
     pub fn is_p2p(&self) -> bool {
         self.metric == 1
     }
+    /// 和同一台主机上的实例之间的unix域套接字通道
+    pub fn is_uds(&self) -> bool {
+        !self.is_tcp && self.index == UDS_INDEX
+    }
+    /// 指数加权平均，减少单次抖动对选路的影响
+    pub(crate) fn update_rt(&mut self, rt: i64) {
+        if self.rt == DEFAULT_RT || rt == DEFAULT_RT {
+            self.rt = rt;
+        } else {
+            self.rt = (self.rt * (RT_SMOOTH - 1) + rt + RT_SMOOTH / 2) / RT_SMOOTH;
+        }
+    }
 }
 
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
@@ -145,43 +244,68 @@ pub fn init_context(
     is_tcp: bool,
     packet_loss_rate: Option<f64>,
     packet_delay: u32,
+    load_balance: LoadBalanceModel,
+    anti_replay: bool,
+    power_save: PowerSave,
+    pairwise_cipher: PairwiseCipher,
+    coalesce: Coalesce,
+    route_hysteresis: RouteHysteresis,
+    peer_auth: PeerAuth,
+    listen: Option<IpAddr>,
+    qos: Qos,
+    hold_punch: HoldPunch,
+    wan_rules: Vec<WanRule>,
+    relay_meter: RelayMeter,
+    peer_filter: PeerFilter,
+    subnet_routes: SubnetRoutes,
+    padding: Padding,
+    server_config: ServerConfig,
+    vlan: VlanTable,
+    ip_conflict: IpConflict,
+    send_queue: SendQueueConfig,
+    broadcast_scope: BroadcastScope,
+    socket_buf: SocketBuf,
 ) -> io::Result<(ChannelContext, mio::net::TcpListener)> {
     assert!(!ports.is_empty(), "not channel");
     let mut udps = Vec::with_capacity(ports.len());
-    //检查系统是否支持ipv6
-    let use_ipv6 = match socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::DGRAM, None) {
-        Ok(_) => true,
-        Err(e) => {
-            log::warn!("{:?}", e);
-            false
-        }
+    //检查系统是否支持ipv6，指定了ipv4监听地址时只使用ipv4
+    let use_ipv6 = match listen {
+        Some(IpAddr::V4(_)) => false,
+        _ => match socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::DGRAM, None) {
+            Ok(_) => true,
+            Err(e) => {
+                log::warn!("{:?}", e);
+                false
+            }
+        },
     };
+    let listen_ip = listen.unwrap_or(if use_ipv6 {
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    } else {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    });
     for port in &ports {
-        //监听v6+v4双栈
-        let (socket, address) = if use_ipv6 {
-            let address: SocketAddr = format!("[::]:{}", port).parse().unwrap();
+        let address = SocketAddr::new(listen_ip, *port);
+        let socket = if use_ipv6 {
             let socket = socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::DGRAM, None)?;
-            io_convert(socket.set_only_v6(false), |_| {
+            //监听所有地址时使用v6+v4双栈
+            io_convert(socket.set_only_v6(!listen_ip.is_unspecified()), |_| {
                 format!("set_only_v6 failed: {}", &address)
             })?;
-            (socket, address)
+            socket
         } else {
-            let address: SocketAddr = format!("0.0.0.0:{}", port).parse().unwrap();
-            (
-                socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::DGRAM, None)?,
-                address,
-            )
+            socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::DGRAM, None)?
         };
 
-        io_convert(socket.set_send_buffer_size(2 * 1024 * 1024), |_| {
-            format!("set_send_buffer_size failed: {}", &address)
-        })?;
-        io_convert(socket.set_recv_buffer_size(2 * 1024 * 1024), |_| {
-            format!("set_recv_buffer_size failed: {}", &address)
-        })?;
+        socket_buf.apply(socket2::SockRef::from(&socket));
         io_convert(socket.bind(&address.into()), |_| {
             format!("bind failed: {}", &address)
         })?;
+        // 同一NAT下的对端通过局域网广播辅助打洞
+        if let Err(e) = socket.set_broadcast(true) {
+            log::warn!("set_broadcast failed: {} {:?}", &address, e);
+        }
+        qos.mark(socket2::SockRef::from(&socket));
         let main_channel: UdpSocket = socket.into();
         udps.push(main_channel);
     }
@@ -193,31 +317,56 @@ pub fn init_context(
         packet_loss_rate,
         packet_delay,
         use_ipv6,
+        load_balance,
+        anti_replay,
+        power_save,
+        pairwise_cipher,
+        coalesce,
+        route_hysteresis,
+        peer_auth,
+        listen,
+        qos,
+        hold_punch,
+        wan_rules,
+        relay_meter,
+        peer_filter,
+        subnet_routes,
+        padding,
+        server_config,
+        vlan,
+        ip_conflict,
+        send_queue,
+        broadcast_scope,
+        socket_buf,
     );
 
     let port = context.main_local_udp_port()?[0];
     //监听v6+v4双栈，tcp通道使用异步io
-    let (socket, address) = if use_ipv6 {
-        let address: SocketAddr = format!("[::]:{}", port).parse().unwrap();
+    let address = SocketAddr::new(listen_ip, port);
+    let socket = if use_ipv6 {
         let socket = socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::STREAM, None)?;
-        io_convert(socket.set_only_v6(false), |_| {
+        io_convert(socket.set_only_v6(!listen_ip.is_unspecified()), |_| {
             format!("set_only_v6 failed: {}", &address)
         })?;
-        (socket, address)
+        socket
     } else {
-        let address: SocketAddr = format!("0.0.0.0:{}", port).parse().unwrap();
-        let socket = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None)?;
-        (socket, address)
+        socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None)?
     };
+    // 接入的tcp连接继承监听socket的标记
+    context.qos.mark(socket2::SockRef::from(&socket));
+    // 打洞时主动连接也绑定这个端口，复用监听端口在nat上的映射
+    if let Err(e) = set_reuse(&socket) {
+        log::warn!("设置tcp端口复用失败 {:?}", e);
+    }
 
     if let Err(e) = socket.bind(&address.into()) {
         if ports[0] == 0 {
             //端口可能冲突，则使用任意端口
             log::warn!("监听tcp端口失败 {:?},重试一次", address);
-            let address: SocketAddr = if use_ipv6 {
-                format!("[::]:{}", 0).parse().unwrap()
+            let address = if use_ipv6 {
+                SocketAddr::new(listen_ip, 0)
             } else {
-                format!("0.0.0.0:{}", port).parse().unwrap()
+                SocketAddr::new(listen_ip, port)
             };
             io_convert(socket.bind(&address.into()), |_| {
                 format!("bind failed: {}", &address)
@@ -234,11 +383,20 @@ pub fn init_context(
     Ok((context, tcp_listener))
 }
 
+/// 设置SO_REUSEADDR/SO_REUSEPORT
+pub(crate) fn set_reuse(socket: &socket2::Socket) -> io::Result<()> {
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    Ok(())
+}
+
 pub fn init_channel<H>(
     tcp_listener: mio::net::TcpListener,
     context: ChannelContext,
     stop_manager: StopManager,
     recv_handler: H,
+    xdp: Option<String>,
 ) -> io::Result<(
     AcceptSocketSender<Option<Vec<mio::net::UdpSocket>>>,
     AcceptSocketSender<(mio::net::TcpStream, SocketAddr, Option<Vec<u8>>)>,
@@ -249,6 +407,21 @@ where
     // udp监听，udp_socket_sender 用于NAT类型切换
     let udp_socket_sender =
         udp_listen(stop_manager.clone(), recv_handler.clone(), context.clone())?;
+    // 实验性的AF_XDP接收加速，和普通的udp接收同时存在
+    #[cfg(all(target_os = "linux", feature = "xdp"))]
+    if let Some(interface) = xdp {
+        xdp::xdp_listen(
+            interface,
+            stop_manager.clone(),
+            recv_handler.clone(),
+            context.clone(),
+        )?;
+    }
+    #[cfg(not(all(target_os = "linux", feature = "xdp")))]
+    let _ = xdp;
+    // 同一台主机上的实例之间走unix域套接字
+    #[cfg(unix)]
+    uds_channel::uds_listen(stop_manager.clone(), recv_handler.clone(), context.clone())?;
     // 建立tcp监听，tcp_socket_sender 用于tcp 直连
     let tcp_socket_sender = tcp_listen(
         tcp_listener,
@@ -0,0 +1,516 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fmt::{Display, Formatter};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::time::{Duration, Instant};
+
+use parking_lot::{Condvar, Mutex, RwLock};
+use rand::Rng;
+
+/// 概率的分母，取值v=[0,100_0000] 概率r=v/100_0000
+const RATE_DENOMINATOR: u32 = 100_0000;
+/// 没有基础延迟时，乱序的包额外延迟的时间，让后面的包先发出
+const REORDER_HOLD: Duration = Duration::from_millis(10);
+/// 延迟队列的上限，超过后丢弃，避免内存无限增长
+const MAX_QUEUE: usize = 4096;
+
+/// 模拟弱网的规则，destination为空时对所有目标生效
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WanRule {
+    pub destination: Option<Ipv4Addr>,
+    // 丢包率
+    pub loss: u32,
+    // 延迟(ms)
+    pub delay: u32,
+    // 延迟的抖动范围(ms)，实际延迟在delay±jitter之间
+    pub jitter: u32,
+    // 乱序率
+    pub reorder: u32,
+}
+
+fn parse_rate(key: &str, value: &str) -> Result<u32, String> {
+    let v = f64::from_str(value).map_err(|e| format!("{}={} {}", key, value, e))?;
+    if !(0.0..=1.0).contains(&v) {
+        return Err(format!("{}={} out of range 0~1", key, value));
+    }
+    Ok((v * RATE_DENOMINATOR as f64) as u32)
+}
+
+impl FromStr for WanRule {
+    type Err = String;
+
+    /// 格式：目标ip或*,loss=0.1,delay=100,jitter=20,reorder=0.01
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',').map(|v| v.trim());
+        let destination = match parts.next().unwrap_or_default() {
+            "*" | "" => None,
+            ip => Some(Ipv4Addr::from_str(ip).map_err(|e| format!("'{}' {}", ip, e))?),
+        };
+        let mut rule = WanRule {
+            destination,
+            loss: 0,
+            delay: 0,
+            jitter: 0,
+            reorder: 0,
+        };
+        for part in parts {
+            let (key, value) = match part.split_once('=') {
+                Some(v) => v,
+                None => return Err(format!("'{}' expected key=value", part)),
+            };
+            let parse_ms =
+                |value: &str| u32::from_str(value).map_err(|e| format!("{}={} {}", key, value, e));
+            match key {
+                "loss" => rule.loss = parse_rate(key, value)?,
+                "delay" => rule.delay = parse_ms(value)?,
+                "jitter" => rule.jitter = parse_ms(value)?,
+                "reorder" => rule.reorder = parse_rate(key, value)?,
+                _ => {
+                    return Err(format!(
+                        "unknown '{}', supported: loss,delay,jitter,reorder",
+                        key
+                    ))
+                }
+            }
+        }
+        Ok(rule)
+    }
+}
+
+impl Display for WanRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.destination {
+            Some(ip) => write!(f, "{}", ip)?,
+            None => f.write_str("*")?,
+        }
+        write!(
+            f,
+            ",loss={},delay={},jitter={},reorder={}",
+            self.loss as f64 / RATE_DENOMINATOR as f64,
+            self.delay,
+            self.jitter,
+            self.reorder as f64 / RATE_DENOMINATOR as f64
+        )
+    }
+}
+
+pub enum Verdict {
+    Send,
+    Drop,
+    Delay(Duration),
+}
+
+/// 延迟发送的数据
+pub struct DelayedPacket {
+    pub buf: Vec<u8>,
+    pub id: Ipv4Addr,
+    pub flow: u32,
+    pub dscp: Option<u8>,
+    pub server_addr: SocketAddr,
+    pub send_default: bool,
+}
+
+struct Delayed {
+    due: Instant,
+    seq: u64,
+    packet: DelayedPacket,
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Self) -> bool {
+        self.due == other.due && self.seq == other.seq
+    }
+}
+
+impl Eq for Delayed {}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Delayed {
+    // 最早到期的排在堆顶
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .due
+            .cmp(&self.due)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+#[derive(Default)]
+struct DelayQueue {
+    heap: BinaryHeap<Delayed>,
+    seq: u64,
+}
+
+/// 计划中的一个阶段，持续duration，rules为空表示不模拟
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WanPhase {
+    pub duration: Duration,
+    pub rules: Vec<WanRule>,
+}
+
+/// 按时间切换的弱网计划，用于可重复的混沌测试
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WanSchedule {
+    pub phases: Vec<WanPhase>,
+    // 执行完后从头循环，否则恢复启动参数的规则
+    pub repeat: bool,
+}
+
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => s.split_at(index),
+        None => (s, "s"),
+    };
+    let num = u64::from_str(num).map_err(|e| format!("'{}' {}", s, e))?;
+    let secs = match unit {
+        "s" => num,
+        "m" => num * 60,
+        "h" => num * 3600,
+        _ => return Err(format!("'{}' unit must be s/m/h", s)),
+    };
+    if secs == 0 {
+        return Err(format!("'{}' must be greater than 0", s));
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+impl FromStr for WanSchedule {
+    type Err = String;
+
+    /// 每行一个阶段：时长 规则;规则，时长单位为s/m/h，规则为clean表示不模拟，
+    /// 最后一行为repeat时循环执行，#开头的行为注释。如：
+    /// 5m clean
+    /// 2m *,loss=0.1
+    /// 1m *,delay=300
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut phases = Vec::new();
+        let mut repeat = false;
+        for (index, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if repeat {
+                return Err(format!("line {}: repeat must be the last line", index + 1));
+            }
+            if line == "repeat" {
+                repeat = true;
+                continue;
+            }
+            let (duration, rules) = match line.split_once(char::is_whitespace) {
+                Some((duration, rules)) => (duration, rules.trim()),
+                None => return Err(format!("line {}: expected '<duration> <rules>'", index + 1)),
+            };
+            let duration =
+                parse_duration(duration).map_err(|e| format!("line {}: {}", index + 1, e))?;
+            let rules = if rules == "clean" {
+                Vec::new()
+            } else {
+                rules
+                    .split(';')
+                    .map(WanRule::from_str)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| format!("line {}: {}", index + 1, e))?
+            };
+            phases.push(WanPhase { duration, rules });
+        }
+        if phases.is_empty() {
+            return Err("schedule has no phase".to_string());
+        }
+        Ok(WanSchedule { phases, repeat })
+    }
+}
+
+/// 当前生效的规则和计划的进度
+#[derive(Clone, Debug)]
+pub struct WanSimStatus {
+    pub rules: Vec<WanRule>,
+    // (当前阶段，阶段总数，剩余时间，是否循环)
+    pub phase: Option<(usize, usize, Duration, bool)>,
+}
+
+struct Running {
+    schedule: WanSchedule,
+    index: usize,
+    start: Instant,
+}
+
+/// 指定目标的规则优先，去掉不起作用的规则
+fn normalize(mut rules: Vec<WanRule>) -> Vec<WanRule> {
+    rules.sort_by_key(|v| v.destination.is_none());
+    rules.retain(|v| v.loss > 0 || v.delay > 0 || v.jitter > 0 || v.reorder > 0);
+    rules
+}
+
+/// 模拟弱网，按目标匹配规则，丢包或延迟发送。
+/// 延迟的包放入队列由单独的线程发送，不阻塞其他目标的数据。
+/// 规则可以在运行中修改，也可以按计划分阶段切换
+pub struct WanSim {
+    // 启动参数的规则，计划结束后恢复
+    base: Vec<WanRule>,
+    rules: RwLock<Vec<WanRule>>,
+    enable: AtomicBool,
+    running: Mutex<Option<Running>>,
+    // 发送延迟包的线程是否已经启动
+    started: AtomicBool,
+    queue: Mutex<DelayQueue>,
+    condvar: Condvar,
+}
+
+impl WanSim {
+    /// packet_loss_rate和packet_delay是全局的旧参数，没有指定*规则时作为默认规则
+    pub fn new(packet_loss_rate: Option<f64>, packet_delay: u32, mut rules: Vec<WanRule>) -> Self {
+        let loss = packet_loss_rate
+            .map(|v| (v.clamp(0.0, 1.0) * RATE_DENOMINATOR as f64) as u32)
+            .unwrap_or(0);
+        if (loss > 0 || packet_delay > 0) && !rules.iter().any(|v| v.destination.is_none()) {
+            rules.push(WanRule {
+                destination: None,
+                loss,
+                delay: packet_delay,
+                jitter: 0,
+                reorder: 0,
+            });
+        }
+        let rules = normalize(rules);
+        Self {
+            base: rules.clone(),
+            enable: AtomicBool::new(!rules.is_empty()),
+            rules: RwLock::new(rules),
+            running: Mutex::new(None),
+            started: AtomicBool::new(false),
+            queue: Mutex::new(DelayQueue::default()),
+            condvar: Condvar::new(),
+        }
+    }
+    pub fn is_enable(&self) -> bool {
+        self.enable.load(AtomicOrdering::Relaxed)
+    }
+    /// 第一次调用返回true，由调用方启动发送线程
+    pub(crate) fn start(&self) -> bool {
+        !self.started.swap(true, AtomicOrdering::AcqRel)
+    }
+    fn apply(&self, rules: Vec<WanRule>) {
+        let rules = normalize(rules);
+        self.enable
+            .store(!rules.is_empty(), AtomicOrdering::Relaxed);
+        *self.rules.write() = rules;
+    }
+    /// 替换当前的规则，停止正在执行的计划，rules为空时关闭模拟
+    pub fn set_rules(&self, rules: Vec<WanRule>) {
+        *self.running.lock() = None;
+        self.apply(rules);
+    }
+    /// 从第一个阶段开始执行计划
+    pub fn set_schedule(&self, schedule: WanSchedule) {
+        self.set_schedule_at(schedule, Instant::now())
+    }
+    fn set_schedule_at(&self, schedule: WanSchedule, now: Instant) {
+        let mut running = self.running.lock();
+        log::info!("模拟弱网计划开始 阶段1/{}", schedule.phases.len());
+        self.apply(schedule.phases[0].rules.clone());
+        *running = Some(Running {
+            schedule,
+            index: 0,
+            start: now,
+        });
+    }
+    /// 到时间后切换到下一个阶段，由发送线程定时调用
+    pub fn tick(&self) {
+        self.tick_at(Instant::now())
+    }
+    fn tick_at(&self, now: Instant) {
+        let mut guard = self.running.lock();
+        let running = match guard.as_mut() {
+            Some(running) => running,
+            None => return,
+        };
+        let mut changed = false;
+        loop {
+            let duration = running.schedule.phases[running.index].duration;
+            if now.saturating_duration_since(running.start) < duration {
+                break;
+            }
+            running.start += duration;
+            running.index += 1;
+            changed = true;
+            if running.index >= running.schedule.phases.len() {
+                if !running.schedule.repeat {
+                    log::info!("模拟弱网计划结束");
+                    *guard = None;
+                    self.apply(self.base.clone());
+                    return;
+                }
+                running.index = 0;
+            }
+        }
+        if changed {
+            log::info!(
+                "模拟弱网切换到阶段{}/{}",
+                running.index + 1,
+                running.schedule.phases.len()
+            );
+            self.apply(running.schedule.phases[running.index].rules.clone());
+        }
+    }
+    pub fn status(&self) -> WanSimStatus {
+        let now = Instant::now();
+        let phase = self.running.lock().as_ref().map(|running| {
+            let duration = running.schedule.phases[running.index].duration;
+            (
+                running.index,
+                running.schedule.phases.len(),
+                duration.saturating_sub(now.saturating_duration_since(running.start)),
+                running.schedule.repeat,
+            )
+        });
+        WanSimStatus {
+            rules: self.rules.read().clone(),
+            phase,
+        }
+    }
+    pub fn judge(&self, destination: &Ipv4Addr) -> Verdict {
+        let rules = self.rules.read();
+        let rule = match rules
+            .iter()
+            .find(|v| v.destination.is_none() || v.destination == Some(*destination))
+        {
+            Some(rule) => rule,
+            None => return Verdict::Send,
+        };
+        let mut rng = rand::thread_rng();
+        if rule.loss > 0 && rng.gen_ratio(rule.loss.min(RATE_DENOMINATOR), RATE_DENOMINATOR) {
+            return Verdict::Drop;
+        }
+        let mut delay = rule.delay as i64;
+        if rule.jitter > 0 {
+            let jitter = rule.jitter as i64;
+            delay += rng.gen_range(-jitter..=jitter);
+        }
+        if rule.reorder > 0 && rng.gen_ratio(rule.reorder.min(RATE_DENOMINATOR), RATE_DENOMINATOR) {
+            // 有基础延迟时乱序的包跳过延迟，插到前面的包之前，否则延后让后面的包先到
+            return if rule.delay > 0 {
+                Verdict::Send
+            } else {
+                Verdict::Delay(REORDER_HOLD)
+            };
+        }
+        if delay <= 0 {
+            Verdict::Send
+        } else {
+            Verdict::Delay(Duration::from_millis(delay as u64))
+        }
+    }
+    /// 放入延迟队列，队列满时返回false
+    pub fn delay(&self, delay: Duration, packet: DelayedPacket) -> bool {
+        let mut queue = self.queue.lock();
+        if queue.heap.len() >= MAX_QUEUE {
+            return false;
+        }
+        queue.seq += 1;
+        let seq = queue.seq;
+        queue.heap.push(Delayed {
+            due: Instant::now() + delay,
+            seq,
+            packet,
+        });
+        drop(queue);
+        self.condvar.notify_one();
+        true
+    }
+    /// 等待下一个到期的包，超过timeout没有则返回None
+    pub fn next(&self, timeout: Duration) -> Option<DelayedPacket> {
+        let deadline = Instant::now() + timeout;
+        let mut queue = self.queue.lock();
+        loop {
+            let now = Instant::now();
+            let wait_until = match queue.heap.peek() {
+                Some(head) if head.due <= now => {
+                    return queue.heap.pop().map(|v| v.packet);
+                }
+                Some(head) => head.due.min(deadline),
+                None => deadline,
+            };
+            if now >= deadline {
+                return None;
+            }
+            self.condvar.wait_until(&mut queue, wait_until);
+        }
+    }
+}
+
+#[test]
+fn test_wan_rule() {
+    let rule = WanRule::from_str("10.26.0.3,loss=0.1,delay=100,jitter=20,reorder=0.01").unwrap();
+    assert_eq!(rule.destination, Some(Ipv4Addr::new(10, 26, 0, 3)));
+    assert_eq!(rule.loss, 10_0000);
+    assert_eq!(rule.delay, 100);
+    assert_eq!(rule.jitter, 20);
+    assert_eq!(rule.reorder, 1_0000);
+    assert_eq!(WanRule::from_str(&rule.to_string()).unwrap(), rule);
+    assert!(WanRule::from_str("*,delay=50")
+        .unwrap()
+        .destination
+        .is_none());
+    assert!(WanRule::from_str("*,loss=2").is_err());
+    assert!(WanRule::from_str("*,speed=1").is_err());
+
+    let sim = WanSim::new(Some(0.0), 0, vec![rule]);
+    assert!(sim.is_enable());
+    assert!(matches!(
+        sim.judge(&Ipv4Addr::new(10, 26, 0, 4)),
+        Verdict::Send
+    ));
+    let packet = |id| DelayedPacket {
+        buf: vec![id],
+        id: Ipv4Addr::new(10, 26, 0, 3),
+        flow: 0,
+        dscp: None,
+        server_addr: "127.0.0.1:29872".parse().unwrap(),
+        send_default: false,
+    };
+    assert!(sim.delay(Duration::from_millis(30), packet(1)));
+    assert!(sim.delay(Duration::from_millis(10), packet(2)));
+    assert!(sim.next(Duration::from_millis(1)).is_none());
+    assert_eq!(sim.next(Duration::from_secs(1)).unwrap().buf, vec![2]);
+    assert_eq!(sim.next(Duration::from_secs(1)).unwrap().buf, vec![1]);
+    assert!(!WanSim::new(None, 0, vec![]).is_enable());
+}
+
+#[test]
+fn test_wan_schedule() {
+    let schedule = WanSchedule::from_str(
+        "# 混沌测试\n5m clean\n2m *,loss=0.1\n60 *,delay=300;10.26.0.3,loss=0.5\nrepeat\n",
+    )
+    .unwrap();
+    assert_eq!(schedule.phases.len(), 3);
+    assert!(schedule.repeat);
+    assert_eq!(schedule.phases[0].duration, Duration::from_secs(300));
+    assert!(schedule.phases[0].rules.is_empty());
+    assert_eq!(schedule.phases[2].rules.len(), 2);
+    assert!(WanSchedule::from_str("5x clean").is_err());
+    assert!(WanSchedule::from_str("repeat\n1m clean").is_err());
+    assert!(WanSchedule::from_str("# empty").is_err());
+
+    let sim = WanSim::new(None, 0, vec![]);
+    let now = Instant::now();
+    sim.set_schedule_at(schedule, now);
+    assert!(!sim.is_enable());
+    sim.tick_at(now + Duration::from_secs(300));
+    assert!(sim.is_enable());
+    assert_eq!(sim.status().phase.map(|v| v.0), Some(1));
+    // 循环回到第一个阶段
+    sim.tick_at(now + Duration::from_secs(300 + 120 + 60));
+    assert!(!sim.is_enable());
+    assert_eq!(sim.status().phase.map(|v| v.0), Some(0));
+    sim.set_rules(vec![WanRule::from_str("*,delay=10").unwrap()]);
+    assert!(sim.is_enable());
+    assert!(sim.status().phase.is_none());
+}
@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// 对端没有给出速率时的初始限速(字节/秒)
+const INITIAL_RATE: u64 = 4 * 1024 * 1024;
+/// 限速的下限，避免连续拥塞时完全断流
+const MIN_RATE: u64 = 32 * 1024;
+/// 令牌桶容量，允许的突发时长
+const BURST: Duration = Duration::from_millis(50);
+/// 同一来源和目标之间拥塞信号的最小间隔
+const SIGNAL_INTERVAL: Duration = Duration::from_millis(100);
+/// 拥塞信号要求的限速时长(ms)
+pub const CONGESTION_HOLD: u16 = 2000;
+/// 接受的最长限速时长
+const MAX_HOLD: Duration = Duration::from_secs(10);
+const SIGNAL_CAPACITY: usize = 1024;
+
+struct Pace {
+    // 字节/秒
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+    until: Instant,
+}
+
+impl Pace {
+    fn burst(&self) -> f64 {
+        self.rate as f64 * BURST.as_secs_f64()
+    }
+}
+
+/// 中转通道的拥塞控制。
+/// 中转方转发失败时向来源发送拥塞信号，发送方收到后在一段时间内对该目标的中转流量限速，
+/// 多个设备共用一个中转时不会被单个设备占满
+pub struct RelayPacer {
+    // 正在限速的目标数量，为0时发送路径不加锁
+    active: AtomicUsize,
+    paced: Mutex<HashMap<Ipv4Addr, Pace>>,
+    // 中转方：最近一次向(来源,目标)发送拥塞信号的时间
+    signaled: Mutex<HashMap<(Ipv4Addr, Ipv4Addr), Instant>>,
+}
+
+impl Default for RelayPacer {
+    fn default() -> Self {
+        Self {
+            active: AtomicUsize::new(0),
+            paced: Mutex::new(HashMap::with_capacity(16)),
+            signaled: Mutex::new(HashMap::with_capacity(16)),
+        }
+    }
+}
+
+impl RelayPacer {
+    pub fn is_idle(&self) -> bool {
+        self.active.load(Ordering::Relaxed) == 0
+    }
+    /// 收到拥塞信号，rate为0时在当前限速的基础上减半
+    pub fn on_signal(&self, destination: Ipv4Addr, rate: u64, hold: Duration) {
+        let hold = hold.min(MAX_HOLD);
+        let now = Instant::now();
+        let mut paced = self.paced.lock();
+        match paced.get_mut(&destination) {
+            Some(pace) => {
+                pace.rate = if rate > 0 {
+                    rate.min(pace.rate)
+                } else {
+                    pace.rate / 2
+                }
+                .max(MIN_RATE);
+                pace.tokens = pace.tokens.min(pace.burst());
+                pace.until = now + hold;
+            }
+            None => {
+                let rate = if rate > 0 { rate } else { INITIAL_RATE }.max(MIN_RATE);
+                let mut pace = Pace {
+                    rate,
+                    tokens: 0.0,
+                    last: now,
+                    until: now + hold,
+                };
+                pace.tokens = pace.burst();
+                paced.insert(destination, pace);
+                self.active.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        log::info!(
+            "中转拥塞 dest={} rate={}KB/s hold={:?}",
+            destination,
+            paced[&destination].rate / 1024,
+            hold
+        );
+    }
+    /// 发往destination的中转包是否可以发送，超过限速时返回false
+    pub fn check(&self, destination: &Ipv4Addr, len: usize) -> bool {
+        if self.is_idle() {
+            return true;
+        }
+        let now = Instant::now();
+        let mut paced = self.paced.lock();
+        let pace = match paced.get_mut(destination) {
+            Some(pace) => pace,
+            None => return true,
+        };
+        if now >= pace.until {
+            // 一段时间没有新的拥塞信号，恢复不限速
+            paced.remove(destination);
+            self.active.fetch_sub(1, Ordering::Relaxed);
+            return true;
+        }
+        let elapsed = now.saturating_duration_since(pace.last).as_secs_f64();
+        pace.last = now;
+        pace.tokens = (pace.tokens + elapsed * pace.rate as f64).min(pace.burst());
+        if pace.tokens >= len as f64 {
+            pace.tokens -= len as f64;
+            true
+        } else {
+            false
+        }
+    }
+    /// 中转方是否需要向source发送拥塞信号，限制发送频率
+    pub fn should_signal(&self, source: Ipv4Addr, destination: Ipv4Addr) -> bool {
+        let now = Instant::now();
+        let mut signaled = self.signaled.lock();
+        if let Some(last) = signaled.get(&(source, destination)) {
+            if now.saturating_duration_since(*last) < SIGNAL_INTERVAL {
+                return false;
+            }
+        }
+        if signaled.len() >= SIGNAL_CAPACITY {
+            signaled.retain(|_, last| now.saturating_duration_since(*last) < SIGNAL_INTERVAL);
+        }
+        signaled.insert((source, destination), now);
+        true
+    }
+}
+
+#[test]
+fn test_relay_pacer() {
+    let pacer = RelayPacer::default();
+    let dest = Ipv4Addr::new(10, 26, 0, 3);
+    assert!(pacer.check(&dest, 1400));
+    pacer.on_signal(dest, 64 * 1024, Duration::from_secs(1));
+    assert!(!pacer.is_idle());
+    // 突发容量用完后丢弃
+    let burst = (64 * 1024) as f64 * BURST.as_secs_f64();
+    let mut sent = 0;
+    while pacer.check(&dest, 100) {
+        sent += 100;
+    }
+    assert!(sent as f64 <= burst + 1400.0);
+    // 再次拥塞时减半
+    pacer.on_signal(dest, 0, Duration::from_millis(0));
+    assert_eq!(pacer.paced.lock()[&dest].rate, 32 * 1024);
+    // 到期后恢复
+    assert!(pacer.check(&dest, 1400));
+    assert!(pacer.is_idle());
+    assert!(pacer.should_signal(Ipv4Addr::new(10, 26, 0, 2), dest));
+    assert!(!pacer.should_signal(Ipv4Addr::new(10, 26, 0, 2), dest));
+}
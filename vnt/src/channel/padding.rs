@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+/// 填充后的长度档位，超过最大一档时填充到虚拟网卡mtu
+const BUCKETS: [usize; 4] = [128, 256, 512, 1024];
+
+/// 把加密的ip包填充到固定的几档长度，并以较低的速率发送掩护包，
+/// 使中间人难以通过包长和发包频率分析虚拟网络内的流量。
+/// 填充的包使用Ipv4Padded协议，对端按ip头中的总长度去掉填充；
+/// 掩护包的内容不是ipv4包，对端解密后直接丢弃
+pub struct Padding {
+    enable: bool,
+    mtu: usize,
+    // 每秒发送的掩护包数，为0则不发送
+    cover_rate: u32,
+}
+
+impl Padding {
+    pub fn new(enable: bool, mtu: u32, cover_rate: u32) -> Self {
+        Self {
+            enable,
+            mtu: mtu as usize,
+            cover_rate,
+        }
+    }
+    pub fn is_enable(&self) -> bool {
+        self.enable
+    }
+    /// 长度为len的ip包填充后的长度，不超过max
+    pub fn bucket(&self, len: usize, max: usize) -> usize {
+        let bucket = BUCKETS
+            .iter()
+            .copied()
+            .find(|v| len <= *v)
+            .unwrap_or(self.mtu);
+        bucket.min(max).max(len)
+    }
+    /// 发送掩护包的间隔，不发送时返回None
+    pub fn cover_interval(&self) -> Option<Duration> {
+        if self.enable && self.cover_rate > 0 {
+            Some(Duration::from_micros(1_000_000 / self.cover_rate as u64))
+        } else {
+            None
+        }
+    }
+    /// 随机选择一档作为掩护包的长度
+    pub fn cover_len(&self, random: usize) -> usize {
+        let num = BUCKETS.len() + 1;
+        match BUCKETS.get(random % num) {
+            Some(len) => (*len).min(self.mtu),
+            None => self.mtu,
+        }
+    }
+}
+
+/// 去掉填充，返回ip包的长度，掩护包返回None
+pub fn unpad(payload: &[u8]) -> Option<usize> {
+    if payload.len() < 20 || payload[0] >> 4 != 4 {
+        return None;
+    }
+    let total_len = u16::from_be_bytes([payload[2], payload[3]]) as usize;
+    if total_len < 20 || total_len > payload.len() {
+        return None;
+    }
+    Some(total_len)
+}
+
+#[test]
+fn test_padding() {
+    let padding = Padding::new(true, 1410, 10);
+    assert_eq!(padding.bucket(60, 1410), 128);
+    assert_eq!(padding.bucket(128, 1410), 128);
+    assert_eq!(padding.bucket(700, 1410), 1024);
+    assert_eq!(padding.bucket(1100, 1410), 1410);
+    // 不超过对端的路径mtu
+    assert_eq!(padding.bucket(1100, 1200), 1200);
+    assert_eq!(padding.bucket(1300, 1200), 1300);
+    assert_eq!(padding.cover_interval(), Some(Duration::from_millis(100)));
+    assert!(Padding::new(true, 1410, 0).cover_interval().is_none());
+
+    let mut buf = [0u8; 128];
+    buf[0] = 0x45;
+    buf[2..4].copy_from_slice(&60u16.to_be_bytes());
+    assert_eq!(unpad(&buf), Some(60));
+    buf[2..4].copy_from_slice(&200u16.to_be_bytes());
+    assert_eq!(unpad(&buf), None);
+    assert_eq!(unpad(&[0u8; 128]), None);
+}
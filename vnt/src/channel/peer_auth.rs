@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+use protobuf::Message;
+
+use crate::cipher::identity::{self, DeviceIdentity, SIGNATURE_LEN};
+use crate::proto::message::PunchInfo;
+use crate::protocol::{other_turn_packet, Protocol};
+
+const AUTH_DOMAIN: &[u8] = b"vnt-peer-auth";
+
+/// 对端身份校验，对端在服务端登记了公钥时，打洞和心跳消息必须带有对应私钥的签名，
+/// 仅持有token的设备无法冒充已有设备的虚拟ip
+#[derive(Clone, Default)]
+pub struct PeerAuth {
+    identity: Option<DeviceIdentity>,
+    peer_keys: Arc<RwLock<HashMap<Ipv4Addr, Vec<u8>>>>,
+}
+
+impl PeerAuth {
+    pub fn new(identity: Option<DeviceIdentity>) -> Self {
+        Self {
+            identity,
+            peer_keys: Default::default(),
+        }
+    }
+    pub fn is_enable(&self) -> bool {
+        self.identity.is_some()
+    }
+    pub fn public_key(&self) -> Option<&[u8]> {
+        self.identity.as_ref().map(|v| v.public_key())
+    }
+    /// 更新服务端下发的对端公钥
+    pub fn set_peer_keys(&self, keys: HashMap<Ipv4Addr, Vec<u8>>) {
+        *self.peer_keys.write() = keys;
+    }
+    pub fn peer_keys(&self) -> Vec<(Ipv4Addr, Vec<u8>)> {
+        let mut list: Vec<(Ipv4Addr, Vec<u8>)> = self
+            .peer_keys
+            .read()
+            .iter()
+            .map(|(ip, key)| (*ip, key.clone()))
+            .collect();
+        list.sort_by_key(|(ip, _)| *ip);
+        list
+    }
+    /// 签名，未配置设备密钥时返回None
+    pub fn sign(
+        &self,
+        protocol: u8,
+        transport_protocol: u8,
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+        data: &[u8],
+    ) -> Option<[u8; SIGNATURE_LEN]> {
+        let identity = self.identity.as_ref()?;
+        let msg = message(protocol, transport_protocol, source, destination, data);
+        Some(identity.sign(&msg))
+    }
+    /// 本地未配置设备密钥或者对端没有登记公钥时不校验
+    pub fn verify(
+        &self,
+        protocol: u8,
+        transport_protocol: u8,
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+        data: &[u8],
+        signature: Option<&[u8]>,
+    ) -> bool {
+        if self.identity.is_none() {
+            return true;
+        }
+        let guard = self.peer_keys.read();
+        match (guard.get(&source), signature) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(public_key), Some(signature)) => {
+                let msg = message(protocol, transport_protocol, source, destination, data);
+                identity::verify(public_key, &msg, signature)
+            }
+        }
+    }
+    /// 签名打洞信息，签名时signature字段为空
+    pub fn sign_punch_info(
+        &self,
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+        info: &mut PunchInfo,
+    ) -> protobuf::Result<()> {
+        info.signature.clear();
+        if !self.is_enable() {
+            return Ok(());
+        }
+        let bytes = info.write_to_bytes()?;
+        if let Some(signature) = self.sign(
+            Protocol::OtherTurn.into(),
+            other_turn_packet::Protocol::Punch.into(),
+            source,
+            destination,
+            &bytes,
+        ) {
+            info.signature = signature.to_vec();
+        }
+        Ok(())
+    }
+    pub fn verify_punch_info(
+        &self,
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+        info: &PunchInfo,
+    ) -> bool {
+        if self.identity.is_none() {
+            return true;
+        }
+        let mut unsigned = info.clone();
+        let signature = std::mem::take(&mut unsigned.signature);
+        let bytes = match unsigned.write_to_bytes() {
+            Ok(bytes) => bytes,
+            Err(_) => return false,
+        };
+        self.verify(
+            Protocol::OtherTurn.into(),
+            other_turn_packet::Protocol::Punch.into(),
+            source,
+            destination,
+            &bytes,
+            if signature.is_empty() {
+                None
+            } else {
+                Some(&signature)
+            },
+        )
+    }
+}
+
+fn message(
+    protocol: u8,
+    transport_protocol: u8,
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    data: &[u8],
+) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(AUTH_DOMAIN.len() + 10 + data.len());
+    msg.extend_from_slice(AUTH_DOMAIN);
+    msg.push(protocol);
+    msg.push(transport_protocol);
+    msg.extend_from_slice(&source.octets());
+    msg.extend_from_slice(&destination.octets());
+    msg.extend_from_slice(data);
+    msg
+}
+
+#[test]
+fn test_peer_auth_disabled() {
+    let auth = PeerAuth::new(None);
+    let ip = Ipv4Addr::new(10, 26, 0, 2);
+    auth.set_peer_keys(HashMap::from([(ip, vec![0u8; 32])]));
+    assert!(auth.sign(3, 1, ip, ip, &[]).is_none());
+    // 本地未开启时不校验对端
+    assert!(auth.verify(3, 1, ip, ip, &[], None));
+    assert_eq!(auth.peer_keys().len(), 1);
+}
@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use packet::ip::ipv4::protocol::Protocol;
+use parking_lot::RwLock;
+
+use crate::channel::acl::Acl;
+
+/// 匹配对端的规则，ip或者设备名称
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PeerMatch {
+    Ip(Ipv4Addr),
+    Name(String),
+}
+
+impl PeerMatch {
+    fn is_match(&self, ip: &Ipv4Addr, name: Option<&String>) -> bool {
+        match self {
+            PeerMatch::Ip(v) => v == ip,
+            PeerMatch::Name(v) => name == Some(v),
+        }
+    }
+}
+
+impl FromStr for PeerMatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(name) = s.strip_prefix("name:") {
+            let name = name.trim();
+            if name.is_empty() || name.len() > 128 {
+                return Err(format!("invalid peer name '{}'", s));
+            }
+            return Ok(PeerMatch::Name(name.to_string()));
+        }
+        let ip = s.strip_prefix("ip:").unwrap_or(s);
+        match Ipv4Addr::from_str(ip.trim()) {
+            Ok(ip) => Ok(PeerMatch::Ip(ip)),
+            Err(e) => Err(format!(
+                "invalid peer '{}' {}, e.g. 10.26.0.7 or name:laptop",
+                s, e
+            )),
+        }
+    }
+}
+
+impl Display for PeerMatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerMatch::Ip(ip) => write!(f, "{}", ip),
+            PeerMatch::Name(name) => write!(f, "name:{}", name),
+        }
+    }
+}
+
+/// 对端黑白名单，不允许的对端不会向其发起打洞，也不接受它的打洞和发给本机的ip数据。
+/// 配置了白名单时只允许白名单内的对端，黑名单优先。
+/// 服务端下发的名单只能在本地名单的基础上进一步限制
+#[derive(Default)]
+pub struct PeerFilter {
+    allow: Vec<PeerMatch>,
+    deny: Vec<PeerMatch>,
+    // 是否接受服务端下发的名单
+    accept_server: bool,
+    // 服务端下发的白名单和黑名单
+    server: RwLock<(Vec<PeerMatch>, Vec<PeerMatch>)>,
+    server_enable: AtomicBool,
+    // 服务端下发的设备名称，用于按名称匹配
+    names: RwLock<HashMap<Ipv4Addr, String>>,
+    // 按对端分组的访问控制
+    acl: Acl,
+}
+
+impl PeerFilter {
+    pub fn new(allow: Vec<PeerMatch>, deny: Vec<PeerMatch>, accept_server: bool, acl: Acl) -> Self {
+        Self {
+            allow,
+            deny,
+            accept_server,
+            server: RwLock::new((Vec::new(), Vec::new())),
+            server_enable: AtomicBool::new(false),
+            names: RwLock::new(HashMap::new()),
+            acl,
+        }
+    }
+    pub fn is_enable(&self) -> bool {
+        !self.allow.is_empty()
+            || !self.deny.is_empty()
+            || self.server_enable.load(Ordering::Relaxed)
+    }
+    /// 更新服务端下发的设备名称
+    pub fn set_names(&self, names: HashMap<Ipv4Addr, String>) {
+        if self.accept_server || self.is_enable() || self.acl.has_name() {
+            *self.names.write() = names;
+        }
+    }
+    /// 更新服务端下发的名单，都为空表示撤销
+    pub fn set_server(&self, allow: Vec<PeerMatch>, deny: Vec<PeerMatch>) {
+        if !self.accept_server {
+            return;
+        }
+        let enable = !allow.is_empty() || !deny.is_empty();
+        *self.server.write() = (allow, deny);
+        self.server_enable.store(enable, Ordering::Relaxed);
+    }
+    pub fn is_allowed(&self, ip: &Ipv4Addr) -> bool {
+        if !self.is_enable() {
+            return true;
+        }
+        let names = self.names.read();
+        let name = names.get(ip);
+        if !is_allowed(&self.allow, &self.deny, ip, name) {
+            return false;
+        }
+        if !self.server_enable.load(Ordering::Relaxed) {
+            return true;
+        }
+        let server = self.server.read();
+        is_allowed(&server.0, &server.1, ip, name)
+    }
+    /// 按访问控制检查对端发来的ip数据
+    pub fn acl_allowed(
+        &self,
+        ip: &Ipv4Addr,
+        protocol: Protocol,
+        offset: u16,
+        payload: &[u8],
+    ) -> bool {
+        if self.acl.is_empty() {
+            return true;
+        }
+        let names = self.names.read();
+        self.acl
+            .is_allowed(ip, names.get(ip), protocol, offset, payload)
+    }
+}
+
+fn is_allowed(
+    allow: &[PeerMatch],
+    deny: &[PeerMatch],
+    ip: &Ipv4Addr,
+    name: Option<&String>,
+) -> bool {
+    if deny.iter().any(|v| v.is_match(ip, name)) {
+        return false;
+    }
+    allow.is_empty() || allow.iter().any(|v| v.is_match(ip, name))
+}
+
+#[test]
+fn test_peer_filter() {
+    let a = Ipv4Addr::new(10, 26, 0, 2);
+    let b = Ipv4Addr::new(10, 26, 0, 3);
+    let c = Ipv4Addr::new(10, 26, 0, 7);
+    assert!(PeerFilter::default().is_allowed(&a));
+
+    let filter = PeerFilter::new(
+        vec![],
+        vec![PeerMatch::from_str("10.26.0.7").unwrap()],
+        false,
+        Acl::default(),
+    );
+    assert!(filter.is_allowed(&a));
+    assert!(!filter.is_allowed(&c));
+
+    let filter = PeerFilter::new(
+        vec![PeerMatch::from_str("name:laptop").unwrap()],
+        vec![PeerMatch::from_str("ip:10.26.0.3").unwrap()],
+        true,
+        Acl::default(),
+    );
+    // 名称未知时不匹配白名单
+    assert!(!filter.is_allowed(&a));
+    filter.set_names(HashMap::from([
+        (a, "laptop".to_string()),
+        (b, "laptop".to_string()),
+    ]));
+    assert!(filter.is_allowed(&a));
+    assert!(!filter.is_allowed(&b));
+    assert!(!filter.is_allowed(&c));
+    // 服务端的名单不能放开本地的限制
+    filter.set_server(vec![PeerMatch::Ip(b)], vec![]);
+    assert!(!filter.is_allowed(&a));
+    assert!(!filter.is_allowed(&b));
+    filter.set_server(vec![], vec![]);
+    assert!(filter.is_allowed(&a));
+    assert!(PeerMatch::from_str("laptop").is_err());
+}
@@ -1,15 +1,21 @@
 use std::collections::HashMap;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::str::FromStr;
+use std::sync::OnceLock;
 use std::time::Duration;
 use std::{io, thread};
 
 use mio::net::TcpStream;
 use rand::prelude::SliceRandom;
 use rand::Rng;
+#[cfg(target_os = "linux")]
+use sha2::{Digest, Sha256};
 
 use crate::channel::context::ChannelContext;
+use crate::channel::event::RouteEventKind;
+use crate::channel::punch_log::{PunchAttempt, PunchOutcome, PunchStrategy};
 use crate::channel::sender::AcceptSocketSender;
+use crate::channel::set_reuse;
 use crate::external_route::ExternalRoute;
 use crate::nat::NatTest;
 
@@ -49,6 +55,13 @@ pub struct NatInfo {
     pub(crate) ipv6: Option<Ipv6Addr>,
     pub(crate) udp_ports: Vec<u16>,
     pub tcp_port: u16,
+    // 和本机在同一个NAT后面(公网ip相同)
+    pub(crate) same_nat: bool,
+    // NAT的过滤行为(RFC 5780)，stun服务器不支持时为Unknown
+    pub(crate) filtering: NatFiltering,
+    // 对端的主机标识和unix域套接字名称，本机的信息不使用这两个字段
+    pub(crate) host_id: Vec<u8>,
+    pub(crate) uds_id: u64,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
@@ -57,6 +70,19 @@ pub enum NatType {
     Cone,
 }
 
+/// NAT对入站数据的过滤行为
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash, Default)]
+pub enum NatFiltering {
+    #[default]
+    Unknown,
+    // 任意地址发来的数据都能进入
+    EndpointIndependent,
+    // 只接收发送过数据的ip
+    AddressDependent,
+    // 只接收发送过数据的ip和端口
+    AddressAndPortDependent,
+}
+
 impl NatInfo {
     pub fn new(
         mut public_ips: Vec<Ipv4Addr>,
@@ -97,13 +123,20 @@ impl NatInfo {
             udp_ports,
             tcp_port,
             nat_type,
+            same_nat: false,
+            filtering: NatFiltering::Unknown,
+            host_id: Vec::new(),
+            uds_id: 0,
         }
     }
-    pub fn update_addr(&mut self, index: usize, ip: Ipv4Addr, port: u16) {
+    /// 更新服务端看到的地址，返回地址是否变化
+    pub fn update_addr(&mut self, index: usize, ip: Ipv4Addr, port: u16) -> bool {
+        let mut changed = false;
         if port != 0 {
             if let Some(public_port) = self.public_ports.get_mut(index) {
                 if *public_port != port {
-                    log::info!("端口变化={}:{}", ip, port)
+                    log::info!("端口变化={}:{}", ip, port);
+                    changed = true;
                 }
                 *public_port = port;
             }
@@ -116,13 +149,44 @@ impl NatInfo {
         {
             if !self.public_ips.contains(&ip) {
                 self.public_ips.push(ip);
-                log::info!("ip变化={},{:?}", ip, self.public_ips)
+                log::info!("ip变化={},{:?}", ip, self.public_ips);
+                changed = true;
             }
         }
+        changed
+    }
+    pub fn update_ipv6_addr(&mut self, ip: Ipv6Addr) -> bool {
+        if ip.is_multicast()
+            || ip.is_unspecified()
+            || ip.is_loopback()
+            || ip.to_ipv4_mapped().is_some()
+        {
+            return false;
+        }
+        if self.ipv6 != Some(ip) {
+            log::info!("ipv6变化={:?}->{}", self.ipv6, ip);
+            self.ipv6 = Some(ip);
+            return true;
+        }
+        false
     }
     pub fn local_ipv4(&self) -> Option<Ipv4Addr> {
         self.local_ipv4
     }
+    /// 对端是否和本机在同一个NAT后面，此时优先使用内网地址打洞，
+    /// 很多家用路由器不支持回流(hairpin)，通过公网地址打洞大概率失败
+    pub fn same_nat(&self) -> bool {
+        self.same_nat
+    }
+    pub fn filtering(&self) -> NatFiltering {
+        self.filtering
+    }
+    /// 公网ip有交集则认为在同一个NAT后面
+    pub fn is_behind_same_nat(&self, local: &NatInfo) -> bool {
+        self.public_ips
+            .iter()
+            .any(|ip| local.public_ips.contains(ip))
+    }
     pub fn ipv6(&self) -> Option<Ipv6Addr> {
         self.ipv6
     }
@@ -156,6 +220,17 @@ impl NatInfo {
             None
         }
     }
+    /// 回环地址，和对端在同一台主机上时使用
+    pub fn loopback_udp_addr(&self, index: usize) -> Option<SocketAddr> {
+        let len = self.udp_ports.len();
+        if len == 0 {
+            return None;
+        }
+        Some(SocketAddr::V4(SocketAddrV4::new(
+            Ipv4Addr::LOCALHOST,
+            self.udp_ports[index % len],
+        )))
+    }
 
     pub fn local_tcp_ipv6addr(&self) -> Option<SocketAddr> {
         if self.tcp_port == 0 {
@@ -177,6 +252,23 @@ impl NatInfo {
             None
         }
     }
+    /// 可以从外部直接连接的tcp地址
+    pub fn public_tcp_addrs(&self) -> Vec<SocketAddr> {
+        let mut addrs = Vec::new();
+        if self.tcp_port == 0 {
+            return addrs;
+        }
+        if let Some(addr) = self.local_tcp_ipv6addr() {
+            addrs.push(addr);
+        }
+        if self.nat_type == NatType::Cone && self.public_ips.len() == 1 {
+            addrs.push(SocketAddr::V4(SocketAddrV4::new(
+                self.public_ips[0],
+                self.tcp_port,
+            )));
+        }
+        addrs
+    }
 }
 
 #[derive(Clone)]
@@ -218,38 +310,139 @@ impl Punch {
 }
 
 impl Punch {
-    fn connect_tcp(&self, buf: &[u8], addr: SocketAddr) -> bool {
+    /// 打洞时连接对端的tcp，记录连接次数和是否被拒绝
+    fn connect_tcp(&self, buf: &[u8], addr: SocketAddr, attempt: &mut PunchAttempt) -> bool {
         if self.nat_test.is_local_address(true, addr) {
             return false;
         }
-        // mio是非阻塞的，不能立马判断是否能连接成功，所以用标准库的tcp
-        match std::net::TcpStream::connect_timeout(&addr, Duration::from_millis(100)) {
+        attempt.sent(PunchStrategy::Tcp, 1);
+        match self.connect_tcp_timeout(buf, addr, Duration::from_millis(100)) {
+            Ok(rs) => rs,
+            Err(e) => {
+                if e.kind() == io::ErrorKind::ConnectionRefused {
+                    attempt.tcp_refused = true;
+                }
+                false
+            }
+        }
+    }
+    fn connect_tcp_timeout(
+        &self,
+        buf: &[u8],
+        addr: SocketAddr,
+        timeout: Duration,
+    ) -> io::Result<bool> {
+        if self.nat_test.is_local_address(true, addr) {
+            return Ok(false);
+        }
+        // mio是非阻塞的，不能立马判断是否能连接成功，所以用阻塞的连接
+        match self.connect_tcp0(addr, timeout) {
             Ok(tcp_stream) => {
                 if tcp_stream.set_nonblocking(true).is_err() {
-                    return false;
+                    return Ok(false);
                 }
-                return self
+                Ok(self
                     .tcp_socket_sender
                     .try_add_socket((TcpStream::from_std(tcp_stream), addr, Some(buf.to_vec())))
-                    .is_ok();
+                    .is_ok())
             }
             Err(e) => {
-                log::warn!("连接到tcp失败,addr={},err={}", addr, e);
+                log::warn!("连接到tcp失败 addr={} err={}", addr, e);
+                Err(e)
+            }
+        }
+    }
+    /// 使用tcp监听的端口发起连接，这样对端看到的是已经告知的端口，锥形nat上的映射也能复用
+    fn connect_tcp0(&self, addr: SocketAddr, timeout: Duration) -> io::Result<std::net::TcpStream> {
+        let tcp_port = self.nat_test.nat_info().tcp_port;
+        if tcp_port != 0 {
+            let domain = socket2::Domain::for_address(addr);
+            let socket = socket2::Socket::new(domain, socket2::Type::STREAM, None)?;
+            let local: SocketAddr = match self.context.listen_ip() {
+                Some(ip) if ip.is_ipv4() == addr.is_ipv4() => SocketAddr::new(ip, tcp_port),
+                _ => {
+                    if addr.is_ipv4() {
+                        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, tcp_port))
+                    } else {
+                        SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, tcp_port, 0, 0))
+                    }
+                }
+            };
+            match set_reuse(&socket).and_then(|_| socket.bind(&local.into())) {
+                Ok(_) => {
+                    self.context.qos.mark(socket2::SockRef::from(&socket));
+                    socket.connect_timeout(&addr.into(), timeout)?;
+                    return Ok(socket.into());
+                }
+                Err(e) => {
+                    log::debug!("绑定tcp端口失败,使用随机端口 port={} err={:?}", tcp_port, e);
+                }
+            }
+        }
+        let stream = std::net::TcpStream::connect_timeout(&addr, timeout)?;
+        self.context.qos.mark(socket2::SockRef::from(&stream));
+        Ok(stream)
+    }
+    /// 反向隧道，主动连接对端可达的tcp地址，自己无法被连接时由对端转发其他设备的数据
+    pub fn connect_relay(&self, buf: &[u8], nat_info: &NatInfo) -> bool {
+        for addr in nat_info.public_tcp_addrs() {
+            if let Ok(true) = self.connect_tcp_timeout(buf, addr, Duration::from_secs(1)) {
+                return true;
             }
         }
         false
     }
+    fn is_same_host(&self, nat_info: &NatInfo) -> bool {
+        match self.context.main_local_udp_port() {
+            Ok(ports) => is_same_host(&self.nat_test.nat_info(), host_id(), &ports, nat_info),
+            Err(_) => false,
+        }
+    }
     pub fn punch(
         &mut self,
         buf: &[u8],
         id: Ipv4Addr,
-        mut nat_info: NatInfo,
+        nat_info: NatInfo,
         punch_tcp: bool,
     ) -> io::Result<()> {
         if self.context.route_table.no_need_punch(&id) {
-            log::info!("已打洞成功,无需打洞:{:?}", id);
+            log::info!("已打洞成功,无需打洞");
             return Ok(());
         }
+        self.context.route_table.event_log.push(
+            id,
+            RouteEventKind::Punch,
+            format!(
+                "nat={:?} public_ips={:?} tcp={} same_nat={}",
+                nat_info.nat_type, nat_info.public_ips, punch_tcp, nat_info.same_nat
+            ),
+        );
+        let mut attempt = PunchAttempt::new(nat_info.nat_type);
+        let rs = self.punch0(buf, id, nat_info, punch_tcp, &mut attempt);
+        if let Err(e) = &rs {
+            attempt.outcome = PunchOutcome::Error(format!("{}", e));
+            self.context.route_table.event_log.push(
+                id,
+                RouteEventKind::PunchFail,
+                format!("{}", e),
+            );
+        }
+        log::debug!(
+            "打洞记录 packets={} strategies={:?}",
+            attempt.packets(),
+            attempt.strategies
+        );
+        self.context.route_table.push_punch(id, attempt);
+        rs
+    }
+    fn punch0(
+        &mut self,
+        buf: &[u8],
+        id: Ipv4Addr,
+        mut nat_info: NatInfo,
+        punch_tcp: bool,
+        attempt: &mut PunchAttempt,
+    ) -> io::Result<()> {
         nat_info
             .public_ips
             .retain(|ip| self.external_route.route(&ip).is_none());
@@ -266,29 +459,65 @@ impl Punch {
         if punch_tcp && self.is_tcp && nat_info.tcp_port != 0 {
             //向tcp发起连接
             if let Some(ipv6_addr) = nat_info.local_tcp_ipv6addr() {
-                if self.connect_tcp(buf, ipv6_addr) {
+                if self.connect_tcp(buf, ipv6_addr, attempt) {
                     // return Ok(());
                 }
             }
             //向tcp发起连接
             if let Some(ipv4_addr) = nat_info.local_tcp_ipv4addr() {
-                if self.connect_tcp(buf, ipv4_addr) {
+                if self.connect_tcp(buf, ipv4_addr, attempt) {
                     // return Ok(());
                 }
             }
             if nat_info.nat_type == NatType::Cone && nat_info.public_ips.len() == 1 {
                 let addr =
                     SocketAddr::V4(SocketAddrV4::new(nat_info.public_ips[0], nat_info.tcp_port));
-                if self.connect_tcp(buf, addr) {
+                if self.connect_tcp(buf, addr, attempt) {
                     // return Ok(());
                 }
             }
         }
         let channel_num = self.context.channel_num();
+        if nat_info.uds_id != 0 && self.context.send_uds(buf, nat_info.uds_id).is_ok() {
+            // 对端的套接字在本机存在，说明在同一台主机上，不需要再判断主机标识
+            attempt.sent(PunchStrategy::Uds, 1);
+        }
+        if self.is_same_host(&nat_info) {
+            // 同一台主机上的实例走回环地址，不经过物理网卡，延迟最低，会被优先选择
+            for index in 0..channel_num {
+                if let Some(addr) = nat_info.loopback_udp_addr(index) {
+                    if self.context.send_main_udp(index, buf, addr).is_ok() {
+                        attempt.sent(PunchStrategy::Loopback, 1);
+                    }
+                }
+            }
+        }
         for index in 0..channel_num {
             if let Some(ipv4_addr) = nat_info.local_udp_ipv4addr(index) {
-                if !self.nat_test.is_local_address(false, ipv4_addr) {
-                    let _ = self.context.send_main_udp(index, buf, ipv4_addr);
+                if !self.nat_test.is_local_address(false, ipv4_addr)
+                    && self.context.send_main_udp(index, buf, ipv4_addr).is_ok()
+                {
+                    attempt.sent(PunchStrategy::Local, 1);
+                }
+            }
+        }
+        if nat_info.same_nat {
+            // 同一NAT下对端的内网地址可能不准确(多网卡、容器等)，再通过局域网广播辅助发现
+            if let Ok(ports) = self.context.main_local_udp_port() {
+                for index in 0..channel_num {
+                    let len = nat_info.udp_ports.len();
+                    if len == 0 {
+                        break;
+                    }
+                    let port = nat_info.udp_ports[index % len];
+                    // 端口和自己相同时会发给自己
+                    if ports.contains(&port) {
+                        continue;
+                    }
+                    let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::BROADCAST, port));
+                    if self.context.send_main_udp(index, buf, addr).is_ok() {
+                        attempt.sent(PunchStrategy::Broadcast, 1);
+                    }
                 }
             }
         }
@@ -298,7 +527,10 @@ impl Punch {
                 if let Some(ipv6_addr) = nat_info.local_udp_ipv6addr(index) {
                     if !self.nat_test.is_local_address(false, ipv6_addr) {
                         let rs = self.context.send_main_udp(index, buf, ipv6_addr);
-                        log::info!("发送到ipv6地址:{:?},rs={:?}", ipv6_addr, rs);
+                        log::info!("发送到ipv6地址 addr={} rs={:?}", ipv6_addr, rs);
+                        if rs.is_ok() {
+                            attempt.sent(PunchStrategy::Ipv6, 1);
+                        }
                         if rs.is_ok() && self.punch_model == PunchModel::IPv6 {
                             return Ok(());
                         }
@@ -306,7 +538,32 @@ impl Punch {
                 }
             }
         }
+        if nat_info.same_nat
+            && nat_info.local_ipv4.is_some()
+            && (nat_info.nat_type == NatType::Symmetric || !self.context.is_cone())
+        {
+            // 同一NAT下通过公网地址通信依赖路由器回流，对称网络的端口预测只会浪费大量的包，
+            // 锥形网络只发少量的包，路由器支持回流时仍然可以打通
+            log::info!("和对端在同一个NAT后面，跳过公网地址打洞");
+            return Ok(());
+        }
+        let plan = public_punch_plan(
+            self.context.is_cone(),
+            self.nat_test.nat_info().filtering,
+            &nat_info,
+        );
+        match plan {
+            PublicPunch::All => {}
+            PublicPunch::Skip => {
+                log::info!("本机对称网络，对端限制端口，跳过公网地址打洞");
+                return Ok(());
+            }
+            _ => {
+                log::info!("根据NAT过滤行为减少打洞 plan={:?}", plan);
+            }
+        }
         match nat_info.nat_type {
+            NatType::Symmetric if plan == PublicPunch::NoPrediction => {}
             NatType::Symmetric => {
                 // 假设对方绑定n个端口，通过NAT对外映射出n个 公网ip:公网端口，自己随机尝试k次的情况下
                 // 猜中的概率 p = 1-((65535-n)/65535)*((65535-n-1)/(65535-1))*...*((65535-n-k+1)/(65535-k+1))
@@ -335,7 +592,16 @@ impl Punch {
                     let mut nums: Vec<u16> = (min_port..max_port).collect();
                     nums.push(max_port);
                     nums.shuffle(&mut rand::thread_rng());
-                    self.punch_symmetric(&nums[..k], buf, &nat_info.public_ips, max_k1 as usize)?;
+                    self.punch_symmetric(
+                        &nums[..k],
+                        buf,
+                        &nat_info.public_ips,
+                        max_k1 as usize,
+                        attempt,
+                    )?;
+                }
+                if plan == PublicPunch::PredictOnly {
+                    return Ok(());
                 }
                 let start = *self.port_index.entry(id.clone()).or_insert(0);
                 let mut end = start + max_k2;
@@ -348,6 +614,7 @@ impl Punch {
                         buf,
                         &nat_info.public_ips,
                         max_k2,
+                        attempt,
                     )?;
                 if index >= self.port_vec.len() {
                     index = 0
@@ -365,9 +632,11 @@ impl Punch {
                         let addr = SocketAddr::V4(SocketAddrV4::new(*ip, port));
                         if is_cone {
                             self.context.send_main_udp(index, buf, addr)?;
+                            attempt.sent(PunchStrategy::Cone, 1);
                         } else {
                             //只有一方是对称，则对称方要使用全部端口发送数据，符合上述计算的概率
-                            self.context.try_send_all(buf, addr);
+                            let count = self.context.try_send_all(buf, addr);
+                            attempt.sent(PunchStrategy::Cone, count as u32);
                         }
                         thread::sleep(Duration::from_millis(2));
                     }
@@ -387,6 +656,7 @@ impl Punch {
         buf: &[u8],
         ips: &Vec<Ipv4Addr>,
         max: usize,
+        attempt: &mut PunchAttempt,
     ) -> io::Result<usize> {
         let mut count = 0;
         for (index, port) in ports.iter().enumerate() {
@@ -397,9 +667,202 @@ impl Punch {
                 }
                 let addr = SocketAddr::V4(SocketAddrV4::new(*pub_ip, *port));
                 self.context.send_main_udp(0, buf, addr)?;
+                attempt.sent(PunchStrategy::SymmetricPrediction, 1);
                 thread::sleep(Duration::from_millis(2));
             }
         }
         Ok(ports.len())
     }
 }
+
+/// 公网地址打洞的范围
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum PublicPunch {
+    All,
+    // 只在预测范围内发送，不再大范围随机发送
+    PredictOnly,
+    // 不向对称网络的对端猜端口，等对端的包进来
+    NoPrediction,
+    // 不可能成功，不发送
+    Skip,
+}
+
+/// 根据双方的NAT类型和过滤行为，跳过不可能成功或者没有必要的打洞方式
+fn public_punch_plan(
+    local_cone: bool,
+    local_filtering: NatFiltering,
+    peer: &NatInfo,
+) -> PublicPunch {
+    if !local_cone && peer.filtering == NatFiltering::AddressAndPortDependent {
+        // 本机每个目标映射出不同的端口，对端只接收它发送过的ip和端口，双方都猜不中
+        return PublicPunch::Skip;
+    }
+    if local_cone && peer.nat_type == NatType::Symmetric {
+        match local_filtering {
+            // 对端的包从任意端口都能进来，收到后直接回复
+            NatFiltering::EndpointIndependent => return PublicPunch::NoPrediction,
+            // 向对端ip发过包之后，对端从任意端口发来的包都能进来
+            NatFiltering::AddressDependent => return PublicPunch::PredictOnly,
+            _ => {}
+        }
+    }
+    PublicPunch::All
+}
+
+#[test]
+fn test_public_punch_plan() {
+    let peer = |nat_type: NatType, filtering: NatFiltering| {
+        let mut info = NatInfo::new(
+            vec![Ipv4Addr::new(8, 8, 8, 8)],
+            vec![1000],
+            0,
+            None,
+            None,
+            vec![1000],
+            0,
+            nat_type,
+        );
+        info.filtering = filtering;
+        info
+    };
+    use NatFiltering::*;
+    let cone_apdf = peer(NatType::Cone, AddressAndPortDependent);
+    let symmetric = peer(NatType::Symmetric, Unknown);
+    assert_eq!(
+        public_punch_plan(false, Unknown, &cone_apdf),
+        PublicPunch::Skip
+    );
+    assert_eq!(
+        public_punch_plan(true, Unknown, &cone_apdf),
+        PublicPunch::All
+    );
+    assert_eq!(
+        public_punch_plan(true, EndpointIndependent, &symmetric),
+        PublicPunch::NoPrediction
+    );
+    assert_eq!(
+        public_punch_plan(true, AddressDependent, &symmetric),
+        PublicPunch::PredictOnly
+    );
+    assert_eq!(
+        public_punch_plan(true, AddressAndPortDependent, &symmetric),
+        PublicPunch::All
+    );
+    // 未知时保持原来的行为
+    assert_eq!(
+        public_punch_plan(false, Unknown, &symmetric),
+        PublicPunch::All
+    );
+}
+
+/// 主机标识，machine-id的摘要，不暴露machine-id本身。
+/// 读取不到时为空，此时不认为和任何对端在同一台主机上
+pub fn host_id() -> &'static [u8] {
+    static HOST_ID: OnceLock<Vec<u8>> = OnceLock::new();
+    HOST_ID.get_or_init(|| {
+        #[cfg(target_os = "linux")]
+        for path in ["/etc/machine-id", "/var/lib/dbus/machine-id"] {
+            if let Ok(id) = std::fs::read_to_string(path) {
+                let id = id.trim();
+                if !id.is_empty() {
+                    let mut hasher = Sha256::new();
+                    hasher.update(b"vnt-host-id");
+                    hasher.update(id.as_bytes());
+                    return hasher.finalize()[..8].to_vec();
+                }
+            }
+        }
+        Vec::new()
+    })
+}
+
+/// 对端是否是同一台主机上的另一个实例(如使用主机网络的容器)。
+/// 两个局域网可能使用相同的内网地址，所以还要求主机标识和公网ip相同，
+/// 并且端口不冲突，是否真的可达由打洞的响应来确认
+fn is_same_host(
+    local: &NatInfo,
+    local_host_id: &[u8],
+    local_ports: &[u16],
+    peer: &NatInfo,
+) -> bool {
+    if local_host_id.is_empty() || peer.host_id != local_host_id {
+        return false;
+    }
+    if !peer.is_behind_same_nat(local) {
+        return false;
+    }
+    match local.local_ipv4 {
+        Some(ip) if peer.local_ipv4 == Some(ip) => {}
+        _ => return false,
+    }
+    !peer.udp_ports.iter().any(|port| local_ports.contains(port))
+}
+
+#[test]
+fn test_same_host() {
+    let new = |public_ip: Ipv4Addr, local: Ipv4Addr, port: u16, host_id: &[u8]| {
+        let mut info = NatInfo::new(
+            vec![public_ip],
+            vec![10000],
+            0,
+            Some(local),
+            None,
+            vec![port],
+            0,
+            NatType::Cone,
+        );
+        info.host_id = host_id.to_vec();
+        info
+    };
+    let public_ip = Ipv4Addr::new(1, 1, 1, 1);
+    let lan_ip = Ipv4Addr::new(192, 168, 1, 2);
+    let local = new(public_ip, lan_ip, 20000, &[]);
+    let peer = new(public_ip, lan_ip, 20001, &[1; 8]);
+    assert!(is_same_host(&local, &[1; 8], &[20000], &peer));
+    // 读取不到主机标识
+    assert!(!is_same_host(&local, &[], &[20000], &peer));
+    // 两个局域网使用了相同的网段
+    let peer = new(Ipv4Addr::new(2, 2, 2, 2), lan_ip, 20001, &[2; 8]);
+    assert!(!is_same_host(&local, &[1; 8], &[20000], &peer));
+    // 主机标识相同但公网ip不同
+    let peer = new(Ipv4Addr::new(2, 2, 2, 2), lan_ip, 20001, &[1; 8]);
+    assert!(!is_same_host(&local, &[1; 8], &[20000], &peer));
+    // 端口冲突说明不在同一个网络命名空间
+    let peer = new(public_ip, lan_ip, 20000, &[1; 8]);
+    assert!(!is_same_host(&local, &[1; 8], &[20000], &peer));
+}
+
+#[test]
+fn test_same_nat() {
+    let new = |public_ips: Vec<Ipv4Addr>, local: Ipv4Addr| {
+        NatInfo::new(
+            public_ips,
+            vec![10000],
+            0,
+            Some(local),
+            None,
+            vec![20000],
+            0,
+            NatType::Cone,
+        )
+    };
+    let local = new(
+        vec![Ipv4Addr::new(1, 1, 1, 1)],
+        Ipv4Addr::new(192, 168, 1, 2),
+    );
+    let peer = new(
+        vec![Ipv4Addr::new(2, 2, 2, 2), Ipv4Addr::new(1, 1, 1, 1)],
+        Ipv4Addr::new(192, 168, 1, 3),
+    );
+    assert!(peer.is_behind_same_nat(&local));
+    // 私有地址会被过滤，不能用来判断
+    let peer = new(
+        vec![Ipv4Addr::new(10, 0, 0, 1)],
+        Ipv4Addr::new(192, 168, 1, 3),
+    );
+    let local = new(
+        vec![Ipv4Addr::new(10, 0, 0, 1)],
+        Ipv4Addr::new(192, 168, 1, 2),
+    );
+    assert!(!peer.is_behind_same_nat(&local));
+}
@@ -0,0 +1,275 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::net::Ipv4Addr;
+
+use parking_lot::Mutex;
+
+use crate::channel::punch::NatType;
+use crate::handle::now_time;
+
+/// 每个对端保留的打洞记录数
+const ATTEMPT_CAPACITY: usize = 16;
+/// 打洞后超过这个时间(ms)还没有建立直连则视为超时
+const ATTEMPT_TIMEOUT: u64 = 10_000;
+
+/// 打洞使用的方式
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PunchStrategy {
+    // 连接对端的tcp监听
+    Tcp,
+    // 同一台主机上的实例
+    Loopback,
+    // 同一台主机上的实例，走unix域套接字
+    Uds,
+    // 对端的内网地址
+    Local,
+    // 和对端在同一个NAT后面时向局域网广播
+    Broadcast,
+    Ipv6,
+    // 对端是锥形网络，直接发到公网地址
+    Cone,
+    // 对端是对称网络，预测端口
+    SymmetricPrediction,
+}
+
+impl Display for PunchStrategy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            PunchStrategy::Tcp => "tcp",
+            PunchStrategy::Loopback => "loopback",
+            PunchStrategy::Uds => "uds",
+            PunchStrategy::Local => "local",
+            PunchStrategy::Broadcast => "broadcast",
+            PunchStrategy::Ipv6 => "ipv6",
+            PunchStrategy::Cone => "cone",
+            PunchStrategy::SymmetricPrediction => "symmetric-prediction",
+        };
+        f.write_str(str)
+    }
+}
+
+/// 打洞的结果
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PunchOutcome {
+    // 等待建立直连
+    Pending,
+    // 建立了直连，内容为通道地址
+    Established(String),
+    // tcp连接被拒绝，udp也没有建立直连
+    TcpRefused,
+    Timeout,
+    // 发送失败等错误
+    Error(String),
+}
+
+impl Display for PunchOutcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PunchOutcome::Pending => f.write_str("pending"),
+            PunchOutcome::Established(addr) => write!(f, "established {}", addr),
+            PunchOutcome::TcpRefused => f.write_str("tcp-refused"),
+            PunchOutcome::Timeout => f.write_str("timeout"),
+            PunchOutcome::Error(e) => write!(f, "error {}", e),
+        }
+    }
+}
+
+/// 一次打洞的记录
+#[derive(Clone, Debug)]
+pub struct PunchAttempt {
+    pub seq: u64,
+    // 毫秒时间戳
+    pub time: u64,
+    // 结束时间，未结束为0
+    pub end_time: u64,
+    pub nat_type: NatType,
+    // 每种方式发送的包数，tcp为连接次数
+    pub strategies: Vec<(PunchStrategy, u32)>,
+    pub tcp_refused: bool,
+    pub outcome: PunchOutcome,
+}
+
+impl PunchAttempt {
+    pub(crate) fn new(nat_type: NatType) -> Self {
+        Self {
+            seq: 0,
+            time: now_time(),
+            end_time: 0,
+            nat_type,
+            strategies: Vec::new(),
+            tcp_refused: false,
+            outcome: PunchOutcome::Pending,
+        }
+    }
+    pub(crate) fn sent(&mut self, strategy: PunchStrategy, packets: u32) {
+        if packets == 0 {
+            return;
+        }
+        match self.strategies.iter_mut().find(|(v, _)| *v == strategy) {
+            Some((_, num)) => *num += packets,
+            None => self.strategies.push((strategy, packets)),
+        }
+    }
+    pub fn packets(&self) -> u32 {
+        self.strategies.iter().map(|(_, num)| *num).sum()
+    }
+    fn finish(&mut self, outcome: PunchOutcome, end_time: u64) {
+        self.outcome = outcome;
+        self.end_time = end_time;
+    }
+    /// 未建立直连时结束记录，force为true时不等待超时，返回是否由这次调用结束
+    fn expire(&mut self, now: u64, force: bool) -> bool {
+        if self.outcome != PunchOutcome::Pending {
+            return false;
+        }
+        let deadline = self.time + ATTEMPT_TIMEOUT;
+        if force || now >= deadline {
+            let outcome = if self.tcp_refused {
+                PunchOutcome::TcpRefused
+            } else {
+                PunchOutcome::Timeout
+            };
+            self.finish(outcome, now.min(deadline));
+            return true;
+        }
+        false
+    }
+    /// 各方式发送的包数，如`local:4,cone:2`
+    pub fn strategies_str(&self) -> String {
+        self.strategies
+            .iter()
+            .map(|(strategy, num)| format!("{}:{}", strategy, num))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// 打洞记录，按对端保存最近的若干次
+pub struct PunchLog {
+    inner: Mutex<(u64, HashMap<Ipv4Addr, VecDeque<PunchAttempt>>)>,
+}
+
+impl PunchLog {
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new((0, HashMap::with_capacity(16))),
+        }
+    }
+    /// 记录一次已经发送完的打洞，之前未结束的记录视为超时，返回这些被结束的记录
+    pub(crate) fn push(&self, ip: Ipv4Addr, mut attempt: PunchAttempt) -> Vec<PunchAttempt> {
+        let mut guard = self.inner.lock();
+        let now = now_time();
+        guard.0 += 1;
+        attempt.seq = guard.0;
+        let list = guard.1.entry(ip).or_default();
+        let mut finished = Vec::new();
+        for v in list.iter_mut() {
+            if v.expire(now, true) {
+                finished.push(v.clone());
+            }
+        }
+        if list.len() >= ATTEMPT_CAPACITY {
+            list.pop_front();
+        }
+        if attempt.outcome != PunchOutcome::Pending && attempt.end_time == 0 {
+            attempt.end_time = now;
+        }
+        list.push_back(attempt);
+        finished
+    }
+    /// 建立了直连，结束最近一次未结束的记录，返回被结束的记录(已经超时的也会返回)
+    pub(crate) fn established(&self, ip: &Ipv4Addr, addr: String) -> Option<PunchAttempt> {
+        let now = now_time();
+        let mut guard = self.inner.lock();
+        let attempt = guard.1.get_mut(ip).and_then(|list| list.back_mut())?;
+        if attempt.expire(now, false) {
+            return Some(attempt.clone());
+        }
+        if attempt.outcome != PunchOutcome::Pending {
+            return None;
+        }
+        attempt.finish(PunchOutcome::Established(addr), now);
+        Some(attempt.clone())
+    }
+    /// 结束已经超时的记录，需要定时调用
+    pub(crate) fn expire(&self) -> Vec<(Ipv4Addr, PunchAttempt)> {
+        let now = now_time();
+        let mut guard = self.inner.lock();
+        let mut finished = Vec::new();
+        for (ip, list) in guard.1.iter_mut() {
+            for v in list.iter_mut() {
+                if v.expire(now, false) {
+                    finished.push((*ip, v.clone()));
+                }
+            }
+        }
+        finished
+    }
+    /// 对端的打洞记录，从旧到新
+    pub fn attempts(&self, ip: &Ipv4Addr) -> Vec<PunchAttempt> {
+        let now = now_time();
+        let guard = self.inner.lock();
+        match guard.1.get(ip) {
+            Some(list) => list
+                .iter()
+                .map(|v| {
+                    let mut v = v.clone();
+                    v.expire(now, false);
+                    v
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+    /// 去掉已经不在设备列表中的对端
+    pub fn retain(&self, ips: &HashSet<Ipv4Addr>) {
+        self.inner.lock().1.retain(|ip, _| ips.contains(ip));
+    }
+}
+
+#[test]
+fn test_punch_log() {
+    let log = PunchLog::new();
+    let ip = Ipv4Addr::new(10, 26, 0, 2);
+    let mut attempt = PunchAttempt::new(NatType::Symmetric);
+    attempt.sent(PunchStrategy::Local, 2);
+    attempt.sent(PunchStrategy::SymmetricPrediction, 60);
+    attempt.sent(PunchStrategy::Local, 2);
+    attempt.tcp_refused = true;
+    assert!(log.push(ip, attempt).is_empty());
+    // 新的打洞开始时上一次视为失败
+    let finished = log.push(ip, PunchAttempt::new(NatType::Cone));
+    assert_eq!(finished.len(), 1);
+    assert_eq!(finished[0].outcome, PunchOutcome::TcpRefused);
+    assert_eq!(
+        finished[0].strategies_str(),
+        "local:4,symmetric-prediction:60"
+    );
+    assert_eq!(
+        log.established(&ip, "1.2.3.4:5000".into())
+            .map(|v| v.outcome),
+        Some(PunchOutcome::Established("1.2.3.4:5000".into()))
+    );
+    // 已经结束的不再重复返回
+    assert!(log.established(&ip, "1.2.3.4:5001".into()).is_none());
+    assert!(log.expire().is_empty());
+    let list = log.attempts(&ip);
+    assert_eq!(list.len(), 2);
+    assert_eq!(list[0].packets(), 64);
+    assert_eq!(list[0].strategies[0], (PunchStrategy::Local, 4));
+    assert_eq!(list[0].outcome, PunchOutcome::TcpRefused);
+    assert_eq!(list[1].outcome.to_string(), "established 1.2.3.4:5000");
+    assert!(list[1].seq > list[0].seq);
+
+    // 超时
+    let mut attempt = PunchAttempt::new(NatType::Cone);
+    attempt.time -= ATTEMPT_TIMEOUT + 1;
+    log.push(ip, attempt);
+    assert_eq!(log.attempts(&ip)[2].outcome, PunchOutcome::Timeout);
+    let expired = log.expire();
+    assert_eq!(expired.len(), 1);
+    assert_eq!(expired[0].1.outcome, PunchOutcome::Timeout);
+    assert!(log.expire().is_empty());
+    log.retain(&HashSet::new());
+    assert!(log.attempts(&ip).is_empty());
+}
@@ -0,0 +1,167 @@
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use socket2::SockRef;
+
+use crate::protocol::{other_turn_packet, Protocol, HEAD_LEN};
+
+/// 隧道包的服务质量标记，让支持QoS的路由器优先转发
+pub struct Qos {
+    // 固定的DSCP值
+    dscp: Option<u8>,
+    // SO_PRIORITY，仅linux
+    #[cfg_attr(not(any(target_os = "linux", target_os = "android")), allow(dead_code))]
+    priority: Option<u32>,
+    // 按内层ip包的DSCP逐包标记外层udp
+    copy: bool,
+    // 控制包(握手、ping、打洞)的DSCP，和数据包区分开，大流量时不影响保活
+    control: Option<u8>,
+    // 每个核心udp socket当前的tos，避免重复设置
+    main_tos: Vec<AtomicU8>,
+}
+
+impl Qos {
+    pub fn new(dscp: Option<u8>, priority: Option<u32>, copy: bool, control: Option<u8>) -> Self {
+        Self {
+            dscp,
+            priority,
+            copy,
+            control,
+            main_tos: Vec::new(),
+        }
+    }
+    pub fn is_enable(&self) -> bool {
+        self.dscp.is_some() || self.priority.is_some() || self.copy || self.control.is_some()
+    }
+    /// 核心udp socket发送这个包时使用的DSCP，None表示不用修改
+    pub fn send_dscp(&self, buf: &[u8], dscp: Option<u8>) -> Option<u8> {
+        let control = match self.control {
+            Some(control) => control,
+            None => return dscp,
+        };
+        if is_control(buf) {
+            Some(control)
+        } else {
+            // 控制包改过标记，数据包要恢复
+            Some(dscp.or(self.dscp).unwrap_or(0))
+        }
+    }
+    pub fn is_copy(&self) -> bool {
+        self.copy
+    }
+    pub(crate) fn init_main(&mut self, channel_num: usize) {
+        let tos = self.dscp.unwrap_or(0) << 2;
+        self.main_tos = (0..channel_num).map(|_| AtomicU8::new(tos)).collect();
+    }
+    /// 设置socket的固定标记，失败只打印日志
+    pub fn mark(&self, socket: SockRef) {
+        if let Some(dscp) = self.dscp {
+            set_tos(&socket, dscp << 2);
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(priority) = self.priority {
+            if let Err(e) = set_priority(&socket, priority) {
+                log::warn!("设置SO_PRIORITY失败 {} {:?}", priority, e);
+            }
+        }
+    }
+    /// 发送前按内层包的DSCP标记核心udp socket，值没有变化时不用系统调用
+    pub fn copy_dscp(&self, index: usize, udp: &UdpSocket, dscp: u8) {
+        let tos = dscp << 2;
+        if let Some(current) = self.main_tos.get(index) {
+            if current.swap(tos, Ordering::Relaxed) != tos {
+                set_tos(&SockRef::from(udp), tos);
+            }
+        }
+    }
+}
+
+/// 握手、ping、打洞等控制包，包头不加密，直接按协议号区分
+pub fn is_control(buf: &[u8]) -> bool {
+    if buf.len() < HEAD_LEN {
+        return false;
+    }
+    match Protocol::from(buf[1]) {
+        Protocol::Service | Protocol::Error | Protocol::Control => true,
+        Protocol::OtherTurn => {
+            other_turn_packet::Protocol::from(buf[2]) == other_turn_packet::Protocol::Punch
+        }
+        _ => false,
+    }
+}
+
+fn set_tos(socket: &SockRef, tos: u8) {
+    // 双栈socket发往ipv4时使用IP_TOS，发往ipv6时使用IPV6_TCLASS
+    let is_v6 = socket
+        .local_addr()
+        .ok()
+        .and_then(|addr| addr.as_socket())
+        .map(|addr| addr.is_ipv6())
+        .unwrap_or(false);
+    if let Err(e) = socket.set_tos(tos as u32) {
+        if !is_v6 {
+            log::warn!("设置IP_TOS失败 {} {:?}", tos, e);
+        }
+    }
+    #[cfg(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "linux",
+        target_os = "macos",
+    ))]
+    if is_v6 {
+        if let Err(e) = socket.set_tclass_v6(tos as u32) {
+            log::warn!("设置IPV6_TCLASS失败 {} {:?}", tos, e);
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_priority(socket: &SockRef, priority: u32) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let value = priority as libc::c_int;
+    let rs = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PRIORITY,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rs == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_copy_dscp() {
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut qos = Qos::new(Some(10), None, true, None);
+    qos.init_main(1);
+    qos.mark(SockRef::from(&udp));
+    assert_eq!(SockRef::from(&udp).tos().unwrap(), 10 << 2);
+    qos.copy_dscp(0, &udp, 46);
+    assert_eq!(SockRef::from(&udp).tos().unwrap(), 46 << 2);
+}
+
+#[test]
+fn test_control_dscp() {
+    let mut buf = [0u8; HEAD_LEN];
+    buf[1] = 3;
+    let qos = Qos::new(Some(10), None, false, Some(48));
+    assert_eq!(qos.send_dscp(&buf, None), Some(48));
+    buf[1] = 4;
+    assert_eq!(qos.send_dscp(&buf, None), Some(10));
+    assert_eq!(qos.send_dscp(&buf, Some(46)), Some(46));
+    buf[1] = 5;
+    buf[2] = 1;
+    assert!(is_control(&buf));
+    buf[2] = 7;
+    assert!(!is_control(&buf));
+    assert_eq!(
+        Qos::new(None, None, false, None).send_dscp(&buf, None),
+        None
+    );
+}
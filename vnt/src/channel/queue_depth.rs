@@ -0,0 +1,80 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+/// 各个队列中等待处理的包数，更新和读取都只有原子操作。
+/// 入队前先计数，失败时再减去，避免出队先于计数时出现下溢
+#[derive(Default)]
+pub struct QueueDepth {
+    // 所有tcp连接发送队列的合计
+    tcp: AtomicUsize,
+    // tcp连接发送队列拥塞时丢弃的包数
+    tcp_drop: AtomicU64,
+    // 网卡数据分发给各个处理线程的队列，开启并行时才有
+    workers: OnceLock<Box<[AtomicUsize]>>,
+}
+
+impl QueueDepth {
+    pub(crate) fn tcp_add(&self) {
+        self.tcp.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn tcp_sub(&self, num: usize) {
+        self.tcp.fetch_sub(num, Ordering::Relaxed);
+    }
+    /// tcp通道发送队列中等待的包数
+    pub fn tcp(&self) -> usize {
+        self.tcp.load(Ordering::Relaxed)
+    }
+    pub(crate) fn tcp_drop_add(&self) {
+        self.tcp_drop.fetch_add(1, Ordering::Relaxed);
+    }
+    /// tcp通道发送队列拥塞时丢弃的包数
+    pub fn tcp_dropped(&self) -> u64 {
+        self.tcp_drop.load(Ordering::Relaxed)
+    }
+    pub(crate) fn init_workers(&self, num: usize) {
+        let _ = self
+            .workers
+            .set((0..num).map(|_| AtomicUsize::new(0)).collect());
+    }
+    pub(crate) fn worker_add(&self, index: usize) {
+        if let Some(v) = self.workers.get().and_then(|v| v.get(index)) {
+            v.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    pub(crate) fn worker_sub(&self, index: usize) {
+        if let Some(v) = self.workers.get().and_then(|v| v.get(index)) {
+            v.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+    /// 处理线程的数量，未开启并行时为0
+    pub fn worker_num(&self) -> usize {
+        self.workers.get().map(|v| v.len()).unwrap_or(0)
+    }
+    /// 第index个处理线程队列中等待的包数
+    pub fn worker(&self, index: usize) -> usize {
+        self.workers
+            .get()
+            .and_then(|v| v.get(index))
+            .map(|v| v.load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+}
+
+#[test]
+fn test_queue_depth() {
+    let depth = QueueDepth::default();
+    depth.tcp_add();
+    depth.tcp_add();
+    depth.tcp_sub(1);
+    assert_eq!(depth.tcp(), 1);
+    // 没有初始化时忽略
+    depth.worker_add(0);
+    assert_eq!(depth.worker_num(), 0);
+    depth.init_workers(2);
+    depth.worker_add(1);
+    depth.worker_add(1);
+    depth.worker_sub(1);
+    assert_eq!(depth.worker(0), 0);
+    assert_eq!(depth.worker(1), 1);
+    assert_eq!(depth.worker(2), 0);
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// 记录的中转对数量上限
+const MAX_PAIRS: usize = 1024;
+/// 超过这个时间没有中转数据的记录可以被淘汰
+const PAIR_EXPIRE: Duration = Duration::from_secs(600);
+/// 令牌桶容量，允许的突发时长
+const BURST: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug)]
+pub struct RelayPair {
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    // 转发的字节数和包数
+    pub bytes: u64,
+    pub packets: u64,
+    // 超出限额丢弃的字节数
+    pub dropped: u64,
+    pub last: Instant,
+}
+
+#[derive(Clone, Debug)]
+pub struct RelayStats {
+    // 中转限额(字节/秒)，0表示不限
+    pub budget: u64,
+    // 为其他设备转发的字节数
+    pub relayed: u64,
+    // 发给自己的字节数
+    pub terminal: u64,
+    pub dropped: u64,
+    pub pairs: Vec<RelayPair>,
+}
+
+/// 区分接收的数据是为其他设备中转的还是发给自己的，按来源和目标统计中转流量，
+/// 设置了限额时超出的中转包直接丢弃，限制贡献给中转的带宽
+pub struct RelayMeter {
+    budget: u64,
+    // 可用的字节数和上次补充的时间
+    bucket: Mutex<(f64, Instant)>,
+    relayed: AtomicU64,
+    terminal: AtomicU64,
+    dropped: AtomicU64,
+    pairs: Mutex<HashMap<(Ipv4Addr, Ipv4Addr), RelayPair>>,
+}
+
+impl RelayMeter {
+    /// budget的单位为KB/s，0表示不限
+    pub fn new(budget: u32) -> Self {
+        let budget = budget as u64 * 1024;
+        Self {
+            budget,
+            bucket: Mutex::new((budget as f64 * BURST.as_secs_f64(), Instant::now())),
+            relayed: AtomicU64::new(0),
+            terminal: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            pairs: Mutex::new(HashMap::with_capacity(16)),
+        }
+    }
+    /// 发给自己的数据
+    pub fn terminal(&self, len: usize) {
+        self.terminal.fetch_add(len as u64, Ordering::Relaxed);
+    }
+    /// 中转一个包，超出限额时返回false
+    pub fn relay(&self, source: Ipv4Addr, destination: Ipv4Addr, len: usize) -> bool {
+        let allow = self.take(len);
+        let len = len as u64;
+        if allow {
+            self.relayed.fetch_add(len, Ordering::Relaxed);
+        } else {
+            self.dropped.fetch_add(len, Ordering::Relaxed);
+        }
+        let now = Instant::now();
+        let mut pairs = self.pairs.lock();
+        if pairs.len() >= MAX_PAIRS && !pairs.contains_key(&(source, destination)) {
+            pairs.retain(|_, v| now.saturating_duration_since(v.last) < PAIR_EXPIRE);
+            if pairs.len() >= MAX_PAIRS {
+                return allow;
+            }
+        }
+        let pair = pairs
+            .entry((source, destination))
+            .or_insert_with(|| RelayPair {
+                source,
+                destination,
+                bytes: 0,
+                packets: 0,
+                dropped: 0,
+                last: now,
+            });
+        pair.last = now;
+        if allow {
+            pair.bytes += len;
+            pair.packets += 1;
+        } else {
+            pair.dropped += len;
+        }
+        allow
+    }
+    fn take(&self, len: usize) -> bool {
+        if self.budget == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let mut bucket = self.bucket.lock();
+        let elapsed = now.saturating_duration_since(bucket.1).as_secs_f64();
+        let capacity = self.budget as f64 * BURST.as_secs_f64();
+        bucket.0 = (bucket.0 + elapsed * self.budget as f64).min(capacity);
+        bucket.1 = now;
+        if bucket.0 >= len as f64 {
+            bucket.0 -= len as f64;
+            true
+        } else {
+            false
+        }
+    }
+    pub fn stats(&self) -> RelayStats {
+        let mut pairs: Vec<RelayPair> = self.pairs.lock().values().cloned().collect();
+        pairs.sort_by_key(|v| std::cmp::Reverse(v.bytes));
+        RelayStats {
+            budget: self.budget,
+            relayed: self.relayed.load(Ordering::Relaxed),
+            terminal: self.terminal.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            pairs,
+        }
+    }
+}
+
+#[test]
+fn test_relay_meter() {
+    let meter = RelayMeter::new(1);
+    let a = Ipv4Addr::new(10, 26, 0, 2);
+    let b = Ipv4Addr::new(10, 26, 0, 3);
+    meter.terminal(100);
+    assert!(meter.relay(a, b, 1000));
+    // 突发容量1KB用完
+    assert!(!meter.relay(a, b, 1000));
+    let stats = meter.stats();
+    assert_eq!(stats.terminal, 100);
+    assert_eq!(stats.relayed, 1000);
+    assert_eq!(stats.dropped, 1000);
+    assert_eq!(stats.pairs.len(), 1);
+    assert_eq!(stats.pairs[0].packets, 1);
+    assert!(RelayMeter::new(0).relay(a, b, 100_000));
+}
@@ -0,0 +1,752 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use arc_swap::ArcSwap;
+use crossbeam_utils::atomic::AtomicCell;
+use parking_lot::Mutex;
+
+use crate::channel::event::{RouteEventKind, RouteEventLog};
+use crate::channel::hysteresis::RouteHysteresis;
+use crate::channel::matrix::RouteMatrix;
+use crate::channel::punch_log::{PunchAttempt, PunchLog, PunchOutcome};
+use crate::channel::{LoadBalanceModel, Route, RouteKey, UseChannelType, DEFAULT_RT};
+
+/// 分片数量，按虚拟ip分散，不同设备的路由更新互不影响
+const SHARD_NUM: usize = 16;
+/// 负载均衡时认为延迟相近的容差(ms)
+const LOAD_BALANCE_RT_TOLERANCE: i64 = 10;
+
+// 路由和最近一次收到数据的时刻，时刻在新旧快照间共享，读快照时更新也不会丢失
+type RouteList = Vec<(Route, Arc<AtomicCell<Instant>>)>;
+type ShardMap = HashMap<Ipv4Addr, Arc<RouteList>>;
+
+enum RouteOp {
+    Add(Route, bool),
+    Remove(RouteKey),
+}
+
+struct Shard {
+    // 只读快照，发送数据时查询路由不加锁
+    snapshot: ArcSwap<ShardMap>,
+    // 等待写入的修改
+    pending: Mutex<Vec<(Ipv4Addr, RouteOp)>>,
+    // 同一时刻只有一个线程生成新快照
+    writer: Mutex<()>,
+}
+
+impl Shard {
+    fn new() -> Self {
+        Self {
+            snapshot: ArcSwap::from_pointee(HashMap::with_capacity(8)),
+            pending: Mutex::new(Vec::with_capacity(8)),
+            writer: Mutex::new(()),
+        }
+    }
+}
+
+/// 路由表。
+/// 按虚拟ip分片，每个分片保存一份不可变快照，读取时不加锁；
+/// 修改先放入分片的待写队列，拿到写锁的线程把队列中的修改合并后一次性发布新快照，
+/// 心跳和打洞并发更新路由时不必每次都复制快照。
+/// 没拿到写锁的修改由正在写入的线程处理，因此修改在返回后可能稍晚才可见
+pub struct RouteTable {
+    shards: Vec<Shard>,
+    pub(crate) first_latency: bool,
+    channel_num: usize,
+    pub(crate) use_channel_type: UseChannelType,
+    pub(crate) load_balance: LoadBalanceModel,
+    // 路由变化记录
+    pub event_log: RouteEventLog,
+    // 其他设备上报的路由，用于生成可达矩阵
+    pub route_matrix: RouteMatrix,
+    // 首选通道切换的迟滞
+    pub hysteresis: RouteHysteresis,
+    // 每个对端最近的打洞记录
+    pub punch_log: PunchLog,
+}
+
+impl RouteTable {
+    pub(crate) fn new(
+        use_channel_type: UseChannelType,
+        first_latency: bool,
+        channel_num: usize,
+        load_balance: LoadBalanceModel,
+        hysteresis: RouteHysteresis,
+    ) -> Self {
+        Self {
+            shards: (0..SHARD_NUM).map(|_| Shard::new()).collect(),
+            use_channel_type,
+            first_latency,
+            channel_num,
+            load_balance,
+            event_log: RouteEventLog::new(),
+            route_matrix: RouteMatrix::new(),
+            hysteresis,
+            punch_log: PunchLog::new(),
+        }
+    }
+    fn shard(&self, id: &Ipv4Addr) -> &Shard {
+        &self.shards[u32::from(*id) as usize % SHARD_NUM]
+    }
+    /// 在当前快照上读取id的路由
+    fn read<R, F: FnOnce(&RouteList) -> R>(&self, id: &Ipv4Addr, f: F) -> Option<R> {
+        self.shard(id).snapshot.load().get(id).map(|v| f(v))
+    }
+    fn for_each<F: FnMut(&Ipv4Addr, &RouteList)>(&self, mut f: F) {
+        for shard in &self.shards {
+            for (ip, routes) in shard.snapshot.load().iter() {
+                f(ip, routes)
+            }
+        }
+    }
+    /// 提交修改，拿到写锁时合并队列中所有的修改并发布新快照
+    fn submit(&self, id: Ipv4Addr, op: RouteOp) {
+        let shard = self.shard(&id);
+        shard.pending.lock().push((id, op));
+        loop {
+            match shard.writer.try_lock() {
+                Some(_guard) => self.apply(shard),
+                // 持有写锁的线程释放后会再检查队列
+                None => break,
+            }
+            // 释放写锁后再检查一次队列，持锁期间其他线程加入的修改由这里处理
+            if shard.pending.lock().is_empty() {
+                break;
+            }
+        }
+    }
+    /// 提交修改并等待写锁，返回时修改已经发布
+    fn submit_sync(&self, id: Ipv4Addr, op: RouteOp) {
+        let shard = self.shard(&id);
+        shard.pending.lock().push((id, op));
+        let _guard = shard.writer.lock();
+        self.apply(shard);
+    }
+    /// 合并队列中的修改，调用方需持有写锁
+    fn apply(&self, shard: &Shard) {
+        let ops = std::mem::take(&mut *shard.pending.lock());
+        if ops.is_empty() {
+            return;
+        }
+        let mut map = ShardMap::clone(&shard.snapshot.load());
+        for (id, op) in ops {
+            match op {
+                RouteOp::Add(route, only_if_absent) => {
+                    self.add_route_(&mut map, id, route, only_if_absent)
+                }
+                RouteOp::Remove(route_key) => self.remove_route_(&mut map, id, route_key),
+            }
+        }
+        shard.snapshot.store(Arc::new(map));
+    }
+}
+
+impl RouteTable {
+    pub(crate) fn get_route_by_id(&self, index: usize, id: &Ipv4Addr) -> io::Result<Route> {
+        let route = self.read(id, |v| {
+            if self.first_latency {
+                return v.first().map(|(route, _)| *route);
+            }
+            let len = v.len();
+            if len == 0 {
+                return None;
+            }
+            let route = &v[index % len].0;
+            // 跳过默认rt的路由(一般是刚加入的)，这有助于提升稳定性
+            if route.rt != DEFAULT_RT {
+                return Some(*route);
+            }
+            v.iter()
+                .map(|(route, _)| *route)
+                .find(|route| route.rt != DEFAULT_RT)
+        });
+        match route {
+            Some(Some(route)) => Ok(route),
+            _ => Err(io::Error::new(io::ErrorKind::NotFound, "route not found")),
+        }
+    }
+    /// 按负载均衡策略返回候选通道，排在前面的优先使用。
+    /// 和get_route_by_id一样使用路由表中的顺序，首选通道经过了迟滞判断，这里不再按延迟重新排序
+    pub(crate) fn get_route_by_flow(&self, id: &Ipv4Addr, flow: u32) -> Vec<Route> {
+        self.read(id, |v| {
+            let mut list = Vec::new();
+            let mut p2p: Vec<Route> = v
+                .iter()
+                .map(|(route, _)| *route)
+                .filter(|route| route.is_p2p() && route.rt != DEFAULT_RT)
+                .collect();
+            if p2p.is_empty() {
+                return list;
+            }
+            if self.load_balance == LoadBalanceModel::Flow {
+                // 只在和首选通道延迟相近的通道间分流，避免把流量分到明显更慢的通道上
+                let first_rt = p2p[0].rt;
+                let close = p2p
+                    .iter()
+                    .take_while(|route| route.rt <= first_rt * 2 + LOAD_BALANCE_RT_TOLERANCE)
+                    .count();
+                p2p[..close].rotate_left(flow as usize % close);
+            }
+            list.extend(p2p);
+            // 非直连的通道作为最后的备选
+            for (route, _) in v {
+                if !route.is_p2p() && route.rt != DEFAULT_RT {
+                    list.push(*route);
+                }
+            }
+            list
+        })
+        .unwrap_or_default()
+    }
+    pub fn add_route_if_absent(&self, id: Ipv4Addr, route: Route) {
+        // 限制通道类型
+        if self.use_channel_type == UseChannelType::P2p && !route.is_p2p() {
+            return;
+        }
+        let key = route.route_key();
+        let exist = self.read(&id, |list| list.iter().any(|(x, _)| x.route_key() == key));
+        if exist == Some(true) {
+            return;
+        }
+        self.submit(id, RouteOp::Add(route, true))
+    }
+    pub fn add_route(&self, id: Ipv4Addr, route: Route) {
+        if self.use_channel_type == UseChannelType::P2p && !route.is_p2p() {
+            return;
+        }
+        self.submit(id, RouteOp::Add(route, false))
+    }
+    fn add_route_(&self, map: &mut ShardMap, id: Ipv4Addr, route: Route, only_if_absent: bool) {
+        let key = route.route_key();
+        let list = Arc::make_mut(
+            map.entry(id)
+                .or_insert_with(|| Arc::new(Vec::with_capacity(4))),
+        );
+        let mut exist = false;
+        for (x, time) in list.iter_mut() {
+            if x.metric < route.metric && !self.first_latency {
+                //非优先延迟的情况下 不能比当前的路径更长
+                return;
+            }
+            if x.route_key() == key {
+                if only_if_absent {
+                    return;
+                }
+                if x.metric != route.metric {
+                    self.event_log.push(
+                        id,
+                        RouteEventKind::MetricChange,
+                        format!("{} {} -> {}", x.addr, x.metric, route.metric),
+                    );
+                }
+                x.metric = route.metric;
+                x.update_rt(route.rt);
+                exist = true;
+                time.store(Instant::now());
+                break;
+            }
+        }
+        if exist {
+            // 这个排序还有待优化，因为后加入的大概率排最后，被直接淘汰的概率也大，可能导致更好的通道被移除了
+            self.sort_(id, list);
+            //如果延迟都稳定了，则去除多余通道
+            for (route, _) in list.iter() {
+                if route.rt == DEFAULT_RT {
+                    return;
+                }
+            }
+            //延迟优先模式需要更多的通道探测延迟最低的路线
+            let limit_len = if self.first_latency {
+                self.channel_num + 2
+            } else {
+                self.channel_num
+            };
+            self.truncate_(list, limit_len);
+        } else {
+            if !self.first_latency {
+                if route.is_p2p() {
+                    //非优先延迟的情况下 添加了直连的则排除非直连的
+                    list.retain(|(k, _)| k.is_p2p());
+                }
+            };
+            //增加路由表容量，避免波动
+            let limit_len = self.channel_num * 2;
+            self.sort_(id, list);
+            self.truncate_(list, limit_len);
+            list.push((route, Arc::new(AtomicCell::new(Instant::now()))));
+            self.event_log.push(
+                id,
+                RouteEventKind::Add,
+                format!(
+                    "{}{} metric={}",
+                    route_prefix(&route),
+                    route.addr,
+                    route.metric
+                ),
+            );
+            if route.is_p2p() {
+                if let Some(attempt) = self
+                    .punch_log
+                    .established(&id, format!("{}{}", route_prefix(&route), route.addr))
+                {
+                    self.punch_finished(id, &attempt);
+                }
+            }
+        }
+    }
+    /// 记录一次打洞，之前还在等待的打洞记为超时
+    pub(crate) fn push_punch(&self, id: Ipv4Addr, attempt: PunchAttempt) {
+        for attempt in self.punch_log.push(id, attempt) {
+            self.punch_finished(id, &attempt);
+        }
+    }
+    /// 打洞超时没有建立直连的记录超时事件，需要定时调用
+    pub(crate) fn expire_punch(&self) {
+        for (id, attempt) in self.punch_log.expire() {
+            self.punch_finished(id, &attempt);
+        }
+    }
+    fn punch_finished(&self, id: Ipv4Addr, attempt: &PunchAttempt) {
+        let elapsed = attempt.end_time.saturating_sub(attempt.time);
+        match &attempt.outcome {
+            PunchOutcome::Established(addr) => self.event_log.push(
+                id,
+                RouteEventKind::PunchSuccess,
+                format!("{} elapsed={}ms", addr, elapsed),
+            ),
+            PunchOutcome::Timeout | PunchOutcome::TcpRefused => self.event_log.push(
+                id,
+                RouteEventKind::PunchTimeout,
+                format!(
+                    "reason={} nat={:?} elapsed={}ms packets={} strategies={}",
+                    attempt.outcome,
+                    attempt.nat_type,
+                    elapsed,
+                    attempt.packets(),
+                    attempt.strategies_str()
+                ),
+            ),
+            PunchOutcome::Pending | PunchOutcome::Error(_) => {}
+        }
+    }
+    /// 按延迟排序，首选通道变化时经过迟滞判断
+    fn sort_(&self, id: Ipv4Addr, list: &mut RouteList) {
+        let current = match list.first() {
+            Some((route, _)) => route.route_key(),
+            None => return,
+        };
+        list.sort_by_key(|(k, _)| k.rt);
+        let best = list[0].0;
+        if best.route_key() == current {
+            return;
+        }
+        if let Some(index) = list.iter().position(|(k, _)| k.route_key() == current) {
+            if self.hysteresis.keep(&id, &list[index].0, &best) {
+                let item = list.remove(index);
+                list.insert(0, item);
+                return;
+            }
+        }
+        self.switch_(id, best);
+    }
+    fn switch_(&self, id: Ipv4Addr, route: Route) {
+        self.event_log.push(
+            id,
+            RouteEventKind::Switch,
+            format!(
+                "{}{} metric={} rt={}",
+                route_prefix(&route),
+                route.addr,
+                route.metric,
+                route.rt
+            ),
+        );
+        self.hysteresis.switch(id, route);
+    }
+    fn truncate_(&self, list: &mut RouteList, len: usize) {
+        if list.len() <= len {
+            return;
+        }
+        if self.first_latency {
+            //找到第一个p2p通道
+            if let Some(index) =
+                list.iter()
+                    .enumerate()
+                    .find_map(|(index, (route, _))| if route.is_p2p() { Some(index) } else { None })
+            {
+                if index >= len {
+                    //保留第一个p2p通道
+                    let route = list.remove(index);
+                    list.truncate(len - 1);
+                    list.push(route);
+                    return;
+                }
+            }
+        }
+        list.truncate(len);
+    }
+    pub fn route(&self, id: &Ipv4Addr) -> Option<Vec<Route>> {
+        self.read(id, |v| v.iter().map(|(i, _)| *i).collect())
+    }
+    pub fn route_one(&self, id: &Ipv4Addr) -> Option<Route> {
+        self.read(id, |v| v.first().map(|(i, _)| *i)).flatten()
+    }
+    pub fn route_one_p2p(&self, id: &Ipv4Addr) -> Option<Route> {
+        self.read(id, |v| v.iter().map(|(i, _)| *i).find(|i| i.is_p2p()))
+            .flatten()
+    }
+    /// 是否有测出延迟的直连路由，只收到对端的数据但发不出去时没有
+    pub fn has_usable_p2p(&self, id: &Ipv4Addr) -> bool {
+        self.read(id, |v| {
+            v.iter()
+                .any(|(route, _)| route.is_p2p() && route.rt != DEFAULT_RT)
+        })
+        .unwrap_or(false)
+    }
+    pub fn route_to_id(&self, route_key: &RouteKey) -> Option<Ipv4Addr> {
+        let mut id = None;
+        self.for_each(|k, v| {
+            if id.is_none()
+                && v.iter()
+                    .any(|(route, _)| &route.route_key() == route_key && route.is_p2p())
+            {
+                id = Some(*k);
+            }
+        });
+        id
+    }
+    pub fn no_need_punch(&self, id: &Ipv4Addr) -> bool {
+        //p2p的通道数符合要求
+        self.p2p_num(id) >= self.channel_num
+    }
+    pub fn p2p_num(&self, id: &Ipv4Addr) -> usize {
+        self.read(id, |v| v.iter().filter(|(k, _)| k.is_p2p()).count())
+            .unwrap_or(0)
+    }
+    /// 返回所有路由
+    pub fn route_table(&self) -> Vec<(Ipv4Addr, Vec<Route>)> {
+        let mut list = Vec::with_capacity(8);
+        self.for_each(|k, v| list.push((*k, v.iter().map(|(i, _)| *i).collect())));
+        list
+    }
+    pub fn route_table_p2p(&self) -> Vec<(Ipv4Addr, Route)> {
+        let mut list = Vec::with_capacity(8);
+        self.for_each(|ip, routes| {
+            if let Some((route, _)) = routes.iter().find(|(route, _)| route.is_p2p()) {
+                list.push((*ip, *route));
+            }
+        });
+        list
+    }
+    pub fn route_table_one(&self) -> Vec<(Ipv4Addr, Route)> {
+        let mut list = Vec::with_capacity(8);
+        self.for_each(|k, v| {
+            if let Some((route, _)) = v.first() {
+                list.push((*k, *route));
+            }
+        });
+        list
+    }
+    /// 所有路由和最近一次收到数据的时刻
+    pub fn route_read_time(&self) -> Vec<(Ipv4Addr, Route, Instant)> {
+        let mut list = Vec::with_capacity(8);
+        self.for_each(|k, v| {
+            for (route, time) in v {
+                list.push((*k, *route, time.load()));
+            }
+        });
+        list
+    }
+    pub fn remove_route(&self, id: &Ipv4Addr, route_key: RouteKey) {
+        self.submit(*id, RouteOp::Remove(route_key))
+    }
+    /// 移除路由，返回时新的快照已经可见
+    pub fn remove_route_sync(&self, id: &Ipv4Addr, route_key: RouteKey) {
+        self.submit_sync(*id, RouteOp::Remove(route_key))
+    }
+    fn remove_route_(&self, map: &mut ShardMap, id: Ipv4Addr, route_key: RouteKey) {
+        if let Some(routes) = map.get_mut(&id) {
+            if !routes.iter().any(|(x, _)| x.route_key() == route_key) {
+                return;
+            }
+            let routes = Arc::make_mut(routes);
+            let first = routes.first().map(|(x, _)| x.route_key());
+            routes.retain(|(x, _)| x.route_key() != route_key);
+            if first == Some(route_key) {
+                if let Some((route, _)) = routes.first() {
+                    self.switch_(id, *route);
+                }
+            }
+            self.event_log.push(
+                id,
+                RouteEventKind::Remove,
+                format!(
+                    "{}{}",
+                    if route_key.is_tcp() { "tcp@" } else { "" },
+                    route_key.addr
+                ),
+            );
+            if routes.is_empty() {
+                map.remove(&id);
+            }
+        }
+    }
+    /// 更新路由入栈包的时刻，长时间没有收到数据的路由将会被剔除
+    pub fn update_read_time(&self, id: &Ipv4Addr, route_key: &RouteKey) {
+        self.read(id, |routes| {
+            if let Some((_, time)) = routes
+                .iter()
+                .find(|(route, _)| &route.route_key() == route_key)
+            {
+                time.store(Instant::now());
+            }
+        });
+    }
+}
+
+/// 事件和打洞记录中标注通道类型
+fn route_prefix(route: &Route) -> &'static str {
+    if route.is_tcp {
+        "tcp@"
+    } else if route.is_uds() {
+        "uds@"
+    } else {
+        ""
+    }
+}
+
+#[cfg(test)]
+fn test_table() -> RouteTable {
+    RouteTable::new(
+        UseChannelType::All,
+        false,
+        2,
+        LoadBalanceModel::None,
+        RouteHysteresis::new(0, 0),
+    )
+}
+
+#[cfg(test)]
+fn test_route(port: u16, rt: i64) -> Route {
+    Route::new(false, 0, ([192, 168, 1, 2], port).into(), 1, rt)
+}
+
+#[test]
+fn test_route_table() {
+    let table = test_table();
+    let id = Ipv4Addr::new(10, 26, 0, 2);
+    table.add_route_if_absent(id, test_route(1000, 20));
+    table.add_route_if_absent(id, test_route(1000, 50));
+    table.add_route(id, test_route(1001, 10));
+    assert_eq!(table.route(&id).unwrap().len(), 2);
+    assert_eq!(table.get_route_by_id(1, &id).unwrap().addr.port(), 1001);
+    let key = test_route(1001, 0).route_key();
+    assert_eq!(table.route_to_id(&key), Some(id));
+    table.remove_route(&id, key);
+    assert_eq!(table.route_one(&id).unwrap().addr.port(), 1000);
+    table.remove_route(&id, test_route(1000, 0).route_key());
+    assert!(table.route(&id).is_none());
+    assert!(table.get_route_by_id(0, &id).is_err());
+}
+
+#[test]
+fn test_route_by_flow_hysteresis() {
+    let table = RouteTable::new(
+        UseChannelType::All,
+        false,
+        2,
+        LoadBalanceModel::None,
+        RouteHysteresis::new(10, 0),
+    );
+    let id = Ipv4Addr::new(10, 26, 0, 2);
+    table.add_route(id, test_route(1000, 50));
+    table.add_route(id, test_route(1001, 45));
+    table.add_route(id, test_route(1001, 45));
+    // 延迟优势不足10ms，保持当前通道
+    assert_eq!(table.get_route_by_id(0, &id).unwrap().addr.port(), 1000);
+    let list = table.get_route_by_flow(&id, 0);
+    assert_eq!(list.len(), 2);
+    assert_eq!(list[0].addr.port(), 1000);
+}
+
+#[test]
+fn test_route_by_flow() {
+    let table = RouteTable::new(
+        UseChannelType::All,
+        false,
+        4,
+        LoadBalanceModel::Flow,
+        RouteHysteresis::new(0, 0),
+    );
+    let id = Ipv4Addr::new(10, 26, 0, 2);
+    for port in 1000..1003 {
+        table.add_route(id, test_route(port, 20));
+    }
+    // 延迟明显更高的通道不参与分流
+    table.add_route(id, test_route(1003, 200));
+    let first = |flow: u32| table.get_route_by_flow(&id, flow)[0].addr.port();
+    // 同一条流总是选择同一个通道
+    for flow in [0, 1, 7, 0x811c9dc5] {
+        assert_eq!(first(flow), first(flow));
+        assert_eq!(table.get_route_by_flow(&id, flow).len(), 4);
+    }
+    // 不同的流分散到延迟相近的通道上
+    let ports: std::collections::HashSet<u16> = (0..30).map(first).collect();
+    assert_eq!(ports.len(), 3);
+    assert!(!ports.contains(&1003));
+    assert_eq!(table.get_route_by_flow(&id, 5)[3].addr.port(), 1003);
+}
+
+#[test]
+fn test_route_table_concurrent_submit() {
+    const THREADS: u32 = 8;
+    const ROUNDS: u16 = 200;
+    let table = test_table();
+    // 同一个分片的不同设备，争抢同一把写锁
+    let ids: Vec<Ipv4Addr> = (0..THREADS)
+        .map(|i| Ipv4Addr::from(0x0A1A_0000 + i * SHARD_NUM as u32))
+        .collect();
+    std::thread::scope(|s| {
+        for id in &ids {
+            let table = &table;
+            s.spawn(move || {
+                for port in 0..ROUNDS {
+                    table.add_route(*id, test_route(1000 + port, 10));
+                    table.remove_route(id, test_route(1000 + port, 0).route_key());
+                }
+                table.add_route(*id, test_route(999, 10));
+            });
+        }
+    });
+    for id in &ids {
+        let routes = table.route(id).unwrap();
+        assert_eq!(routes.len(), 1, "{}", id);
+        assert_eq!(routes[0].addr.port(), 999);
+    }
+    assert!(table.shards.iter().all(|v| v.pending.lock().is_empty()));
+}
+
+#[test]
+fn test_route_table_remove_sync() {
+    let table = test_table();
+    let id = Ipv4Addr::new(10, 26, 0, 2);
+    table.add_route(id, test_route(1000, 10));
+    table.add_route(id, test_route(1001, 20));
+    std::thread::scope(|s| {
+        // 其他线程持有写锁时，普通的移除只进入队列
+        let guard = table.shard(&id).writer.lock();
+        table.remove_route(&id, test_route(1000, 0).route_key());
+        assert_eq!(table.route(&id).unwrap().len(), 2);
+        let handle = s.spawn(|| table.remove_route_sync(&id, test_route(1001, 0).route_key()));
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!handle.is_finished());
+        drop(guard);
+        handle.join().unwrap();
+        // 返回时队列中的修改都已发布
+        assert!(table.route(&id).is_none());
+    });
+}
+
+/// 并发读写的性能对比，cargo test --release route_table_bench -- --ignored --nocapture
+#[test]
+#[ignore]
+fn route_table_bench() {
+    use parking_lot::RwLock;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    const PEERS: u32 = 64;
+    const READERS: usize = 4;
+    const DURATION: Duration = Duration::from_secs(2);
+    let ids: Vec<Ipv4Addr> = (0..PEERS)
+        .map(|i| Ipv4Addr::from(0x0A1A_0002 + i))
+        .collect();
+
+    fn run<R, W>(readers: usize, read: R, write: W) -> (u64, u64)
+    where
+        R: Fn(u64) + Sync,
+        W: Fn(u64) + Sync,
+    {
+        let stop = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            let read_handles: Vec<_> = (0..readers)
+                .map(|_| {
+                    s.spawn(|| {
+                        let mut n = 0u64;
+                        while !stop.load(Ordering::Relaxed) {
+                            read(n);
+                            n += 1;
+                        }
+                        n
+                    })
+                })
+                .collect();
+            let write_handle = s.spawn(|| {
+                let mut n = 0u64;
+                while !stop.load(Ordering::Relaxed) {
+                    write(n);
+                    n += 1;
+                }
+                n
+            });
+            std::thread::sleep(DURATION);
+            stop.store(true, Ordering::Relaxed);
+            let reads = read_handles.into_iter().map(|h| h.join().unwrap()).sum();
+            (reads, write_handle.join().unwrap())
+        })
+    }
+
+    let table = test_table();
+    for id in &ids {
+        table.add_route(*id, test_route(1000, 10));
+        table.add_route(*id, test_route(1001, 20));
+    }
+    let (reads, writes) = run(
+        READERS,
+        |n| {
+            let id = &ids[n as usize % ids.len()];
+            assert!(table.get_route_by_id(n as usize, id).is_ok());
+        },
+        |n| {
+            let id = ids[n as usize % ids.len()];
+            table.add_route(id, test_route(1000 + (n % 2) as u16, (n % 30) as i64 + 1));
+        },
+    );
+
+    // 对比：单个读写锁保护的路由表
+    let locked: RwLock<HashMap<Ipv4Addr, Vec<Route>>> = RwLock::new(
+        ids.iter()
+            .map(|id| (*id, vec![test_route(1000, 10), test_route(1001, 20)]))
+            .collect(),
+    );
+    let (locked_reads, locked_writes) = run(
+        READERS,
+        |n| {
+            let id = &ids[n as usize % ids.len()];
+            let guard = locked.read();
+            let routes = guard.get(id).unwrap();
+            assert!(routes[n as usize % routes.len()].rt != DEFAULT_RT);
+        },
+        |n| {
+            let id = ids[n as usize % ids.len()];
+            let mut guard = locked.write();
+            let routes = guard.get_mut(&id).unwrap();
+            routes[(n % 2) as usize].update_rt((n % 30) as i64 + 1);
+            routes.sort_by_key(|route| route.rt);
+        },
+    );
+    let secs = DURATION.as_secs_f64();
+    println!(
+        "sharded: {:.0} reads/s {:.0} writes/s",
+        reads as f64 / secs,
+        writes as f64 / secs
+    );
+    println!(
+        "rwlock:  {:.0} reads/s {:.0} writes/s",
+        locked_reads as f64 / secs,
+        locked_writes as f64 / secs
+    );
+}
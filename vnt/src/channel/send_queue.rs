@@ -0,0 +1,228 @@
+use std::collections::VecDeque;
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use parking_lot::Mutex;
+
+/// 发送队列满时的丢包策略
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DropPolicy {
+    /// 丢弃新的包
+    TailDrop,
+    /// 丢弃队列中最旧的包，保证新数据的时效
+    DropOldest,
+}
+
+impl FromStr for DropPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "tail" => Ok(DropPolicy::TailDrop),
+            "oldest" => Ok(DropPolicy::DropOldest),
+            _ => Err(format!("not match '{}', enum: tail/oldest", s)),
+        }
+    }
+}
+
+/// tcp连接发送队列的水位，达到高水位后进入拥塞状态，
+/// 拥塞期间按策略丢包，直到队列降到低水位以下
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SendQueueConfig {
+    pub high: usize,
+    pub low: usize,
+    pub policy: DropPolicy,
+}
+
+impl Default for SendQueueConfig {
+    fn default() -> Self {
+        Self {
+            high: 128,
+            low: 64,
+            policy: DropPolicy::TailDrop,
+        }
+    }
+}
+
+impl SendQueueConfig {
+    pub fn new(high: usize, low: usize, policy: DropPolicy) -> Result<Self, String> {
+        if high == 0 || low >= high {
+            return Err(format!("send queue watermark high={} low={}", high, low));
+        }
+        Ok(Self { high, low, policy })
+    }
+}
+
+impl FromStr for SendQueueConfig {
+    type Err = String;
+
+    /// 格式为 高水位[,低水位][,tail|oldest]，低水位默认为高水位的一半
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut high = None;
+        let mut low = None;
+        let mut policy = DropPolicy::TailDrop;
+        for item in s.split(',').map(|v| v.trim()) {
+            match item.parse::<usize>() {
+                Ok(v) => {
+                    if high.is_none() {
+                        high = Some(v);
+                    } else if low.is_none() {
+                        low = Some(v);
+                    } else {
+                        return Err(format!("send queue '{}'", s));
+                    }
+                }
+                Err(_) => policy = item.parse()?,
+            }
+        }
+        let high = high.unwrap_or(Self::default().high);
+        Self::new(high, low.unwrap_or(high / 2), policy)
+    }
+}
+
+/// 单个tcp连接的发送队列，写线程取出后发送
+pub struct SendQueue {
+    config: SendQueueConfig,
+    queue: Mutex<QueueState>,
+    closed: AtomicBool,
+}
+
+struct QueueState {
+    list: VecDeque<Vec<u8>>,
+    // 是否处于拥塞状态
+    congested: bool,
+    // 排在队首的控制包数量
+    urgent: usize,
+}
+
+/// 入队结果，丢弃了包时由调用方计数
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Pushed {
+    Ok,
+    // 丢弃了最旧的包，新包已入队
+    DropOldest,
+}
+
+impl SendQueue {
+    pub fn new(config: SendQueueConfig) -> Self {
+        Self {
+            config,
+            queue: Mutex::new(QueueState {
+                list: VecDeque::with_capacity(config.low),
+                congested: false,
+                urgent: 0,
+            }),
+            closed: AtomicBool::new(false),
+        }
+    }
+    pub(crate) fn push(&self, buf: Vec<u8>) -> io::Result<Pushed> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(io::Error::from(io::ErrorKind::WriteZero));
+        }
+        let mut guard = self.queue.lock();
+        let state = &mut *guard;
+        if state.list.len() >= self.config.high {
+            state.congested = true;
+        }
+        if !state.congested {
+            state.list.push_back(buf);
+            return Ok(Pushed::Ok);
+        }
+        match self.config.policy {
+            DropPolicy::TailDrop => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+            DropPolicy::DropOldest => {
+                if state.urgent >= state.list.len() {
+                    return Err(io::Error::from(io::ErrorKind::WouldBlock));
+                }
+                // 不丢弃控制包
+                state.list.remove(state.urgent);
+                state.list.push_back(buf);
+                Ok(Pushed::DropOldest)
+            }
+        }
+    }
+    /// 控制包排在数据包前面，不受水位限制，避免大流量时保活超时
+    pub(crate) fn push_urgent(&self, buf: Vec<u8>) -> io::Result<Pushed> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(io::Error::from(io::ErrorKind::WriteZero));
+        }
+        let mut guard = self.queue.lock();
+        let state = &mut *guard;
+        if state.urgent >= self.config.high {
+            return Err(io::Error::from(io::ErrorKind::WouldBlock));
+        }
+        state.list.insert(state.urgent, buf);
+        state.urgent += 1;
+        Ok(Pushed::Ok)
+    }
+    pub(crate) fn pop(&self) -> Option<Vec<u8>> {
+        let mut guard = self.queue.lock();
+        let state = &mut *guard;
+        let buf = state.list.pop_front();
+        if state.urgent > 0 {
+            state.urgent -= 1;
+        }
+        if state.congested && state.list.len() <= self.config.low {
+            state.congested = false;
+        }
+        buf
+    }
+    /// 连接关闭，返回丢弃的包数
+    pub(crate) fn close(&self) -> usize {
+        self.closed.store(true, Ordering::Release);
+        let mut guard = self.queue.lock();
+        let len = guard.list.len();
+        guard.list.clear();
+        guard.urgent = 0;
+        len
+    }
+    pub fn is_congested(&self) -> bool {
+        self.queue.lock().congested
+    }
+}
+
+#[test]
+fn test_send_queue() {
+    let config: SendQueueConfig = "4,2".parse().unwrap();
+    assert_eq!(config.policy, DropPolicy::TailDrop);
+    assert!("4,4".parse::<SendQueueConfig>().is_err());
+    assert_eq!(
+        "8,oldest".parse::<SendQueueConfig>().unwrap(),
+        SendQueueConfig::new(8, 4, DropPolicy::DropOldest).unwrap()
+    );
+    let queue = SendQueue::new(config);
+    for i in 0..4u8 {
+        assert_eq!(queue.push(vec![i]).unwrap(), Pushed::Ok);
+    }
+    assert!(queue.push(vec![4]).is_err());
+    assert!(queue.is_congested());
+    // 降到低水位之前仍然丢弃
+    assert_eq!(queue.pop(), Some(vec![0]));
+    assert!(queue.push(vec![5]).is_err());
+    assert_eq!(queue.pop(), Some(vec![1]));
+    assert!(!queue.is_congested());
+    assert_eq!(queue.push(vec![6]).unwrap(), Pushed::Ok);
+    assert_eq!(queue.close(), 3);
+    assert!(queue.push(vec![7]).is_err());
+
+    let queue = SendQueue::new(SendQueueConfig::new(2, 1, DropPolicy::DropOldest).unwrap());
+    queue.push(vec![0]).unwrap();
+    queue.push(vec![1]).unwrap();
+    assert_eq!(queue.push(vec![2]).unwrap(), Pushed::DropOldest);
+    assert_eq!(queue.pop(), Some(vec![1]));
+    assert_eq!(queue.pop(), Some(vec![2]));
+    assert_eq!(queue.pop(), None);
+
+    let queue = SendQueue::new(SendQueueConfig::new(2, 1, DropPolicy::DropOldest).unwrap());
+    queue.push(vec![0]).unwrap();
+    queue.push(vec![1]).unwrap();
+    queue.push_urgent(vec![10]).unwrap();
+    queue.push_urgent(vec![11]).unwrap();
+    // 拥塞时只丢弃数据包
+    assert_eq!(queue.push(vec![2]).unwrap(), Pushed::DropOldest);
+    assert_eq!(queue.pop(), Some(vec![10]));
+    assert_eq!(queue.pop(), Some(vec![11]));
+    assert_eq!(queue.pop(), Some(vec![1]));
+    assert_eq!(queue.pop(), Some(vec![2]));
+}
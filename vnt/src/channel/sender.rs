@@ -7,6 +7,9 @@ use mio::Token;
 
 use crate::channel::context::ChannelContext;
 use crate::channel::notify::{AcceptNotify, WritableNotify};
+use crate::channel::qos::is_control;
+use crate::channel::queue_depth::QueueDepth;
+use crate::channel::send_queue::{Pushed, SendQueue};
 
 #[derive(Clone)]
 pub struct ChannelSender {
@@ -60,12 +63,18 @@ pub struct PacketSender {
 }
 
 impl PacketSender {
-    pub fn new(notify: WritableNotify, buffer: SyncSender<Vec<u8>>, token: Token) -> Self {
+    pub fn new(
+        notify: WritableNotify,
+        buffer: Arc<SendQueue>,
+        token: Token,
+        queue_depth: Arc<QueueDepth>,
+    ) -> Self {
         Self {
             inner: Arc::new(PacketSenderInner {
                 token,
                 notify,
                 buffer,
+                queue_depth,
             }),
         }
     }
@@ -81,7 +90,8 @@ impl PacketSender {
 pub struct PacketSenderInner {
     token: Token,
     notify: WritableNotify,
-    buffer: SyncSender<Vec<u8>>,
+    buffer: Arc<SendQueue>,
+    queue_depth: Arc<QueueDepth>,
 }
 
 impl PacketSenderInner {
@@ -91,12 +101,28 @@ impl PacketSenderInner {
         let mut buf_vec = Vec::with_capacity(buf.len() + 4);
         buf_vec.extend_from_slice(&[0, 0, (len >> 8) as u8, (len & 0xFF) as u8]);
         buf_vec.extend_from_slice(buf);
-        match self.buffer.try_send(buf_vec) {
-            Ok(_) => self.notify.notify(self.token, true),
-            Err(e) => match e {
-                TrySendError::Disconnected(_) => Err(io::Error::from(io::ErrorKind::WriteZero)),
-                TrySendError::Full(_) => Err(io::Error::from(io::ErrorKind::WouldBlock)),
-            },
+        self.queue_depth.tcp_add();
+        let rs = if is_control(buf) {
+            self.buffer.push_urgent(buf_vec)
+        } else {
+            self.buffer.push(buf_vec)
+        };
+        match rs {
+            Ok(pushed) => {
+                if pushed == Pushed::DropOldest {
+                    // 替换了队列中的包，数量不变
+                    self.queue_depth.tcp_sub(1);
+                    self.queue_depth.tcp_drop_add();
+                }
+                self.notify.notify(self.token, true)
+            }
+            Err(e) => {
+                self.queue_depth.tcp_sub(1);
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    self.queue_depth.tcp_drop_add();
+                }
+                Err(e)
+            }
         }
     }
     fn shutdown(&self) -> io::Result<()> {
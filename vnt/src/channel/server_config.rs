@@ -0,0 +1,223 @@
+use std::fmt::{Display, Formatter};
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use parking_lot::RwLock;
+
+use crate::channel::peer_filter::PeerMatch;
+use crate::proto::message::ServerConfig as ServerConfigMessage;
+
+/// 服务端可以下发的配置项
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ConfigField {
+    /// stun服务器
+    Stun,
+    /// 推荐的虚拟网卡mtu
+    Mtu,
+    /// 推荐作为中继的设备
+    Relay,
+    /// 分组访问控制
+    Acl,
+}
+
+impl ConfigField {
+    pub const ALL: [ConfigField; 4] = [
+        ConfigField::Stun,
+        ConfigField::Mtu,
+        ConfigField::Relay,
+        ConfigField::Acl,
+    ];
+}
+
+impl FromStr for ConfigField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "stun" => Ok(ConfigField::Stun),
+            "mtu" => Ok(ConfigField::Mtu),
+            "relay" => Ok(ConfigField::Relay),
+            "acl" => Ok(ConfigField::Acl),
+            _ => Err(format!(
+                "invalid config field '{}', options: stun/mtu/relay/acl/all",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for ConfigField {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ConfigField::Stun => "stun",
+            ConfigField::Mtu => "mtu",
+            ConfigField::Relay => "relay",
+            ConfigField::Acl => "acl",
+        };
+        f.write_str(name)
+    }
+}
+
+/// 按本地策略过滤后的服务端配置
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PushedConfig {
+    pub stun_servers: Vec<String>,
+    pub mtu: Option<u32>,
+    pub relay_hints: Vec<Ipv4Addr>,
+    pub allow_peer: Vec<PeerMatch>,
+    pub deny_peer: Vec<PeerMatch>,
+    // 服务端下发了但本地不接受的配置项
+    pub vetoed: Vec<ConfigField>,
+}
+
+/// 当前生效的一项配置
+#[derive(Clone, Debug)]
+pub struct EffectiveConfig {
+    pub field: ConfigField,
+    pub value: String,
+    /// local、server，或者被本地拒绝时为vetoed
+    pub source: &'static str,
+}
+
+impl EffectiveConfig {
+    pub fn new(field: ConfigField, value: String, source: &'static str) -> Self {
+        Self {
+            field,
+            value,
+            source,
+        }
+    }
+}
+
+/// 服务端下发的配置，只应用本地允许的配置项，本地的黑名单和指定的mtu总是优先
+pub struct ServerConfig {
+    accept: Vec<ConfigField>,
+    // 本地指定了mtu时不接受推荐值
+    local_mtu: bool,
+    max_mtu: u32,
+    pushed: RwLock<Option<PushedConfig>>,
+}
+
+impl ServerConfig {
+    pub fn new(accept: Vec<ConfigField>, local_mtu: bool, max_mtu: u32) -> Self {
+        Self {
+            accept,
+            local_mtu,
+            max_mtu,
+            pushed: RwLock::new(None),
+        }
+    }
+    pub fn accept(&self) -> &[ConfigField] {
+        &self.accept
+    }
+    pub fn accepts(&self, field: ConfigField) -> bool {
+        self.accept.contains(&field)
+    }
+    /// 最近一次收到的配置，从未收到时为None
+    pub fn pushed(&self) -> Option<PushedConfig> {
+        self.pushed.read().clone()
+    }
+    pub fn relay_hints(&self) -> Vec<Ipv4Addr> {
+        match self.pushed.read().as_ref() {
+            Some(pushed) => pushed.relay_hints.clone(),
+            None => Vec::new(),
+        }
+    }
+    /// 过滤服务端下发的配置，和上次相同时返回None
+    pub(crate) fn update(&self, message: ServerConfigMessage) -> Option<PushedConfig> {
+        let pushed = self.filter(message);
+        let mut guard = self.pushed.write();
+        if guard.as_ref() == Some(&pushed) {
+            return None;
+        }
+        *guard = Some(pushed.clone());
+        Some(pushed)
+    }
+    fn filter(&self, message: ServerConfigMessage) -> PushedConfig {
+        let mut pushed = PushedConfig::default();
+        if !message.stun_servers.is_empty() {
+            if self.accepts(ConfigField::Stun) {
+                pushed.stun_servers = message.stun_servers;
+            } else {
+                pushed.vetoed.push(ConfigField::Stun);
+            }
+        }
+        if message.mtu != 0 {
+            // 只接受不超过默认值的mtu，过大时加密后会被分片
+            if self.accepts(ConfigField::Mtu)
+                && !self.local_mtu
+                && (576..=self.max_mtu).contains(&message.mtu)
+            {
+                pushed.mtu = Some(message.mtu);
+            } else {
+                pushed.vetoed.push(ConfigField::Mtu);
+            }
+        }
+        if !message.relay_hints.is_empty() {
+            if self.accepts(ConfigField::Relay) {
+                pushed.relay_hints = message
+                    .relay_hints
+                    .into_iter()
+                    .map(Ipv4Addr::from)
+                    .collect();
+            } else {
+                pushed.vetoed.push(ConfigField::Relay);
+            }
+        }
+        if !message.allow_peers.is_empty() || !message.deny_peers.is_empty() {
+            if self.accepts(ConfigField::Acl) {
+                pushed.allow_peer = parse_peers(&message.allow_peers);
+                pushed.deny_peer = parse_peers(&message.deny_peers);
+            } else {
+                pushed.vetoed.push(ConfigField::Acl);
+            }
+        }
+        pushed
+    }
+}
+
+fn parse_peers(list: &[String]) -> Vec<PeerMatch> {
+    list.iter()
+        .filter_map(|v| match PeerMatch::from_str(v) {
+            Ok(peer) => Some(peer),
+            Err(e) => {
+                log::warn!("服务端下发的访问控制 {}", e);
+                None
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn test_server_config() {
+    let mut message = ServerConfigMessage::new();
+    message.stun_servers = vec!["stun.example.com:3478".to_string()];
+    message.mtu = 1300;
+    message.relay_hints = vec![u32::from(Ipv4Addr::new(10, 26, 0, 5))];
+    message.deny_peers = vec!["10.26.0.9".to_string(), "bad peer".to_string()];
+
+    let config = ServerConfig::new(vec![ConfigField::Stun, ConfigField::Acl], false, 1410);
+    let pushed = config.update(message.clone()).unwrap();
+    assert_eq!(pushed.stun_servers.len(), 1);
+    assert_eq!(pushed.mtu, None);
+    assert!(pushed.relay_hints.is_empty());
+    assert_eq!(
+        pushed.deny_peer,
+        vec![PeerMatch::Ip(Ipv4Addr::new(10, 26, 0, 9))]
+    );
+    assert_eq!(pushed.vetoed, vec![ConfigField::Mtu, ConfigField::Relay]);
+    // 重复下发不产生变化
+    assert!(config.update(message.clone()).is_none());
+
+    // 本地指定了mtu，或者推荐值超过默认值时不接受
+    let config = ServerConfig::new(ConfigField::ALL.to_vec(), true, 1410);
+    assert_eq!(config.update(message.clone()).unwrap().mtu, None);
+    let config = ServerConfig::new(ConfigField::ALL.to_vec(), false, 1410);
+    message.mtu = 1500;
+    assert_eq!(config.update(message.clone()).unwrap().mtu, None);
+    message.mtu = 1300;
+    let pushed = config.update(message).unwrap();
+    assert_eq!(pushed.mtu, Some(1300));
+    assert_eq!(config.relay_hints(), vec![Ipv4Addr::new(10, 26, 0, 5)]);
+    assert!(pushed.vetoed.is_empty());
+}
@@ -0,0 +1,229 @@
+use std::str::FromStr;
+
+use socket2::SockRef;
+
+/// 默认的收发缓冲区大小
+const DEFAULT_SIZE: usize = 2 * 1024 * 1024;
+/// 自动调整时接收缓冲区的上限
+const AUTO_MAX: usize = 32 * 1024 * 1024;
+
+/// udp socket的收发缓冲区大小。
+/// 高码率时系统默认的缓冲区太小，接收线程稍有停顿就会丢包，吞吐会大幅下降，
+/// 开启自动调整时根据socket的丢包计数逐步调大接收缓冲区
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SocketBuf {
+    pub recv: usize,
+    pub send: usize,
+    pub auto: bool,
+}
+
+impl Default for SocketBuf {
+    fn default() -> Self {
+        Self {
+            recv: DEFAULT_SIZE,
+            send: DEFAULT_SIZE,
+            auto: true,
+        }
+    }
+}
+
+impl FromStr for SocketBuf {
+    type Err = String;
+
+    /// 格式为 接收[,发送][,auto|fixed]，大小可以带k/m后缀，发送默认和接收相同
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut sizes = Vec::with_capacity(2);
+        let mut auto = true;
+        for item in s
+            .split(',')
+            .map(|v| v.trim().to_lowercase())
+            .filter(|v| !v.is_empty())
+        {
+            match item.as_str() {
+                "auto" => auto = true,
+                "fixed" => auto = false,
+                _ => {
+                    if sizes.len() == 2 {
+                        return Err(format!("socket buffer '{}'", s));
+                    }
+                    sizes.push(parse_size(&item)?);
+                }
+            }
+        }
+        let recv = sizes.first().copied().unwrap_or(DEFAULT_SIZE);
+        let send = sizes.get(1).copied().unwrap_or(recv);
+        Ok(Self { recv, send, auto })
+    }
+}
+
+fn parse_size(s: &str) -> Result<usize, String> {
+    let (num, unit) = match s.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => s.split_at(i),
+        None => (s, ""),
+    };
+    let num: usize = num
+        .parse()
+        .map_err(|_| format!("socket buffer size '{}'", s))?;
+    let size = match unit {
+        "" | "b" => num,
+        "k" | "kb" => num * 1024,
+        "m" | "mb" => num * 1024 * 1024,
+        _ => return Err(format!("socket buffer size '{}'", s)),
+    };
+    if !(4096..=1024 * 1024 * 1024).contains(&size) {
+        return Err(format!("socket buffer size '{}' out of range", s));
+    }
+    Ok(size)
+}
+
+impl SocketBuf {
+    /// 设置缓冲区大小，被系统上限截断时尝试强制设置，仍然不行则打印日志
+    pub fn apply(&self, socket: SockRef) {
+        set_recv(&socket, self.recv);
+        set_send(&socket, self.send);
+    }
+}
+
+/// 根据这段时间内的丢包数给出新的接收缓冲区大小，不需要调整时返回None
+pub fn next_recv_size(current: usize, drops: u32) -> Option<usize> {
+    if drops == 0 || current >= AUTO_MAX {
+        return None;
+    }
+    Some((current * 2).min(AUTO_MAX))
+}
+
+pub fn set_recv(socket: &SockRef, size: usize) {
+    if let Err(e) = socket.set_recv_buffer_size(size) {
+        log::warn!("set_recv_buffer_size {} {:?}", size, e);
+    }
+    if recv_size(socket) < size {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if force(socket, SO_RCVBUFFORCE, size).is_ok() && recv_size(socket) >= size {
+            return;
+        }
+        log::warn!(
+            "udp接收缓冲区被系统限制为{}，期望{}，可以调大net.core.rmem_max",
+            recv_size(socket),
+            size
+        );
+    }
+}
+
+fn set_send(socket: &SockRef, size: usize) {
+    if let Err(e) = socket.set_send_buffer_size(size) {
+        log::warn!("set_send_buffer_size {} {:?}", size, e);
+    }
+    if send_size(socket) < size {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if force(socket, SO_SNDBUFFORCE, size).is_ok() && send_size(socket) >= size {
+            return;
+        }
+        log::warn!(
+            "udp发送缓冲区被系统限制为{}，期望{}，可以调大net.core.wmem_max",
+            send_size(socket),
+            size
+        );
+    }
+}
+
+/// linux上读到的是内核翻倍后的值，这里换算回设置时的大小
+pub fn recv_size(socket: &SockRef) -> usize {
+    effective(socket.recv_buffer_size().unwrap_or(0))
+}
+
+fn send_size(socket: &SockRef) -> usize {
+    effective(socket.send_buffer_size().unwrap_or(0))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn effective(size: usize) -> usize {
+    size / 2
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn effective(size: usize) -> usize {
+    size
+}
+
+// asm-generic/socket.h
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SO_SNDBUFFORCE: libc::c_int = 32;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SO_RCVBUFFORCE: libc::c_int = 33;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SO_MEMINFO: libc::c_int = 55;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SK_MEMINFO_VARS: usize = 9;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SK_MEMINFO_DROPS: usize = 8;
+
+/// 有CAP_NET_ADMIN时可以突破rmem_max/wmem_max
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn force(socket: &SockRef, opt: libc::c_int, size: usize) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let value = size.min(i32::MAX as usize) as libc::c_int;
+    let rs = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            opt,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rs == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// socket接收队列溢出的丢包数，和SO_RXQ_OVFL附带的是同一个计数，
+/// 通过SO_MEMINFO读取，不需要改动接收路径
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn drops(socket: &SockRef) -> std::io::Result<u32> {
+    use std::os::fd::AsRawFd;
+    let mut info = [0u32; SK_MEMINFO_VARS];
+    let mut len = std::mem::size_of_val(&info) as libc::socklen_t;
+    let rs = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            SO_MEMINFO,
+            info.as_mut_ptr() as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if rs == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(info[SK_MEMINFO_DROPS])
+}
+
+#[test]
+fn test_socket_buf() {
+    assert_eq!("".parse::<SocketBuf>().unwrap(), SocketBuf::default());
+    let buf: SocketBuf = "8m,512k,fixed".parse().unwrap();
+    assert_eq!(buf.recv, 8 * 1024 * 1024);
+    assert_eq!(buf.send, 512 * 1024);
+    assert!(!buf.auto);
+    let buf: SocketBuf = "4M".parse().unwrap();
+    assert_eq!(buf.send, 4 * 1024 * 1024);
+    assert!(buf.auto);
+    assert!("1k".parse::<SocketBuf>().is_err());
+    assert!("1m,1m,1m".parse::<SocketBuf>().is_err());
+    assert!("1g".parse::<SocketBuf>().is_err());
+
+    assert_eq!(next_recv_size(DEFAULT_SIZE, 0), None);
+    assert_eq!(next_recv_size(DEFAULT_SIZE, 3), Some(2 * DEFAULT_SIZE));
+    assert_eq!(next_recv_size(AUTO_MAX / 2 + 1, 3), Some(AUTO_MAX));
+    assert_eq!(next_recv_size(AUTO_MAX, 3), None);
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = SockRef::from(&udp);
+        assert_eq!(drops(&socket).unwrap(), 0);
+        set_recv(&socket, 64 * 1024);
+        assert!(recv_size(&socket) >= 64 * 1024);
+    }
+}
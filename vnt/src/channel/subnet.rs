@@ -0,0 +1,227 @@
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use parking_lot::Mutex;
+
+use crate::handle::now_time;
+
+/// 超过这个时间(ms)没有收到网关的通告则删除它的网段
+const ADVERT_TIMEOUT: u64 = 65_000;
+
+// 网关 -> (最后一次收到通告的时间，网段，子网掩码)
+type Gateways = HashMap<Ipv4Addr, (u64, Vec<(u32, u32)>)>;
+// 网段，子网掩码
+type NetList = Vec<(Ipv4Addr, Ipv4Addr)>;
+
+#[derive(Default)]
+struct State {
+    gateways: Gateways,
+    // 调用方注入的网段，(网段，子网掩码，网关)，优先于学习到的网段
+    external: Vec<(u32, u32, Ipv4Addr)>,
+}
+
+/// 从其他网关学习到的网段路由，以及调用方在运行时注入的网段路由
+pub struct SubnetRoutes {
+    accept: bool,
+    state: Mutex<State>,
+    // 按掩码从长到短排列，转发时只读
+    table: ArcSwap<Vec<(u32, u32, Ipv4Addr)>>,
+}
+
+impl SubnetRoutes {
+    pub fn new(accept: bool) -> Self {
+        Self {
+            accept,
+            state: Mutex::new(State::default()),
+            table: ArcSwap::from_pointee(Vec::new()),
+        }
+    }
+    /// 是否接受其他网关通告的网段
+    pub fn is_accept(&self) -> bool {
+        self.accept
+    }
+    /// 更新网关通告的网段，返回新增和删除的网段
+    pub(crate) fn update(&self, gateway: Ipv4Addr, nets: Vec<(u32, u32)>) -> (NetList, NetList) {
+        self.update_at(gateway, nets, now_time())
+    }
+    fn update_at(
+        &self,
+        gateway: Ipv4Addr,
+        mut nets: Vec<(u32, u32)>,
+        now: u64,
+    ) -> (NetList, NetList) {
+        for (dest, mask) in &mut nets {
+            *dest &= *mask;
+        }
+        nets.sort_unstable();
+        nets.dedup();
+        let mut guard = self.state.lock();
+        let old = net_set(&guard);
+        if nets.is_empty() {
+            guard.gateways.remove(&gateway);
+        } else {
+            guard.gateways.insert(gateway, (now, nets));
+        }
+        self.changed(&guard, old)
+    }
+    /// 注入网段路由，已存在时替换网关，返回新增的网段
+    pub(crate) fn add_external(&self, dest: u32, mask: u32, gateway: Ipv4Addr) -> NetList {
+        let dest = dest & mask;
+        let mut guard = self.state.lock();
+        let old = net_set(&guard);
+        match guard
+            .external
+            .iter_mut()
+            .find(|(d, m, _)| *d == dest && *m == mask)
+        {
+            Some((_, _, g)) => *g = gateway,
+            None => guard.external.push((dest, mask, gateway)),
+        }
+        self.changed(&guard, old).0
+    }
+    /// 删除注入的网段路由，返回是否存在和删除的网段
+    pub(crate) fn remove_external(&self, dest: u32, mask: u32) -> (bool, NetList) {
+        let dest = dest & mask;
+        let mut guard = self.state.lock();
+        let len = guard.external.len();
+        let old = net_set(&guard);
+        guard.external.retain(|(d, m, _)| *d != dest || *m != mask);
+        let exists = guard.external.len() != len;
+        (exists, self.changed(&guard, old).1)
+    }
+    /// 删除超时的网关，返回删除的网段
+    pub(crate) fn expire(&self) -> NetList {
+        self.expire_at(now_time())
+    }
+    fn expire_at(&self, now: u64) -> NetList {
+        let mut guard = self.state.lock();
+        if guard.gateways.is_empty() {
+            return Vec::new();
+        }
+        let old = net_set(&guard);
+        guard
+            .gateways
+            .retain(|_, (time, _)| now.saturating_sub(*time) < ADVERT_TIMEOUT);
+        self.changed(&guard, old).1
+    }
+    fn changed(&self, state: &State, old: HashSet<(u32, u32)>) -> (NetList, NetList) {
+        let new = net_set(state);
+        let mut table: Vec<(u32, u32, Ipv4Addr)> = state.external.clone();
+        for (gateway, (_, nets)) in &state.gateways {
+            for (dest, mask) in nets {
+                if state
+                    .external
+                    .iter()
+                    .any(|(d, m, _)| d == dest && m == mask)
+                {
+                    continue;
+                }
+                // 多个网关通告同一个网段时使用地址最小的网关，保证结果稳定
+                match table.iter_mut().find(|(d, m, _)| d == dest && m == mask) {
+                    Some((_, _, g)) => {
+                        if gateway < g {
+                            *g = *gateway;
+                        }
+                    }
+                    None => table.push((*dest, *mask, *gateway)),
+                }
+            }
+        }
+        table.sort_by(|(dest1, mask1, _), (dest2, mask2, _)| {
+            mask2.cmp(mask1).then(dest1.cmp(dest2))
+        });
+        self.table.store(Arc::new(table));
+        let to_ip = |(dest, mask): &(u32, u32)| (Ipv4Addr::from(*dest), Ipv4Addr::from(*mask));
+        let added = new.difference(&old).map(to_ip).collect();
+        let removed = old.difference(&new).map(to_ip).collect();
+        (added, removed)
+    }
+    /// 目标地址所在网段的网关
+    pub fn route(&self, ip: &Ipv4Addr) -> Option<Ipv4Addr> {
+        let table = self.table.load();
+        if table.is_empty() {
+            return None;
+        }
+        let ip = u32::from(*ip);
+        table
+            .iter()
+            .find(|(dest, mask, _)| ip & *mask == *dest)
+            .map(|(_, _, gateway)| *gateway)
+    }
+    /// 学习到的和注入的路由，(网段，子网掩码，网关)
+    pub fn routes(&self) -> Vec<(Ipv4Addr, Ipv4Addr, Ipv4Addr)> {
+        self.table
+            .load()
+            .iter()
+            .map(|(dest, mask, gateway)| (Ipv4Addr::from(*dest), Ipv4Addr::from(*mask), *gateway))
+            .collect()
+    }
+}
+
+fn net_set(state: &State) -> HashSet<(u32, u32)> {
+    state
+        .gateways
+        .values()
+        .flat_map(|(_, nets)| nets.iter().copied())
+        .chain(state.external.iter().map(|(dest, mask, _)| (*dest, *mask)))
+        .collect()
+}
+
+#[test]
+fn test_subnet_routes() {
+    let routes = SubnetRoutes::new(true);
+    let gw1 = Ipv4Addr::new(10, 26, 0, 2);
+    let gw2 = Ipv4Addr::new(10, 26, 0, 3);
+    let net = |a: u8, b: u8, c: u8, prefix: u32| {
+        (
+            u32::from(Ipv4Addr::new(a, b, c, 0)),
+            u32::MAX << (32 - prefix),
+        )
+    };
+    let (added, removed) = routes.update_at(gw1, vec![net(192, 168, 0, 16)], 0);
+    assert_eq!(added.len(), 1);
+    assert!(removed.is_empty());
+    let (added, _) = routes.update_at(gw2, vec![net(192, 168, 1, 24)], 10_000);
+    assert_eq!(
+        added,
+        vec![(
+            Ipv4Addr::new(192, 168, 1, 0),
+            Ipv4Addr::new(255, 255, 255, 0)
+        )]
+    );
+    // 最长前缀优先
+    assert_eq!(routes.route(&Ipv4Addr::new(192, 168, 1, 9)), Some(gw2));
+    assert_eq!(routes.route(&Ipv4Addr::new(192, 168, 2, 9)), Some(gw1));
+    assert_eq!(routes.route(&Ipv4Addr::new(172, 16, 0, 1)), None);
+    // 重复通告不产生变化
+    let (added, removed) = routes.update_at(gw1, vec![net(192, 168, 0, 16)], 20_000);
+    assert!(added.is_empty() && removed.is_empty());
+    // gw2超时
+    let removed = routes.expire_at(10_000 + ADVERT_TIMEOUT);
+    assert_eq!(removed.len(), 1);
+    assert_eq!(routes.route(&Ipv4Addr::new(192, 168, 1, 9)), Some(gw1));
+    // 通告空列表时删除
+    let (_, removed) = routes.update_at(gw1, Vec::new(), 30_000);
+    assert_eq!(removed.len(), 1);
+    assert!(routes.routes().is_empty());
+
+    // 注入的网段优先于学习到的网段，删除后恢复
+    let (dest, mask) = net(192, 168, 1, 24);
+    routes.update_at(gw2, vec![(dest, mask)], 40_000);
+    assert!(routes.add_external(dest | 1, mask, gw1).is_empty());
+    assert_eq!(routes.route(&Ipv4Addr::new(192, 168, 1, 9)), Some(gw1));
+    let (dest2, mask2) = net(172, 16, 0, 12);
+    assert_eq!(routes.add_external(dest2, mask2, gw2).len(), 1);
+    assert_eq!(routes.route(&Ipv4Addr::new(172, 17, 0, 1)), Some(gw2));
+    assert_eq!(routes.remove_external(dest, mask), (true, Vec::new()));
+    assert_eq!(routes.route(&Ipv4Addr::new(192, 168, 1, 9)), Some(gw2));
+    let (exists, removed) = routes.remove_external(dest2, mask2);
+    assert!(exists && removed.len() == 1);
+    assert!(!routes.remove_external(dest2, mask2).0);
+    // 注入的网段不会超时
+    routes.add_external(dest2, mask2, gw2);
+    routes.expire_at(40_000 + ADVERT_TIMEOUT);
+    assert_eq!(routes.routes().len(), 1);
+}
@@ -9,7 +9,8 @@ use std::os::fd::IntoRawFd;
 use std::os::windows::io::FromRawSocket;
 #[cfg(windows)]
 use std::os::windows::io::IntoRawSocket;
-use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
 use std::{io, thread};
 
 use mio::net::{TcpListener, TcpStream};
@@ -18,6 +19,7 @@ use mio::{Events, Interest, Poll, Registry, Token, Waker};
 use crate::channel::context::ChannelContext;
 use crate::channel::handler::RecvChannelHandler;
 use crate::channel::notify::{AcceptNotify, WritableNotify};
+use crate::channel::send_queue::SendQueue;
 use crate::channel::sender::{AcceptSocketSender, PacketSender};
 use crate::channel::{RouteKey, BUFFER_SIZE};
 use crate::util::StopManager;
@@ -198,7 +200,7 @@ fn tcp_writable_listen(
         (
             TcpStream,
             SocketAddr,
-            Receiver<Vec<u8>>,
+            Arc<SendQueue>,
             Option<(Vec<u8>, usize)>,
         ),
     > = HashMap::with_capacity(32);
@@ -219,7 +221,7 @@ fn tcp_writable_listen(
                                     closed_handle_w(&token, &mut write_map, &context);
                                     continue;
                                 }
-                                if let Err(e) = writable_handle(&token, &mut write_map) {
+                                if let Err(e) = writable_handle(&token, &mut write_map, &context) {
                                     closed_handle_w(&token, &mut write_map, &context);
                                     log::warn!("{:?}", e);
                                 }
@@ -239,21 +241,25 @@ fn tcp_writable_listen(
                                 log::warn!("registry err={:?}", e);
                                 continue;
                             }
-                            let (sender, receiver) = sync_channel(128);
-                            let packet_sender =
-                                PacketSender::new(writable_notify.clone(), sender, token);
+                            let queue = Arc::new(SendQueue::new(context.send_queue));
+                            let packet_sender = PacketSender::new(
+                                writable_notify.clone(),
+                                queue.clone(),
+                                token,
+                                context.queue_depth.clone(),
+                            );
                             if let Some(init_buf) = init_buf {
                                 packet_sender.try_send(&init_buf)?;
                             }
 
                             context.tcp_map.write().insert(addr, packet_sender);
-                            write_map.insert(token, (stream, addr, receiver, None));
+                            write_map.insert(token, (stream, addr, queue, None));
                         }
                     }
                 }
                 token => {
                     if event.is_writable() {
-                        if let Err(e) = writable_handle(&token, &mut write_map) {
+                        if let Err(e) = writable_handle(&token, &mut write_map, &context) {
                             closed_handle_w(&token, &mut write_map, &context);
                             log::warn!("{:?}", e);
                         }
@@ -383,12 +389,13 @@ fn writable_handle(
         (
             TcpStream,
             SocketAddr,
-            Receiver<Vec<u8>>,
+            Arc<SendQueue>,
             Option<(Vec<u8>, usize)>,
         ),
     >,
+    context: &ChannelContext,
 ) -> io::Result<()> {
-    if let Some((stream, _, receiver, last)) = map.get_mut(token) {
+    if let Some((stream, _, queue, last)) = map.get_mut(token) {
         loop {
             if let Some((buf, begin)) = last {
                 match stream.write(&buf[*begin..]) {
@@ -411,16 +418,14 @@ fn writable_handle(
                     }
                 }
             }
-            match receiver.try_recv() {
-                Ok(buf) => *last = Some((buf, 0)),
-                Err(e) => match e {
-                    TryRecvError::Empty => {
-                        break;
-                    }
-                    TryRecvError::Disconnected => {
-                        return Err(io::Error::from(io::ErrorKind::Other));
-                    }
-                },
+            match queue.pop() {
+                Some(buf) => {
+                    context.queue_depth.tcp_sub(1);
+                    *last = Some((buf, 0))
+                }
+                None => {
+                    break;
+                }
             }
         }
     }
@@ -443,14 +448,16 @@ fn closed_handle_w(
         (
             TcpStream,
             SocketAddr,
-            Receiver<Vec<u8>>,
+            Arc<SendQueue>,
             Option<(Vec<u8>, usize)>,
         ),
     >,
     context: &ChannelContext,
 ) {
-    if let Some((tcp, addr, _, _)) = map.remove(token) {
+    if let Some((tcp, addr, queue, _)) = map.remove(token) {
         context.tcp_map.write().remove(&addr);
+        // 丢弃的包不再计入队列
+        context.queue_depth.tcp_sub(queue.close());
         let _ = tcp.shutdown(Shutdown::Both);
     }
 }
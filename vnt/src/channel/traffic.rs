@@ -0,0 +1,112 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// 和对端之间ip数据的累计计数，只增不减，
+/// 需要速率时由调用方间隔采样两次求差
+#[derive(Default)]
+pub struct Traffic {
+    tx_packets: AtomicU64,
+    rx_packets: AtomicU64,
+    // 直连通道的字节数
+    p2p_tx: AtomicU64,
+    p2p_rx: AtomicU64,
+    // 经服务器或其他客户端中转的字节数
+    relay_tx: AtomicU64,
+    relay_rx: AtomicU64,
+    // 解密失败的包数
+    cipher_error: AtomicU64,
+    // 路由失效后重新选择路由发送的次数
+    route_retry: AtomicU64,
+}
+
+/// 某一时刻的计数快照
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct TrafficSnapshot {
+    pub tx_packets: u64,
+    pub rx_packets: u64,
+    pub p2p_tx: u64,
+    pub p2p_rx: u64,
+    pub relay_tx: u64,
+    pub relay_rx: u64,
+    pub cipher_error: u64,
+    pub route_retry: u64,
+}
+
+impl Traffic {
+    pub(crate) fn tx(&self, len: usize, p2p: bool) {
+        self.tx_packets.fetch_add(1, Ordering::Relaxed);
+        if p2p {
+            self.p2p_tx.fetch_add(len as u64, Ordering::Relaxed);
+        } else {
+            self.relay_tx.fetch_add(len as u64, Ordering::Relaxed);
+        }
+    }
+    pub(crate) fn rx(&self, len: usize, p2p: bool) {
+        self.rx_packets.fetch_add(1, Ordering::Relaxed);
+        if p2p {
+            self.p2p_rx.fetch_add(len as u64, Ordering::Relaxed);
+        } else {
+            self.relay_rx.fetch_add(len as u64, Ordering::Relaxed);
+        }
+    }
+    pub(crate) fn cipher_error_add(&self) {
+        self.cipher_error.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn route_retry_add(&self) {
+        self.route_retry.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn snapshot(&self) -> TrafficSnapshot {
+        TrafficSnapshot {
+            tx_packets: self.tx_packets.load(Ordering::Relaxed),
+            rx_packets: self.rx_packets.load(Ordering::Relaxed),
+            p2p_tx: self.p2p_tx.load(Ordering::Relaxed),
+            p2p_rx: self.p2p_rx.load(Ordering::Relaxed),
+            relay_tx: self.relay_tx.load(Ordering::Relaxed),
+            relay_rx: self.relay_rx.load(Ordering::Relaxed),
+            cipher_error: self.cipher_error.load(Ordering::Relaxed),
+            route_retry: self.route_retry.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl TrafficSnapshot {
+    /// 两次快照之间的增量，计数重置(如重启)时按0处理
+    pub fn delta(&self, earlier: &TrafficSnapshot) -> TrafficSnapshot {
+        TrafficSnapshot {
+            tx_packets: self.tx_packets.saturating_sub(earlier.tx_packets),
+            rx_packets: self.rx_packets.saturating_sub(earlier.rx_packets),
+            p2p_tx: self.p2p_tx.saturating_sub(earlier.p2p_tx),
+            p2p_rx: self.p2p_rx.saturating_sub(earlier.p2p_rx),
+            relay_tx: self.relay_tx.saturating_sub(earlier.relay_tx),
+            relay_rx: self.relay_rx.saturating_sub(earlier.relay_rx),
+            cipher_error: self.cipher_error.saturating_sub(earlier.cipher_error),
+            route_retry: self.route_retry.saturating_sub(earlier.route_retry),
+        }
+    }
+}
+
+#[test]
+fn test_traffic() {
+    let traffic = Traffic::default();
+    traffic.tx(100, true);
+    traffic.tx(50, false);
+    traffic.rx(10, true);
+    let first = traffic.snapshot();
+    assert_eq!(first.tx_packets, 2);
+    assert_eq!(first.p2p_tx, 100);
+    assert_eq!(first.relay_tx, 50);
+    traffic.rx(20, false);
+    traffic.cipher_error_add();
+    traffic.route_retry_add();
+    let delta = traffic.snapshot().delta(&first);
+    assert_eq!(
+        delta,
+        TrafficSnapshot {
+            rx_packets: 1,
+            relay_rx: 20,
+            cipher_error: 1,
+            route_retry: 1,
+            ..Default::default()
+        }
+    );
+    assert_eq!(first.delta(&traffic.snapshot()), TrafficSnapshot::default());
+}
@@ -0,0 +1,160 @@
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::{fs, io, thread};
+
+use crate::channel::context::ChannelContext;
+use crate::channel::handler::RecvChannelHandler;
+use crate::channel::{RouteKey, BUFFER_SIZE, UDS_INDEX};
+use crate::util::StopManager;
+
+/// 同一台主机上的实例之间通过unix域套接字通信，不经过网卡。
+/// 每个实例在公共目录下绑定一个随机名称的套接字，并在打洞信息中告知对端，
+/// 对端的套接字文件在本机能找到就说明在同一台主机上(或者容器共享了这个目录)
+pub struct UdsChannel {
+    id: u64,
+    path: PathBuf,
+    socket: UnixDatagram,
+}
+
+impl UdsChannel {
+    pub fn bind() -> io::Result<Self> {
+        let dir = uds_dir();
+        if !dir.exists() {
+            fs::create_dir_all(&dir)?;
+            // 和/tmp一样，所有用户可写，只能删除自己的文件
+            let _ = fs::set_permissions(&dir, fs::Permissions::from_mode(0o1777));
+        }
+        let id = loop {
+            let id: u64 = rand::random();
+            if id != 0 {
+                break id;
+            }
+        };
+        let path = uds_path(id);
+        let socket = UnixDatagram::bind(&path)?;
+        socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+        Ok(Self { id, path, socket })
+    }
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+    pub fn send_to(&self, buf: &[u8], id: u64) -> io::Result<()> {
+        self.socket.send_to(buf, uds_path(id))?;
+        Ok(())
+    }
+}
+
+impl Drop for UdsChannel {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn uds_dir() -> PathBuf {
+    std::env::temp_dir().join("vnt-uds")
+}
+
+fn uds_path(id: u64) -> PathBuf {
+    uds_dir().join(format!("{:016x}.sock", id))
+}
+
+/// 对端套接字对应的路由地址，只用来区分路由，不会真的发到这个地址
+pub fn uds_addr(id: u64) -> SocketAddr {
+    SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(id as u128), 0, 0, 0))
+}
+
+pub fn uds_id(addr: &SocketAddr) -> Option<u64> {
+    match addr {
+        SocketAddr::V6(addr) if addr.port() == 0 => {
+            let id = u128::from(*addr.ip());
+            if id > u64::MAX as u128 || id == 0 {
+                None
+            } else {
+                Some(id as u64)
+            }
+        }
+        _ => None,
+    }
+}
+
+fn peer_id(addr: &std::os::unix::net::SocketAddr) -> Option<u64> {
+    let name = addr.as_pathname()?.file_stem()?.to_str()?;
+    u64::from_str_radix(name, 16).ok()
+}
+
+/// 绑定unix域套接字并接收同主机实例的数据，失败时只记录日志
+pub fn uds_listen<H>(
+    stop_manager: StopManager,
+    mut recv_handler: H,
+    context: ChannelContext,
+) -> io::Result<()>
+where
+    H: RecvChannelHandler,
+{
+    let channel = match UdsChannel::bind() {
+        Ok(channel) => channel,
+        Err(e) => {
+            log::warn!("unix域套接字未启用 {:?}", e);
+            return Ok(());
+        }
+    };
+    let socket = channel.socket.try_clone()?;
+    log::info!("unix域套接字 {:?}", channel.path);
+    if !context.set_uds(channel) {
+        return Ok(());
+    }
+    let worker = stop_manager.add_listener("uds".into(), || {})?;
+    thread::Builder::new()
+        .name("udsRecv".into())
+        .spawn(move || {
+            let mut buf = [0; BUFFER_SIZE];
+            while !stop_manager.is_stop() {
+                match socket.recv_from(&mut buf) {
+                    Ok((len, addr)) => {
+                        // 对端没有绑定时无法回应，丢弃
+                        if let Some(id) = peer_id(&addr) {
+                            recv_handler.handle(
+                                &mut buf[..len],
+                                RouteKey::new(false, UDS_INDEX, uds_addr(id)),
+                                &context,
+                            );
+                        }
+                    }
+                    Err(e)
+                        if e.kind() == io::ErrorKind::WouldBlock
+                            || e.kind() == io::ErrorKind::TimedOut
+                            || e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        log::error!("unix域套接字接收 {:?}", e);
+                        break;
+                    }
+                }
+            }
+            drop(worker);
+        })?;
+    Ok(())
+}
+
+#[test]
+fn test_uds_channel() {
+    assert_eq!(uds_id(&uds_addr(0x1234)), Some(0x1234));
+    assert_eq!(uds_id(&"[::1]:80".parse().unwrap()), None);
+    assert_eq!(uds_id(&"127.0.0.1:0".parse().unwrap()), None);
+
+    let a = UdsChannel::bind().unwrap();
+    let b = UdsChannel::bind().unwrap();
+    a.send_to(b"ping", b.id()).unwrap();
+    let mut buf = [0; 16];
+    let (len, addr) = b.socket.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"ping");
+    assert_eq!(peer_id(&addr), Some(a.id()));
+    // 对端不存在时发送失败
+    let path = a.path.clone();
+    let id = a.id();
+    drop(a);
+    assert!(!path.exists());
+    assert!(b.send_to(b"ping", id).is_err());
+}
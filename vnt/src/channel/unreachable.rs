@@ -0,0 +1,150 @@
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use packet::cal_checksum;
+
+use crate::handle::now_time;
+
+/// 每秒最多生成的不可达报文数，避免大量发包时刷满网卡
+const MAX_PER_SECOND: u64 = 100;
+/// 生成的ip包的ttl
+const TTL: u8 = 64;
+
+/// 目的不可达的原因，对应icmp type=3的code
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Unreachable {
+    // 没有到这个网段的路由
+    Network,
+    // 对端离线
+    Host,
+    // 被黑白名单拦截，或者网关不允许转发(-o)
+    Prohibited,
+}
+
+impl Unreachable {
+    fn code(&self) -> u8 {
+        match self {
+            Unreachable::Network => 0,
+            Unreachable::Host => 1,
+            Unreachable::Prohibited => 13,
+        }
+    }
+}
+
+/// 限制生成不可达报文的速率
+#[derive(Default)]
+pub struct UnreachableLimiter {
+    // 高位为秒数，低16位为这一秒内的数量
+    state: AtomicU64,
+}
+
+impl UnreachableLimiter {
+    pub fn allow(&self) -> bool {
+        self.allow_at(now_time() / 1000)
+    }
+    fn allow_at(&self, second: u64) -> bool {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            let new_state = if state >> 16 != second {
+                (second << 16) | 1
+            } else if state & 0xFFFF < MAX_PER_SECOND {
+                state + 1
+            } else {
+                return false;
+            };
+            match self.state.compare_exchange_weak(
+                state,
+                new_state,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(v) => state = v,
+            }
+        }
+    }
+}
+
+/// 为无法送达的ip包生成icmp目的不可达报文，router为报文的来源地址。
+/// 不回应icmp差错报文、非首个分片和广播，此时返回None
+pub fn unreachable_packet(
+    ip_packet: &[u8],
+    router: Ipv4Addr,
+    reason: Unreachable,
+) -> Option<Vec<u8>> {
+    if ip_packet.len() < 20 || ip_packet[0] >> 4 != 4 {
+        return None;
+    }
+    let ihl = (ip_packet[0] & 0x0F) as usize * 4;
+    if ihl < 20 || ip_packet.len() < ihl {
+        return None;
+    }
+    if u16::from_be_bytes([ip_packet[6], ip_packet[7]]) & 0x1FFF != 0 {
+        return None;
+    }
+    let source = Ipv4Addr::new(ip_packet[12], ip_packet[13], ip_packet[14], ip_packet[15]);
+    if source.is_unspecified() || source.is_broadcast() || source.is_multicast() {
+        return None;
+    }
+    if ip_packet[9] == 1 {
+        // 只回应查询类的icmp
+        match ip_packet.get(ihl) {
+            Some(0) | Some(8) | Some(13) | Some(15) | Some(17) => {}
+            _ => return None,
+        }
+    }
+    // 原包的ip头和前8个字节
+    let quote = &ip_packet[..(ihl + 8).min(ip_packet.len())];
+    let total_len = 20 + 8 + quote.len();
+    let mut buf = vec![0u8; total_len];
+    buf[0] = 0x45;
+    buf[2..4].copy_from_slice(&(total_len as u16).to_be_bytes());
+    buf[8] = TTL;
+    buf[9] = 1;
+    buf[12..16].copy_from_slice(&router.octets());
+    buf[16..20].copy_from_slice(&source.octets());
+    let checksum = cal_checksum(&buf[..20]);
+    buf[10..12].copy_from_slice(&checksum.to_be_bytes());
+    let icmp = &mut buf[20..];
+    icmp[0] = 3;
+    icmp[1] = reason.code();
+    icmp[8..].copy_from_slice(quote);
+    let checksum = cal_checksum(icmp);
+    icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+    Some(buf)
+}
+
+#[test]
+fn test_unreachable_packet() {
+    use packet::icmp::icmp::IcmpPacket;
+    use packet::icmp::{Code, DestinationUnreachable, Kind};
+    use packet::ip::ipv4::packet::IpV4Packet;
+    let mut udp = [0u8; 40];
+    udp[0] = 0x45;
+    udp[9] = 17;
+    udp[12..16].copy_from_slice(&[10, 26, 0, 2]);
+    udp[16..20].copy_from_slice(&[10, 26, 0, 9]);
+    let router = Ipv4Addr::new(10, 26, 0, 1);
+    let buf = unreachable_packet(&udp, router, Unreachable::Host).unwrap();
+    let ipv4 = IpV4Packet::new(&buf[..]).unwrap();
+    assert!(ipv4.is_valid());
+    assert_eq!(ipv4.source_ip(), router);
+    assert_eq!(ipv4.destination_ip(), Ipv4Addr::new(10, 26, 0, 2));
+    let icmp = IcmpPacket::new(ipv4.payload()).unwrap();
+    assert!(icmp.is_valid());
+    assert_eq!(icmp.kind(), Kind::DestinationUnreachable);
+    assert_eq!(
+        icmp.code(),
+        Code::DestinationUnreachable(DestinationUnreachable::DestinationHostUnreachable)
+    );
+    assert_eq!(icmp.payload(), &udp[..28]);
+    // 不回应icmp差错报文
+    assert!(unreachable_packet(&buf, router, Unreachable::Host).is_none());
+
+    let limiter = UnreachableLimiter::default();
+    let count = (0..MAX_PER_SECOND * 2)
+        .filter(|_| limiter.allow_at(1))
+        .count() as u64;
+    assert_eq!(count, MAX_PER_SECOND);
+    assert!(limiter.allow_at(2));
+}
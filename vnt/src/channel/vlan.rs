@@ -0,0 +1,92 @@
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+
+use parking_lot::Mutex;
+
+/// tap模式下的vlan划分，设备之间定时通告自己所在的vlan。
+/// 只在本地vlan中的对端之间转发，一个vlan的设备为access，多个vlan为trunk，
+/// trunk上native vlan(第一个)以外的vlan带802.1Q标签，可以桥接到本地的vlan子接口
+pub struct VlanTable {
+    local: Vec<u16>,
+    // 在线的对端 -> 通告的vlan，还没有收到通告时为空
+    peers: Mutex<HashMap<Ipv4Addr, Vec<u16>>>,
+}
+
+impl VlanTable {
+    pub fn new(local: Vec<u16>) -> Self {
+        Self {
+            local,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+    pub fn is_enable(&self) -> bool {
+        !self.local.is_empty()
+    }
+    pub fn local(&self) -> &[u16] {
+        &self.local
+    }
+    /// 对端通告的vlan
+    pub fn peer(&self, ip: &Ipv4Addr) -> Vec<u16> {
+        self.peers.lock().get(ip).cloned().unwrap_or_default()
+    }
+    /// 更新对端通告的vlan，有变化时返回true
+    pub(crate) fn update(&self, ip: Ipv4Addr, mut vlans: Vec<u16>) -> bool {
+        vlans.dedup();
+        let mut guard = self.peers.lock();
+        if guard.get(&ip) == Some(&vlans) {
+            return false;
+        }
+        guard.insert(ip, vlans);
+        true
+    }
+    /// 和设备列表同步，新的对端在收到通告前不和它互通，有变化时返回true
+    pub(crate) fn sync(&self, ips: &HashSet<Ipv4Addr>) -> bool {
+        let mut guard = self.peers.lock();
+        let len = guard.len();
+        guard.retain(|ip, _| ips.contains(ip));
+        let mut changed = guard.len() != len;
+        for ip in ips {
+            if !guard.contains_key(ip) {
+                guard.insert(*ip, Vec::new());
+                changed = true;
+            }
+        }
+        changed
+    }
+    /// 各个对端和本地共同所在的vlan，按本地的顺序排列
+    pub fn shared(&self) -> HashMap<Ipv4Addr, Vec<u16>> {
+        self.peers
+            .lock()
+            .iter()
+            .map(|(ip, vlans)| {
+                let shared = self
+                    .local
+                    .iter()
+                    .filter(|vid| vlans.contains(vid))
+                    .copied()
+                    .collect();
+                (*ip, shared)
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_vlan_table() {
+    let table = VlanTable::new(vec![10, 20, 30]);
+    let a = Ipv4Addr::new(10, 26, 0, 2);
+    let b = Ipv4Addr::new(10, 26, 0, 3);
+    let c = Ipv4Addr::new(10, 26, 0, 4);
+    assert!(table.sync(&[a, b, c].into_iter().collect()));
+    assert!(table.update(a, vec![20]));
+    assert!(!table.update(a, vec![20]));
+    assert!(table.update(b, vec![40, 30, 10]));
+    let shared = table.shared();
+    assert_eq!(shared[&a], vec![20]);
+    assert_eq!(shared[&b], vec![10, 30]);
+    // 还没有通告的对端不和任何vlan互通
+    assert!(shared[&c].is_empty());
+    assert!(table.sync(&[a].into_iter().collect()));
+    assert_eq!(table.shared().len(), 1);
+    assert!(table.peer(&b).is_empty());
+}
@@ -0,0 +1,719 @@
+//! 实验性的AF_XDP接收加速(linux)。
+//! 在网卡上挂载一个xdp程序，把发往核心udp端口的ipv4包重定向到AF_XDP socket，
+//! 绕过内核协议栈直接交给用户态解密和处理，发送仍然走原来的udp socket。
+//! 只处理不带vlan标签、没有ip选项、没有分片的包，其他的包和没有绑定socket的队列仍然交给内核。
+//! 需要内核5.9以上和CAP_NET_ADMIN、CAP_BPF权限，挂载失败时继续使用普通的udp接收
+use std::ffi::CString;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::{io, mem, ptr, thread};
+
+// musl的off_t都是64位
+#[cfg(target_env = "musl")]
+use libc::mmap as mmap64;
+#[cfg(not(target_env = "musl"))]
+use libc::mmap64;
+
+use crate::channel::context::ChannelContext;
+use crate::channel::handler::RecvChannelHandler;
+use crate::channel::RouteKey;
+use crate::util::StopManager;
+
+const AF_XDP: libc::c_int = 44;
+const SOL_XDP: libc::c_int = 283;
+const XDP_MMAP_OFFSETS: libc::c_int = 1;
+const XDP_RX_RING: libc::c_int = 2;
+const XDP_UMEM_REG: libc::c_int = 4;
+const XDP_UMEM_FILL_RING: libc::c_int = 5;
+const XDP_UMEM_COMPLETION_RING: libc::c_int = 6;
+const XDP_PGOFF_RX_RING: i64 = 0;
+const XDP_UMEM_PGOFF_FILL_RING: i64 = 0x100000000;
+
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_LINK_CREATE: libc::c_long = 28;
+const BPF_MAP_TYPE_XSKMAP: u32 = 17;
+const BPF_PROG_TYPE_XDP: u32 = 6;
+const BPF_XDP: u32 = 37;
+const BPF_FUNC_REDIRECT_MAP: i32 = 51;
+const XDP_PASS: i32 = 2;
+
+const FRAME_SIZE: usize = 2048;
+const FRAME_NUM: u32 = 2048;
+const RING_SIZE: u32 = 1024;
+// 以太网头+ip头+udp头
+const HEADER_LEN: usize = 14 + 20 + 8;
+
+/// 在网卡上启用AF_XDP接收，启用失败或者接收出错时只记录日志并退出xdp线程
+pub fn xdp_listen<H>(
+    interface: String,
+    stop_manager: StopManager,
+    recv_handler: H,
+    context: ChannelContext,
+) -> io::Result<()>
+where
+    H: RecvChannelHandler,
+{
+    let ports = context.main_local_udp_port()?;
+    let worker = stop_manager.add_listener("xdp".into(), || {})?;
+    thread::Builder::new()
+        .name("xdpRecv".into())
+        .spawn(move || {
+            match XdpListener::new(&interface, ports) {
+                Ok(mut listener) => {
+                    log::info!(
+                        "xdp加速已启用 网卡={} 队列数={}",
+                        interface,
+                        listener.sockets.len()
+                    );
+                    if let Err(e) = listener.run(&stop_manager, recv_handler, &context) {
+                        log::error!("xdp接收 {:?}", e);
+                    }
+                }
+                Err(e) => {
+                    log::error!("xdp加速未启用 网卡={} {:?}", interface, e);
+                }
+            }
+            // 只释放xdp自己，普通的udp接收不受影响
+            drop(worker);
+        })?;
+    Ok(())
+}
+
+struct XdpListener {
+    ports: Vec<u16>,
+    sockets: Vec<XskSocket>,
+    // 最后释放，关闭link时从网卡卸载xdp程序
+    _program: XdpProgram,
+}
+
+impl XdpListener {
+    fn new(interface: &str, ports: Vec<u16>) -> io::Result<Self> {
+        let name = CString::new(interface)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
+        let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let queues = rx_queues(interface);
+        let map = bpf_map_create(queues)?;
+        let mut sockets = Vec::with_capacity(queues as usize);
+        for queue in 0..queues {
+            let socket = XskSocket::new(ifindex, queue)?;
+            bpf_map_update(&map, queue, socket.fd.as_raw_fd() as u32)?;
+            sockets.push(socket);
+        }
+        let program = XdpProgram::attach(map, ifindex, &ports)?;
+        Ok(Self {
+            ports,
+            sockets,
+            _program: program,
+        })
+    }
+    fn run<H>(
+        &mut self,
+        stop_manager: &StopManager,
+        mut recv_handler: H,
+        context: &ChannelContext,
+    ) -> io::Result<()>
+    where
+        H: RecvChannelHandler,
+    {
+        let mut fds: Vec<libc::pollfd> = self
+            .sockets
+            .iter()
+            .map(|socket| libc::pollfd {
+                fd: socket.fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        let use_ipv6 = context.use_ipv6();
+        while !stop_manager.is_stop() {
+            let rs = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, 1000) };
+            if rs < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            for (socket, fd) in self.sockets.iter_mut().zip(fds.iter()) {
+                if fd.revents & libc::POLLIN == 0 {
+                    continue;
+                }
+                socket.recv(|frame| {
+                    let (src, port, payload) = match parse_udp(frame) {
+                        Some(rs) => rs,
+                        None => return,
+                    };
+                    let index = match self.ports.iter().position(|p| *p == port) {
+                        Some(index) => index,
+                        None => return,
+                    };
+                    // 和双栈udp socket收到的地址保持一致
+                    let addr = if use_ipv6 {
+                        SocketAddr::V6(SocketAddrV6::new(
+                            src.ip().to_ipv6_mapped(),
+                            src.port(),
+                            0,
+                            0,
+                        ))
+                    } else {
+                        SocketAddr::V4(src)
+                    };
+                    recv_handler.handle(payload, RouteKey::new(false, index, addr), context);
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 网卡的接收队列数
+fn rx_queues(interface: &str) -> u32 {
+    let count = std::fs::read_dir(format!("/sys/class/net/{}/queues", interface))
+        .map(|dir| {
+            dir.filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_name().to_string_lossy().starts_with("rx-"))
+                .count()
+        })
+        .unwrap_or(0);
+    count.max(1) as u32
+}
+
+/// 解析以太网帧中的udp包，返回源地址、目的端口和负载
+fn parse_udp(frame: &mut [u8]) -> Option<(SocketAddrV4, u16, &mut [u8])> {
+    if frame.len() < HEADER_LEN || frame[12..14] != [0x08, 0x00] || frame[14] != 0x45 {
+        return None;
+    }
+    let total_len = u16::from_be_bytes([frame[16], frame[17]]) as usize;
+    if total_len < 28 || 14 + total_len > frame.len() {
+        return None;
+    }
+    let src_ip = Ipv4Addr::new(frame[26], frame[27], frame[28], frame[29]);
+    let src_port = u16::from_be_bytes([frame[34], frame[35]]);
+    let dst_port = u16::from_be_bytes([frame[36], frame[37]]);
+    Some((
+        SocketAddrV4::new(src_ip, src_port),
+        dst_port,
+        &mut frame[HEADER_LEN..14 + total_len],
+    ))
+}
+
+struct Mmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mmap {
+    fn anonymous(len: usize) -> io::Result<Self> {
+        Self::map(
+            -1,
+            len,
+            0,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_POPULATE,
+        )
+    }
+    fn map(fd: RawFd, len: usize, offset: i64, flags: libc::c_int) -> io::Result<Self> {
+        let ptr = unsafe {
+            mmap64(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags,
+                fd,
+                offset as _,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct RingOffset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct MmapOffsets {
+    rx: RingOffset,
+    tx: RingOffset,
+    fr: RingOffset,
+    cr: RingOffset,
+}
+
+#[repr(C)]
+struct UmemReg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+    flags: u32,
+    tx_metadata_len: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct XdpDesc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+#[repr(C)]
+struct SockaddrXdp {
+    family: u16,
+    flags: u16,
+    ifindex: u32,
+    queue_id: u32,
+    shared_umem_fd: u32,
+}
+
+/// 和内核共享的单生产者单消费者环形队列
+struct Ring<T> {
+    map: Mmap,
+    offset: (usize, usize, usize),
+    mask: u32,
+    _marker: std::marker::PhantomData<T>,
+}
+
+impl<T: Copy> Ring<T> {
+    fn new(fd: RawFd, offset: &RingOffset, size: u32, pgoff: i64) -> io::Result<Self> {
+        let len = offset.desc as usize + size as usize * mem::size_of::<T>();
+        let map = Mmap::map(fd, len, pgoff, libc::MAP_SHARED | libc::MAP_POPULATE)?;
+        Ok(Self {
+            map,
+            offset: (
+                offset.producer as usize,
+                offset.consumer as usize,
+                offset.desc as usize,
+            ),
+            mask: size - 1,
+            _marker: Default::default(),
+        })
+    }
+    fn producer(&self) -> &AtomicU32 {
+        unsafe { &*(self.map.ptr.add(self.offset.0) as *const AtomicU32) }
+    }
+    fn consumer(&self) -> &AtomicU32 {
+        unsafe { &*(self.map.ptr.add(self.offset.1) as *const AtomicU32) }
+    }
+    fn desc(&self, index: u32) -> *mut T {
+        unsafe { (self.map.ptr.add(self.offset.2) as *mut T).add((index & self.mask) as usize) }
+    }
+    fn get(&self, index: u32) -> T {
+        unsafe { ptr::read_volatile(self.desc(index)) }
+    }
+    fn set(&self, index: u32, value: T) {
+        unsafe { ptr::write_volatile(self.desc(index), value) }
+    }
+}
+
+/// 绑定到网卡一个接收队列的AF_XDP socket，每个socket使用独立的umem
+struct XskSocket {
+    rx: Ring<XdpDesc>,
+    fill: Ring<u64>,
+    fd: OwnedFd,
+    umem: Mmap,
+}
+
+impl XskSocket {
+    fn new(ifindex: u32, queue: u32) -> io::Result<Self> {
+        let fd = unsafe { libc::socket(AF_XDP, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let raw_fd = fd.as_raw_fd();
+        let umem = Mmap::anonymous(FRAME_SIZE * FRAME_NUM as usize)?;
+        let reg = UmemReg {
+            addr: umem.ptr as u64,
+            len: umem.len as u64,
+            chunk_size: FRAME_SIZE as u32,
+            headroom: 0,
+            flags: 0,
+            tx_metadata_len: 0,
+        };
+        set_option(raw_fd, XDP_UMEM_REG, &reg)?;
+        set_option(raw_fd, XDP_UMEM_FILL_RING, &FRAME_NUM)?;
+        set_option(raw_fd, XDP_UMEM_COMPLETION_RING, &RING_SIZE)?;
+        set_option(raw_fd, XDP_RX_RING, &RING_SIZE)?;
+        let mut offsets = MmapOffsets::default();
+        let mut len = mem::size_of::<MmapOffsets>() as libc::socklen_t;
+        let rs = unsafe {
+            libc::getsockopt(
+                raw_fd,
+                SOL_XDP,
+                XDP_MMAP_OFFSETS,
+                &mut offsets as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if rs < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if len as usize != mem::size_of::<MmapOffsets>() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "kernel does not support xdp ring flags",
+            ));
+        }
+        let rx = Ring::new(raw_fd, &offsets.rx, RING_SIZE, XDP_PGOFF_RX_RING)?;
+        let fill = Ring::new(raw_fd, &offsets.fr, FRAME_NUM, XDP_UMEM_PGOFF_FILL_RING)?;
+        // 所有帧都交给内核接收
+        for i in 0..FRAME_NUM {
+            fill.set(i, i as u64 * FRAME_SIZE as u64);
+        }
+        fill.producer().store(FRAME_NUM, Ordering::Release);
+        let addr = SockaddrXdp {
+            family: AF_XDP as u16,
+            flags: 0,
+            ifindex,
+            queue_id: queue,
+            shared_umem_fd: 0,
+        };
+        let rs = unsafe {
+            libc::bind(
+                raw_fd,
+                &addr as *const _ as *const libc::sockaddr,
+                mem::size_of::<SockaddrXdp>() as libc::socklen_t,
+            )
+        };
+        if rs < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { rx, fill, fd, umem })
+    }
+    /// 处理收到的帧，处理完后帧归还给fill队列
+    fn recv<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&mut [u8]),
+    {
+        let producer = self.rx.producer().load(Ordering::Acquire);
+        let mut consumer = self.rx.consumer().load(Ordering::Relaxed);
+        let mut fill = self.fill.producer().load(Ordering::Relaxed);
+        while consumer != producer {
+            let desc = self.rx.get(consumer);
+            consumer = consumer.wrapping_add(1);
+            let start = desc.addr as usize;
+            let end = start + desc.len as usize;
+            if end <= self.umem.len {
+                let frame = unsafe {
+                    std::slice::from_raw_parts_mut(self.umem.ptr.add(start), end - start)
+                };
+                f(frame);
+            }
+            self.fill.set(fill, desc.addr & !(FRAME_SIZE as u64 - 1));
+            fill = fill.wrapping_add(1);
+        }
+        self.rx.consumer().store(consumer, Ordering::Release);
+        self.fill.producer().store(fill, Ordering::Release);
+    }
+}
+
+fn set_option<T>(fd: RawFd, name: libc::c_int, value: &T) -> io::Result<()> {
+    let rs = unsafe {
+        libc::setsockopt(
+            fd,
+            SOL_XDP,
+            name,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if rs < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn bpf<T>(cmd: libc::c_long, attr: &T) -> io::Result<OwnedFd> {
+    let rs = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::c_uint,
+        )
+    };
+    if rs < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(rs as RawFd) })
+}
+
+#[repr(C)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[repr(C)]
+struct MapUpdateAttr {
+    map_fd: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+}
+
+#[repr(C)]
+struct LinkCreateAttr {
+    prog_fd: u32,
+    target_ifindex: u32,
+    attach_type: u32,
+    flags: u32,
+}
+
+/// 接收队列 -> AF_XDP socket
+fn bpf_map_create(queues: u32) -> io::Result<OwnedFd> {
+    bpf(
+        BPF_MAP_CREATE,
+        &MapCreateAttr {
+            map_type: BPF_MAP_TYPE_XSKMAP,
+            key_size: 4,
+            value_size: 4,
+            max_entries: queues,
+            map_flags: 0,
+        },
+    )
+}
+
+fn bpf_map_update(map: &OwnedFd, key: u32, value: u32) -> io::Result<()> {
+    let rs = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            BPF_MAP_UPDATE_ELEM,
+            &MapUpdateAttr {
+                map_fd: map.as_raw_fd() as u32,
+                key: &key as *const u32 as u64,
+                value: &value as *const u32 as u64,
+                flags: 0,
+            } as *const MapUpdateAttr as *const libc::c_void,
+            mem::size_of::<MapUpdateAttr>() as libc::c_uint,
+        )
+    };
+    if rs < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// 挂载在网卡上的xdp程序，link关闭时自动卸载
+struct XdpProgram {
+    _link: OwnedFd,
+    _prog: OwnedFd,
+    _map: OwnedFd,
+}
+
+impl XdpProgram {
+    fn attach(map: OwnedFd, ifindex: u32, ports: &[u16]) -> io::Result<Self> {
+        let insns = xdp_program(map.as_raw_fd(), ports);
+        let license = b"GPL\0";
+        let mut prog_name = [0u8; 16];
+        prog_name[..7].copy_from_slice(b"vnt_xdp");
+        let prog = bpf(
+            BPF_PROG_LOAD,
+            &ProgLoadAttr {
+                prog_type: BPF_PROG_TYPE_XDP,
+                insn_cnt: insns.len() as u32,
+                insns: insns.as_ptr() as u64,
+                license: license.as_ptr() as u64,
+                log_level: 0,
+                log_size: 0,
+                log_buf: 0,
+                kern_version: 0,
+                prog_flags: 0,
+                prog_name,
+                prog_ifindex: 0,
+                expected_attach_type: BPF_XDP,
+            },
+        )?;
+        let link = bpf(
+            BPF_LINK_CREATE,
+            &LinkCreateAttr {
+                prog_fd: prog.as_raw_fd() as u32,
+                target_ifindex: ifindex,
+                attach_type: BPF_XDP,
+                flags: 0,
+            },
+        )?;
+        Ok(Self {
+            _link: link,
+            _prog: prog,
+            _map: map,
+        })
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct BpfInsn {
+    code: u8,
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+const MOV64_X: u8 = 0xbf;
+const MOV64_K: u8 = 0xb7;
+const ADD64_K: u8 = 0x07;
+const AND64_K: u8 = 0x57;
+const LDX_W: u8 = 0x61;
+const LDX_H: u8 = 0x69;
+const LDX_B: u8 = 0x71;
+const LD_DW: u8 = 0x18;
+const JA: u8 = 0x05;
+const JEQ_K: u8 = 0x15;
+const JNE_K: u8 = 0x55;
+const JGT_X: u8 = 0x2d;
+const CALL: u8 = 0x85;
+const EXIT: u8 = 0x95;
+const PSEUDO_MAP_FD: u8 = 1;
+
+fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> BpfInsn {
+    #[cfg(target_endian = "little")]
+    let regs = dst | (src << 4);
+    #[cfg(target_endian = "big")]
+    let regs = (dst << 4) | src;
+    BpfInsn {
+        code,
+        regs,
+        off,
+        imm,
+    }
+}
+
+/// 按主机字节序读取网络字节序的u16时的值
+fn be16(v: u16) -> i32 {
+    u16::from_ne_bytes(v.to_be_bytes()) as i32
+}
+
+/// 发往指定端口的ipv4 udp包重定向到接收队列对应的socket，
+/// 其他的包和没有socket的队列返回XDP_PASS
+fn xdp_program(map_fd: RawFd, ports: &[u16]) -> Vec<BpfInsn> {
+    let mut prog = Vec::with_capacity(32);
+    // 跳到XDP_PASS和重定向的指令，最后修正偏移
+    let mut to_pass = Vec::new();
+    let mut to_redirect = Vec::new();
+    // r6 = ctx, r2 = data, r3 = data_end
+    prog.push(insn(MOV64_X, 6, 1, 0, 0));
+    prog.push(insn(LDX_W, 2, 1, 0, 0));
+    prog.push(insn(LDX_W, 3, 1, 4, 0));
+    prog.push(insn(MOV64_X, 4, 2, 0, 0));
+    prog.push(insn(ADD64_K, 4, 0, 0, HEADER_LEN as i32));
+    to_pass.push(prog.len());
+    prog.push(insn(JGT_X, 4, 3, 0, 0));
+    // ipv4
+    prog.push(insn(LDX_H, 5, 2, 12, 0));
+    to_pass.push(prog.len());
+    prog.push(insn(JNE_K, 5, 0, 0, be16(0x0800)));
+    // 没有ip选项
+    prog.push(insn(LDX_B, 5, 2, 14, 0));
+    to_pass.push(prog.len());
+    prog.push(insn(JNE_K, 5, 0, 0, 0x45));
+    // udp
+    prog.push(insn(LDX_B, 5, 2, 23, 0));
+    to_pass.push(prog.len());
+    prog.push(insn(JNE_K, 5, 0, 0, libc::IPPROTO_UDP));
+    // 不是分片
+    prog.push(insn(LDX_H, 5, 2, 20, 0));
+    prog.push(insn(AND64_K, 5, 0, 0, be16(0x3fff)));
+    to_pass.push(prog.len());
+    prog.push(insn(JNE_K, 5, 0, 0, 0));
+    // 目的端口
+    prog.push(insn(LDX_H, 5, 2, 36, 0));
+    for port in ports {
+        to_redirect.push(prog.len());
+        prog.push(insn(JEQ_K, 5, 0, 0, be16(*port)));
+    }
+    to_pass.push(prog.len());
+    prog.push(insn(JA, 0, 0, 0, 0));
+    // bpf_redirect_map(map, rx_queue_index, XDP_PASS)
+    let redirect = prog.len();
+    prog.push(insn(LD_DW, 1, PSEUDO_MAP_FD, 0, map_fd));
+    prog.push(insn(0, 0, 0, 0, 0));
+    prog.push(insn(LDX_W, 2, 6, 16, 0));
+    prog.push(insn(MOV64_K, 3, 0, 0, XDP_PASS));
+    prog.push(insn(CALL, 0, 0, 0, BPF_FUNC_REDIRECT_MAP));
+    prog.push(insn(EXIT, 0, 0, 0, 0));
+    let pass = prog.len();
+    prog.push(insn(MOV64_K, 0, 0, 0, XDP_PASS));
+    prog.push(insn(EXIT, 0, 0, 0, 0));
+    for i in to_pass {
+        prog[i].off = (pass - i - 1) as i16;
+    }
+    for i in to_redirect {
+        prog[i].off = (redirect - i - 1) as i16;
+    }
+    prog
+}
+
+#[test]
+fn test_parse_udp() {
+    let mut frame = vec![0u8; HEADER_LEN + 4 + 6];
+    frame[12..14].copy_from_slice(&[0x08, 0x00]);
+    frame[14] = 0x45;
+    frame[16..18].copy_from_slice(&(28u16 + 4).to_be_bytes());
+    frame[23] = 17;
+    frame[26..30].copy_from_slice(&[192, 168, 1, 2]);
+    frame[34..36].copy_from_slice(&29872u16.to_be_bytes());
+    frame[36..38].copy_from_slice(&29871u16.to_be_bytes());
+    frame[HEADER_LEN..HEADER_LEN + 4].copy_from_slice(&[1, 2, 3, 4]);
+    // 以太网帧尾部的填充不属于负载
+    let (src, port, payload) = parse_udp(&mut frame).unwrap();
+    assert_eq!(src, "192.168.1.2:29872".parse().unwrap());
+    assert_eq!(port, 29871);
+    assert_eq!(payload, &[1, 2, 3, 4]);
+    frame[14] = 0x46;
+    assert!(parse_udp(&mut frame).is_none());
+
+    let prog = xdp_program(3, &[29871, 29872]);
+    // 最后一条指令退出，跳转都在程序范围内
+    assert_eq!(prog.last().unwrap().code, EXIT);
+    for (i, insn) in prog.iter().enumerate() {
+        if insn.code & 0x07 == 0x05 && insn.code != CALL && insn.code != EXIT {
+            assert!(i as i64 + 1 + (insn.off as i64) < prog.len() as i64);
+        }
+    }
+}
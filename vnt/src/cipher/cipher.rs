@@ -24,15 +24,8 @@ use crate::cipher::sm4_cbc::Sm4CbcCipher;
     feature = "sm4_cbc"
 ))]
 use crate::cipher::Finger;
+use crate::cipher::{HeaderAuth, KeyDerivation};
 use crate::protocol::NetPacket;
-#[cfg(any(
-    feature = "aes_gcm",
-    feature = "server_encrypt",
-    feature = "aes_cbc",
-    feature = "aes_ecb",
-    feature = "sm4_cbc"
-))]
-use sha2::Digest;
 use std::io;
 use std::str::FromStr;
 
@@ -40,23 +33,34 @@ use std::str::FromStr;
 pub enum CipherModel {
     #[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
     AesGcm,
+    // 不论密码长度都使用128位密钥，低性能设备上速度明显更快
+    #[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
+    Aes128Gcm,
     #[cfg(feature = "aes_cbc")]
     AesCbc,
+    #[cfg(feature = "aes_cbc")]
+    Aes128Cbc,
     #[cfg(feature = "aes_ecb")]
     AesEcb,
     #[cfg(feature = "sm4_cbc")]
     Sm4Cbc,
     None,
+    // 启动时测速，选择最快的AEAD
+    #[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
+    Auto,
 }
 
 impl Display for CipherModel {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let str = match self {
             CipherModel::AesGcm => "aes_gcm".to_string(),
+            CipherModel::Aes128Gcm => "aes-128-gcm".to_string(),
             CipherModel::AesCbc => "aes_cbc".to_string(),
+            CipherModel::Aes128Cbc => "aes-128-cbc".to_string(),
             CipherModel::AesEcb => "aes_ecb".to_string(),
             CipherModel::Sm4Cbc => "sm4_cbc".to_string(),
             CipherModel::None => "none".to_string(),
+            CipherModel::Auto => "auto".to_string(),
         };
         write!(f, "{}", str)
     }
@@ -83,22 +87,30 @@ impl FromStr for CipherModel {
         match s.to_lowercase().trim() {
             #[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
             "aes_gcm" => Ok(CipherModel::AesGcm),
+            #[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
+            "aes-128-gcm" | "aes_128_gcm" => Ok(CipherModel::Aes128Gcm),
             #[cfg(feature = "aes_cbc")]
             "aes_cbc" => Ok(CipherModel::AesCbc),
+            #[cfg(feature = "aes_cbc")]
+            "aes-128-cbc" | "aes_128_cbc" => Ok(CipherModel::Aes128Cbc),
             #[cfg(feature = "aes_ecb")]
             "aes_ecb" => Ok(CipherModel::AesEcb),
             #[cfg(feature = "sm4_cbc")]
             "sm4_cbc" => Ok(CipherModel::Sm4Cbc),
+            #[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
+            "auto" => Ok(CipherModel::Auto),
             _ => {
                 let mut enums = String::new();
                 #[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
-                enums.push_str("/aes_gcm");
+                enums.push_str("/aes_gcm/aes-128-gcm");
                 #[cfg(feature = "aes_cbc")]
-                enums.push_str("/aes_cbc");
+                enums.push_str("/aes_cbc/aes-128-cbc");
                 #[cfg(feature = "aes_ecb")]
                 enums.push_str("/aes_ecb");
                 #[cfg(feature = "sm4_cbc")]
                 enums.push_str("/sm4_cbc");
+                #[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
+                enums.push_str("/auto");
                 let str = if enums.is_empty() {
                     "no encrypt"
                 } else {
@@ -110,6 +122,99 @@ impl FromStr for CipherModel {
     }
 }
 
+impl CipherModel {
+    /// 编译进来的加密模式，不包含none和auto
+    #[allow(clippy::vec_init_then_push)]
+    pub fn compiled() -> Vec<CipherModel> {
+        #[allow(unused_mut)]
+        let mut list = Vec::new();
+        #[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
+        list.push(CipherModel::AesGcm);
+        #[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
+        list.push(CipherModel::Aes128Gcm);
+        #[cfg(feature = "aes_cbc")]
+        list.push(CipherModel::AesCbc);
+        #[cfg(feature = "aes_cbc")]
+        list.push(CipherModel::Aes128Cbc);
+        #[cfg(feature = "aes_ecb")]
+        list.push(CipherModel::AesEcb);
+        #[cfg(feature = "sm4_cbc")]
+        list.push(CipherModel::Sm4Cbc);
+        list
+    }
+    /// 是否同时提供加密和完整性校验
+    pub fn is_aead(&self) -> bool {
+        match self {
+            #[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
+            CipherModel::AesGcm | CipherModel::Aes128Gcm => true,
+            _ => false,
+        }
+    }
+    /// 不论密码长度都使用128位密钥的模式
+    fn is_fixed_128(&self) -> bool {
+        match self {
+            #[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
+            CipherModel::Aes128Gcm => true,
+            #[cfg(feature = "aes_cbc")]
+            CipherModel::Aes128Cbc => true,
+            _ => false,
+        }
+    }
+    /// 使用的密钥长度，从派生出的32字节密钥中截取，
+    /// aes_gcm、aes_cbc和aes_ecb在密码少于8位时使用128位密钥
+    pub fn key_len(&self, password: &str) -> usize {
+        if self.is_fixed_128() {
+            return 16;
+        }
+        match self {
+            #[cfg(feature = "sm4_cbc")]
+            CipherModel::Sm4Cbc => 16,
+            CipherModel::None => 0,
+            _ => {
+                if password.len() < 8 {
+                    16
+                } else {
+                    32
+                }
+            }
+        }
+    }
+}
+
+/// 加密模式在当前CPU上的测速结果
+#[derive(Clone, Debug)]
+pub struct CipherBench {
+    pub model: CipherModel,
+    // 每秒加解密的字节数
+    pub bytes_per_sec: u64,
+}
+
+impl CipherBench {
+    /// 最快的AEAD，不会自动降低为128位密钥
+    pub fn fastest_aead(list: &[CipherBench]) -> Option<CipherModel> {
+        list.iter()
+            .filter(|v| v.model.is_aead() && !v.model.is_fixed_128())
+            .max_by_key(|v| v.bytes_per_sec)
+            .map(|v| v.model)
+    }
+}
+
+impl Display for CipherBench {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}={:.1}MB/s",
+            self.model,
+            self.bytes_per_sec as f64 / 1024.0 / 1024.0
+        )
+    }
+}
+
+// 测速使用的包长度，接近常见的mtu
+const BENCH_PAYLOAD_LEN: usize = 1400;
+// 每种模式的测速时长
+const BENCH_DURATION: std::time::Duration = std::time::Duration::from_millis(100);
+
 #[derive(Clone)]
 pub enum Cipher {
     #[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
@@ -120,9 +225,59 @@ pub enum Cipher {
     AesEcb(AesEcbCipher),
     #[cfg(feature = "sm4_cbc")]
     Sm4Cbc(Sm4CbcCipher),
+    // 不加密，只做消息认证
+    Auth(HeaderAuth),
     None,
 }
 impl Cipher {
+    /// 测量每种编译进来的加密模式的加解密吞吐量
+    pub fn benchmark() -> Vec<CipherBench> {
+        CipherModel::compiled()
+            .into_iter()
+            .filter_map(|model| match Self::bench_model(model) {
+                Ok(bytes_per_sec) => Some(CipherBench {
+                    model,
+                    bytes_per_sec,
+                }),
+                Err(e) => {
+                    log::warn!("测速失败 {} {:?}", model, e);
+                    None
+                }
+            })
+            .collect()
+    }
+    fn bench_model(model: CipherModel) -> io::Result<u64> {
+        let cipher = Cipher::new_password(
+            model,
+            KeyDerivation::V1,
+            "",
+            Some("vnt-benchmark".to_string()),
+            None,
+        );
+        let mut packet = NetPacket::new_encrypt(vec![
+            0u8;
+            12 + BENCH_PAYLOAD_LEN
+                + crate::protocol::body::ENCRYPTION_RESERVED
+        ])?;
+        packet.set_default_version();
+        packet.first_set_ttl(3);
+        packet.set_source(std::net::Ipv4Addr::new(10, 26, 0, 2));
+        packet.set_destination(std::net::Ipv4Addr::new(10, 26, 0, 3));
+        let payload = [0x5au8; BENCH_PAYLOAD_LEN];
+        let start = std::time::Instant::now();
+        let mut bytes = 0u64;
+        while start.elapsed() < BENCH_DURATION {
+            packet.set_payload(&payload)?;
+            cipher.encrypt_ipv4(&mut packet)?;
+            cipher.decrypt_ipv4(&mut packet)?;
+            bytes += BENCH_PAYLOAD_LEN as u64;
+        }
+        let micros = start.elapsed().as_micros().max(1) as u64;
+        Ok(bytes * 1_000_000 / micros)
+    }
+    pub fn new_auth(token: &str) -> Self {
+        Cipher::Auth(HeaderAuth::new(token))
+    }
     #[cfg(not(any(
         feature = "aes_gcm",
         feature = "server_encrypt",
@@ -132,11 +287,28 @@ impl Cipher {
     )))]
     pub fn new_password(
         _model: CipherModel,
+        _kdf: KeyDerivation,
+        _salt: &str,
         _password: Option<String>,
         _token: Option<String>,
     ) -> Self {
         Cipher::None
     }
+    #[cfg(not(any(
+        feature = "aes_gcm",
+        feature = "server_encrypt",
+        feature = "aes_cbc",
+        feature = "aes_ecb",
+        feature = "sm4_cbc"
+    )))]
+    pub(crate) fn new_derived(
+        _model: CipherModel,
+        _key: [u8; 32],
+        _key_len: usize,
+        _token: Option<String>,
+    ) -> Self {
+        Cipher::None
+    }
     #[cfg(any(
         feature = "aes_gcm",
         feature = "server_encrypt",
@@ -144,56 +316,74 @@ impl Cipher {
         feature = "aes_ecb",
         feature = "sm4_cbc"
     ))]
+    /// salt在同一网络内必须相同，使用token
     pub fn new_password(
         model: CipherModel,
+        kdf: KeyDerivation,
+        salt: &str,
         password: Option<String>,
         token: Option<String>,
     ) -> Self {
-        let finger = token.map(|token| Finger::new(&token));
         if let Some(password) = password {
-            let mut hasher = sha2::Sha256::new();
-            hasher.update(password.as_bytes());
-            let key: [u8; 32] = hasher.finalize().into();
-            match model {
-                #[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
-                CipherModel::AesGcm => {
-                    if password.len() < 8 {
-                        let aes = AesGcmCipher::new_128(key[..16].try_into().unwrap(), finger);
-                        Cipher::AesGcm((aes, key[..16].to_vec()))
-                    } else {
-                        let aes = AesGcmCipher::new_256(key, finger);
-                        Cipher::AesGcm((aes, key.to_vec()))
-                    }
-                }
-                #[cfg(feature = "aes_cbc")]
-                CipherModel::AesCbc => {
-                    if password.len() < 8 {
-                        let aes = AesCbcCipher::new_128(key[..16].try_into().unwrap(), finger);
-                        Cipher::AesCbc(aes)
-                    } else {
-                        let aes = AesCbcCipher::new_256(key, finger);
-                        Cipher::AesCbc(aes)
-                    }
+            let key = kdf.derive(&password, salt);
+            Cipher::new_derived(model, key, model.key_len(&password), token)
+        } else {
+            Cipher::None
+        }
+    }
+    #[cfg(any(
+        feature = "aes_gcm",
+        feature = "server_encrypt",
+        feature = "aes_cbc",
+        feature = "aes_ecb",
+        feature = "sm4_cbc"
+    ))]
+    /// 使用已经派生好的密钥，key_len为实际使用的长度
+    pub(crate) fn new_derived(
+        model: CipherModel,
+        key: [u8; 32],
+        key_len: usize,
+        token: Option<String>,
+    ) -> Self {
+        let finger = token.map(|token| Finger::new(&token));
+        match model {
+            // auto在启动时已经替换为测速选出的模式，这里按aes_gcm处理
+            #[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
+            CipherModel::AesGcm | CipherModel::Aes128Gcm | CipherModel::Auto => {
+                if key_len == 16 {
+                    let aes = AesGcmCipher::new_128(key[..16].try_into().unwrap(), finger);
+                    Cipher::AesGcm((aes, key[..16].to_vec()))
+                } else {
+                    let aes = AesGcmCipher::new_256(key, finger);
+                    Cipher::AesGcm((aes, key.to_vec()))
                 }
-                #[cfg(feature = "aes_ecb")]
-                CipherModel::AesEcb => {
-                    if password.len() < 8 {
-                        let aes = AesEcbCipher::new_128(key[..16].try_into().unwrap(), finger);
-                        Cipher::AesEcb(aes)
-                    } else {
-                        let aes = AesEcbCipher::new_256(key, finger);
-                        Cipher::AesEcb(aes)
-                    }
+            }
+            #[cfg(feature = "aes_cbc")]
+            CipherModel::AesCbc | CipherModel::Aes128Cbc => {
+                if key_len == 16 {
+                    let aes = AesCbcCipher::new_128(key[..16].try_into().unwrap(), finger);
+                    Cipher::AesCbc(aes)
+                } else {
+                    let aes = AesCbcCipher::new_256(key, finger);
+                    Cipher::AesCbc(aes)
                 }
-                #[cfg(feature = "sm4_cbc")]
-                CipherModel::Sm4Cbc => {
-                    let aes = Sm4CbcCipher::new_128(key[..16].try_into().unwrap(), finger);
-                    Cipher::Sm4Cbc(aes)
+            }
+            #[cfg(feature = "aes_ecb")]
+            CipherModel::AesEcb => {
+                if key_len == 16 {
+                    let aes = AesEcbCipher::new_128(key[..16].try_into().unwrap(), finger);
+                    Cipher::AesEcb(aes)
+                } else {
+                    let aes = AesEcbCipher::new_256(key, finger);
+                    Cipher::AesEcb(aes)
                 }
-                CipherModel::None => Cipher::None,
             }
-        } else {
-            Cipher::None
+            #[cfg(feature = "sm4_cbc")]
+            CipherModel::Sm4Cbc => {
+                let aes = Sm4CbcCipher::new_128(key[..16].try_into().unwrap(), finger);
+                Cipher::Sm4Cbc(aes)
+            }
+            CipherModel::None => Cipher::None,
         }
     }
     #[cfg(not(any(
@@ -242,6 +432,7 @@ impl Cipher {
             Cipher::AesEcb(aes_ecb) => aes_ecb.decrypt_ipv4(net_packet),
             #[cfg(feature = "sm4_cbc")]
             Cipher::Sm4Cbc(sm4_cbc) => sm4_cbc.decrypt_ipv4(net_packet),
+            Cipher::Auth(auth) => auth.decrypt_ipv4(net_packet),
             Cipher::None => {
                 if net_packet.is_encrypt() {
                     return Err(io::Error::new(io::ErrorKind::Other, "not key"));
@@ -259,9 +450,12 @@ impl Cipher {
     )))]
     pub fn encrypt_ipv4<B: AsRef<[u8]> + AsMut<[u8]>>(
         &self,
-        _net_packet: &mut NetPacket<B>,
+        net_packet: &mut NetPacket<B>,
     ) -> io::Result<()> {
-        Ok(())
+        match self {
+            Cipher::Auth(auth) => auth.encrypt_ipv4(net_packet),
+            Cipher::None => Ok(()),
+        }
     }
     #[cfg(any(
         feature = "aes_gcm",
@@ -283,6 +477,7 @@ impl Cipher {
             Cipher::AesEcb(aes_ecb) => aes_ecb.encrypt_ipv4(net_packet),
             #[cfg(feature = "sm4_cbc")]
             Cipher::Sm4Cbc(sm4_cbc) => sm4_cbc.encrypt_ipv4(net_packet),
+            Cipher::Auth(auth) => auth.encrypt_ipv4(net_packet),
             Cipher::None => Ok(()),
         }
     }
@@ -332,9 +527,17 @@ impl Cipher {
                 .as_ref()
                 .map(|f| f.check_finger(net_packet))
                 .unwrap_or(Ok(())),
+            Cipher::Auth(_) => Ok(()),
             Cipher::None => Ok(()),
         }
     }
+    /// 消息认证模式下校验标签，中转的包不解密，只校验来源是否可信
+    pub fn verify_auth<B: AsRef<[u8]>>(&self, net_packet: &NetPacket<B>) -> io::Result<()> {
+        match self {
+            Cipher::Auth(auth) => auth.verify(net_packet),
+            _ => Ok(()),
+        }
+    }
     pub fn key(&self) -> Option<&[u8]> {
         match self {
             #[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
@@ -345,7 +548,40 @@ impl Cipher {
             Cipher::AesEcb(aes_ecb) => Some(aes_ecb.key()),
             #[cfg(feature = "sm4_cbc")]
             Cipher::Sm4Cbc(sm4_cbc) => Some(sm4_cbc.key()),
+            Cipher::Auth(_) => None,
             Cipher::None => None,
         }
     }
 }
+
+#[test]
+fn test_benchmark() {
+    let list = Cipher::benchmark();
+    assert_eq!(list.len(), CipherModel::compiled().len());
+    assert!(list.iter().all(|v| v.bytes_per_sec > 0));
+    #[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
+    assert_eq!(CipherBench::fastest_aead(&list), Some(CipherModel::AesGcm));
+}
+
+#[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
+#[test]
+fn test_aes_128_model() {
+    let model = CipherModel::from_str("aes-128-gcm").unwrap();
+    assert_eq!(model.to_string(), "aes-128-gcm");
+    assert_eq!(model.key_len("long-password"), 16);
+    assert_eq!(CipherModel::AesGcm.key_len("long-password"), 32);
+    assert_eq!(CipherModel::AesGcm.key_len("short"), 16);
+    let key = |model| {
+        Cipher::new_password(
+            model,
+            KeyDerivation::V1,
+            "",
+            Some("long-password".to_string()),
+            None,
+        )
+        .key()
+        .map(|v| v.len())
+    };
+    assert_eq!(key(model), Some(16));
+    assert_eq!(key(CipherModel::AesGcm), Some(32));
+}
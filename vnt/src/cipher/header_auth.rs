@@ -0,0 +1,165 @@
+use std::io;
+
+use sha2::Digest;
+
+use crate::protocol::{NetPacket, HEAD_LEN};
+
+/// 认证标签长度
+pub const AUTH_TAG_LEN: usize = 12;
+const BLOCK_LEN: usize = 64;
+
+/// 不加密时的消息认证，使用token派生的密钥对包头和载荷计算HMAC-SHA256，
+/// 知道token的设备才能构造合法的包，防止伪造来源虚拟ip
+#[derive(Clone)]
+pub struct HeaderAuth {
+    key: [u8; 32],
+}
+
+impl HeaderAuth {
+    pub fn new(token: &str) -> Self {
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(b"vnt-header-auth");
+        hasher.update(token.as_bytes());
+        HeaderAuth {
+            key: hasher.finalize().into(),
+        }
+    }
+    pub fn verify<B: AsRef<[u8]>>(&self, net_packet: &NetPacket<B>) -> io::Result<()> {
+        if !net_packet.is_encrypt() {
+            //没有认证标签的数据直接丢弃
+            return Err(io::Error::new(io::ErrorKind::Other, "not auth"));
+        }
+        let payload = net_packet.payload();
+        if payload.len() < AUTH_TAG_LEN {
+            log::error!("数据异常,长度小于{}", AUTH_TAG_LEN);
+            return Err(io::Error::new(io::ErrorKind::Other, "data err"));
+        }
+        let (body, tag) = payload.split_at(payload.len() - AUTH_TAG_LEN);
+        let expect = self.tag(&header(net_packet), body);
+        if !ct_eq(&expect, tag) {
+            return Err(io::Error::new(io::ErrorKind::Other, "auth err"));
+        }
+        Ok(())
+    }
+    pub fn decrypt_ipv4<B: AsRef<[u8]> + AsMut<[u8]>>(
+        &self,
+        net_packet: &mut NetPacket<B>,
+    ) -> io::Result<()> {
+        self.verify(net_packet)?;
+        net_packet.set_encrypt_flag(false);
+        net_packet.set_data_len(net_packet.data_len() - AUTH_TAG_LEN)?;
+        Ok(())
+    }
+    /// net_packet 必须预留AUTH_TAG_LEN长度
+    pub fn encrypt_ipv4<B: AsRef<[u8]> + AsMut<[u8]>>(
+        &self,
+        net_packet: &mut NetPacket<B>,
+    ) -> io::Result<()> {
+        let data_len = net_packet.data_len();
+        let tag = self.tag(&header(net_packet), net_packet.payload());
+        net_packet.set_data_len(data_len + AUTH_TAG_LEN)?;
+        net_packet.payload_mut()[data_len - HEAD_LEN..].copy_from_slice(&tag);
+        net_packet.set_encrypt_flag(true);
+        Ok(())
+    }
+    fn tag(&self, header: &[u8], body: &[u8]) -> [u8; AUTH_TAG_LEN] {
+        let mac = hmac_sha256(&self.key, &[header, body]);
+        mac[..AUTH_TAG_LEN].try_into().unwrap()
+    }
+}
+
+/// HMAC-SHA256，data按顺序拼接后计算，key超过64字节时先做一次sha256
+pub(crate) fn hmac_sha256(key: &[u8], data: &[&[u8]]) -> [u8; 32] {
+    let mut block_key = [0u8; BLOCK_LEN];
+    if key.len() > BLOCK_LEN {
+        block_key[..32].copy_from_slice(&sha2::Sha256::digest(key));
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(block_key.map(|v| v ^ 0x36));
+    for v in data {
+        hasher.update(v);
+    }
+    let inner: [u8; 32] = hasher.finalize().into();
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(block_key.map(|v| v ^ 0x5c));
+    hasher.update(inner);
+    hasher.finalize().into()
+}
+
+/// 固定时间比较，耗时和内容无关，长度不同时直接返回false
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// 中转时会变化的ttl不参与计算
+fn header<B: AsRef<[u8]>>(net_packet: &NetPacket<B>) -> [u8; 12] {
+    let mut header = [0; 12];
+    header[0..4].copy_from_slice(&net_packet.source().octets());
+    header[4..8].copy_from_slice(&net_packet.destination().octets());
+    header[8] = net_packet.protocol().into();
+    header[9] = net_packet.transport_protocol();
+    header[10] = net_packet.is_gateway() as u8;
+    header[11] = net_packet.source_ttl();
+    header
+}
+
+#[test]
+fn test_header_auth() {
+    let auth = HeaderAuth::new("token");
+    let mut packet = NetPacket::new_encrypt(vec![
+        0u8;
+        12 + 4
+            + crate::protocol::body::ENCRYPTION_RESERVED
+    ])
+    .unwrap();
+    packet.set_default_version();
+    packet.first_set_ttl(3);
+    packet.set_source(std::net::Ipv4Addr::new(10, 26, 0, 2));
+    packet.set_destination(std::net::Ipv4Addr::new(10, 26, 0, 3));
+    packet.set_payload(&[1, 2, 3, 4]).unwrap();
+    auth.encrypt_ipv4(&mut packet).unwrap();
+    // 中转时ttl变化不影响校验
+    packet.incr_ttl();
+    auth.verify(&packet).unwrap();
+    assert!(HeaderAuth::new("other").verify(&packet).is_err());
+    packet.set_source(std::net::Ipv4Addr::new(10, 26, 0, 4));
+    assert!(auth.verify(&packet).is_err());
+    packet.set_source(std::net::Ipv4Addr::new(10, 26, 0, 2));
+    auth.decrypt_ipv4(&mut packet).unwrap();
+    assert_eq!(packet.payload(), &[1, 2, 3, 4]);
+}
+
+#[test]
+fn test_hmac_sha256() {
+    let hex = |mac: [u8; 32]| -> String { mac.iter().map(|v| format!("{:02x}", v)).collect() };
+    // RFC 4231 test case 2
+    assert_eq!(
+        hex(hmac_sha256(
+            b"Jefe",
+            &[b"what do ya ", b"want for nothing?"]
+        )),
+        "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+    );
+    // RFC 4231 test case 6，key长度超过块大小
+    assert_eq!(
+        hex(hmac_sha256(
+            &[0xaa; 131],
+            &[b"Test Using Larger Than Block-Size Key - Hash Key First"]
+        )),
+        "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+    );
+}
+
+#[test]
+fn test_ct_eq() {
+    assert!(ct_eq(b"", b""));
+    assert!(ct_eq(b"abc", b"abc"));
+    assert!(!ct_eq(b"abc", b"abd"));
+    assert!(!ct_eq(b"abc", b"ab"));
+    assert!(!ct_eq(b"", b"a"));
+}
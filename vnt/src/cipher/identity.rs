@@ -0,0 +1,111 @@
+use std::io;
+#[cfg(feature = "device_auth")]
+use std::sync::Arc;
+
+#[cfg(feature = "device_auth")]
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+
+/// ed25519签名长度
+pub const SIGNATURE_LEN: usize = 64;
+
+/// 设备身份，ed25519密钥对，私钥以pkcs8格式保存在本地文件
+#[derive(Clone)]
+pub struct DeviceIdentity {
+    #[cfg(feature = "device_auth")]
+    key_pair: Arc<Ed25519KeyPair>,
+    public_key: Vec<u8>,
+}
+
+impl DeviceIdentity {
+    /// 从文件加载密钥，文件不存在时生成新的密钥并保存
+    #[cfg(feature = "device_auth")]
+    pub fn load_or_generate(path: &str) -> io::Result<Self> {
+        let pkcs8 = match std::fs::read(path) {
+            Ok(pkcs8) => pkcs8,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let rng = ring::rand::SystemRandom::new();
+                let document = Ed25519KeyPair::generate_pkcs8(&rng).map_err(|e| {
+                    io::Error::new(io::ErrorKind::Other, format!("generate device key {:?}", e))
+                })?;
+                save_key(path, document.as_ref())?;
+                log::info!("生成设备密钥 {}", path);
+                document.as_ref().to_vec()
+            }
+            Err(e) => return Err(e),
+        };
+        let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&pkcs8).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("device key {} {:?}", path, e),
+            )
+        })?;
+        let public_key = key_pair.public_key().as_ref().to_vec();
+        Ok(Self {
+            key_pair: Arc::new(key_pair),
+            public_key,
+        })
+    }
+    #[cfg(not(feature = "device_auth"))]
+    pub fn load_or_generate(_path: &str) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "device key requires feature device_auth",
+        ))
+    }
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+    #[cfg(feature = "device_auth")]
+    pub fn sign(&self, msg: &[u8]) -> [u8; SIGNATURE_LEN] {
+        let mut signature = [0u8; SIGNATURE_LEN];
+        signature.copy_from_slice(self.key_pair.sign(msg).as_ref());
+        signature
+    }
+    #[cfg(not(feature = "device_auth"))]
+    pub fn sign(&self, _msg: &[u8]) -> [u8; SIGNATURE_LEN] {
+        [0u8; SIGNATURE_LEN]
+    }
+}
+
+/// 使用对端公钥校验签名
+pub fn verify(public_key: &[u8], msg: &[u8], signature: &[u8]) -> bool {
+    #[cfg(feature = "device_auth")]
+    {
+        UnparsedPublicKey::new(&ED25519, public_key)
+            .verify(msg, signature)
+            .is_ok()
+    }
+    #[cfg(not(feature = "device_auth"))]
+    {
+        let _ = (public_key, msg, signature);
+        false
+    }
+}
+
+#[cfg(feature = "device_auth")]
+fn save_key(path: &str, key: &[u8]) -> io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        // 私钥只允许当前用户读写
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    io::Write::write_all(&mut file, key)
+}
+
+#[cfg(feature = "device_auth")]
+#[test]
+fn test_device_identity() {
+    let path = std::env::temp_dir().join(format!("vnt-key-test-{}", std::process::id()));
+    let path = path.to_str().unwrap();
+    let identity = DeviceIdentity::load_or_generate(path).unwrap();
+    let loaded = DeviceIdentity::load_or_generate(path).unwrap();
+    let _ = std::fs::remove_file(path);
+    assert_eq!(identity.public_key(), loaded.public_key());
+    let signature = identity.sign(b"vnt");
+    assert!(verify(loaded.public_key(), b"vnt", &signature));
+    assert!(!verify(loaded.public_key(), b"vnt2", &signature));
+}
@@ -0,0 +1,189 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use sha2::Digest;
+
+/// 密码到密钥的派生方式。
+/// 派生方式和参数会混入注册时上报的密码摘要，不一致的设备在列表中显示为不匹配，
+/// 非v1时摘要后追加一个字节的版本号，新版本客户端据此给出明确的错误提示
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum KeyDerivation {
+    // sha256(password)，兼容旧版本
+    #[default]
+    V1,
+    // memory的单位为KiB
+    Argon2id {
+        memory: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+}
+
+impl KeyDerivation {
+    pub const ARGON2ID_MEMORY: u32 = 19 * 1024;
+    pub const ARGON2ID_ITERATIONS: u32 = 2;
+    pub const ARGON2ID_PARALLELISM: u32 = 1;
+    pub fn version(&self) -> u8 {
+        match self {
+            KeyDerivation::V1 => 1,
+            KeyDerivation::Argon2id { .. } => 2,
+        }
+    }
+    /// 派生32字节的密钥，salt在同一网络内必须相同
+    pub fn derive(&self, password: &str, salt: &str) -> [u8; 32] {
+        match self {
+            KeyDerivation::V1 => {
+                let mut hasher = sha2::Sha256::new();
+                hasher.update(password.as_bytes());
+                hasher.finalize().into()
+            }
+            KeyDerivation::Argon2id {
+                memory,
+                iterations,
+                parallelism,
+            } => {
+                // argon2要求salt至少8字节，使用摘要固定长度
+                let mut hasher = sha2::Sha256::new();
+                hasher.update(b"vnt-kdf-salt:");
+                hasher.update(salt.as_bytes());
+                let salt: [u8; 32] = hasher.finalize().into();
+                // 参数在解析时已经校验
+                let params = Params::new(*memory, *iterations, *parallelism, Some(32)).unwrap();
+                let mut key = [0u8; 32];
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(password.as_bytes(), &salt[..16], &mut key)
+                    .unwrap();
+                key
+            }
+        }
+    }
+    /// 注册时上报的密码摘要，v1保持旧的格式
+    pub fn secret_hash(&self, cipher_model: &str, password: &str, token: &str) -> Vec<u8> {
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(cipher_model.as_bytes());
+        hasher.update(password.as_bytes());
+        hasher.update(token.as_bytes());
+        if *self != KeyDerivation::V1 {
+            hasher.update(self.to_string().as_bytes());
+        }
+        let key: [u8; 32] = hasher.finalize().into();
+        let mut hash = key[16..].to_vec();
+        if *self != KeyDerivation::V1 {
+            hash.push(self.version());
+        }
+        hash
+    }
+    /// 从对端上报的密码摘要中取出派生方式的版本，空摘要返回None
+    pub fn hash_version(hash: &[u8]) -> Option<u8> {
+        match hash.len() {
+            0 => None,
+            16 => Some(1),
+            _ => hash.last().copied(),
+        }
+    }
+}
+
+impl Display for KeyDerivation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyDerivation::V1 => f.write_str("v1"),
+            KeyDerivation::Argon2id {
+                memory,
+                iterations,
+                parallelism,
+            } => write!(
+                f,
+                "argon2id:m={},t={},p={}",
+                memory, iterations, parallelism
+            ),
+        }
+    }
+}
+
+impl FromStr for KeyDerivation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        let (name, args) = match s.split_once(':') {
+            Some((name, args)) => (name, args),
+            None => (s.as_str(), ""),
+        };
+        match name {
+            "v1" => {
+                if !args.is_empty() {
+                    return Err(format!("v1 has no parameters '{}'", s));
+                }
+                Ok(KeyDerivation::V1)
+            }
+            "v2" | "argon2id" => {
+                let mut memory = Self::ARGON2ID_MEMORY;
+                let mut iterations = Self::ARGON2ID_ITERATIONS;
+                let mut parallelism = Self::ARGON2ID_PARALLELISM;
+                for kv in args.split(',').filter(|v| !v.is_empty()) {
+                    let (k, v) = match kv.split_once('=') {
+                        Some(kv) => kv,
+                        None => return Err(format!("invalid parameter '{}'", kv)),
+                    };
+                    let v = v
+                        .trim()
+                        .parse::<u32>()
+                        .map_err(|e| format!("invalid parameter '{}' {}", kv, e))?;
+                    match k.trim() {
+                        "m" => memory = v,
+                        "t" => iterations = v,
+                        "p" => parallelism = v,
+                        _ => return Err(format!("invalid parameter '{}'", kv)),
+                    }
+                }
+                if let Err(e) = Params::new(memory, iterations, parallelism, Some(32)) {
+                    return Err(format!("argon2id parameters '{}' {}", s, e));
+                }
+                Ok(KeyDerivation::Argon2id {
+                    memory,
+                    iterations,
+                    parallelism,
+                })
+            }
+            _ => Err(format!(
+                "not match '{}', enum:v1/argon2id[:m=<KiB>,t=<iterations>,p=<parallelism>]",
+                s
+            )),
+        }
+    }
+}
+
+#[test]
+fn test_key_derivation() {
+    let kdf = KeyDerivation::from_str("argon2id:m=64,t=1").unwrap();
+    assert_eq!(
+        kdf,
+        KeyDerivation::Argon2id {
+            memory: 64,
+            iterations: 1,
+            parallelism: 1
+        }
+    );
+    assert_eq!(KeyDerivation::from_str(&kdf.to_string()).unwrap(), kdf);
+    assert!(KeyDerivation::from_str("argon2id:m=1").is_err());
+    assert!(KeyDerivation::from_str("scrypt").is_err());
+    assert_eq!(
+        kdf.derive("password", "token"),
+        kdf.derive("password", "token")
+    );
+    assert_ne!(
+        kdf.derive("password", "token"),
+        kdf.derive("password", "other")
+    );
+    assert_ne!(
+        kdf.derive("password", "token"),
+        KeyDerivation::V1.derive("password", "token")
+    );
+    let v1 = KeyDerivation::V1.secret_hash("aes_gcm", "password", "token");
+    assert_eq!(v1.len(), 16);
+    assert_eq!(KeyDerivation::hash_version(&v1), Some(1));
+    let v2 = kdf.secret_hash("aes_gcm", "password", "token");
+    assert_eq!(KeyDerivation::hash_version(&v2), Some(2));
+    assert_eq!(KeyDerivation::hash_version(&[]), None);
+}
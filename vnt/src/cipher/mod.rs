@@ -15,9 +15,14 @@ mod cipher;
     feature = "sm4_cbc"
 ))]
 mod finger;
+mod header_auth;
+pub mod identity;
+mod kdf;
 #[cfg(feature = "aes_ecb")]
 #[cfg(any(feature = "openssl-vendored", feature = "openssl"))]
 mod openssl_aes_ecb;
+mod pairwise;
+mod replay;
 #[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
 #[cfg(feature = "ring-cipher")]
 mod ring_aes_gcm_cipher;
@@ -25,6 +30,7 @@ mod ring_aes_gcm_cipher;
 #[cfg(feature = "sm4_cbc")]
 mod sm4_cbc;
 pub use cipher::Cipher;
+pub use cipher::CipherBench;
 pub use cipher::CipherModel;
 #[cfg(any(
     feature = "aes_gcm",
@@ -34,6 +40,12 @@ pub use cipher::CipherModel;
     feature = "sm4_cbc"
 ))]
 pub use finger::Finger;
+pub use header_auth::ct_eq;
+pub(crate) use header_auth::hmac_sha256;
+pub use header_auth::HeaderAuth;
+pub use kdf::KeyDerivation;
+pub use pairwise::PairwiseCipher;
+pub use replay::{ReplayGuard, SEQ_LEN};
 #[cfg(feature = "server_encrypt")]
 mod rsa_cipher;
 #[cfg(feature = "server_encrypt")]
@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+use crate::cipher::{hmac_sha256, Cipher, CipherModel, KeyDerivation};
+
+/// 点对点预共享密钥，和指定对端之间的ip数据使用 HMAC(密码派生的密钥, psk) 作为密钥加密，
+/// 即使同一网络内的其他客户端知道密码也无法解密这部分数据。通信双方都需要配置相同的psk
+#[derive(Clone, Default)]
+pub struct PairwiseCipher {
+    map: Arc<HashMap<Ipv4Addr, Cipher>>,
+}
+
+impl PairwiseCipher {
+    pub fn new(
+        model: CipherModel,
+        kdf: KeyDerivation,
+        salt: &str,
+        password: Option<String>,
+        token: Option<String>,
+        psk: &[(Ipv4Addr, String)],
+    ) -> Self {
+        let password = password.unwrap_or_default();
+        let mut map = HashMap::with_capacity(psk.len());
+        if psk.is_empty() {
+            return Self { map: Arc::new(map) };
+        }
+        let group_key = kdf.derive(&password, salt);
+        let key_len = model.key_len(&password);
+        for (ip, key) in psk {
+            let cipher =
+                Cipher::new_derived(model, psk_key(&group_key, key), key_len, token.clone());
+            map.insert(*ip, cipher);
+        }
+        Self { map: Arc::new(map) }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+    /// 获取和对端通信使用的加密器，没有配置psk则使用默认的
+    pub fn get<'a>(&'a self, ip: &Ipv4Addr, default: &'a Cipher) -> &'a Cipher {
+        self.map.get(ip).unwrap_or(default)
+    }
+}
+
+/// 组密钥作为HMAC的密钥，psk作为消息，密码和psk的边界不会混淆
+fn psk_key(group_key: &[u8; 32], psk: &str) -> [u8; 32] {
+    hmac_sha256(group_key, &[b"vnt-psk", psk.as_bytes()])
+}
+
+#[test]
+fn test_psk_key() {
+    let kdf = KeyDerivation::V1;
+    let key = |password: &str, psk: &str| psk_key(&kdf.derive(password, "token"), psk);
+    // 旧的拼接方式下这两组得到相同的密钥
+    assert_ne!(key("a:psk:b", "c"), key("a", "b:psk:c"));
+    assert_ne!(key("a", "b"), key("a", "c"));
+    assert_ne!(key("a", "b"), key("b", "b"));
+    assert_eq!(key("a", "b"), key("a", "b"));
+}
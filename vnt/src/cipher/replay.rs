@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+
+use crate::protocol::NetPacket;
+
+/// 序号长度
+pub const SEQ_LEN: usize = 8;
+/// 滑动窗口大小，多通道和多线程发送时包可能乱序，窗口不能太小
+const WINDOW_SIZE: u64 = 1024;
+const WINDOW_WORDS: usize = (WINDOW_SIZE / 64) as usize;
+
+/// 防重放，在加密前的数据末尾追加递增序号，解密后按来源ip校验序号，
+/// 重复的和落后于窗口的包都将被丢弃。通信双方都需要开启
+#[derive(Clone)]
+pub struct ReplayGuard {
+    inner: Arc<ReplayGuardInner>,
+}
+
+struct ReplayGuardInner {
+    seq: AtomicU64,
+    windows: Mutex<HashMap<Ipv4Addr, ReplayWindow>>,
+    drop_count: AtomicU64,
+}
+
+impl ReplayGuard {
+    pub fn new() -> Self {
+        // 以微秒时间戳作为起始序号，重启后序号依然大于之前发出的
+        let start = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|v| v.as_micros() as u64)
+            .unwrap_or(0);
+        Self {
+            inner: Arc::new(ReplayGuardInner {
+                seq: AtomicU64::new(start),
+                windows: Mutex::new(HashMap::with_capacity(16)),
+                drop_count: AtomicU64::new(0),
+            }),
+        }
+    }
+    /// 追加序号，需要在加密前调用
+    pub fn seal<B: AsRef<[u8]> + AsMut<[u8]>>(
+        &self,
+        net_packet: &mut NetPacket<B>,
+    ) -> io::Result<()> {
+        let seq = self.inner.seq.fetch_add(1, Ordering::Relaxed);
+        let data_len = net_packet.data_len();
+        net_packet.set_data_len(data_len + SEQ_LEN)?;
+        let payload = net_packet.payload_mut();
+        let len = payload.len();
+        payload[len - SEQ_LEN..].copy_from_slice(&seq.to_be_bytes());
+        Ok(())
+    }
+    /// 校验并去除序号，需要在解密后调用，返回false表示是重放的包
+    pub fn open<B: AsRef<[u8]> + AsMut<[u8]>>(
+        &self,
+        net_packet: &mut NetPacket<B>,
+    ) -> io::Result<bool> {
+        let payload = net_packet.payload();
+        if payload.len() < SEQ_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not seq"));
+        }
+        let seq = u64::from_be_bytes(payload[payload.len() - SEQ_LEN..].try_into().unwrap());
+        let source = net_packet.source();
+        net_packet.set_data_len(net_packet.data_len() - SEQ_LEN)?;
+        let accept = self
+            .inner
+            .windows
+            .lock()
+            .entry(source)
+            .or_insert_with(ReplayWindow::new)
+            .check(seq);
+        if !accept {
+            self.inner.drop_count.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(accept)
+    }
+    /// 丢弃的重放包数量
+    pub fn drop_count(&self) -> u64 {
+        self.inner.drop_count.load(Ordering::Relaxed)
+    }
+    /// 清除对端的窗口，下一个包重新作为起点
+    pub fn remove(&self, ip: &Ipv4Addr) {
+        self.inner.windows.lock().remove(ip);
+    }
+}
+
+struct ReplayWindow {
+    max: u64,
+    bitmap: [u64; WINDOW_WORDS],
+    init: bool,
+}
+
+impl ReplayWindow {
+    fn new() -> Self {
+        Self {
+            max: 0,
+            bitmap: [0; WINDOW_WORDS],
+            init: false,
+        }
+    }
+    fn bit(&mut self, seq: u64) -> (usize, u64) {
+        let index = (seq % WINDOW_SIZE) as usize;
+        (index / 64, 1 << (index % 64))
+    }
+    fn check(&mut self, seq: u64) -> bool {
+        if !self.init {
+            self.init = true;
+            self.max = seq;
+            let (word, mask) = self.bit(seq);
+            self.bitmap[word] |= mask;
+            return true;
+        }
+        if seq > self.max {
+            // 窗口前移，清除移出的位
+            let diff = seq - self.max;
+            if diff >= WINDOW_SIZE {
+                self.bitmap = [0; WINDOW_WORDS];
+            } else {
+                for s in self.max + 1..=seq {
+                    let (word, mask) = self.bit(s);
+                    self.bitmap[word] &= !mask;
+                }
+            }
+            self.max = seq;
+            let (word, mask) = self.bit(seq);
+            self.bitmap[word] |= mask;
+            return true;
+        }
+        if self.max - seq >= WINDOW_SIZE {
+            // 过期的包
+            return false;
+        }
+        let (word, mask) = self.bit(seq);
+        if self.bitmap[word] & mask != 0 {
+            // 重复的包
+            return false;
+        }
+        self.bitmap[word] |= mask;
+        true
+    }
+}
+
+#[test]
+fn test_replay_window() {
+    let mut window = ReplayWindow::new();
+    assert!(window.check(100));
+    assert!(!window.check(100));
+    assert!(window.check(99));
+    assert!(window.check(102));
+    assert!(window.check(101));
+    assert!(!window.check(101));
+    assert!(window.check(100 + WINDOW_SIZE + 10));
+    assert!(!window.check(100));
+    assert!(!window.check(100 + WINDOW_SIZE + 10));
+}
+
+#[test]
+fn test_replay_guard_remove() {
+    let ip = Ipv4Addr::new(10, 26, 0, 2);
+    let packet = |guard: &ReplayGuard| {
+        let mut net_packet = NetPacket::new(vec![0u8; 12 + 4 + SEQ_LEN]).unwrap();
+        net_packet.set_data_len(12 + 4).unwrap();
+        net_packet.set_source(ip);
+        guard.seal(&mut net_packet).unwrap();
+        net_packet
+    };
+    let receiver = ReplayGuard::new();
+    let old = ReplayGuard::new();
+    assert!(receiver.open(&mut packet(&old)).unwrap());
+    // 同一个ip换成了序号更小的设备
+    let new = ReplayGuard::new();
+    new.inner.seq.store(1000, Ordering::Relaxed);
+    assert!(!receiver.open(&mut packet(&new)).unwrap());
+    receiver.remove(&ip);
+    assert!(receiver.open(&mut packet(&new)).unwrap());
+    assert!(receiver.open(&mut packet(&new)).unwrap());
+    assert_eq!(receiver.drop_count(), 1);
+}
@@ -1,34 +1,62 @@
 use std::collections::HashMap;
 use std::io;
 use std::net::Ipv4Addr;
+use std::sync::mpsc::Receiver;
 use std::sync::Arc;
 use std::time::Duration;
 
 use crossbeam_utils::atomic::AtomicCell;
 use parking_lot::{Mutex, RwLock};
 use rand::Rng;
-use rsa::signature::digest::Digest;
 #[cfg(not(target_os = "android"))]
 use tun::device::IFace;
 
+use crate::channel::acl::Acl;
+use crate::channel::broadcast_scope::BroadcastScope;
+use crate::channel::coalesce::Coalesce;
 use crate::channel::context::ChannelContext;
+use crate::channel::event::{RouteEvent, StateEvent};
+use crate::channel::hold_punch::HoldPunch;
+use crate::channel::hysteresis::RouteHysteresis;
 use crate::channel::idle::Idle;
+use crate::channel::idle::PowerSave;
+use crate::channel::ip_conflict::IpConflict;
+use crate::channel::matrix::PeerRoute;
+use crate::channel::netem::{WanRule, WanSchedule, WanSimStatus};
+use crate::channel::padding::Padding;
+use crate::channel::peer_auth::PeerAuth;
+use crate::channel::peer_filter::{PeerFilter, PeerMatch};
 use crate::channel::punch::{NatInfo, Punch};
+use crate::channel::punch_log::PunchAttempt;
+use crate::channel::qos::Qos;
+use crate::channel::relay_meter::{RelayMeter, RelayStats};
+use crate::channel::server_config::{ConfigField, EffectiveConfig, ServerConfig};
+use crate::channel::subnet::SubnetRoutes;
+use crate::channel::vlan::VlanTable;
 use crate::channel::{init_channel, init_context, Route, RouteKey};
-use crate::cipher::Cipher;
+use crate::cipher::identity::DeviceIdentity;
 #[cfg(feature = "server_encrypt")]
 use crate::cipher::RsaCipher;
-use crate::core::Config;
+use crate::cipher::{Cipher, PairwiseCipher};
+use crate::core::{Config, StatsHandle};
+use crate::error::VntError;
 use crate::external_route::{AllowExternalRoute, ExternalRoute};
-use crate::handle::handshaker::Handshake;
+use crate::handle::crypto_pool::CryptoPool;
+use crate::handle::diag::{Diag, PeerDiag};
+use crate::handle::handshaker::{Handshake, Knock};
 use crate::handle::maintain::PunchReceiver;
+use crate::handle::presence::Presence;
 use crate::handle::recv_data::RecvDataHandler;
+use crate::handle::route_report;
+use crate::handle::server_hint::ServerHints;
+use crate::handle::server_ports::ServerPorts;
+use crate::handle::service::{ServiceHandler, ServiceProtocol, ServiceRegistry};
+use crate::handle::speed_test::{SpeedTest, SpeedTestResult};
 use crate::handle::{maintain, BaseConfigInfo, ConnectStatus, CurrentDeviceInfo, PeerDeviceInfo};
 use crate::nat::NatTest;
+use crate::protocol::capability::Capabilities;
 use crate::tun_tap_device::tun_create_helper::{DeviceAdapter, TunDeviceHelper};
-use crate::util::{
-    Scheduler, SingleU64Adder, StopManager, U64Adder, WatchSingleU64Adder, WatchU64Adder,
-};
+use crate::util::{Scheduler, StopManager, U64Adder, WatchU64Adder};
 use crate::{nat, VntCallback};
 #[cfg(not(target_os = "android"))]
 use crate::{tun_tap_device, DeviceInfo};
@@ -43,13 +71,36 @@ pub struct Vnt {
     context: ChannelContext,
     peer_nat_info_map: Arc<RwLock<HashMap<Ipv4Addr, NatInfo>>>,
     down_count_watcher: WatchU64Adder,
-    up_count_watcher: WatchSingleU64Adder,
-    client_secret_hash: Option<[u8; 16]>,
+    up_count_watcher: WatchU64Adder,
+    client_secret_hash: Option<Vec<u8>>,
+    client_cipher: Cipher,
+    server_cipher: Cipher,
+    diag: Diag,
+    speed_test: SpeedTest,
+    services: ServiceRegistry,
+    device_adapter: DeviceAdapter,
+    net_change: maintain::NetChange,
+    #[cfg(feature = "ip_proxy")]
+    proxy_map: Option<crate::ip_proxy::IpProxyMap>,
 }
 
 impl Vnt {
-    pub fn new<Call: VntCallback>(config: Config, callback: Call) -> io::Result<Self> {
+    pub fn new<Call: VntCallback>(mut config: Config, callback: Call) -> Result<Self, VntError> {
         log::info!("config:{:?}", config);
+        //auto模式先测速，选择当前CPU上最快的AEAD
+        #[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
+        if config.cipher_model == crate::cipher::CipherModel::Auto {
+            let bench = Cipher::benchmark();
+            let model = match crate::cipher::CipherBench::fastest_aead(&bench) {
+                Some(model) => model,
+                None => {
+                    return Err(VntError::Cipher("no aead cipher".into()));
+                }
+            };
+            log::info!("auto cipher:{} {:?}", model, bench);
+            config.cipher_model = model;
+            callback.cipher_select(crate::handle::callback::CipherSelectInfo::new(model, bench));
+        }
         //服务端非对称加密
         #[cfg(feature = "server_encrypt")]
         let rsa_cipher: Arc<Mutex<Option<RsaCipher>>> = Arc::new(Mutex::new(None));
@@ -57,7 +108,8 @@ impl Vnt {
         let server_cipher: Cipher = if config.server_encrypt {
             let mut key = [0u8; 32];
             rand::thread_rng().fill(&mut key);
-            Cipher::new_key(key, config.token.clone())?
+            Cipher::new_key(key, config.token.clone())
+                .map_err(|e| VntError::Cipher(format!("server cipher {}", e)))?
         } else {
             Cipher::None
         };
@@ -66,9 +118,27 @@ impl Vnt {
         } else {
             None
         };
-        //客户端对称加密
-        let client_cipher =
-            Cipher::new_password(config.cipher_model, config.password.clone(), finger);
+        //客户端对称加密，不加密时可以只做消息认证
+        let client_cipher = if config.password.is_none() && config.header_auth {
+            Cipher::new_auth(&config.token)
+        } else {
+            Cipher::new_password(
+                config.cipher_model,
+                config.kdf,
+                &config.token,
+                config.password.clone(),
+                finger.clone(),
+            )
+        };
+        //和指定对端通信时混入psk
+        let pairwise_cipher = PairwiseCipher::new(
+            config.cipher_model,
+            config.kdf,
+            &config.token,
+            config.password.clone(),
+            finger,
+            &config.psk,
+        );
         //当前设备信息
         let current_device = Arc::new(AtomicCell::new(CurrentDeviceInfo::new0(
             config.server_address,
@@ -77,22 +147,32 @@ impl Vnt {
         let device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>> =
             Arc::new(Mutex::new((0, Vec::with_capacity(16))));
         //基础信息
+        let client_secret_hash = config.password.as_ref().map(|v| {
+            config
+                .kdf
+                .secret_hash(&config.cipher_model.to_string(), v, &config.token)
+        });
+        //服务端地址通告的签名密钥，和加密使用同样的派生方式，不会发给服务端
+        let hint_key = config
+            .password
+            .as_ref()
+            .map(|v| config.kdf.derive(v, &config.token));
+        let server_hints = ServerHints::new(hint_key.as_ref().map(|v| &v[..]));
         let config_info = BaseConfigInfo::new(
             config.name.clone(),
             config.token.clone(),
             config.ip,
-            config.password.as_ref().map(|v| {
-                let mut hasher = sha2::Sha256::new();
-                hasher.update(config.cipher_model.to_string().as_bytes());
-                hasher.update(v.as_bytes());
-                hasher.update(config.token.as_bytes());
-                let key: [u8; 32] = hasher.finalize().into();
-                key[16..].try_into().unwrap()
-            }),
+            client_secret_hash,
             config.server_encrypt,
             config.device_id.clone(),
             config.server_address_str.clone(),
             config.name_servers.clone(),
+            config.prefer_ipv6_server,
+            config.server_proxy.clone(),
+            ServerPorts::new(config.server_ports.clone()),
+            server_hints,
+            Presence::new(device_list.clone()),
+            config.dhcp_pool,
         );
         let ports = config.ports.as_ref().map_or(vec![0, 0], |v| {
             if v.is_empty() {
@@ -101,6 +181,10 @@ impl Vnt {
                 v.clone()
             }
         });
+        let identity = match &config.device_key {
+            Some(path) => Some(DeviceIdentity::load_or_generate(path)?),
+            None => None,
+        };
         //通道上下文
         let (context, tcp_listener) = init_context(
             ports,
@@ -109,7 +193,49 @@ impl Vnt {
             config.tcp,
             config.packet_loss_rate,
             config.packet_delay,
+            config.load_balance,
+            config.anti_replay,
+            PowerSave::new(config.power_save, config.power_save_multiple),
+            pairwise_cipher,
+            Coalesce::new(config.coalesce),
+            RouteHysteresis::new(config.route_hysteresis, config.route_hold_down),
+            PeerAuth::new(identity),
+            config.listen,
+            Qos::new(
+                config.dscp,
+                config.so_priority,
+                config.dscp_copy,
+                config.control_dscp,
+            ),
+            HoldPunch::new(config.hold_punch),
+            config.wan_sim.clone(),
+            RelayMeter::new(config.relay_budget),
+            PeerFilter::new(
+                config.allow_peer.clone(),
+                config.deny_peer.clone(),
+                config.accept_server_config.contains(&ConfigField::Acl),
+                Acl::new(&config.acl),
+            ),
+            SubnetRoutes::new(config.accept_subnets),
+            Padding::new(config.padding, config.tun_mtu(), config.cover_traffic),
+            ServerConfig::new(
+                config.accept_server_config.clone(),
+                config.mtu.is_some(),
+                config.tun_mtu(),
+            ),
+            #[cfg(any(target_os = "windows", target_os = "linux"))]
+            VlanTable::new(config.vlan.clone()),
+            #[cfg(not(any(target_os = "windows", target_os = "linux")))]
+            VlanTable::new(Vec::new()),
+            IpConflict::new(config.ip_conflict_reassign),
+            config.send_queue,
+            BroadcastScope::new(config.broadcast_mask)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+            config.socket_buf,
         )?;
+        if let Some(wan_schedule) = config.wan_schedule.clone() {
+            context.set_wan_schedule(wan_schedule);
+        }
         let local_ipv4 = nat::local_ipv4();
         let local_ipv6 = nat::local_ipv6();
         let udp_ports = context.main_local_udp_port()?;
@@ -127,9 +253,19 @@ impl Vnt {
         // pc上先创建虚拟网卡
         #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
         let device = {
-            let device = tun_tap_device::create_device(&config)?;
-            let tun_info = DeviceInfo::new(device.name()?, device.version()?);
+            let device = tun_tap_device::create_device(&config).map_err(VntError::TunCreate)?;
+            let tun_info = DeviceInfo::new(
+                device.name().map_err(VntError::TunCreate)?,
+                device.version().map_err(VntError::TunCreate)?,
+            );
             callback.create_tun(tun_info);
+            // 网卡已经创建，在启动其他线程前放弃root权限
+            #[cfg(target_os = "linux")]
+            if let Some(run_as) = &config.run_as {
+                crate::util::RunAs::lookup(run_as)
+                    .and_then(|v| v.drop_privileges())
+                    .map_err(VntError::Io)?;
+            }
             device
         };
         // 服务停止管理器
@@ -140,16 +276,34 @@ impl Vnt {
         // 定时器
         let scheduler = Scheduler::new(stop_manager.clone())?;
         let external_route = ExternalRoute::new(config.in_ips.clone());
-        let out_external_route = AllowExternalRoute::new(config.out_ips.clone());
+        #[allow(unused_mut)]
+        let mut out_ips = config.out_ips.clone();
+        // ipv6映射的网段也需要允许转发
+        #[cfg(feature = "ip_proxy")]
+        out_ips.extend(config.out_ips6.iter().map(|(dest, mask, _)| (*dest, *mask)));
+        // 网关模式允许转发到任意地址
+        #[cfg(feature = "ip_proxy")]
+        let gateway = config.gateway.is_some();
+        #[cfg(not(feature = "ip_proxy"))]
+        let gateway = false;
+        if gateway {
+            out_ips.push((0, 0));
+        }
+        let out_external_route = AllowExternalRoute::new(out_ips.clone());
 
         #[cfg(feature = "ip_proxy")]
-        let proxy_map = if !config.out_ips.is_empty() && !config.no_proxy {
+        let proxy_map = if !out_ips.is_empty() && !config.no_proxy {
             Some(crate::ip_proxy::init_proxy(
                 context.clone(),
                 scheduler.clone(),
                 stop_manager.clone(),
                 current_device.clone(),
                 client_cipher.clone(),
+                config.tcp_proxy_keepalive,
+                config.tcp_proxy_idle,
+                config.tcp_proxy_max_conn,
+                config.out_ips6.clone(),
+                config.gateway,
             )?)
         } else {
             None
@@ -157,11 +311,20 @@ impl Vnt {
         let (punch_sender, punch_receiver) = maintain::punch_channel();
         let peer_nat_info_map: Arc<RwLock<HashMap<Ipv4Addr, NatInfo>>> =
             Arc::new(RwLock::new(HashMap::with_capacity(16)));
-        let down_counter =
-            U64Adder::with_capacity(config.ports.as_ref().map(|v| v.len()).unwrap_or_default() + 8);
+        // 开启并行加密时每个处理线程也占用一个计数槽
+        let down_counter = U64Adder::with_capacity(
+            config.ports.as_ref().map(|v| v.len()).unwrap_or_default() + 8 + config.parallel_crypto,
+        );
         let down_count_watcher = down_counter.watch();
-        let handshake = Handshake::new(rsa_cipher.clone());
-        let up_counter = SingleU64Adder::new();
+        let handshake = Handshake::new(
+            rsa_cipher.clone(),
+            config
+                .knock_key
+                .clone()
+                .map(|key| Knock::new(key, config.token.clone(), config.knock_port)),
+        );
+        // 多队列网卡时每个队列一个读线程
+        let up_counter = U64Adder::with_capacity(config.parallel + 1);
         let up_count_watcher = up_counter.watch();
         let tun_helper = TunDeviceHelper::new(
             stop_manager.clone(),
@@ -173,6 +336,7 @@ impl Vnt {
             client_cipher.clone(),
             server_cipher.clone(),
             config.parallel,
+            config.parallel_crypto,
             up_counter,
             device_list.clone(),
         );
@@ -181,13 +345,25 @@ impl Vnt {
         #[cfg(target_os = "android")]
         let device_adapter = DeviceAdapter::new(tun_helper);
 
+        let diag = Diag::new(
+            config.allow_diag,
+            if config.password.is_some() {
+                config.cipher_model.to_string()
+            } else if config.header_auth {
+                "auth".to_string()
+            } else {
+                "none".to_string()
+            },
+        );
+        let speed_test = SpeedTest::new(config.allow_speed_test);
+        let services = ServiceRegistry::default();
         let handler = RecvDataHandler::new(
             #[cfg(feature = "server_encrypt")]
             rsa_cipher,
             server_cipher.clone(),
             client_cipher.clone(),
             current_device.clone(),
-            device_adapter,
+            device_adapter.clone(),
             device_list.clone(),
             config_info.clone(),
             nat_test.clone(),
@@ -200,11 +376,20 @@ impl Vnt {
             proxy_map.clone(),
             down_counter,
             handshake.clone(),
+            diag.clone(),
+            speed_test.clone(),
+            services.clone(),
         );
+        let handler = CryptoPool::new(handler, config.parallel_crypto, &context)?;
 
         //初始化网络数据通道
-        let (udp_socket_sender, tcp_socket_sender) =
-            init_channel(tcp_listener, context.clone(), stop_manager.clone(), handler)?;
+        let (udp_socket_sender, tcp_socket_sender) = init_channel(
+            tcp_listener,
+            context.clone(),
+            stop_manager.clone(),
+            handler,
+            config.xdp.clone(),
+        )?;
         // 打洞逻辑
         let punch = Punch::new(
             context.clone(),
@@ -216,7 +401,7 @@ impl Vnt {
         );
 
         #[cfg(not(target_os = "android"))]
-        tun_helper.start(device)?;
+        tun_helper.start(device).map_err(VntError::TunCreate)?;
 
         maintain::idle_gateway(
             &scheduler,
@@ -226,8 +411,77 @@ impl Vnt {
             tcp_socket_sender.clone(),
             callback.clone(),
             0,
+            handshake.clone(),
+        );
+        // 本地地址变化时迁移连接
+        let net_change = maintain::NetChange::default();
+        let net_watched = maintain::migrate(
+            &scheduler,
+            context.clone(),
+            nat_test.clone(),
+            current_device.clone(),
+            device_list.clone(),
+            client_cipher.clone(),
+            config_info.clone(),
             handshake,
+            udp_socket_sender.clone(),
+            &net_change,
+            &stop_manager,
         );
+        // 路径mtu探测
+        maintain::path_mtu(
+            &scheduler,
+            context.clone(),
+            current_device.clone(),
+            client_cipher.clone(),
+            config.tun_mtu() as u16,
+        );
+        if !config.use_channel_type.is_only_relay() {
+            // 反向隧道
+            maintain::reverse_tunnel(
+                &scheduler,
+                context.clone(),
+                current_device.clone(),
+                device_list.clone(),
+                peer_nat_info_map.clone(),
+                client_cipher.clone(),
+                punch.clone(),
+                config.reverse_tunnel,
+            );
+        }
+        // 网段通告
+        maintain::subnet_advert(
+            &scheduler,
+            context.clone(),
+            current_device.clone(),
+            device_list.clone(),
+            client_cipher.clone(),
+            device_adapter.clone(),
+            if config.advertise_subnets {
+                out_ips.clone()
+            } else {
+                Vec::new()
+            },
+            gateway,
+        );
+        // vlan通告
+        maintain::vlan_advert(
+            &scheduler,
+            context.clone(),
+            current_device.clone(),
+            device_list.clone(),
+            client_cipher.clone(),
+            device_adapter.clone(),
+        );
+        // 掩护流量
+        maintain::cover_traffic(
+            &scheduler,
+            context.clone(),
+            current_device.clone(),
+            client_cipher.clone(),
+        );
+        let vnt_client_cipher = client_cipher.clone();
+        let vnt_server_cipher = server_cipher.clone();
         {
             let context = context.clone();
             let nat_test = nat_test.clone();
@@ -243,8 +497,17 @@ impl Vnt {
                     context.clone(),
                     nat_test.clone(),
                     udp_socket_sender,
+                    net_watched,
                 );
             }
+            // 推送状态变化给订阅者
+            maintain::state_tick(
+                &scheduler,
+                context.clone(),
+                current_device.clone(),
+                down_count_watcher.clone(),
+                up_count_watcher.clone(),
+            );
             //延迟启动
             scheduler.timeout(Duration::from_secs(3), move |scheduler| {
                 start(
@@ -276,6 +539,15 @@ impl Vnt {
             down_count_watcher,
             up_count_watcher,
             client_secret_hash: config_info.client_secret_hash,
+            client_cipher: vnt_client_cipher,
+            server_cipher: vnt_server_cipher,
+            diag,
+            speed_test,
+            services,
+            device_adapter,
+            net_change,
+            #[cfg(feature = "ip_proxy")]
+            proxy_map,
         })
     }
 }
@@ -293,7 +565,7 @@ pub fn start<Call: VntCallback>(
     punch: Punch,
     callback: Call,
     down_count_watcher: WatchU64Adder,
-    up_count_watcher: WatchSingleU64Adder,
+    up_count_watcher: WatchU64Adder,
 ) {
     // 定时心跳
     maintain::heartbeat(
@@ -304,6 +576,39 @@ pub fn start<Call: VntCallback>(
         client_cipher.clone(),
         server_cipher.clone(),
     );
+    // p2p路由保活
+    maintain::hold_punch(
+        &scheduler,
+        context.clone(),
+        current_device.clone(),
+        client_cipher.clone(),
+    );
+    // ip冲突检测
+    maintain::ip_announce(
+        &scheduler,
+        context.clone(),
+        current_device.clone(),
+        device_list.clone(),
+        client_cipher.clone(),
+        callback.clone(),
+        0,
+        Ipv4Addr::UNSPECIFIED,
+    );
+    // 按丢包调整udp接收缓冲区
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if context.socket_buf.auto {
+        maintain::udp_buf_tune(scheduler, context.clone(), Vec::new());
+    }
+    // 服务端地址通告，没有配置密码时不启用
+    if config_info.server_hints.is_enable() {
+        maintain::server_hint(
+            &scheduler,
+            context.clone(),
+            current_device.clone(),
+            client_cipher.clone(),
+            config_info.server_hints.clone(),
+        );
+    }
     // 路由空闲检测逻辑
     let idle = Idle::new(Duration::from_secs(10), context.clone());
     // 定时空闲检查
@@ -312,7 +617,7 @@ pub fn start<Call: VntCallback>(
         idle,
         context.clone(),
         current_device.clone(),
-        callback,
+        callback.clone(),
     );
     // 定时客户端中继检测
     if !context.use_channel_type().is_only_p2p() {
@@ -343,6 +648,7 @@ pub fn start<Call: VntCallback>(
             client_cipher.clone(),
             punch_receiver,
             punch,
+            callback,
         );
     }
     maintain::up_status(
@@ -403,10 +709,353 @@ impl Vnt {
     pub fn down_stream(&self) -> u64 {
         self.down_count_watcher.get()
     }
+    /// 无锁的统计句柄，包括流量和各个队列的积压
+    pub fn stats_handle(&self) -> StatsHandle {
+        StatsHandle::new(
+            self.up_count_watcher.clone(),
+            self.down_count_watcher.clone(),
+            self.context.queue_depth.clone(),
+            self.context.traffic.clone(),
+        )
+    }
+    /// 中转流量统计
+    pub fn relay_stats(&self) -> RelayStats {
+        self.context.relay_meter.stats()
+    }
+    /// 订阅状态变化，订阅时先推送当前的连接状态和设备列表，
+    /// 之后推送设备列表更新、路由变化、每秒的流量统计和连接状态变化
+    pub fn subscribe(&self) -> Receiver<StateEvent> {
+        let (sender, receiver) = self.context.route_table.event_log.subscribers.subscribe();
+        let _ = sender.try_send(StateEvent::Status(self.connection_status()));
+        let (revision, peers) = self.device_list.lock().clone();
+        let _ = sender.try_send(StateEvent::PeerList { revision, peers });
+        receiver
+    }
+    /// 返回序号大于seq的路由事件
+    pub fn route_events(&self, seq: u64, limit: usize) -> Vec<RouteEvent> {
+        self.context.route_table.event_log.since(seq, limit)
+    }
+    /// 从其他网关学习到的和注入的网段，(网段，子网掩码，网关)
+    pub fn subnet_routes(&self) -> Vec<(Ipv4Addr, Ipv4Addr, Ipv4Addr)> {
+        self.context.subnet_routes.routes()
+    }
+    /// 运行时把网段(如192.168.1.0/24)的流量转发给指定的对端，并添加系统路由，
+    /// 同一网段再次添加时替换对端。优先于从其他网关学习到的网段，-i指定的网段仍然优先
+    pub fn add_external_route(&self, cidr: &str, via_peer: Ipv4Addr) -> io::Result<()> {
+        let (dest, mask) = parse_cidr(cidr)?;
+        let current_device = self.current_device.load();
+        if via_peer == current_device.virtual_ip {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "via_peer is self",
+            ));
+        }
+        if current_device.virtual_ip != Ipv4Addr::UNSPECIFIED {
+            let virtual_netmask = u32::from(current_device.virtual_netmask);
+            let virtual_network = u32::from(current_device.virtual_network);
+            if u32::from(via_peer) & virtual_netmask != virtual_network {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("via_peer {} not in virtual network", via_peer),
+                ));
+            }
+            // 不能覆盖虚拟网段
+            let overlap_mask = mask & virtual_netmask;
+            if dest & overlap_mask == virtual_network & overlap_mask {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} overlaps virtual network", cidr),
+                ));
+            }
+        }
+        if !self.context.peer_filter.is_allowed(&via_peer) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("via_peer {} is filtered", via_peer),
+            ));
+        }
+        let added = self
+            .context
+            .subnet_routes
+            .add_external(dest, mask, via_peer);
+        log::info!("注入网段 {} -> {}", cidr, via_peer);
+        maintain::sync_subnet_routes(&self.device_adapter, added, Vec::new());
+        Ok(())
+    }
+    /// 删除注入的网段，返回是否存在
+    pub fn remove_external_route(&self, cidr: &str) -> io::Result<bool> {
+        let (dest, mask) = parse_cidr(cidr)?;
+        let (exists, removed) = self.context.subnet_routes.remove_external(dest, mask);
+        if exists {
+            log::info!("删除注入的网段 {}", cidr);
+        }
+        maintain::sync_subnet_routes(&self.device_adapter, Vec::new(), removed);
+        Ok(exists)
+    }
+    /// 允许服务端下发的配置项
+    pub fn accept_server_config(&self) -> &[ConfigField] {
+        self.context.server_config.accept()
+    }
+    /// 当前生效的配置，包括本地配置、服务端下发的配置和被本地拒绝的配置项
+    pub fn effective_config(&self) -> Vec<EffectiveConfig> {
+        let pushed = self.context.server_config.pushed().unwrap_or_default();
+        let join = |list: &[String]| list.join(",");
+        let peers = |allow: &[PeerMatch], deny: &[PeerMatch]| {
+            let allow: Vec<String> = allow.iter().map(|v| v.to_string()).collect();
+            let deny: Vec<String> = deny.iter().map(|v| v.to_string()).collect();
+            format!("allow=[{}] deny=[{}]", allow.join(","), deny.join(","))
+        };
+        let mut list = vec![
+            EffectiveConfig::new(ConfigField::Stun, join(&self.config.stun_server), "local"),
+            EffectiveConfig::new(ConfigField::Stun, join(&pushed.stun_servers), "server"),
+        ];
+        match pushed.mtu {
+            Some(mtu) => list.push(EffectiveConfig::new(
+                ConfigField::Mtu,
+                mtu.to_string(),
+                "server",
+            )),
+            None => list.push(EffectiveConfig::new(
+                ConfigField::Mtu,
+                self.config.tun_mtu().to_string(),
+                "local",
+            )),
+        }
+        let hints: Vec<String> = pushed.relay_hints.iter().map(|v| v.to_string()).collect();
+        list.push(EffectiveConfig::new(
+            ConfigField::Relay,
+            join(&hints),
+            "server",
+        ));
+        if !self.config.allow_peer.is_empty() || !self.config.deny_peer.is_empty() {
+            list.push(EffectiveConfig::new(
+                ConfigField::Acl,
+                peers(&self.config.allow_peer, &self.config.deny_peer),
+                "local",
+            ));
+        }
+        if !pushed.allow_peer.is_empty() || !pushed.deny_peer.is_empty() {
+            list.push(EffectiveConfig::new(
+                ConfigField::Acl,
+                peers(&pushed.allow_peer, &pushed.deny_peer),
+                "server",
+            ));
+        }
+        list.retain(|v| !v.value.is_empty());
+        for field in pushed.vetoed {
+            list.push(EffectiveConfig::new(field, String::new(), "vetoed"));
+        }
+        list
+    }
+    /// 最近几次向对端打洞的记录，从旧到新
+    pub fn punch_log(&self, ip: &Ipv4Addr) -> Vec<PunchAttempt> {
+        self.context.route_table.punch_log.attempts(ip)
+    }
+    /// 对端的能力，未知的对端返回空
+    pub fn peer_capabilities(&self, ip: &Ipv4Addr) -> Capabilities {
+        self.context.capabilities.peer(ip)
+    }
+    pub fn server_capabilities(&self) -> Capabilities {
+        self.context.capabilities.server()
+    }
+    /// 运行时调整日志级别，如 `info,punch=debug`
+    pub fn set_log_filter(&self, spec: &str) -> io::Result<()> {
+        crate::util::set_log_filter(spec)
+    }
+    /// 内存中序号大于seq、不低于level级别的日志，需要使用FilterLogger
+    pub fn recent_logs(
+        &self,
+        seq: u64,
+        level: log::LevelFilter,
+        limit: usize,
+    ) -> Vec<crate::util::LogLine> {
+        crate::util::recent_logs(seq, level, limit)
+    }
+    /// 请求所有在线设备上报路由，结果稍后通过route_matrix获取
+    pub fn request_route_matrix(&self) -> io::Result<()> {
+        let current_device = self.current_device.load();
+        for info in self.device_list() {
+            if !info.status.is_online() {
+                continue;
+            }
+            let packet = route_report::route_report_request_packet(
+                &self.client_cipher,
+                current_device.virtual_ip,
+                info.virtual_ip,
+            )?;
+            if let Err(e) = self.context.send_ipv4_by_id(
+                packet.buffer(),
+                &info.virtual_ip,
+                current_device.connect_server,
+                current_device.status.online(),
+            ) {
+                log::warn!("请求上报路由失败 peer={} err={:?}", info.virtual_ip, e);
+            }
+        }
+        Ok(())
+    }
+    /// 可达矩阵，第一行是当前设备，其余是max_age内其他设备上报的路由
+    pub fn route_matrix(&self, max_age: Duration) -> Vec<(Ipv4Addr, Vec<PeerRoute>)> {
+        let current_device = self.current_device.load();
+        let mut matrix = vec![(
+            current_device.virtual_ip,
+            route_report::local_routes(&self.context, &current_device),
+        )];
+        matrix.extend(self.context.route_table.route_matrix.reports(max_age));
+        matrix
+    }
+    /// 请求对端的诊断信息，结果稍后通过peer_diag获取
+    pub fn request_diag(&self, ip: &Ipv4Addr) -> io::Result<()> {
+        let current_device = self.current_device.load();
+        let packet =
+            self.diag
+                .request_packet(&self.client_cipher, current_device.virtual_ip, *ip)?;
+        self.context.send_ipv4_by_id(
+            packet.buffer(),
+            ip,
+            current_device.connect_server,
+            current_device.status.online(),
+        )
+    }
+    pub fn peer_diag(&self, ip: &Ipv4Addr) -> Option<PeerDiag> {
+        self.diag.response(ip)
+    }
+    /// 和对端双向测速，阻塞到测速结束
+    pub fn speed_test(
+        &self,
+        ip: &Ipv4Addr,
+        duration: Duration,
+        tcp: bool,
+    ) -> io::Result<SpeedTestResult> {
+        let current_device = self.current_device.load();
+        self.speed_test.run(
+            &self.context,
+            &self.client_cipher,
+            &current_device,
+            *ip,
+            duration,
+            tcp,
+        )
+    }
+    /// 注册自定义协议，协议号只能使用128-255，收到该协议号的数据时交给handler处理
+    pub fn register_service_handler<H: ServiceHandler>(
+        &self,
+        protocol: ServiceProtocol,
+        handler: H,
+    ) -> io::Result<()> {
+        self.services.register(protocol, handler)
+    }
+    pub fn unregister_service_handler(&self, protocol: &ServiceProtocol) -> bool {
+        self.services.unregister(protocol)
+    }
+    /// 发送自定义协议数据，Service发往服务端，OtherTurn发往ip对应的客户端
+    pub fn send_service(
+        &self,
+        protocol: ServiceProtocol,
+        ip: &Ipv4Addr,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        if protocol.is_reserved() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("protocol {:?} is reserved", protocol),
+            ));
+        }
+        let current_device = self.current_device.load();
+        match protocol {
+            ServiceProtocol::Service(_) => {
+                let packet = protocol.packet(
+                    &self.server_cipher,
+                    current_device.virtual_ip,
+                    current_device.virtual_gateway,
+                    payload,
+                )?;
+                self.context
+                    .send_default(packet.buffer(), current_device.connect_server)
+            }
+            ServiceProtocol::OtherTurn(_) => {
+                let packet = protocol.packet(
+                    &self.client_cipher,
+                    current_device.virtual_ip,
+                    *ip,
+                    payload,
+                )?;
+                self.context.send_ipv4_by_id(
+                    packet.buffer(),
+                    ip,
+                    current_device.connect_server,
+                    current_device.status.online(),
+                )
+            }
+        }
+    }
+    /// 当前设备的公钥，未配置设备密钥时为None
+    pub fn device_public_key(&self) -> Option<Vec<u8>> {
+        self.context.peer_auth.public_key().map(|v| v.to_vec())
+    }
+    /// 服务端下发的对端公钥
+    pub fn peer_public_keys(&self) -> Vec<(Ipv4Addr, Vec<u8>)> {
+        self.context.peer_auth.peer_keys()
+    }
+    pub fn replay_drop_count(&self) -> u64 {
+        self.context.replay_drop_count()
+    }
+    /// tcp代理因空闲超时或连接数超限关闭的连接数
+    #[cfg(feature = "ip_proxy")]
+    pub fn tcp_proxy_evict_count(&self) -> u64 {
+        self.proxy_map
+            .as_ref()
+            .map(|v| v.tcp_evict_count())
+            .unwrap_or(0)
+    }
+    /// 网关模式下各对端的连接数和本周期的流量
+    #[cfg(feature = "ip_proxy")]
+    pub fn gateway_usage(&self) -> Vec<(Ipv4Addr, crate::ip_proxy::gateway::PeerUsage)> {
+        self.proxy_map
+            .as_ref()
+            .map(|v| v.gateway_usage())
+            .unwrap_or_default()
+    }
+    /// 进入或退出低功耗状态(如Android的Doze)，低功耗时放大心跳间隔并暂停打洞
+    pub fn set_low_power(&self, low_power: bool) {
+        self.context.power_save.set_low_power(low_power);
+    }
+    /// 应用感知到网络变化时调用，立即重新绑定并迁移连接
+    pub fn network_changed(&self) -> bool {
+        self.net_change.notify()
+    }
+    /// 运行中修改模拟弱网的规则，为空时关闭，会停止正在执行的计划
+    pub fn set_wan_sim(&self, rules: Vec<WanRule>) {
+        self.context.set_wan_rules(rules)
+    }
+    pub fn set_wan_schedule(&self, schedule: WanSchedule) {
+        self.context.set_wan_schedule(schedule)
+    }
+    pub fn wan_sim_status(&self) -> WanSimStatus {
+        self.context.wan_sim_status()
+    }
     pub fn stop(&self) {
+        maintain::notify_offline(
+            &self.context,
+            &self.current_device.load(),
+            &self.client_cipher,
+        );
         self.stop_manager.stop()
     }
     pub fn wait(&self) {
         self.stop_manager.wait()
     }
 }
+
+/// 解析 192.168.1.0/24 格式的网段，返回(网段，子网掩码)
+fn parse_cidr(cidr: &str) -> io::Result<(u32, u32)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("cidr {}", cidr));
+    let (ip, prefix) = cidr.trim().split_once('/').ok_or_else(invalid)?;
+    let ip = ip.parse::<Ipv4Addr>().map_err(|_| invalid())?;
+    let prefix = prefix.parse::<u32>().map_err(|_| invalid())?;
+    if prefix == 0 || prefix > 32 {
+        return Err(invalid());
+    }
+    let mask = u32::MAX << (32 - prefix);
+    Ok((u32::from(ip) & mask, mask))
+}
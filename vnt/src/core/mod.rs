@@ -1,15 +1,27 @@
 use anyhow::anyhow;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::str::FromStr;
 
 pub use conn::Vnt;
+pub use stats::StatsHandle;
 
+use crate::channel::acl::AclGroup;
+use crate::channel::broadcast_scope::BroadcastScope;
+use crate::channel::netem::{WanRule, WanSchedule};
+use crate::channel::peer_filter::PeerMatch;
 use crate::channel::punch::PunchModel;
-use crate::channel::UseChannelType;
-use crate::cipher::CipherModel;
-use crate::util::{address_choose, dns_query_all};
+use crate::channel::send_queue::SendQueueConfig;
+use crate::channel::server_config::ConfigField;
+use crate::channel::sock_buf::SocketBuf;
+use crate::channel::{LoadBalanceModel, UseChannelType};
+use crate::cipher::{CipherModel, KeyDerivation};
+#[cfg(feature = "ip_proxy")]
+use crate::ip_proxy::gateway::GatewayConfig;
+use crate::tun_tap_device::dhcp_pool::DhcpPool;
+use crate::util::{address_choose, dns_query_all, ServerProxy};
 
 mod conn;
+mod stats;
 
 #[derive(Clone, Debug)]
 pub struct Config {
@@ -30,6 +42,13 @@ pub struct Config {
     pub ip: Option<Ipv4Addr>,
     #[cfg(feature = "ip_proxy")]
     pub no_proxy: bool,
+    // tcp代理的保活时间(秒)、空闲超时时间(秒，0不超时)、最大连接数(0不限制)
+    #[cfg(feature = "ip_proxy")]
+    pub tcp_proxy_keepalive: u32,
+    #[cfg(feature = "ip_proxy")]
+    pub tcp_proxy_idle: u32,
+    #[cfg(feature = "ip_proxy")]
+    pub tcp_proxy_max_conn: u32,
     pub server_encrypt: bool,
     pub parallel: usize,
     pub cipher_model: CipherModel,
@@ -43,6 +62,102 @@ pub struct Config {
     //控制丢包率
     pub packet_loss_rate: Option<f64>,
     pub packet_delay: u32,
+    //多条p2p通道的负载均衡
+    pub load_balance: LoadBalanceModel,
+    //防重放
+    pub anti_replay: bool,
+    pub prefer_ipv6_server: bool,
+    // 无数据多少分钟后进入省电模式，0表示不开启
+    pub power_save: u32,
+    pub power_save_multiple: u32,
+    // 和指定虚拟ip通信时额外使用的预共享密钥
+    pub psk: Vec<(Ipv4Addr, String)>,
+    // 单包认证的密钥和端口，端口默认和服务端口一致
+    pub knock_key: Option<String>,
+    pub knock_port: Option<u16>,
+    // 小包合并的聚合等待时间(微秒)，0表示不开启
+    pub coalesce: u32,
+    // 允许其他设备远程获取诊断信息
+    pub allow_diag: bool,
+    // 允许其他设备向本机发起测速
+    pub allow_speed_test: bool,
+    // 连接服务器使用的代理
+    pub server_proxy: Option<ServerProxy>,
+    // 切换首选通道需要的最小延迟改善(ms)
+    pub route_hysteresis: u32,
+    // 两次切换首选通道的最小间隔(秒)
+    pub route_hold_down: u32,
+    // 设备私钥文件，配置后使用ed25519签名打洞和心跳消息
+    pub device_key: Option<String>,
+    // 入站被拦截时，主动和多少个设备保持tcp连接作为中继，0表示不开启
+    pub reverse_tunnel: usize,
+    // 不加密时对数据包做消息认证，防止伪造来源
+    pub header_auth: bool,
+    // 通道socket绑定的本地地址，为空时监听所有地址
+    pub listen: Option<IpAddr>,
+    // 外层包的DSCP值(0~63)
+    pub dscp: Option<u8>,
+    // socket的SO_PRIORITY，仅linux
+    pub so_priority: Option<u32>,
+    // 外层udp包复制内层ip包的DSCP
+    pub dscp_copy: bool,
+    // p2p路由保活的初始间隔(秒)，为0则不开启
+    pub hold_punch: u32,
+    // 按目标模拟弱网的规则
+    pub wan_sim: Vec<WanRule>,
+    // 为其他设备中转的带宽限额(KB/s)，为0则不限
+    pub relay_budget: u32,
+    // 密码到密钥的派生方式，同一网络内必须一致
+    pub kdf: KeyDerivation,
+    // 对端白名单和黑名单
+    pub allow_peer: Vec<PeerMatch>,
+    pub deny_peer: Vec<PeerMatch>,
+    // 关闭网卡多队列，内核不支持IFF_MULTI_QUEUE时使用
+    #[cfg(target_os = "linux")]
+    pub no_multi_queue: bool,
+    // 点对网的ipv6映射，网段内的tcp连接由代理转发到对应的ipv6地址
+    #[cfg(feature = "ip_proxy")]
+    pub out_ips6: Vec<(u32, u32, Ipv6Addr)>,
+    // 向其他设备通告自己转发的网段(-o)
+    pub advertise_subnets: bool,
+    // 接受其他网关通告的网段，自动添加路由
+    pub accept_subnets: bool,
+    // 把加密的数据包填充到固定的几档长度
+    pub padding: bool,
+    // 每秒发送的掩护包数，0为不发送
+    pub cover_traffic: u32,
+    // 允许服务端下发的配置项
+    pub accept_server_config: Vec<ConfigField>,
+    // 服务端的备用端口，服务端口不通时同时探测
+    pub server_ports: Vec<u16>,
+    // tap模式下所在的vlan，第一个为native vlan
+    #[cfg(any(target_os = "windows", target_os = "linux"))]
+    pub vlan: Vec<u16>,
+    // 发现虚拟ip冲突时重新申请ip
+    pub ip_conflict_reassign: bool,
+    // 启用AF_XDP接收加速的网卡(实验性)
+    pub xdp: Option<String>,
+    // tcp连接发送队列的水位和丢包策略
+    pub send_queue: SendQueueConfig,
+    // 按对端分组的访问控制
+    pub acl: Vec<AclGroup>,
+    // 控制包的DSCP，和数据包区分开
+    pub control_dscp: Option<u8>,
+    // 加解密线程数，小于2时在收发线程直接处理
+    pub parallel_crypto: usize,
+    // 网关模式，通告默认路由，代理其他设备访问外部网络
+    #[cfg(feature = "ip_proxy")]
+    pub gateway: Option<GatewayConfig>,
+    // 广播组的子网掩码，把服务端分配的网段划分成更小的广播范围
+    pub broadcast_mask: Option<Ipv4Addr>,
+    // 创建虚拟网卡后切换到的用户，格式为 user[:group]
+    pub run_as: Option<String>,
+    // 按时间分阶段模拟弱网的计划
+    pub wan_schedule: Option<WanSchedule>,
+    // udp socket的收发缓冲区
+    pub socket_buf: SocketBuf,
+    // tap模式下dhcp分配给桥接设备的地址范围
+    pub dhcp_pool: Option<DhcpPool>,
 }
 
 impl Config {
@@ -61,6 +176,9 @@ impl Config {
         tcp: bool,
         ip: Option<Ipv4Addr>,
         #[cfg(feature = "ip_proxy")] no_proxy: bool,
+        #[cfg(feature = "ip_proxy")] tcp_proxy_keepalive: u32,
+        #[cfg(feature = "ip_proxy")] tcp_proxy_idle: u32,
+        #[cfg(feature = "ip_proxy")] tcp_proxy_max_conn: u32,
         server_encrypt: bool,
         parallel: usize,
         cipher_model: CipherModel,
@@ -72,6 +190,54 @@ impl Config {
         use_channel_type: UseChannelType,
         packet_loss_rate: Option<f64>,
         packet_delay: u32,
+        load_balance: LoadBalanceModel,
+        anti_replay: bool,
+        prefer_ipv6_server: bool,
+        power_save: u32,
+        power_save_multiple: u32,
+        psk: Vec<(Ipv4Addr, String)>,
+        knock_key: Option<String>,
+        knock_port: Option<u16>,
+        coalesce: u32,
+        allow_diag: bool,
+        allow_speed_test: bool,
+        server_proxy: Option<String>,
+        route_hysteresis: u32,
+        route_hold_down: u32,
+        device_key: Option<String>,
+        reverse_tunnel: usize,
+        header_auth: bool,
+        listen: Option<String>,
+        dscp: Option<u8>,
+        so_priority: Option<u32>,
+        dscp_copy: bool,
+        hold_punch: u32,
+        wan_sim: Vec<String>,
+        relay_budget: u32,
+        kdf: KeyDerivation,
+        allow_peer: Vec<String>,
+        deny_peer: Vec<String>,
+        #[cfg(target_os = "linux")] no_multi_queue: bool,
+        #[cfg(feature = "ip_proxy")] out_ips6: Vec<(u32, u32, Ipv6Addr)>,
+        advertise_subnets: bool,
+        accept_subnets: bool,
+        padding: bool,
+        cover_traffic: u32,
+        accept_server_config: Vec<String>,
+        server_ports: Vec<u16>,
+        #[cfg(any(target_os = "windows", target_os = "linux"))] vlan: Vec<u16>,
+        ip_conflict_reassign: bool,
+        xdp: Option<String>,
+        send_queue: SendQueueConfig,
+        acl: Vec<AclGroup>,
+        control_dscp: Option<u8>,
+        parallel_crypto: usize,
+        #[cfg(feature = "ip_proxy")] gateway: Option<GatewayConfig>,
+        broadcast_mask: Option<Ipv4Addr>,
+        run_as: Option<String>,
+        wan_schedule: Option<String>,
+        socket_buf: SocketBuf,
+        dhcp_pool: Option<DhcpPool>,
     ) -> anyhow::Result<Self> {
         for x in stun_server.iter_mut() {
             if !x.contains(":") {
@@ -79,7 +245,10 @@ impl Config {
             }
         }
         for x in name_servers.iter_mut() {
-            if Ipv6Addr::from_str(x).is_ok() {
+            if x.contains("://") {
+                // DoT/DoH地址，端口使用协议默认值
+                crate::util::NameServer::from_str(x)?;
+            } else if Ipv6Addr::from_str(x).is_ok() {
                 x.push_str(":53");
             } else if !x.contains(":") {
                 x.push_str(":53");
@@ -94,8 +263,128 @@ impl Config {
         if name.is_empty() || name.len() > 128 {
             return Err(anyhow!("name too long"));
         }
-        let server_address =
-            address_choose(dns_query_all(&server_address_str, name_servers.clone())?)?;
+        if !psk.is_empty() && (password.is_none() || cipher_model == CipherModel::None) {
+            return Err(anyhow!("psk requires password"));
+        }
+        #[cfg(not(feature = "device_auth"))]
+        if device_key.is_some() {
+            return Err(anyhow!("device key requires feature device_auth"));
+        }
+        #[cfg(not(all(target_os = "linux", feature = "xdp")))]
+        if xdp.is_some() {
+            return Err(anyhow!("xdp requires feature xdp on linux"));
+        }
+        let server_proxy = match server_proxy {
+            Some(server_proxy) => {
+                if !tcp {
+                    return Err(anyhow!("server proxy requires tcp"));
+                }
+                Some(ServerProxy::from_str(&server_proxy).map_err(|e| anyhow!("{}", e))?)
+            }
+            None => None,
+        };
+        let listen = match listen {
+            Some(listen) => Some(
+                IpAddr::from_str(listen.trim_start_matches('[').trim_end_matches(']'))
+                    .map_err(|e| anyhow!("listen {:?} {}", listen, e))?,
+            ),
+            None => None,
+        };
+        if let Some(dscp) = dscp {
+            if dscp > 63 {
+                return Err(anyhow!("dscp {} out of range 0~63", dscp));
+            }
+        }
+        if let Some(dscp) = control_dscp {
+            if dscp > 63 {
+                return Err(anyhow!("control dscp {} out of range 0~63", dscp));
+            }
+        }
+        let wan_sim = wan_sim
+            .iter()
+            .map(|v| WanRule::from_str(v).map_err(|e| anyhow!("wan sim {:?} {}", v, e)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let wan_schedule = match wan_schedule {
+            Some(wan_schedule) => Some(
+                WanSchedule::from_str(&wan_schedule).map_err(|e| anyhow!("wan schedule {}", e))?,
+            ),
+            None => None,
+        };
+        let allow_peer = allow_peer
+            .iter()
+            .map(|v| PeerMatch::from_str(v).map_err(|e| anyhow!("allow peer {}", e)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let deny_peer = deny_peer
+            .iter()
+            .map(|v| PeerMatch::from_str(v).map_err(|e| anyhow!("deny peer {}", e)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        #[cfg(feature = "ip_proxy")]
+        let has_out_ips = !out_ips.is_empty() || !out_ips6.is_empty();
+        #[cfg(not(feature = "ip_proxy"))]
+        let has_out_ips = !out_ips.is_empty();
+        if advertise_subnets && !has_out_ips {
+            return Err(anyhow!("advertise subnets requires out ip"));
+        }
+        BroadcastScope::new(broadcast_mask).map_err(|e| anyhow!("{}", e))?;
+        if let Some(run_as) = &run_as {
+            #[cfg(target_os = "linux")]
+            crate::util::RunAs::lookup(run_as).map_err(|e| anyhow!("run as {}", e))?;
+            #[cfg(not(target_os = "linux"))]
+            return Err(anyhow!("run as {} is only supported on linux", run_as));
+        }
+        #[cfg(feature = "ip_proxy")]
+        if gateway.is_some() && no_proxy {
+            return Err(anyhow!("gateway requires the built-in proxy"));
+        }
+        if padding && (password.is_none() || cipher_model == CipherModel::None) {
+            return Err(anyhow!("padding requires password"));
+        }
+        if padding && coalesce > 0 {
+            return Err(anyhow!("padding conflicts with coalesce"));
+        }
+        if cover_traffic > 0 && !padding {
+            return Err(anyhow!("cover traffic requires padding"));
+        }
+        let mut fields = Vec::new();
+        for v in &accept_server_config {
+            if v.trim().eq_ignore_ascii_case("all") {
+                fields = ConfigField::ALL.to_vec();
+                break;
+            }
+            let field = ConfigField::from_str(v).map_err(|e| anyhow!("{}", e))?;
+            if !fields.contains(&field) {
+                fields.push(field);
+            }
+        }
+        let accept_server_config = fields;
+        if server_ports.contains(&0) {
+            return Err(anyhow!("server port 0"));
+        }
+        #[cfg(any(target_os = "windows", target_os = "linux"))]
+        {
+            if !vlan.is_empty() && !tap {
+                return Err(anyhow!("vlan requires tap"));
+            }
+            if dhcp_pool.is_some() && !tap {
+                return Err(anyhow!("dhcp pool requires tap"));
+            }
+            for (i, vid) in vlan.iter().enumerate() {
+                if !(1..=4094).contains(vid) {
+                    return Err(anyhow!("vlan {} out of range 1~4094", vid));
+                }
+                if vlan[..i].contains(vid) {
+                    return Err(anyhow!("vlan {} repeated", vid));
+                }
+            }
+        }
+        #[cfg(not(any(target_os = "windows", target_os = "linux")))]
+        if dhcp_pool.is_some() {
+            return Err(anyhow!("dhcp pool requires tap"));
+        }
+        let server_address = address_choose(
+            dns_query_all(&server_address_str, name_servers.clone())?,
+            prefer_ipv6_server,
+        )?;
         Ok(Self {
             #[cfg(any(target_os = "windows", target_os = "linux"))]
             tap,
@@ -114,6 +403,12 @@ impl Config {
             ip,
             #[cfg(feature = "ip_proxy")]
             no_proxy,
+            #[cfg(feature = "ip_proxy")]
+            tcp_proxy_keepalive,
+            #[cfg(feature = "ip_proxy")]
+            tcp_proxy_idle,
+            #[cfg(feature = "ip_proxy")]
+            tcp_proxy_max_conn,
             server_encrypt,
             parallel,
             cipher_model,
@@ -126,6 +421,63 @@ impl Config {
             use_channel_type,
             packet_loss_rate,
             packet_delay,
+            load_balance,
+            anti_replay,
+            prefer_ipv6_server,
+            power_save,
+            power_save_multiple,
+            psk,
+            knock_key,
+            knock_port,
+            coalesce,
+            allow_diag,
+            allow_speed_test,
+            server_proxy,
+            route_hysteresis,
+            route_hold_down,
+            device_key,
+            reverse_tunnel,
+            header_auth,
+            listen,
+            dscp,
+            so_priority,
+            dscp_copy,
+            hold_punch,
+            wan_sim,
+            relay_budget,
+            kdf,
+            allow_peer,
+            deny_peer,
+            #[cfg(target_os = "linux")]
+            no_multi_queue,
+            #[cfg(feature = "ip_proxy")]
+            out_ips6,
+            advertise_subnets,
+            accept_subnets,
+            padding,
+            cover_traffic,
+            accept_server_config,
+            server_ports,
+            #[cfg(any(target_os = "windows", target_os = "linux"))]
+            vlan,
+            ip_conflict_reassign,
+            xdp,
+            send_queue,
+            acl,
+            control_dscp,
+            parallel_crypto,
+            #[cfg(feature = "ip_proxy")]
+            gateway,
+            broadcast_mask,
+            run_as,
+            wan_schedule,
+            socket_buf,
+            dhcp_pool,
         })
     }
+    /// 虚拟网卡的mtu，没有指定时加密需要预留更多空间
+    pub fn tun_mtu(&self) -> u32 {
+        self.mtu
+            .unwrap_or_else(|| if self.password.is_none() { 1450 } else { 1410 })
+    }
 }
@@ -0,0 +1,59 @@
+use std::sync::Arc;
+
+use crate::channel::queue_depth::QueueDepth;
+use crate::channel::traffic::{Traffic, TrafficSnapshot};
+use crate::util::WatchU64Adder;
+
+/// 共享的统计计数，读取时只有原子读，不加锁也没有系统调用，
+/// 适合界面高频刷新。可以clone后放到其他线程使用
+#[derive(Clone)]
+pub struct StatsHandle {
+    up: WatchU64Adder,
+    down: WatchU64Adder,
+    queue_depth: Arc<QueueDepth>,
+    traffic: Arc<Traffic>,
+}
+
+impl StatsHandle {
+    pub(crate) fn new(
+        up: WatchU64Adder,
+        down: WatchU64Adder,
+        queue_depth: Arc<QueueDepth>,
+        traffic: Arc<Traffic>,
+    ) -> Self {
+        Self {
+            up,
+            down,
+            queue_depth,
+            traffic,
+        }
+    }
+    /// 上行总字节数
+    pub fn up_stream(&self) -> u64 {
+        self.up.get()
+    }
+    /// 下行总字节数
+    pub fn down_stream(&self) -> u64 {
+        self.down.get()
+    }
+    /// tcp通道发送队列中等待的包数
+    pub fn tcp_queue_depth(&self) -> usize {
+        self.queue_depth.tcp()
+    }
+    /// tcp通道发送队列拥塞时丢弃的包数
+    pub fn tcp_queue_dropped(&self) -> u64 {
+        self.queue_depth.tcp_dropped()
+    }
+    /// 网卡数据处理线程数，未开启并行时为0
+    pub fn worker_num(&self) -> usize {
+        self.queue_depth.worker_num()
+    }
+    /// 第index个处理线程队列中等待的包数
+    pub fn worker_queue_depth(&self, index: usize) -> usize {
+        self.queue_depth.worker(index)
+    }
+    /// 和对端之间ip数据的累计包数、直连/中转字节数和解密失败数
+    pub fn traffic(&self) -> TrafficSnapshot {
+        self.traffic.snapshot()
+    }
+}
@@ -0,0 +1,378 @@
+//! 运行环境自检，在启动前检查虚拟网卡、权限、和服务器及stun服务器的连通性等，
+//! 给出可以操作的建议，命令行和图形界面都可以调用
+
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, TcpStream, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::nat;
+use crate::protocol::{service_packet, NetPacket, Protocol};
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+    Skip,
+}
+
+impl Display for CheckStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            CheckStatus::Pass => "PASS",
+            CheckStatus::Warn => "WARN",
+            CheckStatus::Fail => "FAIL",
+            CheckStatus::Skip => "SKIP",
+        };
+        f.write_str(s)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+    // 检查不通过时的处理建议
+    pub hint: Option<String>,
+}
+
+impl CheckResult {
+    fn new(name: &'static str, status: CheckStatus, detail: String) -> Self {
+        Self {
+            name,
+            status,
+            detail,
+            hint: None,
+        }
+    }
+    fn hint(mut self, hint: &str) -> Self {
+        self.hint = Some(hint.to_string());
+        self
+    }
+}
+
+pub struct DoctorOptions {
+    // 服务器地址，和-s参数相同
+    pub server: String,
+    pub name_servers: Vec<String>,
+    pub stun_server: Vec<String>,
+    // 虚拟网段，用于检查和本地路由是否冲突
+    pub virtual_network: (Ipv4Addr, u8),
+}
+
+/// 依次执行所有检查
+pub fn run(options: &DoctorOptions) -> Vec<CheckResult> {
+    let mut list = vec![check_tun(), check_privilege()];
+    list.extend(check_server(&options.server, options.name_servers.clone()));
+    list.push(check_stun(&options.stun_server));
+    list.push(check_ipv6());
+    list.push(check_route_clash(options.virtual_network));
+    list
+}
+
+/// 虚拟网卡驱动是否可用
+pub fn check_tun() -> CheckResult {
+    const NAME: &str = "tun";
+    #[cfg(target_os = "linux")]
+    {
+        match std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/net/tun")
+        {
+            Ok(_) => CheckResult::new(NAME, CheckStatus::Pass, "/dev/net/tun".into()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                CheckResult::new(NAME, CheckStatus::Fail, format!("/dev/net/tun {}", e))
+                    .hint("加载tun模块: modprobe tun，容器中需要映射/dev/net/tun")
+            }
+            Err(e) => CheckResult::new(NAME, CheckStatus::Fail, format!("/dev/net/tun {}", e))
+                .hint("使用root权限运行，或授予CAP_NET_ADMIN"),
+        }
+    }
+    #[cfg(target_os = "windows")]
+    {
+        match unsafe { libloading::Library::new("wintun.dll") } {
+            Ok(_) => CheckResult::new(NAME, CheckStatus::Pass, "wintun.dll".into()),
+            Err(e) => CheckResult::new(NAME, CheckStatus::Fail, format!("wintun.dll {}", e))
+                .hint("把和系统架构一致的wintun.dll放到程序所在目录"),
+        }
+    }
+    #[cfg(target_os = "macos")]
+    {
+        CheckResult::new(NAME, CheckStatus::Pass, "utun".into())
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "windows", target_os = "macos")))]
+    {
+        CheckResult::new(NAME, CheckStatus::Skip, "provided by the platform".into())
+    }
+}
+
+/// 创建网卡和修改路由需要的权限
+pub fn check_privilege() -> CheckResult {
+    const NAME: &str = "privilege";
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    {
+        let euid = unsafe { libc::geteuid() };
+        if euid == 0 {
+            CheckResult::new(NAME, CheckStatus::Pass, "root".into())
+        } else {
+            CheckResult::new(NAME, CheckStatus::Fail, format!("euid={}", euid))
+                .hint("使用sudo或root用户运行")
+        }
+    }
+    #[cfg(target_os = "windows")]
+    {
+        // 只有管理员才能查询会话
+        match std::process::Command::new("net")
+            .arg("session")
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+        {
+            Ok(status) if status.success() => {
+                CheckResult::new(NAME, CheckStatus::Pass, "administrator".into())
+            }
+            Ok(_) => CheckResult::new(NAME, CheckStatus::Fail, "not administrator".into())
+                .hint("右键以管理员身份运行"),
+            Err(e) => CheckResult::new(NAME, CheckStatus::Skip, format!("{}", e)),
+        }
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+    {
+        CheckResult::new(NAME, CheckStatus::Skip, "provided by the platform".into())
+    }
+}
+
+/// 服务器的udp和tcp连通性，据此给出防火墙的提示
+pub fn check_server(server: &str, name_servers: Vec<String>) -> Vec<CheckResult> {
+    let addr = match crate::util::dns_query_all(server, name_servers) {
+        Ok(addrs) if !addrs.is_empty() => addrs[0],
+        Ok(_) => {
+            return vec![CheckResult::new(
+                "server",
+                CheckStatus::Fail,
+                format!("{} no address", server),
+            )
+            .hint("检查服务器地址，或用--dns指定DNS服务器")]
+        }
+        Err(e) => {
+            return vec![
+                CheckResult::new("server", CheckStatus::Fail, format!("{} {}", server, e))
+                    .hint("检查服务器地址，或用--dns指定DNS服务器"),
+            ]
+        }
+    };
+    let udp = probe_udp(addr);
+    let tcp = probe_tcp(addr);
+    let mut list = Vec::with_capacity(3);
+    list.push(match &udp {
+        Ok(rt) => CheckResult::new(
+            "server-udp",
+            CheckStatus::Pass,
+            format!("{} rt={}ms", addr, rt.as_millis()),
+        ),
+        Err(e) => CheckResult::new("server-udp", CheckStatus::Fail, format!("{} {}", addr, e)),
+    });
+    list.push(match &tcp {
+        Ok(rt) => CheckResult::new(
+            "server-tcp",
+            CheckStatus::Pass,
+            format!("{} rt={}ms", addr, rt.as_millis()),
+        ),
+        Err(e) => CheckResult::new("server-tcp", CheckStatus::Fail, format!("{} {}", addr, e)),
+    });
+    list.push(match (udp.is_ok(), tcp.is_ok()) {
+        (true, _) => CheckResult::new("firewall", CheckStatus::Pass, "udp allowed".into()),
+        (false, true) => CheckResult::new(
+            "firewall",
+            CheckStatus::Warn,
+            "udp blocked, tcp allowed".into(),
+        )
+        .hint("出站udp可能被防火墙拦截，放行udp或使用--tcp连接服务器"),
+        (false, false) => CheckResult::new(
+            "firewall",
+            CheckStatus::Fail,
+            "udp and tcp unreachable".into(),
+        )
+        .hint("确认服务器在运行，检查本机和网络的防火墙是否拦截该端口"),
+    });
+    list
+}
+
+fn probe_udp(addr: SocketAddr) -> io::Result<Duration> {
+    let udp = match addr {
+        SocketAddr::V4(_) => UdpSocket::bind("0.0.0.0:0")?,
+        SocketAddr::V6(_) => UdpSocket::bind("[::]:0")?,
+    };
+    udp.connect(addr)?;
+    udp.set_read_timeout(Some(TIMEOUT))?;
+    let request = crate::handle::handshaker::handshake_request_packet(false, None, 0)?;
+    let mut buf = [0u8; 4096];
+    for _ in 0..3 {
+        let start = Instant::now();
+        udp.send(request.buffer())?;
+        let len = match udp.recv(&mut buf) {
+            Ok(len) => len,
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                continue
+            }
+            Err(e) => return Err(e),
+        };
+        let packet = NetPacket::new(&buf[..len])?;
+        if packet.protocol() == Protocol::Service
+            && packet.transport_protocol() == service_packet::Protocol::HandshakeResponse.into()
+        {
+            return Ok(start.elapsed());
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "no handshake response",
+    ))
+}
+
+fn probe_tcp(addr: SocketAddr) -> io::Result<Duration> {
+    let start = Instant::now();
+    TcpStream::connect_timeout(&addr, TIMEOUT * 3)?;
+    Ok(start.elapsed())
+}
+
+/// stun服务器是否可达
+pub fn check_stun(stun_server: &[String]) -> CheckResult {
+    const NAME: &str = "stun";
+    let mut ok = 0;
+    let mut detail = Vec::with_capacity(stun_server.len());
+    for server in stun_server {
+        let start = Instant::now();
+        match nat::stun_probe(server) {
+            Ok(addr) => {
+                ok += 1;
+                detail.push(format!(
+                    "{} -> {} {}ms",
+                    server,
+                    addr,
+                    start.elapsed().as_millis()
+                ));
+            }
+            Err(e) => detail.push(format!("{} {}", server, e)),
+        }
+    }
+    let detail = detail.join("; ");
+    if stun_server.is_empty() {
+        CheckResult::new(NAME, CheckStatus::Skip, "no stun server".into())
+    } else if ok == stun_server.len() {
+        CheckResult::new(NAME, CheckStatus::Pass, detail)
+    } else if ok > 0 {
+        CheckResult::new(NAME, CheckStatus::Warn, detail).hint("去掉不可用的stun服务器(-e)")
+    } else {
+        CheckResult::new(NAME, CheckStatus::Fail, detail)
+            .hint("无法探测NAT类型，p2p成功率会降低，检查出站udp或更换stun服务器(-e)")
+    }
+}
+
+/// 是否有公网ipv6地址，没有时不影响使用，只是少一种p2p方式
+pub fn check_ipv6() -> CheckResult {
+    const NAME: &str = "ipv6";
+    match nat::local_ipv6() {
+        Some(ip)
+            if !ip.is_loopback()
+                && !ip.is_unspecified()
+                && (ip.segments()[0] & 0xE000) == 0x2000 =>
+        {
+            CheckResult::new(NAME, CheckStatus::Pass, ip.to_string())
+        }
+        Some(ip) => CheckResult::new(NAME, CheckStatus::Warn, format!("{} is not global", ip)),
+        None => CheckResult::new(NAME, CheckStatus::Warn, "unavailable".into())
+            .hint("没有ipv6不影响使用，p2p只使用ipv4"),
+    }
+}
+
+/// 虚拟网段是否和本地网络或已有路由重叠
+pub fn check_route_clash(network: (Ipv4Addr, u8)) -> CheckResult {
+    const NAME: &str = "route";
+    let mut clash = Vec::new();
+    if let Some(ip) = nat::local_ipv4() {
+        if overlap(network, (ip, 32)) {
+            clash.push(format!("local ip {}", ip));
+        }
+    }
+    #[cfg(target_os = "linux")]
+    match linux_routes() {
+        Ok(routes) => {
+            for (iface, dest, prefix) in routes {
+                // 默认路由和vnt自己的网卡不算冲突
+                if prefix == 0 || iface.starts_with("vnt") {
+                    continue;
+                }
+                if overlap(network, (dest, prefix)) {
+                    clash.push(format!("{}/{} dev {}", dest, prefix, iface));
+                }
+            }
+        }
+        Err(e) => log::warn!("read routes {:?}", e),
+    }
+    let network_str = format!("{}/{}", network.0, network.1);
+    if clash.is_empty() {
+        CheckResult::new(NAME, CheckStatus::Pass, network_str)
+    } else {
+        CheckResult::new(
+            NAME,
+            CheckStatus::Fail,
+            format!("{} clash with {}", network_str, clash.join(", ")),
+        )
+        .hint("虚拟网段和本地网络冲突，在服务端更换网段")
+    }
+}
+
+fn overlap(a: (Ipv4Addr, u8), b: (Ipv4Addr, u8)) -> bool {
+    let prefix = a.1.min(b.1).min(32);
+    let mask = if prefix == 0 {
+        0
+    } else {
+        u32::MAX << (32 - prefix)
+    };
+    u32::from(a.0) & mask == u32::from(b.0) & mask
+}
+
+#[cfg(target_os = "linux")]
+fn linux_routes() -> io::Result<Vec<(String, Ipv4Addr, u8)>> {
+    let content = std::fs::read_to_string("/proc/net/route")?;
+    let mut list = Vec::new();
+    for line in content.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 8 {
+            continue;
+        }
+        // 网络字节序的地址按主机字节序输出为十六进制
+        let (dest, mask) = match (
+            u32::from_str_radix(fields[1], 16),
+            u32::from_str_radix(fields[7], 16),
+        ) {
+            (Ok(dest), Ok(mask)) => (dest, mask),
+            _ => continue,
+        };
+        let dest = Ipv4Addr::from(dest.to_ne_bytes());
+        list.push((fields[0].to_string(), dest, mask.count_ones() as u8));
+    }
+    Ok(list)
+}
+
+/// 是否有未通过的检查
+pub fn has_failure(list: &[CheckResult]) -> bool {
+    list.iter().any(|v| v.status == CheckStatus::Fail)
+}
+
+#[test]
+fn test_overlap() {
+    let network = (Ipv4Addr::new(10, 26, 0, 0), 24);
+    assert!(overlap(network, (Ipv4Addr::new(10, 26, 0, 5), 32)));
+    assert!(overlap(network, (Ipv4Addr::new(10, 0, 0, 0), 8)));
+    assert!(!overlap(network, (Ipv4Addr::new(10, 27, 0, 0), 24)));
+    assert!(!overlap(network, (Ipv4Addr::new(192, 168, 1, 0), 24)));
+}
@@ -0,0 +1,138 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::Ipv4Addr;
+
+use crate::handle::callback::ErrorType;
+
+/// vnt运行中的错误，通过回调、JNI和命令行退出码对外暴露，调用方可以按类型处理
+#[derive(Debug)]
+pub enum VntError {
+    /// token错误，可能是服务端设置了白名单
+    TokenInvalid,
+    /// 和服务端断开连接
+    Disconnect(Option<String>),
+    /// 服务端地址用尽
+    AddressExhausted,
+    /// 虚拟ip已被其他设备使用
+    IpConflict,
+    /// 运行中发现其他在线设备使用了相同的虚拟ip，例如克隆了配置
+    IpConflictDetected(Ipv4Addr),
+    /// 虚拟ip不在网段内
+    InvalidIp,
+    /// 虚拟ip和本地ip冲突
+    LocalIpExists(io::Error),
+    /// 握手被拒绝，包括回调拒绝和服务端指纹不匹配
+    HandshakeRejected(String),
+    /// 服务端协议版本不兼容
+    VersionMismatch(u8),
+    /// 客户端版本低于服务端要求的最低版本
+    VersionTooOld(Option<String>),
+    /// 设备名称已被其他设备使用
+    NameDuplicated,
+    /// 打洞失败
+    PunchFailed(Ipv4Addr, io::Error),
+    /// 加密相关错误
+    Cipher(String),
+    /// 创建虚拟网卡失败
+    TunCreate(io::Error),
+    Io(io::Error),
+    /// 服务端返回的其他错误
+    Other(String),
+}
+
+impl VntError {
+    pub fn kind(&self) -> ErrorType {
+        match self {
+            VntError::TokenInvalid => ErrorType::TokenError,
+            VntError::Disconnect(_) => ErrorType::Disconnect,
+            VntError::AddressExhausted => ErrorType::AddressExhausted,
+            VntError::IpConflict => ErrorType::IpAlreadyExists,
+            VntError::IpConflictDetected(_) => ErrorType::IpConflictDetected,
+            VntError::InvalidIp => ErrorType::InvalidIp,
+            VntError::LocalIpExists(_) => ErrorType::LocalIpExists,
+            VntError::HandshakeRejected(_) => ErrorType::HandshakeRejected,
+            VntError::VersionMismatch(_) => ErrorType::VersionMismatch,
+            VntError::VersionTooOld(_) => ErrorType::VersionTooOld,
+            VntError::NameDuplicated => ErrorType::NameDuplicated,
+            VntError::PunchFailed(..) => ErrorType::PunchFailed,
+            VntError::Cipher(_) => ErrorType::CipherError,
+            VntError::TunCreate(_) => ErrorType::TunCreateFailed,
+            VntError::Io(_) => ErrorType::IoError,
+            VntError::Other(_) => ErrorType::Unknown,
+        }
+    }
+    /// 稳定的错误码，也用作命令行的退出码
+    pub fn code(&self) -> u8 {
+        self.kind().into()
+    }
+    /// 发生后无法自行恢复，需要修改配置后重启
+    pub fn is_fatal(&self) -> bool {
+        self.kind().is_fatal()
+    }
+    pub(crate) fn detail(&self) -> Option<String> {
+        match self {
+            VntError::Disconnect(msg) => msg.clone(),
+            VntError::LocalIpExists(e) | VntError::TunCreate(e) | VntError::Io(e) => {
+                Some(e.to_string())
+            }
+            VntError::HandshakeRejected(msg) | VntError::Cipher(msg) | VntError::Other(msg) => {
+                Some(msg.clone())
+            }
+            VntError::VersionMismatch(version) => Some(format!("version={}", version)),
+            VntError::VersionTooOld(required) => required
+                .as_ref()
+                .map(|required| format!("required={},current={}", required, crate::VNT_VERSION)),
+            VntError::PunchFailed(ip, e) => Some(format!("peer={},{}", ip, e)),
+            VntError::IpConflictDetected(ip) => Some(format!("ip={}", ip)),
+            _ => None,
+        }
+    }
+}
+
+impl Display for VntError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.detail() {
+            Some(detail) => write!(f, "{:?}({}): {}", self.kind(), self.code(), detail),
+            None => write!(f, "{:?}({})", self.kind(), self.code()),
+        }
+    }
+}
+
+impl std::error::Error for VntError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VntError::LocalIpExists(e)
+            | VntError::TunCreate(e)
+            | VntError::Io(e)
+            | VntError::PunchFailed(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for VntError {
+    fn from(e: io::Error) -> Self {
+        VntError::Io(e)
+    }
+}
+
+impl From<VntError> for io::Error {
+    fn from(e: VntError) -> Self {
+        match e {
+            VntError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::Other, e),
+        }
+    }
+}
+
+#[test]
+fn test_code() {
+    let e = VntError::TunCreate(io::Error::new(io::ErrorKind::Other, "no driver"));
+    assert_eq!(e.code(), 11);
+    assert!(e.is_fatal());
+    assert_eq!(e.to_string(), "TunCreateFailed(11): no driver");
+    assert_eq!(VntError::TokenInvalid.code(), 1);
+    assert!(!VntError::Disconnect(None).is_fatal());
+    let e: io::Error = VntError::Io(io::Error::new(io::ErrorKind::NotFound, "x")).into();
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+}
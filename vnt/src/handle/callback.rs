@@ -1,3 +1,5 @@
+use crate::cipher::{CipherBench, CipherModel};
+use crate::error::VntError;
 use crate::handle::PeerDeviceStatus;
 #[cfg(feature = "server_encrypt")]
 use rsa::RsaPublicKey;
@@ -164,6 +166,21 @@ impl ErrorInfo {
     }
 }
 
+impl From<VntError> for ErrorInfo {
+    fn from(e: VntError) -> Self {
+        let code = e.kind();
+        let msg = e.detail();
+        let source = match e {
+            VntError::LocalIpExists(e)
+            | VntError::TunCreate(e)
+            | VntError::Io(e)
+            | VntError::PunchFailed(_, e) => Some(e),
+            _ => None,
+        };
+        Self { code, msg, source }
+    }
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum ErrorType {
     TokenError,
@@ -172,9 +189,45 @@ pub enum ErrorType {
     IpAlreadyExists,
     InvalidIp,
     LocalIpExists,
+    HandshakeRejected,
+    VersionMismatch,
+    PunchFailed,
+    CipherError,
+    TunCreateFailed,
+    IoError,
+    // 和对端的密钥派生方式不一致，无法互相解密
+    KdfMismatch,
+    // 连续多次握手没有响应，降低重试频率
+    ServerUnreachable,
+    // 运行中发现其他在线设备使用了相同的虚拟ip
+    IpConflictDetected,
+    // 客户端版本过低，被服务端拒绝
+    VersionTooOld,
+    // 设备名称重复
+    NameDuplicated,
     Unknown,
 }
 
+impl ErrorType {
+    /// 需要修改配置后重启才能恢复的错误
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            ErrorType::TokenError
+                | ErrorType::AddressExhausted
+                | ErrorType::IpAlreadyExists
+                | ErrorType::InvalidIp
+                | ErrorType::LocalIpExists
+                | ErrorType::HandshakeRejected
+                | ErrorType::VersionMismatch
+                | ErrorType::VersionTooOld
+                | ErrorType::NameDuplicated
+                | ErrorType::CipherError
+                | ErrorType::TunCreateFailed
+        )
+    }
+}
+
 impl Into<u8> for ErrorType {
     fn into(self) -> u8 {
         match self {
@@ -184,6 +237,17 @@ impl Into<u8> for ErrorType {
             ErrorType::IpAlreadyExists => 4,
             ErrorType::InvalidIp => 5,
             ErrorType::LocalIpExists => 6,
+            ErrorType::HandshakeRejected => 7,
+            ErrorType::VersionMismatch => 8,
+            ErrorType::PunchFailed => 9,
+            ErrorType::CipherError => 10,
+            ErrorType::TunCreateFailed => 11,
+            ErrorType::IoError => 12,
+            ErrorType::KdfMismatch => 13,
+            ErrorType::ServerUnreachable => 14,
+            ErrorType::IpConflictDetected => 15,
+            ErrorType::VersionTooOld => 16,
+            ErrorType::NameDuplicated => 17,
             ErrorType::Unknown => 255,
         }
     }
@@ -266,6 +330,72 @@ impl Display for PeerClientInfo {
     }
 }
 
+#[derive(Debug)]
+pub struct RouteChangeInfo {
+    // 对端虚拟IP
+    pub virtual_ip: Ipv4Addr,
+    // 新的首选通道
+    pub address: SocketAddr,
+    pub is_tcp: bool,
+    pub metric: u8,
+    pub rt: i64,
+}
+
+impl RouteChangeInfo {
+    pub fn new(
+        virtual_ip: Ipv4Addr,
+        address: SocketAddr,
+        is_tcp: bool,
+        metric: u8,
+        rt: i64,
+    ) -> Self {
+        Self {
+            virtual_ip,
+            address,
+            is_tcp,
+            metric,
+            rt,
+        }
+    }
+}
+
+impl Display for RouteChangeInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!(
+            "ip={} ,address={}{} ,metric={} ,rt={}",
+            self.virtual_ip,
+            if self.is_tcp { "tcp@" } else { "" },
+            self.address,
+            self.metric,
+            self.rt
+        ))
+    }
+}
+
+/// auto模式下根据测速选择的加密模式
+#[derive(Clone, Debug)]
+pub struct CipherSelectInfo {
+    pub model: CipherModel,
+    pub bench: Vec<CipherBench>,
+}
+
+impl CipherSelectInfo {
+    pub fn new(model: CipherModel, bench: Vec<CipherBench>) -> Self {
+        Self { model, bench }
+    }
+}
+
+impl Display for CipherSelectInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let bench: Vec<String> = self.bench.iter().map(|v| v.to_string()).collect();
+        f.write_str(&format!(
+            "model={} ,bench=[{}]",
+            self.model,
+            bench.join(",")
+        ))
+    }
+}
+
 pub trait VntCallback: Clone + Send + Sync + 'static {
     /// 启动成功
     fn success(&self) {}
@@ -288,6 +418,10 @@ pub trait VntCallback: Clone + Send + Sync + 'static {
         0
     }
     fn peer_client_list(&self, _info: Vec<PeerClientInfo>) {}
+    /// 到对端的首选通道发生变化
+    fn route_change(&self, _info: RouteChangeInfo) {}
+    /// auto加密模式选定
+    fn cipher_select(&self, _info: CipherSelectInfo) {}
     /// 异常信息
     fn error(&self, _info: ErrorInfo) {}
     /// 服务停止
@@ -0,0 +1,85 @@
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::Arc;
+use std::thread;
+
+use crate::channel::context::ChannelContext;
+use crate::channel::handler::RecvChannelHandler;
+use crate::channel::RouteKey;
+use crate::util::LogSpan;
+
+/// 每个线程最多排队的包数，超过时读取线程等待，避免无限堆积
+const PIPELINE_DEPTH: usize = 64;
+
+// 数据、来源和分发时的日志上下文
+type Task = (Vec<u8>, RouteKey, Option<LogSpan>);
+
+/// 收到的数据按来源分发给多个线程解密和处理，读取线程可以继续读取下一个包。
+/// 同一个来源的数据总是由同一个线程处理，保证单个对端的包不乱序
+#[derive(Clone)]
+pub enum CryptoPool<H> {
+    Inline(H),
+    Pool(Arc<Vec<SyncSender<Task>>>),
+}
+
+impl<H: RecvChannelHandler> CryptoPool<H> {
+    /// num小于2时不开启线程，在读取线程直接处理
+    pub fn new(handler: H, num: usize, context: &ChannelContext) -> io::Result<Self> {
+        if num < 2 {
+            return Ok(CryptoPool::Inline(handler));
+        }
+        let mut senders = Vec::with_capacity(num);
+        for index in 0..num {
+            let (sender, receiver) = sync_channel::<Task>(PIPELINE_DEPTH);
+            let mut handler = handler.clone();
+            let context = context.clone();
+            thread::Builder::new()
+                .name(format!("cryptoWorker-{}", index))
+                .spawn(move || {
+                    // 所有读取线程退出后结束
+                    while let Ok((mut buf, route_key, span)) = receiver.recv() {
+                        let _span = span.map(|v| v.enter());
+                        handler.handle(&mut buf, route_key, &context);
+                    }
+                })?;
+            senders.push(sender);
+        }
+        Ok(CryptoPool::Pool(Arc::new(senders)))
+    }
+}
+
+impl<H: RecvChannelHandler> RecvChannelHandler for CryptoPool<H> {
+    fn handle(&mut self, buf: &mut [u8], route_key: RouteKey, context: &ChannelContext) {
+        match self {
+            CryptoPool::Inline(handler) => handler.handle(buf, route_key, context),
+            CryptoPool::Pool(senders) => {
+                if buf.len() < 12 {
+                    return;
+                }
+                let index = worker_index(&buf[4..8], senders.len());
+                if senders[index]
+                    .send((buf.to_vec(), route_key, LogSpan::current()))
+                    .is_err()
+                {
+                    log::warn!("crypto worker {} stopped", index);
+                }
+            }
+        }
+    }
+}
+
+/// 按来源的虚拟ip选择线程
+fn worker_index(source: &[u8], num: usize) -> usize {
+    let source = Ipv4Addr::new(source[0], source[1], source[2], source[3]);
+    u32::from(source) as usize % num
+}
+
+#[test]
+fn test_worker_index() {
+    let ip = |last: u8| [10, 26, 0, last];
+    assert_eq!(worker_index(&ip(2), 4), worker_index(&ip(2), 4));
+    // 连续的虚拟ip分散到不同线程
+    let list: std::collections::HashSet<usize> = (2..6).map(|v| worker_index(&ip(v), 4)).collect();
+    assert_eq!(list.len(), 4);
+}
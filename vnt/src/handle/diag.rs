@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::Instant;
+
+use parking_lot::Mutex;
+use protobuf::Message;
+
+use crate::channel::context::ChannelContext;
+use crate::channel::punch::NatType;
+use crate::cipher::Cipher;
+use crate::handle::route_report::other_turn_packet;
+use crate::nat::NatTest;
+use crate::proto::message::{DiagResponse, PunchNatType};
+use crate::protocol::{other_turn_packet, NetPacket};
+
+/// 对端返回的诊断信息
+#[derive(Clone, Debug)]
+pub struct PeerDiag {
+    pub time: Instant,
+    // 对端是否允许诊断，不允许时其他字段为空
+    pub allow: bool,
+    pub version: String,
+    pub nat_type: NatType,
+    pub public_ips: Vec<Ipv4Addr>,
+    pub public_ports: Vec<u16>,
+    pub local_ip: Ipv4Addr,
+    pub ipv6: Option<Ipv6Addr>,
+    pub cipher_model: String,
+    // 对端到当前设备的路由
+    pub p2p: bool,
+    pub metric: u8,
+    pub rt: i64,
+}
+
+/// 远程诊断，对端开启了allow_diag才会返回信息
+#[derive(Clone)]
+pub struct Diag {
+    allow: bool,
+    cipher_model: String,
+    responses: Arc<Mutex<HashMap<Ipv4Addr, PeerDiag>>>,
+}
+
+impl Diag {
+    pub fn new(allow: bool, cipher_model: String) -> Self {
+        Self {
+            allow,
+            cipher_model,
+            responses: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+    /// 请求对端的诊断信息
+    pub fn request_packet(
+        &self,
+        client_cipher: &Cipher,
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+    ) -> io::Result<NetPacket<Vec<u8>>> {
+        self.responses.lock().remove(&destination);
+        other_turn_packet(
+            client_cipher,
+            other_turn_packet::Protocol::DiagRequest,
+            source,
+            destination,
+            &[],
+        )
+    }
+    /// 回应诊断请求
+    pub fn response_packet(
+        &self,
+        context: &ChannelContext,
+        nat_test: &NatTest,
+        client_cipher: &Cipher,
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+    ) -> io::Result<NetPacket<Vec<u8>>> {
+        let mut response = DiagResponse::new();
+        response.allow = self.allow;
+        if self.allow {
+            let nat_info = nat_test.nat_info();
+            response.version = crate::VNT_VERSION.to_string();
+            response.nat_type = protobuf::EnumOrUnknown::new(PunchNatType::from(nat_info.nat_type));
+            response.public_ip_list = nat_info.public_ips.iter().map(|v| (*v).into()).collect();
+            response.public_ports = nat_info.public_ports.iter().map(|v| *v as u32).collect();
+            response.local_ip = nat_info
+                .local_ipv4()
+                .unwrap_or(Ipv4Addr::UNSPECIFIED)
+                .into();
+            if let Some(ipv6) = nat_info.ipv6() {
+                response.ipv6 = ipv6.octets().to_vec();
+            }
+            response.cipher_model = self.cipher_model.clone();
+            if let Some(route) = context.route_table.route_one(&destination) {
+                response.p2p = route.is_p2p();
+                response.metric = route.metric as u32;
+                response.rt = route.rt;
+            }
+        }
+        let bytes = response
+            .write_to_bytes()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("diag {:?}", e)))?;
+        other_turn_packet(
+            client_cipher,
+            other_turn_packet::Protocol::DiagResponse,
+            source,
+            destination,
+            &bytes,
+        )
+    }
+    pub fn handle_response(&self, source: Ipv4Addr, payload: &[u8]) -> io::Result<()> {
+        let response = DiagResponse::parse_from_bytes(payload)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("diag {:?}", e)))?;
+        let ipv6 = if response.ipv6.len() == 16 {
+            let ipv6: [u8; 16] = response.ipv6.try_into().unwrap();
+            Some(Ipv6Addr::from(ipv6))
+        } else {
+            None
+        };
+        let diag = PeerDiag {
+            time: Instant::now(),
+            allow: response.allow,
+            version: response.version,
+            nat_type: response.nat_type.enum_value_or_default().into(),
+            public_ips: response
+                .public_ip_list
+                .iter()
+                .map(|v| Ipv4Addr::from(*v))
+                .collect(),
+            public_ports: response.public_ports.iter().map(|v| *v as u16).collect(),
+            local_ip: response.local_ip.into(),
+            ipv6,
+            cipher_model: response.cipher_model,
+            p2p: response.p2p,
+            metric: response.metric as u8,
+            rt: response.rt,
+        };
+        self.responses.lock().insert(source, diag);
+        Ok(())
+    }
+    pub fn response(&self, ip: &Ipv4Addr) -> Option<PeerDiag> {
+        self.responses.lock().get(ip).cloned()
+    }
+    pub fn is_allow(&self) -> bool {
+        self.allow
+    }
+}
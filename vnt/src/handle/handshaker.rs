@@ -1,21 +1,24 @@
 use std::io;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crossbeam_utils::atomic::AtomicCell;
 use parking_lot::Mutex;
 use protobuf::Message;
+use rand::{Rng, RngCore};
+use sha2::{Digest, Sha256};
 
 use crate::channel::context::ChannelContext;
+use crate::cipher::hmac_sha256;
 #[cfg(feature = "server_encrypt")]
 use crate::cipher::RsaCipher;
 use crate::handle::{GATEWAY_IP, SELF_IP};
 use crate::proto::message::HandshakeRequest;
 #[cfg(feature = "server_encrypt")]
-use crate::proto::message::SecretHandshakeRequest;
+use crate::proto::message::{HandshakeResponse, SecretHandshakeRequest};
 #[cfg(feature = "server_encrypt")]
 use crate::protocol::body::RSA_ENCRYPTION_RESERVED;
+use crate::protocol::capability::Capabilities;
 use crate::protocol::{service_packet, NetPacket, Protocol, MAX_TTL};
 
 pub enum HandshakeEnum {
@@ -25,56 +28,285 @@ pub enum HandshakeEnum {
     ServerError(String),
     Other(String),
 }
+/// 握手重试间隔从2秒开始翻倍，最长60秒
+const RETRY_BASE: Duration = Duration::from_secs(2);
+const RETRY_MAX: Duration = Duration::from_secs(60);
+/// 连续这么多次握手没有响应后熔断，之后按RETRY_OPEN的间隔试探
+pub const BREAKER_THRESHOLD: u32 = 8;
+const RETRY_OPEN: Duration = Duration::from_secs(300);
+
+struct RetryState {
+    // 连续没有响应的次数
+    failures: u32,
+    // 下次允许发送的时间
+    next: Instant,
+    // 刚进入熔断，还没有通知
+    tripped: bool,
+}
+
+#[cfg(feature = "server_encrypt")]
+#[derive(Default)]
+struct Transcript {
+    // 最近一次加密握手的转录摘要，以及期望服务端返回的确认值
+    current: Option<([u8; 32], [u8; 32])>,
+    // 服务端声明支持校验
+    required: bool,
+    // 已经校验成功过，之后的握手都必须确认，不再依赖未认证的能力声明
+    confirmed: bool,
+}
+
 #[derive(Clone)]
 pub struct Handshake {
-    time: Arc<AtomicCell<Instant>>,
+    retry: Arc<Mutex<RetryState>>,
     rsa_cipher: Arc<Mutex<Option<RsaCipher>>>,
+    knock: Option<Arc<Knock>>,
+    #[cfg(feature = "server_encrypt")]
+    transcript: Arc<Mutex<Transcript>>,
 }
 impl Handshake {
-    pub fn new(rsa_cipher: Arc<Mutex<Option<RsaCipher>>>) -> Self {
+    pub fn new(rsa_cipher: Arc<Mutex<Option<RsaCipher>>>, knock: Option<Knock>) -> Self {
         Handshake {
-            time: Arc::new(AtomicCell::new(Instant::now() - Duration::from_secs(60))),
+            retry: Arc::new(Mutex::new(RetryState {
+                failures: 0,
+                next: Instant::now(),
+                tripped: false,
+            })),
             rsa_cipher,
+            knock: knock.map(Arc::new),
+            #[cfg(feature = "server_encrypt")]
+            transcript: Arc::new(Mutex::new(Transcript::default())),
         }
     }
+    /// 按退避间隔发送握手请求，还没到重试时间时不发送
     pub fn send(&self, context: &ChannelContext, secret: bool, addr: SocketAddr) -> io::Result<()> {
-        let last = self.time.load();
-        //短时间不重复发送
-        if last.elapsed() < Duration::from_secs(3) {
-            return Ok(());
+        self.send_all(context, secret, &[addr])
+    }
+    /// 同时向多个地址发送握手请求，只算一次重试
+    pub fn send_all(
+        &self,
+        context: &ChannelContext,
+        secret: bool,
+        addrs: &[SocketAddr],
+    ) -> io::Result<()> {
+        {
+            let mut retry = self.retry.lock();
+            let now = Instant::now();
+            if now < retry.next {
+                return Ok(());
+            }
+            retry.failures = retry.failures.saturating_add(1);
+            retry.next = now + backoff(retry.failures, rand::thread_rng().gen());
+            if retry.failures == BREAKER_THRESHOLD {
+                retry.tripped = true;
+            }
+        }
+        let request_packet = self.handshake_request_packet(secret, context.conn_ids.local())?;
+        let mut rs = Ok(());
+        for addr in addrs {
+            if let Some(knock) = &self.knock {
+                // 敲门失败时这个地址的握手也不会被接受，继续尝试其余地址
+                if let Err(e) = knock.send(context, *addr) {
+                    log::warn!("发送敲门包失败 {:?} {:?}", addr, e);
+                    rs = Err(e);
+                    continue;
+                }
+            }
+            log::info!("发送握手请求,secret={},{:?}", secret, addr);
+            if let Err(e) = context.send_default(request_packet.buffer(), *addr) {
+                rs = Err(e);
+            }
         }
-        let request_packet = self.handshake_request_packet(secret)?;
-        log::info!("发送握手请求,secret={},{:?}", secret, addr);
-        context.send_default(request_packet.buffer(), addr)?;
-        self.time.store(Instant::now());
-        Ok(())
+        rs
+    }
+    /// 连续没有响应的次数
+    pub fn failures(&self) -> u32 {
+        self.retry.lock().failures
+    }
+    /// 距离下次允许重试的时间
+    pub fn retry_delay(&self) -> Duration {
+        self.retry
+            .lock()
+            .next
+            .saturating_duration_since(Instant::now())
+    }
+    /// 收到握手响应，重置退避和熔断，返回是否是本轮重试的第一个响应
+    pub fn succeed(&self) -> bool {
+        let mut retry = self.retry.lock();
+        retry.tripped = false;
+        std::mem::replace(&mut retry.failures, 0) > 0
+    }
+    /// 网络变化后立即重试
+    pub fn retry_now(&self) {
+        let mut retry = self.retry.lock();
+        retry.failures = 0;
+        retry.tripped = false;
+        retry.next = Instant::now();
+    }
+    /// 是否刚进入熔断，每次熔断只返回一次true
+    pub fn take_tripped(&self) -> bool {
+        std::mem::replace(&mut self.retry.lock().tripped, false)
     }
     /// 第一次握手数据
-    pub fn handshake_request_packet(&self, secret: bool) -> io::Result<NetPacket<Vec<u8>>> {
-        let mut request = HandshakeRequest::new();
-        request.secret = secret;
-        request.version = crate::VNT_VERSION.to_string();
-        if let Some(finger) = self.rsa_cipher.lock().as_ref().map(|v| v.finger().clone()) {
-            request.key_finger = finger;
+    pub fn handshake_request_packet(
+        &self,
+        secret: bool,
+        conn_id: u64,
+    ) -> io::Result<NetPacket<Vec<u8>>> {
+        let finger = self.rsa_cipher.lock().as_ref().map(|v| v.finger().clone());
+        handshake_request_packet(secret, finger, conn_id)
+    }
+    /// 记录发给服务端的转录摘要，key为加密握手上传的密钥，required为服务端声明支持校验
+    #[cfg(feature = "server_encrypt")]
+    pub fn set_transcript(&self, transcript: [u8; 32], key: &[u8], required: bool) {
+        let mut guard = self.transcript.lock();
+        guard.current = Some((transcript, transcript_confirm(key, &transcript)));
+        guard.required = required;
+    }
+    #[cfg(feature = "server_encrypt")]
+    pub fn transcript(&self) -> Option<[u8; 32]> {
+        self.transcript
+            .lock()
+            .current
+            .map(|(transcript, _)| transcript)
+    }
+    /// 校验加密握手响应中服务端的确认值，确认值由上传的密钥计算，中间人无法伪造。
+    /// 服务端声明支持或者之前确认过时必须一致，旧版本服务端没有确认时放行
+    #[cfg(feature = "server_encrypt")]
+    pub fn verify_transcript(&self, confirmed: &[u8]) -> bool {
+        let mut guard = self.transcript.lock();
+        let expect = match guard.current {
+            Some((_, expect)) => expect,
+            None => return false,
+        };
+        if confirmed.is_empty() {
+            return !guard.required && !guard.confirmed;
+        }
+        if !crate::cipher::ct_eq(&expect, confirmed) {
+            return false;
+        }
+        guard.confirmed = true;
+        true
+    }
+}
+
+/// 握手请求中声明的能力
+fn request_capabilities() -> Capabilities {
+    Capabilities::BASE | Capabilities::SERVER_CONFIG | Capabilities::TRANSCRIPT
+}
+
+/// 第failures次失败后的重试间隔，在退避间隔的后一半内随机，避免大量客户端同时重连
+fn backoff(failures: u32, random: u64) -> Duration {
+    let delay = if failures >= BREAKER_THRESHOLD {
+        RETRY_OPEN
+    } else {
+        RETRY_BASE
+            .saturating_mul(1u32 << failures.saturating_sub(1).min(16))
+            .min(RETRY_MAX)
+    };
+    let half = delay.as_millis() as u64 / 2;
+    Duration::from_millis(half + random % (half + 1))
+}
+
+/// 握手请求，key_finger为已知的服务端公钥指纹，conn_id为本机的连接id
+pub fn handshake_request_packet(
+    secret: bool,
+    key_finger: Option<String>,
+    conn_id: u64,
+) -> io::Result<NetPacket<Vec<u8>>> {
+    let mut request = HandshakeRequest::new();
+    request.secret = secret;
+    request.version = crate::VNT_VERSION.to_string();
+    request.capabilities = request_capabilities().bits();
+    request.conn_id = conn_id;
+    if let Some(finger) = key_finger {
+        request.key_finger = finger;
+    }
+    let bytes = request.write_to_bytes().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!("handshake_request_packet {:?}", e),
+        )
+    })?;
+    let buf = vec![0u8; 12 + bytes.len()];
+    let mut net_packet = NetPacket::new(buf)?;
+    net_packet.set_default_version();
+    net_packet.set_gateway_flag(true);
+    net_packet.set_destination(GATEWAY_IP);
+    net_packet.set_source(SELF_IP);
+    net_packet.set_protocol(Protocol::Service);
+    net_packet.set_transport_protocol(service_packet::Protocol::HandshakeRequest.into());
+    net_packet.first_set_ttl(MAX_TTL);
+    net_packet.set_payload(&bytes)?;
+    Ok(net_packet)
+}
+
+/// 单包认证(SPA)，握手前先向服务端发送一个认证包，
+/// 自建服务端可以据此放行防火墙，端口对未认证的扫描保持关闭
+pub struct Knock {
+    key: String,
+    token: String,
+    port: Option<u16>,
+}
+
+const KNOCK_MAGIC: &[u8; 4] = b"VNTK";
+const KNOCK_LEN: usize = 4 + 8 + 8 + 8 + 32;
+
+impl Knock {
+    pub fn new(key: String, token: String, port: Option<u16>) -> Self {
+        Self { key, token, port }
+    }
+    /// 认证包总是走udp，tcp模式下也在建立连接之前发送
+    fn send(&self, context: &ChannelContext, mut addr: SocketAddr) -> io::Result<()> {
+        if let Some(port) = self.port {
+            addr.set_port(port);
         }
-        let bytes = request.write_to_bytes().map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!("handshake_request_packet {:?}", e),
-            )
-        })?;
-        let buf = vec![0u8; 12 + bytes.len()];
-        let mut net_packet = NetPacket::new(buf)?;
-        net_packet.set_default_version();
-        net_packet.set_gateway_flag(true);
-        net_packet.set_destination(GATEWAY_IP);
-        net_packet.set_source(SELF_IP);
-        net_packet.set_protocol(Protocol::Service);
-        net_packet.set_transport_protocol(service_packet::Protocol::HandshakeRequest.into());
-        net_packet.first_set_ttl(MAX_TTL);
-        net_packet.set_payload(&bytes)?;
-        Ok(net_packet)
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+            .as_secs();
+        let buf = self.packet(time, rand::thread_rng().next_u64());
+        log::info!("发送单包认证 addr={:?}", addr);
+        context.send_main_udp(0, &buf, addr)
+    }
+    /// |magic(4)|时间戳(8)|随机数(8)|token摘要(8)|hmac(32)|
+    ///
+    /// hmac = HMAC-SHA256(key, 时间戳+随机数+token)，
+    /// token摘要是sha256(token)的前8字节，服务端以此找到对应的token，不暴露token本身
+    fn packet(&self, time: u64, nonce: u64) -> [u8; KNOCK_LEN] {
+        let mut buf = [0u8; KNOCK_LEN];
+        buf[..4].copy_from_slice(KNOCK_MAGIC);
+        buf[4..12].copy_from_slice(&time.to_be_bytes());
+        buf[12..20].copy_from_slice(&nonce.to_be_bytes());
+        buf[20..28].copy_from_slice(&Sha256::digest(self.token.as_bytes())[..8]);
+        let mac = hmac_sha256(self.key.as_bytes(), &[&buf[4..20], self.token.as_bytes()]);
+        buf[28..].copy_from_slice(&mac);
+        buf
+    }
+}
+
+/// 第一次握手的转录摘要，覆盖客户端请求的加密标志和能力，以及服务端响应的版本、加密标志、能力和公钥指纹。
+/// 第二次握手时发给服务端，中间人去掉加密标志或者替换公钥后双方算出的摘要不同，服务端拒绝握手
+#[cfg(feature = "server_encrypt")]
+pub fn handshake_transcript(secret: bool, response: &HandshakeResponse) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"vnt-handshake-transcript");
+    hasher.update([secret as u8]);
+    hasher.update(request_capabilities().bits().to_be_bytes());
+    for item in [response.version.as_bytes(), response.key_finger.as_bytes()] {
+        hasher.update((item.len() as u32).to_be_bytes());
+        hasher.update(item);
     }
+    hasher.update([response.secret as u8]);
+    hasher.update(response.capabilities.to_be_bytes());
+    hasher.finalize().into()
+}
+
+/// 服务端对转录摘要的确认值，HMAC-SHA256(sha256(key), 标签+摘要)，
+/// key是用服务端公钥加密上传的会话密钥，只有持有私钥的服务端能算出
+#[cfg(feature = "server_encrypt")]
+pub fn transcript_confirm(key: &[u8], transcript: &[u8; 32]) -> [u8; 32] {
+    let key: [u8; 32] = Sha256::digest(key).into();
+    hmac_sha256(&key, &[b"vnt-handshake-confirm", transcript])
 }
 
 /// 第二次加密握手
@@ -83,10 +315,14 @@ pub fn secret_handshake_request_packet(
     rsa_cipher: &RsaCipher,
     token: String,
     key: &[u8],
+    transcript: Option<[u8; 32]>,
 ) -> io::Result<NetPacket<Vec<u8>>> {
     let mut request = SecretHandshakeRequest::new();
     request.token = token;
     request.key = key.to_vec();
+    if let Some(transcript) = transcript {
+        request.transcript = transcript.to_vec();
+    }
     let bytes = request.write_to_bytes().map_err(|e| {
         io::Error::new(
             io::ErrorKind::Other,
@@ -107,3 +343,54 @@ pub fn secret_handshake_request_packet(
     net_packet.set_payload(&bytes)?;
     Ok(rsa_cipher.encrypt(&mut net_packet)?)
 }
+
+#[test]
+fn test_backoff() {
+    assert_eq!(backoff(1, 0), Duration::from_secs(1));
+    assert!(backoff(1, u64::MAX) <= Duration::from_secs(2));
+    assert_eq!(backoff(3, 0), Duration::from_secs(4));
+    assert!(backoff(3, u64::MAX) <= Duration::from_secs(8));
+    // 不超过最大间隔
+    assert_eq!(backoff(7, 0), Duration::from_secs(30));
+    assert_eq!(backoff(BREAKER_THRESHOLD, 0), Duration::from_secs(150));
+    assert!(backoff(100, u64::MAX) <= RETRY_OPEN);
+}
+
+#[cfg(feature = "server_encrypt")]
+#[test]
+fn test_handshake_transcript() {
+    let mut response = HandshakeResponse::new();
+    response.version = "1.2.9".to_string();
+    response.secret = true;
+    response.key_finger = "finger".to_string();
+    response.capabilities = Capabilities::TRANSCRIPT.bits();
+    let transcript = handshake_transcript(true, &response);
+    let key = [7u8; 32];
+    let confirm = transcript_confirm(&key, &transcript);
+    let handshake = Handshake::new(Arc::new(Mutex::new(None)), None);
+    assert!(!handshake.verify_transcript(&confirm));
+    // 旧版本服务端不确认
+    handshake.set_transcript(transcript, &key, false);
+    assert!(handshake.verify_transcript(&[]));
+    handshake.set_transcript(transcript, &key, true);
+    // 服务端支持校验时必须确认
+    assert!(!handshake.verify_transcript(&[]));
+    // 只知道公开参数算不出确认值
+    assert!(!handshake.verify_transcript(&transcript));
+    assert!(!handshake.verify_transcript(&transcript_confirm(&[8u8; 32], &transcript)));
+    // 去掉加密标志或者替换公钥后摘要不同
+    let mut stripped = response.clone();
+    stripped.secret = false;
+    let stripped = handshake_transcript(true, &stripped);
+    assert!(!handshake.verify_transcript(&transcript_confirm(&key, &stripped)));
+    let mut swapped = response.clone();
+    swapped.key_finger = "other".to_string();
+    let swapped = handshake_transcript(true, &swapped);
+    assert!(!handshake.verify_transcript(&transcript_confirm(&key, &swapped)));
+    assert_ne!(handshake_transcript(false, &response), transcript);
+    assert!(handshake.verify_transcript(&confirm));
+    // 确认过之后，即使能力声明被去掉也必须确认
+    handshake.set_transcript(transcript, &key, false);
+    assert!(!handshake.verify_transcript(&[]));
+    assert!(handshake.verify_transcript(&confirm));
+}
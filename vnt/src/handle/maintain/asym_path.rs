@@ -0,0 +1,181 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use crate::channel::context::ChannelContext;
+use crate::channel::punch::NatInfo;
+use crate::channel::{Route, RouteKey};
+use crate::cipher::Cipher;
+use crate::handle::CurrentDeviceInfo;
+use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::protocol::control_packet::{PathProbePacket, PATH_PROBE_LEN};
+use crate::protocol::{control_packet, NetPacket, Protocol, MAX_TTL};
+
+/// 每个本地socket最多探测的候选地址数
+const MAX_CANDIDATES: usize = 8;
+/// 单向路由的延迟上限(ms)
+const MAX_RT: i64 = 5000;
+
+/// 收到对端直连的心跳或打洞请求，而自己没有可用的直连路由时，
+/// 说明只有对端到本机的方向可达，经服务端通知对端探测
+pub fn on_direct_inbound(
+    context: &ChannelContext,
+    client_cipher: &Cipher,
+    current_device: &CurrentDeviceInfo,
+    source: Ipv4Addr,
+    route_key: RouteKey,
+) -> io::Result<()> {
+    if route_key.is_tcp()
+        || route_key.addr == current_device.connect_server
+        || context.use_channel_type().is_only_relay()
+        || context.route_table.has_usable_p2p(&source)
+    {
+        return Ok(());
+    }
+    if !context.asym_paths.on_inbound(source) {
+        return Ok(());
+    }
+    log::info!(
+        "只收到对端的直连包 peer={} route={:?}，通知对端探测单向通道",
+        source,
+        route_key
+    );
+    let packet = path_packet(
+        client_cipher,
+        control_packet::Protocol::PathNotice,
+        current_device.virtual_ip,
+        source,
+        None,
+    )?;
+    context.send_default(packet.buffer(), current_device.connect_server)
+}
+
+/// 对端通知它能收到本机的直连包，从每个本地socket向对端的候选地址发送探测
+pub fn on_path_notice(
+    context: &ChannelContext,
+    client_cipher: &Cipher,
+    current_device: &CurrentDeviceInfo,
+    source: Ipv4Addr,
+    nat_info: Option<NatInfo>,
+) -> io::Result<()> {
+    if context.use_channel_type().is_only_relay() {
+        return Ok(());
+    }
+    let nat_info = match nat_info {
+        Some(nat_info) => nat_info,
+        None => return Ok(()),
+    };
+    let nonce = match context.asym_paths.start_probe(source, rand::random()) {
+        Some(nonce) => nonce,
+        None => return Ok(()),
+    };
+    let candidates = candidates(&nat_info);
+    log::info!("探测单向通道 peer={} candidates={:?}", source, candidates);
+    for index in 0..context.channel_num() {
+        for addr in &candidates {
+            let packet = path_packet(
+                client_cipher,
+                control_packet::Protocol::PathProbe,
+                current_device.virtual_ip,
+                source,
+                Some((nonce, *addr, index as u8)),
+            )?;
+            if let Err(e) = context.send_main_udp(index, packet.buffer(), SocketAddr::V4(*addr)) {
+                log::warn!("单向通道探测 {:?} {:?}", addr, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 收到直连的探测，经服务端原样带回
+pub fn on_path_probe<B: AsRef<[u8]>>(
+    context: &ChannelContext,
+    client_cipher: &Cipher,
+    current_device: &CurrentDeviceInfo,
+    source: Ipv4Addr,
+    probe: &PathProbePacket<B>,
+) -> io::Result<()> {
+    let packet = path_packet(
+        client_cipher,
+        control_packet::Protocol::PathConfirm,
+        current_device.virtual_ip,
+        source,
+        Some((probe.nonce(), probe.destination(), probe.index())),
+    )?;
+    context.send_default(packet.buffer(), current_device.connect_server)
+}
+
+/// 对端确认收到了探测，添加只用于发送的直连路由，对端发来的数据仍然走中转
+pub fn on_path_confirm<B: AsRef<[u8]>>(
+    context: &ChannelContext,
+    source: Ipv4Addr,
+    probe: &PathProbePacket<B>,
+) {
+    let index = probe.index() as usize;
+    if index >= context.channel_num() || context.use_channel_type().is_only_relay() {
+        return;
+    }
+    let route_key = RouteKey::new(false, index, SocketAddr::V4(probe.destination()));
+    if let Some(elapsed) = context.asym_paths.confirm(source, probe.nonce(), route_key) {
+        // 确认经过服务端，按一半估算直连的延迟
+        let rt = (elapsed.as_millis() as i64 / 2).clamp(1, MAX_RT);
+        log::info!(
+            "单向直连通道 peer={} route={:?} rt={}",
+            source,
+            route_key,
+            rt
+        );
+        context
+            .route_table
+            .add_route(source, Route::from(route_key, 1, rt));
+    }
+}
+
+/// 对端的候选地址，同一个NAT后面时加上内网地址
+fn candidates(nat_info: &NatInfo) -> Vec<SocketAddrV4> {
+    let mut list = Vec::new();
+    if nat_info.same_nat {
+        if let Some(local_ip) = nat_info.local_ipv4 {
+            for port in &nat_info.udp_ports {
+                list.push(SocketAddrV4::new(local_ip, *port));
+            }
+        }
+    }
+    for ip in &nat_info.public_ips {
+        for port in &nat_info.public_ports {
+            if *port != 0 {
+                list.push(SocketAddrV4::new(*ip, *port));
+            }
+        }
+    }
+    list.dedup();
+    list.truncate(MAX_CANDIDATES);
+    list
+}
+
+fn path_packet(
+    client_cipher: &Cipher,
+    protocol: control_packet::Protocol,
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    probe: Option<(u32, SocketAddrV4, u8)>,
+) -> io::Result<NetPacket<[u8; 12 + PATH_PROBE_LEN + ENCRYPTION_RESERVED]>> {
+    let mut packet = NetPacket::new_encrypt([0; 12 + PATH_PROBE_LEN + ENCRYPTION_RESERVED])?;
+    packet.set_default_version();
+    packet.set_protocol(Protocol::Control);
+    packet.set_transport_protocol(protocol.into());
+    packet.first_set_ttl(MAX_TTL);
+    packet.set_source(source);
+    packet.set_destination(destination);
+    match probe {
+        Some((nonce, addr, index)) => {
+            let mut probe = PathProbePacket::new(packet.payload_mut())?;
+            probe.set_nonce(nonce);
+            probe.set_destination(addr);
+            probe.set_index(index);
+        }
+        None => packet.set_data_len(12)?,
+    }
+    client_cipher.encrypt_ipv4(&mut packet)?;
+    Ok(packet)
+}
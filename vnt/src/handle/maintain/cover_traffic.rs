@@ -0,0 +1,83 @@
+use std::io;
+use std::sync::Arc;
+
+use crossbeam_utils::atomic::AtomicCell;
+use rand::Rng;
+
+use crate::channel::context::ChannelContext;
+use crate::cipher::{Cipher, SEQ_LEN};
+use crate::handle::CurrentDeviceInfo;
+use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::protocol::capability::Capabilities;
+use crate::protocol::{ip_turn_packet, NetPacket, Protocol};
+use crate::util::Scheduler;
+
+/// 掩护流量，按设置的速率随机向一个直连的设备发送填充过的空包，
+/// 对端解密后丢弃
+pub fn cover_traffic(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    client_cipher: Cipher,
+) {
+    let interval = match context.padding.cover_interval() {
+        Some(interval) => interval,
+        None => return,
+    };
+    if let Err(e) = send_cover(&context, &current_device.load(), &client_cipher) {
+        log::warn!("掩护流量 {:?}", e);
+    }
+    let rs = scheduler.timeout(interval, move |s| {
+        cover_traffic(s, context, current_device, client_cipher)
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
+
+fn send_cover(
+    context: &ChannelContext,
+    current_device: &CurrentDeviceInfo,
+    client_cipher: &Cipher,
+) -> io::Result<()> {
+    if current_device.status.offline() {
+        return Ok(());
+    }
+    let peers: Vec<_> = context
+        .route_table
+        .route_table_p2p()
+        .into_iter()
+        .map(|(ip, _)| ip)
+        .filter(|ip| {
+            *ip != current_device.virtual_gateway
+                && context.capabilities.supports(ip, Capabilities::PADDING)
+        })
+        .collect();
+    if peers.is_empty() {
+        return Ok(());
+    }
+    let mut rng = rand::thread_rng();
+    let dest_ip = peers[rng.gen_range(0..peers.len())];
+    let len = context.padding.cover_len(rng.gen());
+    let mut buf = vec![0u8; 12 + len + SEQ_LEN + ENCRYPTION_RESERVED];
+    let mut net_packet = NetPacket::new0(12 + len, &mut buf[..])?;
+    net_packet.set_default_version();
+    net_packet.set_protocol(Protocol::IpTurn);
+    net_packet.set_transport_protocol(ip_turn_packet::Protocol::Ipv4Padded.into());
+    net_packet.first_set_ttl(6);
+    net_packet.set_source(current_device.virtual_ip);
+    net_packet.set_destination(dest_ip);
+    if let Some(replay_guard) = &context.replay_guard {
+        replay_guard.seal(&mut net_packet)?;
+    }
+    context
+        .pairwise_cipher
+        .get(&dest_ip, client_cipher)
+        .encrypt_ipv4(&mut net_packet)?;
+    context.send_ipv4_by_id(
+        net_packet.buffer(),
+        &dest_ip,
+        current_device.connect_server,
+        false,
+    )
+}
@@ -8,13 +8,22 @@ use parking_lot::Mutex;
 use rand::prelude::SliceRandom;
 
 use crate::channel::context::ChannelContext;
+use crate::channel::RouteKey;
 use crate::cipher::Cipher;
 use crate::handle::{CurrentDeviceInfo, PeerDeviceInfo};
 use crate::protocol::body::ENCRYPTION_RESERVED;
-use crate::protocol::control_packet::PingPacket;
+use crate::protocol::capability::Capabilities;
+use crate::protocol::control_packet::{
+    PingPacket, PongPacket, PING_LEN, PING_SIGNED_LEN, PING_TIME32_LEN,
+};
 use crate::protocol::{control_packet, NetPacket, Protocol};
 use crate::util::Scheduler;
 
+/// 超过这个值的延迟视为无效
+const MAX_RT: i64 = 30_000;
+/// 中转选择参考的路由上报的有效期
+const MATRIX_MAX_AGE: Duration = Duration::from_secs(60);
+
 /// 定时发送心跳包
 pub fn heartbeat(
     scheduler: &Scheduler,
@@ -24,22 +33,46 @@ pub fn heartbeat(
     client_cipher: Cipher,
     server_cipher: Cipher,
 ) {
+    heartbeat_(
+        scheduler,
+        context,
+        current_device_info,
+        device_list,
+        client_cipher,
+        server_cipher,
+        0,
+    )
+}
+
+fn heartbeat_(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    current_device_info: Arc<AtomicCell<CurrentDeviceInfo>>,
+    device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
+    client_cipher: Cipher,
+    server_cipher: Cipher,
+    count: usize,
+) {
+    // 省电模式下只保持和服务端的心跳，客户端间的心跳降低频率
+    let send_peer = !context.power_save.skip(count);
     heartbeat0(
         &context,
         &current_device_info.load(),
         &device_list,
         &client_cipher,
         &server_cipher,
+        send_peer,
     );
     // 心跳包 3秒发送一次
-    let rs = scheduler.timeout(Duration::from_secs(3), |s| {
-        heartbeat(
+    let rs = scheduler.timeout(Duration::from_secs(3), move |s| {
+        heartbeat_(
             s,
             context,
             current_device_info,
             device_list,
             client_cipher,
             server_cipher,
+            count.wrapping_add(1),
         )
     });
     if !rs {
@@ -53,6 +86,7 @@ fn heartbeat0(
     device_list: &Mutex<(u16, Vec<PeerDeviceInfo>)>,
     client_cipher: &Cipher,
     server_cipher: &Cipher,
+    send_peer: bool,
 ) {
     let gateway_ip = current_device.virtual_gateway;
     let src_ip = current_device.virtual_ip;
@@ -78,8 +112,10 @@ fn heartbeat0(
                 continue;
             }
             heartbeat_packet_server(device_list, server_cipher, src_ip, gateway_ip)
+        } else if !send_peer {
+            continue;
         } else {
-            heartbeat_packet_client(client_cipher, src_ip, dest_ip)
+            heartbeat_packet_client(context, client_cipher, src_ip, dest_ip)
         };
         let net_packet = match net_packet {
             Ok(net_packet) => net_packet,
@@ -91,9 +127,14 @@ fn heartbeat0(
         for route in routes {
             if let Err(e) = context.send_by_key(net_packet.buffer(), route.route_key()) {
                 log::warn!("heartbeat err={:?}", e)
+            } else {
+                context.hold_punch.sent(route.route_key(), false);
             }
         }
     }
+    if !send_peer {
+        return;
+    }
     let peer_list = { device_list.lock().1.clone() };
     for peer in &peer_list {
         if !peer.status.is_online() {
@@ -107,13 +148,14 @@ fn heartbeat0(
         }
         if context.route_table.route_one(&peer.virtual_ip).is_none() {
             //路由为空，则向服务端地址发送
-            let net_packet = match heartbeat_packet_client(client_cipher, src_ip, peer.virtual_ip) {
-                Ok(net_packet) => net_packet,
-                Err(e) => {
-                    log::error!("heartbeat_packet err={:?}", e);
-                    continue;
-                }
-            };
+            let net_packet =
+                match heartbeat_packet_client(context, client_cipher, src_ip, peer.virtual_ip) {
+                    Ok(net_packet) => net_packet,
+                    Err(e) => {
+                        log::error!("heartbeat_packet err={:?}", e);
+                        continue;
+                    }
+                };
             if let Err(e) = context.send_default(net_packet.buffer(), current_device.connect_server)
             {
                 log::error!("heartbeat_packet send_default err={:?}", e);
@@ -122,6 +164,67 @@ fn heartbeat0(
     }
 }
 
+/// p2p路由保活，心跳间隔内没有发送过数据的路由补发心跳
+pub fn hold_punch(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    client_cipher: Cipher,
+) {
+    if !context.hold_punch.is_enable() {
+        return;
+    }
+    hold_punch0(&context, &current_device.load(), &client_cipher);
+    let rs = scheduler.timeout(Duration::from_secs(1), move |s| {
+        hold_punch(s, context, current_device, client_cipher)
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
+
+fn hold_punch0(
+    context: &ChannelContext,
+    current_device: &CurrentDeviceInfo,
+    client_cipher: &Cipher,
+) {
+    if current_device.status.offline() || context.power_save.is_low_power() {
+        // 低功耗时只靠放大间隔后的心跳保活
+        return;
+    }
+    let routes: Vec<(Ipv4Addr, RouteKey)> = context
+        .route_table
+        .route_table_p2p()
+        .into_iter()
+        .filter(|(ip, route)| !route.is_tcp && !current_device.is_gateway(ip))
+        .map(|(ip, route)| (ip, route.route_key()))
+        .collect();
+    let keys: Vec<RouteKey> = routes.iter().map(|(_, key)| *key).collect();
+    for route_key in context.hold_punch.due(&keys) {
+        let dest_ip = match routes.iter().find(|(_, key)| *key == route_key) {
+            Some((ip, _)) => *ip,
+            None => continue,
+        };
+        let net_packet = match heartbeat_packet_client(
+            context,
+            client_cipher,
+            current_device.virtual_ip,
+            dest_ip,
+        ) {
+            Ok(net_packet) => net_packet,
+            Err(e) => {
+                log::error!("hold_punch packet err={:?}", e);
+                continue;
+            }
+        };
+        if let Err(e) = context.send_by_key(net_packet.buffer(), route_key) {
+            log::warn!("hold_punch err={:?}", e)
+        } else {
+            context.hold_punch.sent(route_key, true);
+        }
+    }
+}
+
 /// 客户端中继路径探测,延迟启动
 pub fn client_relay(
     scheduler: &Scheduler,
@@ -146,7 +249,9 @@ fn client_relay_(
     device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
     client_cipher: Cipher,
 ) {
-    if let Err(e) = client_relay0(
+    if context.power_save.is_saving() {
+        // 省电模式下不探测
+    } else if let Err(e) = client_relay0(
         &context,
         &current_device.load(),
         &device_list,
@@ -186,11 +291,28 @@ fn client_relay0(
         {
             continue;
         }
-        let client_packet =
-            heartbeat_packet_client(client_cipher, current_device.virtual_ip, peer.virtual_ip)?;
+        let client_packet = heartbeat_packet_client(
+            context,
+            client_cipher,
+            current_device.virtual_ip,
+            peer.virtual_ip,
+        )?;
 
         //随机发送到其他地址，看有没有客户端符合转发条件
         routes.shuffle(&mut rand::thread_rng());
+        // 可达矩阵中延迟最低的中转设备优先
+        let local: Vec<(Ipv4Addr, i64)> =
+            routes.iter().map(|(ip, route)| (*ip, route.rt)).collect();
+        if let Some(relay) =
+            context
+                .route_table
+                .route_matrix
+                .best_relay(&local, peer.virtual_ip, MATRIX_MAX_AGE)
+        {
+            if let Some(index) = routes.iter().position(|(ip, _)| *ip == relay) {
+                routes.swap(0, index);
+            }
+        }
 
         for (index, (ip, route)) in routes.iter().enumerate() {
             if current_device.is_gateway(ip) {
@@ -207,12 +329,16 @@ fn client_relay0(
     Ok(())
 }
 
+type HeartbeatPacket =
+    NetPacket<[u8; 12 + PING_SIGNED_LEN + PING_TIME32_LEN + ENCRYPTION_RESERVED]>;
+
 /// 构建心跳包
-fn heartbeat_packet(
-    src: Ipv4Addr,
-    dest: Ipv4Addr,
-) -> io::Result<NetPacket<[u8; 12 + 4 + ENCRYPTION_RESERVED]>> {
-    let mut net_packet = NetPacket::new_encrypt([0u8; 12 + 4 + ENCRYPTION_RESERVED])?;
+fn heartbeat_packet(src: Ipv4Addr, dest: Ipv4Addr) -> io::Result<HeartbeatPacket> {
+    let mut net_packet = NetPacket::new_encrypt(
+        [0u8; 12 + PING_SIGNED_LEN + PING_TIME32_LEN + ENCRYPTION_RESERVED],
+    )?;
+    // 默认不带flags
+    net_packet.set_data_len(12 + 4)?;
     net_packet.set_default_version();
     net_packet.set_protocol(Protocol::Control);
     net_packet.set_transport_protocol(control_packet::Protocol::Ping.into());
@@ -224,12 +350,35 @@ fn heartbeat_packet(
     Ok(net_packet)
 }
 
-fn heartbeat_packet_client(
+pub(crate) fn heartbeat_packet_client(
+    context: &ChannelContext,
     client_cipher: &Cipher,
     src: Ipv4Addr,
     dest: Ipv4Addr,
-) -> io::Result<NetPacket<[u8; 12 + 4 + ENCRYPTION_RESERVED]>> {
+) -> io::Result<HeartbeatPacket> {
     let mut net_packet = heartbeat_packet(src, dest)?;
+    net_packet.set_data_len(12 + PING_LEN)?;
+    let mut ping = PingPacket::new(net_packet.payload_mut())?;
+    ping.set_flags(Capabilities::local(context.coalesce.is_enable()).ping_flags());
+    if context.peer_auth.is_enable() {
+        // 签名覆盖time、epoch和flags
+        if let Some(signature) = context.peer_auth.sign(
+            Protocol::Control.into(),
+            control_packet::Protocol::Ping.into(),
+            src,
+            dest,
+            net_packet.payload(),
+        ) {
+            net_packet.set_data_len(12 + PING_SIGNED_LEN)?;
+            let mut ping = PingPacket::new(net_packet.payload_mut())?;
+            ping.set_signature(&signature);
+        }
+    }
+    // 32位时间戳放在最后，不影响旧版本解析签名
+    let len = net_packet.payload().len();
+    net_packet.set_data_len(12 + len + PING_TIME32_LEN)?;
+    let mut ping = PingPacket::new(net_packet.payload_mut())?;
+    ping.set_time32(crate::handle::now_time() as u32);
     client_cipher.encrypt_ipv4(&mut net_packet)?;
     Ok(net_packet)
 }
@@ -239,7 +388,7 @@ fn heartbeat_packet_server(
     server_cipher: &Cipher,
     src: Ipv4Addr,
     dest: Ipv4Addr,
-) -> io::Result<NetPacket<[u8; 12 + 4 + ENCRYPTION_RESERVED]>> {
+) -> io::Result<HeartbeatPacket> {
     let mut net_packet = heartbeat_packet(src, dest)?;
     let mut ping = PingPacket::new(net_packet.payload_mut())?;
     ping.set_epoch(device_list.lock().0);
@@ -247,3 +396,50 @@ fn heartbeat_packet_server(
     server_cipher.encrypt_ipv4(&mut net_packet)?;
     Ok(net_packet)
 }
+
+/// 根据回应中原样带回的时间戳计算延迟，只使用本地时钟，和对端时钟无关
+pub(crate) fn pong_rt<B: AsRef<[u8]>>(pong_packet: &PongPacket<B>) -> Option<i64> {
+    pong_rt_at(pong_packet, crate::handle::now_time())
+}
+
+fn pong_rt_at<B: AsRef<[u8]>>(pong_packet: &PongPacket<B>, now: u64) -> Option<i64> {
+    let rt = match pong_packet.time32() {
+        Some(time) => (now as u32).wrapping_sub(time) as i64,
+        // 旧版本只带回16位时间，回绕后依然能算出65秒以内的延迟
+        None => (now as u16).wrapping_sub(pong_packet.time()) as i64,
+    };
+    // 本地时钟回拨等情况会得到很大的值，丢弃
+    if rt > MAX_RT {
+        return None;
+    }
+    Some(rt)
+}
+
+#[test]
+fn test_pong_rt() {
+    use crate::protocol::control_packet::PING_FLAG_TIME32;
+    let pong32 = |time: u32, now: u64| {
+        let mut buf = [0u8; PING_LEN + PING_TIME32_LEN];
+        let mut pong = PongPacket::new(&mut buf[..]).unwrap();
+        pong.set_flags(PING_FLAG_TIME32);
+        pong.set_time32(time);
+        pong_rt_at(&PongPacket::new(&buf[..]).unwrap(), now)
+    };
+    let now = 1_700_000_000_000u64;
+    assert_eq!(pong32((now - 100) as u32, now), Some(100));
+    // 32位时间戳回绕
+    let wrap = (1u64 << 32) * 400 + 20;
+    assert_eq!(pong32(u32::MAX - 9, wrap), Some(30));
+    // 超过MAX_RT的丢弃，本地时钟回拨时同样很大
+    assert_eq!(pong32((now - MAX_RT as u64) as u32, now), Some(MAX_RT));
+    assert_eq!(pong32((now - MAX_RT as u64 - 1) as u32, now), None);
+    assert_eq!(pong32((now + 1) as u32, now), None);
+    // 旧版本只有16位时间
+    let mut buf = [0u8; 4];
+    let mut pong = PongPacket::new(&mut buf[..]).unwrap();
+    pong.set_time((now - 50) as u16);
+    assert_eq!(
+        pong_rt_at(&PongPacket::new(&buf[..]).unwrap(), now),
+        Some(50)
+    );
+}
@@ -9,11 +9,12 @@ use mio::net::TcpStream;
 use crate::channel::context::ChannelContext;
 use crate::channel::idle::{Idle, IdleType};
 use crate::channel::sender::AcceptSocketSender;
-use crate::handle::callback::{ConnectInfo, ErrorType};
-use crate::handle::handshaker::Handshake;
+use crate::handle::callback::{ConnectInfo, ErrorInfo, ErrorType, RouteChangeInfo};
+use crate::handle::handshaker::{Handshake, BREAKER_THRESHOLD};
+use crate::handle::server_ports::PROBE_AFTER;
 use crate::handle::{BaseConfigInfo, ConnectStatus, CurrentDeviceInfo};
 use crate::util::{address_choose, dns_query_all, Scheduler};
-use crate::{ErrorInfo, VntCallback};
+use crate::{VntCallback, VntError};
 
 pub fn idle_route<Call: VntCallback>(
     scheduler: &Scheduler,
@@ -41,7 +42,7 @@ pub fn idle_gateway<Call: VntCallback>(
     mut connect_count: usize,
     handshake: Handshake,
 ) {
-    idle_gateway0(
+    let delay = idle_gateway0(
         &context,
         &current_device_info,
         &config,
@@ -50,7 +51,7 @@ pub fn idle_gateway<Call: VntCallback>(
         &mut connect_count,
         &handshake,
     );
-    let rs = scheduler.timeout(Duration::from_secs(5), move |s| {
+    let rs = scheduler.timeout(delay, move |s| {
         idle_gateway(
             s,
             context,
@@ -75,7 +76,7 @@ fn idle_gateway0<Call: VntCallback>(
     call: &Call,
     connect_count: &mut usize,
     handshake: &Handshake,
-) {
+) -> Duration {
     if let Err(e) = check_gateway_channel(
         context,
         current_device,
@@ -85,12 +86,33 @@ fn idle_gateway0<Call: VntCallback>(
         connect_count,
         handshake,
     ) {
+        let cur = current_device.load();
+        call.error(
+            VntError::Disconnect(Some(format!(
+                "connect:{},error:{:?}",
+                cur.connect_server, e
+            )))
+            .into(),
+        );
+    }
+    if handshake.take_tripped() {
         let cur = current_device.load();
         call.error(ErrorInfo::new_msg(
-            ErrorType::Disconnect,
-            format!("connect:{},error:{:?}", cur.connect_server, e),
+            ErrorType::ServerUnreachable,
+            format!(
+                "connect:{},no handshake response after {} attempts",
+                cur.connect_server, BREAKER_THRESHOLD
+            ),
         ));
     }
+    // 离线时按握手的退避间隔检查
+    if current_device.load().status.offline() {
+        handshake
+            .retry_delay()
+            .clamp(Duration::from_millis(100), Duration::from_secs(5))
+    } else {
+        Duration::from_secs(5)
+    }
 }
 
 fn idle_route0<Call: VntCallback>(
@@ -99,15 +121,26 @@ fn idle_route0<Call: VntCallback>(
     current_device: &AtomicCell<CurrentDeviceInfo>,
     call: &Call,
 ) -> Duration {
+    for (ip, route) in context.route_table.hysteresis.take_changes() {
+        call.route_change(RouteChangeInfo::new(
+            ip,
+            route.addr,
+            route.is_tcp,
+            route.metric,
+            route.rt,
+        ));
+    }
+    context.route_table.expire_punch();
     let cur = current_device.load();
     match idle.next_idle() {
         IdleType::Timeout(ip, route) => {
-            log::info!("route Timeout {:?},{:?}", ip, route);
+            log::info!("路由超时 peer={} route={:?}", ip, route.route_key());
             context.remove_route(&ip, route.route_key());
+            context.hold_punch.on_timeout(&route.route_key());
             if cur.is_gateway(&ip) {
                 //网关路由过期，则需要改变状态
                 crate::handle::change_status(current_device, ConnectStatus::Connecting);
-                call.error(ErrorInfo::new(ErrorType::Disconnect));
+                call.error(VntError::Disconnect(None).into());
             }
             Duration::from_millis(100)
         }
@@ -126,27 +159,50 @@ fn check_gateway_channel<Call: VntCallback>(
     handshake: &Handshake,
 ) -> io::Result<()> {
     let mut current_device = current_device_info.load();
-    if current_device.status.offline() {
+    if current_device.status.offline() && handshake.retry_delay().is_zero() {
         *count += 1;
         // 探测服务器地址
         current_device = domain_request0(current_device_info, config);
         //需要重连
         call.connect(ConnectInfo::new(*count, current_device.connect_server));
         log::info!("发送握手请求,{:?}", config);
-        if let Err(e) = handshake.send(context, config.server_secret, current_device.connect_server)
+        // 首次连接或者连续失败时同时探测所有备用端口
+        let mut addrs = if config.server_ports.is_enable()
+            && (*count == 1 || handshake.failures() >= PROBE_AFTER)
         {
+            config
+                .server_ports
+                .candidates(current_device.connect_server)
+        } else {
+            vec![current_device.connect_server]
+        };
+        // 连续握手失败时同时尝试对端通告的服务端地址
+        if handshake.failures() >= PROBE_AFTER {
+            if let Some(addr) = config.server_hints.candidate(current_device.connect_server) {
+                if !addrs.contains(&addr) {
+                    log::info!("尝试对端通告的服务端地址:{}", addr);
+                    addrs.push(addr);
+                }
+            }
+        }
+        if let Err(e) = handshake.send_all(context, config.server_secret, &addrs) {
             log::warn!("{:?}", e);
             if context.is_main_tcp() {
-                let request_packet = handshake.handshake_request_packet(config.server_secret)?;
+                let request_packet = handshake
+                    .handshake_request_packet(config.server_secret, context.conn_ids.local())?;
                 //tcp需要重连
-                let tcp_stream = std::net::TcpStream::connect_timeout(
-                    &current_device.connect_server,
-                    Duration::from_secs(5),
-                )?;
+                let (tcp_stream, addr) = if addrs.len() > 1 {
+                    connect_server_any(context, config, &addrs)?
+                } else {
+                    (
+                        connect_server(context, config, current_device.connect_server)?,
+                        current_device.connect_server,
+                    )
+                };
                 tcp_stream.set_nonblocking(true)?;
                 if let Err(e) = tcp_socket_sender.try_add_socket((
                     TcpStream::from_std(tcp_stream),
-                    current_device.connect_server,
+                    addr,
                     Some(request_packet.into_buffer()),
                 )) {
                     log::warn!("{:?}", e)
@@ -173,8 +229,12 @@ pub fn domain_request0(
                 addrs
             );
 
-            match address_choose(addrs) {
-                Ok(addr) => {
+            match address_choose(addrs, config.prefer_ipv6_server) {
+                Ok(mut addr) => {
+                    // 使用当前网络下探测到的可用端口
+                    if let Some(port) = config.server_ports.working() {
+                        addr.set_port(port);
+                    }
                     if addr != current_dev.connect_server {
                         let mut tmp = current_dev.clone();
                         tmp.connect_server = addr;
@@ -201,3 +261,72 @@ pub fn domain_request0(
     }
     current_dev
 }
+
+/// 同时连接服务器的多个端口，返回最先连上的
+fn connect_server_any(
+    context: &ChannelContext,
+    config: &BaseConfigInfo,
+    addrs: &[SocketAddr],
+) -> io::Result<(std::net::TcpStream, SocketAddr)> {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    for addr in addrs {
+        let addr = *addr;
+        let sender = sender.clone();
+        let context = context.clone();
+        let config = config.clone();
+        std::thread::Builder::new()
+            .name("serverPortProbe".into())
+            .spawn(move || {
+                let rs = connect_server(&context, &config, addr);
+                let _ = sender.send(rs.map(|stream| (stream, addr)));
+            })?;
+    }
+    drop(sender);
+    let mut last_err = io::Error::new(io::ErrorKind::Other, "no server port");
+    // 其他连上的连接随发送端丢弃
+    for rs in receiver {
+        match rs {
+            Ok(rs) => {
+                log::info!("服务端端口探测成功 {}", rs.1);
+                return Ok(rs);
+            }
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+/// 连接服务器，配置了代理则先走代理，代理失败再直连
+fn connect_server(
+    context: &ChannelContext,
+    config: &BaseConfigInfo,
+    addr: SocketAddr,
+) -> io::Result<std::net::TcpStream> {
+    if let Some(server_proxy) = &config.server_proxy {
+        match server_proxy.connect(addr, Duration::from_secs(5)) {
+            Ok(tcp_stream) => return Ok(tcp_stream),
+            Err(e) => {
+                log::warn!("代理连接失败,改为直连 proxy={} err={:?}", server_proxy, e);
+            }
+        }
+    }
+    match context.listen_ip() {
+        // 指定了监听地址时，从这个地址连接服务器
+        Some(ip) if ip.is_ipv4() == addr.is_ipv4() && !ip.is_unspecified() => {
+            let socket = socket2::Socket::new(
+                socket2::Domain::for_address(addr),
+                socket2::Type::STREAM,
+                None,
+            )?;
+            socket.bind(&SocketAddr::new(ip, 0).into())?;
+            context.qos.mark(socket2::SockRef::from(&socket));
+            socket.connect_timeout(&addr.into(), Duration::from_secs(5))?;
+            Ok(socket.into())
+        }
+        _ => {
+            let stream = std::net::TcpStream::connect_timeout(&addr, Duration::from_secs(5))?;
+            context.qos.mark(socket2::SockRef::from(&stream));
+            Ok(stream)
+        }
+    }
+}
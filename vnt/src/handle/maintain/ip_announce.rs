@@ -0,0 +1,178 @@
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossbeam_utils::atomic::AtomicCell;
+use parking_lot::Mutex;
+
+use crate::channel::context::ChannelContext;
+use crate::channel::RouteKey;
+use crate::cipher::Cipher;
+use crate::handle::{change_status, ConnectStatus, CurrentDeviceInfo, PeerDeviceInfo};
+use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::protocol::control_packet::{InstancePacket, INSTANCE_LEN};
+use crate::protocol::{control_packet, NetPacket, Protocol, MAX_TTL};
+use crate::util::Scheduler;
+use crate::{VntCallback, VntError};
+
+/// 每隔这么多次检查通告一次，检查间隔5秒
+const ANNOUNCE_TICKS: usize = 6;
+
+/// ip通告，定时向在线设备和自己的ip(经服务端转发给注册了这个ip的实例)发送实例id，
+/// 并处理检测到的ip冲突
+pub fn ip_announce<Call: VntCallback>(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
+    client_cipher: Cipher,
+    call: Call,
+    mut tick: usize,
+    mut announced: Ipv4Addr,
+) {
+    let current = current_device.load();
+    if let Some(other) = context.ip_conflict.take_detected() {
+        on_conflict(&context, &current_device, &current, &call, other);
+    } else if current.status.online() {
+        // ip变化后立即通告
+        if tick == 0 || announced != current.virtual_ip {
+            announced = current.virtual_ip;
+            if let Err(e) = send_announce(&context, &current, &device_list, &client_cipher) {
+                log::warn!("ip通告 {:?}", e);
+            }
+        }
+        tick = (tick + 1) % ANNOUNCE_TICKS;
+    }
+    let rs = scheduler.timeout(Duration::from_secs(5), move |s| {
+        ip_announce(
+            s,
+            context,
+            current_device,
+            device_list,
+            client_cipher,
+            call,
+            tick,
+            announced,
+        )
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
+
+fn on_conflict<Call: VntCallback>(
+    context: &ChannelContext,
+    current_device: &AtomicCell<CurrentDeviceInfo>,
+    current: &CurrentDeviceInfo,
+    call: &Call,
+    other: u64,
+) {
+    log::warn!(
+        "虚拟ip冲突 ip={} instance={:x} other={:x}",
+        current.virtual_ip,
+        context.ip_conflict.instance_id(),
+        other
+    );
+    call.error(VntError::IpConflictDetected(current.virtual_ip).into());
+    if context.ip_conflict.should_reassign(other) {
+        context.ip_conflict.set_reassigned();
+        log::warn!("使用新的设备id重新注册，建议使用-d指定不同的设备id");
+        // 断开后由服务端重连任务重新握手和注册
+        change_status(current_device, ConnectStatus::Connecting);
+    }
+}
+
+fn send_announce(
+    context: &ChannelContext,
+    current_device: &CurrentDeviceInfo,
+    device_list: &Mutex<(u16, Vec<PeerDeviceInfo>)>,
+    client_cipher: &Cipher,
+) -> io::Result<()> {
+    let instance_id = context.ip_conflict.instance_id();
+    // 发给自己的ip只能经过服务端
+    let packet = instance_packet(
+        client_cipher,
+        control_packet::Protocol::IpAnnounce,
+        current_device.virtual_ip,
+        current_device.virtual_ip,
+        instance_id,
+    )?;
+    context.send_default(packet.buffer(), current_device.connect_server)?;
+    let peer_list = { device_list.lock().1.clone() };
+    for peer in &peer_list {
+        if !peer.status.is_online() || peer.virtual_ip == current_device.virtual_ip {
+            continue;
+        }
+        let packet = instance_packet(
+            client_cipher,
+            control_packet::Protocol::IpAnnounce,
+            current_device.virtual_ip,
+            peer.virtual_ip,
+            instance_id,
+        )?;
+        context.send_ipv4_by_id(
+            packet.buffer(),
+            &peer.virtual_ip,
+            current_device.connect_server,
+            true,
+        )?;
+    }
+    Ok(())
+}
+
+/// 收到对端的ip通告，发给自己的ip但实例id不同时是冲突，
+/// 对端的两个实例交替出现时分别通知它们
+pub fn handle_ip_announce(
+    context: &ChannelContext,
+    client_cipher: &Cipher,
+    current_device: &CurrentDeviceInfo,
+    source: Ipv4Addr,
+    instance_id: u64,
+    route_key: RouteKey,
+) -> io::Result<()> {
+    if source == current_device.virtual_ip {
+        context.ip_conflict.detect(instance_id);
+        return Ok(());
+    }
+    let notify = match context.ip_conflict.on_announce(
+        source,
+        instance_id,
+        route_key,
+        crate::handle::now_time(),
+    ) {
+        Some(notify) => notify,
+        None => return Ok(()),
+    };
+    log::warn!("设备{}的ip被两个实例同时使用", source);
+    for (route_key, other) in notify {
+        let packet = instance_packet(
+            client_cipher,
+            control_packet::Protocol::IpConflict,
+            current_device.virtual_ip,
+            source,
+            other,
+        )?;
+        context.send_by_key(packet.buffer(), route_key)?;
+    }
+    Ok(())
+}
+
+fn instance_packet(
+    client_cipher: &Cipher,
+    protocol: control_packet::Protocol,
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    instance_id: u64,
+) -> io::Result<NetPacket<[u8; 12 + INSTANCE_LEN + ENCRYPTION_RESERVED]>> {
+    let mut packet = NetPacket::new_encrypt([0; 12 + INSTANCE_LEN + ENCRYPTION_RESERVED])?;
+    packet.set_default_version();
+    packet.set_protocol(Protocol::Control);
+    packet.set_transport_protocol(protocol.into());
+    packet.first_set_ttl(MAX_TTL);
+    packet.set_source(source);
+    packet.set_destination(destination);
+    InstancePacket::new(packet.payload_mut())?.set_instance_id(instance_id);
+    client_cipher.encrypt_ipv4(&mut packet)?;
+    Ok(packet)
+}
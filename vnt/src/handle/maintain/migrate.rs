@@ -0,0 +1,368 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_utils::atomic::AtomicCell;
+use parking_lot::Mutex;
+
+use crate::channel::context::ChannelContext;
+use crate::channel::event::RouteEventKind;
+use crate::channel::punch::NatInfo;
+use crate::channel::sender::AcceptSocketSender;
+use crate::cipher::Cipher;
+use crate::handle::handshaker::Handshake;
+use crate::handle::maintain::idle::domain_request0;
+use crate::handle::maintain::punch::punch_packet;
+use crate::handle::maintain::re_nat_type::{re_test_nat, retrieve_nat_type0};
+use crate::handle::{BaseConfigInfo, ConnectStatus, CurrentDeviceInfo, PeerDeviceInfo};
+use crate::nat;
+use crate::nat::NatTest;
+use crate::util::{watch_net_change, Scheduler, StopManager};
+
+type LocalAddr = (Option<Ipv4Addr>, Option<Ipv6Addr>);
+type NetChangeFn = Box<dyn Fn() + Send>;
+
+/// 不能监听网络变化时检查本地地址的间隔
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// 能监听网络变化时只检查公网地址变化，本地地址每FULL_CHECK_TICKS次检查一次，防止漏掉通知
+const WATCH_INTERVAL: Duration = Duration::from_secs(10);
+const FULL_CHECK_TICKS: usize = 6;
+/// 收到通知后等待网络稳定再检查，合并短时间内的多次通知
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+#[derive(Clone)]
+struct Migrate {
+    context: ChannelContext,
+    nat_test: NatTest,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
+    client_cipher: Cipher,
+    config: BaseConfigInfo,
+    handshake: Handshake,
+    udp_socket_sender: AcceptSocketSender<Option<Vec<mio::net::UdpSocket>>>,
+    last_addr: Arc<Mutex<LocalAddr>>,
+    // 已经安排了检查，还没有执行
+    pending: Arc<AtomicBool>,
+    // 外部通知的网络变化，地址没变也迁移
+    force: Arc<AtomicBool>,
+}
+
+/// 由应用通知网络变化，如Android在ConnectivityManager的回调中调用，
+/// 系统通知可能收不到或者地址检查发现不了变化(切换网络后本地地址相同)
+#[derive(Clone, Default)]
+pub struct NetChange {
+    inner: Arc<Mutex<Option<NetChangeFn>>>,
+}
+
+impl NetChange {
+    /// 立即重新绑定和迁移连接，返回false表示还没有启动
+    pub fn notify(&self) -> bool {
+        match self.inner.lock().as_ref() {
+            Some(f) => {
+                f();
+                true
+            }
+            None => false,
+        }
+    }
+    fn set<F: Fn() + Send + 'static>(&self, f: F) {
+        *self.inner.lock() = Some(Box::new(f));
+    }
+}
+
+/// 监测本地地址，网络切换(如wifi和移动网络互切)后立即迁移连接，不用等待路由超时。
+/// 优先使用系统的网络变化通知，返回是否在监听通知
+pub fn migrate(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    nat_test: NatTest,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
+    client_cipher: Cipher,
+    config: BaseConfigInfo,
+    handshake: Handshake,
+    udp_socket_sender: AcceptSocketSender<Option<Vec<mio::net::UdpSocket>>>,
+    net_change: &NetChange,
+    stop_manager: &StopManager,
+) -> bool {
+    let migrate = Migrate {
+        context,
+        nat_test,
+        current_device,
+        device_list,
+        client_cipher,
+        config,
+        handshake,
+        udp_socket_sender,
+        last_addr: Arc::new(Mutex::new(local_addr())),
+        pending: Arc::new(AtomicBool::new(false)),
+        force: Arc::new(AtomicBool::new(false)),
+    };
+    {
+        let migrate = migrate.clone();
+        let scheduler = scheduler.clone();
+        net_change.set(move || {
+            migrate.force.store(true, Ordering::Release);
+            if migrate.pending.swap(true, Ordering::AcqRel) {
+                return;
+            }
+            let migrate = migrate.clone();
+            scheduler.timeout(DEBOUNCE, move |_| migrate.on_net_change());
+        });
+    }
+    let net_watched = {
+        let migrate = migrate.clone();
+        let scheduler = scheduler.clone();
+        watch_net_change(stop_manager, move || {
+            if migrate.context.is_stop() {
+                return false;
+            }
+            if migrate.pending.swap(true, Ordering::AcqRel) {
+                return true;
+            }
+            let migrate = migrate.clone();
+            scheduler.timeout(DEBOUNCE, move |_| migrate.on_net_change())
+        })
+    };
+    if net_watched {
+        log::info!("使用系统网络变化通知");
+        migrate_(scheduler, migrate, WATCH_INTERVAL, 0);
+    } else {
+        migrate_(scheduler, migrate, POLL_INTERVAL, 0);
+    }
+    net_watched
+}
+
+fn migrate_(scheduler: &Scheduler, migrate: Migrate, interval: Duration, tick: usize) {
+    if interval == POLL_INTERVAL || tick == 0 {
+        migrate.check_local_addr(false);
+    } else {
+        migrate.check_nat();
+    }
+    let rs = scheduler.timeout(interval, move |s| {
+        migrate_(s, migrate, interval, (tick + 1) % FULL_CHECK_TICKS)
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
+
+impl Migrate {
+    fn on_net_change(&self) {
+        self.pending.store(false, Ordering::Release);
+        if self.current_device.load().status.offline() {
+            // 网络恢复后不等待握手退避
+            self.handshake.retry_now();
+        }
+        let force = self.force.swap(false, Ordering::AcqRel);
+        self.check_local_addr(force);
+        if !self.context.use_channel_type().is_only_relay() {
+            // 地址恢复或者上次探测失败时重新探测，地址没变时不会探测
+            retrieve_nat_type0(
+                self.context.clone(),
+                self.nat_test.clone(),
+                self.udp_socket_sender.clone(),
+            );
+        }
+    }
+    fn check_local_addr(&self, force: bool) {
+        let local_addr = local_addr();
+        let last_addr = {
+            let mut guard = self.last_addr.lock();
+            if local_addr == (None, None) {
+                // 网络断开期间保留旧地址，恢复后再比较
+                return;
+            }
+            std::mem::replace(&mut *guard, local_addr)
+        };
+        if local_addr != last_addr || force {
+            log::info!("本地地址变化 {:?} -> {:?}", last_addr, local_addr);
+            self.handshake.retry_now();
+            migrate0(
+                &self.context,
+                &self.nat_test,
+                &self.current_device,
+                &self.device_list,
+                &self.client_cipher,
+                &self.config,
+                &self.handshake,
+                &self.udp_socket_sender,
+            );
+        } else {
+            self.check_nat();
+        }
+    }
+    fn check_nat(&self) {
+        if self.nat_test.take_changed() {
+            nat_changed(
+                &self.context,
+                &self.nat_test,
+                &self.current_device,
+                &self.device_list,
+                &self.client_cipher,
+            );
+        }
+    }
+}
+
+fn local_addr() -> LocalAddr {
+    // 不使用nat::local_ipv4，断网时频繁检测会刷日志
+    (nat::local_ipv4_().ok(), nat::local_ipv6_().ok())
+}
+
+fn migrate0(
+    context: &ChannelContext,
+    nat_test: &NatTest,
+    current_device: &Arc<AtomicCell<CurrentDeviceInfo>>,
+    device_list: &Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
+    client_cipher: &Cipher,
+    config: &BaseConfigInfo,
+    handshake: &Handshake,
+    udp_socket_sender: &AcceptSocketSender<Option<Vec<mio::net::UdpSocket>>>,
+) {
+    let cur = current_device.load();
+    // 旧地址上的直连通道都已失效，移除后由打洞重建
+    let mut peers = Vec::new();
+    for (ip, routes) in context.route_table.route_table() {
+        if cur.is_gateway(&ip) {
+            continue;
+        }
+        for route in routes {
+            if route.is_p2p() {
+                context.remove_route(&ip, route.route_key());
+                if !peers.contains(&ip) {
+                    peers.push(ip);
+                    context.route_table.event_log.push(
+                        ip,
+                        RouteEventKind::Migrate,
+                        format!("{:?}", route.addr),
+                    );
+                }
+            }
+        }
+    }
+    if cur.status.offline() {
+        return;
+    }
+    if context.is_main_tcp() {
+        // tcp连接绑定在旧地址上，交给idle_gateway重连，重连前会重新解析服务器地址
+        crate::handle::change_status(current_device, ConnectStatus::Connecting);
+    }
+    let context = context.clone();
+    let nat_test = nat_test.clone();
+    let current_device = current_device.clone();
+    let device_list = device_list.clone();
+    let client_cipher = client_cipher.clone();
+    let config = config.clone();
+    let handshake = handshake.clone();
+    let udp_socket_sender = udp_socket_sender.clone();
+    let rs = thread::Builder::new()
+        .name("migrate".into())
+        .spawn(move || {
+            let mut cur = cur;
+            if !context.is_main_tcp() {
+                // 换了网络后服务器域名可能解析到其他地址，重新解析后从新地址握手，服务端更新设备地址
+                cur = domain_request0(&current_device, &config);
+                if let Err(e) = handshake.send(&context, config.server_secret, cur.connect_server) {
+                    log::warn!("迁移握手 {:?}", e);
+                }
+            }
+            if context.use_channel_type().is_only_relay() {
+                return;
+            }
+            let nat_info = match re_test_nat(&context, &nat_test, &udp_socket_sender) {
+                Some(nat_info) => nat_info,
+                None => return,
+            };
+            notify_peers(
+                &context,
+                &client_cipher,
+                &cur,
+                &nat_info,
+                &device_list,
+                &peers,
+            );
+        });
+    if let Err(e) = rs {
+        log::warn!("{:?}", e);
+    }
+}
+
+/// 公网地址变化(如运营商更换地址)后，本地地址不变，直连通道可能仍然可以发出数据，
+/// 但对端发来的数据到不了，主动把新地址告知已直连的设备，不用等待路由超时
+fn nat_changed(
+    context: &ChannelContext,
+    nat_test: &NatTest,
+    current_device: &AtomicCell<CurrentDeviceInfo>,
+    device_list: &Mutex<(u16, Vec<PeerDeviceInfo>)>,
+    client_cipher: &Cipher,
+) {
+    let cur = current_device.load();
+    if cur.status.offline() || context.use_channel_type().is_only_relay() {
+        return;
+    }
+    let peers: Vec<Ipv4Addr> = context
+        .route_table
+        .route_table()
+        .into_iter()
+        .filter(|(ip, routes)| !cur.is_gateway(ip) && routes.iter().any(|v| v.is_p2p()))
+        .map(|(ip, _)| ip)
+        .collect();
+    if peers.is_empty() {
+        return;
+    }
+    log::info!("公网地址变化,通知直连设备 {:?}", peers);
+    for ip in &peers {
+        context.route_table.event_log.push(
+            *ip,
+            RouteEventKind::NatChange,
+            "notify peer".to_string(),
+        );
+    }
+    notify_peers(
+        context,
+        client_cipher,
+        &cur,
+        &nat_test.nat_info(),
+        device_list,
+        &peers,
+    );
+}
+
+/// 把新的nat信息经服务端发给之前直连的设备，对端收到后立即打洞
+fn notify_peers(
+    context: &ChannelContext,
+    client_cipher: &Cipher,
+    cur: &CurrentDeviceInfo,
+    nat_info: &NatInfo,
+    device_list: &Mutex<(u16, Vec<PeerDeviceInfo>)>,
+    peers: &[Ipv4Addr],
+) {
+    let online: Vec<Ipv4Addr> = {
+        device_list
+            .lock()
+            .1
+            .iter()
+            .filter(|peer| {
+                peer.status.is_online()
+                    && peers.contains(&peer.virtual_ip)
+                    && context.peer_filter.is_allowed(&peer.virtual_ip)
+            })
+            .map(|peer| peer.virtual_ip)
+            .collect()
+    };
+    for peer_ip in online {
+        let packet = match punch_packet(context, client_cipher, cur.virtual_ip, nat_info, peer_ip) {
+            Ok(packet) => packet,
+            Err(e) => {
+                log::warn!("{:?}", e);
+                continue;
+            }
+        };
+        if let Err(e) = context.send_default(packet.buffer(), cur.connect_server) {
+            log::warn!("地址变化通知 peer={} {:?}", peer_ip, e);
+        }
+    }
+}
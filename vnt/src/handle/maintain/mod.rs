@@ -1,10 +1,15 @@
 mod heartbeat;
 pub use heartbeat::client_relay;
 pub use heartbeat::heartbeat;
+pub use heartbeat::hold_punch;
+pub(crate) use heartbeat::pong_rt;
 
 mod re_nat_type;
 pub use re_nat_type::retrieve_nat_type;
 
+mod migrate;
+pub use migrate::{migrate, NetChange};
+
 mod addr_request;
 pub use addr_request::addr_request;
 
@@ -17,3 +22,40 @@ pub use idle::idle_route;
 
 mod up_status;
 pub use up_status::*;
+
+mod state_tick;
+pub use state_tick::state_tick;
+
+mod path_mtu;
+pub use path_mtu::path_mtu;
+
+mod reverse_tunnel;
+pub use reverse_tunnel::{handle_reverse_tunnel, reverse_tunnel};
+
+mod subnet_advert;
+pub use subnet_advert::{
+    handle_subnet_advert, restore_subnet_routes, subnet_advert, sync_subnet_routes,
+};
+
+mod cover_traffic;
+pub use cover_traffic::cover_traffic;
+
+mod ip_announce;
+pub use ip_announce::{handle_ip_announce, ip_announce};
+
+mod vlan_advert;
+pub use vlan_advert::{handle_vlan_advert, sync_vlan, vlan_advert};
+
+mod asym_path;
+pub use asym_path::{on_direct_inbound, on_path_confirm, on_path_notice, on_path_probe};
+
+mod server_hint;
+pub use server_hint::{on_server_hint, server_hint};
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod udp_buf;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use udp_buf::udp_buf_tune;
+
+mod presence;
+pub use presence::{notify_offline, on_peer_offline, reset_replay};
@@ -0,0 +1,81 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossbeam_utils::atomic::AtomicCell;
+
+use crate::channel::context::ChannelContext;
+use crate::cipher::{Cipher, SEQ_LEN};
+use crate::handle::CurrentDeviceInfo;
+use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::protocol::control_packet::MtuProbePacket;
+use crate::protocol::{control_packet, NetPacket, Protocol, MAX_TTL};
+use crate::util::Scheduler;
+
+/// 路径mtu探测
+pub fn path_mtu(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    client_cipher: Cipher,
+    mtu: u16,
+) {
+    probe(&context, &current_device, &client_cipher, mtu);
+    let rs = scheduler.timeout(Duration::from_secs(3), move |s| {
+        path_mtu(s, context, current_device, client_cipher, mtu)
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
+
+fn probe(
+    context: &ChannelContext,
+    current_device: &AtomicCell<CurrentDeviceInfo>,
+    client_cipher: &Cipher,
+    mtu: u16,
+) {
+    let current_device = current_device.load();
+    if current_device.status.offline() {
+        return;
+    }
+    for (peer_ip, size) in context.path_mtu.probe_targets(mtu) {
+        // 填充到和同样长度的ip数据包加密后一致
+        let payload_len = size as usize + SEQ_LEN;
+        let mut packet =
+            match NetPacket::new_encrypt(vec![0u8; 12 + payload_len + ENCRYPTION_RESERVED]) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    log::warn!("{:?}", e);
+                    return;
+                }
+            };
+        packet.set_default_version();
+        packet.set_protocol(Protocol::Control);
+        packet.set_transport_protocol(control_packet::Protocol::MtuProbe.into());
+        packet.first_set_ttl(MAX_TTL);
+        packet.set_source(current_device.virtual_ip);
+        packet.set_destination(peer_ip);
+        match MtuProbePacket::new(packet.payload_mut()) {
+            Ok(mut probe) => {
+                probe.set_size(size);
+                probe.set_reply(false);
+            }
+            Err(e) => {
+                log::warn!("{:?}", e);
+                return;
+            }
+        }
+        if let Err(e) = client_cipher.encrypt_ipv4(&mut packet) {
+            log::warn!("MtuProbe err={:?}", e);
+            return;
+        }
+        if let Err(e) = context.send_ipv4_by_id(
+            packet.buffer(),
+            &peer_ip,
+            current_device.connect_server,
+            current_device.status.online(),
+        ) {
+            log::warn!("MtuProbe peer={} err={:?}", peer_ip, e);
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use std::io;
+use std::net::Ipv4Addr;
+
+use crate::channel::context::ChannelContext;
+use crate::channel::event::StateEvent;
+use crate::channel::RouteKey;
+use crate::cipher::Cipher;
+use crate::handle::presence::{Presence, PresenceEvent, PresenceKind};
+use crate::handle::CurrentDeviceInfo;
+use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::protocol::capability::Capabilities;
+use crate::protocol::{control_packet, NetPacket, Protocol, MAX_TTL};
+
+/// 正常退出前直连通知对端自己下线，对端不用等路由超时和服务端推送
+pub fn notify_offline(
+    context: &ChannelContext,
+    current_device: &CurrentDeviceInfo,
+    client_cipher: &Cipher,
+) {
+    if !current_device.status.online() {
+        return;
+    }
+    for (peer, route) in context.route_table.route_table_p2p() {
+        if !context.capabilities.supports(&peer, Capabilities::PRESENCE) {
+            continue;
+        }
+        let rs = offline_packet(client_cipher, current_device.virtual_ip, peer)
+            .and_then(|packet| context.send_by_key(packet.buffer(), route.route_key()));
+        if let Err(e) = rs {
+            log::warn!("下线通知 peer={} {:?}", peer, e);
+        }
+    }
+}
+
+/// 收到对端直连发来的下线通知，移除这条直连路由并标记为离线
+pub fn on_peer_offline(
+    context: &ChannelContext,
+    presence: &Presence,
+    source: Ipv4Addr,
+    route_key: RouteKey,
+) {
+    context.remove_route(&source, route_key);
+    if let Some(event) = presence.offline(source) {
+        reset_replay(context, &event);
+        log::info!("对端状态变化 {} {} {}", event.ip, event.name, event.kind);
+        let subscribers = &context.route_table.event_log.subscribers;
+        if !subscribers.is_empty() {
+            subscribers.publish(StateEvent::Presence(event));
+        }
+    }
+}
+
+/// 对端下线、重新上线或者换了ip后，这个ip上的序号可能从更小的值重新开始(换了设备或者时钟回拨)，
+/// 清除防重放窗口，否则之后的包都会被当作重放丢弃
+pub fn reset_replay(context: &ChannelContext, event: &PresenceEvent) {
+    let replay_guard = match &context.replay_guard {
+        Some(replay_guard) => replay_guard,
+        None => return,
+    };
+    match &event.kind {
+        PresenceKind::IpChange(old_ip) => {
+            replay_guard.remove(old_ip);
+            replay_guard.remove(&event.ip);
+        }
+        PresenceKind::NameChange(_) => {}
+        _ => replay_guard.remove(&event.ip),
+    }
+}
+
+fn offline_packet(
+    client_cipher: &Cipher,
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+) -> io::Result<NetPacket<[u8; 12 + ENCRYPTION_RESERVED]>> {
+    let mut packet = NetPacket::new_encrypt([0; 12 + ENCRYPTION_RESERVED])?;
+    packet.set_default_version();
+    packet.set_protocol(Protocol::Control);
+    packet.set_transport_protocol(control_packet::Protocol::PeerOffline.into());
+    packet.first_set_ttl(MAX_TTL);
+    packet.set_source(source);
+    packet.set_destination(destination);
+    client_cipher.encrypt_ipv4(&mut packet)?;
+    Ok(packet)
+}
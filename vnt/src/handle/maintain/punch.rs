@@ -11,14 +11,20 @@ use protobuf::Message;
 use rand::prelude::SliceRandom;
 
 use crate::channel::context::ChannelContext;
+use crate::channel::event::RouteEventKind;
+use crate::channel::peer_auth::PeerAuth;
 use crate::channel::punch::{NatInfo, NatType, Punch};
+use crate::cipher::identity::SIGNATURE_LEN;
 use crate::cipher::Cipher;
+use crate::error::VntError;
+use crate::handle::callback::VntCallback;
 use crate::handle::{CurrentDeviceInfo, PeerDeviceInfo};
 use crate::nat::NatTest;
-use crate::proto::message::{PunchInfo, PunchNatType};
+use crate::proto::message::{PunchInfo, PunchNatFiltering, PunchNatType};
 use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::protocol::capability::Capabilities;
 use crate::protocol::{control_packet, other_turn_packet, NetPacket, Protocol, MAX_TTL};
-use crate::util::Scheduler;
+use crate::util::{LogSpan, Scheduler};
 
 #[derive(Clone)]
 pub struct PunchSender {
@@ -84,7 +90,7 @@ pub fn punch_channel() -> (PunchSender, PunchReceiver) {
     )
 }
 
-pub fn punch(
+pub fn punch<Call: VntCallback>(
     scheduler: &Scheduler,
     context: ChannelContext,
     nat_test: NatTest,
@@ -93,9 +99,11 @@ pub fn punch(
     client_cipher: Cipher,
     receiver: PunchReceiver,
     punch: Punch,
+    callback: Call,
 ) {
     let punch_record = Arc::new(Mutex::new(HashMap::new()));
     let last_punch_record = HashMap::new();
+    let peer_auth = context.peer_auth.clone();
     punch_request(
         scheduler,
         context,
@@ -112,10 +120,20 @@ pub fn punch(
         let current_device = current_device.clone();
         let client_cipher = client_cipher.clone();
         let punch_record = punch_record.clone();
+        let peer_auth = peer_auth.clone();
+        let callback = callback.clone();
         thread::Builder::new()
             .name("punch".into())
             .spawn(move || {
-                punch_start(receiver, punch, current_device, client_cipher, punch_record);
+                punch_start(
+                    receiver,
+                    punch,
+                    current_device,
+                    client_cipher,
+                    punch_record,
+                    peer_auth,
+                    callback,
+                );
             })
             .expect("punch");
     };
@@ -126,21 +144,28 @@ pub fn punch(
 }
 
 /// 接收打洞消息，配合对端打洞
-fn punch_start(
+fn punch_start<Call: VntCallback>(
     receiver: Receiver<(Ipv4Addr, NatInfo)>,
     mut punch: Punch,
     current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
     client_cipher: Cipher,
     punch_record: Arc<Mutex<HashMap<Ipv4Addr, usize>>>,
+    peer_auth: PeerAuth,
+    callback: Call,
 ) {
     while let Ok((peer_ip, nat_info)) = receiver.recv() {
-        let mut packet = NetPacket::new_encrypt([0u8; 12 + ENCRYPTION_RESERVED]).unwrap();
-        packet.set_default_version();
-        packet.first_set_ttl(1);
-        packet.set_protocol(Protocol::Control);
-        packet.set_transport_protocol(control_packet::Protocol::PunchRequest.into());
-        packet.set_source(current_device.load().virtual_ip());
-        packet.set_destination(peer_ip);
+        let packet = match punch_request_packet(
+            &peer_auth,
+            &client_cipher,
+            current_device.load().virtual_ip(),
+            peer_ip,
+        ) {
+            Ok(packet) => packet,
+            Err(e) => {
+                log::error!("{:?}", e);
+                continue;
+            }
+        };
         let count = {
             let mut guard = punch_record.lock();
             if let Some(v) = guard.get_mut(&peer_ip) {
@@ -151,18 +176,47 @@ fn punch_start(
                 0
             }
         };
-        log::info!("第{}次发起打洞,目标:{:?},{:?} ", count, peer_ip, nat_info);
-
-        if let Err(e) = client_cipher.encrypt_ipv4(&mut packet) {
-            log::error!("{:?}", e);
-            continue;
-        }
+        let _span = LogSpan::new(Some(peer_ip), None, Some(Protocol::Control)).enter();
+        log::info!("发起打洞 count={} nat={:?}", count, nat_info);
         if let Err(e) = punch.punch(packet.buffer(), peer_ip, nat_info, count < 2) {
-            log::warn!("{:?}", e)
+            log::warn!("{:?}", e);
+            callback.error(VntError::PunchFailed(peer_ip, e).into());
         }
     }
 }
 
+pub(crate) type PunchRequestPacket = NetPacket<[u8; 12 + SIGNATURE_LEN + ENCRYPTION_RESERVED]>;
+
+/// 构建打洞请求，对端收到后会沿同一通道回应
+pub(crate) fn punch_request_packet(
+    peer_auth: &PeerAuth,
+    client_cipher: &Cipher,
+    virtual_ip: Ipv4Addr,
+    peer_ip: Ipv4Addr,
+) -> io::Result<PunchRequestPacket> {
+    let mut packet = NetPacket::new_encrypt([0u8; 12 + SIGNATURE_LEN + ENCRYPTION_RESERVED])?;
+    packet.set_default_version();
+    packet.first_set_ttl(1);
+    packet.set_protocol(Protocol::Control);
+    packet.set_transport_protocol(control_packet::Protocol::PunchRequest.into());
+    packet.set_source(virtual_ip);
+    packet.set_destination(peer_ip);
+    // 开启设备身份校验时带上签名，否则是空包
+    if let Some(signature) = peer_auth.sign(
+        Protocol::Control.into(),
+        control_packet::Protocol::PunchRequest.into(),
+        virtual_ip,
+        peer_ip,
+        &[],
+    ) {
+        packet.payload_mut().copy_from_slice(&signature);
+    } else {
+        packet.set_data_len(12)?;
+    }
+    client_cipher.encrypt_ipv4(&mut packet)?;
+    Ok(packet)
+}
+
 /// 定时发起打洞请求
 fn punch_request(
     scheduler: &Scheduler,
@@ -177,7 +231,9 @@ fn punch_request(
 ) {
     let curr = current_device.load();
     let secs = if curr.status.online() {
-        if let Err(e) = punch0(
+        if context.power_save.is_low_power() || context.power_save.skip(count) {
+            // 低功耗时暂停打洞，省电模式下降低打洞频率
+        } else if let Err(e) = punch0(
             &context,
             &nat_test,
             &device_list,
@@ -239,7 +295,11 @@ fn punch0(
         .lock()
         .1
         .iter()
-        .filter(|info| info.status.is_online() && info.virtual_ip > current_ip)
+        .filter(|info| {
+            info.status.is_online()
+                && info.virtual_ip > current_ip
+                && context.peer_filter.is_allowed(&info.virtual_ip)
+        })
         .cloned()
         .collect();
     list.shuffle(&mut rand::thread_rng());
@@ -273,6 +333,7 @@ fn punch0(
         if total_count > last_punch + punch_count.min(max_punch_interval) {
             last_punch_record.insert(info.virtual_ip, total_count);
             let packet = punch_packet(
+                context,
                 client_cipher,
                 current_device.virtual_ip(),
                 &nat_info,
@@ -285,6 +346,11 @@ fn punch0(
                 punch_count,
                 total_count,
             );
+            context.route_table.event_log.push(
+                info.virtual_ip,
+                RouteEventKind::PunchRequest,
+                format!("count={} nat={:?}", punch_count, nat_info.nat_type),
+            );
             context.send_default(packet.buffer(), current_device.connect_server)?;
             break;
         }
@@ -292,7 +358,8 @@ fn punch0(
     Ok(())
 }
 
-fn punch_packet(
+pub(crate) fn punch_packet(
+    context: &ChannelContext,
     client_cipher: &Cipher,
     virtual_ip: Ipv4Addr,
     nat_info: &NatInfo,
@@ -317,6 +384,16 @@ fn punch_packet(
         punch_reply.ipv6 = ipv6.octets().to_vec();
     }
     punch_reply.nat_type = protobuf::EnumOrUnknown::new(PunchNatType::from(nat_info.nat_type));
+    punch_reply.filtering =
+        protobuf::EnumOrUnknown::new(PunchNatFiltering::from(nat_info.filtering));
+    punch_reply.capabilities = Capabilities::local(context.coalesce.is_enable()).bits();
+    punch_reply.conn_id = context.conn_ids.local();
+    punch_reply.host_id = crate::channel::punch::host_id().to_vec();
+    punch_reply.uds_id = context.uds_id();
+    context
+        .peer_auth
+        .sign_punch_info(virtual_ip, dest, &mut punch_reply)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("punch_packet {:?}", e)))?;
     log::info!("请求打洞={:?}", punch_reply);
     let bytes = punch_reply
         .write_to_bytes()
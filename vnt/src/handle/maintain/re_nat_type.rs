@@ -2,25 +2,39 @@ use std::thread;
 use std::time::Duration;
 
 use crate::channel::context::ChannelContext;
+use crate::channel::punch::NatInfo;
 use crate::channel::sender::AcceptSocketSender;
 use crate::nat;
 use crate::nat::NatTest;
 use crate::util::Scheduler;
 
-/// 10分钟探测一次nat
+/// 能监听网络变化时，探测成功后的检查间隔
+const WATCH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// 每分钟检查一次本地地址，地址变化、上次探测失败或者结果过期时重新探测nat。
+/// 能监听网络变化时由地址变化触发探测，这里只负责失败重试和结果过期后的重新探测，间隔更长
 pub fn retrieve_nat_type(
     scheduler: &Scheduler,
     context: ChannelContext,
     nat_test: NatTest,
     udp_socket_sender: AcceptSocketSender<Option<Vec<mio::net::UdpSocket>>>,
+    net_watched: bool,
 ) {
     retrieve_nat_type0(context.clone(), nat_test.clone(), udp_socket_sender.clone());
-    scheduler.timeout(Duration::from_secs(60 * 10), move |s| {
-        retrieve_nat_type(s, context, nat_test, udp_socket_sender)
+    let interval = if net_watched {
+        match nat_test.retry_after() {
+            Some(delay) => delay.max(Duration::from_secs(5)),
+            None => WATCH_INTERVAL,
+        }
+    } else {
+        Duration::from_secs(60)
+    };
+    scheduler.timeout(interval, move |s| {
+        retrieve_nat_type(s, context, nat_test, udp_socket_sender, net_watched)
     });
 }
 
-fn retrieve_nat_type0(
+pub(crate) fn retrieve_nat_type0(
     context: ChannelContext,
     nat_test: NatTest,
     udp_socket_sender: AcceptSocketSender<Option<Vec<mio::net::UdpSocket>>>,
@@ -28,21 +42,33 @@ fn retrieve_nat_type0(
     thread::Builder::new()
         .name("natTest".into())
         .spawn(move || {
-            if nat_test.can_update() {
-                let local_ipv4 = nat::local_ipv4();
-                let local_ipv6 = nat::local_ipv6();
-                match nat_test.re_test(local_ipv4, local_ipv6) {
-                    Ok(nat_info) => {
-                        log::info!("当前nat信息:{:?}", nat_info);
-                        if let Err(e) = context.switch(nat_info.nat_type, &udp_socket_sender) {
-                            log::warn!("{:?}", e);
-                        }
-                    }
-                    Err(e) => {
-                        log::warn!("nat re_test {:?}", e);
-                    }
-                };
-            }
+            re_test_nat(&context, &nat_test, &udp_socket_sender);
         })
         .expect("natTest");
 }
+
+/// 重新探测nat，成功时返回新的nat信息
+pub(crate) fn re_test_nat(
+    context: &ChannelContext,
+    nat_test: &NatTest,
+    udp_socket_sender: &AcceptSocketSender<Option<Vec<mio::net::UdpSocket>>>,
+) -> Option<NatInfo> {
+    let local_ipv4 = nat::local_ipv4();
+    let local_ipv6 = nat::local_ipv6();
+    if !nat_test.can_update(local_ipv4, local_ipv6) {
+        return None;
+    }
+    match nat_test.re_test(local_ipv4, local_ipv6) {
+        Ok(nat_info) => {
+            log::info!("当前nat信息:{:?}", nat_info);
+            if let Err(e) = context.switch(nat_info.nat_type, udp_socket_sender) {
+                log::warn!("{:?}", e);
+            }
+            Some(nat_info)
+        }
+        Err(e) => {
+            log::warn!("nat re_test {:?}", e);
+            None
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_utils::atomic::AtomicCell;
+use parking_lot::{Mutex, RwLock};
+use rand::prelude::SliceRandom;
+
+use crate::channel::context::ChannelContext;
+use crate::channel::punch::{NatInfo, Punch};
+use crate::cipher::Cipher;
+use crate::handle::maintain::heartbeat::heartbeat_packet_client;
+use crate::handle::maintain::punch::punch_request_packet;
+use crate::handle::route_report::other_turn_packet;
+use crate::handle::{CurrentDeviceInfo, PeerDeviceInfo};
+use crate::protocol::other_turn_packet;
+use crate::util::Scheduler;
+
+/// 反向隧道，自己无法被其他设备连接时(入站tcp/udp都被拦截)，
+/// 主动和num个公网可达的设备保持tcp连接，并告知其他设备经由这些设备访问自己
+pub fn reverse_tunnel(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
+    peer_nat_info_map: Arc<RwLock<HashMap<Ipv4Addr, NatInfo>>>,
+    client_cipher: Cipher,
+    punch: Punch,
+    num: usize,
+) {
+    if num == 0 {
+        return;
+    }
+    let rs = scheduler.timeout(Duration::from_secs(5), move |s| {
+        reverse_tunnel_(
+            s,
+            context,
+            current_device,
+            device_list,
+            peer_nat_info_map,
+            client_cipher,
+            punch,
+            num,
+        )
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
+
+fn reverse_tunnel_(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
+    peer_nat_info_map: Arc<RwLock<HashMap<Ipv4Addr, NatInfo>>>,
+    client_cipher: Cipher,
+    punch: Punch,
+    num: usize,
+) {
+    if let Err(e) = reverse_tunnel0(
+        &context,
+        &current_device.load(),
+        &device_list,
+        &peer_nat_info_map,
+        &client_cipher,
+        &punch,
+        num,
+    ) {
+        log::warn!("反向隧道 {:?}", e);
+    }
+    let rs = scheduler.timeout(Duration::from_secs(10), move |s| {
+        reverse_tunnel_(
+            s,
+            context,
+            current_device,
+            device_list,
+            peer_nat_info_map,
+            client_cipher,
+            punch,
+            num,
+        )
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
+
+fn reverse_tunnel0(
+    context: &ChannelContext,
+    current_device: &CurrentDeviceInfo,
+    device_list: &Mutex<(u16, Vec<PeerDeviceInfo>)>,
+    peer_nat_info_map: &RwLock<HashMap<Ipv4Addr, NatInfo>>,
+    client_cipher: &Cipher,
+    punch: &Punch,
+    num: usize,
+) -> io::Result<()> {
+    if current_device.status.offline() {
+        return Ok(());
+    }
+    let relays = relay_list(context, current_device);
+    let peer_list = { device_list.lock().1.clone() };
+    if relays.len() < num {
+        // 每轮随机选一个新建连接，连接是阻塞的，不占用定时器线程
+        let candidates: Vec<(Ipv4Addr, NatInfo)> = {
+            let guard = peer_nat_info_map.read();
+            peer_list
+                .iter()
+                .filter(|peer| peer.status.is_online() && !relays.contains(&peer.virtual_ip))
+                .filter_map(|peer| {
+                    guard
+                        .get(&peer.virtual_ip)
+                        .filter(|nat_info| !nat_info.public_tcp_addrs().is_empty())
+                        .map(|nat_info| (peer.virtual_ip, nat_info.clone()))
+                })
+                .collect()
+        };
+        // 优先选择服务端推荐的中继
+        let hints = context.server_config.relay_hints();
+        let hinted: Vec<(Ipv4Addr, NatInfo)> = candidates
+            .iter()
+            .filter(|(ip, _)| hints.contains(ip))
+            .cloned()
+            .collect();
+        let candidates = if hinted.is_empty() {
+            candidates
+        } else {
+            hinted
+        };
+        if let Some((peer_ip, nat_info)) = candidates.choose(&mut rand::thread_rng()).cloned() {
+            let packet = punch_request_packet(
+                &context.peer_auth,
+                client_cipher,
+                current_device.virtual_ip,
+                peer_ip,
+            )?;
+            let punch = punch.clone();
+            thread::Builder::new()
+                .name("reverseTunnel".into())
+                .spawn(move || {
+                    let rs = punch.connect_relay(packet.buffer(), &nat_info);
+                    log::info!("建立反向隧道 relay={} rs={}", peer_ip, rs);
+                })?;
+        }
+    }
+    if relays.is_empty() {
+        return Ok(());
+    }
+    let mut payload = Vec::with_capacity(relays.len() * 4);
+    for ip in &relays {
+        payload.extend_from_slice(&ip.octets());
+    }
+    for peer in &peer_list {
+        if !peer.status.is_online()
+            || peer.virtual_ip == current_device.virtual_ip
+            || relays.contains(&peer.virtual_ip)
+            || context
+                .route_table
+                .route_one_p2p(&peer.virtual_ip)
+                .is_some()
+        {
+            continue;
+        }
+        let packet = other_turn_packet(
+            client_cipher,
+            other_turn_packet::Protocol::ReverseTunnel,
+            current_device.virtual_ip,
+            peer.virtual_ip,
+            &payload,
+        )?;
+        context.send_ipv4_by_id(
+            packet.buffer(),
+            &peer.virtual_ip,
+            current_device.connect_server,
+            true,
+        )?;
+    }
+    Ok(())
+}
+
+/// 已经建立tcp直连的设备，都可以作为中继
+fn relay_list(context: &ChannelContext, current_device: &CurrentDeviceInfo) -> Vec<Ipv4Addr> {
+    context
+        .route_table
+        .route_table_p2p()
+        .into_iter()
+        .filter(|(ip, route)| route.is_tcp && !current_device.is_gateway(ip))
+        .map(|(ip, _)| ip)
+        .collect()
+}
+
+/// 收到对方的中继列表，经由和自己直连的中继发送心跳，收到回应后即添加经过中继的路由
+pub fn handle_reverse_tunnel(
+    context: &ChannelContext,
+    client_cipher: &Cipher,
+    current_device: &CurrentDeviceInfo,
+    source: Ipv4Addr,
+    payload: &[u8],
+) -> io::Result<()> {
+    if context.route_table.route_one_p2p(&source).is_some() {
+        return Ok(());
+    }
+    let packet =
+        heartbeat_packet_client(context, client_cipher, current_device.virtual_ip, source)?;
+    for ip in payload.chunks_exact(4) {
+        let relay = Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]);
+        if relay == current_device.virtual_ip {
+            continue;
+        }
+        if let Some(route) = context.route_table.route_one_p2p(&relay) {
+            context.send_by_key(packet.buffer(), route.route_key())?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossbeam_utils::atomic::AtomicCell;
+
+use crate::channel::context::ChannelContext;
+use crate::cipher::Cipher;
+use crate::handle::server_hint::{ServerHints, HINT_MAX_LEN};
+use crate::handle::CurrentDeviceInfo;
+use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::protocol::{control_packet, NetPacket, Protocol, MAX_TTL};
+use crate::util::Scheduler;
+
+/// 通告间隔
+const HINT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// 定时把正在使用的服务端地址通告给对端，只发给有直连路由的对端，
+/// 对端连不上服务端时经服务端中转的通告也收不到
+pub fn server_hint(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    client_cipher: Cipher,
+    server_hints: ServerHints,
+) {
+    let current = current_device.load();
+    if current.status.online() && !context.use_channel_type().is_only_relay() {
+        if let Err(e) = send_hints(&context, &current, &client_cipher, &server_hints) {
+            log::warn!("服务端地址通告 {:?}", e);
+        }
+    }
+    let rs = scheduler.timeout(HINT_INTERVAL, move |s| {
+        server_hint(s, context, current_device, client_cipher, server_hints)
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
+
+fn send_hints(
+    context: &ChannelContext,
+    current_device: &CurrentDeviceInfo,
+    client_cipher: &Cipher,
+    server_hints: &ServerHints,
+) -> io::Result<()> {
+    let data = server_hints.encode(current_device.virtual_ip, current_device.connect_server)?;
+    for (peer, route) in context.route_table.route_table_p2p() {
+        let packet = hint_packet(client_cipher, current_device.virtual_ip, peer, &data)?;
+        if let Err(e) = context.send_by_key(packet.buffer(), route.route_key()) {
+            log::warn!("服务端地址通告 peer={} {:?}", peer, e);
+        }
+    }
+    Ok(())
+}
+
+/// 收到对端直连发来的通告，校验通过后记录
+pub fn on_server_hint(
+    server_hints: &ServerHints,
+    current_device: &CurrentDeviceInfo,
+    source: Ipv4Addr,
+    data: &[u8],
+) {
+    if !server_hints.is_enable() {
+        return;
+    }
+    match server_hints.decode(source, data) {
+        Ok(addr) => {
+            if server_hints.record(source, addr) && addr != current_device.connect_server {
+                log::info!("对端{}通告的服务端地址:{}", source, addr);
+            }
+        }
+        Err(e) => {
+            log::warn!("服务端地址通告校验失败 peer={} {:?}", source, e);
+        }
+    }
+}
+
+fn hint_packet(
+    client_cipher: &Cipher,
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    data: &[u8],
+) -> io::Result<NetPacket<[u8; 12 + HINT_MAX_LEN + ENCRYPTION_RESERVED]>> {
+    let mut packet = NetPacket::new_encrypt([0; 12 + HINT_MAX_LEN + ENCRYPTION_RESERVED])?;
+    packet.set_default_version();
+    packet.set_protocol(Protocol::Control);
+    packet.set_transport_protocol(control_packet::Protocol::ServerHint.into());
+    packet.first_set_ttl(MAX_TTL);
+    packet.set_source(source);
+    packet.set_destination(destination);
+    packet.set_data_len(12 + data.len())?;
+    packet.payload_mut().copy_from_slice(data);
+    client_cipher.encrypt_ipv4(&mut packet)?;
+    Ok(packet)
+}
@@ -0,0 +1,62 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossbeam_utils::atomic::AtomicCell;
+
+use crate::channel::context::ChannelContext;
+use crate::channel::event::StateEvent;
+use crate::handle::{ConnectStatus, CurrentDeviceInfo};
+use crate::util::{Scheduler, WatchU64Adder};
+
+/// 每秒向订阅者推送流量统计和连接状态的变化
+pub fn state_tick(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    down_count_watcher: WatchU64Adder,
+    up_count_watcher: WatchU64Adder,
+) {
+    let status = current_device.load().status;
+    state_tick0(
+        scheduler,
+        context,
+        current_device,
+        down_count_watcher,
+        up_count_watcher,
+        status,
+    );
+}
+
+fn state_tick0(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    down_count_watcher: WatchU64Adder,
+    up_count_watcher: WatchU64Adder,
+    last_status: ConnectStatus,
+) {
+    let status = current_device.load().status;
+    let subscribers = &context.route_table.event_log.subscribers;
+    if status != last_status {
+        subscribers.publish(StateEvent::Status(status));
+    }
+    if !subscribers.is_empty() {
+        subscribers.publish(StateEvent::Stats {
+            up_stream: up_count_watcher.get(),
+            down_stream: down_count_watcher.get(),
+        });
+    }
+    let rs = scheduler.timeout(Duration::from_secs(1), move |s| {
+        state_tick0(
+            s,
+            context,
+            current_device,
+            down_count_watcher,
+            up_count_watcher,
+            status,
+        )
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
@@ -0,0 +1,261 @@
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossbeam_utils::atomic::AtomicCell;
+use parking_lot::Mutex;
+use protobuf::Message;
+
+use crate::channel::context::ChannelContext;
+use crate::cipher::Cipher;
+use crate::external_route::{AllowExternalRoute, ExternalRoute};
+use crate::handle::route_report::other_turn_packet;
+use crate::handle::{CurrentDeviceInfo, PeerDeviceInfo};
+use crate::proto::message::{SubnetAdvert, SubnetInfo};
+use crate::protocol::other_turn_packet;
+use crate::tun_tap_device::tun_create_helper::DeviceAdapter;
+use crate::util::Scheduler;
+#[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
+use tun::device::IFace;
+
+/// 网段通告，作为网关时定时向在线设备通告自己转发的网段，
+/// 接受通告时删除超时的网段路由。default_route为网关模式，同时通告默认路由
+pub fn subnet_advert(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
+    client_cipher: Cipher,
+    device: DeviceAdapter,
+    subnets: Vec<(u32, u32)>,
+    default_route: bool,
+) {
+    // 非网关模式不通告默认路由
+    let mut subnets: Vec<(u32, u32)> = subnets
+        .into_iter()
+        .filter(|(_, mask)| *mask != 0)
+        .map(|(dest, mask)| (dest & mask, mask))
+        .collect();
+    if default_route {
+        subnets.push((0, 0));
+    }
+    if subnets.is_empty() && !context.subnet_routes.is_accept() {
+        return;
+    }
+    let payload = match subnet_advert_payload(&subnets) {
+        Ok(payload) => payload,
+        Err(e) => {
+            log::error!("网段通告 {:?}", e);
+            return;
+        }
+    };
+    let rs = scheduler.timeout(Duration::from_secs(5), move |s| {
+        subnet_advert_(
+            s,
+            context,
+            current_device,
+            device_list,
+            client_cipher,
+            device,
+            payload,
+        )
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
+
+fn subnet_advert_(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
+    client_cipher: Cipher,
+    device: DeviceAdapter,
+    payload: Option<Vec<u8>>,
+) {
+    if let Some(payload) = &payload {
+        if let Err(e) = send_advert(
+            &context,
+            &current_device.load(),
+            &device_list,
+            &client_cipher,
+            payload,
+        ) {
+            log::warn!("网段通告 {:?}", e);
+        }
+    }
+    for (dest, mask) in context.subnet_routes.expire() {
+        log::info!("网段通告超时 {}/{}", dest, mask);
+        delete_route(&device, dest, mask);
+    }
+    let rs = scheduler.timeout(Duration::from_secs(20), move |s| {
+        subnet_advert_(
+            s,
+            context,
+            current_device,
+            device_list,
+            client_cipher,
+            device,
+            payload,
+        )
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
+
+fn subnet_advert_payload(subnets: &[(u32, u32)]) -> io::Result<Option<Vec<u8>>> {
+    if subnets.is_empty() {
+        return Ok(None);
+    }
+    let mut advert = SubnetAdvert::new();
+    for (dest, mask) in subnets {
+        let mut info = SubnetInfo::new();
+        info.network = *dest;
+        info.mask = *mask;
+        advert.subnet_list.push(info);
+    }
+    let bytes = advert
+        .write_to_bytes()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("subnet_advert {:?}", e)))?;
+    Ok(Some(bytes))
+}
+
+fn send_advert(
+    context: &ChannelContext,
+    current_device: &CurrentDeviceInfo,
+    device_list: &Mutex<(u16, Vec<PeerDeviceInfo>)>,
+    client_cipher: &Cipher,
+    payload: &[u8],
+) -> io::Result<()> {
+    if current_device.status.offline() {
+        return Ok(());
+    }
+    let peer_list = { device_list.lock().1.clone() };
+    for peer in &peer_list {
+        if !peer.status.is_online()
+            || peer.virtual_ip == current_device.virtual_ip
+            || !context.peer_filter.is_allowed(&peer.virtual_ip)
+        {
+            continue;
+        }
+        let packet = other_turn_packet(
+            client_cipher,
+            other_turn_packet::Protocol::SubnetAdvert,
+            current_device.virtual_ip,
+            peer.virtual_ip,
+            payload,
+        )?;
+        context.send_ipv4_by_id(
+            packet.buffer(),
+            &peer.virtual_ip,
+            current_device.connect_server,
+            true,
+        )?;
+    }
+    Ok(())
+}
+
+/// 收到网关的网段通告，更新路由表并添加系统路由。
+/// 忽略和虚拟网络、本地网络、-i指定的网段以及自己转发的网段冲突的网段
+pub fn handle_subnet_advert(
+    context: &ChannelContext,
+    device: &DeviceAdapter,
+    current_device: &CurrentDeviceInfo,
+    in_route: &ExternalRoute,
+    out_route: &AllowExternalRoute,
+    local_ipv4: Option<Ipv4Addr>,
+    source: Ipv4Addr,
+    payload: &[u8],
+) -> io::Result<()> {
+    if !context.subnet_routes.is_accept() {
+        return Ok(());
+    }
+    let advert = SubnetAdvert::parse_from_bytes(payload)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("subnet_advert {:?}", e)))?;
+    let virtual_network = u32::from(current_device.virtual_network);
+    let virtual_netmask = u32::from(current_device.virtual_netmask);
+    let local_ipv4 = local_ipv4.map(u32::from);
+    let nets: Vec<(u32, u32)> = advert
+        .subnet_list
+        .iter()
+        .map(|info| (info.network & info.mask, info.mask))
+        .filter(|(dest, mask)| {
+            let overlap_mask = *mask & virtual_netmask;
+            // 默认路由只作为转发的最后选择，不检查网段冲突
+            (*mask == 0
+                || (*dest & overlap_mask != virtual_network & overlap_mask
+                    && !matches!(local_ipv4, Some(ip) if ip & *mask == *dest)))
+                && in_route.route(&Ipv4Addr::from(*dest)).is_none()
+                && !out_route.allow(&Ipv4Addr::from(*dest))
+        })
+        .collect();
+    let (added, removed) = context.subnet_routes.update(source, nets);
+    for (dest, mask) in removed {
+        log::info!("网关{}撤销网段 {}/{}", source, dest, mask);
+        delete_route(device, dest, mask);
+    }
+    for (dest, mask) in added {
+        if mask.is_unspecified() {
+            log::info!(
+                "网关{}通告默认路由，需要全部流量经过网关时使用 -i 0.0.0.0/0,{} 添加系统路由",
+                source,
+                source
+            );
+            continue;
+        }
+        log::info!("网关{}通告网段 {}/{}", source, dest, mask);
+        add_route(device, dest, mask);
+    }
+    Ok(())
+}
+
+/// 注入或删除网段后同步系统路由
+pub fn sync_subnet_routes(
+    device: &DeviceAdapter,
+    added: Vec<(Ipv4Addr, Ipv4Addr)>,
+    removed: Vec<(Ipv4Addr, Ipv4Addr)>,
+) {
+    for (dest, mask) in removed {
+        delete_route(device, dest, mask);
+    }
+    for (dest, mask) in added {
+        add_route(device, dest, mask);
+    }
+}
+
+/// 重新设置ip后恢复学习到的网段路由
+pub fn restore_subnet_routes(context: &ChannelContext, device: &DeviceAdapter) {
+    for (dest, mask, _) in context.subnet_routes.routes() {
+        add_route(device, dest, mask);
+    }
+}
+
+#[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
+fn add_route(device: &DeviceAdapter, dest: Ipv4Addr, mask: Ipv4Addr) {
+    // 不修改系统的默认路由
+    if mask.is_unspecified() {
+        return;
+    }
+    if let Err(e) = device.add_route(dest, mask, 1) {
+        log::warn!("添加网段路由失败 {}/{} {:?}", dest, mask, e);
+    }
+}
+
+#[cfg(target_os = "android")]
+fn add_route(_device: &DeviceAdapter, _dest: Ipv4Addr, _mask: Ipv4Addr) {}
+
+#[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
+fn delete_route(device: &DeviceAdapter, dest: Ipv4Addr, mask: Ipv4Addr) {
+    if mask.is_unspecified() {
+        return;
+    }
+    if let Err(e) = device.delete_route(dest, mask) {
+        log::warn!("删除网段路由失败 {}/{} {:?}", dest, mask, e);
+    }
+}
+
+#[cfg(target_os = "android")]
+fn delete_route(_device: &DeviceAdapter, _dest: Ipv4Addr, _mask: Ipv4Addr) {}
@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+use socket2::SockRef;
+
+use crate::channel::context::ChannelContext;
+use crate::channel::sock_buf::{drops, next_recv_size, recv_size, set_recv};
+use crate::util::Scheduler;
+
+/// 检查丢包的间隔
+const TUNE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 根据核心udp socket的丢包计数自动调大接收缓冲区，
+/// last为上次读到的丢包数，None表示已经调不上去了，不再调整
+pub fn udp_buf_tune(scheduler: &Scheduler, context: ChannelContext, mut last: Vec<Option<u32>>) {
+    if last.is_empty() {
+        last = context
+            .main_udp_socket
+            .iter()
+            .map(|udp| drops(&SockRef::from(udp)).ok())
+            .collect();
+    } else {
+        for (index, udp) in context.main_udp_socket.iter().enumerate() {
+            let previous = match last[index] {
+                Some(previous) => previous,
+                None => continue,
+            };
+            let socket = SockRef::from(udp);
+            let total = match drops(&socket) {
+                Ok(total) => total,
+                Err(e) => {
+                    log::warn!("读取udp丢包计数失败 {:?}", e);
+                    last[index] = None;
+                    continue;
+                }
+            };
+            last[index] = Some(total);
+            let current = recv_size(&socket);
+            if let Some(size) = next_recv_size(current, total.wrapping_sub(previous)) {
+                log::info!(
+                    "udp通道{}丢包{}，接收缓冲区从{}调整为{}",
+                    index,
+                    total.wrapping_sub(previous),
+                    current,
+                    size
+                );
+                set_recv(&socket, size);
+                if recv_size(&socket) <= current {
+                    last[index] = None;
+                }
+            }
+        }
+    }
+    let rs = scheduler.timeout(TUNE_INTERVAL, move |s| udp_buf_tune(s, context, last));
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
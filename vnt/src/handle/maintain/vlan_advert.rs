@@ -0,0 +1,158 @@
+use std::collections::HashSet;
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossbeam_utils::atomic::AtomicCell;
+use parking_lot::Mutex;
+use protobuf::Message;
+
+use crate::channel::context::ChannelContext;
+use crate::cipher::Cipher;
+use crate::handle::route_report::other_turn_packet;
+use crate::handle::{CurrentDeviceInfo, PeerDeviceInfo};
+use crate::proto::message::VlanAdvert;
+use crate::protocol::other_turn_packet;
+use crate::tun_tap_device::tun_create_helper::DeviceAdapter;
+use crate::util::Scheduler;
+
+/// vlan通告，tap模式下定时向在线设备通告自己所在的vlan，
+/// 并把对端的vlan同步到网卡
+pub fn vlan_advert(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
+    client_cipher: Cipher,
+    device: DeviceAdapter,
+) {
+    if !context.vlan.is_enable() {
+        return;
+    }
+    let mut advert = VlanAdvert::new();
+    advert.vlans = context.vlan.local().iter().map(|v| *v as u32).collect();
+    let payload = match advert.write_to_bytes() {
+        Ok(payload) => payload,
+        Err(e) => {
+            log::error!("vlan通告 {:?}", e);
+            return;
+        }
+    };
+    vlan_advert_(
+        scheduler,
+        context,
+        current_device,
+        device_list,
+        client_cipher,
+        device,
+        payload,
+    );
+}
+
+fn vlan_advert_(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
+    client_cipher: Cipher,
+    device: DeviceAdapter,
+    payload: Vec<u8>,
+) {
+    let peer_list = { device_list.lock().1.clone() };
+    let ips: HashSet<Ipv4Addr> = peer_list.iter().map(|peer| peer.virtual_ip).collect();
+    if context.vlan.sync(&ips) {
+        sync_vlan(&context, &device);
+    }
+    if let Err(e) = send_advert(
+        &context,
+        &current_device.load(),
+        &peer_list,
+        &client_cipher,
+        &payload,
+    ) {
+        log::warn!("vlan通告 {:?}", e);
+    }
+    let rs = scheduler.timeout(Duration::from_secs(10), move |s| {
+        vlan_advert_(
+            s,
+            context,
+            current_device,
+            device_list,
+            client_cipher,
+            device,
+            payload,
+        )
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
+
+fn send_advert(
+    context: &ChannelContext,
+    current_device: &CurrentDeviceInfo,
+    peer_list: &[PeerDeviceInfo],
+    client_cipher: &Cipher,
+    payload: &[u8],
+) -> io::Result<()> {
+    if current_device.status.offline() {
+        return Ok(());
+    }
+    for peer in peer_list {
+        if !peer.status.is_online()
+            || peer.virtual_ip == current_device.virtual_ip
+            || !context.peer_filter.is_allowed(&peer.virtual_ip)
+        {
+            continue;
+        }
+        let packet = other_turn_packet(
+            client_cipher,
+            other_turn_packet::Protocol::VlanAdvert,
+            current_device.virtual_ip,
+            peer.virtual_ip,
+            payload,
+        )?;
+        context.send_ipv4_by_id(
+            packet.buffer(),
+            &peer.virtual_ip,
+            current_device.connect_server,
+            true,
+        )?;
+    }
+    Ok(())
+}
+
+/// 收到对端的vlan通告
+pub fn handle_vlan_advert(
+    context: &ChannelContext,
+    device: &DeviceAdapter,
+    source: Ipv4Addr,
+    payload: &[u8],
+) -> io::Result<()> {
+    if !context.vlan.is_enable() {
+        return Ok(());
+    }
+    let advert = VlanAdvert::parse_from_bytes(payload)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("vlan_advert {:?}", e)))?;
+    let vlans: Vec<u16> = advert
+        .vlans
+        .iter()
+        .filter(|v| (1..=4094).contains(*v))
+        .map(|v| *v as u16)
+        .collect();
+    if context.vlan.update(source, vlans.clone()) {
+        log::info!("设备{}所在的vlan {:?}", source, vlans);
+        sync_vlan(context, device);
+    }
+    Ok(())
+}
+
+/// 把对端和本地共同所在的vlan同步到网卡
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+pub fn sync_vlan(context: &ChannelContext, device: &DeviceAdapter) {
+    device.set_tap_vlan_peers(context.vlan.shared());
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux")))]
+pub fn sync_vlan(_context: &ChannelContext, _device: &DeviceAdapter) {}
@@ -1,11 +1,25 @@
 use crossbeam_utils::atomic::AtomicCell;
 use std::net::{Ipv4Addr, SocketAddr};
 
+use crate::handle::presence::Presence;
+use crate::handle::server_hint::ServerHints;
+use crate::handle::server_ports::ServerPorts;
+use crate::tun_tap_device::dhcp_pool::DhcpPool;
+use crate::util::ServerProxy;
+
 pub mod callback;
+pub mod crypto_pool;
+pub mod diag;
 pub mod handshaker;
 pub mod maintain;
+pub mod presence;
 pub mod recv_data;
 pub mod registrar;
+pub mod route_report;
+pub mod server_hint;
+pub mod server_ports;
+pub mod service;
+pub mod speed_test;
 pub mod tun_tap;
 
 const SELF_IP: Ipv4Addr = Ipv4Addr::new(0, 0, 0, 2);
@@ -58,11 +72,22 @@ pub struct BaseConfigInfo {
     pub name: String,
     pub token: String,
     pub ip: Option<Ipv4Addr>,
-    pub client_secret_hash: Option<[u8; 16]>,
+    pub client_secret_hash: Option<Vec<u8>>,
     pub server_secret: bool,
     pub device_id: String,
     pub server_addr: String,
     pub name_servers: Vec<String>,
+    pub prefer_ipv6_server: bool,
+    // 通过代理连接服务器，仅tcp模式
+    pub server_proxy: Option<ServerProxy>,
+    // 服务端的备用端口
+    pub server_ports: ServerPorts,
+    // 对端通告的服务端地址
+    pub server_hints: ServerHints,
+    // 对端在线状态
+    pub presence: Presence,
+    // tap模式下dhcp分配给桥接设备的地址范围
+    pub dhcp_pool: Option<DhcpPool>,
 }
 
 impl BaseConfigInfo {
@@ -70,11 +95,17 @@ impl BaseConfigInfo {
         name: String,
         token: String,
         ip: Option<Ipv4Addr>,
-        client_secret_hash: Option<[u8; 16]>,
+        client_secret_hash: Option<Vec<u8>>,
         server_secret: bool,
         device_id: String,
         server_addr: String,
         name_servers: Vec<String>,
+        prefer_ipv6_server: bool,
+        server_proxy: Option<ServerProxy>,
+        server_ports: ServerPorts,
+        server_hints: ServerHints,
+        presence: Presence,
+        dhcp_pool: Option<DhcpPool>,
     ) -> Self {
         Self {
             name,
@@ -85,6 +116,12 @@ impl BaseConfigInfo {
             device_id,
             server_addr,
             name_servers,
+            prefer_ipv6_server,
+            server_proxy,
+            server_ports,
+            server_hints,
+            presence,
+            dhcp_pool,
         }
     }
 }
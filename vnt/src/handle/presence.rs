@@ -0,0 +1,268 @@
+use std::fmt::{Display, Formatter};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::{fmt, mem};
+
+use parking_lot::Mutex;
+
+use crate::handle::{PeerDeviceInfo, PeerDeviceStatus};
+use crate::proto::message::{DeviceDelta, DeviceInfo};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PresenceKind {
+    // 新加入网络
+    Join,
+    // 从设备列表中移除
+    Leave,
+    Online,
+    Offline,
+    // 虚拟ip变化，为之前的ip
+    IpChange(Ipv4Addr),
+    // 名称变化，为之前的名称
+    NameChange(String),
+}
+
+impl Display for PresenceKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PresenceKind::Join => f.write_str("join"),
+            PresenceKind::Leave => f.write_str("leave"),
+            PresenceKind::Online => f.write_str("online"),
+            PresenceKind::Offline => f.write_str("offline"),
+            PresenceKind::IpChange(ip) => write!(f, "ip-change({})", ip),
+            PresenceKind::NameChange(name) => write!(f, "name-change({})", name),
+        }
+    }
+}
+
+/// 单个对端的在线状态变化
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PresenceEvent {
+    pub ip: Ipv4Addr,
+    pub name: String,
+    pub kind: PresenceKind,
+}
+
+impl PresenceEvent {
+    fn new(info: &DeviceInfo, kind: PresenceKind) -> Self {
+        Self {
+            ip: Ipv4Addr::from(info.virtual_ip),
+            name: info.name.clone(),
+            kind,
+        }
+    }
+}
+
+/// 对端在线状态。
+/// 服务端在设备变化时推送增量，不再依赖心跳发现纪元变化后拉取全量列表，
+/// 增量的基础版本和本地不一致时才重新拉取；对端正常退出时也会直连通知
+#[derive(Clone)]
+pub struct Presence {
+    // 服务端下发的原始列表，增量在这个列表上合并
+    raw: Arc<Mutex<(u16, Vec<DeviceInfo>)>>,
+    device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
+}
+
+impl fmt::Debug for Presence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let guard = self.raw.lock();
+        f.debug_struct("Presence")
+            .field("epoch", &guard.0)
+            .field("devices", &guard.1.len())
+            .finish()
+    }
+}
+
+impl Presence {
+    pub fn new(device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>) -> Self {
+        Self {
+            raw: Arc::new(Mutex::new((0, Vec::new()))),
+            device_list,
+        }
+    }
+    /// 收到全量列表，返回和之前相比的变化
+    pub fn replace(&self, epoch: u16, list: &[DeviceInfo]) -> Vec<PresenceEvent> {
+        let mut guard = self.raw.lock();
+        let events = diff(&guard.1, list);
+        guard.0 = epoch;
+        guard.1 = list.to_vec();
+        events
+    }
+    /// 合并增量，返回合并后的全量列表和变化，基础版本不一致时返回None，需要重新拉取
+    pub fn apply(&self, delta: DeviceDelta) -> Option<(Vec<DeviceInfo>, Vec<PresenceEvent>)> {
+        let mut guard = self.raw.lock();
+        if guard.0 != delta.base_epoch as u16 {
+            return None;
+        }
+        let mut list = guard.1.clone();
+        let mut events = Vec::new();
+        for ip in delta.removed {
+            if let Some(pos) = list.iter().position(|v| v.virtual_ip == ip) {
+                let info = list.remove(pos);
+                events.push(PresenceEvent::new(&info, PresenceKind::Leave));
+            }
+        }
+        for change in delta.changes {
+            let info = match change.info.into_option() {
+                Some(info) => info,
+                None => continue,
+            };
+            let old_ip = if change.old_virtual_ip == 0 {
+                info.virtual_ip
+            } else {
+                change.old_virtual_ip
+            };
+            match list.iter().position(|v| v.virtual_ip == old_ip) {
+                Some(pos) => {
+                    let old = mem::replace(&mut list[pos], info);
+                    changed(&old, &list[pos], &mut events);
+                }
+                None => {
+                    events.push(PresenceEvent::new(&info, PresenceKind::Join));
+                    list.push(info);
+                }
+            }
+        }
+        guard.0 = delta.epoch as u16;
+        guard.1 = list.clone();
+        Some((list, events))
+    }
+    /// 对端直连通知下线，服务端要等超时才能发现，这里先标记为离线
+    pub fn offline(&self, ip: Ipv4Addr) -> Option<PresenceEvent> {
+        let event = {
+            let mut guard = self.raw.lock();
+            let info = guard.1.iter_mut().find(|v| v.virtual_ip == ip.into())?;
+            if !PeerDeviceStatus::from(info.device_status as u8).is_online() {
+                return None;
+            }
+            info.device_status = Into::<u8>::into(PeerDeviceStatus::Offline) as u32;
+            PresenceEvent::new(info, PresenceKind::Offline)
+        };
+        if let Some(peer) = self
+            .device_list
+            .lock()
+            .1
+            .iter_mut()
+            .find(|v| v.virtual_ip == ip)
+        {
+            peer.status = PeerDeviceStatus::Offline;
+        }
+        Some(event)
+    }
+}
+
+fn changed(old: &DeviceInfo, new: &DeviceInfo, events: &mut Vec<PresenceEvent>) {
+    if old.virtual_ip != new.virtual_ip {
+        events.push(PresenceEvent::new(
+            new,
+            PresenceKind::IpChange(Ipv4Addr::from(old.virtual_ip)),
+        ));
+    }
+    if old.name != new.name {
+        events.push(PresenceEvent::new(
+            new,
+            PresenceKind::NameChange(old.name.clone()),
+        ));
+    }
+    let online = PeerDeviceStatus::from(new.device_status as u8).is_online();
+    if PeerDeviceStatus::from(old.device_status as u8).is_online() != online {
+        let kind = if online {
+            PresenceKind::Online
+        } else {
+            PresenceKind::Offline
+        };
+        events.push(PresenceEvent::new(new, kind));
+    }
+}
+
+/// 全量列表的变化，ip不同但名称相同的视为同一个设备换了ip
+fn diff(old: &[DeviceInfo], new: &[DeviceInfo]) -> Vec<PresenceEvent> {
+    let mut events = Vec::new();
+    let mut matched = vec![false; old.len()];
+    for info in new {
+        let pos = old
+            .iter()
+            .position(|v| v.virtual_ip == info.virtual_ip)
+            .or_else(|| {
+                old.iter().enumerate().position(|(i, v)| {
+                    !matched[i]
+                        && v.name == info.name
+                        && !new.iter().any(|n| n.virtual_ip == v.virtual_ip)
+                })
+            });
+        match pos {
+            Some(pos) => {
+                matched[pos] = true;
+                changed(&old[pos], info, &mut events);
+            }
+            None => events.push(PresenceEvent::new(info, PresenceKind::Join)),
+        }
+    }
+    for (i, info) in old.iter().enumerate() {
+        if !matched[i] {
+            events.push(PresenceEvent::new(info, PresenceKind::Leave));
+        }
+    }
+    events
+}
+
+#[test]
+fn test_presence() {
+    use crate::proto::message::DeviceChange;
+    let device = |name: &str, ip: u32, status: u32| {
+        let mut info = DeviceInfo::new();
+        info.name = name.into();
+        info.virtual_ip = ip;
+        info.device_status = status;
+        info
+    };
+    let presence = Presence::new(Arc::new(Mutex::new((0, Vec::new()))));
+    let events = presence.replace(1, &[device("a", 2, 0), device("b", 3, 0)]);
+    assert_eq!(events.len(), 2);
+    assert!(events.iter().all(|v| v.kind == PresenceKind::Join));
+
+    // 基础版本不一致
+    let mut delta = DeviceDelta::new();
+    delta.base_epoch = 0;
+    delta.epoch = 2;
+    assert!(presence.apply(delta.clone()).is_none());
+
+    delta.base_epoch = 1;
+    delta.removed.push(3);
+    let mut change = DeviceChange::new();
+    change.info = protobuf::MessageField::some(device("c", 5, 1));
+    change.old_virtual_ip = 2;
+    delta.changes.push(change);
+    let mut change = DeviceChange::new();
+    change.info = protobuf::MessageField::some(device("d", 6, 0));
+    delta.changes.push(change);
+    let (list, events) = presence.apply(delta).unwrap();
+    assert_eq!(list.len(), 2);
+    let kinds: Vec<PresenceKind> = events.into_iter().map(|v| v.kind).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            PresenceKind::Leave,
+            PresenceKind::IpChange(Ipv4Addr::from(2)),
+            PresenceKind::NameChange("a".into()),
+            PresenceKind::Offline,
+            PresenceKind::Join,
+        ]
+    );
+    assert_eq!(presence.offline(Ipv4Addr::from(5)), None);
+    assert_eq!(
+        presence.offline(Ipv4Addr::from(6)).map(|v| v.kind),
+        Some(PresenceKind::Offline)
+    );
+
+    // 全量列表中名称相同ip不同的视为换了ip
+    let events = presence.replace(3, &[device("d", 7, 1)]);
+    let kinds: Vec<PresenceKind> = events.into_iter().map(|v| v.kind).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            PresenceKind::IpChange(Ipv4Addr::from(6)),
+            PresenceKind::Leave
+        ]
+    );
+}
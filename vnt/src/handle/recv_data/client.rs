@@ -4,25 +4,42 @@ use std::collections::HashMap;
 use std::io;
 use std::net::{Ipv4Addr, Ipv6Addr};
 use std::sync::Arc;
+use std::time::Duration;
 
 use packet::icmp::{icmp, Kind};
 use packet::ip::ipv4;
 use packet::ip::ipv4::packet::IpV4Packet;
 
+use crate::channel::coalesce;
 use crate::channel::context::ChannelContext;
+use crate::channel::fragment::clamp_mss;
+use crate::channel::padding;
 use crate::channel::punch::NatInfo;
+use crate::channel::unreachable::{unreachable_packet, Unreachable};
 use crate::channel::{Route, RouteKey};
-use crate::cipher::Cipher;
-use crate::external_route::AllowExternalRoute;
-use crate::handle::maintain::PunchSender;
+use crate::cipher::identity::SIGNATURE_LEN;
+use crate::cipher::{Cipher, SEQ_LEN};
+use crate::external_route::{AllowExternalRoute, ExternalRoute};
+use crate::handle::diag::Diag;
+use crate::handle::maintain::{self, PunchSender};
+use crate::handle::presence::Presence;
 use crate::handle::recv_data::PacketHandler;
+use crate::handle::route_report;
+use crate::handle::server_hint::ServerHints;
+use crate::handle::service::{ServiceProtocol, ServiceRegistry, ServiceReply};
+use crate::handle::speed_test::SpeedTest;
 use crate::handle::CurrentDeviceInfo;
 #[cfg(feature = "ip_proxy")]
 use crate::ip_proxy::{IpProxyMap, ProxyHandler};
 use crate::nat::NatTest;
-use crate::proto::message::{PunchInfo, PunchNatType};
+use crate::proto::message::{PunchInfo, PunchNatFiltering, PunchNatType};
 use crate::protocol::body::ENCRYPTION_RESERVED;
-use crate::protocol::control_packet::ControlPacket;
+use crate::protocol::capability::Capabilities;
+use crate::protocol::control_packet::{
+    ControlPacket, MtuProbePacket, PongPacket, MTU_PROBE_LEN, PING_FLAG_COALESCE,
+    PING_FLAG_FRAGMENT, PING_FLAG_TIME32, PING_LEN, PING_SIGNED_LEN, PING_TIME32_LEN,
+};
+use crate::protocol::ip_turn_packet::FragmentPacket;
 use crate::protocol::{
     control_packet, ip_turn_packet, other_turn_packet, NetPacket, Protocol, MAX_TTL,
 };
@@ -38,9 +55,15 @@ pub struct ClientPacketHandler {
     punch_sender: PunchSender,
     peer_nat_info_map: Arc<RwLock<HashMap<Ipv4Addr, NatInfo>>>,
     nat_test: NatTest,
+    in_route: ExternalRoute,
     route: AllowExternalRoute,
     #[cfg(feature = "ip_proxy")]
     ip_proxy_map: Option<IpProxyMap>,
+    diag: Diag,
+    speed_test: SpeedTest,
+    services: ServiceRegistry,
+    server_hints: ServerHints,
+    presence: Presence,
 }
 
 impl ClientPacketHandler {
@@ -50,8 +73,14 @@ impl ClientPacketHandler {
         punch_sender: PunchSender,
         peer_nat_info_map: Arc<RwLock<HashMap<Ipv4Addr, NatInfo>>>,
         nat_test: NatTest,
+        in_route: ExternalRoute,
         route: AllowExternalRoute,
         #[cfg(feature = "ip_proxy")] ip_proxy_map: Option<IpProxyMap>,
+        diag: Diag,
+        speed_test: SpeedTest,
+        services: ServiceRegistry,
+        server_hints: ServerHints,
+        presence: Presence,
     ) -> Self {
         Self {
             device,
@@ -59,9 +88,15 @@ impl ClientPacketHandler {
             punch_sender,
             peer_nat_info_map,
             nat_test,
+            in_route,
             route,
             #[cfg(feature = "ip_proxy")]
             ip_proxy_map,
+            diag,
+            speed_test,
+            services,
+            server_hints,
+            presence,
         }
     }
 }
@@ -74,7 +109,24 @@ impl PacketHandler for ClientPacketHandler {
         context: &ChannelContext,
         current_device: &CurrentDeviceInfo,
     ) -> io::Result<()> {
-        self.client_cipher.decrypt_ipv4(&mut net_packet)?;
+        if !context.peer_filter.is_allowed(&net_packet.source()) && is_filtered(&net_packet) {
+            log::debug!("拒绝对端");
+            return Ok(());
+        }
+        let client_cipher = if net_packet.protocol() == Protocol::IpTurn
+            && net_packet.destination() == current_device.virtual_ip
+        {
+            // 发给自己的ip数据可能使用了psk
+            context
+                .pairwise_cipher
+                .get(&net_packet.source(), &self.client_cipher)
+        } else {
+            &self.client_cipher
+        };
+        if let Err(e) = client_cipher.decrypt_ipv4(&mut net_packet) {
+            context.traffic.cipher_error_add();
+            return Err(e);
+        }
         context
             .route_table
             .update_read_time(&net_packet.source(), &route_key);
@@ -85,6 +137,10 @@ impl PacketHandler for ClientPacketHandler {
                 self.control(context, current_device, net_packet, route_key)?;
             }
             Protocol::IpTurn => {
+                // 没有经过其他节点转发的才是直连
+                let p2p = net_packet.source_ttl() == net_packet.ttl()
+                    && route_key.addr != current_device.connect_server;
+                context.traffic.rx(net_packet.buffer().len(), p2p);
                 self.ip_turn(net_packet, context, current_device, route_key)?;
             }
             Protocol::OtherTurn => {
@@ -96,6 +152,24 @@ impl PacketHandler for ClientPacketHandler {
     }
 }
 
+/// 黑白名单拦截的包：ip数据和打洞相关的消息，心跳等不受影响
+fn is_filtered(net_packet: &NetPacket<&mut [u8]>) -> bool {
+    match net_packet.protocol() {
+        Protocol::IpTurn => true,
+        Protocol::OtherTurn => matches!(
+            other_turn_packet::Protocol::from(net_packet.transport_protocol()),
+            other_turn_packet::Protocol::Punch
+                | other_turn_packet::Protocol::SubnetAdvert
+                | other_turn_packet::Protocol::VlanAdvert
+        ),
+        Protocol::Control => matches!(
+            control_packet::Protocol::from(net_packet.transport_protocol()),
+            control_packet::Protocol::PunchRequest | control_packet::Protocol::PunchResponse
+        ),
+        _ => false,
+    }
+}
+
 impl ClientPacketHandler {
     fn ip_turn(
         &self,
@@ -108,74 +182,107 @@ impl ClientPacketHandler {
         let source = net_packet.source();
         match ip_turn_packet::Protocol::from(net_packet.transport_protocol()) {
             ip_turn_packet::Protocol::Ipv4 => {
-                let mut ipv4 = IpV4Packet::new(net_packet.payload_mut())?;
-                match ipv4.protocol() {
-                    ipv4::protocol::Protocol::Icmp => {
-                        if ipv4.destination_ip() == destination {
-                            let mut icmp_packet = icmp::IcmpPacket::new(ipv4.payload_mut())?;
-                            if icmp_packet.kind() == Kind::EchoRequest {
-                                //开启ping
-                                icmp_packet.set_kind(Kind::EchoReply);
-                                icmp_packet.update_checksum();
-                                ipv4.set_source_ip(destination);
-                                ipv4.set_destination_ip(source);
-                                ipv4.update_checksum();
-                                net_packet.set_source(destination);
-                                net_packet.set_destination(source);
-                                //不管加不加密，和接收到的数据长度都一致
-                                self.client_cipher.encrypt_ipv4(&mut net_packet)?;
-                                context.send_by_key(net_packet.buffer(), route_key)?;
-                                return Ok(());
-                            }
-                        }
+                if let Some(replay_guard) = &context.replay_guard {
+                    if !replay_guard.open(&mut net_packet)? {
+                        //重放的包
+                        return Ok(());
                     }
-                    _ => {}
-                }
-                // ip代理只关心实际目标
-                let real_dest = ipv4.destination_ip();
-                if real_dest != destination
-                    && !(real_dest.is_broadcast()
-                        || real_dest.is_multicast()
-                        || real_dest == current_device.broadcast_ip
-                        || real_dest.is_unspecified())
-                {
-                    if !self.route.allow(&real_dest) {
-                        //拦截不符合的目标
+                }
+                context.power_save.active();
+                if self.ipv4(&mut net_packet, context, current_device, route_key)? {
+                    self.device.write(net_packet.payload())?;
+                }
+            }
+            ip_turn_packet::Protocol::Ipv4Batch => {
+                if let Some(replay_guard) = &context.replay_guard {
+                    if !replay_guard.open(&mut net_packet)? {
                         return Ok(());
                     }
-                    match ipv4.protocol() {
-                        ipv4::protocol::Protocol::Tcp => {
-                            let payload = ipv4.payload();
-                            if payload.len() < 20 {
-                                return Ok(());
-                            }
-                            let destination_port =
-                                u16::from_be_bytes(payload[2..4].try_into().unwrap());
-                            if self.nat_test.is_local_tcp(real_dest, destination_port) {
-                                return Ok(());
-                            }
-                        }
-                        ipv4::protocol::Protocol::Udp => {
-                            let payload = ipv4.payload();
-                            if payload.len() < 8 {
-                                return Ok(());
-                            }
-                            let destination_port =
-                                u16::from_be_bytes(payload[2..4].try_into().unwrap());
-                            if self.nat_test.is_local_udp(real_dest, destination_port) {
-                                return Ok(());
-                            }
+                }
+                context.power_save.active();
+                // 合并发送的一批包，处理完后按顺序一次写入虚拟网卡
+                let list = coalesce::split(net_packet.payload())?;
+                // 拆分成单独的包处理，回应icmp时需要预留加密的空间，所有包共用一块缓冲区
+                let reserve = 12 + SEQ_LEN + ENCRYPTION_RESERVED;
+                let mut arena = vec![0u8; list.iter().map(|v| v.len() + reserve).sum()];
+                let mut queue: Vec<(usize, usize)> = Vec::with_capacity(list.len());
+                let mut offset = 0;
+                for ip_packet in list {
+                    let end = offset + ip_packet.len() + reserve;
+                    let mut packet =
+                        NetPacket::new0(12 + ip_packet.len(), &mut arena[offset..end])?;
+                    packet.set_default_version();
+                    packet.set_protocol(Protocol::IpTurn);
+                    packet.set_transport_protocol(ip_turn_packet::Protocol::Ipv4.into());
+                    packet.first_set_ttl(MAX_TTL);
+                    packet.set_source(source);
+                    packet.set_destination(destination);
+                    packet.set_payload(ip_packet)?;
+                    match self.ipv4(&mut packet, context, current_device, route_key) {
+                        Ok(true) => queue.push((offset + 12, ip_packet.len())),
+                        Ok(false) => {}
+                        Err(e) => {
+                            log::warn!("batch err={:?}", e);
                         }
-                        _ => {}
                     }
-                    #[cfg(feature = "ip_proxy")]
-                    if let Some(ip_proxy_map) = &self.ip_proxy_map {
-                        if ip_proxy_map.recv_handle(&mut ipv4, source, destination)? {
-                            return Ok(());
-                        }
+                    offset = end;
+                }
+                if !queue.is_empty() {
+                    let bufs: Vec<&[u8]> = queue
+                        .iter()
+                        .map(|(start, len)| &arena[*start..start + len])
+                        .collect();
+                    self.device.write_batch(&bufs)?;
+                }
+            }
+            ip_turn_packet::Protocol::Ipv4Fragment => {
+                if let Some(replay_guard) = &context.replay_guard {
+                    if !replay_guard.open(&mut net_packet)? {
+                        return Ok(());
                     }
                 }
-                self.device.write(net_packet.payload())?;
+                context.power_save.active();
+                let fragment = FragmentPacket::new(net_packet.payload())?;
+                let ip_packet = match context.path_mtu.reassemble(
+                    source,
+                    fragment.id(),
+                    fragment.index(),
+                    fragment.count(),
+                    fragment.data(),
+                ) {
+                    Some(ip_packet) => ip_packet,
+                    None => return Ok(()),
+                };
+                let mut buf = vec![0u8; 12 + ip_packet.len() + SEQ_LEN + ENCRYPTION_RESERVED];
+                let mut packet = NetPacket::new0(12 + ip_packet.len(), &mut buf[..])?;
+                packet.set_default_version();
+                packet.set_protocol(Protocol::IpTurn);
+                packet.set_transport_protocol(ip_turn_packet::Protocol::Ipv4.into());
+                packet.first_set_ttl(MAX_TTL);
+                packet.set_source(source);
+                packet.set_destination(destination);
+                packet.set_payload(&ip_packet)?;
+                if self.ipv4(&mut packet, context, current_device, route_key)? {
+                    self.device.write(packet.payload())?;
+                }
+            }
+            ip_turn_packet::Protocol::Ipv4Padded => {
+                if let Some(replay_guard) = &context.replay_guard {
+                    if !replay_guard.open(&mut net_packet)? {
+                        return Ok(());
+                    }
+                }
+                let len = match padding::unpad(net_packet.payload()) {
+                    Some(len) => len,
+                    // 掩护包
+                    None => return Ok(()),
+                };
+                net_packet.set_data_len(12 + len)?;
+                net_packet.set_transport_protocol(ip_turn_packet::Protocol::Ipv4.into());
+                context.power_save.active();
+                if self.ipv4(&mut net_packet, context, current_device, route_key)? {
+                    self.device.write(net_packet.payload())?;
+                }
             }
             ip_turn_packet::Protocol::Ipv4Broadcast => {
                 //客户端不帮忙转发广播包，所以不会出现这种类型的数据
@@ -184,6 +291,145 @@ impl ClientPacketHandler {
         }
         Ok(())
     }
+    /// 处理单个ip包，返回true表示需要写入虚拟网卡
+    fn ipv4(
+        &self,
+        net_packet: &mut NetPacket<&mut [u8]>,
+        context: &ChannelContext,
+        current_device: &CurrentDeviceInfo,
+        route_key: RouteKey,
+    ) -> io::Result<bool> {
+        let destination = net_packet.destination();
+        let source = net_packet.source();
+        if let Some(limit) = context.path_mtu.limit(&source) {
+            // 对端发来的syn带的mss决定本机发往对端的tcp包长度
+            clamp_mss(net_packet.payload_mut(), limit);
+        }
+        let mut ipv4 = IpV4Packet::new(net_packet.payload_mut())?;
+        if !ipv4.is_consistent() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("ipv4 length {} inconsistent", ipv4.length()),
+            ));
+        }
+        if !context
+            .peer_filter
+            .acl_allowed(&source, ipv4.protocol(), ipv4.offset(), ipv4.payload())
+        {
+            log::debug!("acl拒绝 protocol={:?}", ipv4.protocol());
+            return Ok(false);
+        }
+        match ipv4.protocol() {
+            ipv4::protocol::Protocol::Icmp => {
+                if ipv4.destination_ip() == destination {
+                    let mut icmp_packet = icmp::IcmpPacket::new(ipv4.payload_mut())?;
+                    if icmp_packet.kind() == Kind::EchoRequest {
+                        //开启ping
+                        icmp_packet.set_kind(Kind::EchoReply);
+                        icmp_packet.update_checksum();
+                        ipv4.set_source_ip(destination);
+                        ipv4.set_destination_ip(source);
+                        ipv4.update_checksum();
+                        net_packet.set_source(destination);
+                        net_packet.set_destination(source);
+                        //不管加不加密，和接收到的数据长度都一致
+                        if let Some(replay_guard) = &context.replay_guard {
+                            replay_guard.seal(net_packet)?;
+                        }
+                        context
+                            .pairwise_cipher
+                            .get(&source, &self.client_cipher)
+                            .encrypt_ipv4(net_packet)?;
+                        context.send_by_key(net_packet.buffer(), route_key)?;
+                        return Ok(false);
+                    }
+                }
+            }
+            _ => {}
+        }
+        // ip代理只关心实际目标
+        let real_dest = ipv4.destination_ip();
+        if real_dest != destination
+            && !(real_dest.is_broadcast()
+                || real_dest.is_multicast()
+                || real_dest == current_device.broadcast_ip
+                || real_dest.is_unspecified())
+        {
+            if !self.route.allow(&real_dest) {
+                //拦截不符合的目标，回应icmp不可达
+                self.reply_unreachable(context, current_device, ipv4.buffer, source, route_key)?;
+                return Ok(false);
+            }
+            match ipv4.protocol() {
+                ipv4::protocol::Protocol::Tcp => {
+                    let payload = ipv4.payload();
+                    if payload.len() < 20 {
+                        return Ok(false);
+                    }
+                    let destination_port = u16::from_be_bytes(payload[2..4].try_into().unwrap());
+                    if self.nat_test.is_local_tcp(real_dest, destination_port) {
+                        return Ok(false);
+                    }
+                }
+                ipv4::protocol::Protocol::Udp => {
+                    let payload = ipv4.payload();
+                    if payload.len() < 8 {
+                        return Ok(false);
+                    }
+                    let destination_port = u16::from_be_bytes(payload[2..4].try_into().unwrap());
+                    if self.nat_test.is_local_udp(real_dest, destination_port) {
+                        return Ok(false);
+                    }
+                }
+                _ => {}
+            }
+            #[cfg(feature = "ip_proxy")]
+            if let Some(ip_proxy_map) = &self.ip_proxy_map {
+                if ip_proxy_map.recv_handle(&mut ipv4, source, destination)? {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+    /// 通过收到数据的通道回应对端icmp不可达
+    fn reply_unreachable(
+        &self,
+        context: &ChannelContext,
+        current_device: &CurrentDeviceInfo,
+        ip_packet: &[u8],
+        peer: Ipv4Addr,
+        route_key: RouteKey,
+    ) -> io::Result<()> {
+        if !context.unreachable_limiter.allow() {
+            return Ok(());
+        }
+        let icmp = match unreachable_packet(
+            ip_packet,
+            current_device.virtual_ip,
+            Unreachable::Prohibited,
+        ) {
+            Some(icmp) => icmp,
+            None => return Ok(()),
+        };
+        let mut buf = vec![0u8; 12 + icmp.len() + SEQ_LEN + ENCRYPTION_RESERVED];
+        let mut net_packet = NetPacket::new0(12 + icmp.len(), &mut buf[..])?;
+        net_packet.set_default_version();
+        net_packet.set_protocol(Protocol::IpTurn);
+        net_packet.set_transport_protocol(ip_turn_packet::Protocol::Ipv4.into());
+        net_packet.first_set_ttl(MAX_TTL);
+        net_packet.set_source(current_device.virtual_ip);
+        net_packet.set_destination(peer);
+        net_packet.set_payload(&icmp)?;
+        if let Some(replay_guard) = &context.replay_guard {
+            replay_guard.seal(&mut net_packet)?;
+        }
+        context
+            .pairwise_cipher
+            .get(&peer, &self.client_cipher)
+            .encrypt_ipv4(&mut net_packet)?;
+        context.send_by_key(net_packet.buffer(), route_key)
+    }
     fn control(
         &self,
         context: &ChannelContext,
@@ -193,28 +439,109 @@ impl ClientPacketHandler {
     ) -> io::Result<()> {
         let metric = net_packet.source_ttl() - net_packet.ttl() + 1;
         let source = net_packet.source();
+        let destination = net_packet.destination();
         match ControlPacket::new(net_packet.transport_protocol(), net_packet.payload())? {
-            ControlPacket::PingPacket(_) => {
+            ControlPacket::PingPacket(ping_packet) => {
+                if !context.peer_auth.verify(
+                    Protocol::Control.into(),
+                    control_packet::Protocol::Ping.into(),
+                    source,
+                    destination,
+                    ping_packet.signed_data(),
+                    ping_packet.signature(),
+                ) {
+                    log::warn!("心跳签名校验失败");
+                    return Ok(());
+                }
+                if metric == 1 {
+                    maintain::on_direct_inbound(
+                        context,
+                        &self.client_cipher,
+                        current_device,
+                        source,
+                        route_key,
+                    )?;
+                }
+                context
+                    .capabilities
+                    .set_peer_ping_flags(source, ping_packet.flags());
+                context
+                    .coalesce
+                    .set_peer_support(source, ping_packet.flags() & PING_FLAG_COALESCE != 0);
+                context
+                    .path_mtu
+                    .set_peer_support(source, ping_packet.flags() & PING_FLAG_FRAGMENT != 0);
+                // 32位时间戳原样带回
+                let time32 = ping_packet.time32();
+                // 回应中带上自己的能力
+                let mut flags = if context.coalesce.is_enable() {
+                    PING_FLAG_COALESCE | PING_FLAG_FRAGMENT
+                } else {
+                    PING_FLAG_FRAGMENT
+                };
+                if time32.is_some() {
+                    flags |= PING_FLAG_TIME32;
+                }
+                let mut pong_packet = PongPacket::new(net_packet.payload_mut())?;
+                pong_packet.set_flags(flags);
                 net_packet.set_transport_protocol(control_packet::Protocol::Pong.into());
                 net_packet.set_source(current_device.virtual_ip);
                 net_packet.set_destination(source);
                 net_packet.first_set_ttl(MAX_TTL);
+                // 替换成自己的签名，不能签名时去掉对端的签名
+                let len = net_packet.payload().len();
+                if len >= PING_LEN {
+                    match context.peer_auth.sign(
+                        Protocol::Control.into(),
+                        control_packet::Protocol::Pong.into(),
+                        current_device.virtual_ip,
+                        source,
+                        &net_packet.payload()[..PING_LEN],
+                    ) {
+                        Some(signature) if len >= PING_SIGNED_LEN => {
+                            net_packet.set_data_len(12 + PING_SIGNED_LEN)?;
+                            PongPacket::new(net_packet.payload_mut())?.set_signature(&signature);
+                        }
+                        _ => net_packet.set_data_len(12 + PING_LEN)?,
+                    }
+                    if let Some(time32) = time32 {
+                        let len = net_packet.payload().len();
+                        net_packet.set_data_len(12 + len + PING_TIME32_LEN)?;
+                        PongPacket::new(net_packet.payload_mut())?.set_time32(time32);
+                    }
+                }
                 self.client_cipher.encrypt_ipv4(&mut net_packet)?;
                 context.send_by_key(net_packet.buffer(), route_key)?;
                 let route = Route::from_default_rt(route_key, metric);
                 context.route_table.add_route_if_absent(source, route);
             }
             ControlPacket::PongPacket(pong_packet) => {
-                let current_time = crate::handle::now_time() as u16;
-                if current_time < pong_packet.time() {
+                if !context.peer_auth.verify(
+                    Protocol::Control.into(),
+                    control_packet::Protocol::Pong.into(),
+                    source,
+                    destination,
+                    pong_packet.signed_data(),
+                    pong_packet.signature(),
+                ) {
+                    log::warn!("心跳签名校验失败");
                     return Ok(());
                 }
-                let rt = (current_time - pong_packet.time()) as i64;
+                if pong_packet.flags() & PING_FLAG_COALESCE != 0 {
+                    context.coalesce.set_peer_support(source, true);
+                }
+                if pong_packet.flags() & PING_FLAG_FRAGMENT != 0 {
+                    context.path_mtu.set_peer_support(source, true);
+                }
+                let rt = match maintain::pong_rt(&pong_packet) {
+                    Some(rt) => rt,
+                    None => return Ok(()),
+                };
                 let route = Route::from(route_key, metric, rt);
                 context.route_table.add_route(source, route);
             }
             ControlPacket::PunchRequest => {
-                log::info!("PunchRequest={:?},source={}", route_key, source);
+                log::info!("收到打洞请求");
                 if context.use_channel_type().is_only_relay() {
                     return Ok(());
                 }
@@ -225,12 +552,33 @@ impl ClientPacketHandler {
                 {
                     return Ok(());
                 }
+                maintain::on_direct_inbound(
+                    context,
+                    &self.client_cipher,
+                    current_device,
+                    source,
+                    route_key,
+                )?;
 
                 //回应
                 net_packet.set_transport_protocol(control_packet::Protocol::PunchResponse.into());
                 net_packet.set_source(current_device.virtual_ip);
                 net_packet.set_destination(source);
                 net_packet.first_set_ttl(1);
+                // 请求带签名时才有空间放自己的签名
+                match context.peer_auth.sign(
+                    Protocol::Control.into(),
+                    control_packet::Protocol::PunchResponse.into(),
+                    current_device.virtual_ip,
+                    source,
+                    &[],
+                ) {
+                    Some(signature) if net_packet.payload().len() >= SIGNATURE_LEN => {
+                        net_packet.set_data_len(12 + SIGNATURE_LEN)?;
+                        net_packet.payload_mut().copy_from_slice(&signature);
+                    }
+                    _ => net_packet.set_data_len(12)?,
+                }
                 self.client_cipher.encrypt_ipv4(&mut net_packet)?;
                 context.send_by_key(net_packet.buffer(), route_key)?;
                 // 收到PunchRequest就添加路由，会导致单向通信的问题，删掉试试
@@ -238,7 +586,7 @@ impl ClientPacketHandler {
                 // context.route_table.add_route_if_absent(source, route);
             }
             ControlPacket::PunchResponse => {
-                log::info!("PunchResponse={:?},source={}", route_key, source);
+                log::info!("收到打洞响应");
                 if context.use_channel_type().is_only_relay() {
                     return Ok(());
                 }
@@ -248,6 +596,17 @@ impl ClientPacketHandler {
                 {
                     return Ok(());
                 }
+                if !context.peer_auth.verify(
+                    Protocol::Control.into(),
+                    control_packet::Protocol::PunchResponse.into(),
+                    source,
+                    destination,
+                    &[],
+                    net_packet.payload().get(..SIGNATURE_LEN),
+                ) {
+                    log::warn!("打洞响应签名校验失败");
+                    return Ok(());
+                }
                 let route = Route::from_default_rt(route_key, 1);
                 context.route_table.add_route_if_absent(source, route);
             }
@@ -269,6 +628,85 @@ impl ClientPacketHandler {
                 std::net::IpAddr::V6(_) => {}
             },
             ControlPacket::AddrResponse(_) => {}
+            ControlPacket::Congestion(congestion_packet) => {
+                context.relay_pacer.on_signal(
+                    congestion_packet.destination(),
+                    congestion_packet.rate() as u64 * 1024,
+                    Duration::from_millis(congestion_packet.hold() as u64),
+                );
+            }
+            ControlPacket::MtuProbe(probe_packet) => {
+                if probe_packet.is_reply() {
+                    context.path_mtu.on_reply(source, probe_packet.size());
+                    return Ok(());
+                }
+                // 回应不需要填充
+                let mut packet =
+                    NetPacket::new_encrypt([0; 12 + MTU_PROBE_LEN + ENCRYPTION_RESERVED])?;
+                packet.set_default_version();
+                packet.set_protocol(Protocol::Control);
+                packet.set_transport_protocol(control_packet::Protocol::MtuProbe.into());
+                packet.first_set_ttl(MAX_TTL);
+                packet.set_source(current_device.virtual_ip);
+                packet.set_destination(source);
+                let mut reply = MtuProbePacket::new(packet.payload_mut())?;
+                reply.set_size(probe_packet.size());
+                reply.set_reply(true);
+                self.client_cipher.encrypt_ipv4(&mut packet)?;
+                context.send_by_key(packet.buffer(), route_key)?;
+            }
+            ControlPacket::IpAnnounce(instance_packet) => {
+                maintain::handle_ip_announce(
+                    context,
+                    &self.client_cipher,
+                    current_device,
+                    source,
+                    instance_packet.instance_id(),
+                    route_key,
+                )?;
+            }
+            ControlPacket::IpConflict(instance_packet) => {
+                if destination == current_device.virtual_ip {
+                    log::warn!("设备{}通知ip冲突", source);
+                    context.ip_conflict.detect(instance_packet.instance_id());
+                }
+            }
+            ControlPacket::PathNotice => {
+                let nat_info = self.peer_nat_info_map.read().get(&source).cloned();
+                maintain::on_path_notice(
+                    context,
+                    &self.client_cipher,
+                    current_device,
+                    source,
+                    nat_info,
+                )?;
+            }
+            ControlPacket::PathProbe(probe_packet) => {
+                if metric == 1 {
+                    maintain::on_path_probe(
+                        context,
+                        &self.client_cipher,
+                        current_device,
+                        source,
+                        &probe_packet,
+                    )?;
+                }
+            }
+            ControlPacket::PathConfirm(probe_packet) => {
+                maintain::on_path_confirm(context, source, &probe_packet);
+            }
+            ControlPacket::ServerHint(data) => {
+                // 只接受直连收到的通告
+                if metric == 1 {
+                    maintain::on_server_hint(&self.server_hints, current_device, source, data);
+                }
+            }
+            ControlPacket::PeerOffline => {
+                // 只接受直连收到的通知
+                if metric == 1 {
+                    maintain::on_peer_offline(context, &self.presence, source, route_key);
+                }
+            }
         }
         Ok(())
     }
@@ -279,16 +717,31 @@ impl ClientPacketHandler {
         net_packet: NetPacket<&mut [u8]>,
         route_key: RouteKey,
     ) -> io::Result<()> {
-        if context.use_channel_type().is_only_relay() {
-            return Ok(());
-        }
         let source = net_packet.source();
         match other_turn_packet::Protocol::from(net_packet.transport_protocol()) {
             other_turn_packet::Protocol::Punch => {
+                if context.use_channel_type().is_only_relay() {
+                    return Ok(());
+                }
                 let mut punch_info =
                     PunchInfo::parse_from_bytes(net_packet.payload()).map_err(|e| {
                         io::Error::new(io::ErrorKind::Other, format!("PunchInfo {:?}", e))
                     })?;
+                if !context.peer_auth.verify_punch_info(
+                    source,
+                    net_packet.destination(),
+                    &punch_info,
+                ) {
+                    log::warn!("打洞信息签名校验失败");
+                    return Ok(());
+                }
+                // 旧版本不带能力位图，保留心跳中得知的能力
+                if punch_info.capabilities != 0 {
+                    context
+                        .capabilities
+                        .set_peer(source, Capabilities::from_bits(punch_info.capabilities));
+                }
+                context.conn_ids.set_peer(source, punch_info.conn_id);
                 let public_ips = punch_info
                     .public_ip_list
                     .iter()
@@ -310,7 +763,7 @@ impl ClientPacketHandler {
                 if punch_info.udp_ports.is_empty() {
                     punch_info.udp_ports.push(punch_info.local_port);
                 }
-                let peer_nat_info = NatInfo::new(
+                let mut peer_nat_info = NatInfo::new(
                     public_ips,
                     punch_info.public_ports.iter().map(|e| *e as u16).collect(),
                     punch_info.public_port_range as u16,
@@ -320,6 +773,17 @@ impl ClientPacketHandler {
                     tcp_port,
                     punch_info.nat_type.enum_value_or_default().into(),
                 );
+                peer_nat_info.filtering = punch_info.filtering.enum_value_or_default().into();
+                peer_nat_info.host_id = punch_info.host_id;
+                peer_nat_info.uds_id = punch_info.uds_id;
+                peer_nat_info.same_nat =
+                    peer_nat_info.is_behind_same_nat(&self.nat_test.nat_info());
+                if peer_nat_info.same_nat {
+                    log::debug!(
+                        "和对端在同一个NAT后面 public_ips={:?}",
+                        peer_nat_info.public_ips
+                    );
+                }
                 {
                     let peer_nat_info = peer_nat_info.clone();
                     self.peer_nat_info_map.write().insert(source, peer_nat_info);
@@ -340,6 +804,8 @@ impl ClientPacketHandler {
                     punch_reply.tcp_port = nat_info.tcp_port as u32;
                     punch_reply.nat_type =
                         protobuf::EnumOrUnknown::new(PunchNatType::from(nat_info.nat_type));
+                    punch_reply.filtering =
+                        protobuf::EnumOrUnknown::new(PunchNatFiltering::from(nat_info.filtering));
                     punch_reply.local_ip =
                         u32::from(nat_info.local_ipv4().unwrap_or(Ipv4Addr::UNSPECIFIED));
                     punch_reply.local_port = nat_info.udp_ports[0] as u32;
@@ -348,6 +814,14 @@ impl ClientPacketHandler {
                         punch_reply.ipv6 = ipv6.octets().to_vec();
                         punch_reply.ipv6_port = nat_info.udp_ports[0] as u32;
                     }
+                    punch_reply.host_id = crate::channel::punch::host_id().to_vec();
+                    punch_reply.uds_id = context.uds_id();
+                    context
+                        .peer_auth
+                        .sign_punch_info(current_device.virtual_ip(), source, &mut punch_reply)
+                        .map_err(|e| {
+                            io::Error::new(io::ErrorKind::Other, format!("punch_reply {:?}", e))
+                        })?;
                     let bytes = punch_reply.write_to_bytes().map_err(|e| {
                         io::Error::new(io::ErrorKind::Other, format!("punch_reply {:?}", e))
                     })?;
@@ -368,8 +842,98 @@ impl ClientPacketHandler {
                     self.punch_sender.send(false, source, peer_nat_info);
                 }
             }
+            other_turn_packet::Protocol::RouteReportRequest => {
+                let list = route_report::local_routes(context, current_device);
+                let packet = route_report::route_report_packet(
+                    &self.client_cipher,
+                    &list,
+                    current_device.virtual_ip,
+                    source,
+                )?;
+                context.send_by_key(packet.buffer(), route_key)?;
+            }
+            other_turn_packet::Protocol::RouteReport => {
+                let list = route_report::parse_route_report(net_packet.payload())?;
+                context.route_table.route_matrix.update(source, list);
+            }
+            other_turn_packet::Protocol::DiagRequest => {
+                if !self.diag.is_allow() {
+                    log::info!("拒绝诊断请求");
+                }
+                let packet = self.diag.response_packet(
+                    context,
+                    &self.nat_test,
+                    &self.client_cipher,
+                    current_device.virtual_ip,
+                    source,
+                )?;
+                context.send_by_key(packet.buffer(), route_key)?;
+            }
+            other_turn_packet::Protocol::DiagResponse => {
+                self.diag.handle_response(source, net_packet.payload())?;
+            }
+            other_turn_packet::Protocol::SpeedTestStart => {
+                self.speed_test.handle_start(
+                    context,
+                    &self.client_cipher,
+                    current_device,
+                    source,
+                    net_packet.payload(),
+                )?;
+            }
+            other_turn_packet::Protocol::SpeedTestData => {
+                self.speed_test.handle_data(source, net_packet.payload());
+            }
+            other_turn_packet::Protocol::SpeedTestEnd => {
+                if let Some(buf) = self.speed_test.report_packet(
+                    &self.client_cipher,
+                    current_device.virtual_ip,
+                    source,
+                    net_packet.payload(),
+                )? {
+                    context.send_by_key(&buf, route_key)?;
+                }
+            }
+            other_turn_packet::Protocol::SpeedTestReport => {
+                self.speed_test
+                    .handle_report(source, net_packet.payload())?;
+            }
+            other_turn_packet::Protocol::ReverseTunnel => {
+                maintain::handle_reverse_tunnel(
+                    context,
+                    &self.client_cipher,
+                    current_device,
+                    source,
+                    net_packet.payload(),
+                )?;
+            }
+            other_turn_packet::Protocol::SubnetAdvert => {
+                maintain::handle_subnet_advert(
+                    context,
+                    &self.device,
+                    current_device,
+                    &self.in_route,
+                    &self.route,
+                    self.nat_test.nat_info().local_ipv4(),
+                    source,
+                    net_packet.payload(),
+                )?;
+            }
+            other_turn_packet::Protocol::VlanAdvert => {
+                maintain::handle_vlan_advert(context, &self.device, source, net_packet.payload())?;
+            }
             other_turn_packet::Protocol::Unknown(e) => {
-                log::warn!("不支持的转发协议 {:?},source:{:?}", e, source);
+                let reply = ServiceReply::new(
+                    ServiceProtocol::OtherTurn(e),
+                    context.clone(),
+                    self.client_cipher.clone(),
+                    current_device.virtual_ip,
+                    source,
+                    route_key,
+                );
+                if !self.services.handle(net_packet.payload(), reply) {
+                    log::warn!("不支持的转发协议 turn={:?}", e);
+                }
             }
         }
         Ok(())
@@ -15,18 +15,21 @@ use crate::cipher::Cipher;
 use crate::cipher::RsaCipher;
 use crate::external_route::{AllowExternalRoute, ExternalRoute};
 use crate::handle::callback::VntCallback;
+use crate::handle::diag::Diag;
 use crate::handle::handshaker::Handshake;
 use crate::handle::maintain::PunchSender;
 use crate::handle::recv_data::client::ClientPacketHandler;
 use crate::handle::recv_data::server::ServerPacketHandler;
 use crate::handle::recv_data::turn::TurnPacketHandler;
+use crate::handle::service::ServiceRegistry;
+use crate::handle::speed_test::SpeedTest;
 use crate::handle::{BaseConfigInfo, CurrentDeviceInfo, PeerDeviceInfo, SELF_IP};
 #[cfg(feature = "ip_proxy")]
 use crate::ip_proxy::IpProxyMap;
 use crate::nat::NatTest;
-use crate::protocol::NetPacket;
+use crate::protocol::{NetPacket, Protocol};
 use crate::tun_tap_device::tun_create_helper::DeviceAdapter;
-use crate::util::U64Adder;
+use crate::util::{LogSpan, U64Adder};
 
 mod client;
 mod server;
@@ -67,7 +70,12 @@ impl<Call: VntCallback> RecvDataHandler<Call> {
         #[cfg(feature = "ip_proxy")] ip_proxy_map: Option<IpProxyMap>,
         counter: U64Adder,
         handshake: Handshake,
+        diag: Diag,
+        speed_test: SpeedTest,
+        services: ServiceRegistry,
     ) -> Self {
+        let server_hints = config_info.server_hints.clone();
+        let presence = config_info.presence.clone();
         let server = ServerPacketHandler::new(
             #[cfg(feature = "server_encrypt")]
             rsa_cipher,
@@ -80,18 +88,25 @@ impl<Call: VntCallback> RecvDataHandler<Call> {
             callback,
             external_route.clone(),
             handshake,
+            services.clone(),
         );
+        let turn = TurnPacketHandler::new(client_cipher.clone());
         let client = ClientPacketHandler::new(
             device.clone(),
             client_cipher,
             punch_sender,
             peer_nat_info_map,
             nat_test,
+            external_route,
             route,
             #[cfg(feature = "ip_proxy")]
             ip_proxy_map,
+            diag,
+            speed_test,
+            services,
+            server_hints,
+            presence,
         );
-        let turn = TurnPacketHandler::new();
         Self {
             current_device,
             turn,
@@ -108,21 +123,43 @@ impl<Call: VntCallback> RecvDataHandler<Call> {
     ) -> io::Result<()> {
         // 统计流量
         self.counter.add(buf.len() as _);
-        let net_packet = NetPacket::new(buf)?;
+        let mut net_packet = NetPacket::new(buf)?;
+        let _span = LogSpan::new(
+            Some(net_packet.source()),
+            Some(route_key),
+            Some(net_packet.protocol()),
+        )
+        .enter();
         if net_packet.ttl() == 0 || net_packet.source_ttl() < net_packet.ttl() {
             log::warn!("丢弃过时包:{:?}", net_packet.head());
             return Ok(());
         }
         let current_device = self.current_device.load();
         let dest = net_packet.destination();
-        if dest == current_device.virtual_ip
-            || dest.is_broadcast()
+        let broadcast = dest.is_broadcast()
             || dest.is_multicast()
+            || dest == current_device.broadcast_ip
+            || context
+                .broadcast_scope
+                .is_group_broadcast(current_device.virtual_ip, dest);
+        if dest == current_device.virtual_ip
+            || broadcast
             || dest == SELF_IP
             || dest.is_unspecified()
-            || dest == current_device.broadcast_ip
         {
-            //发给自己的包
+            if broadcast
+                && !net_packet.is_gateway()
+                && net_packet.protocol() == Protocol::IpTurn
+                && !context
+                    .broadcast_scope
+                    .is_member(current_device.virtual_ip, net_packet.source())
+            {
+                //不在同一个广播组的设备的广播
+                return Ok(());
+            }
+            //发给自己的包，连接id只在中转时使用
+            net_packet.strip_conn_id();
+            context.relay_meter.terminal(net_packet.buffer().len());
             if net_packet.is_gateway() {
                 //服务端-客户端包
                 self.server
@@ -1,8 +1,9 @@
 use std::io;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
+use std::time::Duration;
 #[cfg(feature = "server_encrypt")]
-use std::time::{Duration, Instant};
+use std::time::Instant;
 
 use crossbeam_utils::atomic::AtomicCell;
 use parking_lot::Mutex;
@@ -13,25 +14,35 @@ use packet::ip::ipv4;
 use packet::ip::ipv4::packet::IpV4Packet;
 
 use crate::channel::context::ChannelContext;
+use crate::channel::event::StateEvent;
 use crate::channel::{Route, RouteKey};
-use crate::cipher::Cipher;
 #[cfg(feature = "server_encrypt")]
 use crate::cipher::RsaCipher;
+use crate::cipher::{Cipher, KeyDerivation};
+use crate::error::VntError;
 use crate::external_route::ExternalRoute;
 use crate::handle::callback::{ErrorInfo, ErrorType, HandshakeInfo, RegisterInfo, VntCallback};
 #[cfg(feature = "server_encrypt")]
 use crate::handle::handshaker;
 use crate::handle::handshaker::Handshake;
+use crate::handle::maintain::{self, pong_rt};
+use crate::handle::presence::PresenceEvent;
 use crate::handle::recv_data::PacketHandler;
+use crate::handle::service::{ServiceProtocol, ServiceRegistry, ServiceReply};
 use crate::handle::{
     registrar, BaseConfigInfo, ConnectStatus, CurrentDeviceInfo, PeerDeviceInfo, GATEWAY_IP,
 };
 use crate::nat::NatTest;
-use crate::proto::message::{DeviceList, HandshakeResponse, RegistrationResponse};
+#[cfg(feature = "server_encrypt")]
+use crate::proto::message::SecretHandshakeResponse;
+use crate::proto::message::{
+    DeviceDelta, DeviceList, HandshakeResponse, RegistrationResponse, ServerConfig,
+};
 use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::protocol::capability::Capabilities;
 use crate::protocol::control_packet::ControlPacket;
 use crate::protocol::error_packet::InErrorPacket;
-use crate::protocol::{ip_turn_packet, service_packet, NetPacket, Protocol, MAX_TTL};
+use crate::protocol::{ip_turn_packet, service_packet, NetPacket, Protocol, Version, MAX_TTL};
 use crate::tun_tap_device::tun_create_helper::DeviceAdapter;
 use crate::{proto, PeerClientInfo};
 #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
@@ -55,6 +66,7 @@ pub struct ServerPacketHandler<Call> {
     route_record: Arc<Mutex<Vec<(Ipv4Addr, Ipv4Addr)>>>,
     external_route: ExternalRoute,
     handshake: Handshake,
+    services: ServiceRegistry,
 }
 
 impl<Call> ServerPacketHandler<Call> {
@@ -69,6 +81,7 @@ impl<Call> ServerPacketHandler<Call> {
         callback: Call,
         external_route: ExternalRoute,
         handshake: Handshake,
+        services: ServiceRegistry,
     ) -> Self {
         Self {
             #[cfg(feature = "server_encrypt")]
@@ -86,6 +99,7 @@ impl<Call> ServerPacketHandler<Call> {
             route_record: Arc::new(Mutex::default()),
             external_route,
             handshake,
+            services,
         }
     }
 }
@@ -101,6 +115,12 @@ impl<Call: VntCallback> PacketHandler for ServerPacketHandler<Call> {
         context
             .route_table
             .update_read_time(&net_packet.source(), &route_key);
+        if let Version::Unknown(version) = net_packet.version() {
+            // 服务端协议不兼容，数据无法解析
+            self.callback
+                .error(VntError::VersionMismatch(version).into());
+            return Ok(());
+        }
         if net_packet.protocol() == Protocol::Error
             && net_packet.transport_protocol()
                 == crate::protocol::error_packet::Protocol::NoKey.into()
@@ -126,6 +146,7 @@ impl<Call: VntCallback> PacketHandler for ServerPacketHandler<Call> {
                             rsa_cipher,
                             self.config_info.token.clone(),
                             key,
+                            self.handshake.transcript(),
                         )?;
                         context.send_by_key(packet.buffer(), route_key)?;
                     }
@@ -140,9 +161,44 @@ impl<Call: VntCallback> PacketHandler for ServerPacketHandler<Call> {
                     io::Error::new(io::ErrorKind::Other, format!("HandshakeResponse {:?}", e))
                 })?;
             log::info!("握手响应:{:?},{}", route_key, response);
+            let first = self.handshake.succeed();
+            if let Some(addr) = self
+                .config_info
+                .server_hints
+                .matched(route_key.addr, current_device.connect_server)
+            {
+                // 对端通告的地址响应了握手，之后使用这个地址
+                if !first {
+                    return Ok(());
+                }
+                self.switch_server(addr);
+            } else if self.config_info.server_ports.is_enable() {
+                // 双栈socket收到的地址可能是ipv4映射的ipv6地址，只比较端口
+                let port = route_key.addr.port();
+                if port != current_device.connect_server.port() {
+                    // 同时探测多个端口时只使用最先响应的端口
+                    if !first {
+                        return Ok(());
+                    }
+                    self.switch_server_port(port);
+                } else if first {
+                    self.config_info.server_ports.record(port);
+                }
+            }
+            context
+                .capabilities
+                .set_server(Capabilities::from_bits(response.capabilities));
+            if !response.stun_servers.is_empty() {
+                self.nat_test
+                    .merge_stun_server(response.stun_servers.to_vec());
+            }
             //如果开启了加密，则发送加密握手请求
             #[cfg(feature = "server_encrypt")]
             if let Some(key) = self.server_cipher.key() {
+                // 第二次握手绑定这次握手的参数，服务端确认后才注册
+                let transcript = handshaker::handshake_transcript(true, &response);
+                let required = Capabilities::from_bits(response.capabilities)
+                    .contains(Capabilities::TRANSCRIPT);
                 {
                     let guard = self.rsa_cipher.lock();
                     if let Some(rsa_cipher) = guard.as_ref() {
@@ -151,8 +207,10 @@ impl<Call: VntCallback> PacketHandler for ServerPacketHandler<Call> {
                                 rsa_cipher,
                                 self.config_info.token.clone(),
                                 key,
+                                Some(transcript),
                             )?;
                             drop(guard);
+                            self.handshake.set_transcript(transcript, key, required);
                             context.send_by_key(packet.buffer(), route_key)?;
                             return Ok(());
                         }
@@ -162,6 +220,13 @@ impl<Call: VntCallback> PacketHandler for ServerPacketHandler<Call> {
                             response.key_finger,
                             route_key
                         );
+                        self.callback.error(
+                            VntError::HandshakeRejected(format!(
+                                "server key changed,finger={}",
+                                response.key_finger
+                            ))
+                            .into(),
+                        );
                         return Ok(());
                     }
                     drop(guard);
@@ -173,6 +238,8 @@ impl<Call: VntCallback> PacketHandler for ServerPacketHandler<Call> {
                         rsa_cipher.finger(),
                         response.key_finger
                     );
+                    self.callback
+                        .error(VntError::HandshakeRejected("server finger mismatch".into()).into());
                     return Ok(());
                 }
                 let handshake_info = HandshakeInfo::new(
@@ -187,9 +254,14 @@ impl<Call: VntCallback> PacketHandler for ServerPacketHandler<Call> {
                         &rsa_cipher,
                         self.config_info.token.clone(),
                         key,
+                        Some(transcript),
                     )?;
+                    self.handshake.set_transcript(transcript, key, required);
                     context.send_by_key(packet.buffer(), route_key)?;
                     self.rsa_cipher.lock().replace(rsa_cipher);
+                } else {
+                    self.callback
+                        .error(VntError::HandshakeRejected("rejected by callback".into()).into());
                 }
                 return Ok(());
             }
@@ -200,12 +272,18 @@ impl<Call: VntCallback> PacketHandler for ServerPacketHandler<Call> {
             if self.callback.handshake(handshake_info) {
                 //没有加密，则发送注册请求
                 self.register(current_device, context)?;
+            } else {
+                self.callback
+                    .error(VntError::HandshakeRejected("rejected by callback".into()).into());
             }
 
             return Ok(());
         }
         //服务端数据解密
-        self.server_cipher.decrypt_ipv4(&mut net_packet)?;
+        if let Err(e) = self.server_cipher.decrypt_ipv4(&mut net_packet) {
+            context.traffic.cipher_error_add();
+            return Err(e);
+        }
         match net_packet.protocol() {
             Protocol::Service => {
                 self.service(context, current_device, net_packet, route_key)?;
@@ -235,6 +313,9 @@ impl<Call: VntCallback> PacketHandler for ServerPacketHandler<Call> {
                         }
                     }
                     ip_turn_packet::Protocol::Ipv4Broadcast => {}
+                    ip_turn_packet::Protocol::Ipv4Batch => {}
+                    ip_turn_packet::Protocol::Ipv4Fragment => {}
+                    ip_turn_packet::Protocol::Ipv4Padded => {}
                     ip_turn_packet::Protocol::Unknown(_) => {}
                 }
             }
@@ -270,6 +351,10 @@ impl<Call: VntCallback> ServerPacketHandler<Call> {
                 let register_info = RegisterInfo::new(virtual_ip, virtual_netmask, virtual_gateway);
                 log::info!("注册成功：{:?}", register_info);
                 if self.callback.register(register_info) {
+                    if let Err(e) = context.broadcast_scope.apply(virtual_netmask) {
+                        log::error!("广播组和服务端网段不匹配，不划分广播组 {}", e);
+                        self.callback.error(VntError::Other(e).into());
+                    }
                     let route = Route::from_default_rt(route_key, 1);
                     context
                         .route_table
@@ -278,6 +363,9 @@ impl<Call: VntCallback> ServerPacketHandler<Call> {
                     let public_port = response.public_port as u16;
                     self.nat_test
                         .update_addr(route_key.index(), public_ip, public_port);
+                    if let Ok(public_ipv6) = <[u8; 16]>::try_from(response.public_ipv6.as_slice()) {
+                        self.nat_test.update_ipv6_addr(Ipv6Addr::from(public_ipv6));
+                    }
                     let old = current_device;
                     let mut cur = *current_device;
                     loop {
@@ -315,17 +403,17 @@ impl<Call: VntCallback> ServerPacketHandler<Call> {
                             );
                             let device_fd = self.callback.generate_tun(device_config);
                             if device_fd == 0 {
-                                self.callback.error(ErrorInfo::new_msg(
-                                    ErrorType::Unknown,
-                                    "device_fd == 0".into(),
-                                ));
+                                self.callback.error(
+                                    VntError::TunCreate(io::Error::new(
+                                        io::ErrorKind::Other,
+                                        "device_fd == 0",
+                                    ))
+                                    .into(),
+                                );
                             } else {
                                 let device = Arc::new(tun::Device::new(device_fd as _)?);
                                 if let Err(e) = self.device.start(device) {
-                                    self.callback.error(ErrorInfo::new_msg(
-                                        ErrorType::Unknown,
-                                        format!("{:?}", e),
-                                    ));
+                                    self.callback.error(VntError::TunCreate(e).into());
                                 }
                             }
                         }
@@ -333,12 +421,23 @@ impl<Call: VntCallback> ServerPacketHandler<Call> {
                         {
                             if let Err(e) = self.device.set_ip(virtual_ip, virtual_netmask) {
                                 log::error!("LocalIpExists {:?}", e);
-                                self.callback.error(ErrorInfo::new_msg(
-                                    ErrorType::LocalIpExists,
-                                    format!("set_ip {:?}", e),
-                                ));
+                                self.callback.error(VntError::LocalIpExists(e).into());
                                 return Ok(());
                             }
+                            #[cfg(any(target_os = "windows", target_os = "linux"))]
+                            self.device.set_tap_info(
+                                crate::tun_tap_device::virtual_mac(
+                                    &self.config_info.device_id,
+                                    virtual_ip,
+                                ),
+                                self.dhcp_option(
+                                    &response,
+                                    virtual_ip,
+                                    virtual_netmask,
+                                    virtual_gateway,
+                                    virtual_network,
+                                ),
+                            );
                             let mut guard = self.route_record.lock();
                             for (dest, mask) in guard.drain(..) {
                                 if let Err(e) = self.device.delete_route(dest, mask) {
@@ -381,9 +480,19 @@ impl<Call: VntCallback> ServerPacketHandler<Call> {
                                     guard.push((dest, mask));
                                 }
                             }
+                            maintain::restore_subnet_routes(context, &self.device);
                         }
                     }
-                    self.set_device_info_list(response.device_info_list, response.epoch as _);
+                    let events = self
+                        .config_info
+                        .presence
+                        .replace(response.epoch as _, &response.device_info_list);
+                    self.set_device_info_list(
+                        context,
+                        response.device_info_list,
+                        response.epoch as _,
+                        events,
+                    );
                     if old.status.offline() {
                         self.callback.success();
                     }
@@ -393,13 +502,77 @@ impl<Call: VntCallback> ServerPacketHandler<Call> {
                 let response = DeviceList::parse_from_bytes(net_packet.payload()).map_err(|e| {
                     io::Error::new(io::ErrorKind::Other, format!("PushDeviceList {:?}", e))
                 })?;
-                self.set_device_info_list(response.device_info_list, response.epoch as _);
+                let events = self
+                    .config_info
+                    .presence
+                    .replace(response.epoch as _, &response.device_info_list);
+                self.set_device_info_list(
+                    context,
+                    response.device_info_list,
+                    response.epoch as _,
+                    events,
+                );
+            }
+            service_packet::Protocol::PushDeviceDelta => {
+                let delta = DeviceDelta::parse_from_bytes(net_packet.payload()).map_err(|e| {
+                    io::Error::new(io::ErrorKind::Other, format!("PushDeviceDelta {:?}", e))
+                })?;
+                let epoch = delta.epoch as u16;
+                match self.config_info.presence.apply(delta) {
+                    Some((device_info_list, events)) => {
+                        self.set_device_info_list(context, device_info_list, epoch, events);
+                    }
+                    None => {
+                        //中间漏掉了增量，重新拉取全量
+                        self.pull_device_list(context, current_device)?;
+                    }
+                }
+            }
+            service_packet::Protocol::PushConfig => {
+                let config = ServerConfig::parse_from_bytes(net_packet.payload()).map_err(|e| {
+                    io::Error::new(io::ErrorKind::Other, format!("PushConfig {:?}", e))
+                })?;
+                self.apply_config(context, config);
             }
             service_packet::Protocol::SecretHandshakeResponse => {
                 log::info!("SecretHandshakeResponse");
+                #[cfg(feature = "server_encrypt")]
+                {
+                    let response = SecretHandshakeResponse::parse_from_bytes(net_packet.payload())
+                        .map_err(|e| {
+                            io::Error::new(
+                                io::ErrorKind::Other,
+                                format!("SecretHandshakeResponse {:?}", e),
+                            )
+                        })?;
+                    if !self.handshake.verify_transcript(&response.transcript) {
+                        log::warn!("加密握手转录摘要不一致，可能被中间人篡改,{:?}", route_key);
+                        self.callback.error(
+                            VntError::HandshakeRejected("handshake transcript mismatch".into())
+                                .into(),
+                        );
+                        return Ok(());
+                    }
+                }
                 //加密握手结束，发送注册数据
                 self.register(current_device, context)?;
             }
+            service_packet::Protocol::Unknown(e) => {
+                let reply = ServiceReply::new(
+                    ServiceProtocol::Service(e),
+                    context.clone(),
+                    self.server_cipher.clone(),
+                    current_device.virtual_ip,
+                    net_packet.source(),
+                    route_key,
+                );
+                if !self.services.handle(net_packet.payload(), reply) {
+                    log::warn!(
+                        "service_packet::Protocol::Unknown = {:?}",
+                        net_packet.head()
+                    );
+                }
+            }
             _ => {
                 log::warn!(
                     "service_packet::Protocol::Unknown = {:?}",
@@ -409,7 +582,97 @@ impl<Call: VntCallback> ServerPacketHandler<Call> {
         }
         Ok(())
     }
-    fn set_device_info_list(&self, device_info_list: Vec<proto::message::DeviceInfo>, epoch: u16) {
+    /// 切换到响应握手的服务端端口，并记录当前网络下可用的端口
+    fn switch_server_port(&self, port: u16) {
+        log::info!("使用服务端端口 {}", port);
+        self.config_info.server_ports.record(port);
+        let mut cur = self.current_device.load();
+        loop {
+            let mut new_current_device = cur;
+            new_current_device.connect_server.set_port(port);
+            match self
+                .current_device
+                .compare_exchange(cur, new_current_device)
+            {
+                Ok(_) => break,
+                Err(c) => cur = c,
+            }
+        }
+    }
+    /// 切换到对端通告的服务端地址
+    fn switch_server(&self, addr: SocketAddr) {
+        log::info!("使用对端通告的服务端地址 {}", addr);
+        let mut cur = self.current_device.load();
+        loop {
+            let mut new_current_device = cur;
+            new_current_device.connect_server = addr;
+            match self
+                .current_device
+                .compare_exchange(cur, new_current_device)
+            {
+                Ok(_) => break,
+                Err(c) => cur = c,
+            }
+        }
+    }
+    /// 应用服务端下发的配置，本地不接受的配置项已经过滤掉
+    fn apply_config(&self, context: &ChannelContext, config: ServerConfig) {
+        let pushed = match context.server_config.update(config) {
+            Some(pushed) => pushed,
+            None => return,
+        };
+        log::info!("服务端下发配置:{:?}", pushed);
+        if !pushed.stun_servers.is_empty() {
+            self.nat_test.merge_stun_server(pushed.stun_servers);
+        }
+        #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
+        if let Some(mtu) = pushed.mtu {
+            if let Err(e) = self.device.set_mtu(mtu) {
+                log::warn!("设置服务端推荐的mtu失败 {:?}", e);
+            }
+        }
+        context
+            .peer_filter
+            .set_server(pushed.allow_peer, pushed.deny_peer);
+    }
+    fn set_device_info_list(
+        &self,
+        context: &ChannelContext,
+        device_info_list: Vec<proto::message::DeviceInfo>,
+        epoch: u16,
+        events: Vec<PresenceEvent>,
+    ) {
+        // 登记了公钥的设备需要校验签名
+        context.peer_auth.set_peer_keys(
+            device_info_list
+                .iter()
+                .filter(|info| !info.device_public_key.is_empty())
+                .map(|info| {
+                    (
+                        Ipv4Addr::from(info.virtual_ip),
+                        info.device_public_key.clone(),
+                    )
+                })
+                .collect(),
+        );
+        let ips = device_info_list
+            .iter()
+            .map(|info| Ipv4Addr::from(info.virtual_ip))
+            .collect();
+        context.capabilities.retain(&ips);
+        context.conn_ids.retain(&ips);
+        context.asym_paths.retain(&ips);
+        context.ip_conflict.retain(&ips);
+        if context.vlan.sync(&ips) {
+            maintain::sync_vlan(context, &self.device);
+        }
+        context.route_table.punch_log.retain(&ips);
+        context.peer_filter.set_names(
+            device_info_list
+                .iter()
+                .map(|info| (Ipv4Addr::from(info.virtual_ip), info.name.clone()))
+                .collect(),
+        );
         let ip_list: Vec<PeerDeviceInfo> = device_info_list
             .into_iter()
             .map(|info| {
@@ -424,10 +687,25 @@ impl<Call: VntCallback> ServerPacketHandler<Call> {
             .collect();
         {
             let mut dev = self.device_list.lock();
+            self.check_kdf(&dev.1, &ip_list);
             //这里可能会收到旧的消息，但是随着时间推移总会收到新的
             dev.0 = epoch;
             dev.1 = ip_list.clone();
         }
+        let subscribers = &context.route_table.event_log.subscribers;
+        if !subscribers.is_empty() {
+            subscribers.publish(StateEvent::PeerList {
+                revision: epoch,
+                peers: ip_list.clone(),
+            });
+        }
+        for event in events {
+            maintain::reset_replay(context, &event);
+            log::info!("对端状态变化 {} {} {}", event.ip, event.name, event.kind);
+            if !subscribers.is_empty() {
+                subscribers.publish(StateEvent::Presence(event));
+            }
+        }
         self.callback.peer_client_list(
             ip_list
                 .into_iter()
@@ -435,6 +713,80 @@ impl<Call: VntCallback> ServerPacketHandler<Call> {
                 .collect(),
         );
     }
+    /// 桥接设备的dhcp信息，本机的ip和其他设备的ip不分配，没有指定地址范围时不回复dhcp
+    #[cfg(any(target_os = "windows", target_os = "linux"))]
+    fn dhcp_option(
+        &self,
+        response: &RegistrationResponse,
+        virtual_ip: Ipv4Addr,
+        virtual_netmask: Ipv4Addr,
+        virtual_gateway: Ipv4Addr,
+        virtual_network: Ipv4Addr,
+    ) -> Option<tun::DhcpOption> {
+        let pool = self.config_info.dhcp_pool?;
+        let mut used: Vec<Ipv4Addr> = response
+            .device_info_list
+            .iter()
+            .map(|info| Ipv4Addr::from(info.virtual_ip))
+            .collect();
+        used.push(virtual_ip);
+        let pool = pool.addrs(virtual_network, virtual_netmask, virtual_gateway, &used);
+        if pool.is_empty() {
+            log::warn!("dhcp地址范围内没有可分配的地址");
+            return None;
+        }
+        Some(tun::DhcpOption {
+            pool,
+            mask: virtual_netmask,
+            gateway: virtual_gateway,
+            mtu: self.device.mtu().unwrap_or_default() as u16,
+        })
+    }
+    fn pull_device_list(
+        &self,
+        context: &ChannelContext,
+        current_device: &CurrentDeviceInfo,
+    ) -> io::Result<()> {
+        let mut poll_device = NetPacket::new_encrypt([0; 12 + ENCRYPTION_RESERVED])?;
+        poll_device.set_source(current_device.virtual_ip);
+        poll_device.set_destination(GATEWAY_IP);
+        poll_device.set_default_version();
+        poll_device.set_gateway_flag(true);
+        poll_device.first_set_ttl(MAX_TTL);
+        poll_device.set_protocol(Protocol::Service);
+        poll_device.set_transport_protocol(service_packet::Protocol::PullDeviceList.into());
+        self.server_cipher.encrypt_ipv4(&mut poll_device)?;
+        //发送到默认服务端即可
+        context.send_default(poll_device.buffer(), current_device.connect_server)?;
+        Ok(())
+    }
+    /// 对端的密钥派生版本和本机不同时无法解密，新出现或变化时报错
+    fn check_kdf(&self, old_list: &[PeerDeviceInfo], new_list: &[PeerDeviceInfo]) {
+        let current = match &self.config_info.client_secret_hash {
+            Some(hash) => KeyDerivation::hash_version(hash),
+            None => return,
+        };
+        for peer in new_list {
+            let version = KeyDerivation::hash_version(&peer.client_secret_hash);
+            if version.is_none() || version == current {
+                continue;
+            }
+            if old_list.iter().any(|v| {
+                v.virtual_ip == peer.virtual_ip && v.client_secret_hash == peer.client_secret_hash
+            }) {
+                continue;
+            }
+            let msg = format!(
+                "{}({}) key derivation v{} != v{}, use the same --kdf",
+                peer.name,
+                peer.virtual_ip,
+                version.unwrap_or_default(),
+                current.unwrap_or_default()
+            );
+            self.callback
+                .error(ErrorInfo::new_msg(ErrorType::KdfMismatch, msg));
+        }
+    }
     fn register(
         &self,
         current_device: &CurrentDeviceInfo,
@@ -445,17 +797,22 @@ impl<Call: VntCallback> ServerPacketHandler<Call> {
             return Ok(());
         }
         let token = self.config_info.token.clone();
-        let device_id = self.config_info.device_id.clone();
+        let device_id = context.ip_conflict.device_id(&self.config_info.device_id);
         let name = self.config_info.name.clone();
         let client_secret = self
             .config_info
             .client_secret_hash
             .as_ref()
             .map(|v| v.as_ref());
+        // ip冲突后重新申请ip
+        let reassign = context.ip_conflict.is_reassigned();
         let mut ip = self.config_info.ip;
         if ip.is_none() {
             ip = Some(current_device.virtual_ip)
         }
+        if reassign {
+            ip = None
+        }
         let response = registrar::registration_request_packet(
             &self.server_cipher,
             token,
@@ -463,8 +820,9 @@ impl<Call: VntCallback> ServerPacketHandler<Call> {
             name,
             ip,
             false,
-            false,
+            reassign,
             client_secret,
+            context.peer_auth.public_key(),
         )?;
         log::info!("发送注册请求，{:?}", self.config_info);
         //注册请求只发送到默认通道
@@ -480,13 +838,11 @@ impl<Call: VntCallback> ServerPacketHandler<Call> {
         match InErrorPacket::new(net_packet.transport_protocol(), net_packet.payload())? {
             InErrorPacket::TokenError => {
                 // token错误，可能是服务端设置了白名单
-                let err = ErrorInfo::new(ErrorType::TokenError);
-                self.callback.error(err);
+                self.callback.error(VntError::TokenInvalid.into());
             }
             InErrorPacket::Disconnect => {
                 crate::handle::change_status(&self.current_device, ConnectStatus::Connecting);
-                let err = ErrorInfo::new(ErrorType::Disconnect);
-                self.callback.error(err);
+                self.callback.error(VntError::Disconnect(None).into());
                 //掉线epoch要归零
                 {
                     let mut dev = self.device_list.lock();
@@ -499,24 +855,28 @@ impl<Call: VntCallback> ServerPacketHandler<Call> {
             }
             InErrorPacket::AddressExhausted => {
                 // 地址用尽
-                let err = ErrorInfo::new(ErrorType::AddressExhausted);
-                self.callback.error(err);
+                self.callback.error(VntError::AddressExhausted.into());
             }
             InErrorPacket::OtherError(e) => {
-                let err = ErrorInfo::new_msg(ErrorType::Unknown, e.message()?);
-                self.callback.error(err);
+                self.callback.error(VntError::Other(e.message()?).into());
             }
             InErrorPacket::IpAlreadyExists => {
-                let err = ErrorInfo::new(ErrorType::IpAlreadyExists);
-                self.callback.error(err);
+                self.callback.error(VntError::IpConflict.into());
             }
             InErrorPacket::InvalidIp => {
-                let err = ErrorInfo::new(ErrorType::InvalidIp);
-                self.callback.error(err);
+                self.callback.error(VntError::InvalidIp.into());
             }
             InErrorPacket::NoKey => {
                 //这个类型最开头已经处理过，这里忽略
             }
+            InErrorPacket::VersionTooOld(e) => {
+                let required = e.message().ok().filter(|v| !v.is_empty());
+                self.callback
+                    .error(VntError::VersionTooOld(required).into());
+            }
+            InErrorPacket::NameDuplicated => {
+                self.callback.error(VntError::NameDuplicated.into());
+            }
         }
         Ok(())
     }
@@ -529,29 +889,17 @@ impl<Call: VntCallback> ServerPacketHandler<Call> {
     ) -> io::Result<()> {
         match ControlPacket::new(net_packet.transport_protocol(), net_packet.payload())? {
             ControlPacket::PongPacket(pong_packet) => {
-                let current_time = crate::handle::now_time() as u16;
-                if current_time < pong_packet.time() {
-                    return Ok(());
-                }
+                let rt = match pong_rt(&pong_packet) {
+                    Some(rt) => rt,
+                    None => return Ok(()),
+                };
                 let metric = net_packet.source_ttl() - net_packet.ttl() + 1;
-                let rt = (current_time - pong_packet.time()) as i64;
                 let route = Route::from(route_key, metric, rt);
                 context.route_table.add_route(net_packet.source(), route);
                 let epoch = self.device_list.lock().0;
                 if pong_packet.epoch() != epoch {
                     //纪元不一致，可能有新客户端连接，向服务端拉取客户端列表
-                    let mut poll_device = NetPacket::new_encrypt([0; 12 + ENCRYPTION_RESERVED])?;
-                    poll_device.set_source(current_device.virtual_ip);
-                    poll_device.set_destination(GATEWAY_IP);
-                    poll_device.set_default_version();
-                    poll_device.set_gateway_flag(true);
-                    poll_device.first_set_ttl(MAX_TTL);
-                    poll_device.set_protocol(Protocol::Service);
-                    poll_device
-                        .set_transport_protocol(service_packet::Protocol::PullDeviceList.into());
-                    self.server_cipher.encrypt_ipv4(&mut poll_device)?;
-                    //发送到默认服务端即可
-                    context.send_default(poll_device.buffer(), current_device.connect_server)?;
+                    self.pull_device_list(context, current_device)?;
                 }
             }
             ControlPacket::AddrResponse(addr_packet) => {
@@ -562,6 +910,14 @@ impl<Call: VntCallback> ServerPacketHandler<Call> {
                     addr_packet.port(),
                 );
             }
+            //服务端中转拥塞
+            ControlPacket::Congestion(congestion_packet) => {
+                context.relay_pacer.on_signal(
+                    congestion_packet.destination(),
+                    congestion_packet.rate() as u64 * 1024,
+                    Duration::from_millis(congestion_packet.hold() as u64),
+                );
+            }
             _ => {}
         }
         Ok(())
@@ -1,16 +1,51 @@
+use std::io;
+use std::net::Ipv4Addr;
+
 use crate::channel::context::ChannelContext;
+use crate::channel::pacing::CONGESTION_HOLD;
 use crate::channel::RouteKey;
+use crate::cipher::Cipher;
 use crate::handle::recv_data::PacketHandler;
 use crate::handle::CurrentDeviceInfo;
-use crate::protocol::NetPacket;
+use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::protocol::control_packet::{CongestionPacket, CONGESTION_LEN};
+use crate::protocol::{control_packet, NetPacket, Protocol, MAX_TTL};
 
 /// 处理客户端中转包
 #[derive(Clone)]
-pub struct TurnPacketHandler {}
+pub struct TurnPacketHandler {
+    client_cipher: Cipher,
+}
+
+impl TurnPacketHandler {
+    pub fn new(client_cipher: Cipher) -> Self {
+        Self { client_cipher }
+    }
+}
 
 impl TurnPacketHandler {
-    pub fn new() -> Self {
-        Self {}
+    fn congestion(
+        &self,
+        context: &ChannelContext,
+        current_device: &CurrentDeviceInfo,
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+        route_key: RouteKey,
+    ) -> io::Result<()> {
+        let mut packet = NetPacket::new_encrypt([0; 12 + CONGESTION_LEN + ENCRYPTION_RESERVED])?;
+        packet.set_default_version();
+        packet.set_protocol(Protocol::Control);
+        packet.set_transport_protocol(control_packet::Protocol::Congestion.into());
+        packet.first_set_ttl(MAX_TTL);
+        packet.set_source(current_device.virtual_ip);
+        packet.set_destination(source);
+        let mut congestion = CongestionPacket::new(packet.payload_mut())?;
+        congestion.set_destination(destination);
+        congestion.set_hold(CONGESTION_HOLD);
+        // 由发送方自行降速
+        congestion.set_rate(0);
+        self.client_cipher.encrypt_ipv4(&mut packet)?;
+        context.send_by_key(packet.buffer(), route_key)
     }
 }
 
@@ -20,20 +55,64 @@ impl PacketHandler for TurnPacketHandler {
         mut net_packet: NetPacket<&mut [u8]>,
         route_key: RouteKey,
         context: &ChannelContext,
-        _current_device: &CurrentDeviceInfo,
-    ) -> std::io::Result<()> {
+        current_device: &CurrentDeviceInfo,
+    ) -> io::Result<()> {
+        // 末尾的连接id不参与消息认证
+        let conn_id = net_packet.strip_conn_id();
+        // 开启消息认证时不转发伪造的包
+        self.client_cipher.verify_auth(&net_packet)?;
         // ttl减一
         let ttl = net_packet.incr_ttl();
         if ttl > 0 {
             let destination = net_packet.destination();
-            if let Some(route) = context.route_table.route_one(&destination) {
+            // 优先按连接id查找目标设备，原样带给下一跳
+            let target = match conn_id {
+                Some(conn_id) => {
+                    net_packet.append_conn_id(conn_id)?;
+                    context.conn_ids.lookup(conn_id).unwrap_or(destination)
+                }
+                None => destination,
+            };
+            if let Some(route) = context.route_table.route_one(&target) {
                 if route.addr == route_key.addr {
                     //防止环路
                     log::warn!("来源和目标相同 {:?},{:?}", route_key, net_packet.head());
                     return Ok(());
                 }
                 if route.metric <= ttl {
-                    return context.send_by_key(net_packet.buffer(), route.route_key());
+                    let source = net_packet.source();
+                    if !context
+                        .relay_meter
+                        .relay(source, destination, net_packet.buffer().len())
+                    {
+                        // 超出中转限额，通知来源降速
+                        if context.relay_pacer.should_signal(source, destination) {
+                            self.congestion(
+                                context,
+                                current_device,
+                                source,
+                                destination,
+                                route_key,
+                            )?;
+                        }
+                        return Ok(());
+                    }
+                    let rs = context.send_by_key(net_packet.buffer(), route.route_key());
+                    if let Err(e) = &rs {
+                        if e.kind() == io::ErrorKind::WouldBlock {
+                            // 发送缓冲区满了，通知来源降速
+                            if context.relay_pacer.should_signal(source, destination) {
+                                self.congestion(
+                                    context,
+                                    current_device,
+                                    source,
+                                    destination,
+                                    route_key,
+                                )?;
+                            }
+                        }
+                    }
+                    return rs;
                 }
             }
             //其他没有路由的不转发
@@ -19,6 +19,7 @@ pub fn registration_request_packet(
     is_fast: bool,
     allow_ip_change: bool,
     client_secret_hash: Option<&[u8]>,
+    device_public_key: Option<&[u8]>,
 ) -> io::Result<NetPacket<Vec<u8>>> {
     let mut request = RegistrationRequest::new();
     request.token = token;
@@ -36,6 +37,11 @@ pub fn registration_request_packet(
             .client_secret_hash
             .extend_from_slice(client_secret_hash);
     }
+    if let Some(device_public_key) = device_public_key {
+        request
+            .device_public_key
+            .extend_from_slice(device_public_key);
+    }
     let bytes = request.write_to_bytes().map_err(|e| {
         io::Error::new(io::ErrorKind::Other, format!("RegistrationRequest {:?}", e))
     })?;
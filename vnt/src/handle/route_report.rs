@@ -0,0 +1,109 @@
+use std::io;
+use std::net::Ipv4Addr;
+
+use protobuf::Message;
+
+use crate::channel::context::ChannelContext;
+use crate::channel::matrix::PeerRoute;
+use crate::cipher::Cipher;
+use crate::handle::CurrentDeviceInfo;
+use crate::proto::message::{PeerRouteInfo, PeerRouteReport};
+use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::protocol::{other_turn_packet, NetPacket, Protocol, MAX_TTL};
+
+/// 当前设备到其他设备的最优路由
+pub fn local_routes(
+    context: &ChannelContext,
+    current_device: &CurrentDeviceInfo,
+) -> Vec<PeerRoute> {
+    context
+        .route_table
+        .route_table_one()
+        .into_iter()
+        .filter(|(ip, _)| !current_device.is_gateway(ip))
+        .map(|(ip, route)| PeerRoute {
+            ip,
+            p2p: route.is_p2p(),
+            metric: route.metric,
+            rt: route.rt,
+        })
+        .collect()
+}
+
+/// 请求对方上报路由
+pub fn route_report_request_packet(
+    client_cipher: &Cipher,
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+) -> io::Result<NetPacket<Vec<u8>>> {
+    other_turn_packet(
+        client_cipher,
+        other_turn_packet::Protocol::RouteReportRequest,
+        source,
+        destination,
+        &[],
+    )
+}
+
+/// 上报当前设备的路由
+pub fn route_report_packet(
+    client_cipher: &Cipher,
+    list: &[PeerRoute],
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+) -> io::Result<NetPacket<Vec<u8>>> {
+    let mut report = PeerRouteReport::new();
+    for route in list {
+        let mut info = PeerRouteInfo::new();
+        info.virtual_ip = route.ip.into();
+        info.p2p = route.p2p;
+        info.metric = route.metric as u32;
+        info.rt = route.rt;
+        report.route_list.push(info);
+    }
+    let bytes = report
+        .write_to_bytes()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("route_report {:?}", e)))?;
+    other_turn_packet(
+        client_cipher,
+        other_turn_packet::Protocol::RouteReport,
+        source,
+        destination,
+        &bytes,
+    )
+}
+
+pub fn parse_route_report(payload: &[u8]) -> io::Result<Vec<PeerRoute>> {
+    let report = PeerRouteReport::parse_from_bytes(payload)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("route_report {:?}", e)))?;
+    Ok(report
+        .route_list
+        .iter()
+        .map(|v| PeerRoute {
+            ip: v.virtual_ip.into(),
+            p2p: v.p2p,
+            metric: v.metric as u8,
+            rt: v.rt,
+        })
+        .collect())
+}
+
+/// 构建客户端之间的OtherTurn包
+pub(crate) fn other_turn_packet(
+    client_cipher: &Cipher,
+    protocol: other_turn_packet::Protocol,
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    bytes: &[u8],
+) -> io::Result<NetPacket<Vec<u8>>> {
+    let mut net_packet = NetPacket::new_encrypt(vec![0u8; 12 + bytes.len() + ENCRYPTION_RESERVED])?;
+    net_packet.set_default_version();
+    net_packet.set_protocol(Protocol::OtherTurn);
+    net_packet.set_transport_protocol(protocol.into());
+    net_packet.first_set_ttl(MAX_TTL);
+    net_packet.set_source(source);
+    net_packet.set_destination(destination);
+    net_packet.set_payload(bytes)?;
+    client_cipher.encrypt_ipv4(&mut net_packet)?;
+    Ok(net_packet)
+}
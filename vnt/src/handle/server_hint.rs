@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{fmt, io};
+
+use parking_lot::Mutex;
+
+use crate::cipher::{ct_eq, hmac_sha256};
+
+/// 通告的有效期，超过后不再使用，也用于拒绝重放的旧通告
+const HINT_TTL: Duration = Duration::from_secs(600);
+/// 签名长度
+pub const HINT_TAG_LEN: usize = 16;
+/// 通告的最大长度，地址为ipv6时
+pub const HINT_MAX_LEN: usize = 1 + 16 + 2 + 8 + HINT_TAG_LEN;
+
+/// 对端通告的服务端地址。
+/// 在线的设备定时把正在使用的服务端地址直连发给对端，服务端域名解析失败或者ip变化后连不上时，
+/// 还能直连对端的设备改用对端通告的地址握手。
+/// 通告使用密码派生的密钥签名，这个密钥不会发给服务端，没有配置密码时不发送也不接受通告
+#[derive(Clone)]
+pub struct ServerHints {
+    key: Option<[u8; 32]>,
+    // 对端 -> (通告的地址，收到的时间)
+    hints: Arc<Mutex<HashMap<Ipv4Addr, (SocketAddr, Instant)>>>,
+}
+
+impl fmt::Debug for ServerHints {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerHints")
+            .field("hints", &self.hints.lock().len())
+            .finish()
+    }
+}
+
+impl ServerHints {
+    /// cipher_key为密码经过kdf派生的密钥
+    pub fn new(cipher_key: Option<&[u8]>) -> Self {
+        Self {
+            key: cipher_key.map(|key| hmac_sha256(key, &[b"vnt-server-hint"])),
+            hints: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+    pub fn is_enable(&self) -> bool {
+        self.key.is_some()
+    }
+    fn key(&self) -> io::Result<&[u8; 32]> {
+        self.key
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "no password"))
+    }
+    /// 生成通告，格式为 地址族(4/6) + ip + 端口 + 秒级时间戳 + 签名
+    pub fn encode(&self, source: Ipv4Addr, addr: SocketAddr) -> io::Result<Vec<u8>> {
+        self.encode_at(source, addr, unix_secs())
+    }
+    fn encode_at(&self, source: Ipv4Addr, addr: SocketAddr, time: u64) -> io::Result<Vec<u8>> {
+        let key = self.key()?;
+        let addr = canonical(addr);
+        let mut buf = Vec::with_capacity(HINT_MAX_LEN);
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                buf.push(4);
+                buf.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                buf.push(6);
+                buf.extend_from_slice(&ip.octets());
+            }
+        }
+        buf.extend_from_slice(&addr.port().to_be_bytes());
+        buf.extend_from_slice(&time.to_be_bytes());
+        let tag = hmac_sha256(key, &[&source.octets(), &buf]);
+        buf.extend_from_slice(&tag[..HINT_TAG_LEN]);
+        Ok(buf)
+    }
+    /// 校验对端的通告，返回通告的服务端地址
+    pub fn decode(&self, source: Ipv4Addr, data: &[u8]) -> io::Result<SocketAddr> {
+        self.decode_at(source, data, unix_secs())
+    }
+    fn decode_at(&self, source: Ipv4Addr, data: &[u8], now: u64) -> io::Result<SocketAddr> {
+        let key = self.key()?;
+        let ip_len = match data.first() {
+            Some(4) => 4,
+            Some(6) => 16,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "family")),
+        };
+        let len = 1 + ip_len + 2 + 8;
+        if data.len() != len + HINT_TAG_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "len"));
+        }
+        let (body, tag) = data.split_at(len);
+        let expect = hmac_sha256(key, &[&source.octets(), body]);
+        if !ct_eq(&expect[..HINT_TAG_LEN], tag) {
+            return Err(io::Error::new(io::ErrorKind::Other, "auth err"));
+        }
+        let time = u64::from_be_bytes(body[len - 8..].try_into().unwrap());
+        if now.abs_diff(time) > HINT_TTL.as_secs() {
+            return Err(io::Error::new(io::ErrorKind::Other, "expired"));
+        }
+        let ip = if ip_len == 4 {
+            IpAddr::V4(Ipv4Addr::new(body[1], body[2], body[3], body[4]))
+        } else {
+            let octets: [u8; 16] = body[1..17].try_into().unwrap();
+            IpAddr::V6(Ipv6Addr::from(octets))
+        };
+        let port = u16::from_be_bytes([body[1 + ip_len], body[2 + ip_len]]);
+        if ip.is_unspecified() || port == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "addr"));
+        }
+        Ok(SocketAddr::new(ip, port))
+    }
+    /// 记录对端通告的地址，返回地址是否有变化
+    pub fn record(&self, peer: Ipv4Addr, addr: SocketAddr) -> bool {
+        self.record_at(peer, addr, Instant::now())
+    }
+    fn record_at(&self, peer: Ipv4Addr, addr: SocketAddr, now: Instant) -> bool {
+        let mut guard = self.hints.lock();
+        guard.retain(|_, (_, time)| now.saturating_duration_since(*time) < HINT_TTL);
+        match guard.insert(peer, (addr, now)) {
+            Some((old, _)) => old != addr,
+            None => true,
+        }
+    }
+    /// 和当前地址不同的通告地址，多个时取通告的对端最多的
+    pub fn candidate(&self, current: SocketAddr) -> Option<SocketAddr> {
+        self.candidate_at(current, Instant::now())
+    }
+    fn candidate_at(&self, current: SocketAddr, now: Instant) -> Option<SocketAddr> {
+        let current = canonical(current);
+        let mut votes: HashMap<SocketAddr, (usize, Instant)> = HashMap::new();
+        for (addr, time) in self.hints.lock().values() {
+            if *addr == current || now.saturating_duration_since(*time) >= HINT_TTL {
+                continue;
+            }
+            let vote = votes.entry(*addr).or_insert((0, *time));
+            vote.0 += 1;
+            vote.1 = vote.1.max(*time);
+        }
+        votes
+            .into_iter()
+            .max_by_key(|(_, vote)| *vote)
+            .map(|(addr, _)| addr)
+    }
+    /// 握手响应来自对端通告的地址而不是当前地址时，返回这个地址
+    pub fn matched(&self, from: SocketAddr, current: SocketAddr) -> Option<SocketAddr> {
+        let from = canonical(from);
+        if from == canonical(current) {
+            return None;
+        }
+        let now = Instant::now();
+        self.hints
+            .lock()
+            .values()
+            .any(|(addr, time)| *addr == from && now.saturating_duration_since(*time) < HINT_TTL)
+            .then_some(from)
+    }
+}
+
+/// 双栈socket收到的ipv4映射地址转换成ipv4地址
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or_default()
+}
+
+#[test]
+fn test_server_hints() {
+    let hints = ServerHints::new(Some(&[1; 32]));
+    let peer = Ipv4Addr::new(10, 26, 0, 2);
+    let addr: SocketAddr = "1.2.3.4:29872".parse().unwrap();
+    let data = hints.encode_at(peer, addr, 1000).unwrap();
+    assert_eq!(hints.decode_at(peer, &data, 1000).unwrap(), addr);
+    // 来源、密钥不同或者过期的通告不接受
+    assert!(hints
+        .decode_at(Ipv4Addr::new(10, 26, 0, 3), &data, 1000)
+        .is_err());
+    assert!(ServerHints::new(Some(&[2; 32]))
+        .decode_at(peer, &data, 1000)
+        .is_err());
+    // 没有配置密码时不生成也不接受通告
+    let none = ServerHints::new(None);
+    assert!(!none.is_enable());
+    assert!(none.encode_at(peer, addr, 1000).is_err());
+    assert!(none.decode_at(peer, &data, 1000).is_err());
+    assert!(hints
+        .decode_at(peer, &data, 1000 + HINT_TTL.as_secs() + 1)
+        .is_err());
+    let v6: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+    let data = hints.encode_at(peer, v6, 1000).unwrap();
+    assert_eq!(data.len(), HINT_MAX_LEN);
+    assert_eq!(hints.decode_at(peer, &data, 1000).unwrap(), v6);
+
+    let now = Instant::now();
+    let other: SocketAddr = "5.6.7.8:29872".parse().unwrap();
+    assert!(hints.record_at(peer, addr, now));
+    assert!(!hints.record_at(peer, addr, now));
+    assert!(hints.record_at(Ipv4Addr::new(10, 26, 0, 3), other, now));
+    assert!(hints.record_at(Ipv4Addr::new(10, 26, 0, 4), other, now));
+    assert_eq!(hints.candidate_at(addr, now), Some(other));
+    assert_eq!(hints.candidate_at(other, now), Some(addr));
+    assert_eq!(hints.candidate_at(addr, now + HINT_TTL), None);
+    let mapped: SocketAddr = "[::ffff:5.6.7.8]:29872".parse().unwrap();
+    assert_eq!(hints.matched(mapped, addr), Some(other));
+    assert_eq!(hints.matched(addr, addr), None);
+}
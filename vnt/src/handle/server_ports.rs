@@ -0,0 +1,138 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+/// 首次连接或者连续这么多次握手没有响应时，同时探测所有端口
+pub const PROBE_AFTER: u32 = 3;
+
+/// 服务端的备用端口，服务端口被封锁时(例如只放行443、53)同时向所有端口握手，
+/// 使用最先响应的端口，并按网络记录下来，下次回到这个网络时直接使用
+#[derive(Clone, Debug, Default)]
+pub struct ServerPorts {
+    ports: Vec<u16>,
+    // 网络标识 -> 可用的端口
+    working: Arc<Mutex<HashMap<u64, u16>>>,
+}
+
+impl ServerPorts {
+    pub fn new(ports: Vec<u16>) -> Self {
+        Self {
+            ports,
+            working: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+    pub fn is_enable(&self) -> bool {
+        !self.ports.is_empty()
+    }
+    /// 当前网络下记录的可用端口
+    pub fn working(&self) -> Option<u16> {
+        if !self.is_enable() {
+            return None;
+        }
+        self.working.lock().get(&network_id()).copied()
+    }
+    /// 记录当前网络下可用的端口
+    pub fn record(&self, port: u16) {
+        if self.is_enable() {
+            self.working.lock().insert(network_id(), port);
+        }
+    }
+    /// 需要同时探测的地址，addr排在第一个
+    pub fn candidates(&self, addr: SocketAddr) -> Vec<SocketAddr> {
+        let mut list = vec![addr];
+        for port in &self.ports {
+            let candidate = SocketAddr::new(addr.ip(), *port);
+            if !list.contains(&candidate) {
+                list.push(candidate);
+            }
+        }
+        list
+    }
+}
+
+/// 当前网络的标识，linux上使用默认网关的ip和mac地址，
+/// 其他平台使用本机ipv4地址所在的/24网段
+fn network_id() -> u64 {
+    let mut hasher = DefaultHasher::new();
+    #[cfg(target_os = "linux")]
+    if let Some(gateway) = default_gateway() {
+        gateway.hash(&mut hasher);
+        return hasher.finish();
+    }
+    crate::nat::local_ipv4()
+        .map(|ip| u32::from(ip) & 0xFFFF_FF00)
+        .hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(target_os = "linux")]
+fn default_gateway() -> Option<(Ipv4Addr, String)> {
+    let route = std::fs::read_to_string("/proc/net/route").ok()?;
+    let gateway = parse_default_gateway(&route)?;
+    let mac = std::fs::read_to_string("/proc/net/arp")
+        .ok()
+        .and_then(|arp| parse_arp_mac(&arp, gateway))
+        .unwrap_or_default();
+    Some((gateway, mac))
+}
+
+/// /proc/net/route中目标为0.0.0.0的网关，地址是小端的十六进制
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_default_gateway(route: &str) -> Option<Ipv4Addr> {
+    route.lines().skip(1).find_map(|line| {
+        let cols: Vec<&str> = line.split_whitespace().collect();
+        if cols.len() < 3 || cols[1] != "00000000" {
+            return None;
+        }
+        let gateway = u32::from_str_radix(cols[2], 16).ok()?;
+        if gateway == 0 {
+            return None;
+        }
+        Some(Ipv4Addr::from(gateway.to_le_bytes()))
+    })
+}
+
+/// /proc/net/arp中ip对应的mac地址
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_arp_mac(arp: &str, ip: Ipv4Addr) -> Option<String> {
+    let ip = ip.to_string();
+    arp.lines().skip(1).find_map(|line| {
+        let cols: Vec<&str> = line.split_whitespace().collect();
+        if cols.len() > 3 && cols[0] == ip {
+            Some(cols[3].to_string())
+        } else {
+            None
+        }
+    })
+}
+
+#[test]
+fn test_server_ports() {
+    let route = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                 eth0\t0000A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\n\
+                 eth0\t00000000\t0100A8C0\t0003\t0\t0\t100\t00000000\n";
+    let gateway = parse_default_gateway(route).unwrap();
+    assert_eq!(gateway, Ipv4Addr::new(192, 168, 0, 1));
+    let arp = "IP address       HW type     Flags       HW address            Mask     Device\n\
+               192.168.0.1      0x1         0x2         aa:bb:cc:dd:ee:ff     *        eth0\n";
+    assert_eq!(
+        parse_arp_mac(arp, gateway).as_deref(),
+        Some("aa:bb:cc:dd:ee:ff")
+    );
+    assert!(parse_arp_mac(arp, Ipv4Addr::new(192, 168, 0, 2)).is_none());
+
+    let ports = ServerPorts::new(vec![443, 29872, 53]);
+    let addr: SocketAddr = "1.2.3.4:29872".parse().unwrap();
+    let list = ports.candidates(addr);
+    assert_eq!(list.len(), 3);
+    assert_eq!(list[0], addr);
+    assert_eq!(list[1].port(), 443);
+    assert!(ports.working().is_none());
+    ports.record(443);
+    assert_eq!(ports.working(), Some(443));
+    assert!(ServerPorts::default().working().is_none());
+}
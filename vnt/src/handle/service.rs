@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+
+use crate::channel::context::ChannelContext;
+use crate::channel::RouteKey;
+use crate::cipher::Cipher;
+use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::protocol::{NetPacket, Protocol, HEAD_LEN, MAX_TTL};
+
+/// 自定义协议可用的最小协议号，更小的号码留给内置协议，
+/// 避免以后新增内置协议时和已经部署的自定义协议冲突
+pub const USER_PROTOCOL_START: u8 = 128;
+
+/// 自定义协议号，只能使用128-255
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum ServiceProtocol {
+    /// 服务端和客户端之间的协议，对应Protocol::Service
+    Service(u8),
+    /// 客户端之间的协议，对应Protocol::OtherTurn
+    OtherTurn(u8),
+}
+
+impl ServiceProtocol {
+    /// 是否在内置协议的保留范围内
+    pub fn is_reserved(&self) -> bool {
+        match self {
+            ServiceProtocol::Service(v) | ServiceProtocol::OtherTurn(v) => *v < USER_PROTOCOL_START,
+        }
+    }
+    pub(crate) fn packet(
+        &self,
+        cipher: &Cipher,
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+        bytes: &[u8],
+    ) -> io::Result<NetPacket<Vec<u8>>> {
+        let mut net_packet =
+            NetPacket::new_encrypt(vec![0u8; HEAD_LEN + bytes.len() + ENCRYPTION_RESERVED])?;
+        net_packet.set_default_version();
+        match self {
+            ServiceProtocol::Service(v) => {
+                net_packet.set_gateway_flag(true);
+                net_packet.set_protocol(Protocol::Service);
+                net_packet.set_transport_protocol(*v);
+            }
+            ServiceProtocol::OtherTurn(v) => {
+                net_packet.set_protocol(Protocol::OtherTurn);
+                net_packet.set_transport_protocol(*v);
+            }
+        }
+        net_packet.first_set_ttl(MAX_TTL);
+        net_packet.set_source(source);
+        net_packet.set_destination(destination);
+        net_packet.set_payload(bytes)?;
+        cipher.encrypt_ipv4(&mut net_packet)?;
+        Ok(net_packet)
+    }
+}
+
+/// 回复发送方，可以克隆后在其他线程使用
+#[derive(Clone)]
+pub struct ServiceReply {
+    protocol: ServiceProtocol,
+    context: ChannelContext,
+    cipher: Cipher,
+    virtual_ip: Ipv4Addr,
+    source: Ipv4Addr,
+    route_key: RouteKey,
+}
+
+impl ServiceReply {
+    pub(crate) fn new(
+        protocol: ServiceProtocol,
+        context: ChannelContext,
+        cipher: Cipher,
+        virtual_ip: Ipv4Addr,
+        source: Ipv4Addr,
+        route_key: RouteKey,
+    ) -> Self {
+        Self {
+            protocol,
+            context,
+            cipher,
+            virtual_ip,
+            source,
+            route_key,
+        }
+    }
+    pub fn protocol(&self) -> ServiceProtocol {
+        self.protocol
+    }
+    /// 发送方的虚拟ip，服务端发来的包为网关ip
+    pub fn source(&self) -> Ipv4Addr {
+        self.source
+    }
+    /// 使用相同协议号，沿收到数据的通道回复
+    pub fn send(&self, payload: &[u8]) -> io::Result<()> {
+        let packet = self
+            .protocol
+            .packet(&self.cipher, self.virtual_ip, self.source, payload)?;
+        self.context.send_by_key(packet.buffer(), self.route_key)
+    }
+}
+
+/// 自定义协议处理器，在接收线程中调用，不要长时间阻塞
+pub trait ServiceHandler: Send + Sync + 'static {
+    fn handle(&self, payload: &[u8], reply: ServiceReply);
+}
+
+impl<F> ServiceHandler for F
+where
+    F: Fn(&[u8], ServiceReply) + Send + Sync + 'static,
+{
+    fn handle(&self, payload: &[u8], reply: ServiceReply) {
+        self(payload, reply)
+    }
+}
+
+/// 已注册的自定义协议
+#[derive(Clone, Default)]
+pub struct ServiceRegistry {
+    handlers: Arc<RwLock<HashMap<ServiceProtocol, Arc<dyn ServiceHandler>>>>,
+}
+
+impl ServiceRegistry {
+    pub fn register<H: ServiceHandler>(
+        &self,
+        protocol: ServiceProtocol,
+        handler: H,
+    ) -> io::Result<()> {
+        if protocol.is_reserved() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "protocol {:?} is reserved, use {}-255",
+                    protocol, USER_PROTOCOL_START
+                ),
+            ));
+        }
+        self.handlers.write().insert(protocol, Arc::new(handler));
+        Ok(())
+    }
+    pub fn unregister(&self, protocol: &ServiceProtocol) -> bool {
+        self.handlers.write().remove(protocol).is_some()
+    }
+    /// 交给注册的处理器，没有注册时返回false
+    pub fn handle(&self, payload: &[u8], reply: ServiceReply) -> bool {
+        let handler = self.handlers.read().get(&reply.protocol).cloned();
+        if let Some(handler) = handler {
+            handler.handle(payload, reply);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[test]
+fn test_service_protocol() {
+    assert!(ServiceProtocol::Service(1).is_reserved());
+    assert!(ServiceProtocol::OtherTurn(9).is_reserved());
+    // 内置协议未占用但在保留范围内
+    assert!(ServiceProtocol::OtherTurn(100).is_reserved());
+    assert!(!ServiceProtocol::OtherTurn(128).is_reserved());
+    assert!(!ServiceProtocol::Service(255).is_reserved());
+    let registry = ServiceRegistry::default();
+    assert!(registry
+        .register(
+            ServiceProtocol::OtherTurn(1),
+            |_: &[u8], _: ServiceReply| {}
+        )
+        .is_err());
+    assert!(registry
+        .register(
+            ServiceProtocol::Service(127),
+            |_: &[u8], _: ServiceReply| {}
+        )
+        .is_err());
+    assert!(registry
+        .register(
+            ServiceProtocol::OtherTurn(200),
+            |_: &[u8], _: ServiceReply| {}
+        )
+        .is_ok());
+    assert!(registry.unregister(&ServiceProtocol::OtherTurn(200)));
+}
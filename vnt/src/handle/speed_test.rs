@@ -0,0 +1,419 @@
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{io, thread};
+
+use parking_lot::Mutex;
+
+use crate::channel::context::ChannelContext;
+use crate::cipher::Cipher;
+use crate::handle::route_report::other_turn_packet;
+use crate::handle::CurrentDeviceInfo;
+use crate::protocol::other_turn_packet;
+
+/// 测速数据包的载荷长度
+const DATA_LEN: usize = 1200;
+/// 测速时长上限
+pub const MAX_DURATION: Duration = Duration::from_secs(60);
+/// 同时响应的测速数上限
+const MAX_SESSIONS: usize = 2;
+
+/// 单个方向的测速结果
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SpeedStats {
+    // 收到的包数
+    pub packets: u64,
+    // 收到的字节数
+    pub bytes: u64,
+    // 丢失的包数
+    pub lost: u64,
+    // 抖动(微秒)
+    pub jitter_us: u64,
+    // 第一个包到最后一个包的时间(毫秒)
+    pub duration_ms: u64,
+}
+
+impl SpeedStats {
+    /// 有效吞吐量(bit/s)
+    pub fn goodput(&self) -> u64 {
+        if self.duration_ms == 0 {
+            return 0;
+        }
+        self.bytes * 8 * 1000 / self.duration_ms
+    }
+    /// 丢包率
+    pub fn loss_rate(&self) -> f64 {
+        let total = self.packets + self.lost;
+        if total == 0 {
+            return 0.0;
+        }
+        self.lost as f64 / total as f64
+    }
+    fn encode(&self, session: u32) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(44);
+        buf.extend_from_slice(&session.to_be_bytes());
+        buf.extend_from_slice(&self.packets.to_be_bytes());
+        buf.extend_from_slice(&self.bytes.to_be_bytes());
+        buf.extend_from_slice(&self.lost.to_be_bytes());
+        buf.extend_from_slice(&self.jitter_us.to_be_bytes());
+        buf.extend_from_slice(&self.duration_ms.to_be_bytes());
+        buf
+    }
+    fn decode(buf: &[u8]) -> io::Result<(u32, Self)> {
+        if buf.len() < 44 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "speed report"));
+        }
+        let u64_at = |i: usize| u64::from_be_bytes(buf[i..i + 8].try_into().unwrap());
+        Ok((
+            u32::from_be_bytes(buf[..4].try_into().unwrap()),
+            Self {
+                packets: u64_at(4),
+                bytes: u64_at(12),
+                lost: u64_at(20),
+                jitter_us: u64_at(28),
+                duration_ms: u64_at(36),
+            },
+        ))
+    }
+}
+
+/// 双向测速结果，upload为本机到对端，download为对端到本机
+#[derive(Copy, Clone, Debug)]
+pub struct SpeedTestResult {
+    pub upload: Option<SpeedStats>,
+    pub download: SpeedStats,
+}
+
+struct RecvStats {
+    session: u32,
+    first: Instant,
+    last: Instant,
+    packets: u64,
+    bytes: u64,
+    max_seq: u32,
+    last_transit: Option<i64>,
+    jitter: f64,
+}
+
+impl RecvStats {
+    fn new(session: u32) -> Self {
+        let now = Instant::now();
+        Self {
+            session,
+            first: now,
+            last: now,
+            packets: 0,
+            bytes: 0,
+            max_seq: 0,
+            last_transit: None,
+            jitter: 0.0,
+        }
+    }
+    fn stats(&self) -> SpeedStats {
+        let expected = if self.packets == 0 {
+            0
+        } else {
+            self.max_seq as u64 + 1
+        };
+        SpeedStats {
+            packets: self.packets,
+            bytes: self.bytes,
+            lost: expected.saturating_sub(self.packets),
+            jitter_us: self.jitter as u64,
+            duration_ms: self.last.duration_since(self.first).as_millis() as u64,
+        }
+    }
+}
+
+/// 设备间测速，通过OtherTurn协议协商，双方同时向对方发送测速包，结束后交换接收统计
+/// 需要开启allow_speed_test才会响应对端的测速，并且只响应直连的对端
+#[derive(Clone)]
+pub struct SpeedTest {
+    allow: bool,
+    inner: Arc<Mutex<SpeedTestInner>>,
+}
+
+#[derive(Default)]
+struct SpeedTestInner {
+    recv: HashMap<Ipv4Addr, RecvStats>,
+    reports: HashMap<Ipv4Addr, (u32, SpeedStats)>,
+    // 正在反向发送测速包的对端
+    sending: HashSet<Ipv4Addr>,
+}
+
+impl SpeedTest {
+    pub fn new(allow: bool) -> Self {
+        Self {
+            allow,
+            inner: Arc::new(Mutex::new(SpeedTestInner::default())),
+        }
+    }
+    /// 发起测速，阻塞到测速结束
+    pub fn run(
+        &self,
+        context: &ChannelContext,
+        client_cipher: &Cipher,
+        current_device: &CurrentDeviceInfo,
+        ip: Ipv4Addr,
+        duration: Duration,
+        tcp: bool,
+    ) -> io::Result<SpeedTestResult> {
+        let duration = duration.min(MAX_DURATION);
+        if context.route_table.route_one_p2p(&ip).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "speed test requires a direct route",
+            ));
+        }
+        let session: u32 = rand::random();
+        {
+            let mut guard = self.inner.lock();
+            guard.recv.insert(ip, RecvStats::new(session));
+            guard.reports.remove(&ip);
+        }
+        let mut start = Vec::with_capacity(7);
+        start.extend_from_slice(&session.to_be_bytes());
+        start.extend_from_slice(&(duration.as_secs() as u16).to_be_bytes());
+        start.push(tcp as u8);
+        let packet = other_turn_packet(
+            client_cipher,
+            other_turn_packet::Protocol::SpeedTestStart,
+            current_device.virtual_ip,
+            ip,
+            &start,
+        )?;
+        send(context, ip, tcp, packet.buffer())?;
+        blast(
+            context,
+            client_cipher,
+            current_device,
+            ip,
+            session,
+            duration,
+            tcp,
+        )?;
+        // 等待在途的数据
+        thread::sleep(Duration::from_secs(1));
+        let packet = other_turn_packet(
+            client_cipher,
+            other_turn_packet::Protocol::SpeedTestEnd,
+            current_device.virtual_ip,
+            ip,
+            &session.to_be_bytes(),
+        )?;
+        let mut upload = None;
+        for _ in 0..30 {
+            // 结束包可能丢失，没收到回应时重发
+            if send(context, ip, tcp, packet.buffer()).is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+            if let Some((s, stats)) = self.inner.lock().reports.get(&ip) {
+                if *s == session {
+                    upload = Some(*stats);
+                    break;
+                }
+            }
+        }
+        let download = self
+            .inner
+            .lock()
+            .recv
+            .remove(&ip)
+            .map(|v| v.stats())
+            .unwrap_or_default();
+        Ok(SpeedTestResult { upload, download })
+    }
+    /// 对端发起测速，开始统计并且反向发送测速包
+    pub fn handle_start(
+        &self,
+        context: &ChannelContext,
+        client_cipher: &Cipher,
+        current_device: &CurrentDeviceInfo,
+        source: Ipv4Addr,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        if payload.len() < 7 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "speed start"));
+        }
+        let session = u32::from_be_bytes(payload[..4].try_into().unwrap());
+        let secs = u16::from_be_bytes(payload[4..6].try_into().unwrap());
+        let duration = Duration::from_secs(secs as u64).min(MAX_DURATION);
+        let tcp = payload[6] != 0;
+        if !self.allow {
+            log::info!("拒绝测速请求 peer={}", source);
+            return Ok(());
+        }
+        if context.route_table.route_one_p2p(&source).is_none() {
+            // 不占用服务器和其他设备的中转带宽
+            log::info!("拒绝测速请求，没有直连 peer={}", source);
+            return Ok(());
+        }
+        {
+            let mut guard = self.inner.lock();
+            if guard.sending.contains(&source) || guard.sending.len() >= MAX_SESSIONS {
+                log::info!("拒绝测速请求，测速数已达上限 peer={}", source);
+                return Ok(());
+            }
+            guard.sending.insert(source);
+            guard.recv.insert(source, RecvStats::new(session));
+        }
+        log::info!(
+            "开始测速 peer={} duration={:?} tcp={}",
+            source,
+            duration,
+            tcp
+        );
+        let context = context.clone();
+        let client_cipher = client_cipher.clone();
+        let current_device = *current_device;
+        let inner = self.inner.clone();
+        let rs = thread::Builder::new()
+            .name("speedTest".into())
+            .spawn(move || {
+                if let Err(e) = blast(
+                    &context,
+                    &client_cipher,
+                    &current_device,
+                    source,
+                    session,
+                    duration,
+                    tcp,
+                ) {
+                    log::warn!("测速发送失败 peer={} err={:?}", source, e);
+                }
+                inner.lock().sending.remove(&source);
+            });
+        if let Err(e) = rs {
+            self.inner.lock().sending.remove(&source);
+            return Err(e);
+        }
+        Ok(())
+    }
+    pub fn handle_data(&self, source: Ipv4Addr, payload: &[u8]) {
+        if payload.len() < 16 {
+            return;
+        }
+        let session = u32::from_be_bytes(payload[..4].try_into().unwrap());
+        let seq = u32::from_be_bytes(payload[4..8].try_into().unwrap());
+        let send_us = u64::from_be_bytes(payload[8..16].try_into().unwrap()) as i64;
+        let mut guard = self.inner.lock();
+        let stats = match guard.recv.get_mut(&source) {
+            Some(stats) if stats.session == session => stats,
+            _ => return,
+        };
+        let now = Instant::now();
+        if stats.packets == 0 {
+            stats.first = now;
+        }
+        stats.last = now;
+        stats.packets += 1;
+        stats.bytes += payload.len() as u64;
+        stats.max_seq = stats.max_seq.max(seq);
+        // RFC 3550 的抖动计算，两端时钟的偏差不影响结果
+        let transit = now.duration_since(stats.first).as_micros() as i64 - send_us;
+        if let Some(last_transit) = stats.last_transit {
+            let d = (transit - last_transit).abs() as f64;
+            stats.jitter += (d - stats.jitter) / 16.0;
+        }
+        stats.last_transit = Some(transit);
+    }
+    /// 对端发送结束，返回本机的接收统计
+    pub fn report_packet(
+        &self,
+        client_cipher: &Cipher,
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+        payload: &[u8],
+    ) -> io::Result<Option<Vec<u8>>> {
+        if payload.len() < 4 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "speed end"));
+        }
+        let session = u32::from_be_bytes(payload[..4].try_into().unwrap());
+        let stats = match self.inner.lock().recv.get(&destination) {
+            Some(stats) if stats.session == session => stats.stats(),
+            _ => return Ok(None),
+        };
+        let packet = other_turn_packet(
+            client_cipher,
+            other_turn_packet::Protocol::SpeedTestReport,
+            source,
+            destination,
+            &stats.encode(session),
+        )?;
+        Ok(Some(packet.into_buffer()))
+    }
+    pub fn handle_report(&self, source: Ipv4Addr, payload: &[u8]) -> io::Result<()> {
+        let report = SpeedStats::decode(payload)?;
+        self.inner.lock().reports.insert(source, report);
+        Ok(())
+    }
+}
+
+/// 测速包只走直连通道，直连断开时测速失败，不会改由服务器中转
+fn send(context: &ChannelContext, ip: Ipv4Addr, tcp: bool, buf: &[u8]) -> io::Result<()> {
+    let route = context.route_table.route(&ip).and_then(|list| {
+        list.into_iter()
+            .find(|route| route.is_p2p() && route.is_tcp == tcp)
+    });
+    match route {
+        Some(route) => context.send_by_key(buf, route.route_key()),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "direct route not found",
+        )),
+    }
+}
+
+fn blast(
+    context: &ChannelContext,
+    client_cipher: &Cipher,
+    current_device: &CurrentDeviceInfo,
+    ip: Ipv4Addr,
+    session: u32,
+    duration: Duration,
+    tcp: bool,
+) -> io::Result<()> {
+    let start = Instant::now();
+    let mut payload = vec![0u8; DATA_LEN];
+    payload[..4].copy_from_slice(&session.to_be_bytes());
+    let mut seq: u32 = 0;
+    while start.elapsed() < duration {
+        payload[4..8].copy_from_slice(&seq.to_be_bytes());
+        payload[8..16].copy_from_slice(&(start.elapsed().as_micros() as u64).to_be_bytes());
+        let packet = other_turn_packet(
+            client_cipher,
+            other_turn_packet::Protocol::SpeedTestData,
+            current_device.virtual_ip,
+            ip,
+            &payload,
+        )?;
+        match send(context, ip, tcp, packet.buffer()) {
+            Ok(_) => {
+                seq = seq.wrapping_add(1);
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_micros(200));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_speed_stats() {
+    let stats = SpeedStats {
+        packets: 90,
+        bytes: 125_000,
+        lost: 10,
+        jitter_us: 300,
+        duration_ms: 1000,
+    };
+    let (session, decode) = SpeedStats::decode(&stats.encode(7)).unwrap();
+    assert_eq!(session, 7);
+    assert_eq!(decode.packets, 90);
+    assert_eq!(decode.goodput(), 1_000_000);
+    assert_eq!(decode.loss_rate(), 0.1);
+}
@@ -1,6 +1,13 @@
 use std::sync::mpsc::{sync_channel, Receiver, SendError, SyncSender};
+use std::sync::Arc;
 
-pub fn channel_group<T>(size: usize, bound: usize) -> (GroupSyncSender<T>, Vec<Receiver<T>>) {
+use crate::channel::queue_depth::QueueDepth;
+
+pub fn channel_group<T>(
+    size: usize,
+    bound: usize,
+    queue_depth: Arc<QueueDepth>,
+) -> (GroupSyncSender<T>, Vec<Receiver<T>>) {
     let mut senders = Vec::with_capacity(size);
     let mut receivers = Vec::with_capacity(size);
     for _ in 0..size {
@@ -8,10 +15,12 @@ pub fn channel_group<T>(size: usize, bound: usize) -> (GroupSyncSender<T>, Vec<R
         senders.push(s);
         receivers.push(r);
     }
+    queue_depth.init_workers(size);
     (
         GroupSyncSender {
             count: 0,
             base: senders,
+            queue_depth,
         },
         receivers,
     )
@@ -20,11 +29,26 @@ pub fn channel_group<T>(size: usize, bound: usize) -> (GroupSyncSender<T>, Vec<R
 pub struct GroupSyncSender<T> {
     count: usize,
     base: Vec<SyncSender<T>>,
+    queue_depth: Arc<QueueDepth>,
 }
 
 impl<T> GroupSyncSender<T> {
     pub fn send(&mut self, t: T) -> Result<(), SendError<T>> {
         self.count += 1;
-        self.base[self.count % self.base.len()].send(t)
+        let index = self.count % self.base.len();
+        self.send_index(index, t)
+    }
+    /// 相同的key总是发到同一个线程，保证顺序
+    pub fn send_by_key(&mut self, key: u32, t: T) -> Result<(), SendError<T>> {
+        let index = key as usize % self.base.len();
+        self.send_index(index, t)
+    }
+    fn send_index(&mut self, index: usize, t: T) -> Result<(), SendError<T>> {
+        self.queue_depth.worker_add(index);
+        let rs = self.base[index].send(t);
+        if rs.is_err() {
+            self.queue_depth.worker_sub(index);
+        }
+        rs
     }
 }
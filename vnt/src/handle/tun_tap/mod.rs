@@ -6,15 +6,19 @@ use parking_lot::Mutex;
 use packet::ip::ipv4::packet::IpV4Packet;
 use packet::ip::ipv4::protocol::Protocol;
 
+use crate::channel::coalesce::COALESCE_MAX_PACKET;
 use crate::channel::context::ChannelContext;
-use crate::cipher::Cipher;
+use crate::channel::fragment::{clamp_mss, MAX_FRAGMENTS};
+use crate::channel::unreachable::Unreachable;
+use crate::cipher::{Cipher, ReplayGuard, SEQ_LEN};
 use crate::external_route::ExternalRoute;
 use crate::handle::{check_dest, CurrentDeviceInfo, PeerDeviceInfo};
 #[cfg(feature = "ip_proxy")]
 use crate::ip_proxy::{IpProxyMap, ProxyHandler};
 use crate::protocol;
 use crate::protocol::body::ENCRYPTION_RESERVED;
-use crate::protocol::ip_turn_packet::BroadcastPacket;
+use crate::protocol::capability::Capabilities;
+use crate::protocol::ip_turn_packet::{BroadcastPacket, FragmentPacket, FRAGMENT_HEAD_LEN};
 use crate::protocol::{ip_turn_packet, NetPacket, MAX_TTL};
 
 mod channel_group;
@@ -103,6 +107,72 @@ fn broadcast(
     sender.send_default(server_packet.buffer(), current_device.connect_server)
 }
 
+/// 划分了广播组时只发给同组的设备，服务端会广播给整个网段，所以中转的也逐个发送。
+/// 传入的是未加密的包，加密的nonce包含目的地址，每个对端单独改地址后再加密
+fn broadcast_scoped(
+    sender: &ChannelContext,
+    client_cipher: &Cipher,
+    net_packet: &NetPacket<&mut [u8]>,
+    current_device: &CurrentDeviceInfo,
+    device_list: &Mutex<(u16, Vec<PeerDeviceInfo>)>,
+) -> io::Result<()> {
+    let scope = &sender.broadcast_scope;
+    let list: Vec<Ipv4Addr> = device_list
+        .lock()
+        .1
+        .iter()
+        .filter(|info| {
+            info.status.is_online() && scope.is_member(current_device.virtual_ip, info.virtual_ip)
+        })
+        .map(|info| info.virtual_ip)
+        .collect();
+    for peer_ip in list {
+        //目的地址改为对端，没有划分广播组的对端也能接收，服务端也不会再次广播
+        let net_packet = peer_copy(
+            net_packet.buffer(),
+            peer_ip,
+            sender.replay_guard.as_ref(),
+            sender.pairwise_cipher.get(&peer_ip, client_cipher),
+        )?;
+        if let Some(route) = sender.route_table.route_one_p2p(&peer_ip) {
+            if sender
+                .send_by_key(net_packet.buffer(), route.route_key())
+                .is_ok()
+            {
+                continue;
+            }
+        }
+        if current_device.status.offline() {
+            continue;
+        }
+        sender.send_ipv4_by_id(
+            net_packet.buffer(),
+            &peer_ip,
+            current_device.connect_server,
+            true,
+        )?;
+    }
+    Ok(())
+}
+
+/// 复制未加密的包，目的地址改为对端后追加序号并加密
+fn peer_copy(
+    plaintext: &[u8],
+    peer_ip: Ipv4Addr,
+    replay_guard: Option<&ReplayGuard>,
+    cipher: &Cipher,
+) -> io::Result<NetPacket<Vec<u8>>> {
+    let mut buf = vec![0u8; plaintext.len() + SEQ_LEN + ENCRYPTION_RESERVED];
+    buf[..plaintext.len()].copy_from_slice(plaintext);
+    let mut net_packet = NetPacket::new0(plaintext.len(), buf)?;
+    net_packet.set_destination(peer_ip);
+    if let Some(replay_guard) = replay_guard {
+        replay_guard.seal(&mut net_packet)?;
+    }
+    cipher.encrypt_ipv4(&mut net_packet)?;
+    Ok(net_packet)
+}
+
 /// 实现一个原地发送，必须保证是如下结构
 /// |12字节开头|ip报文|至少1024字节结尾|
 ///
@@ -117,11 +187,22 @@ pub fn base_handle(
     client_cipher: &Cipher,
     server_cipher: &Cipher,
     device_list: &Mutex<(u16, Vec<PeerDeviceInfo>)>,
-) -> io::Result<()> {
+) -> io::Result<Option<Unreachable>> {
     let ipv4_packet = IpV4Packet::new(&buf[12..data_len])?;
+    context.power_save.active();
     let protocol = ipv4_packet.protocol();
     let src_ip = ipv4_packet.source_ip();
     let mut dest_ip = ipv4_packet.destination_ip();
+    let flow = if context.load_balance().is_none() {
+        0
+    } else {
+        flow_hash(&ipv4_packet)
+    };
+    let dscp = if context.qos.is_copy() {
+        Some(ipv4_packet.dscp())
+    } else {
+        None
+    };
     let mut net_packet = NetPacket::new0(data_len, buf)?;
     net_packet.set_default_version();
     net_packet.set_protocol(protocol::Protocol::IpTurn);
@@ -136,15 +217,33 @@ pub fn base_handle(
             server_cipher.encrypt_ipv4(&mut net_packet)?;
             context.send_default(net_packet.buffer(), current_device.connect_server)?;
         }
-        return Ok(());
+        return Ok(None);
     }
     if dest_ip.is_multicast() {
         //当作广播处理
         dest_ip = Ipv4Addr::BROADCAST;
         net_packet.set_destination(Ipv4Addr::BROADCAST);
     }
-    if dest_ip.is_broadcast() || current_device.broadcast_ip == dest_ip {
+    if dest_ip.is_broadcast()
+        || current_device.broadcast_ip == dest_ip
+        || context
+            .broadcast_scope
+            .is_group_broadcast(current_device.virtual_ip, dest_ip)
+    {
+        if context.broadcast_scope.mask().is_some() {
+            broadcast_scoped(
+                context,
+                client_cipher,
+                &net_packet,
+                &current_device,
+                device_list,
+            )?;
+            return Ok(None);
+        }
         // 广播 发送到直连目标
+        if let Some(replay_guard) = &context.replay_guard {
+            replay_guard.seal(&mut net_packet)?;
+        }
         client_cipher.encrypt_ipv4(&mut net_packet)?;
         broadcast(
             server_cipher,
@@ -153,31 +252,168 @@ pub fn base_handle(
             &current_device,
             device_list,
         )?;
-        return Ok(());
+        return Ok(None);
     }
     if !check_dest(
         dest_ip,
         current_device.virtual_netmask,
         current_device.virtual_network,
     ) {
-        if let Some(r_dest_ip) = ip_route.route(&dest_ip) {
+        //-i指定的路由优先于学习到的网段
+        if let Some(r_dest_ip) = ip_route
+            .route(&dest_ip)
+            .or_else(|| context.subnet_routes.route(&dest_ip))
+        {
             //路由的目标不能是自己
             if r_dest_ip == src_ip {
-                return Ok(());
+                return Ok(None);
             }
             //需要修改目的地址
             dest_ip = r_dest_ip;
             net_packet.set_destination(r_dest_ip);
         } else {
-            return Ok(());
+            return Ok(Some(Unreachable::Network));
         }
     }
+    if !context.peer_filter.is_allowed(&dest_ip) {
+        // 对端在黑名单中，对方的数据也不会被接收
+        return Ok(Some(Unreachable::Prohibited));
+    }
+    // 有直连通道时不用再查设备列表
+    if context.route_table.p2p_num(&dest_ip) == 0
+        && device_list
+            .lock()
+            .1
+            .iter()
+            .any(|info| info.virtual_ip == dest_ip && !info.status.is_online())
+    {
+        return Ok(Some(Unreachable::Host));
+    }
     #[cfg(feature = "ip_proxy")]
     if let Some(proxy_map) = proxy_map {
         let mut ipv4_packet = IpV4Packet::new(net_packet.payload_mut())?;
         proxy_map.send_handle(&mut ipv4_packet)?;
     }
-    client_cipher.encrypt_ipv4(&mut net_packet)?;
+    if let Some(limit) = context.path_mtu.limit(&dest_ip) {
+        // 对端路径mtu较小，优先调整tcp mss，其他超长的包分片发送
+        clamp_mss(net_packet.payload_mut(), limit);
+        if net_packet.data_len() - 12 > limit {
+            flush_batch(context, client_cipher, &current_device, dest_ip)?;
+            send_fragments(
+                context,
+                client_cipher,
+                &current_device,
+                src_ip,
+                dest_ip,
+                flow,
+                dscp,
+                net_packet.payload(),
+                limit,
+            )?;
+            return Ok(None);
+        }
+    }
+    if context.coalesce.is_enable()
+        && src_ip == current_device.virtual_ip
+        && net_packet.data_len() - 12 <= COALESCE_MAX_PACKET
+        && context.coalesce.is_peer_support(&dest_ip)
+    {
+        // 小包先放入队列，由聚合线程或者下一个包触发发送
+        if let Some(batch) = context.coalesce.push(dest_ip, net_packet.payload()) {
+            send_batch(context, client_cipher, &current_device, dest_ip, &batch)?;
+        }
+        return Ok(None);
+    }
+    flush_batch(context, client_cipher, &current_device, dest_ip)?;
+    if context.padding.is_enable()
+        && context
+            .capabilities
+            .supports(&dest_ip, Capabilities::PADDING)
+    {
+        pad(context, &mut net_packet, &dest_ip)?;
+    }
+    if let Some(replay_guard) = &context.replay_guard {
+        replay_guard.seal(&mut net_packet)?;
+    }
+    context
+        .pairwise_cipher
+        .get(&dest_ip, client_cipher)
+        .encrypt_ipv4(&mut net_packet)?;
+    context.send_ipv4_by_flow(
+        net_packet.buffer(),
+        &dest_ip,
+        flow,
+        dscp,
+        current_device.connect_server,
+        current_device.status.online(),
+    )?;
+    Ok(None)
+}
+
+/// 把ip包填充到固定的长度档位，预留序号和加密的空间，放不下时不填充
+fn pad(
+    context: &ChannelContext,
+    net_packet: &mut NetPacket<&mut [u8]>,
+    dest_ip: &Ipv4Addr,
+) -> io::Result<()> {
+    let len = net_packet.data_len() - 12;
+    let capacity = net_packet
+        .raw_buffer()
+        .len()
+        .saturating_sub(12 + SEQ_LEN + ENCRYPTION_RESERVED);
+    let max = match context.path_mtu.limit(dest_ip) {
+        Some(limit) => limit.min(capacity),
+        None => capacity,
+    };
+    let padded = context.padding.bucket(len, max);
+    if padded > len {
+        net_packet.set_data_len(12 + padded)?;
+        net_packet.payload_mut()[len..].fill(0);
+        net_packet.set_transport_protocol(ip_turn_packet::Protocol::Ipv4Padded.into());
+    }
+    Ok(())
+}
+
+/// 先发送积累的小包，保证和之后不合并的包的顺序
+fn flush_batch(
+    context: &ChannelContext,
+    client_cipher: &Cipher,
+    current_device: &CurrentDeviceInfo,
+    dest_ip: Ipv4Addr,
+) -> io::Result<()> {
+    if !context.coalesce.is_enable() {
+        return Ok(());
+    }
+    match context.coalesce.take(&dest_ip) {
+        Some(batch) => send_batch(context, client_cipher, current_device, dest_ip, &batch),
+        None => Ok(()),
+    }
+}
+
+/// 发送合并后的小包
+pub fn send_batch(
+    context: &ChannelContext,
+    client_cipher: &Cipher,
+    current_device: &CurrentDeviceInfo,
+    dest_ip: Ipv4Addr,
+    batch: &[u8],
+) -> io::Result<()> {
+    let mut buf = vec![0u8; 12 + batch.len() + SEQ_LEN + ENCRYPTION_RESERVED];
+    let mut net_packet = NetPacket::new0(12 + batch.len(), &mut buf[..])?;
+    net_packet.set_default_version();
+    net_packet.set_protocol(protocol::Protocol::IpTurn);
+    net_packet.set_transport_protocol(ip_turn_packet::Protocol::Ipv4Batch.into());
+    net_packet.first_set_ttl(6);
+    net_packet.set_source(current_device.virtual_ip);
+    net_packet.set_destination(dest_ip);
+    net_packet.set_payload(batch)?;
+    if let Some(replay_guard) = &context.replay_guard {
+        replay_guard.seal(&mut net_packet)?;
+    }
+    context
+        .pairwise_cipher
+        .get(&dest_ip, client_cipher)
+        .encrypt_ipv4(&mut net_packet)?;
     context.send_ipv4_by_id(
         net_packet.buffer(),
         &dest_ip,
@@ -185,3 +421,135 @@ pub fn base_handle(
         current_device.status.online(),
     )
 }
+
+/// 按limit把ip包分片发送
+fn send_fragments(
+    context: &ChannelContext,
+    client_cipher: &Cipher,
+    current_device: &CurrentDeviceInfo,
+    src_ip: Ipv4Addr,
+    dest_ip: Ipv4Addr,
+    flow: u32,
+    dscp: Option<u8>,
+    ip_packet: &[u8],
+    limit: usize,
+) -> io::Result<()> {
+    let chunk_len = limit.saturating_sub(FRAGMENT_HEAD_LEN).max(1);
+    let count = ip_packet.len().div_ceil(chunk_len);
+    if count > MAX_FRAGMENTS {
+        return Err(io::Error::new(io::ErrorKind::Other, "too many fragments"));
+    }
+    let id = context.path_mtu.next_id();
+    let mut buf = vec![0u8; 12 + FRAGMENT_HEAD_LEN + chunk_len + SEQ_LEN + ENCRYPTION_RESERVED];
+    for (index, chunk) in ip_packet.chunks(chunk_len).enumerate() {
+        let data_len = 12 + FRAGMENT_HEAD_LEN + chunk.len();
+        let mut net_packet = NetPacket::new0(data_len, &mut buf[..])?;
+        net_packet.set_default_version();
+        net_packet.set_protocol(protocol::Protocol::IpTurn);
+        net_packet.set_transport_protocol(ip_turn_packet::Protocol::Ipv4Fragment.into());
+        net_packet.first_set_ttl(6);
+        net_packet.set_source(src_ip);
+        net_packet.set_destination(dest_ip);
+        let mut fragment = FragmentPacket::unchecked(net_packet.payload_mut());
+        fragment.set_head(id, index as u8, count as u8);
+        fragment.set_data(chunk)?;
+        if let Some(replay_guard) = &context.replay_guard {
+            replay_guard.seal(&mut net_packet)?;
+        }
+        context
+            .pairwise_cipher
+            .get(&dest_ip, client_cipher)
+            .encrypt_ipv4(&mut net_packet)?;
+        context.send_ipv4_by_flow(
+            net_packet.buffer(),
+            &dest_ip,
+            flow,
+            dscp,
+            current_device.connect_server,
+            current_device.status.online(),
+        )?;
+    }
+    Ok(())
+}
+
+/// 数据流哈希，由源ip、目的ip、协议和端口计算，分片的包只使用ip和协议
+fn flow_hash(ipv4_packet: &IpV4Packet<&[u8]>) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    let mut fnv = |bytes: &[u8]| {
+        for b in bytes {
+            hash ^= *b as u32;
+            hash = hash.wrapping_mul(0x01000193);
+        }
+    };
+    fnv(&ipv4_packet.source_ip().octets());
+    fnv(&ipv4_packet.destination_ip().octets());
+    let protocol = ipv4_packet.protocol();
+    fnv(&[protocol.into()]);
+    let not_fragment = ipv4_packet.offset() == 0 && ipv4_packet.flags() & 0b001 == 0;
+    if not_fragment && (protocol == Protocol::Tcp || protocol == Protocol::Udp) {
+        let payload = ipv4_packet.payload();
+        if payload.len() >= 4 {
+            // 源端口和目的端口
+            fnv(&payload[..4]);
+        }
+    }
+    hash
+}
+
+#[cfg(any(feature = "aes_gcm", feature = "server_encrypt"))]
+#[test]
+fn test_peer_copy() {
+    use crate::cipher::{CipherModel, KeyDerivation};
+    let cipher = Cipher::new_password(
+        CipherModel::AesGcm,
+        KeyDerivation::V1,
+        "",
+        Some("password".to_string()),
+        Some("token".to_string()),
+    );
+    let source = Ipv4Addr::new(10, 26, 0, 2);
+    let data = [1u8, 2, 3, 4, 5];
+    let mut buf = [0u8; 12 + 5];
+    let mut plaintext = NetPacket::new0(buf.len(), &mut buf[..]).unwrap();
+    plaintext.set_default_version();
+    plaintext.set_protocol(protocol::Protocol::IpTurn);
+    plaintext.set_transport_protocol(ip_turn_packet::Protocol::Ipv4.into());
+    plaintext.first_set_ttl(6);
+    plaintext.set_source(source);
+    plaintext.set_destination(Ipv4Addr::BROADCAST);
+    plaintext.set_payload(&data).unwrap();
+    let replay_guard = ReplayGuard::new();
+    for peer_ip in [Ipv4Addr::new(10, 26, 0, 3), Ipv4Addr::new(10, 26, 0, 4)] {
+        let packet = peer_copy(plaintext.buffer(), peer_ip, Some(&replay_guard), &cipher).unwrap();
+        // 对端按收到的目的地址解密
+        let mut received = packet.buffer().to_vec();
+        let mut received = NetPacket::new(&mut received[..]).unwrap();
+        assert_eq!(received.destination(), peer_ip);
+        cipher.decrypt_ipv4(&mut received).unwrap();
+        assert!(replay_guard.open(&mut received).unwrap());
+        assert_eq!(received.payload(), &data);
+    }
+}
+
+#[test]
+fn test_flow_hash() {
+    // 20字节头部 + 源端口、目的端口
+    let packet = |src_port: u16, dst_port: u16, more_fragments: bool| {
+        let mut buf = [0u8; 24];
+        buf[0] = 0x45;
+        let mut packet = IpV4Packet::unchecked(&mut buf[..]);
+        packet.set_protocol(Protocol::Tcp);
+        packet.set_source_ip(Ipv4Addr::new(10, 26, 0, 2));
+        packet.set_destination_ip(Ipv4Addr::new(10, 26, 0, 3));
+        packet.set_flags(more_fragments as u8);
+        packet.payload_mut()[..2].copy_from_slice(&src_port.to_be_bytes());
+        packet.payload_mut()[2..4].copy_from_slice(&dst_port.to_be_bytes());
+        flow_hash(&IpV4Packet::new(&buf[..]).unwrap())
+    };
+    // 同一条流的哈希不变，端口不同则是不同的流
+    assert_eq!(packet(40000, 443, false), packet(40000, 443, false));
+    assert_ne!(packet(40000, 443, false), packet(40001, 443, false));
+    assert_ne!(packet(40000, 443, false), packet(40000, 80, false));
+    // 分片只使用ip和协议，端口不参与计算
+    assert_eq!(packet(40000, 443, true), packet(40001, 80, true));
+}
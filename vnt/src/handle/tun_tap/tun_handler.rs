@@ -1,4 +1,5 @@
 use std::sync::Arc;
+use std::time::Duration;
 use std::{io, thread};
 
 use crossbeam_utils::atomic::AtomicCell;
@@ -12,13 +13,21 @@ use tun::device::IFace;
 use tun::Device;
 
 use crate::channel::context::ChannelContext;
+use crate::channel::unreachable::unreachable_packet;
 use crate::cipher::Cipher;
 use crate::external_route::ExternalRoute;
 use crate::handle::tun_tap::channel_group::{channel_group, GroupSyncSender};
 use crate::handle::{CurrentDeviceInfo, PeerDeviceInfo};
 #[cfg(feature = "ip_proxy")]
 use crate::ip_proxy::IpProxyMap;
-use crate::util::{SingleU64Adder, StopManager};
+use crate::util::{StopManager, U64Adder};
+
+/// 每次从网卡批量读取的最大包数量
+const READ_BATCH: usize = 16;
+const BUF_LEN: usize = 1024 * 16;
+/// 网卡重建的最长时间，超过后放弃
+#[cfg(target_os = "windows")]
+const RECREATE_TIMEOUT: Duration = Duration::from_secs(60);
 
 fn icmp(device_writer: &Device, mut ipv4_packet: IpV4Packet<&mut [u8]>) -> io::Result<()> {
     if ipv4_packet.protocol() == ipv4::protocol::Protocol::Icmp {
@@ -59,7 +68,7 @@ fn handle(
     if src_ip == dest_ip {
         return icmp(&device_writer, ipv4_packet);
     }
-    return crate::handle::tun_tap::base_handle(
+    let reason = match crate::handle::tun_tap::base_handle(
         context,
         data,
         len,
@@ -70,7 +79,19 @@ fn handle(
         client_cipher,
         server_cipher,
         device_list,
-    );
+    )? {
+        Some(reason) => reason,
+        None => return Ok(()),
+    };
+    // 无法送达时回应icmp不可达，让应用尽快失败，而不是等待超时
+    if context.unreachable_limiter.allow() {
+        if let Some(buf) =
+            unreachable_packet(&data[12..len], current_device.virtual_gateway, reason)
+        {
+            device_writer.write(&buf)?;
+        }
+    }
+    Ok(())
 }
 
 pub fn start(
@@ -83,7 +104,8 @@ pub fn start(
     client_cipher: Cipher,
     server_cipher: Cipher,
     parallel: usize,
-    mut up_counter: SingleU64Adder,
+    parallel_crypto: usize,
+    mut up_counter: U64Adder,
     device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
 ) -> io::Result<()> {
     let worker = {
@@ -103,8 +125,68 @@ pub fn start(
             }
         })?
     };
-    if parallel > 1 {
-        let (sender, receivers) = channel_group::<(Vec<u8>, usize)>(parallel, 16);
+    if context.coalesce.is_enable() {
+        let context = context.clone();
+        let stop_manager = stop_manager.clone();
+        let current_device = current_device.clone();
+        let client_cipher = client_cipher.clone();
+        thread::Builder::new()
+            .name("tunCoalesce".into())
+            .spawn(move || {
+                coalesce_flush(&stop_manager, &context, &current_device, &client_cipher)
+            })?;
+    }
+    #[cfg(target_os = "linux")]
+    if device.queue_num() > 1 {
+        // 多队列网卡，每个队列一个线程读取并处理，不再经过分发
+        let worker = Arc::new(Mutex::new(Some(worker)));
+        for queue in 0..device.queue_num() {
+            let stop_manager = stop_manager.clone();
+            let context = context.clone();
+            let device = device.clone();
+            let current_device = current_device.clone();
+            let ip_route = ip_route.clone();
+            #[cfg(feature = "ip_proxy")]
+            let ip_proxy_map = ip_proxy_map.clone();
+            let client_cipher = client_cipher.clone();
+            let server_cipher = server_cipher.clone();
+            let mut up_counter = up_counter.clone();
+            let device_list = device_list.clone();
+            let worker = worker.clone();
+            thread::Builder::new()
+                .name(format!("tunHandlerQ-{}", queue))
+                .spawn(move || {
+                    if let Err(e) = start_simple(
+                        stop_manager,
+                        &context,
+                        device,
+                        Some(queue),
+                        current_device,
+                        ip_route,
+                        #[cfg(feature = "ip_proxy")]
+                        ip_proxy_map,
+                        client_cipher,
+                        server_cipher,
+                        &mut up_counter,
+                        device_list,
+                    ) {
+                        log::warn!("stop:{}", e);
+                    }
+                    if let Some(worker) = worker.lock().take() {
+                        worker.stop_all();
+                    }
+                })?;
+        }
+        return Ok(());
+    }
+    if parallel > 1 || parallel_crypto > 1 {
+        // 开启并行加密时按目标分发，同一对端的包不乱序
+        let keyed = parallel_crypto > 1;
+        let (sender, receivers) = channel_group::<(Vec<u8>, usize)>(
+            parallel.max(parallel_crypto),
+            16,
+            context.queue_depth.clone(),
+        );
         for (index, receiver) in receivers.into_iter().enumerate() {
             let context = context.clone();
             let device = device.clone();
@@ -119,6 +201,7 @@ pub fn start(
                 .name(format!("tunHandler-{}", index))
                 .spawn(move || {
                     while let Ok((mut buf, len)) = receiver.recv() {
+                        context.queue_depth.worker_sub(index);
                         #[cfg(not(target_os = "macos"))]
                         let start = 0;
                         #[cfg(target_os = "macos")]
@@ -147,7 +230,7 @@ pub fn start(
         thread::Builder::new()
             .name("tunHandlerM".into())
             .spawn(move || {
-                if let Err(e) = start_multi(stop_manager, device, sender, &mut up_counter) {
+                if let Err(e) = start_multi(stop_manager, device, sender, keyed, &mut up_counter) {
                     log::warn!("stop:{}", e);
                 }
                 worker.stop_all();
@@ -160,6 +243,7 @@ pub fn start(
                     stop_manager,
                     &context,
                     device,
+                    None,
                     current_device,
                     ip_route,
                     #[cfg(feature = "ip_proxy")]
@@ -181,42 +265,65 @@ fn start_simple(
     stop_manager: StopManager,
     context: &ChannelContext,
     device: Arc<Device>,
+    queue: Option<usize>,
     current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
     ip_route: ExternalRoute,
     #[cfg(feature = "ip_proxy")] ip_proxy_map: Option<IpProxyMap>,
     client_cipher: Cipher,
     server_cipher: Cipher,
-    up_counter: &mut SingleU64Adder,
+    up_counter: &mut U64Adder,
     device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
 ) -> io::Result<()> {
-    let mut buf = [0; 1024 * 16];
+    let mut bufs = vec![[0u8; BUF_LEN]; READ_BATCH];
+    let mut sizes = [0usize; READ_BATCH];
     loop {
         if stop_manager.is_stop() {
             return Ok(());
         }
-        let len = device.read(&mut buf[12..])? + 12;
-        //单线程的
-        up_counter.add(len as u64);
-        #[cfg(any(target_os = "macos"))]
-        let mut buf = &mut buf[4..];
-        // buf是重复利用的，需要重置头部
-        buf[..12].fill(0);
-        match handle(
-            context,
-            &mut buf,
-            len,
-            &device,
-            current_device.load(),
-            &ip_route,
-            #[cfg(feature = "ip_proxy")]
-            &ip_proxy_map,
-            &client_cipher,
-            &server_cipher,
-            &device_list,
-        ) {
-            Ok(_) => {}
-            Err(e) => {
-                log::warn!("{:?}", e)
+        let num = {
+            let mut slices: Vec<&mut [u8]> = bufs.iter_mut().map(|buf| &mut buf[12..]).collect();
+            match queue {
+                #[cfg(target_os = "linux")]
+                Some(queue) => {
+                    sizes[0] = device.read_queue(queue, slices[0])?;
+                    1
+                }
+                #[cfg(target_os = "windows")]
+                _ => match device.read_batch(&mut slices, &mut sizes) {
+                    Ok(num) => num,
+                    Err(e) => {
+                        recreate(&stop_manager, &device, e)?;
+                        continue;
+                    }
+                },
+                #[cfg(not(target_os = "windows"))]
+                _ => device.read_batch(&mut slices, &mut sizes)?,
+            }
+        };
+        for (buf, len) in bufs.iter_mut().zip(sizes).take(num) {
+            let len = len + 12;
+            up_counter.add(len as u64);
+            #[cfg(any(target_os = "macos"))]
+            let buf = &mut buf[4..];
+            // buf是重复利用的，需要重置头部
+            buf[..12].fill(0);
+            match handle(
+                context,
+                &mut buf[..],
+                len,
+                &device,
+                current_device.load(),
+                &ip_route,
+                #[cfg(feature = "ip_proxy")]
+                &ip_proxy_map,
+                &client_cipher,
+                &server_cipher,
+                &device_list,
+            ) {
+                Ok(_) => {}
+                Err(e) => {
+                    log::warn!("{:?}", e)
+                }
             }
         }
     }
@@ -226,18 +333,107 @@ fn start_multi(
     stop_manager: StopManager,
     device: Arc<Device>,
     mut group_sync_sender: GroupSyncSender<(Vec<u8>, usize)>,
-    up_counter: &mut SingleU64Adder,
+    keyed: bool,
+    up_counter: &mut U64Adder,
 ) -> io::Result<()> {
+    let mut bufs: Vec<Vec<u8>> = (0..READ_BATCH).map(|_| vec![0; BUF_LEN]).collect();
+    let mut sizes = [0usize; READ_BATCH];
     loop {
         if stop_manager.is_stop() {
             return Ok(());
         }
-        let mut buf = vec![0; 1024 * 16];
-        let len = device.read(&mut buf[12..])? + 12;
-        //单线程的
-        up_counter.add(len as u64);
-        if group_sync_sender.send((buf, len)).is_err() {
-            return Ok(());
+        let num = {
+            let mut slices: Vec<&mut [u8]> = bufs.iter_mut().map(|buf| &mut buf[12..]).collect();
+            #[cfg(target_os = "windows")]
+            let num = match device.read_batch(&mut slices, &mut sizes) {
+                Ok(num) => num,
+                Err(e) => {
+                    recreate(&stop_manager, &device, e)?;
+                    continue;
+                }
+            };
+            #[cfg(not(target_os = "windows"))]
+            let num = device.read_batch(&mut slices, &mut sizes)?;
+            num
+        };
+        for (buf, len) in bufs.iter_mut().zip(sizes).take(num) {
+            let len = len + 12;
+            //单线程的
+            up_counter.add(len as u64);
+            let buf = std::mem::replace(buf, vec![0; BUF_LEN]);
+            let rs = if keyed {
+                group_sync_sender.send_by_key(destination(&buf, len), (buf, len))
+            } else {
+                group_sync_sender.send((buf, len))
+            };
+            if rs.is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// ipv4包的目标地址，用于按对端分发
+fn destination(buf: &[u8], len: usize) -> u32 {
+    #[cfg(not(target_os = "macos"))]
+    let start = 12;
+    #[cfg(target_os = "macos")]
+    let start = 16;
+    if len < start + 20 {
+        return 0;
+    }
+    u32::from_be_bytes(buf[start + 16..start + 20].try_into().unwrap())
+}
+
+/// windows的网卡偶尔会被重置，读取失败时重建网卡，ip和路由由网卡自己恢复，
+/// 重建期间写入网卡的数据会先缓存，超过RECREATE_TIMEOUT仍未成功则停止
+#[cfg(target_os = "windows")]
+fn recreate(stop_manager: &StopManager, device: &Device, e: io::Error) -> io::Result<()> {
+    if stop_manager.is_stop() || device.is_shutdown() {
+        return Err(e);
+    }
+    log::warn!("网卡读取失败，尝试重建网卡 {:?}", e);
+    let start = std::time::Instant::now();
+    loop {
+        match device.recreate() {
+            Ok(_) => return Ok(()),
+            Err(e) => {
+                if stop_manager.is_stop()
+                    || device.is_shutdown()
+                    || start.elapsed() > RECREATE_TIMEOUT
+                {
+                    return Err(e);
+                }
+                log::warn!("重建网卡失败 {:?}", e);
+            }
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// 将超过聚合时间的小包发送出去
+fn coalesce_flush(
+    stop_manager: &StopManager,
+    context: &ChannelContext,
+    current_device: &AtomicCell<CurrentDeviceInfo>,
+    client_cipher: &Cipher,
+) {
+    while !stop_manager.is_stop() {
+        let list = context.coalesce.wait_expired(Duration::from_secs(1));
+        if list.is_empty() {
+            continue;
+        }
+        let current_device = current_device.load();
+        for (dest_ip, batch) in list {
+            if let Err(e) = crate::handle::tun_tap::send_batch(
+                context,
+                client_cipher,
+                &current_device,
+                dest_ip,
+                &batch,
+            ) {
+                log::warn!("coalesce peer={} err={:?}", dest_ip, e);
+            }
         }
     }
 }
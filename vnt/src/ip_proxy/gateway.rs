@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::str::FromStr;
+
+use packet::ip::ipv4::packet::IpV4Packet;
+use packet::ip::ipv4::protocol::Protocol;
+use parking_lot::Mutex;
+
+use crate::handle::now_time;
+
+/// 流量配额的统计周期(ms)
+const QUOTA_WINDOW: u64 = 24 * 3600 * 1000;
+/// 清理超时连接的间隔(ms)
+const SWEEP_INTERVAL: u64 = 1000;
+// 各协议连接的空闲超时时间(ms)
+const TCP_TIMEOUT: u64 = 2 * 3600 * 1000;
+const TCP_CLOSE_TIMEOUT: u64 = 10 * 1000;
+const UDP_TIMEOUT: u64 = 60 * 1000;
+const ICMP_TIMEOUT: u64 = 30 * 1000;
+
+/// 网关模式的限制，格式为 每个对端的最大连接数[,每个对端每天的流量配额(MB)]，
+/// 配额为0则不限
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct GatewayConfig {
+    pub max_flows: usize,
+    pub quota: u64,
+}
+
+impl Default for GatewayConfig {
+    fn default() -> Self {
+        Self {
+            max_flows: 1024,
+            quota: 0,
+        }
+    }
+}
+
+impl FromStr for GatewayConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Ok(Self::default());
+        }
+        let (max_flows, quota) = match s.split_once(',') {
+            Some((max_flows, quota)) => (max_flows.trim(), Some(quota.trim())),
+            None => (s, None),
+        };
+        let max_flows = match max_flows.parse::<usize>() {
+            Ok(v) if v > 0 => v,
+            _ => return Err(format!("gateway '{}', e.g. 1024,2048", s)),
+        };
+        let quota = match quota.map(|v| v.parse::<u64>()) {
+            Some(Ok(v)) => v * 1024 * 1024,
+            Some(Err(_)) => return Err(format!("gateway '{}', e.g. 1024,2048", s)),
+            None => 0,
+        };
+        Ok(Self { max_flows, quota })
+    }
+}
+
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq)]
+struct FlowKey {
+    protocol: u8,
+    source: SocketAddrV4,
+    destination: SocketAddrV4,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PeerUsage {
+    // 当前的连接数
+    pub flows: usize,
+    // 本周期内经网关上传和下载的字节数
+    pub bytes: u64,
+}
+
+struct State {
+    // 连接 -> (所属对端，最后活动时间，空闲超时时间)
+    flows: HashMap<FlowKey, (Ipv4Addr, u64, u64)>,
+    peers: HashMap<Ipv4Addr, PeerUsage>,
+    last_sweep: u64,
+    window_start: u64,
+}
+
+/// 网关模式的连接跟踪，按对端限制连接数和流量
+pub struct ConnTrack {
+    config: GatewayConfig,
+    state: Mutex<State>,
+}
+
+impl ConnTrack {
+    pub fn new(config: GatewayConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(State {
+                flows: HashMap::with_capacity(64),
+                peers: HashMap::with_capacity(16),
+                last_sweep: 0,
+                window_start: 0,
+            }),
+        }
+    }
+    /// 对端经网关访问外部的包，连接数或流量超限时返回false，由调用方丢弃
+    pub fn track(&self, peer: Ipv4Addr, ipv4: &IpV4Packet<&mut [u8]>) -> bool {
+        let len = ipv4.buffer.len() as u64;
+        match flow_key(ipv4) {
+            Some((key, timeout)) => self.track_at(peer, key, timeout, len, now_time()),
+            None => false,
+        }
+    }
+    fn track_at(&self, peer: Ipv4Addr, key: FlowKey, timeout: u64, len: u64, now: u64) -> bool {
+        let mut guard = self.state.lock();
+        let state = &mut *guard;
+        if now.saturating_sub(state.window_start) >= QUOTA_WINDOW {
+            state.window_start = now;
+            for usage in state.peers.values_mut() {
+                usage.bytes = 0;
+            }
+        }
+        if now.saturating_sub(state.last_sweep) >= SWEEP_INTERVAL {
+            state.last_sweep = now;
+            sweep(state, now);
+        }
+        let usage = state.peers.entry(peer).or_default();
+        if self.config.quota > 0 && usage.bytes >= self.config.quota {
+            return false;
+        }
+        match state.flows.get_mut(&key) {
+            Some(flow) => {
+                *flow = (peer, now, timeout);
+            }
+            None => {
+                if usage.flows >= self.config.max_flows {
+                    log::debug!("{} 网关连接数超限 {:?}", peer, key);
+                    return false;
+                }
+                usage.flows += 1;
+                state.flows.insert(key, (peer, now, timeout));
+            }
+        }
+        usage.bytes += len;
+        true
+    }
+    /// 外部回应给对端的数据计入对端的流量
+    pub fn download(&self, peer: Ipv4Addr, len: usize) {
+        if let Some(usage) = self.state.lock().peers.get_mut(&peer) {
+            usage.bytes += len as u64;
+        }
+    }
+    /// 各对端的连接数和本周期的流量
+    pub fn usage(&self) -> Vec<(Ipv4Addr, PeerUsage)> {
+        let guard = self.state.lock();
+        let mut list: Vec<(Ipv4Addr, PeerUsage)> = guard
+            .peers
+            .iter()
+            .map(|(ip, usage)| (*ip, *usage))
+            .collect();
+        list.sort_by_key(|(ip, _)| *ip);
+        list
+    }
+}
+
+fn sweep(state: &mut State, now: u64) {
+    let peers = &mut state.peers;
+    state.flows.retain(|_, (peer, last, timeout)| {
+        if now.saturating_sub(*last) < *timeout {
+            return true;
+        }
+        if let Some(usage) = peers.get_mut(peer) {
+            usage.flows = usage.flows.saturating_sub(1);
+        }
+        false
+    });
+    // 没有连接并且没有用量的对端不再保留
+    peers.retain(|_, usage| usage.flows > 0 || usage.bytes > 0);
+}
+
+/// 连接标识和空闲超时时间，icmp使用标识符代替端口
+fn flow_key(ipv4: &IpV4Packet<&mut [u8]>) -> Option<(FlowKey, u64)> {
+    let payload = ipv4.payload();
+    let (source_port, destination_port, timeout) = match ipv4.protocol() {
+        Protocol::Tcp => {
+            if payload.len() < 14 {
+                return None;
+            }
+            // 收到fin或rst后很快释放连接数
+            let timeout = if payload[13] & 0x05 != 0 {
+                TCP_CLOSE_TIMEOUT
+            } else {
+                TCP_TIMEOUT
+            };
+            (
+                u16::from_be_bytes([payload[0], payload[1]]),
+                u16::from_be_bytes([payload[2], payload[3]]),
+                timeout,
+            )
+        }
+        Protocol::Udp => {
+            if payload.len() < 8 {
+                return None;
+            }
+            (
+                u16::from_be_bytes([payload[0], payload[1]]),
+                u16::from_be_bytes([payload[2], payload[3]]),
+                UDP_TIMEOUT,
+            )
+        }
+        Protocol::Icmp => {
+            if payload.len() < 8 {
+                return None;
+            }
+            (
+                u16::from_be_bytes([payload[4], payload[5]]),
+                0,
+                ICMP_TIMEOUT,
+            )
+        }
+        _ => return None,
+    };
+    Some((
+        FlowKey {
+            protocol: ipv4.buffer[9],
+            source: SocketAddrV4::new(ipv4.source_ip(), source_port),
+            destination: SocketAddrV4::new(ipv4.destination_ip(), destination_port),
+        },
+        timeout,
+    ))
+}
+
+#[test]
+fn test_conn_track() {
+    assert_eq!(
+        "".parse::<GatewayConfig>().unwrap(),
+        GatewayConfig::default()
+    );
+    assert_eq!(
+        "2,1".parse::<GatewayConfig>().unwrap(),
+        GatewayConfig {
+            max_flows: 2,
+            quota: 1024 * 1024,
+        }
+    );
+    assert!("0".parse::<GatewayConfig>().is_err());
+    assert!("2,x".parse::<GatewayConfig>().is_err());
+
+    let track = ConnTrack::new(GatewayConfig {
+        max_flows: 2,
+        quota: 1000,
+    });
+    let peer = Ipv4Addr::new(10, 26, 0, 2);
+    let key = |port: u16| FlowKey {
+        protocol: 17,
+        source: SocketAddrV4::new(peer, port),
+        destination: SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 53),
+    };
+    assert!(track.track_at(peer, key(1), UDP_TIMEOUT, 100, 0));
+    assert!(track.track_at(peer, key(2), UDP_TIMEOUT, 100, 0));
+    // 已有的连接不受连接数限制
+    assert!(track.track_at(peer, key(1), UDP_TIMEOUT, 100, 10));
+    assert!(!track.track_at(peer, key(3), UDP_TIMEOUT, 100, 10));
+    // 超时释放后可以建立新连接
+    assert!(track.track_at(peer, key(3), UDP_TIMEOUT, 100, UDP_TIMEOUT + 5));
+    assert_eq!(track.usage()[0].1.flows, 2);
+    track.download(peer, 600);
+    assert!(!track.track_at(peer, key(1), UDP_TIMEOUT, 100, UDP_TIMEOUT + 6));
+    // 新的周期重新计算配额
+    assert!(track.track_at(peer, key(1), UDP_TIMEOUT, 100, QUOTA_WINDOW));
+}
@@ -205,7 +205,17 @@ fn recv_handle(
                         net_packet.first_set_ttl(MAX_TTL);
                         net_packet.set_source(virtual_ip);
                         net_packet.set_destination(dest_ip);
-                        if let Err(e) = client_cipher.encrypt_ipv4(&mut net_packet) {
+                        if let Some(replay_guard) = &context.replay_guard {
+                            if let Err(e) = replay_guard.seal(&mut net_packet) {
+                                log::warn!("{}", e);
+                                return;
+                            }
+                        }
+                        if let Err(e) = context
+                            .pairwise_cipher
+                            .get(&dest_ip, client_cipher)
+                            .encrypt_ipv4(&mut net_packet)
+                        {
                             log::warn!("加密失败:{}", e);
                             return;
                         }
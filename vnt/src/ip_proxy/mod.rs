@@ -1,5 +1,5 @@
 use std::io;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::sync::Arc;
 
 use crossbeam_utils::atomic::AtomicCell;
@@ -10,11 +10,13 @@ use packet::ip::ipv4::packet::IpV4Packet;
 use crate::channel::context::ChannelContext;
 use crate::cipher::Cipher;
 use crate::handle::CurrentDeviceInfo;
+use crate::ip_proxy::gateway::{ConnTrack, GatewayConfig, PeerUsage};
 use crate::ip_proxy::icmp_proxy::IcmpProxy;
 use crate::ip_proxy::tcp_proxy::TcpProxy;
 use crate::ip_proxy::udp_proxy::UdpProxy;
 use crate::util::{Scheduler, StopManager};
 
+pub mod gateway;
 pub mod icmp_proxy;
 pub mod tcp_proxy;
 pub mod udp_proxy;
@@ -34,6 +36,8 @@ pub struct IpProxyMap {
     icmp_proxy: IcmpProxy,
     tcp_proxy: TcpProxy,
     udp_proxy: UdpProxy,
+    // 网关模式的连接跟踪
+    gateway: Option<Arc<ConnTrack>>,
 }
 
 pub fn init_proxy(
@@ -42,18 +46,43 @@ pub fn init_proxy(
     stop_manager: StopManager,
     current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
     client_cipher: Cipher,
+    tcp_proxy_keepalive: u32,
+    tcp_proxy_idle: u32,
+    tcp_proxy_max_conn: u32,
+    out_ips6: Vec<(u32, u32, Ipv6Addr)>,
+    gateway: Option<GatewayConfig>,
 ) -> io::Result<IpProxyMap> {
     let icmp_proxy = IcmpProxy::new(context, stop_manager.clone(), current_device, client_cipher)?;
-    let tcp_proxy = TcpProxy::new(stop_manager.clone())?;
+    let tcp_proxy = TcpProxy::new(
+        stop_manager.clone(),
+        tcp_proxy_keepalive,
+        tcp_proxy_idle,
+        tcp_proxy_max_conn,
+        out_ips6,
+    )?;
     let udp_proxy = UdpProxy::new(scheduler, stop_manager)?;
 
     Ok(IpProxyMap {
         icmp_proxy,
         tcp_proxy,
         udp_proxy,
+        gateway: gateway.map(|config| Arc::new(ConnTrack::new(config))),
     })
 }
 
+impl IpProxyMap {
+    pub fn tcp_evict_count(&self) -> u64 {
+        self.tcp_proxy.evict_count()
+    }
+    /// 网关模式下各对端的连接数和流量
+    pub fn gateway_usage(&self) -> Vec<(Ipv4Addr, PeerUsage)> {
+        self.gateway
+            .as_ref()
+            .map(|gateway| gateway.usage())
+            .unwrap_or_default()
+    }
+}
+
 impl ProxyHandler for IpProxyMap {
     fn recv_handle(
         &self,
@@ -61,6 +90,12 @@ impl ProxyHandler for IpProxyMap {
         source: Ipv4Addr,
         destination: Ipv4Addr,
     ) -> io::Result<bool> {
+        if let Some(gateway) = &self.gateway {
+            if !gateway.track(source, ipv4) {
+                // 超出连接数或流量配额，直接丢弃
+                return Ok(true);
+            }
+        }
         match ipv4.protocol() {
             ipv4::protocol::Protocol::Tcp => self.tcp_proxy.recv_handle(ipv4, source, destination),
             ipv4::protocol::Protocol::Udp => self.udp_proxy.recv_handle(ipv4, source, destination),
@@ -81,11 +116,19 @@ impl ProxyHandler for IpProxyMap {
     }
 
     fn send_handle(&self, ipv4: &mut IpV4Packet<&mut [u8]>) -> io::Result<()> {
+        let source = ipv4.source_ip();
         match ipv4.protocol() {
-            ipv4::protocol::Protocol::Tcp => self.tcp_proxy.send_handle(ipv4),
-            ipv4::protocol::Protocol::Udp => self.udp_proxy.send_handle(ipv4),
-            ipv4::protocol::Protocol::Icmp => self.icmp_proxy.send_handle(ipv4),
-            _ => Ok(()),
+            ipv4::protocol::Protocol::Tcp => self.tcp_proxy.send_handle(ipv4)?,
+            ipv4::protocol::Protocol::Udp => self.udp_proxy.send_handle(ipv4)?,
+            ipv4::protocol::Protocol::Icmp => self.icmp_proxy.send_handle(ipv4)?,
+            _ => return Ok(()),
+        }
+        if let Some(gateway) = &self.gateway {
+            // 源地址被代理改写说明是外部的回应
+            if source != ipv4.source_ip() {
+                gateway.download(ipv4.destination_ip(), ipv4.buffer.len());
+            }
         }
+        Ok(())
     }
 }
@@ -1,11 +1,12 @@
 use std::io::{Read, Write};
-use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddrV4, SocketAddrV6};
 #[cfg(unix)]
 use std::os::fd::AsRawFd;
 #[cfg(windows)]
 use std::os::windows::io::AsRawSocket;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{collections::HashMap, io, net::SocketAddr, thread};
 
 use bytes::{BufMut, BytesMut};
@@ -24,30 +25,94 @@ const SERVER: Token = Token(SERVER_VAL);
 const NOTIFY_VAL: usize = 1;
 const NOTIFY: Token = Token(NOTIFY_VAL);
 
+/// 空闲检查的间隔
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Clone)]
 pub struct TcpProxy {
     port: u16,
     nat_map: Arc<Mutex<HashMap<SocketAddrV4, SocketAddrV4>>>,
+    evict_count: Arc<AtomicU64>,
+}
+
+/// 点对网的ipv6映射，虚拟网段的主机位填入ipv6地址的低位
+#[derive(Clone, Debug)]
+struct Ipv6Map {
+    list: Arc<Vec<(u32, u32, Ipv6Addr)>>,
+}
+
+impl Ipv6Map {
+    fn new(list: Vec<(u32, u32, Ipv6Addr)>) -> Self {
+        Self {
+            list: Arc::new(list),
+        }
+    }
+    /// 实际连接的目标地址
+    fn target(&self, addr: SocketAddrV4) -> SocketAddr {
+        let ip = u32::from(*addr.ip());
+        for (dest, mask, ipv6) in self.list.iter() {
+            if ip & mask == *dest {
+                let ipv6 = Ipv6Addr::from(u128::from(*ipv6) | (ip & !mask) as u128);
+                return SocketAddrV6::new(ipv6, addr.port(), 0, 0).into();
+            }
+        }
+        addr.into()
+    }
+}
+
+#[derive(Clone, Debug)]
+struct TcpProxyOption {
+    // tcp保活探测开始时间
+    keepalive: Duration,
+    // 无数据超时时间，为0则不超时
+    idle_timeout: Duration,
+    // 最大连接数，为0则不限制
+    max_conn: usize,
+    ipv6_map: Ipv6Map,
 }
 
 impl TcpProxy {
-    pub fn new(stop_manager: StopManager) -> io::Result<Self> {
+    pub fn new(
+        stop_manager: StopManager,
+        keepalive: u32,
+        idle_timeout: u32,
+        max_conn: u32,
+        out_ips6: Vec<(u32, u32, Ipv6Addr)>,
+    ) -> io::Result<Self> {
         let nat_map: Arc<Mutex<HashMap<SocketAddrV4, SocketAddrV4>>> =
             Arc::new(Mutex::new(HashMap::with_capacity(16)));
-        let tcp_listener = TcpListener::bind(format!("0.0.0.0:{}", 0).parse().unwrap())?;
+        let evict_count = Arc::new(AtomicU64::new(0));
+        let tcp_listener = bind_listener()?;
         let port = tcp_listener.local_addr()?.port();
+        let option = TcpProxyOption {
+            keepalive: Duration::from_secs(keepalive.max(1) as u64),
+            idle_timeout: Duration::from_secs(idle_timeout as u64),
+            max_conn: max_conn as usize,
+            ipv6_map: Ipv6Map::new(out_ips6),
+        };
         {
             let nat_map = nat_map.clone();
+            let evict_count = evict_count.clone();
             thread::Builder::new()
                 .name("tcpProxy".into())
                 .spawn(move || {
-                    if let Err(e) = tcp_proxy(tcp_listener, nat_map, stop_manager) {
+                    if let Err(e) =
+                        tcp_proxy(tcp_listener, nat_map, stop_manager, option, evict_count)
+                    {
                         log::warn!("tcp_proxy:{:?}", e);
                     }
                 })
                 .expect("tcpProxy");
         }
-        Ok(Self { port, nat_map })
+        Ok(Self {
+            port,
+            nat_map,
+            evict_count,
+        })
+    }
+    /// 因空闲超时或连接数超限被关闭的连接数
+    pub fn evict_count(&self) -> u64 {
+        self.evict_count.load(Ordering::Relaxed)
     }
 }
 
@@ -97,6 +162,8 @@ fn tcp_proxy(
     mut tcp_listener: TcpListener,
     nat_map: Arc<Mutex<HashMap<SocketAddrV4, SocketAddrV4>>>,
     stop_manager: StopManager,
+    option: TcpProxyOption,
+    evict_count: Arc<AtomicU64>,
 ) -> io::Result<()> {
     let mut poll = Poll::new()?;
     poll.registry()
@@ -111,11 +178,43 @@ fn tcp_proxy(
             log::warn!("stop tcp_proxy:{:?}", e);
         }
     })?;
+    let mut last_check = Instant::now();
     loop {
-        poll.poll(&mut events, None)?;
+        // 有连接在等待缓存发完时也需要定时检查
+        let timeout = if option.idle_timeout.is_zero() && !tcp_map.values().any(|v| v.is_draining())
+        {
+            None
+        } else {
+            Some(IDLE_CHECK_INTERVAL)
+        };
+        poll.poll(&mut events, timeout)?;
         if stop_manager.is_stop() {
             return Ok(());
         }
+        if timeout.is_some() && last_check.elapsed() >= IDLE_CHECK_INTERVAL {
+            last_check = Instant::now();
+            let expired: Vec<usize> = tcp_map
+                .iter()
+                .filter(|(_, v)| v.drain_expired())
+                .map(|(k, _)| *k)
+                .collect();
+            for index in expired {
+                log::info!("tcp代理连接关闭后缓存数据未能发完,fd={}", index);
+                close(index, &mut tcp_map, &mut mapping);
+            }
+            if !option.idle_timeout.is_zero() {
+                let idle: Vec<usize> = tcp_map
+                    .iter()
+                    .filter(|(_, v)| v.last_active.elapsed() >= option.idle_timeout)
+                    .map(|(k, _)| *k)
+                    .collect();
+                for index in idle {
+                    log::info!("tcp代理连接空闲超时,fd={}", index);
+                    close(index, &mut tcp_map, &mut mapping);
+                    evict_count.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
         for event in events.iter() {
             match event.token() {
                 SERVER => {
@@ -125,6 +224,8 @@ fn tcp_proxy(
                         &nat_map,
                         &mut tcp_map,
                         &mut mapping,
+                        &option,
+                        &evict_count,
                     );
                 }
                 NOTIFY => {
@@ -144,46 +245,14 @@ fn tcp_proxy(
                             continue;
                         }
                     };
-                    let (stream1, stream2, buf1, buf2, state1, state2) = val.as_mut(index);
-                    if event.is_readable() {
-                        if let Err(_) = readable_handle(stream1, stream2, buf1, state2) {
-                            *state1 |= READ_CLOSED;
-                        }
-                    }
-                    if event.is_writable() {
-                        let read = buf2.len() >= BUF_LEN;
-                        if let Err(_) = writable_handle(stream1, buf2) {
-                            *state1 |= WRITE_CLOSED;
-                        } else if read {
-                            if readable_handle(stream2, stream1, buf2, state1).is_err() {
-                                *state2 |= READ_CLOSED;
-                            }
-                        }
-                    }
-                    if event.is_read_closed() || event.is_error() {
-                        *state1 |= READ_CLOSED;
-                    }
-                    if event.is_write_closed() || event.is_error() {
-                        *state1 |= WRITE_CLOSED;
-                    }
-                    if is_write_closed(*state1) {
-                        let _ = stream1.shutdown(Shutdown::Write);
-                        let _ = stream2.shutdown(Shutdown::Read);
-                    }
-                    if is_read_closed(*state1) {
-                        let _ = stream1.shutdown(Shutdown::Read);
-                        if buf1.is_empty() {
-                            let _ = stream2.shutdown(Shutdown::Write);
-                        }
-                    }
-                    if (is_both_closed(*state1) && buf1.is_empty())
-                        || (is_both_closed(*state2) && buf2.is_empty())
-                        || (is_write_closed(*state1) && is_write_closed(*state2)
-                            || (is_read_closed(*state1)
-                                && is_read_closed(*state2)
-                                && buf1.is_empty()
-                                && buf2.is_empty()))
-                    {
+                    val.last_active = Instant::now();
+                    if val.handle(
+                        index,
+                        event.is_readable() || event.is_read_closed(),
+                        event.is_writable(),
+                        event.is_write_closed(),
+                        event.is_error(),
+                    ) {
                         close(src_index, &mut tcp_map, &mut mapping);
                     }
                 }
@@ -198,6 +267,8 @@ fn accept_handle(
     nat_map: &Mutex<HashMap<SocketAddrV4, SocketAddrV4>>,
     tcp_map: &mut HashMap<usize, ProxyValue>,
     mapping: &mut HashMap<usize, usize>,
+    option: &TcpProxyOption,
+    evict_count: &AtomicU64,
 ) {
     loop {
         match tcp_listener.accept() {
@@ -210,16 +281,30 @@ fn accept_handle(
                     log::error!("fd错误:{:?}", src_fd);
                     continue;
                 }
+                // 双栈监听时ipv4的来源是映射地址，其他ipv6来源不会来自虚拟网络
                 let addr = match addr {
                     SocketAddr::V4(addr) => addr,
-                    SocketAddr::V6(_) => {
-                        // 忽略ipv6
-                        continue;
-                    }
+                    SocketAddr::V6(addr) => match addr.ip().to_ipv4_mapped() {
+                        Some(ip) => SocketAddrV4::new(ip, addr.port()),
+                        None => continue,
+                    },
                 };
                 let _ = src_stream.set_nodelay(false);
                 if let Some(dest_addr) = nat_map.lock().get(&addr).cloned() {
-                    match tcp_connect(addr.port(), dest_addr.into()) {
+                    if option.max_conn > 0 && tcp_map.len() >= option.max_conn {
+                        // 连接数达到上限，关闭空闲最久的连接
+                        let oldest = tcp_map
+                            .iter()
+                            .min_by_key(|(_, v)| v.last_active)
+                            .map(|(k, _)| *k);
+                        if let Some(index) = oldest {
+                            log::info!("tcp代理连接数达到上限{},关闭fd={}", option.max_conn, index);
+                            close(index, tcp_map, mapping);
+                            evict_count.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                    let target = option.ipv6_map.target(dest_addr);
+                    match tcp_connect(addr.port(), target, option.keepalive) {
                         Ok(mut dest_stream) => {
                             #[cfg(windows)]
                             let dest_fd = dest_stream.as_raw_socket() as usize;
@@ -252,7 +337,7 @@ fn accept_handle(
                             mapping.insert(dest_fd, src_fd);
                         }
                         Err(e) => {
-                            log::error!("connect:{:?} {}->{}", e, addr, dest_addr);
+                            log::error!("connect:{:?} {}->{}({})", e, addr, dest_addr, target);
                         }
                     }
                 }
@@ -267,21 +352,50 @@ fn accept_handle(
     }
 }
 
-fn tcp_connect(src_port: u16, addr: SocketAddr) -> io::Result<TcpStream> {
+/// 优先监听双栈地址，系统不支持ipv6时退回ipv4
+fn bind_listener() -> io::Result<TcpListener> {
+    let bind = |addr: SocketAddr| -> io::Result<TcpListener> {
+        let socket = socket2::Socket::new(
+            socket2::Domain::for_address(addr),
+            socket2::Type::STREAM,
+            Some(socket2::Protocol::TCP),
+        )?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(false)?;
+        }
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        socket.set_nonblocking(true)?;
+        Ok(TcpListener::from_std(socket.into()))
+    };
+    match bind(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0).into()) {
+        Ok(listener) => Ok(listener),
+        Err(e) => {
+            log::warn!("tcp代理监听双栈地址失败,使用ipv4 {:?}", e);
+            bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0).into())
+        }
+    }
+}
+
+fn tcp_connect(src_port: u16, addr: SocketAddr, keepalive: Duration) -> io::Result<TcpStream> {
     let socket = socket2::Socket::new(
-        socket2::Domain::IPV4,
+        socket2::Domain::for_address(addr),
         socket2::Type::STREAM,
         Some(socket2::Protocol::TCP),
     )?;
-    if socket
-        .bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, src_port).into())
-        .is_err()
-    {
-        socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0).into())?;
+    let unspecified: SocketAddr = if addr.is_ipv6() {
+        SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, src_port, 0, 0).into()
+    } else {
+        SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, src_port).into()
+    };
+    if socket.bind(&unspecified.into()).is_err() {
+        let mut unspecified = unspecified;
+        unspecified.set_port(0);
+        socket.bind(&unspecified.into())?;
     }
     if let Err(e) = socket.set_tcp_keepalive(
         &socket2::TcpKeepalive::new()
-            .with_time(Duration::from_secs(120))
+            .with_time(keepalive)
             .with_interval(Duration::from_secs(10)),
     ) {
         log::warn!("set_tcp_keepalive err {:?}", e);
@@ -292,22 +406,41 @@ fn tcp_connect(src_port: u16, addr: SocketAddr) -> io::Result<TcpStream> {
     Ok(TcpStream::from_std(socket.into()))
 }
 
+/// 连接双方都不再有数据读入后，等待缓存数据发完的最长时间
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 代理连接的一端，便于测试时替换
+trait ProxyStream: Read + Write {
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl ProxyStream for TcpStream {
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
 #[derive(Debug)]
-struct ProxyValue {
-    src_stream: TcpStream,
-    dest_stream: TcpStream,
+struct ProxyValue<S = TcpStream> {
+    src_stream: S,
+    dest_stream: S,
     src_fd: usize,
     dest_fd: usize,
+    // 从src读取，待写入dest的数据
     src_buf: BytesMut,
+    // 从dest读取，待写入src的数据
     dest_buf: BytesMut,
     src_state: u8,
     dest_state: u8,
+    last_active: Instant,
+    // 双方都读到结束时开始计时，超时后不再等待缓存发完
+    drain_start: Option<Instant>,
 }
 
 const BUF_LEN: usize = 65536;
 
-impl ProxyValue {
-    fn new(src_stream: TcpStream, dest_stream: TcpStream, src_fd: usize, dest_fd: usize) -> Self {
+impl<S: ProxyStream> ProxyValue<S> {
+    fn new(src_stream: S, dest_stream: S, src_fd: usize, dest_fd: usize) -> Self {
         Self {
             src_stream,
             dest_stream,
@@ -317,14 +450,16 @@ impl ProxyValue {
             dest_buf: BytesMut::with_capacity(BUF_LEN),
             src_state: NORMAL,
             dest_state: NORMAL,
+            last_active: Instant::now(),
+            drain_start: None,
         }
     }
     fn as_mut(
         &mut self,
         index: usize,
     ) -> (
-        &mut TcpStream,
-        &mut TcpStream,
+        &mut S,
+        &mut S,
         &mut BytesMut,
         &mut BytesMut,
         &mut u8,
@@ -350,11 +485,112 @@ impl ProxyValue {
             )
         }
     }
+    /// 处理index对应一端的事件，返回true表示两个方向都已结束，需要关闭
+    fn handle(
+        &mut self,
+        index: usize,
+        readable: bool,
+        writable: bool,
+        write_closed: bool,
+        error: bool,
+    ) -> bool {
+        let (stream1, stream2, buf1, buf2, state1, state2) = self.as_mut(index);
+        if error {
+            *state1 |= READ_CLOSED | WRITE_CLOSED;
+        }
+        if write_closed {
+            *state1 |= WRITE_CLOSED;
+        }
+        // 对端的FIN不代表数据已经读完，读到结束才标记关闭
+        if readable
+            && !is_read_closed(*state1)
+            && !is_write_closed(*state2)
+            && readable_handle(stream1, stream2, buf1, state2).is_err()
+        {
+            *state1 |= READ_CLOSED;
+        }
+        if writable && !is_write_closed(*state1) {
+            let read = buf2.len() >= BUF_LEN;
+            if writable_handle(stream1, buf2).is_err() {
+                *state1 |= WRITE_CLOSED;
+            } else if read && !is_read_closed(*state2) {
+                // 缓存满时停止了读取，腾出空间后继续
+                if readable_handle(stream2, stream1, buf2, state1).is_err() {
+                    *state2 |= READ_CLOSED;
+                }
+            }
+        }
+        self.settle()
+    }
+    /// 根据两端的状态推进半关闭，返回true表示可以关闭
+    fn settle(&mut self) -> bool {
+        // src -> dest
+        let src_done = half_close(
+            &self.src_stream,
+            &mut self.src_state,
+            &self.dest_stream,
+            &mut self.dest_state,
+            &mut self.src_buf,
+        );
+        // dest -> src
+        let dest_done = half_close(
+            &self.dest_stream,
+            &mut self.dest_state,
+            &self.src_stream,
+            &mut self.src_state,
+            &mut self.dest_buf,
+        );
+        if src_done && dest_done {
+            return true;
+        }
+        if is_read_closed(self.src_state)
+            && is_read_closed(self.dest_state)
+            && self.drain_start.is_none()
+        {
+            self.drain_start = Some(Instant::now());
+        }
+        false
+    }
+    fn is_draining(&self) -> bool {
+        self.drain_start.is_some()
+    }
+    fn drain_expired(&self) -> bool {
+        self.drain_start
+            .map(|time| time.elapsed() >= DRAIN_TIMEOUT)
+            .unwrap_or(false)
+    }
+}
+
+/// 处理from -> to方向，from读完并且缓存发完后给to发送FIN，to不能写入时丢弃缓存并停止读取from。
+/// 返回这个方向是否已经结束
+fn half_close<S: ProxyStream>(
+    from: &S,
+    from_state: &mut u8,
+    to: &S,
+    to_state: &mut u8,
+    buf: &mut BytesMut,
+) -> bool {
+    if is_write_closed(*to_state) {
+        if !is_read_closed(*from_state) {
+            let _ = from.shutdown(Shutdown::Read);
+            *from_state |= READ_CLOSED;
+        }
+        buf.clear();
+        return true;
+    }
+    if is_read_closed(*from_state) && buf.is_empty() {
+        if *to_state & SHUT_WRITE == 0 {
+            let _ = to.shutdown(Shutdown::Write);
+            *to_state |= SHUT_WRITE;
+        }
+        return true;
+    }
+    false
 }
 
-fn readable_handle(
-    stream1: &mut TcpStream,
-    stream2: &mut TcpStream,
+fn readable_handle<S: ProxyStream>(
+    stream1: &mut S,
+    stream2: &mut S,
     mid_buf: &mut BytesMut,
     state2: &mut u8,
 ) -> io::Result<()> {
@@ -378,14 +614,15 @@ fn readable_handle(
                             Ok(end) => {
                                 if end == 0 {
                                     *state2 |= WRITE_CLOSED;
-                                    return Err(io::Error::from(io::ErrorKind::WriteZero));
+                                    return Ok(());
                                 }
                                 buf = &buf[end..];
                             }
                             Err(e) => {
                                 if e.kind() != io::ErrorKind::WouldBlock {
+                                    // 写入端的错误不影响读取端的状态
                                     *state2 |= WRITE_CLOSED;
-                                    return Err(e);
+                                    return Ok(());
                                 }
                                 break;
                             }
@@ -409,9 +646,10 @@ fn readable_handle(
     Ok(())
 }
 
-fn writable_handle(stream: &mut TcpStream, mid_buf: &mut BytesMut) -> io::Result<()> {
+fn writable_handle<S: ProxyStream>(stream: &mut S, mid_buf: &mut BytesMut) -> io::Result<()> {
     while !mid_buf.is_empty() {
-        match stream.write(&mid_buf) {
+        match stream.write(mid_buf) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
             Ok(len) => {
                 let _ = mid_buf.split_to(len);
             }
@@ -439,19 +677,164 @@ fn close(
     }
 }
 
-const NORMAL: u8 = 0b00;
-const READ_CLOSED: u8 = 0b01;
-const WRITE_CLOSED: u8 = 0b10;
-const BOTH_CLOSED: u8 = 0b11;
+const NORMAL: u8 = 0b000;
+// 不会再读到数据
+const READ_CLOSED: u8 = 0b001;
+// 不能再写入
+const WRITE_CLOSED: u8 = 0b010;
+// 已经发送了FIN
+const SHUT_WRITE: u8 = 0b100;
 
 fn is_read_closed(state: u8) -> bool {
-    (state & READ_CLOSED == READ_CLOSED) || is_both_closed(state)
+    state & READ_CLOSED == READ_CLOSED
 }
 
 fn is_write_closed(state: u8) -> bool {
-    (state & WRITE_CLOSED == WRITE_CLOSED) || is_both_closed(state)
+    state & WRITE_CLOSED == WRITE_CLOSED
 }
 
-fn is_both_closed(state: u8) -> bool {
-    state & BOTH_CLOSED == BOTH_CLOSED
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// 模拟的非阻塞连接，input为对端发来的数据，output为写给对端的数据
+    #[derive(Default)]
+    struct MockStream {
+        input: VecDeque<u8>,
+        eof: bool,
+        output: Vec<u8>,
+        // 可写入的字节数，用完后返回WouldBlock
+        write_cap: usize,
+        shutdown: RefCell<Vec<Shutdown>>,
+    }
+
+    impl MockStream {
+        fn new(write_cap: usize) -> Self {
+            Self {
+                write_cap,
+                ..Default::default()
+            }
+        }
+        fn is_shutdown(&self, how: Shutdown) -> bool {
+            self.shutdown.borrow().contains(&how)
+        }
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.input.is_empty() {
+                return if self.eof {
+                    Ok(0)
+                } else {
+                    Err(io::Error::from(io::ErrorKind::WouldBlock))
+                };
+            }
+            let len = buf.len().min(self.input.len());
+            for (i, v) in self.input.drain(..len).enumerate() {
+                buf[i] = v;
+            }
+            Ok(len)
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.write_cap == 0 {
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+            let len = buf.len().min(self.write_cap);
+            self.write_cap -= len;
+            self.output.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ProxyStream for MockStream {
+        fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+            self.shutdown.borrow_mut().push(how);
+            Ok(())
+        }
+    }
+
+    const SRC: usize = 10;
+    const DEST: usize = 11;
+
+    #[test]
+    fn ipv6_map_target() {
+        let map = Ipv6Map::new(vec![(
+            u32::from(Ipv4Addr::new(192, 168, 10, 0)),
+            0xFFFF_FF00,
+            "fd00::100".parse().unwrap(),
+        )]);
+        let addr = SocketAddrV4::new(Ipv4Addr::new(192, 168, 10, 5), 80);
+        assert_eq!(map.target(addr), "[fd00::105]:80".parse().unwrap());
+        let addr = SocketAddrV4::new(Ipv4Addr::new(192, 168, 11, 5), 80);
+        assert_eq!(map.target(addr), SocketAddr::V4(addr));
+    }
+
+    #[test]
+    fn half_close_keeps_other_direction() {
+        let mut val = ProxyValue::new(MockStream::new(1024), MockStream::new(1024), SRC, DEST);
+        // 客户端发完请求后关闭写入
+        val.src_stream.input.extend(b"request");
+        val.src_stream.eof = true;
+        assert!(!val.handle(SRC, true, false, false, false));
+        assert_eq!(val.dest_stream.output, b"request");
+        assert!(val.dest_stream.is_shutdown(Shutdown::Write));
+        // 响应依然可以回到客户端
+        val.dest_stream.input.extend(b"response");
+        assert!(!val.handle(DEST, true, false, false, false));
+        assert_eq!(val.src_stream.output, b"response");
+        val.dest_stream.eof = true;
+        assert!(val.handle(DEST, true, false, false, false));
+        assert!(val.src_stream.is_shutdown(Shutdown::Write));
+    }
+
+    #[test]
+    fn fin_waits_for_buffered_data() {
+        let mut val = ProxyValue::new(MockStream::new(1024), MockStream::new(4), SRC, DEST);
+        val.src_stream.input.extend(b"0123456789");
+        val.src_stream.eof = true;
+        assert!(!val.handle(SRC, true, false, false, false));
+        // 缓存没发完之前不能发送FIN
+        assert_eq!(val.dest_stream.output, b"0123");
+        assert!(!val.dest_stream.is_shutdown(Shutdown::Write));
+        val.dest_stream.write_cap = 1024;
+        assert!(!val.handle(DEST, false, true, false, false));
+        assert_eq!(val.dest_stream.output, b"0123456789");
+        assert!(val.dest_stream.is_shutdown(Shutdown::Write));
+    }
+
+    #[test]
+    fn drain_timer_starts_when_both_read_closed() {
+        let mut val = ProxyValue::new(MockStream::new(1024), MockStream::new(0), SRC, DEST);
+        val.src_stream.input.extend(b"data");
+        val.src_stream.eof = true;
+        val.dest_stream.eof = true;
+        assert!(!val.handle(SRC, true, false, false, false));
+        assert!(!val.is_draining());
+        assert!(!val.handle(DEST, true, false, false, false));
+        assert!(val.is_draining());
+        assert!(!val.drain_expired());
+        val.drain_start = Some(Instant::now() - DRAIN_TIMEOUT);
+        assert!(val.drain_expired());
+    }
+
+    #[test]
+    fn error_discards_undeliverable_data() {
+        let mut val = ProxyValue::new(MockStream::new(1024), MockStream::new(0), SRC, DEST);
+        val.src_stream.input.extend(b"data");
+        assert!(!val.handle(SRC, true, false, false, false));
+        assert_eq!(val.src_buf.len(), 4);
+        // 目标连接出错，数据无法送达
+        assert!(val.handle(DEST, false, false, false, true));
+        assert!(val.src_buf.is_empty());
+        assert!(val.src_stream.is_shutdown(Shutdown::Read));
+    }
 }
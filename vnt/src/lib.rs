@@ -3,6 +3,8 @@ pub const VNT_VERSION: &'static str = env!("CARGO_PKG_VERSION");
 pub mod channel;
 pub mod cipher;
 pub mod core;
+pub mod doctor;
+pub mod error;
 pub mod external_route;
 pub mod handle;
 #[cfg(feature = "ip_proxy")]
@@ -13,4 +15,5 @@ pub mod protocol;
 pub mod tun_tap_device;
 pub mod util;
 
+pub use error::VntError;
 pub use handle::callback::*;
@@ -1,17 +1,19 @@
 use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::net::{SocketAddr, UdpSocket};
-use std::ops::Sub;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use crossbeam_utils::atomic::AtomicCell;
 use parking_lot::Mutex;
 
-use crate::channel::punch::{NatInfo, NatType};
-use crate::proto::message::PunchNatType;
+use crate::channel::punch::{NatFiltering, NatInfo, NatType};
+use crate::nat::stun_pool::{StunPool, StunSource};
+use crate::proto::message::{PunchNatFiltering, PunchNatType};
 
 mod stun;
+pub use stun::stun_probe;
+pub mod stun_pool;
 
 pub fn local_ipv4_() -> io::Result<Ipv4Addr> {
     let socket = UdpSocket::bind("0.0.0.0:0")?;
@@ -33,6 +35,12 @@ pub fn local_ipv4() -> Option<Ipv4Addr> {
     }
 }
 
+/// 运营商级NAT地址段 100.64.0.0/10
+pub fn is_cgnat(ip: &Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    octets[0] == 100 && (octets[1] & 0xC0) == 64
+}
+
 pub fn local_ipv6_() -> io::Result<Ipv6Addr> {
     let socket = UdpSocket::bind("[::]:0")?;
     socket.connect("[2001:4860:4860:0000:0000:0000:0000:8888]:80")?;
@@ -53,15 +61,53 @@ pub fn local_ipv6() -> Option<Ipv6Addr> {
     }
 }
 
+/// 探测失败后的首次重试间隔
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+/// 失败退避的最大间隔
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(30 * 60);
+/// 本地地址不变时缓存结果的最长时间，超过后重新探测，NAT映射可能在本地地址不变时变化(如上游路由器重启)
+const MAX_CACHE_AGE: Duration = Duration::from_secs(10 * 60);
+/// 每次探测使用的stun服务器数量
+const STUN_TEST_NUM: usize = 3;
+
 #[derive(Clone)]
 pub struct NatTest {
-    stun_server: Vec<String>,
+    stun_pool: Arc<StunPool>,
     info: Arc<Mutex<NatInfo>>,
-    time: Arc<AtomicCell<Instant>>,
+    state: Arc<Mutex<NatTestState>>,
+    // 公网地址变化后置位，由定时任务通知对端
+    changed: Arc<AtomicBool>,
     udp_ports: Vec<u16>,
     tcp_port: u16,
 }
 
+/// 探测结果的缓存状态，本地地址不变时复用上次的结果，失败时指数退避，避免频繁唤醒移动网络
+struct NatTestState {
+    // 上次探测成功时的本地地址
+    local_addr: Option<(Option<Ipv4Addr>, Option<Ipv6Addr>)>,
+    // 上次探测成功的时间
+    tested_time: Instant,
+    // 连续失败次数
+    fail_count: u32,
+    // 下次允许探测的时间
+    next_time: Instant,
+}
+
+impl NatTestState {
+    /// 本地地址变化、上次探测失败或者结果过期时需要探测
+    fn need_test(&self, now: Instant, local_addr: (Option<Ipv4Addr>, Option<Ipv6Addr>)) -> bool {
+        if now < self.next_time {
+            return false;
+        }
+        self.local_addr != Some(local_addr)
+            || now.saturating_duration_since(self.tested_time) >= MAX_CACHE_AGE
+    }
+    fn retry_interval(&self) -> Duration {
+        let multiple = 1u32 << self.fail_count.saturating_sub(1).min(10);
+        (RETRY_INTERVAL * multiple).min(MAX_RETRY_INTERVAL)
+    }
+}
+
 impl From<NatType> for PunchNatType {
     fn from(value: NatType) -> Self {
         match value {
@@ -80,17 +126,37 @@ impl Into<NatType> for PunchNatType {
     }
 }
 
+impl From<NatFiltering> for PunchNatFiltering {
+    fn from(value: NatFiltering) -> Self {
+        match value {
+            NatFiltering::Unknown => PunchNatFiltering::FilteringUnknown,
+            NatFiltering::EndpointIndependent => PunchNatFiltering::EndpointIndependent,
+            NatFiltering::AddressDependent => PunchNatFiltering::AddressDependent,
+            NatFiltering::AddressAndPortDependent => PunchNatFiltering::AddressAndPortDependent,
+        }
+    }
+}
+
+impl From<PunchNatFiltering> for NatFiltering {
+    fn from(value: PunchNatFiltering) -> Self {
+        match value {
+            PunchNatFiltering::FilteringUnknown => NatFiltering::Unknown,
+            PunchNatFiltering::EndpointIndependent => NatFiltering::EndpointIndependent,
+            PunchNatFiltering::AddressDependent => NatFiltering::AddressDependent,
+            PunchNatFiltering::AddressAndPortDependent => NatFiltering::AddressAndPortDependent,
+        }
+    }
+}
+
 impl NatTest {
     pub fn new(
         _channel_num: usize,
-        mut stun_server: Vec<String>,
+        stun_server: Vec<String>,
         local_ipv4: Option<Ipv4Addr>,
         ipv6: Option<Ipv6Addr>,
         udp_ports: Vec<u16>,
         tcp_port: u16,
     ) -> NatTest {
-        let server = stun_server[0].clone();
-        stun_server.resize(3, server);
         let ports = vec![0; udp_ports.len()];
         let nat_info = NatInfo::new(
             Vec::new(),
@@ -104,21 +170,46 @@ impl NatTest {
         );
         let info = Arc::new(Mutex::new(nat_info));
         NatTest {
-            stun_server,
+            stun_pool: Arc::new(StunPool::new(stun_server)),
             info,
-            time: Arc::new(AtomicCell::new(
-                Instant::now().sub(Duration::from_secs(100)),
-            )),
+            state: Arc::new(Mutex::new(NatTestState {
+                local_addr: None,
+                tested_time: Instant::now(),
+                fail_count: 0,
+                next_time: Instant::now(),
+            })),
+            changed: Arc::new(AtomicBool::new(false)),
             udp_ports,
             tcp_port,
         }
     }
-    pub fn can_update(&self) -> bool {
-        let last = self.time.load();
-        last.elapsed() > Duration::from_secs(10)
-            && self.time.compare_exchange(last, Instant::now()).is_ok()
+    /// 是否需要重新探测，本地地址变化、上次探测失败或者距上次探测超过10分钟时探测
+    pub fn can_update(&self, local_ipv4: Option<Ipv4Addr>, ipv6: Option<Ipv6Addr>) -> bool {
+        let mut state = self.state.lock();
+        let now = Instant::now();
+        if !state.need_test(now, (local_ipv4, ipv6)) {
+            return false;
+        }
+        // 探测期间不重复探测
+        state.next_time = now + Duration::from_secs(10);
+        true
     }
 
+    /// 从未探测成功或者上次探测失败时，距离下次可以探测的时间
+    pub fn retry_after(&self) -> Option<Duration> {
+        let state = self.state.lock();
+        if state.local_addr.is_some() {
+            return None;
+        }
+        Some(state.next_time.saturating_duration_since(Instant::now()))
+    }
+    /// 服务端下发的stun服务器
+    pub fn merge_stun_server(&self, stun_server: Vec<String>) {
+        self.stun_pool.merge(stun_server, StunSource::Server)
+    }
+    pub fn stun_pool(&self) -> &StunPool {
+        &self.stun_pool
+    }
     pub fn nat_info(&self) -> NatInfo {
         self.info.lock().clone()
     }
@@ -184,16 +275,61 @@ impl NatTest {
     }
     pub fn update_addr(&self, index: usize, ip: Ipv4Addr, port: u16) {
         let mut guard = self.info.lock();
-        guard.update_addr(index, ip, port)
+        if guard.update_addr(index, ip, port) {
+            self.changed.store(true, Ordering::Release);
+        }
+    }
+    /// 服务端看到的ipv6地址，打洞时告知对端
+    pub fn update_ipv6_addr(&self, ip: Ipv6Addr) {
+        let mut guard = self.info.lock();
+        if guard.update_ipv6_addr(ip) {
+            self.changed.store(true, Ordering::Release);
+        }
+    }
+    /// 公网地址是否发生了变化，取出后清除
+    pub fn take_changed(&self) -> bool {
+        self.changed.swap(false, Ordering::AcqRel)
     }
     pub fn re_test(
         &self,
         local_ipv4: Option<Ipv4Addr>,
         ipv6: Option<Ipv6Addr>,
     ) -> io::Result<NatInfo> {
-        let (nat_type, public_ips, port_range) = stun::stun_test_nat(self.stun_server.clone())?;
+        self.stun_pool.refresh();
+        let stun_server = self.stun_pool.select(STUN_TEST_NUM);
+        let (nat_type, public_ips, port_range) =
+            match stun::stun_test_nat(stun_server, self.stun_pool.clone()) {
+                Ok(rs) => {
+                    let mut state = self.state.lock();
+                    state.local_addr = Some((local_ipv4, ipv6));
+                    state.tested_time = Instant::now();
+                    state.fail_count = 0;
+                    state.next_time = state.tested_time;
+                    rs
+                }
+                Err(e) => {
+                    let mut state = self.state.lock();
+                    state.local_addr = None;
+                    state.fail_count += 1;
+                    let interval = state.retry_interval();
+                    state.next_time = Instant::now() + interval;
+                    log::warn!("nat探测失败,{:?}后重试", interval);
+                    return Err(e);
+                }
+            };
+        // 过滤行为不影响打洞以外的功能，失败时保留Unknown
+        let filtering = stun::stun_test_filtering(&self.stun_pool.select(STUN_TEST_NUM));
         let mut guard = self.info.lock();
+        if guard.nat_type != nat_type
+            || guard.filtering != filtering
+            || guard.public_port_range != port_range
+            || guard.public_ips.len() != public_ips.len()
+            || public_ips.iter().any(|ip| !guard.public_ips.contains(ip))
+        {
+            self.changed.store(true, Ordering::Release);
+        }
         guard.nat_type = nat_type;
+        guard.filtering = filtering;
         guard.public_ips = public_ips;
         guard.public_port_range = port_range;
         guard.local_ipv4 = local_ipv4;
@@ -202,3 +338,38 @@ impl NatTest {
         Ok(guard.clone())
     }
 }
+
+#[test]
+fn test_nat_retry_interval() {
+    let now = Instant::now();
+    let mut state = NatTestState {
+        local_addr: None,
+        tested_time: now,
+        fail_count: 1,
+        next_time: now,
+    };
+    assert_eq!(state.retry_interval(), RETRY_INTERVAL);
+    state.fail_count = 3;
+    assert_eq!(state.retry_interval(), RETRY_INTERVAL * 4);
+    state.fail_count = 100;
+    assert_eq!(state.retry_interval(), MAX_RETRY_INTERVAL);
+
+    let addr = (Some(Ipv4Addr::new(192, 168, 1, 2)), None);
+    assert!(state.need_test(now, addr));
+    state.local_addr = Some(addr);
+    // 本地地址不变时使用缓存，过期后重新探测
+    assert!(!state.need_test(now + Duration::from_secs(60), addr));
+    assert!(state.need_test(now + MAX_CACHE_AGE, addr));
+    assert!(state.need_test(now, (Some(Ipv4Addr::new(192, 168, 1, 3)), None)));
+    // 退避期间不探测
+    state.next_time = now + RETRY_INTERVAL;
+    assert!(!state.need_test(now, (None, None)));
+}
+
+#[test]
+fn test_is_cgnat() {
+    assert!(is_cgnat(&Ipv4Addr::new(100, 64, 0, 1)));
+    assert!(is_cgnat(&Ipv4Addr::new(100, 127, 255, 254)));
+    assert!(!is_cgnat(&Ipv4Addr::new(100, 128, 0, 1)));
+    assert!(!is_cgnat(&Ipv4Addr::new(192, 168, 1, 1)));
+}
@@ -1,21 +1,32 @@
 use std::collections::HashSet;
 use std::io;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
-use std::time::Duration;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::channel::punch::NatType;
+use crate::channel::punch::{NatFiltering, NatType};
+use crate::nat::stun_pool::StunPool;
 use std::net::UdpSocket;
 use stun_format::Attr;
-pub fn stun_test_nat(stun_servers: Vec<String>) -> io::Result<(NatType, Vec<Ipv4Addr>, u16)> {
+pub fn stun_test_nat(
+    stun_servers: Vec<String>,
+    stun_pool: Arc<StunPool>,
+) -> io::Result<(NatType, Vec<Ipv4Addr>, u16)> {
+    if stun_servers.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "no stun server"));
+    }
     let mut th = Vec::new();
     for _ in 0..2 {
         let stun_servers = stun_servers.clone();
-        let handle = std::thread::spawn(move || stun_test_nat0(stun_servers));
+        let stun_pool = stun_pool.clone();
+        let handle = std::thread::spawn(move || stun_test_nat0(stun_servers, &stun_pool));
         th.push(handle);
     }
     let mut nat_type = NatType::Cone;
     let mut port_range = 0;
     let mut hash_set = HashSet::new();
+    let mut last_err = None;
+    let mut ok = false;
     for x in th {
         match x.join().unwrap() {
             Ok((nat_type_t, ip_list_t, port_range_t)) => {
@@ -28,15 +39,25 @@ pub fn stun_test_nat(stun_servers: Vec<String>) -> io::Result<(NatType, Vec<Ipv4
                 if port_range < port_range_t {
                     port_range = port_range_t;
                 }
+                ok = true;
             }
             Err(e) => {
                 log::warn!("{:?}", e);
+                last_err = Some(e);
             }
         }
     }
+    if !ok {
+        if let Some(e) = last_err {
+            return Err(e);
+        }
+    }
     Ok((nat_type, hash_set.into_iter().collect(), port_range))
 }
-pub fn stun_test_nat0(stun_servers: Vec<String>) -> io::Result<(NatType, Vec<Ipv4Addr>, u16)> {
+pub fn stun_test_nat0(
+    stun_servers: Vec<String>,
+    stun_pool: &StunPool,
+) -> io::Result<(NatType, Vec<Ipv4Addr>, u16)> {
     let udp = UdpSocket::bind("0.0.0.0:0")?;
     udp.set_read_timeout(Some(Duration::from_millis(500)))?;
     let mut nat_type = NatType::Cone;
@@ -44,8 +65,10 @@ pub fn stun_test_nat0(stun_servers: Vec<String>) -> io::Result<(NatType, Vec<Ipv
     let mut hash_set = HashSet::new();
     let mut pub_addrs = HashSet::new();
     for x in &stun_servers {
+        let start = Instant::now();
         match test_nat(&udp, x) {
             Ok((addr, nat_type_t, ip_list_t, port_range_t)) => {
+                stun_pool.report(x, Some(start.elapsed()));
                 if nat_type_t == NatType::Symmetric {
                     nat_type = NatType::Symmetric;
                 }
@@ -58,16 +81,30 @@ pub fn stun_test_nat0(stun_servers: Vec<String>) -> io::Result<(NatType, Vec<Ipv
                 pub_addrs.insert(addr);
             }
             Err(e) => {
+                stun_pool.report(x, None);
                 log::warn!("stun {} error {:?} ", x, e);
             }
         }
     }
+    if pub_addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "all stun servers are unreachable",
+        ));
+    }
     if pub_addrs.len() > 1 {
         nat_type = NatType::Symmetric;
     }
     Ok((nat_type, hash_set.into_iter().collect(), port_range))
 }
 
+/// 单独探测一个stun服务器，返回映射地址
+pub fn stun_probe(stun_server: &String) -> io::Result<SocketAddr> {
+    let udp = UdpSocket::bind("0.0.0.0:0")?;
+    udp.set_read_timeout(Some(Duration::from_millis(500)))?;
+    test_nat(&udp, stun_server).map(|(addr, _, _, _)| addr)
+}
+
 fn test_nat(
     udp: &UdpSocket,
     stun_server: &String,
@@ -176,6 +213,95 @@ fn test_nat_(
     Err(io::Error::new(io::ErrorKind::Other, "stun response err"))
 }
 
+/// 按RFC 5780探测NAT的过滤行为，依次尝试stun服务器，直到有服务器支持CHANGE-REQUEST
+pub fn stun_test_filtering(stun_servers: &[String]) -> NatFiltering {
+    for stun_server in stun_servers {
+        match test_filtering(stun_server) {
+            Ok(NatFiltering::Unknown) => {}
+            Ok(filtering) => {
+                log::info!("stun {} filtering {:?}", stun_server, filtering);
+                return filtering;
+            }
+            Err(e) => {
+                log::warn!("stun {} filtering error {:?}", stun_server, e);
+            }
+        }
+    }
+    NatFiltering::Unknown
+}
+
+fn test_filtering(stun_server: &String) -> io::Result<NatFiltering> {
+    let server = match stun_server.to_socket_addrs()?.find(|addr| addr.is_ipv4()) {
+        Some(addr) => addr,
+        None => return Ok(NatFiltering::Unknown),
+    };
+    // 不能connect，否则收不到从其他地址发来的响应
+    let udp = UdpSocket::bind("0.0.0.0:0")?;
+    udp.set_read_timeout(Some(Duration::from_millis(500)))?;
+    let tid = rand::random::<u128>();
+    let changed = match filtering_request(&udp, server, false, false, tid)? {
+        Some((_, Some(changed))) => changed,
+        _ => return Ok(NatFiltering::Unknown),
+    };
+    if changed.ip() == server.ip() || changed.port() == server.port() {
+        // 服务器没有第二个ip和端口，不支持RFC 5780
+        return Ok(NatFiltering::Unknown);
+    }
+    // 从另一个ip和端口响应，能收到说明不过滤
+    if let Some((from, _)) = filtering_request(&udp, server, true, true, tid + 1)? {
+        if from.ip() != server.ip() {
+            return Ok(NatFiltering::EndpointIndependent);
+        }
+        // 服务器忽略了CHANGE-REQUEST
+        return Ok(NatFiltering::Unknown);
+    }
+    // 从同一个ip的另一个端口响应
+    match filtering_request(&udp, server, false, true, tid + 2)? {
+        Some((from, _)) if from.ip() == server.ip() && from.port() != server.port() => {
+            Ok(NatFiltering::AddressDependent)
+        }
+        Some(_) => Ok(NatFiltering::Unknown),
+        None => Ok(NatFiltering::AddressAndPortDependent),
+    }
+}
+
+/// 发送一次请求，返回响应的来源地址和服务器的另一个地址，超时返回None
+fn filtering_request(
+    udp: &UdpSocket,
+    server: SocketAddr,
+    change_ip: bool,
+    change_port: bool,
+    tid: u128,
+) -> io::Result<Option<(SocketAddr, Option<SocketAddr>)>> {
+    let mut buf = [0u8; 28];
+    let mut msg = stun_format::MsgBuilder::from(buf.as_mut_slice());
+    msg.typ(stun_format::MsgType::BindingRequest).unwrap();
+    msg.tid(tid).unwrap();
+    msg.add_attr(Attr::ChangeRequest {
+        change_ip,
+        change_port,
+    })
+    .unwrap();
+    udp.send_to(msg.as_bytes(), server)?;
+    let mut buf = [0; 1024];
+    let (len, from) = match udp.recv_from(&mut buf) {
+        Ok(rs) => rs,
+        Err(e) => {
+            if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut {
+                return Ok(None);
+            }
+            return Err(e);
+        }
+    };
+    // 之前请求的迟到响应会被当作服务器不支持，结果为Unknown，不会误判
+    let msg = stun_format::Msg::from(&buf[..len]);
+    let changed = msg.attrs_iter().find_map(|attr| match attr {
+        Attr::ChangedAddress(addr) => Some(stun_addr(addr)),
+        _ => None,
+    });
+    Ok(Some((from, changed)))
+}
+
 fn stun_addr(addr: stun_format::SocketAddr) -> SocketAddr {
     match addr {
         stun_format::SocketAddr::V4(ip, port) => {
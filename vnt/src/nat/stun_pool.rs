@@ -0,0 +1,277 @@
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// 连续失败多少次认为服务器不可用
+const DEAD_THRESHOLD: u32 = 3;
+/// 不可用后首次重新尝试的间隔
+const DEAD_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// 不可用的最长间隔
+const MAX_DEAD_INTERVAL: Duration = Duration::from_secs(6 * 3600);
+/// 重新获取服务器列表的间隔
+const FETCH_INTERVAL: Duration = Duration::from_secs(3600);
+/// 列表中服务器数量的上限
+const MAX_SERVERS: usize = 32;
+/// 获取列表时响应的最大长度
+const MAX_BODY: usize = 64 * 1024;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum StunSource {
+    // 启动参数指定
+    Config,
+    // 从url获取
+    Url,
+    // 服务端下发
+    Server,
+}
+
+#[derive(Clone, Debug)]
+pub struct StunServer {
+    pub addr: String,
+    pub source: StunSource,
+    pub success: u32,
+    pub fail: u32,
+    // 连续失败次数
+    pub consecutive_fail: u32,
+    // 响应时间的平滑值
+    pub rtt: Option<Duration>,
+    // 不可用时，到这个时间之前不再使用
+    pub dead_until: Option<Instant>,
+}
+
+impl StunServer {
+    fn new(addr: String, source: StunSource) -> Self {
+        Self {
+            addr,
+            source,
+            success: 0,
+            fail: 0,
+            consecutive_fail: 0,
+            rtt: None,
+            dead_until: None,
+        }
+    }
+    /// 成功率和响应时间的综合评分，越大越好，没有数据的服务器按中等水平对待
+    pub fn score(&self) -> f64 {
+        let rate = (self.success as f64 + 1.0) / ((self.success + self.fail) as f64 + 2.0);
+        let rtt = self.rtt.map(|v| v.as_millis() as f64).unwrap_or(200.0);
+        rate * 1000.0 / (rtt + 50.0)
+    }
+    fn is_dead(&self, now: Instant) -> bool {
+        matches!(self.dead_until, Some(until) if now < until)
+    }
+}
+
+/// stun服务器列表。
+/// 列表来自启动参数、url和服务端握手时下发，按成功率和响应时间评分，
+/// 探测时优先使用评分高的服务器，连续失败的服务器在一段时间内不再使用
+pub struct StunPool {
+    urls: Vec<String>,
+    servers: Mutex<Vec<StunServer>>,
+    next_fetch: Mutex<Instant>,
+}
+
+impl StunPool {
+    /// 以http://或https://开头的视为服务器列表的url，其他为stun服务器地址
+    pub fn new(stun_server: Vec<String>) -> Self {
+        let mut urls = Vec::new();
+        let mut servers = Vec::new();
+        for x in stun_server {
+            if x.starts_with("http://") || x.starts_with("https://") {
+                urls.push(x);
+            } else if !servers.iter().any(|v: &StunServer| v.addr == x) {
+                servers.push(StunServer::new(x, StunSource::Config));
+            }
+        }
+        Self {
+            urls,
+            servers: Mutex::new(servers),
+            next_fetch: Mutex::new(Instant::now()),
+        }
+    }
+    /// 加入新的服务器，已存在的不重复添加
+    pub fn merge(&self, list: Vec<String>, source: StunSource) {
+        let mut servers = self.servers.lock();
+        for addr in list {
+            let addr = match normalize(&addr) {
+                Some(addr) => addr,
+                None => continue,
+            };
+            if servers.len() >= MAX_SERVERS {
+                break;
+            }
+            if !servers.iter().any(|v| v.addr == addr) {
+                log::info!("新增stun服务器 {} {:?}", addr, source);
+                servers.push(StunServer::new(addr, source));
+            }
+        }
+    }
+    /// 到期时从url重新获取服务器列表
+    pub fn refresh(&self) {
+        if self.urls.is_empty() {
+            return;
+        }
+        {
+            let mut next_fetch = self.next_fetch.lock();
+            let now = Instant::now();
+            if now < *next_fetch {
+                return;
+            }
+            *next_fetch = now + FETCH_INTERVAL;
+        }
+        for url in &self.urls {
+            match fetch(url) {
+                Ok(list) => self.merge(list, StunSource::Url),
+                Err(e) => log::warn!("获取stun服务器列表失败 {} {:?}", url, e),
+            }
+        }
+    }
+    /// 选出num个服务器，不足时重复使用，都不可用时仍然返回评分最高的
+    pub fn select(&self, num: usize) -> Vec<String> {
+        let now = Instant::now();
+        let servers = self.servers.lock();
+        let mut alive: Vec<&StunServer> = servers.iter().filter(|v| !v.is_dead(now)).collect();
+        if alive.is_empty() {
+            alive = servers.iter().collect();
+        }
+        alive.sort_by(|a, b| b.score().total_cmp(&a.score()));
+        alive
+            .iter()
+            .cycle()
+            .take(num)
+            .map(|v| v.addr.clone())
+            .collect()
+    }
+    /// 记录一次探测结果，rtt为None表示失败
+    pub fn report(&self, addr: &str, rtt: Option<Duration>) {
+        let mut servers = self.servers.lock();
+        let server = match servers.iter_mut().find(|v| v.addr == addr) {
+            Some(server) => server,
+            None => return,
+        };
+        match rtt {
+            Some(rtt) => {
+                server.success = server.success.saturating_add(1);
+                server.consecutive_fail = 0;
+                server.dead_until = None;
+                server.rtt = Some(match server.rtt {
+                    Some(v) => (v * 7 + rtt) / 8,
+                    None => rtt,
+                });
+            }
+            None => {
+                server.fail = server.fail.saturating_add(1);
+                server.consecutive_fail = server.consecutive_fail.saturating_add(1);
+                if server.consecutive_fail >= DEAD_THRESHOLD {
+                    let multiple = 1u32 << (server.consecutive_fail - DEAD_THRESHOLD).min(6);
+                    let interval = (DEAD_INTERVAL * multiple).min(MAX_DEAD_INTERVAL);
+                    log::warn!("stun服务器 {} 不可用,{:?}后重试", server.addr, interval);
+                    server.dead_until = Some(Instant::now() + interval);
+                }
+            }
+        }
+    }
+    pub fn servers(&self) -> Vec<StunServer> {
+        self.servers.lock().clone()
+    }
+}
+
+/// 没有端口时使用3478
+fn normalize(addr: &str) -> Option<String> {
+    let addr = addr.trim();
+    if addr.is_empty() || addr.starts_with('#') {
+        return None;
+    }
+    if addr.contains(':') {
+        Some(addr.to_string())
+    } else {
+        Some(format!("{}:3478", addr))
+    }
+}
+
+/// 获取服务器列表，响应内容每行一个地址，#开头的为注释
+fn fetch(url: &str) -> io::Result<Vec<String>> {
+    let rest = match url.strip_prefix("http://") {
+        Some(rest) => rest,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "only http:// is supported",
+            ))
+        }
+    };
+    let (host, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+    let addr = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+    let addr = match addr.to_socket_addrs()?.next() {
+        Some(addr) => addr,
+        None => return Err(io::Error::new(io::ErrorKind::NotFound, host.to_string())),
+    };
+    let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs(5))?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    // 使用http/1.0，避免分块传输
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: vnt\r\nConnection: close\r\n\r\n",
+        path, host
+    )?;
+    let mut response = Vec::new();
+    stream
+        .take(MAX_BODY as u64 + 4096)
+        .read_to_end(&mut response)?;
+    parse_response(&response)
+}
+
+fn parse_response(response: &[u8]) -> io::Result<Vec<String>> {
+    let response = String::from_utf8_lossy(response);
+    let (head, body) = match response.split_once("\r\n\r\n") {
+        Some(v) => v,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData, "bad response")),
+    };
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("http status: {}", status),
+        ));
+    }
+    Ok(body.lines().filter_map(normalize).collect())
+}
+
+#[test]
+fn test_stun_pool() {
+    let pool = StunPool::new(vec![
+        "stun1.local:3478".to_string(),
+        "stun2.local:3478".to_string(),
+        "http://example.local/stun.txt".to_string(),
+    ]);
+    assert_eq!(pool.urls.len(), 1);
+    pool.merge(
+        vec!["stun2.local:3478".into(), "stun3.local".into(), "#x".into()],
+        StunSource::Server,
+    );
+    assert_eq!(pool.servers().len(), 3);
+    pool.report("stun2.local:3478", Some(Duration::from_millis(20)));
+    assert_eq!(pool.select(1), vec!["stun2.local:3478".to_string()]);
+    for _ in 0..DEAD_THRESHOLD {
+        pool.report("stun1.local:3478", None);
+    }
+    let list = pool.select(3);
+    assert_eq!(list.len(), 3);
+    assert!(!list.contains(&"stun1.local:3478".to_string()));
+
+    let list = parse_response(b"HTTP/1.1 200 OK\r\nX: y\r\n\r\nstun.a.com\n# c\nstun.b.com:3479\n")
+        .unwrap();
+    assert_eq!(list, vec!["stun.a.com:3478", "stun.b.com:3479"]);
+    assert!(parse_response(b"HTTP/1.1 404 Not Found\r\n\r\n").is_err());
+}
@@ -0,0 +1,157 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::net::Ipv4Addr;
+use std::ops::BitOr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use parking_lot::RwLock;
+
+/// 能力位图，握手和打洞时交换，使用新的包类型前先检查对端是否支持，
+/// 低8位和心跳的flags一致，旧版本只能通过心跳得知这部分能力
+#[derive(Copy, Clone, Default, Eq, PartialEq, Debug)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    /// 支持小包合并
+    pub const COALESCE: Capabilities = Capabilities(1);
+    /// 心跳末尾带32位毫秒时间戳
+    pub const TIME32: Capabilities = Capabilities(1 << 1);
+    /// 支持路径mtu探测和分片重组
+    pub const FRAGMENT: Capabilities = Capabilities(1 << 2);
+    /// 能接收填充的ip包和掩护包
+    pub const PADDING: Capabilities = Capabilities(1 << 3);
+    /// 数据压缩，预留
+    pub const COMPRESSION: Capabilities = Capabilities(1 << 8);
+    /// 虚拟网络内的ipv6，预留
+    pub const IPV6_OVERLAY: Capabilities = Capabilities(1 << 9);
+    /// 能接收服务端下发的配置，只在握手时告知服务端
+    pub const SERVER_CONFIG: Capabilities = Capabilities(1 << 10);
+    /// 加密握手绑定第一次握手的转录摘要，只在握手时交换
+    pub const TRANSCRIPT: Capabilities = Capabilities(1 << 11);
+    /// 能接收末尾带连接id扩展的包，中转时能按连接id转发
+    pub const CONN_ID: Capabilities = Capabilities(1 << 12);
+    /// 能接收服务端推送的设备列表增量和对端直连发来的下线通知
+    pub const PRESENCE: Capabilities = Capabilities(1 << 13);
+    /// 当前版本总是支持的能力
+    pub const BASE: Capabilities = Capabilities(
+        Self::TIME32.0 | Self::FRAGMENT.0 | Self::PADDING.0 | Self::CONN_ID.0 | Self::PRESENCE.0,
+    );
+    const PING_MASK: u32 = 0xFF;
+
+    pub fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+    /// 本端的能力，合并小包需要开启后才告知对端
+    pub fn local(coalesce: bool) -> Self {
+        if coalesce {
+            Self::BASE | Self::COALESCE
+        } else {
+            Self::BASE
+        }
+    }
+    pub fn from_ping_flags(flags: u8) -> Self {
+        Self(flags as u32)
+    }
+    /// 心跳中的flags
+    pub fn ping_flags(&self) -> u8 {
+        (self.0 & Self::PING_MASK) as u8
+    }
+    pub fn contains(&self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Capabilities(self.0 | rhs.0)
+    }
+}
+
+impl Display for Capabilities {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let names = [
+            (Self::COALESCE, "coalesce"),
+            (Self::TIME32, "time32"),
+            (Self::FRAGMENT, "fragment"),
+            (Self::PADDING, "padding"),
+            (Self::COMPRESSION, "compression"),
+            (Self::IPV6_OVERLAY, "ipv6"),
+            (Self::SERVER_CONFIG, "server_config"),
+            (Self::TRANSCRIPT, "transcript"),
+            (Self::CONN_ID, "conn_id"),
+            (Self::PRESENCE, "presence"),
+        ];
+        let list: Vec<&str> = names
+            .iter()
+            .filter(|(v, _)| self.contains(*v))
+            .map(|(_, name)| *name)
+            .collect();
+        write!(f, "{}", list.join(","))
+    }
+}
+
+/// 服务端和各个对端的能力
+#[derive(Default)]
+pub struct PeerCapabilities {
+    server: AtomicU32,
+    peers: RwLock<HashMap<Ipv4Addr, Capabilities>>,
+}
+
+impl PeerCapabilities {
+    pub fn set_server(&self, capabilities: Capabilities) {
+        self.server.store(capabilities.bits(), Ordering::Relaxed);
+    }
+    pub fn server(&self) -> Capabilities {
+        Capabilities(self.server.load(Ordering::Relaxed))
+    }
+    /// 打洞信息中带的完整能力
+    pub fn set_peer(&self, ip: Ipv4Addr, capabilities: Capabilities) {
+        self.peers.write().insert(ip, capabilities);
+    }
+    /// 心跳中的flags只更新低8位
+    pub fn set_peer_ping_flags(&self, ip: Ipv4Addr, flags: u8) {
+        let mut peers = self.peers.write();
+        let capabilities = peers.entry(ip).or_default();
+        capabilities.0 = (capabilities.0 & !Capabilities::PING_MASK) | flags as u32;
+    }
+    /// 未知的对端视为没有任何能力
+    pub fn peer(&self, ip: &Ipv4Addr) -> Capabilities {
+        self.peers.read().get(ip).copied().unwrap_or_default()
+    }
+    pub fn supports(&self, ip: &Ipv4Addr, capability: Capabilities) -> bool {
+        self.peer(ip).contains(capability)
+    }
+    /// 去掉已经不在设备列表中的对端
+    pub fn retain(&self, ips: &HashSet<Ipv4Addr>) {
+        self.peers.write().retain(|ip, _| ips.contains(ip));
+    }
+}
+
+#[test]
+fn test_capabilities() {
+    let local = Capabilities::local(true);
+    assert!(local.contains(Capabilities::COALESCE | Capabilities::FRAGMENT));
+    assert!(!local.contains(Capabilities::COMPRESSION));
+    assert_eq!(local.ping_flags(), 0b0000_1111);
+    assert_eq!(
+        local.to_string(),
+        "coalesce,time32,fragment,padding,conn_id,presence"
+    );
+
+    let peers = PeerCapabilities::default();
+    let ip = Ipv4Addr::new(10, 26, 0, 2);
+    assert!(!peers.supports(&ip, Capabilities::TIME32));
+    peers.set_peer(ip, Capabilities::BASE | Capabilities::COMPRESSION);
+    // 旧版本的心跳不会清除高位的能力
+    peers.set_peer_ping_flags(ip, Capabilities::COALESCE.ping_flags());
+    assert!(peers.supports(&ip, Capabilities::COMPRESSION | Capabilities::COALESCE));
+    assert!(!peers.supports(&ip, Capabilities::FRAGMENT));
+    peers.retain(&HashSet::new());
+    assert_eq!(peers.peer(&ip), Capabilities::NONE);
+}
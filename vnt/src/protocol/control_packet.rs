@@ -1,6 +1,8 @@
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, SocketAddrV4};
 use std::{fmt, io};
 
+use crate::cipher::identity::SIGNATURE_LEN;
+
 #[derive(Eq, PartialEq, Copy, Clone, Debug)]
 pub enum Protocol {
     /// ping请求
@@ -10,6 +12,10 @@ pub enum Protocol {
         +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
         |              time                          |                    echo                        |
         +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |        flags         |
+        +-+-+-+-+-+-+-+-+-+-+-+
+        客户端之间的心跳可选带上flags，表示支持的能力
+        开启设备身份校验时flags后面跟64字节的ed25519签名
     */
     Ping,
     /*
@@ -27,6 +33,74 @@ pub enum Protocol {
     ///获取对端看到的地址
     AddrRequest,
     AddrResponse,
+    /// 中转拥塞，通知发送方对指定目标限速
+    /*
+         0                                            15                                              31
+         0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |                                      destination                                             |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |              hold(ms)                      |                 rate(KB/s)                     |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        rate为0表示由发送方自行降速
+    */
+    Congestion,
+    /// 路径mtu探测
+    /*
+         0                                            15                                              31
+         0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |              size                          |         reply          |       padding...       |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        探测包填充到size对应的长度，回应不填充，只带回size
+    */
+    MtuProbe,
+    /// 虚拟ip通告，定时发给对端和自己的ip，用于发现ip冲突
+    /*
+         0                                            15                                              31
+         0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |                                      instance id(64)                                         |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        启动时生成的随机实例id
+    */
+    IpAnnounce,
+    /// 对端发现同一个ip的两个实例，通知双方，instance id为对方的实例id，格式同IpAnnounce
+    IpConflict,
+    /// 收到了直连的包但发不过去，经服务端通知对端探测单向通道，没有载荷
+    PathNotice,
+    /// 单向通道探测，直连发到对端的候选地址
+    /*
+         0                                            15                                              31
+         0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |                                         nonce(32)                                            |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |                                      destination ip                                          |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |           destination port                 |         index          |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        index为发送探测的本地udp socket
+    */
+    PathProbe,
+    /// 收到探测后经服务端原样带回，格式同PathProbe
+    PathConfirm,
+    /// 服务端地址通告，直连发给对端
+    /*
+         0                                            15                                              31
+         0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |       family         |                      ip(4/16)...                                      |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |             port                           |                 time(64)...                     |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        |                                      tag(128)...                                             |
+        +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+        family为4或6，time为秒级时间戳，tag为token和密码派生的密钥对来源ip和前面字段的HMAC
+    */
+    ServerHint,
+    /// 正常退出前直连通知对端自己下线，没有载荷
+    PeerOffline,
     Unknown(u8),
 }
 
@@ -39,6 +113,15 @@ impl From<u8> for Protocol {
             4 => Protocol::PunchResponse,
             5 => Protocol::AddrRequest,
             6 => Protocol::AddrResponse,
+            7 => Protocol::Congestion,
+            8 => Protocol::MtuProbe,
+            9 => Protocol::IpAnnounce,
+            10 => Protocol::IpConflict,
+            11 => Protocol::PathNotice,
+            12 => Protocol::PathProbe,
+            13 => Protocol::PathConfirm,
+            14 => Protocol::ServerHint,
+            15 => Protocol::PeerOffline,
             val => Protocol::Unknown(val),
         }
     }
@@ -53,6 +136,15 @@ impl Into<u8> for Protocol {
             Protocol::PunchResponse => 4,
             Protocol::AddrRequest => 5,
             Protocol::AddrResponse => 6,
+            Protocol::Congestion => 7,
+            Protocol::MtuProbe => 8,
+            Protocol::IpAnnounce => 9,
+            Protocol::IpConflict => 10,
+            Protocol::PathNotice => 11,
+            Protocol::PathProbe => 12,
+            Protocol::PathConfirm => 13,
+            Protocol::ServerHint => 14,
+            Protocol::PeerOffline => 15,
             Protocol::Unknown(val) => val,
         }
     }
@@ -65,6 +157,15 @@ pub enum ControlPacket<B> {
     PunchResponse,
     AddrRequest,
     AddrResponse(AddrPacket<B>),
+    Congestion(CongestionPacket<B>),
+    MtuProbe(MtuProbePacket<B>),
+    IpAnnounce(InstancePacket<B>),
+    IpConflict(InstancePacket<B>),
+    PathNotice,
+    PathProbe(PathProbePacket<B>),
+    PathConfirm(PathProbePacket<B>),
+    ServerHint(B),
+    PeerOffline,
 }
 
 impl<B: AsRef<[u8]>> ControlPacket<B> {
@@ -76,6 +177,15 @@ impl<B: AsRef<[u8]>> ControlPacket<B> {
             Protocol::PunchResponse => Ok(ControlPacket::PunchResponse),
             Protocol::AddrRequest => Ok(ControlPacket::AddrRequest),
             Protocol::AddrResponse => Ok(ControlPacket::AddrResponse(AddrPacket::new(buffer)?)),
+            Protocol::Congestion => Ok(ControlPacket::Congestion(CongestionPacket::new(buffer)?)),
+            Protocol::MtuProbe => Ok(ControlPacket::MtuProbe(MtuProbePacket::new(buffer)?)),
+            Protocol::IpAnnounce => Ok(ControlPacket::IpAnnounce(InstancePacket::new(buffer)?)),
+            Protocol::IpConflict => Ok(ControlPacket::IpConflict(InstancePacket::new(buffer)?)),
+            Protocol::PathNotice => Ok(ControlPacket::PathNotice),
+            Protocol::PathProbe => Ok(ControlPacket::PathProbe(PathProbePacket::new(buffer)?)),
+            Protocol::PathConfirm => Ok(ControlPacket::PathConfirm(PathProbePacket::new(buffer)?)),
+            Protocol::ServerHint => Ok(ControlPacket::ServerHint(buffer)),
+            Protocol::PeerOffline => Ok(ControlPacket::PeerOffline),
             Protocol::Unknown(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "Unsupported")),
         }
     }
@@ -88,6 +198,19 @@ pub struct PingPacket<B> {
 
 pub type PongPacket<B> = PingPacket<B>;
 
+/// 支持小包合并
+pub const PING_FLAG_COALESCE: u8 = 0b0000_0001;
+/// 末尾带32位毫秒时间戳，回应时原样带回
+pub const PING_FLAG_TIME32: u8 = 0b0000_0010;
+/// 支持路径mtu探测和分片重组
+pub const PING_FLAG_FRAGMENT: u8 = 0b0000_0100;
+/// 带flags的心跳长度
+pub const PING_LEN: usize = 5;
+/// 带签名的心跳长度
+pub const PING_SIGNED_LEN: usize = PING_LEN + SIGNATURE_LEN;
+/// 32位时间戳长度，放在最后，旧版本回应时会截掉
+pub const PING_TIME32_LEN: usize = 4;
+
 impl<B: AsRef<[u8]>> PingPacket<B> {
     pub fn new(buffer: B) -> io::Result<PingPacket<B>> {
         let len = buffer.as_ref().len();
@@ -105,6 +228,33 @@ impl<B: AsRef<[u8]>> PingPacket<B> {
     pub fn epoch(&self) -> u16 {
         u16::from_be_bytes(self.buffer.as_ref()[2..4].try_into().unwrap())
     }
+    /// 旧版本没有这个字段，返回0
+    pub fn flags(&self) -> u8 {
+        self.buffer.as_ref().get(4).copied().unwrap_or(0)
+    }
+    /// 签名覆盖的部分
+    pub fn signed_data(&self) -> &[u8] {
+        let buf = self.buffer.as_ref();
+        &buf[..buf.len().min(PING_LEN)]
+    }
+    pub fn signature(&self) -> Option<&[u8]> {
+        self.buffer.as_ref().get(PING_LEN..PING_SIGNED_LEN)
+    }
+    /// 32位时间戳，旧版本没有这个字段
+    pub fn time32(&self) -> Option<u32> {
+        let buf = self.buffer.as_ref();
+        if self.flags() & PING_FLAG_TIME32 == 0 {
+            return None;
+        }
+        // 只可能跟在flags或签名后面
+        if buf.len() != PING_LEN + PING_TIME32_LEN && buf.len() != PING_SIGNED_LEN + PING_TIME32_LEN
+        {
+            return None;
+        }
+        Some(u32::from_be_bytes(
+            buf[buf.len() - PING_TIME32_LEN..].try_into().unwrap(),
+        ))
+    }
 }
 
 impl<B: AsRef<[u8]> + AsMut<[u8]>> PingPacket<B> {
@@ -114,6 +264,24 @@ impl<B: AsRef<[u8]> + AsMut<[u8]>> PingPacket<B> {
     pub fn set_epoch(&mut self, epoch: u16) {
         self.buffer.as_mut()[2..4].copy_from_slice(&epoch.to_be_bytes())
     }
+    pub fn set_flags(&mut self, flags: u8) {
+        if let Some(v) = self.buffer.as_mut().get_mut(4) {
+            *v = flags;
+        }
+    }
+    pub fn set_signature(&mut self, signature: &[u8; SIGNATURE_LEN]) {
+        if let Some(v) = self.buffer.as_mut().get_mut(PING_LEN..PING_SIGNED_LEN) {
+            v.copy_from_slice(signature);
+        }
+    }
+    /// 写在最后4字节，需要先设置好长度
+    pub fn set_time32(&mut self, time: u32) {
+        let buf = self.buffer.as_mut();
+        let len = buf.len();
+        if len >= PING_LEN + PING_TIME32_LEN {
+            buf[len - PING_TIME32_LEN..].copy_from_slice(&time.to_be_bytes());
+        }
+    }
 }
 
 impl<B: AsRef<[u8]>> fmt::Debug for PingPacket<B> {
@@ -121,6 +289,8 @@ impl<B: AsRef<[u8]>> fmt::Debug for PingPacket<B> {
         f.debug_struct("PingPacket")
             .field("time", &self.time())
             .field("epoch", &self.epoch())
+            .field("flags", &self.flags())
+            .field("time32", &self.time32())
             .finish()
     }
 }
@@ -163,3 +333,168 @@ impl<B: AsRef<[u8]>> fmt::Debug for AddrPacket<B> {
             .finish()
     }
 }
+
+pub struct CongestionPacket<B> {
+    buffer: B,
+}
+
+pub const CONGESTION_LEN: usize = 8;
+
+impl<B: AsRef<[u8]>> CongestionPacket<B> {
+    pub fn new(buffer: B) -> io::Result<CongestionPacket<B>> {
+        let len = buffer.as_ref().len();
+        if len < CONGESTION_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "len < 8"));
+        }
+        Ok(CongestionPacket { buffer })
+    }
+    pub fn destination(&self) -> Ipv4Addr {
+        let buf = self.buffer.as_ref();
+        Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3])
+    }
+    pub fn hold(&self) -> u16 {
+        u16::from_be_bytes(self.buffer.as_ref()[4..6].try_into().unwrap())
+    }
+    pub fn rate(&self) -> u16 {
+        u16::from_be_bytes(self.buffer.as_ref()[6..8].try_into().unwrap())
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> CongestionPacket<B> {
+    pub fn set_destination(&mut self, ip: Ipv4Addr) {
+        self.buffer.as_mut()[..4].copy_from_slice(&ip.octets())
+    }
+    pub fn set_hold(&mut self, hold: u16) {
+        self.buffer.as_mut()[4..6].copy_from_slice(&hold.to_be_bytes())
+    }
+    pub fn set_rate(&mut self, rate: u16) {
+        self.buffer.as_mut()[6..8].copy_from_slice(&rate.to_be_bytes())
+    }
+}
+
+impl<B: AsRef<[u8]>> fmt::Debug for CongestionPacket<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CongestionPacket")
+            .field("destination", &self.destination())
+            .field("hold", &self.hold())
+            .field("rate", &self.rate())
+            .finish()
+    }
+}
+
+pub struct MtuProbePacket<B> {
+    buffer: B,
+}
+
+pub const MTU_PROBE_LEN: usize = 3;
+
+impl<B: AsRef<[u8]>> MtuProbePacket<B> {
+    pub fn new(buffer: B) -> io::Result<MtuProbePacket<B>> {
+        let len = buffer.as_ref().len();
+        if len < MTU_PROBE_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "len < 3"));
+        }
+        Ok(MtuProbePacket { buffer })
+    }
+    pub fn size(&self) -> u16 {
+        u16::from_be_bytes(self.buffer.as_ref()[..2].try_into().unwrap())
+    }
+    pub fn is_reply(&self) -> bool {
+        self.buffer.as_ref()[2] != 0
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> MtuProbePacket<B> {
+    pub fn set_size(&mut self, size: u16) {
+        self.buffer.as_mut()[..2].copy_from_slice(&size.to_be_bytes())
+    }
+    pub fn set_reply(&mut self, reply: bool) {
+        self.buffer.as_mut()[2] = reply as u8
+    }
+}
+
+/// ip通告和冲突通知
+pub struct InstancePacket<B> {
+    buffer: B,
+}
+
+pub const INSTANCE_LEN: usize = 8;
+
+impl<B: AsRef<[u8]>> InstancePacket<B> {
+    pub fn new(buffer: B) -> io::Result<InstancePacket<B>> {
+        let len = buffer.as_ref().len();
+        if len < INSTANCE_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "len < 8"));
+        }
+        Ok(InstancePacket { buffer })
+    }
+    pub fn instance_id(&self) -> u64 {
+        u64::from_be_bytes(self.buffer.as_ref()[..8].try_into().unwrap())
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> InstancePacket<B> {
+    pub fn set_instance_id(&mut self, instance_id: u64) {
+        self.buffer.as_mut()[..8].copy_from_slice(&instance_id.to_be_bytes())
+    }
+}
+
+/// 单向通道的探测和确认
+pub struct PathProbePacket<B> {
+    buffer: B,
+}
+
+pub const PATH_PROBE_LEN: usize = 11;
+
+impl<B: AsRef<[u8]>> PathProbePacket<B> {
+    pub fn new(buffer: B) -> io::Result<PathProbePacket<B>> {
+        let len = buffer.as_ref().len();
+        if len < PATH_PROBE_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "len < 11"));
+        }
+        Ok(PathProbePacket { buffer })
+    }
+    pub fn nonce(&self) -> u32 {
+        u32::from_be_bytes(self.buffer.as_ref()[..4].try_into().unwrap())
+    }
+    pub fn destination(&self) -> SocketAddrV4 {
+        let buf = self.buffer.as_ref();
+        SocketAddrV4::new(
+            Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]),
+            u16::from_be_bytes([buf[8], buf[9]]),
+        )
+    }
+    pub fn index(&self) -> u8 {
+        self.buffer.as_ref()[10]
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> PathProbePacket<B> {
+    pub fn set_nonce(&mut self, nonce: u32) {
+        self.buffer.as_mut()[..4].copy_from_slice(&nonce.to_be_bytes())
+    }
+    pub fn set_destination(&mut self, destination: SocketAddrV4) {
+        let buf = self.buffer.as_mut();
+        buf[4..8].copy_from_slice(&destination.ip().octets());
+        buf[8..10].copy_from_slice(&destination.port().to_be_bytes());
+    }
+    pub fn set_index(&mut self, index: u8) {
+        self.buffer.as_mut()[10] = index
+    }
+}
+
+#[test]
+fn test_ping_time32() {
+    let mut buf = [0u8; PING_SIGNED_LEN + PING_TIME32_LEN];
+    let mut ping = PingPacket::new(&mut buf[..PING_LEN]).unwrap();
+    ping.set_flags(PING_FLAG_TIME32);
+    assert_eq!(ping.time32(), None);
+    let mut ping = PingPacket::new(&mut buf[..]).unwrap();
+    ping.set_signature(&[1; SIGNATURE_LEN]);
+    ping.set_time32(u32::MAX - 1);
+    assert_eq!(ping.time32(), Some(u32::MAX - 1));
+    assert_eq!(ping.signature(), Some(&[1; SIGNATURE_LEN][..]));
+    // 旧版本回应时截掉时间戳
+    let pong = PongPacket::new(&buf[..PING_SIGNED_LEN]).unwrap();
+    assert_eq!(pong.time32(), None);
+}
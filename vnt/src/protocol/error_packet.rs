@@ -8,6 +8,10 @@ pub enum Protocol {
     IpAlreadyExists,
     InvalidIp,
     NoKey,
+    // 客户端版本低于服务端要求的最低版本，负载为要求的版本
+    VersionTooOld,
+    // 设备名称已被同一网络中的其他设备使用
+    NameDuplicated,
     Other(u8),
 }
 
@@ -20,6 +24,8 @@ impl From<u8> for Protocol {
             4 => Self::IpAlreadyExists,
             5 => Self::InvalidIp,
             6 => Self::NoKey,
+            7 => Self::VersionTooOld,
+            8 => Self::NameDuplicated,
             val => Self::Other(val),
         }
     }
@@ -34,6 +40,8 @@ impl Into<u8> for Protocol {
             Protocol::IpAlreadyExists => 4,
             Protocol::InvalidIp => 5,
             Protocol::NoKey => 6,
+            Protocol::VersionTooOld => 7,
+            Protocol::NameDuplicated => 8,
             Protocol::Other(val) => val,
         }
     }
@@ -46,6 +54,8 @@ pub enum InErrorPacket<B> {
     IpAlreadyExists,
     InvalidIp,
     NoKey,
+    VersionTooOld(ErrorPacket<B>),
+    NameDuplicated,
     OtherError(ErrorPacket<B>),
 }
 
@@ -58,6 +68,8 @@ impl<B: AsRef<[u8]>> InErrorPacket<B> {
             Protocol::IpAlreadyExists => Ok(InErrorPacket::IpAlreadyExists),
             Protocol::InvalidIp => Ok(InErrorPacket::InvalidIp),
             Protocol::NoKey => Ok(InErrorPacket::NoKey),
+            Protocol::VersionTooOld => Ok(InErrorPacket::VersionTooOld(ErrorPacket::new(buffer)?)),
+            Protocol::NameDuplicated => Ok(InErrorPacket::NameDuplicated),
             Protocol::Other(_) => Ok(InErrorPacket::OtherError(ErrorPacket::new(buffer)?)),
         }
     }
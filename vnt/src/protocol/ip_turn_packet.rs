@@ -5,6 +5,12 @@ use std::net::Ipv4Addr;
 pub enum Protocol {
     Ipv4,
     Ipv4Broadcast,
+    // 多个小ip包合并
+    Ipv4Batch,
+    // 超过对端路径mtu的ip包分片发送
+    Ipv4Fragment,
+    // 填充到固定长度的ip包，或者不含ip包的掩护包
+    Ipv4Padded,
     Unknown(u8),
 }
 
@@ -13,6 +19,9 @@ impl From<u8> for Protocol {
         match value {
             4 => Protocol::Ipv4,
             201 => Protocol::Ipv4Broadcast,
+            202 => Protocol::Ipv4Batch,
+            203 => Protocol::Ipv4Fragment,
+            204 => Protocol::Ipv4Padded,
             val => Protocol::Unknown(val),
         }
     }
@@ -23,6 +32,9 @@ impl Into<u8> for Protocol {
         match self {
             Protocol::Ipv4 => 4,
             Protocol::Ipv4Broadcast => 201,
+            Protocol::Ipv4Batch => 202,
+            Protocol::Ipv4Fragment => 203,
+            Protocol::Ipv4Padded => 204,
             Protocol::Unknown(val) => val,
         }
     }
@@ -39,7 +51,7 @@ impl<B: AsRef<[u8]>> BroadcastPacket<B> {
     pub fn new(buffer: B) -> io::Result<Self> {
         let len = buffer.as_ref().len();
         let packet = Self::unchecked(buffer);
-        if len < 2 + 4 || packet.addr_num() == 0 {
+        if len < 2 + 4 || packet.addr_num() == 0 || len < 1 + packet.addr_num() as usize * 4 {
             Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "BroadcastPacket InvalidData",
@@ -113,3 +125,66 @@ impl<B: AsRef<[u8]> + AsMut<[u8]>> BroadcastPacket<B> {
         Ok(())
     }
 }
+
+/*
+     0                                            15                                              31
+     0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1
+    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+    |                    id                      |         index          |         count          |
+    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+    |                                          data                                                |
+    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+*/
+/// ip包的分片，同一个ip包的分片使用相同的id
+pub struct FragmentPacket<B> {
+    buffer: B,
+}
+
+pub const FRAGMENT_HEAD_LEN: usize = 4;
+
+impl<B: AsRef<[u8]>> FragmentPacket<B> {
+    pub fn new(buffer: B) -> io::Result<Self> {
+        let len = buffer.as_ref().len();
+        let packet = Self { buffer };
+        if len <= FRAGMENT_HEAD_LEN || packet.index() >= packet.count() {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "FragmentPacket InvalidData",
+            ))
+        } else {
+            Ok(packet)
+        }
+    }
+    pub fn id(&self) -> u16 {
+        u16::from_be_bytes(self.buffer.as_ref()[..2].try_into().unwrap())
+    }
+    pub fn index(&self) -> u8 {
+        self.buffer.as_ref()[2]
+    }
+    pub fn count(&self) -> u8 {
+        self.buffer.as_ref()[3]
+    }
+    pub fn data(&self) -> &[u8] {
+        &self.buffer.as_ref()[FRAGMENT_HEAD_LEN..]
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> FragmentPacket<B> {
+    pub fn unchecked(buffer: B) -> Self {
+        Self { buffer }
+    }
+    pub fn set_head(&mut self, id: u16, index: u8, count: u8) {
+        let buf = self.buffer.as_mut();
+        buf[..2].copy_from_slice(&id.to_be_bytes());
+        buf[2] = index;
+        buf[3] = count;
+    }
+    pub fn set_data(&mut self, data: &[u8]) -> io::Result<()> {
+        let buf = self.buffer.as_mut();
+        if buf.len() != FRAGMENT_HEAD_LEN + data.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "data len"));
+        }
+        buf[FRAGMENT_HEAD_LEN..].copy_from_slice(data);
+        Ok(())
+    }
+}
@@ -6,7 +6,7 @@ use std::{fmt, io};
    0                                            15                                              31
    0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5  6  7  8  9  0  1
   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-  |e |s |u |u|   版本(4) |      协议(8)          |      上层协议(8)        | 初始ttl(4) | 生存时间(4) |
+  |e |s |x |u|   版本(4) |      协议(8)          |      上层协议(8)        | 初始ttl(4) | 生存时间(4) |
   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
   |                                          源ip地址(32)                                         |
   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
@@ -14,11 +14,15 @@ use std::{fmt, io};
   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
   |                                           数据体                                              |
   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-  注：e为是否加密标志，s为服务端通信包标志，u未使用
+  注：e为是否加密标志，s为服务端通信包标志，x为连接id扩展标志，u未使用
+  带连接id扩展时包的末尾是64位的连接id，在加密数据之后，中转时不需要解密就能读取
 */
 pub const HEAD_LEN: usize = 12;
+/// 连接id扩展的长度
+pub const CONN_ID_LEN: usize = 8;
 
 pub mod body;
+pub mod capability;
 pub mod control_packet;
 pub mod error_packet;
 pub mod ip_turn_packet;
@@ -158,6 +162,21 @@ impl<B: AsRef<[u8]>> NetPacket<B> {
     pub fn is_gateway(&self) -> bool {
         self.buffer.as_ref()[0] & 0x40 == 0x40
     }
+    /// 末尾带连接id扩展
+    pub fn has_conn_id(&self) -> bool {
+        self.buffer.as_ref()[0] & 0x20 == 0x20
+    }
+    /// 末尾的连接id，长度不够时视为没有
+    pub fn conn_id(&self) -> Option<u64> {
+        if !self.has_conn_id() || self.data_len < HEAD_LEN + CONN_ID_LEN {
+            return None;
+        }
+        let tmp: [u8; CONN_ID_LEN] = self.buffer.as_ref()
+            [self.data_len - CONN_ID_LEN..self.data_len]
+            .try_into()
+            .unwrap();
+        Some(u64::from_be_bytes(tmp))
+    }
     pub fn version(&self) -> Version {
         Version::from(self.buffer.as_ref()[0] & 0x0F)
     }
@@ -208,6 +227,25 @@ impl<B: AsRef<[u8]> + AsMut<[u8]>> NetPacket<B> {
             self.buffer.as_mut()[0] = self.buffer.as_ref()[0] & 0xBF
         };
     }
+    /// 去掉末尾的连接id，之后按普通的包处理
+    pub fn strip_conn_id(&mut self) -> Option<u64> {
+        let id = self.conn_id();
+        if self.has_conn_id() {
+            self.buffer.as_mut()[0] &= !0x20;
+        }
+        if id.is_some() {
+            self.data_len -= CONN_ID_LEN;
+        }
+        id
+    }
+    /// 在末尾加上连接id，需要预留CONN_ID_LEN长度，在加密之后调用
+    pub fn append_conn_id(&mut self, id: u64) -> io::Result<()> {
+        let data_len = self.data_len;
+        self.set_data_len(data_len + CONN_ID_LEN)?;
+        self.buffer.as_mut()[data_len..data_len + CONN_ID_LEN].copy_from_slice(&id.to_be_bytes());
+        self.buffer.as_mut()[0] |= 0x20;
+        Ok(())
+    }
     pub fn set_default_version(&mut self) {
         let v: u8 = Version::V2.into();
         self.buffer.as_mut()[0] = (self.buffer.as_ref()[0] & 0xF0) | (0x0F & v);
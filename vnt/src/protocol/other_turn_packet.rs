@@ -1,6 +1,24 @@
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum Protocol {
     Punch,
+    // 请求对方上报路由
+    RouteReportRequest,
+    // 上报自己到其他设备的路由
+    RouteReport,
+    // 请求对方返回诊断信息
+    DiagRequest,
+    DiagResponse,
+    // 测速
+    SpeedTestStart,
+    SpeedTestData,
+    SpeedTestEnd,
+    SpeedTestReport,
+    // 告知对方可以经由哪些中继设备访问自己
+    ReverseTunnel,
+    // 网关通告自己转发的网段
+    SubnetAdvert,
+    // tap模式下通告自己所在的vlan
+    VlanAdvert,
     Unknown(u8),
 }
 
@@ -8,6 +26,17 @@ impl From<u8> for Protocol {
     fn from(value: u8) -> Self {
         match value {
             1 => Protocol::Punch,
+            2 => Protocol::RouteReportRequest,
+            3 => Protocol::RouteReport,
+            4 => Protocol::DiagRequest,
+            5 => Protocol::DiagResponse,
+            6 => Protocol::SpeedTestStart,
+            7 => Protocol::SpeedTestData,
+            8 => Protocol::SpeedTestEnd,
+            9 => Protocol::SpeedTestReport,
+            10 => Protocol::ReverseTunnel,
+            11 => Protocol::SubnetAdvert,
+            12 => Protocol::VlanAdvert,
             val => Protocol::Unknown(val),
         }
     }
@@ -17,6 +46,17 @@ impl Into<u8> for Protocol {
     fn into(self) -> u8 {
         match self {
             Protocol::Punch => 1,
+            Protocol::RouteReportRequest => 2,
+            Protocol::RouteReport => 3,
+            Protocol::DiagRequest => 4,
+            Protocol::DiagResponse => 5,
+            Protocol::SpeedTestStart => 6,
+            Protocol::SpeedTestData => 7,
+            Protocol::SpeedTestEnd => 8,
+            Protocol::SpeedTestReport => 9,
+            Protocol::ReverseTunnel => 10,
+            Protocol::SubnetAdvert => 11,
+            Protocol::VlanAdvert => 12,
             Protocol::Unknown(val) => val,
         }
     }
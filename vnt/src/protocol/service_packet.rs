@@ -15,6 +15,10 @@ pub enum Protocol {
     SecretHandshakeResponse,
     /// 客户端上报状态
     ClientStatusInfo,
+    /// 服务端下发配置
+    PushConfig,
+    /// 推送设备列表的增量
+    PushDeviceDelta,
     Unknown(u8),
 }
 
@@ -30,6 +34,8 @@ impl From<u8> for Protocol {
             7 => Self::SecretHandshakeRequest,
             8 => Self::SecretHandshakeResponse,
             9 => Self::ClientStatusInfo,
+            10 => Self::PushConfig,
+            11 => Self::PushDeviceDelta,
             val => Self::Unknown(val),
         }
     }
@@ -47,6 +53,8 @@ impl Into<u8> for Protocol {
             Self::SecretHandshakeRequest => 7,
             Self::SecretHandshakeResponse => 8,
             Self::ClientStatusInfo => 9,
+            Self::PushConfig => 10,
+            Self::PushDeviceDelta => 11,
             Self::Unknown(val) => val,
         }
     }
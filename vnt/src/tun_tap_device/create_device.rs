@@ -25,7 +25,23 @@ pub fn create_device(config: &crate::core::Config) -> io::Result<Arc<Device>> {
         if &device_name == default_name {
             delete_device(default_name);
         }
-        Arc::new(Device::new(Some(device_name), config.tap)?)
+        // 并行度大于1时每个任务一个网卡队列
+        let queues = if config.no_multi_queue {
+            1
+        } else {
+            config.parallel
+        };
+        if queues > 1 {
+            match Device::new_multi_queue(Some(device_name.clone()), config.tap, queues) {
+                Ok(device) => Arc::new(device),
+                Err(e) => {
+                    log::warn!("网卡多队列打开失败,使用单队列 {:?}", e);
+                    Arc::new(Device::new(Some(device_name), config.tap)?)
+                }
+            }
+        } else {
+            Arc::new(Device::new(Some(device_name), config.tap)?)
+        }
     };
     #[cfg(target_os = "macos")]
     let device = Arc::new(Device::new(config.device_name.clone())?);
@@ -37,17 +53,27 @@ pub fn create_device(config: &crate::core::Config) -> io::Result<Arc<Device>> {
             .unwrap_or(default_name.to_string()),
         config.tap,
     )?);
-    let mtu = config.mtu.unwrap_or_else(|| {
-        if config.password.is_none() {
-            1450
-        } else {
-            1410
-        }
-    });
-    device.set_mtu(mtu)?;
+    device.set_mtu(config.tun_mtu())?;
+    #[cfg(any(target_os = "windows", target_os = "linux"))]
+    device.set_tap_vlan(config.vlan.clone());
     Ok(device)
 }
 
+/// tap模式下的虚拟mac，由设备id和虚拟ip生成，保证同一设备重启后不变
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+pub fn virtual_mac(device_id: &str, virtual_ip: std::net::Ipv4Addr) -> [u8; 6] {
+    use sha2::Digest;
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(device_id.as_bytes());
+    hasher.update(virtual_ip.octets());
+    let hash = hasher.finalize();
+    let mut mac = [0u8; 6];
+    mac.copy_from_slice(&hash[..6]);
+    // 本地管理的单播地址
+    mac[0] = (mac[0] & 0xFE) | 0x02;
+    mac
+}
+
 #[cfg(target_os = "linux")]
 fn delete_device(name: &str) {
     // 删除默认网卡，此操作有风险，后续可能去除
@@ -62,3 +88,13 @@ fn delete_device(name: &str) {
         log::warn!("删除网卡失败:{:?}", delete_tun);
     }
 }
+
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+#[test]
+fn test_virtual_mac() {
+    let ip = std::net::Ipv4Addr::new(10, 26, 0, 2);
+    let mac = virtual_mac("device-1", ip);
+    assert_eq!(mac, virtual_mac("device-1", ip));
+    assert_ne!(mac, virtual_mac("device-2", ip));
+    assert_eq!(mac[0] & 0x03, 0x02);
+}
@@ -0,0 +1,78 @@
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+/// 地址范围的上限
+const MAX_POOL_SIZE: u32 = 1024;
+
+/// tap模式下dhcp分配给桥接设备的地址范围，格式为 起始ip-结束ip 或者单个ip，
+/// 本机网卡使用的是服务端分配的虚拟ip，桥接设备需要另外的地址
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DhcpPool {
+    pub start: Ipv4Addr,
+    pub end: Ipv4Addr,
+}
+
+impl FromStr for DhcpPool {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = match s.split_once('-') {
+            Some((start, end)) => (start.trim(), end.trim()),
+            None => (s.trim(), s.trim()),
+        };
+        let start = Ipv4Addr::from_str(start).map_err(|e| format!("dhcp pool '{}' {}", s, e))?;
+        let end = Ipv4Addr::from_str(end).map_err(|e| format!("dhcp pool '{}' {}", s, e))?;
+        if u32::from(start) > u32::from(end) {
+            return Err(format!("dhcp pool '{}' start > end", s));
+        }
+        if u32::from(end) - u32::from(start) >= MAX_POOL_SIZE {
+            return Err(format!("dhcp pool '{}' too large", s));
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl DhcpPool {
+    /// 可以分配的地址，去掉不在虚拟网段内的、网段和广播地址、网关以及已被设备使用的地址
+    pub fn addrs(
+        &self,
+        network: Ipv4Addr,
+        netmask: Ipv4Addr,
+        gateway: Ipv4Addr,
+        used: &[Ipv4Addr],
+    ) -> Vec<Ipv4Addr> {
+        let mask = u32::from(netmask);
+        let network = u32::from(network) & mask;
+        let broadcast = network | !mask;
+        (u32::from(self.start)..=u32::from(self.end))
+            .filter(|ip| ip & mask == network && *ip != network && *ip != broadcast)
+            .map(Ipv4Addr::from)
+            .filter(|ip| *ip != gateway && !used.contains(ip))
+            .collect()
+    }
+}
+
+#[test]
+fn test_dhcp_pool() {
+    let pool: DhcpPool = "10.26.0.250 - 10.26.1.1".parse().unwrap();
+    let addrs = pool.addrs(
+        Ipv4Addr::new(10, 26, 0, 0),
+        Ipv4Addr::new(255, 255, 255, 0),
+        Ipv4Addr::new(10, 26, 0, 1),
+        &[Ipv4Addr::new(10, 26, 0, 252)],
+    );
+    assert_eq!(
+        addrs,
+        vec![
+            Ipv4Addr::new(10, 26, 0, 250),
+            Ipv4Addr::new(10, 26, 0, 251),
+            Ipv4Addr::new(10, 26, 0, 253),
+            Ipv4Addr::new(10, 26, 0, 254),
+        ]
+    );
+    let single: DhcpPool = "10.26.0.9".parse().unwrap();
+    assert_eq!(single.start, single.end);
+    assert!("10.26.0.9-10.26.0.8".parse::<DhcpPool>().is_err());
+    assert!("10.26.0.0-10.26.255.255".parse::<DhcpPool>().is_err());
+    assert!("10.26.0.x".parse::<DhcpPool>().is_err());
+}
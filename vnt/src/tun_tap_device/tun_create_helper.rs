@@ -11,7 +11,7 @@ use crate::cipher::Cipher;
 use crate::external_route::ExternalRoute;
 use crate::handle::{CurrentDeviceInfo, PeerDeviceInfo};
 use crate::ip_proxy::IpProxyMap;
-use crate::util::{SingleU64Adder, StopManager};
+use crate::util::{StopManager, U64Adder};
 #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
 #[repr(transparent)]
 #[derive(Clone)]
@@ -56,6 +56,14 @@ impl DeviceAdapter {
             Err(io::Error::new(io::ErrorKind::Other, "not tun device"))
         }
     }
+    pub fn write_batch(&self, bufs: &[&[u8]]) -> io::Result<usize> {
+        if let Some(device) = self.tun.lock().as_ref() {
+            use tun::device::IFace;
+            device.write_batch(bufs)
+        } else {
+            Err(io::Error::new(io::ErrorKind::Other, "not tun device"))
+        }
+    }
     pub fn start(&self, device: Arc<Device>) -> io::Result<()> {
         self.tun_device_helper.start(device.clone())?;
         self.tun.lock().replace(device);
@@ -78,7 +86,8 @@ struct TunDeviceHelperInner {
     client_cipher: Cipher,
     server_cipher: Cipher,
     parallel: usize,
-    up_counter: SingleU64Adder,
+    parallel_crypto: usize,
+    up_counter: U64Adder,
     device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
 }
 
@@ -92,7 +101,8 @@ impl TunDeviceHelper {
         client_cipher: Cipher,
         server_cipher: Cipher,
         parallel: usize,
-        up_counter: SingleU64Adder,
+        parallel_crypto: usize,
+        up_counter: U64Adder,
         device_list: Arc<Mutex<(u16, Vec<PeerDeviceInfo>)>>,
     ) -> Self {
         Self {
@@ -105,6 +115,7 @@ impl TunDeviceHelper {
                 client_cipher,
                 server_cipher,
                 parallel,
+                parallel_crypto,
                 up_counter,
                 device_list,
             }))),
@@ -123,6 +134,7 @@ impl TunDeviceHelper {
                 inner.client_cipher,
                 inner.server_cipher,
                 inner.parallel,
+                inner.parallel_crypto,
                 inner.up_counter,
                 inner.device_list,
             )?;
@@ -1,19 +1,31 @@
 use std::cell::RefCell;
 use std::collections::HashMap;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
 use std::str::FromStr;
-use std::time::Duration;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 use std::{io, thread};
 
 use anyhow::Context;
 use dns_parser::{Builder, Packet, QueryClass, QueryType, RData, ResponseCode};
+use parking_lot::Mutex;
 
 thread_local! {
     static HISTORY: RefCell<HashMap<SocketAddr,usize>> = RefCell::new(HashMap::new());
 }
 
-/// 保留一个地址使用记录，使用过的地址后续不再选中，直到地址全使用过
-pub fn address_choose(addrs: Vec<SocketAddr>) -> anyhow::Result<SocketAddr> {
+/// 保留一个地址使用记录，使用过的地址后续不再选中，直到地址全使用过。
+/// 指定prefer_ipv6或者本机ipv4处于运营商级NAT(CGNAT)下时，ipv6地址轮换完之后重新从ipv6开始，
+/// 只有ipv6都连不上才使用ipv4
+pub fn address_choose(addrs: Vec<SocketAddr>, prefer_ipv6: bool) -> anyhow::Result<SocketAddr> {
+    let prefer_ipv6 = prefer_ipv6
+        || match crate::nat::local_ipv4() {
+            Some(ip) if crate::nat::is_cgnat(&ip) => {
+                log::info!("本机ipv4 {} 处于CGNAT下,优先使用ipv6连接服务器", ip);
+                true
+            }
+            _ => false,
+        };
     HISTORY.with(|history| {
         let mut available = Vec::new();
         for x in &addrs {
@@ -22,7 +34,10 @@ pub fn address_choose(addrs: Vec<SocketAddr>) -> anyhow::Result<SocketAddr> {
                 available.push(*x);
             }
         }
-        if available.is_empty() {
+        let ipv6_used_up = prefer_ipv6
+            && addrs.iter().any(|v| v.is_ipv6())
+            && !available.iter().any(|v| v.is_ipv6());
+        if available.is_empty() || ipv6_used_up {
             available = addrs;
             history.borrow_mut().clear();
         }
@@ -73,6 +88,118 @@ fn address_choose0(addrs: Vec<SocketAddr>) -> anyhow::Result<SocketAddr> {
     }
 }
 
+/// 解析服务器。tls://和https://开头的使用DoT/DoH，证书按域名校验，
+/// 用#指定解析服务器的固定ip，避免解析服务器自身的域名也被污染，
+/// 如 tls://dns.alidns.com#223.5.5.5 、https://dns.alidns.com/dns-query#223.5.5.5
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum NameServer {
+    Udp(SocketAddr),
+    Tls {
+        addr: SocketAddr,
+        server_name: String,
+    },
+    Https {
+        addr: SocketAddr,
+        server_name: String,
+        path: String,
+    },
+}
+
+impl FromStr for NameServer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (rest, https) = if let Some(rest) = s.strip_prefix("tls://") {
+            (rest, false)
+        } else if let Some(rest) = s.strip_prefix("https://") {
+            (rest, true)
+        } else {
+            return Ok(NameServer::Udp(
+                SocketAddr::from_str(s).with_context(|| format!("DNS {:?} error", s))?,
+            ));
+        };
+        if !cfg!(feature = "dns_tls") {
+            return Err(anyhow::anyhow!("DNS {:?} requires feature dns_tls", s));
+        }
+        let (rest, pin) = match rest.split_once('#') {
+            Some((rest, pin)) => (
+                rest,
+                Some(IpAddr::from_str(pin).with_context(|| format!("DNS {:?} pin error", s))?),
+            ),
+            None => (rest, None),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/dns-query"),
+        };
+        let default_port = if https { 443 } else { 853 };
+        let (host, port) = match SocketAddr::from_str(authority) {
+            Ok(addr) => (addr.ip().to_string(), addr.port()),
+            Err(_) => match authority.rsplit_once(':') {
+                Some((host, port)) if !host.contains(':') => (
+                    host.to_string(),
+                    u16::from_str(port).with_context(|| format!("DNS {:?} port error", s))?,
+                ),
+                _ => (
+                    authority
+                        .trim_start_matches('[')
+                        .trim_end_matches(']')
+                        .to_string(),
+                    default_port,
+                ),
+            },
+        };
+        let ip = match pin {
+            Some(ip) => ip,
+            None => IpAddr::from_str(&host)
+                .with_context(|| format!("DNS {:?} must be an ip or pinned with #ip", s))?,
+        };
+        let addr = SocketAddr::new(ip, port);
+        if https {
+            Ok(NameServer::Https {
+                addr,
+                server_name: host,
+                path: path.to_string(),
+            })
+        } else {
+            Ok(NameServer::Tls {
+                addr,
+                server_name: host,
+            })
+        }
+    }
+}
+
+/// (域名,解析服务器) -> (过期时间,结果)
+type DnsCache = HashMap<(String, String), (Instant, Vec<SocketAddr>)>;
+/// 解析结果缓存，按记录的ttl过期
+static DNS_CACHE: OnceLock<Mutex<DnsCache>> = OnceLock::new();
+/// 缓存的最长时间，避免ttl过长时服务端地址变化感知不到
+const MAX_CACHE_TTL: u32 = 3600;
+
+fn cache_get(domain: &str, name_server: &str) -> Option<Vec<SocketAddr>> {
+    let cache = DNS_CACHE.get()?.lock();
+    match cache.get(&(domain.to_string(), name_server.to_string())) {
+        Some((expire, addrs)) if *expire > Instant::now() => Some(addrs.clone()),
+        _ => None,
+    }
+}
+
+fn cache_put(domain: &str, name_server: &str, addrs: &[SocketAddr], ttl: u32) {
+    let ttl = ttl.min(MAX_CACHE_TTL);
+    if ttl == 0 || addrs.is_empty() {
+        return;
+    }
+    let mut cache = DNS_CACHE.get_or_init(Default::default).lock();
+    let now = Instant::now();
+    cache.retain(|_, (expire, _)| *expire > now);
+    cache.insert(
+        (domain.to_string(), name_server.to_string()),
+        (now + Duration::from_secs(ttl as u64), addrs.to_vec()),
+    );
+}
+
 pub fn dns_query_all(
     domain: &str,
     mut name_servers: Vec<String>,
@@ -100,9 +227,15 @@ pub fn dns_query_all(
             }
 
             let mut err: Option<anyhow::Error> = None;
-            for name_server in name_servers {
-                if let Some(domain) = txt_domain.as_ref() {
-                    return txt_dns(domain, name_server);
+            for name_server_str in name_servers {
+                if let Some(addrs) = cache_get(domain, &name_server_str) {
+                    return Ok(addrs);
+                }
+                let name_server = NameServer::from_str(&name_server_str)?;
+                if let Some(txt) = txt_domain.as_ref() {
+                    let (addrs, ttl) = txt_dns0(txt, &name_server)?;
+                    cache_put(domain, &name_server_str, &addrs, ttl);
+                    return Ok(addrs);
                 }
                 let end_index = domain
                     .rfind(":")
@@ -113,29 +246,32 @@ pub fn dns_query_all(
                 let th1 = {
                     let host = host.to_string();
                     let name_server = name_server.clone();
-                    thread::spawn(move || a_dns(host, name_server))
+                    thread::spawn(move || a_dns0(&host, &name_server))
                 };
                 let th2 = {
                     let host = host.to_string();
                     let name_server = name_server.clone();
-                    thread::spawn(move || aaaa_dns(host, name_server))
+                    thread::spawn(move || aaaa_dns0(&host, &name_server))
                 };
                 let mut addr = Vec::new();
+                let mut min_ttl = u32::MAX;
                 match th1.join().unwrap() {
-                    Ok(rs) => {
+                    Ok((rs, ttl)) => {
                         for ip in rs {
                             addr.push(SocketAddr::new(ip.into(), port));
                         }
+                        min_ttl = min_ttl.min(ttl);
                     }
                     Err(e) => {
                         err.replace(anyhow::anyhow!("{}", e));
                     }
                 }
                 match th2.join().unwrap() {
-                    Ok(rs) => {
+                    Ok((rs, ttl)) => {
                         for ip in rs {
                             addr.push(SocketAddr::new(ip.into(), port));
                         }
+                        min_ttl = min_ttl.min(ttl);
                     }
                     Err(e) => {
                         if addr.is_empty() {
@@ -151,6 +287,7 @@ pub fn dns_query_all(
                 if addr.is_empty() {
                     continue;
                 }
+                cache_put(domain, &name_server_str, &addr, min_ttl);
                 return Ok(addr);
             }
             if let Some(e) = err {
@@ -163,9 +300,8 @@ pub fn dns_query_all(
 }
 
 fn query<'a>(
-    udp: &UdpSocket,
+    name_server: &NameServer,
     domain: &str,
-    name_server: SocketAddr,
     record_type: QueryType,
     buf: &'a mut [u8],
 ) -> anyhow::Result<Packet<'a>> {
@@ -173,27 +309,7 @@ fn query<'a>(
     builder.add_question(domain, false, record_type, QueryClass::IN);
     let packet = builder.build().unwrap();
 
-    udp.connect(name_server)
-        .with_context(|| format!("DNS {:?} error ", name_server))?;
-    let mut count = 0;
-    let len = loop {
-        udp.send(&packet)?;
-
-        match udp.recv(buf) {
-            Ok(len) => {
-                break len;
-            }
-            Err(e) => {
-                if e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock {
-                    count += 1;
-                    if count < 3 {
-                        continue;
-                    }
-                }
-                Err(e).with_context(|| format!("DNS {:?} recv error ", name_server))?
-            }
-        };
-    };
+    let len = exchange(name_server, &packet, buf)?;
 
     let pkt = Packet::parse(&buf[..len])
         .with_context(|| format!("domain {:?} DNS {:?} data error ", domain, name_server))?;
@@ -216,14 +332,64 @@ fn query<'a>(
     Ok(pkt)
 }
 
+fn exchange(name_server: &NameServer, request: &[u8], buf: &mut [u8]) -> anyhow::Result<usize> {
+    match name_server {
+        NameServer::Udp(name_server) => {
+            let udp = bind_udp(*name_server)?;
+            udp.connect(name_server)
+                .with_context(|| format!("DNS {:?} error ", name_server))?;
+            let mut count = 0;
+            loop {
+                udp.send(request)?;
+
+                match udp.recv(buf) {
+                    Ok(len) => {
+                        return Ok(len);
+                    }
+                    Err(e) => {
+                        if e.kind() == io::ErrorKind::TimedOut
+                            || e.kind() == io::ErrorKind::WouldBlock
+                        {
+                            count += 1;
+                            if count < 3 {
+                                continue;
+                            }
+                        }
+                        Err(e).with_context(|| format!("DNS {:?} recv error ", name_server))?
+                    }
+                };
+            }
+        }
+        #[cfg(feature = "dns_tls")]
+        NameServer::Tls { addr, server_name } => {
+            super::dns_tls::tls_exchange(*addr, server_name, request, buf)
+        }
+        #[cfg(feature = "dns_tls")]
+        NameServer::Https {
+            addr,
+            server_name,
+            path,
+        } => super::dns_tls::https_exchange(*addr, server_name, path, request, buf),
+        #[cfg(not(feature = "dns_tls"))]
+        _ => Err(anyhow::anyhow!(
+            "DNS {:?} requires feature dns_tls",
+            name_server
+        )),
+    }
+}
+
 pub fn txt_dns(domain: &str, name_server: String) -> anyhow::Result<Vec<SocketAddr>> {
-    let name_server: SocketAddr = name_server.parse()?;
-    let udp = bind_udp(name_server)?;
+    Ok(txt_dns0(domain, &NameServer::from_str(&name_server)?)?.0)
+}
+
+fn txt_dns0(domain: &str, name_server: &NameServer) -> anyhow::Result<(Vec<SocketAddr>, u32)> {
     let mut buf = [0; 65536];
-    let message = query(&udp, domain, name_server, QueryType::TXT, &mut buf)?;
+    let message = query(name_server, domain, QueryType::TXT, &mut buf)?;
     let mut rs = Vec::new();
+    let mut ttl = u32::MAX;
     for record in message.answers {
         if let RData::TXT(txt) = record.data {
+            ttl = ttl.min(record.ttl);
             for x in txt.iter() {
                 let txt = std::str::from_utf8(x).context("record type txt is not string")?;
                 let addr = SocketAddr::from_str(&txt.to_string())
@@ -232,7 +398,7 @@ pub fn txt_dns(domain: &str, name_server: String) -> anyhow::Result<Vec<SocketAd
             }
         }
     }
-    Ok(rs)
+    Ok((rs, ttl))
 }
 
 fn bind_udp(name_server: SocketAddr) -> anyhow::Result<UdpSocket> {
@@ -246,29 +412,57 @@ fn bind_udp(name_server: SocketAddr) -> anyhow::Result<UdpSocket> {
 }
 
 pub fn a_dns(domain: String, name_server: String) -> anyhow::Result<Vec<Ipv4Addr>> {
-    let name_server: SocketAddr = name_server.parse()?;
-    let udp = bind_udp(name_server)?;
+    Ok(a_dns0(&domain, &NameServer::from_str(&name_server)?)?.0)
+}
+
+fn a_dns0(domain: &str, name_server: &NameServer) -> anyhow::Result<(Vec<Ipv4Addr>, u32)> {
     let mut buf = [0; 65536];
-    let message = query(&udp, &domain, name_server, QueryType::A, &mut buf)?;
+    let message = query(name_server, domain, QueryType::A, &mut buf)?;
     let mut rs = Vec::new();
+    let mut ttl = u32::MAX;
     for record in message.answers {
         if let RData::A(a) = record.data {
+            ttl = ttl.min(record.ttl);
             rs.push(a.0);
         }
     }
-    Ok(rs)
+    Ok((rs, ttl))
 }
 
 pub fn aaaa_dns(domain: String, name_server: String) -> anyhow::Result<Vec<Ipv6Addr>> {
-    let name_server: SocketAddr = name_server.parse()?;
-    let udp = bind_udp(name_server)?;
+    Ok(aaaa_dns0(&domain, &NameServer::from_str(&name_server)?)?.0)
+}
+
+fn aaaa_dns0(domain: &str, name_server: &NameServer) -> anyhow::Result<(Vec<Ipv6Addr>, u32)> {
     let mut buf = [0; 65536];
-    let message = query(&udp, &domain, name_server, QueryType::AAAA, &mut buf)?;
+    let message = query(name_server, domain, QueryType::AAAA, &mut buf)?;
     let mut rs = Vec::new();
+    let mut ttl = u32::MAX;
     for record in message.answers {
         if let RData::AAAA(a) = record.data {
+            ttl = ttl.min(record.ttl);
             rs.push(a.0);
         }
     }
-    Ok(rs)
+    Ok((rs, ttl))
+}
+
+#[test]
+fn test_name_server() {
+    assert_eq!(
+        NameServer::from_str("223.5.5.5:53").unwrap(),
+        NameServer::Udp("223.5.5.5:53".parse().unwrap())
+    );
+    let rs = NameServer::from_str("https://dns.alidns.com/dns-query#223.5.5.5");
+    #[cfg(feature = "dns_tls")]
+    assert_eq!(
+        rs.unwrap(),
+        NameServer::Https {
+            addr: "223.5.5.5:443".parse().unwrap(),
+            server_name: "dns.alidns.com".to_string(),
+            path: "/dns-query".to_string(),
+        }
+    );
+    #[cfg(not(feature = "dns_tls"))]
+    assert!(rs.is_err());
 }
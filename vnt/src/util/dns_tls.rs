@@ -0,0 +1,113 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use anyhow::Context;
+
+static CLIENT_CONFIG: OnceLock<Arc<rustls::ClientConfig>> = OnceLock::new();
+
+fn client_config() -> Arc<rustls::ClientConfig> {
+    CLIENT_CONFIG
+        .get_or_init(|| {
+            let mut root_store = rustls::RootCertStore::empty();
+            root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+                rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            }));
+            Arc::new(
+                rustls::ClientConfig::builder()
+                    .with_safe_defaults()
+                    .with_root_certificates(root_store)
+                    .with_no_client_auth(),
+            )
+        })
+        .clone()
+}
+
+/// 连接固定的解析服务器地址，证书按server_name校验
+fn connect(
+    addr: SocketAddr,
+    server_name: &str,
+) -> anyhow::Result<rustls::StreamOwned<rustls::ClientConnection, TcpStream>> {
+    let timeout = Duration::from_secs(3);
+    let tcp = TcpStream::connect_timeout(&addr, timeout)
+        .with_context(|| format!("DNS {:?} connect error", addr))?;
+    tcp.set_read_timeout(Some(timeout))?;
+    tcp.set_write_timeout(Some(timeout))?;
+    let name = rustls::ServerName::try_from(server_name)
+        .with_context(|| format!("DNS server name {:?} error", server_name))?;
+    let conn = rustls::ClientConnection::new(client_config(), name)?;
+    Ok(rustls::StreamOwned::new(conn, tcp))
+}
+
+/// DNS over TLS，报文前加两字节长度
+pub fn tls_exchange(
+    addr: SocketAddr,
+    server_name: &str,
+    request: &[u8],
+    buf: &mut [u8],
+) -> anyhow::Result<usize> {
+    let mut stream = connect(addr, server_name)?;
+    let mut data = Vec::with_capacity(2 + request.len());
+    data.extend_from_slice(&(request.len() as u16).to_be_bytes());
+    data.extend_from_slice(request);
+    stream.write_all(&data)?;
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len)?;
+    let len = u16::from_be_bytes(len) as usize;
+    if len > buf.len() {
+        return Err(anyhow::anyhow!("DNS {:?} response too long", addr));
+    }
+    stream.read_exact(&mut buf[..len])?;
+    Ok(len)
+}
+
+/// DNS over HTTPS，使用POST发送wire格式的报文。
+/// 使用HTTP/1.0避免分块传输，读到连接关闭即为完整响应
+pub fn https_exchange(
+    addr: SocketAddr,
+    server_name: &str,
+    path: &str,
+    request: &[u8],
+    buf: &mut [u8],
+) -> anyhow::Result<usize> {
+    let mut stream = connect(addr, server_name)?;
+    let mut data = format!(
+        "POST {} HTTP/1.0\r\nHost: {}\r\nAccept: application/dns-message\r\nContent-Type: application/dns-message\r\nContent-Length: {}\r\n\r\n",
+        path,
+        server_name,
+        request.len()
+    )
+    .into_bytes();
+    data.extend_from_slice(request);
+    stream.write_all(&data)?;
+    let mut response = Vec::new();
+    if let Err(e) = stream.read_to_end(&mut response) {
+        // 部分服务器不发送close_notify，已经读到数据时忽略
+        if response.is_empty() {
+            return Err(e).with_context(|| format!("DNS {:?} recv error", addr));
+        }
+    }
+    let head_end = response
+        .windows(4)
+        .position(|v| v == b"\r\n\r\n")
+        .with_context(|| format!("DNS {:?} http response error", addr))?;
+    let head = String::from_utf8_lossy(&response[..head_end]);
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .with_context(|| format!("DNS {:?} http response error", addr))?;
+    if status != "200" {
+        return Err(anyhow::anyhow!("DNS {:?} http status {}", addr, status));
+    }
+    let body = &response[head_end + 4..];
+    if body.len() > buf.len() {
+        return Err(anyhow::anyhow!("DNS {:?} response too long", addr));
+    }
+    buf[..body.len()].copy_from_slice(body);
+    Ok(body.len())
+}
@@ -0,0 +1,228 @@
+use std::cell::Cell;
+use std::fmt::{Display, Formatter};
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::{fmt, io};
+
+use log::{LevelFilter, Log, Metadata, Record};
+use parking_lot::{const_rwlock, RwLock};
+
+use crate::channel::RouteKey;
+use crate::protocol::Protocol;
+
+/// 运行时的日志过滤规则，为None时不过滤
+static FILTER: RwLock<Option<LogFilter>> = const_rwlock(None);
+
+thread_local! {
+    // 当前线程所在的日志上下文
+    static SPAN: Cell<Option<LogSpan>> = const { Cell::new(None) };
+}
+
+/// 日志上下文，进入后当前线程输出的日志都会附加`peer= route= proto=`字段，
+/// 这样处理某个包或者某个对端时的日志不需要每处都手动带上这些信息。
+/// 没有使用tracing的span：日志经由log输出到log4rs，tracing的span字段不会出现在log的记录中，
+/// 要用tracing需要整体替换掉log4rs和现有的配置文件。
+/// 上下文只在当前线程有效，交给其他线程处理时用`current`取出后在那边重新`enter`
+#[derive(Copy, Clone, Debug, Default)]
+pub struct LogSpan {
+    pub peer: Option<Ipv4Addr>,
+    pub route: Option<RouteKey>,
+    pub proto: Option<Protocol>,
+}
+
+impl LogSpan {
+    pub fn new(peer: Option<Ipv4Addr>, route: Option<RouteKey>, proto: Option<Protocol>) -> Self {
+        Self { peer, route, proto }
+    }
+    /// 当前线程所在的上下文
+    pub fn current() -> Option<LogSpan> {
+        SPAN.with(|v| v.get())
+    }
+    /// 进入上下文，返回值离开作用域时恢复之前的上下文
+    pub fn enter(self) -> SpanGuard {
+        SpanGuard(SPAN.with(|v| v.replace(Some(self))))
+    }
+}
+
+impl Display for LogSpan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if let Some(peer) = self.peer {
+            write!(f, " peer={}", peer)?;
+        }
+        if let Some(route) = self.route {
+            let kind = if route.is_tcp() { "tcp" } else { "udp" };
+            write!(f, " route={}:{}", kind, route.addr)?;
+        }
+        if let Some(proto) = self.proto {
+            write!(f, " proto={:?}", proto)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct SpanGuard(Option<LogSpan>);
+
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        SPAN.with(|v| v.set(self.0));
+    }
+}
+
+/// 日志过滤规则，格式同RUST_LOG，如 `info,punch=debug,vnt::channel=trace`。
+/// 目标可以是模块路径前缀，也可以是路径中的一段，如`punch`匹配所有打洞相关的模块
+#[derive(Clone, Debug)]
+struct LogFilter {
+    default: LevelFilter,
+    directives: Vec<(String, LevelFilter)>,
+}
+
+impl LogFilter {
+    fn parse(spec: &str) -> io::Result<Self> {
+        let mut default = LevelFilter::Info;
+        let mut directives = Vec::new();
+        for item in spec.split(',') {
+            let item = item.trim();
+            if item.is_empty() {
+                continue;
+            }
+            match item.split_once('=') {
+                Some((target, level)) => {
+                    let level = LevelFilter::from_str(level.trim()).map_err(|_| {
+                        io::Error::new(io::ErrorKind::Other, format!("level error '{}'", item))
+                    })?;
+                    directives.push((target.trim().to_string(), level));
+                }
+                None => {
+                    default = LevelFilter::from_str(item).map_err(|_| {
+                        io::Error::new(io::ErrorKind::Other, format!("level error '{}'", item))
+                    })?;
+                }
+            }
+        }
+        // 更具体的规则优先
+        directives.sort_by_key(|v| std::cmp::Reverse(v.0.len()));
+        Ok(Self {
+            default,
+            directives,
+        })
+    }
+    fn level(&self, target: &str) -> LevelFilter {
+        for (name, level) in &self.directives {
+            if target == name
+                || (target.starts_with(name.as_str()) && target[name.len()..].starts_with("::"))
+                || target.split("::").any(|v| v == name)
+            {
+                return *level;
+            }
+        }
+        self.default
+    }
+    fn max_level(&self) -> LevelFilter {
+        self.directives
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, |a, b| a.max(b))
+    }
+}
+
+/// 包装日志实现，在其基础上按运行时规则过滤，并在内存中保留最近的日志，
+/// 内部日志实现的级别需要放开(如log4rs的root设为trace)，由这里控制输出的级别
+pub struct FilterLogger<L> {
+    inner: L,
+}
+
+impl<L: Log> FilterLogger<L> {
+    pub fn new(inner: L) -> Self {
+        Self { inner }
+    }
+    fn log0(&self, record: &Record) {
+        super::log_ring::push(record);
+        if self.inner.enabled(record.metadata()) {
+            self.inner.log(record)
+        }
+    }
+}
+
+impl<L: Log> Log for FilterLogger<L> {
+    /// 通过过滤的日志总是记录到内存中，内部日志实现可以不输出
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        if let Some(filter) = FILTER.read().as_ref() {
+            if metadata.level() > filter.level(metadata.target()) {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match LogSpan::current() {
+            Some(span) => self.log0(
+                &Record::builder()
+                    .args(format_args!("{}{}", record.args(), span))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            ),
+            None => self.log0(record),
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+/// 设置日志过滤规则，无需重启即可调整某个模块的日志级别
+pub fn set_log_filter(spec: &str) -> io::Result<()> {
+    let filter = LogFilter::parse(spec)?;
+    log::set_max_level(filter.max_level());
+    FILTER.write().replace(filter);
+    Ok(())
+}
+
+#[test]
+fn test_log_filter() {
+    let filter = LogFilter::parse("warn,punch=debug,vnt::channel=trace").unwrap();
+    assert_eq!(
+        filter.level("vnt::handle::maintain::punch"),
+        LevelFilter::Debug
+    );
+    assert_eq!(filter.level("vnt::channel::punch"), LevelFilter::Trace);
+    assert_eq!(filter.level("vnt::channel"), LevelFilter::Trace);
+    assert_eq!(filter.level("vnt::channelx"), LevelFilter::Warn);
+    assert_eq!(filter.level("vnt::handle::recv_data"), LevelFilter::Warn);
+    assert_eq!(filter.max_level(), LevelFilter::Trace);
+    assert!(LogFilter::parse("punch=abc").is_err());
+
+    let route = RouteKey::new(false, 0, "1.2.3.4:5".parse().unwrap());
+    let outer = LogSpan::new(Some(Ipv4Addr::new(10, 0, 0, 2)), None, None).enter();
+    {
+        let _inner = LogSpan::new(
+            Some(Ipv4Addr::new(10, 0, 0, 3)),
+            Some(route),
+            Some(Protocol::Control),
+        )
+        .enter();
+        assert_eq!(
+            LogSpan::current().unwrap().to_string(),
+            " peer=10.0.0.3 route=udp:1.2.3.4:5 proto=Control"
+        );
+    }
+    assert_eq!(LogSpan::current().unwrap().to_string(), " peer=10.0.0.2");
+    // 交给其他线程时带上当前的上下文
+    let span = LogSpan::current();
+    std::thread::spawn(move || {
+        assert!(LogSpan::current().is_none());
+        let _span = span.map(|v| v.enter());
+        assert_eq!(LogSpan::current().unwrap().to_string(), " peer=10.0.0.2");
+    })
+    .join()
+    .unwrap();
+    drop(outer);
+    assert!(LogSpan::current().is_none());
+}
@@ -0,0 +1,86 @@
+use std::collections::VecDeque;
+
+use log::{Level, LevelFilter, Record};
+use parking_lot::{const_mutex, Mutex};
+
+use crate::handle::now_time;
+
+/// 内存中保留的日志行数，超出时丢弃最旧的
+const LOG_CAPACITY: usize = 5000;
+/// 单行日志最多保留的字节数
+const LINE_MAX: usize = 1024;
+
+// (最新的序号，日志)
+static LOG_RING: Mutex<(u64, VecDeque<LogLine>)> = const_mutex((0, VecDeque::new()));
+
+/// 一行日志，序号从1开始递增
+#[derive(Clone, Debug)]
+pub struct LogLine {
+    pub seq: u64,
+    pub time: u64,
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+}
+
+/// 记录通过过滤的日志，用于后台运行时直接查看最近的日志
+pub(crate) fn push(record: &Record) {
+    let mut message = record.args().to_string();
+    if message.len() > LINE_MAX {
+        let mut end = LINE_MAX;
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.truncate(end);
+    }
+    let mut guard = LOG_RING.lock();
+    guard.0 += 1;
+    let seq = guard.0;
+    if guard.1.len() >= LOG_CAPACITY {
+        guard.1.pop_front();
+    }
+    guard.1.push_back(LogLine {
+        seq,
+        time: now_time(),
+        level: record.level(),
+        target: record.target().to_string(),
+        message,
+    });
+}
+
+/// 返回序号大于seq并且不低于level级别的日志，最多limit条
+pub fn recent_logs(seq: u64, level: LevelFilter, limit: usize) -> Vec<LogLine> {
+    let guard = LOG_RING.lock();
+    guard
+        .1
+        .iter()
+        .filter(|v| v.seq > seq && v.level <= level)
+        .take(limit)
+        .cloned()
+        .collect()
+}
+
+#[test]
+fn test_log_ring() {
+    let record = |level: Level, message: &str| {
+        push(
+            &Record::builder()
+                .level(level)
+                .target("vnt::test")
+                .args(format_args!("{}", message))
+                .build(),
+        )
+    };
+    let start = LOG_RING.lock().0;
+    record(Level::Info, "a");
+    record(Level::Warn, "b");
+    record(Level::Error, &"中".repeat(LINE_MAX));
+    let list = recent_logs(start, LevelFilter::Warn, 10);
+    assert_eq!(list.len(), 2);
+    assert_eq!(list[0].message, "b");
+    assert!(list[1].message.len() <= LINE_MAX);
+    assert_eq!(recent_logs(start, LevelFilter::Trace, 1)[0].message, "a");
+    assert!(recent_logs(list[1].seq, LevelFilter::Trace, 10)
+        .iter()
+        .all(|v| v.seq > list[1].seq));
+}
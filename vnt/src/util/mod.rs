@@ -10,3 +10,22 @@ pub use counter::*;
 
 mod dns_query;
 pub use dns_query::*;
+#[cfg(feature = "dns_tls")]
+mod dns_tls;
+
+mod log_filter;
+pub use log_filter::{set_log_filter, FilterLogger, LogSpan, SpanGuard};
+mod log_ring;
+pub use log_ring::{recent_logs, LogLine};
+
+mod server_proxy;
+pub use server_proxy::ServerProxy;
+
+mod net_watch;
+pub use net_watch::watch_net_change;
+
+// macos上无法保留网络权限，之后的ifconfig和route需要root，只支持linux
+#[cfg(target_os = "linux")]
+mod privilege;
+#[cfg(target_os = "linux")]
+pub use privilege::RunAs;
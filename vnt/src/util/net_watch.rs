@@ -0,0 +1,408 @@
+use std::io;
+use std::sync::Arc;
+use std::thread;
+
+use crate::util::StopManager;
+
+/// 监听系统网络变化(网卡上下线、地址增删)，有变化时调用f，f返回false时停止监听。
+/// 通知可能包含和本程序无关的变化，调用方需要自行比较地址。
+/// 停止时取消正在等待的通知，监听线程随之退出。
+/// 返回false表示当前平台不支持，调用方需要定时轮询
+pub fn watch_net_change<F>(stop_manager: &StopManager, mut f: F) -> bool
+where
+    F: FnMut() -> bool + Send + 'static,
+{
+    let watcher = match NetWatcher::new() {
+        Ok(watcher) => Arc::new(watcher),
+        Err(e) => {
+            log::warn!("不支持监听网络变化,改为轮询 {:?}", e);
+            return false;
+        }
+    };
+    let worker = {
+        let watcher = watcher.clone();
+        match stop_manager.add_listener("netWatch".into(), move || watcher.cancel()) {
+            Ok(worker) => worker,
+            Err(e) => {
+                log::warn!("{:?}", e);
+                return false;
+            }
+        }
+    };
+    let stop_manager = stop_manager.clone();
+    let rs = thread::Builder::new()
+        .name("netWatch".into())
+        .spawn(move || {
+            loop {
+                match watcher.wait() {
+                    Ok(true) => {}
+                    // 已取消
+                    Ok(false) => break,
+                    Err(e) => {
+                        log::warn!("网络变化监听失败 {:?}", e);
+                        break;
+                    }
+                }
+                if stop_manager.is_stop() || !f() {
+                    break;
+                }
+            }
+            drop(worker);
+        });
+    match rs {
+        Ok(_) => true,
+        Err(e) => {
+            log::warn!("{:?}", e);
+            false
+        }
+    }
+}
+
+/// 用管道唤醒阻塞等待通知的线程
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+struct Canceler {
+    read: std::os::fd::OwnedFd,
+    write: std::os::fd::OwnedFd,
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+impl Canceler {
+    fn new() -> io::Result<Self> {
+        use std::os::fd::FromRawFd;
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (read, write) = unsafe {
+            (
+                std::os::fd::OwnedFd::from_raw_fd(fds[0]),
+                std::os::fd::OwnedFd::from_raw_fd(fds[1]),
+            )
+        };
+        for fd in fds {
+            unsafe {
+                libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            }
+        }
+        Ok(Self { read, write })
+    }
+    fn cancel(&self) {
+        let buf = [1u8];
+        unsafe {
+            libc::write(
+                std::os::fd::AsRawFd::as_raw_fd(&self.write),
+                buf.as_ptr() as *const libc::c_void,
+                1,
+            );
+        }
+    }
+    /// 等待fd可读，返回false表示已取消
+    fn readable(&self, fd: &std::os::fd::OwnedFd) -> io::Result<bool> {
+        use std::os::fd::AsRawFd;
+        let mut fds = [
+            libc::pollfd {
+                fd: fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: self.read.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        loop {
+            if unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) } < 0 {
+                let e = io::Error::last_os_error();
+                if e.raw_os_error() == Some(libc::EINTR) {
+                    continue;
+                }
+                return Err(e);
+            }
+            if fds[1].revents != 0 {
+                return Ok(false);
+            }
+            if fds[0].revents != 0 {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+/// netlink订阅网卡和地址变化
+#[cfg(target_os = "linux")]
+struct NetWatcher {
+    fd: std::os::fd::OwnedFd,
+    canceler: Canceler,
+}
+
+#[cfg(target_os = "linux")]
+impl NetWatcher {
+    fn new() -> io::Result<Self> {
+        use std::os::fd::FromRawFd;
+        unsafe {
+            let fd = libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            );
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let fd = std::os::fd::OwnedFd::from_raw_fd(fd);
+            let mut addr: libc::sockaddr_nl = std::mem::zeroed();
+            addr.nl_family = libc::AF_NETLINK as u16;
+            // 不订阅路由变化，添加虚拟网卡路由时不产生通知
+            addr.nl_groups =
+                (libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV6_IFADDR) as u32;
+            if libc::bind(
+                std::os::fd::AsRawFd::as_raw_fd(&fd),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            ) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self {
+                fd,
+                canceler: Canceler::new()?,
+            })
+        }
+    }
+    fn cancel(&self) {
+        self.canceler.cancel()
+    }
+    /// 有变化时返回true，取消时返回false
+    fn wait(&self) -> io::Result<bool> {
+        let mut buf = [0u8; 4096];
+        loop {
+            if !self.canceler.readable(&self.fd)? {
+                return Ok(false);
+            }
+            let len = unsafe {
+                libc::recv(
+                    std::os::fd::AsRawFd::as_raw_fd(&self.fd),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if len > 0 {
+                return Ok(true);
+            }
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                // 通知太多时缓冲区溢出，同样说明发生了变化
+                Some(libc::ENOBUFS) => return Ok(true),
+                _ => return Err(e),
+            }
+        }
+    }
+}
+
+/// 路由socket，SystemConfiguration也是通过它得知地址变化
+#[cfg(target_os = "macos")]
+struct NetWatcher {
+    fd: std::os::fd::OwnedFd,
+    canceler: Canceler,
+}
+
+#[cfg(target_os = "macos")]
+impl NetWatcher {
+    const RTM_NEWADDR: u8 = 0xc;
+    const RTM_DELADDR: u8 = 0xd;
+    const RTM_IFINFO: u8 = 0xe;
+    fn new() -> io::Result<Self> {
+        use std::os::fd::FromRawFd;
+        let fd = unsafe { libc::socket(libc::PF_ROUTE, libc::SOCK_RAW, libc::AF_UNSPEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            fd: unsafe { std::os::fd::OwnedFd::from_raw_fd(fd) },
+            canceler: Canceler::new()?,
+        })
+    }
+    fn cancel(&self) {
+        self.canceler.cancel()
+    }
+    fn wait(&self) -> io::Result<bool> {
+        let mut buf = [0u8; 2048];
+        loop {
+            if !self.canceler.readable(&self.fd)? {
+                return Ok(false);
+            }
+            let len = unsafe {
+                libc::recv(
+                    std::os::fd::AsRawFd::as_raw_fd(&self.fd),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if len < 0 {
+                let e = io::Error::last_os_error();
+                if e.raw_os_error() == Some(libc::EINTR) {
+                    continue;
+                }
+                return Err(e);
+            }
+            // rt_msghdr: msglen(u16) version(u8) type(u8)，忽略路由增删
+            if len >= 4
+                && matches!(
+                    buf[3],
+                    Self::RTM_NEWADDR | Self::RTM_DELADDR | Self::RTM_IFINFO
+                )
+            {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+#[link(name = "iphlpapi")]
+extern "system" {
+    fn NotifyAddrChange(handle: *mut *mut std::ffi::c_void, overlapped: *mut Overlapped) -> u32;
+    fn CancelIPChangeNotify(overlapped: *mut Overlapped) -> i32;
+}
+
+#[cfg(target_os = "windows")]
+#[link(name = "kernel32")]
+extern "system" {
+    fn CreateEventW(
+        attributes: *mut std::ffi::c_void,
+        manual_reset: i32,
+        initial_state: i32,
+        name: *const u16,
+    ) -> *mut std::ffi::c_void;
+    fn SetEvent(event: *mut std::ffi::c_void) -> i32;
+    fn CloseHandle(handle: *mut std::ffi::c_void) -> i32;
+    fn WaitForSingleObject(handle: *mut std::ffi::c_void, milliseconds: u32) -> u32;
+    fn WaitForMultipleObjects(
+        count: u32,
+        handles: *const *mut std::ffi::c_void,
+        wait_all: i32,
+        milliseconds: u32,
+    ) -> u32;
+}
+
+#[cfg(target_os = "windows")]
+#[repr(C)]
+struct Overlapped {
+    internal: usize,
+    internal_high: usize,
+    offset: u32,
+    offset_high: u32,
+    event: *mut std::ffi::c_void,
+}
+
+/// 异步的NotifyAddrChange，等待地址变化事件和停止事件
+#[cfg(target_os = "windows")]
+struct NetWatcher {
+    notify: *mut std::ffi::c_void,
+    stop: *mut std::ffi::c_void,
+}
+
+// 事件句柄可以跨线程使用
+#[cfg(target_os = "windows")]
+unsafe impl Send for NetWatcher {}
+#[cfg(target_os = "windows")]
+unsafe impl Sync for NetWatcher {}
+
+#[cfg(target_os = "windows")]
+impl NetWatcher {
+    const ERROR_IO_PENDING: u32 = 997;
+    const INFINITE: u32 = u32::MAX;
+    fn new() -> io::Result<Self> {
+        unsafe {
+            let notify = CreateEventW(std::ptr::null_mut(), 0, 0, std::ptr::null());
+            if notify.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            // 停止事件手动重置，取消后一直有信号
+            let stop = CreateEventW(std::ptr::null_mut(), 1, 0, std::ptr::null());
+            if stop.is_null() {
+                let e = io::Error::last_os_error();
+                CloseHandle(notify);
+                return Err(e);
+            }
+            Ok(Self { notify, stop })
+        }
+    }
+    fn cancel(&self) {
+        unsafe {
+            SetEvent(self.stop);
+        }
+    }
+    fn wait(&self) -> io::Result<bool> {
+        unsafe {
+            let mut overlapped = Overlapped {
+                internal: 0,
+                internal_high: 0,
+                offset: 0,
+                offset_high: 0,
+                event: self.notify,
+            };
+            let mut handle = std::ptr::null_mut();
+            let rs = NotifyAddrChange(&mut handle, &mut overlapped);
+            if rs != Self::ERROR_IO_PENDING {
+                return Err(io::Error::from_raw_os_error(rs as i32));
+            }
+            let handles = [self.notify, self.stop];
+            let rs = WaitForMultipleObjects(2, handles.as_ptr(), 0, Self::INFINITE);
+            if rs == 0 {
+                return Ok(true);
+            }
+            let e = io::Error::last_os_error();
+            // 取消后等待请求结束，overlapped在这之前不能释放
+            if CancelIPChangeNotify(&mut overlapped) != 0 {
+                WaitForSingleObject(self.notify, 1000);
+            }
+            if rs == 1 {
+                Ok(false)
+            } else {
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl Drop for NetWatcher {
+    fn drop(&mut self) {
+        unsafe {
+            CloseHandle(self.notify);
+            CloseHandle(self.stop);
+        }
+    }
+}
+
+/// android上普通应用无法订阅netlink
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+struct NetWatcher;
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+impl NetWatcher {
+    fn new() -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "net watch"))
+    }
+    fn cancel(&self) {}
+    fn wait(&self) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_watch_net_change_stop() {
+    let stop_manager = StopManager::new(|| {});
+    if !watch_net_change(&stop_manager, || true) {
+        // 沙箱中可能无法创建netlink socket
+        return;
+    }
+    // 停止后监听线程退出，wait才能返回
+    stop_manager.stop();
+    stop_manager.wait();
+}
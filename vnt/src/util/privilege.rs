@@ -0,0 +1,176 @@
+use std::ffi::CString;
+use std::io;
+
+/// 创建虚拟网卡后切换到的用户和组，格式为 user[:group]，也可以是数字id，
+/// 没有指定组时使用用户的主组
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RunAs {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+impl RunAs {
+    pub fn lookup(s: &str) -> io::Result<Self> {
+        let (user, group) = match s.split_once(':') {
+            Some((user, group)) => (user.trim(), Some(group.trim())),
+            None => (s.trim(), None),
+        };
+        let (uid, user_gid) = match user.parse::<libc::uid_t>() {
+            Ok(uid) => (uid, None),
+            Err(_) => {
+                let (uid, gid) = lookup_user(user)?;
+                (uid, Some(gid))
+            }
+        };
+        let gid = match group {
+            Some(group) => match group.parse::<libc::gid_t>() {
+                Ok(gid) => gid,
+                Err(_) => lookup_group(group)?,
+            },
+            None => match user_gid {
+                Some(gid) => gid,
+                None => uid as libc::gid_t,
+            },
+        };
+        Ok(Self { uid, gid })
+    }
+    /// 放弃root权限，只保留网络管理相关的能力，
+    /// 之后设置虚拟ip、修改路由仍然可用。需要在启动其他线程之前调用
+    pub fn drop_privileges(&self) -> io::Result<()> {
+        if unsafe { libc::geteuid() } != 0 {
+            if unsafe { libc::geteuid() } == self.uid {
+                return Ok(());
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "switching user requires root",
+            ));
+        }
+        if unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        unsafe {
+            if libc::setgroups(1, &self.gid) != 0
+                || libc::setgid(self.gid) != 0
+                || libc::setuid(self.uid) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        retain_net_caps()?;
+        // 确认无法再切换回root
+        if self.uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "privileges could be regained after drop",
+            ));
+        }
+        log::info!("已切换到 uid={} gid={}", self.uid, self.gid);
+        Ok(())
+    }
+}
+
+fn lookup_user(name: &str) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let c_name = CString::new(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("user {}", name)))?;
+    let passwd = unsafe { libc::getpwnam(c_name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("user {} not found", name),
+        ));
+    }
+    unsafe { Ok(((*passwd).pw_uid, (*passwd).pw_gid)) }
+}
+
+fn lookup_group(name: &str) -> io::Result<libc::gid_t> {
+    let c_name = CString::new(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("group {}", name)))?;
+    let group = unsafe { libc::getgrnam(c_name.as_ptr()) };
+    if group.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("group {} not found", name),
+        ));
+    }
+    unsafe { Ok((*group).gr_gid) }
+}
+
+// linux/capability.h
+const CAP_NET_BIND_SERVICE: u32 = 10;
+const CAP_NET_ADMIN: u32 = 12;
+const CAP_NET_RAW: u32 = 13;
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x20080522;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// 只保留网卡管理、原始套接字(icmp代理)和绑定低端口的能力，
+/// 并加入ambient集合，使执行的ip命令也能修改路由
+fn retain_net_caps() -> io::Result<()> {
+    let caps = (1 << CAP_NET_ADMIN) | (1 << CAP_NET_RAW) | (1 << CAP_NET_BIND_SERVICE);
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = [
+        CapData {
+            effective: caps,
+            permitted: caps,
+            inheritable: caps,
+        },
+        CapData::default(),
+    ];
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe {
+        libc::prctl(libc::PR_SET_KEEPCAPS, 0, 0, 0, 0);
+    }
+    for cap in [CAP_NET_ADMIN, CAP_NET_RAW] {
+        let rs = unsafe {
+            libc::prctl(
+                libc::PR_CAP_AMBIENT,
+                libc::PR_CAP_AMBIENT_RAISE as libc::c_ulong,
+                cap as libc::c_ulong,
+                0,
+                0,
+            )
+        };
+        if rs != 0 {
+            // 低版本内核不支持ambient，执行的命令会失败
+            log::warn!(
+                "ambient capability {} {:?}",
+                cap,
+                io::Error::last_os_error()
+            );
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_run_as_lookup() {
+    assert_eq!(RunAs::lookup("0").unwrap(), RunAs { uid: 0, gid: 0 });
+    assert_eq!(RunAs::lookup("root:0").unwrap(), RunAs { uid: 0, gid: 0 });
+    assert_eq!(
+        RunAs::lookup("65534:100").unwrap(),
+        RunAs {
+            uid: 65534,
+            gid: 100
+        }
+    );
+    assert!(RunAs::lookup("vnt-no-such-user").is_err());
+    assert!(RunAs::lookup("0:vnt-no-such-group").is_err());
+}
@@ -21,4 +21,22 @@ pub trait IFace {
 
     fn read(&self, buf: &mut [u8]) -> Result<usize>;
     fn write(&self, buf: &[u8]) -> Result<usize>;
+    /// 批量读取，第i个包写入bufs[i]，长度写入sizes[i]，返回读取的包数量。
+    /// 至少阻塞到读取一个包，默认实现每次只读一个包
+    fn read_batch(&self, bufs: &mut [&mut [u8]], sizes: &mut [usize]) -> Result<usize> {
+        sizes[0] = self.read(bufs[0])?;
+        Ok(1)
+    }
+    /// 按顺序批量写入，返回写入的包数量，默认实现逐个写入
+    fn write_batch(&self, bufs: &[&[u8]]) -> Result<usize> {
+        for (index, buf) in bufs.iter().enumerate() {
+            if let Err(e) = self.write(buf) {
+                if index == 0 {
+                    return Err(e);
+                }
+                return Ok(index);
+            }
+        }
+        Ok(bufs.len())
+    }
 }
@@ -5,6 +5,8 @@
 pub mod device;
 mod packet;
 
+pub use packet::DhcpOption;
+
 #[cfg(target_os = "linux")]
 mod linux;
 #[cfg(target_os = "linux")]
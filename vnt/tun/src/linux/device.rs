@@ -1,7 +1,9 @@
+use std::collections::HashMap;
 use std::ffi::{CStr, CString};
 use std::net::Ipv4Addr;
 use std::os::fd::AsRawFd;
 use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::{io, mem, ptr};
 
 use libc::{
@@ -13,17 +15,30 @@ use crate::device::IFace;
 use crate::linux::route;
 use crate::linux::sys::*;
 use crate::packet;
+use crate::packet::{DhcpOption, TapState};
 use crate::unix::{exe_cmd, Fd, SockAddr};
 
 pub struct Device {
     name: String,
     ctl: Fd,
-    tun: Fd,
-    mac: Option<[u8; 6]>,
+    // 多队列时每个队列一个fd，第一个为主队列
+    queues: Vec<Fd>,
+    tap: Option<TapState>,
+}
+
+/// 写入时每个线程固定使用一个队列
+static NEXT_QUEUE: AtomicUsize = AtomicUsize::new(0);
+thread_local! {
+    static WRITE_QUEUE: usize = NEXT_QUEUE.fetch_add(1, Ordering::Relaxed);
 }
 
 impl Device {
     pub fn new(name: Option<String>, tap: bool) -> io::Result<Self> {
+        Self::new_multi_queue(name, tap, 1)
+    }
+    /// 使用IFF_MULTI_QUEUE打开多个队列，内核不支持时返回错误
+    pub fn new_multi_queue(name: Option<String>, tap: bool, queues_num: usize) -> io::Result<Self> {
+        let queues_num = queues_num.max(1);
         let device = unsafe {
             let dev = match name {
                 Some(name) => {
@@ -52,8 +67,6 @@ impl Device {
 
             let device_type: c_short = if tap { IFF_TAP } else { IFF_TUN } as c_short;
 
-            let queues_num = 1;
-
             let iff_no_pi = IFF_NO_PI as c_short;
             let iff_multi_queue = IFF_MULTI_QUEUE as c_short;
             let packet_information = false;
@@ -67,13 +80,24 @@ impl Device {
             if tunsetiff(tun.0, &mut req as *mut _ as *mut _) < 0 {
                 return Err(io::Error::last_os_error());
             }
+            let mut queues = Vec::with_capacity(queues_num);
+            queues.push(tun);
+            // 其余队列使用内核分配的名称挂到同一网卡上
+            for _ in 1..queues_num {
+                let queue = Fd::new(libc::open(b"/dev/net/tun\0".as_ptr() as *const _, O_RDWR))
+                    .map_err(|_| io::Error::last_os_error())?;
+                if tunsetiff(queue.0, &mut req as *mut _ as *mut _) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                queues.push(queue);
+            }
 
             let ctl = Fd::new(libc::socket(AF_INET, SOCK_DGRAM, 0))?;
 
             let name = CStr::from_ptr(req.ifr_name.as_ptr())
                 .to_string_lossy()
                 .to_string();
-            let mac = if tap {
+            let tap_state = if tap {
                 let get_mac_cmd = format!("cat /sys/class/net/{}/address", name);
                 let mac_out = exe_cmd(&get_mac_cmd)?;
                 let mac_str = String::from_utf8(mac_out.stdout).unwrap();
@@ -82,24 +106,66 @@ impl Device {
                 for i in 0..6 {
                     mac[i] = u8::from_str_radix(&split.next().unwrap()[..2], 16).unwrap();
                 }
-                Some(mac)
+                Some(TapState::new(mac))
             } else {
                 None
             };
-            let set_txqueuelen  = format!("ifconfig {} txqueuelen 1000", name);
-            if let Err(e) = exe_cmd(&set_txqueuelen){
-                log::warn!("{:?}",e);
+            let set_txqueuelen = format!("ifconfig {} txqueuelen 1000", name);
+            if let Err(e) = exe_cmd(&set_txqueuelen) {
+                log::warn!("{:?}", e);
             }
             Device {
                 name,
-                tun,
+                queues,
                 ctl,
-                mac,
+                tap: tap_state,
             }
         };
         device.enabled(true)?;
         Ok(device)
     }
+    pub fn queue_num(&self) -> usize {
+        self.queues.len()
+    }
+    /// 从指定的队列读取，index超出时使用主队列
+    pub fn read_queue(&self, index: usize, buf: &mut [u8]) -> io::Result<usize> {
+        let queue = self.queues.get(index).unwrap_or(&self.queues[0]);
+        if let Some(tap) = &self.tap {
+            packet::read_tap(
+                buf,
+                |eth_buf| queue.read(eth_buf),
+                |eth_buf| queue.write(eth_buf),
+                tap,
+            )
+        } else {
+            queue.read(buf)
+        }
+    }
+    fn write_queue(&self) -> &Fd {
+        if self.queues.len() == 1 {
+            return &self.queues[0];
+        }
+        let index = WRITE_QUEUE.with(|v| *v);
+        &self.queues[index % self.queues.len()]
+    }
+    /// 设置tap模式下的虚拟mac和dhcp信息，tun模式忽略
+    pub fn set_tap_info(&self, virtual_mac: [u8; 6], dhcp: Option<DhcpOption>) {
+        if let Some(tap) = &self.tap {
+            tap.set(virtual_mac, dhcp);
+        }
+    }
+    /// 设置tap模式下本地所在的vlan，tun模式忽略
+    pub fn set_tap_vlan(&self, local: Vec<u16>) {
+        if let Some(tap) = &self.tap {
+            tap.set_vlan(local);
+        }
+    }
+    /// 设置tap模式下对端和本地共同所在的vlan，tun模式忽略
+    pub fn set_tap_vlan_peers(&self, peers: HashMap<Ipv4Addr, Vec<u16>>) {
+        if let Some(tap) = &self.tap {
+            tap.set_vlan_peers(peers);
+        }
+    }
 }
 
 impl Device {
@@ -286,22 +352,15 @@ impl IFace for Device {
     }
 
     fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
-        if self.mac.is_some() {
-            packet::read_tap(
-                buf,
-                |eth_buf| self.tun.read(eth_buf),
-                |eth_buf| self.tun.write(eth_buf),
-            )
-        } else {
-            self.tun.read(buf)
-        }
+        self.read_queue(0, buf)
     }
 
     fn write(&self, buf: &[u8]) -> io::Result<usize> {
-        if let Some(mac) = &self.mac {
-            packet::write_tap(buf, |eth_buf| self.tun.write(eth_buf), mac)
+        let queue = self.write_queue();
+        if let Some(tap) = &self.tap {
+            packet::write_tap(buf, |eth_buf| queue.write(eth_buf), tap)
         } else {
-            self.tun.write(buf)
+            queue.write(buf)
         }
     }
 }
@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+
+/// tap模式下通过dhcp下发给桥接设备的地址信息，
+/// pool为可以分配的地址，不能包含本机网卡的ip，每个桥接设备分配一个
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DhcpOption {
+    pub pool: Vec<Ipv4Addr>,
+    pub mask: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub mtu: u16,
+}
+
+/// dhcp服务端，按客户端mac分配地址
+pub struct DhcpServer {
+    option: DhcpOption,
+    leases: HashMap<[u8; 6], Ipv4Addr>,
+}
+
+impl DhcpServer {
+    pub fn new(option: DhcpOption) -> Self {
+        Self {
+            option,
+            leases: HashMap::new(),
+        }
+    }
+    /// 更新地址信息，保留仍在地址池中的租约
+    pub fn set_option(&mut self, option: DhcpOption) {
+        self.leases.retain(|_, ip| option.pool.contains(ip));
+        self.option = option;
+    }
+    /// 已分配的地址
+    pub fn leases(&self) -> impl Iterator<Item = (&[u8; 6], &Ipv4Addr)> {
+        self.leases.iter()
+    }
+    /// 为mac分配地址，分配过的返回原来的地址，地址池用完时返回None
+    fn lease(&mut self, mac: [u8; 6]) -> Option<Ipv4Addr> {
+        if let Some(ip) = self.leases.get(&mac) {
+            return Some(*ip);
+        }
+        let ip = *self
+            .option
+            .pool
+            .iter()
+            .find(|ip| !self.leases.values().any(|v| v == *ip))?;
+        self.leases.insert(mac, ip);
+        Some(ip)
+    }
+    /// 处理dhcp discover/request，返回回复的ipv4报文、客户端mac和分配的地址
+    pub fn reply(&mut self, ipv4: &[u8]) -> Option<(Vec<u8>, [u8; 6], Ipv4Addr)> {
+        let (dhcp, reply_type) = parse(ipv4)?;
+        let mut chaddr = [0u8; 6];
+        chaddr.copy_from_slice(&dhcp[28..34]);
+        let ip = self.lease(chaddr)?;
+        Some((reply(dhcp, reply_type, &self.option, ip), chaddr, ip))
+    }
+}
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const LEASE_TIME: u32 = 86400;
+
+/// 判断ipv4报文是否是发往dhcp服务端的请求
+pub fn is_dhcp_request(ipv4: &[u8]) -> bool {
+    if ipv4.len() < 20 || ipv4[0] >> 4 != 4 || ipv4[9] != 17 {
+        return false;
+    }
+    let head_len = ((ipv4[0] & 0xF) as usize) * 4;
+    if ipv4.len() < head_len + 8 {
+        return false;
+    }
+    let udp = &ipv4[head_len..];
+    u16::from_be_bytes([udp[0], udp[1]]) == 68 && u16::from_be_bytes([udp[2], udp[3]]) == 67
+}
+
+/// 解析dhcp discover/request，返回dhcp报文和回复的消息类型
+fn parse(ipv4: &[u8]) -> Option<(&[u8], u8)> {
+    let head_len = ((ipv4[0] & 0xF) as usize) * 4;
+    let dhcp = ipv4.get(head_len + 8..)?;
+    // 固定部分236字节 + magic cookie
+    if dhcp.len() < 240 || dhcp[0] != 1 || dhcp[236..240] != MAGIC_COOKIE {
+        return None;
+    }
+    let mut msg_type = 0;
+    let mut options = &dhcp[240..];
+    while options.len() >= 2 && options[0] != 255 {
+        if options[0] == 0 {
+            options = &options[1..];
+            continue;
+        }
+        let len = options[1] as usize;
+        if options.len() < 2 + len {
+            return None;
+        }
+        if options[0] == 53 && len == 1 {
+            msg_type = options[2];
+        }
+        options = &options[2 + len..];
+    }
+    let reply_type = match msg_type {
+        DISCOVER => OFFER,
+        REQUEST => ACK,
+        _ => return None,
+    };
+    Some((dhcp, reply_type))
+}
+
+/// 生成回复的ipv4报文，ip为分配给客户端的地址
+fn reply(dhcp: &[u8], reply_type: u8, option: &DhcpOption, ip: Ipv4Addr) -> Vec<u8> {
+    let mut payload = vec![0u8; 240];
+    payload[0] = 2; // op = BOOTREPLY
+    payload[1] = 1; // htype = ethernet
+    payload[2] = 6; // hlen
+    payload[4..8].copy_from_slice(&dhcp[4..8]); // xid
+    payload[10..12].copy_from_slice(&dhcp[10..12]); // flags
+    payload[16..20].copy_from_slice(&ip.octets()); // yiaddr
+    payload[20..24].copy_from_slice(&option.gateway.octets()); // siaddr
+    payload[28..44].copy_from_slice(&dhcp[28..44]); // chaddr
+    payload[236..240].copy_from_slice(&MAGIC_COOKIE);
+    payload.extend_from_slice(&[53, 1, reply_type]);
+    payload.extend_from_slice(&[54, 4]);
+    payload.extend_from_slice(&option.gateway.octets());
+    payload.extend_from_slice(&[51, 4]);
+    payload.extend_from_slice(&LEASE_TIME.to_be_bytes());
+    payload.extend_from_slice(&[1, 4]);
+    payload.extend_from_slice(&option.mask.octets());
+    payload.extend_from_slice(&[3, 4]);
+    payload.extend_from_slice(&option.gateway.octets());
+    if option.mtu > 0 {
+        payload.extend_from_slice(&[26, 2]);
+        payload.extend_from_slice(&option.mtu.to_be_bytes());
+    }
+    payload.push(255);
+
+    let udp_len = 8 + payload.len();
+    let total_len = 20 + udp_len;
+    let mut packet = vec![0u8; total_len];
+    packet[0] = 0x45;
+    packet[2..4].copy_from_slice(&(total_len as u16).to_be_bytes());
+    packet[8] = 64;
+    packet[9] = 17;
+    packet[12..16].copy_from_slice(&option.gateway.octets());
+    packet[16..20].copy_from_slice(&Ipv4Addr::BROADCAST.octets());
+    let checksum = checksum(&packet[..20]);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    let udp = &mut packet[20..];
+    udp[0..2].copy_from_slice(&67u16.to_be_bytes());
+    udp[2..4].copy_from_slice(&68u16.to_be_bytes());
+    udp[4..6].copy_from_slice(&(udp_len as u16).to_be_bytes());
+    // udp校验和可以为0
+    udp[8..].copy_from_slice(&payload);
+    packet
+}
+
+fn checksum(buf: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    for chunk in buf.chunks(2) {
+        let v = if chunk.len() == 2 {
+            u16::from_be_bytes([chunk[0], chunk[1]])
+        } else {
+            u16::from_be_bytes([chunk[0], 0])
+        };
+        sum += v as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+fn request(msg_type: u8, mac: [u8; 6]) -> Vec<u8> {
+    let mut dhcp = vec![0u8; 240];
+    dhcp[0] = 1;
+    dhcp[1] = 1;
+    dhcp[2] = 6;
+    dhcp[4..8].copy_from_slice(&[1, 2, 3, 4]);
+    dhcp[28..34].copy_from_slice(&mac);
+    dhcp[236..240].copy_from_slice(&MAGIC_COOKIE);
+    dhcp.extend_from_slice(&[53, 1, msg_type, 0, 255]);
+    let mut packet = vec![0u8; 28];
+    packet[0] = 0x45;
+    packet[9] = 17;
+    packet[20..22].copy_from_slice(&68u16.to_be_bytes());
+    packet[22..24].copy_from_slice(&67u16.to_be_bytes());
+    packet.extend_from_slice(&dhcp);
+    packet
+}
+
+#[test]
+fn test_is_dhcp_request() {
+    let packet = request(DISCOVER, [2, 0, 0, 0, 0, 1]);
+    assert!(is_dhcp_request(&packet));
+    assert!(!is_dhcp_request(&packet[..20]));
+    let mut tcp = packet.clone();
+    tcp[9] = 6;
+    assert!(!is_dhcp_request(&tcp));
+    let mut reply = packet.clone();
+    reply[20..22].copy_from_slice(&67u16.to_be_bytes());
+    reply[22..24].copy_from_slice(&68u16.to_be_bytes());
+    assert!(!is_dhcp_request(&reply));
+}
+
+#[test]
+fn test_dhcp_reply() {
+    let pool = vec![Ipv4Addr::new(10, 26, 0, 200), Ipv4Addr::new(10, 26, 0, 201)];
+    let mut server = DhcpServer::new(DhcpOption {
+        pool: pool.clone(),
+        mask: Ipv4Addr::new(255, 255, 255, 0),
+        gateway: Ipv4Addr::new(10, 26, 0, 1),
+        mtu: 1400,
+    });
+    let mac1 = [2, 0, 0, 0, 0, 1];
+    let mac2 = [2, 0, 0, 0, 0, 2];
+    let (packet, mac, ip) = server.reply(&request(DISCOVER, mac1)).unwrap();
+    assert_eq!((mac, ip), (mac1, pool[0]));
+    assert_eq!(checksum(&packet[..20]), 0);
+    let dhcp = &packet[28..];
+    assert_eq!(dhcp[0], 2);
+    assert_eq!(&dhcp[4..8], &[1, 2, 3, 4]);
+    assert_eq!(&dhcp[16..20], &pool[0].octets());
+    assert_eq!(&dhcp[240..243], &[53, 1, OFFER]);
+    // 同一个客户端续约得到相同的地址，不同客户端地址不同
+    let (packet, _, ip) = server.reply(&request(REQUEST, mac1)).unwrap();
+    assert_eq!(ip, pool[0]);
+    assert_eq!(&packet[28 + 240..28 + 243], &[53, 1, ACK]);
+    assert_eq!(server.reply(&request(DISCOVER, mac2)).unwrap().2, pool[1]);
+    // 地址池用完
+    assert!(server
+        .reply(&request(DISCOVER, [2, 0, 0, 0, 0, 3]))
+        .is_none());
+    // 其他类型的消息不回复
+    assert!(server.reply(&request(7, mac1)).is_none());
+    assert!(server.reply(&request(DISCOVER, mac1)[..100]).is_none());
+
+    let mut option = server.option.clone();
+    option.pool = vec![pool[1]];
+    server.set_option(option);
+    assert_eq!(server.leases().count(), 1);
+    assert!(server.reply(&request(DISCOVER, mac1)).is_none());
+}
@@ -1,11 +1,102 @@
 use crate::packet::ethernet::protocol::Protocol;
+use std::collections::HashMap;
 use std::io;
+use std::net::Ipv4Addr;
+use std::sync::{Mutex, RwLock};
 
 pub mod arp;
+pub mod dhcp;
 pub mod ethernet;
+pub mod vlan;
+
+pub use dhcp::DhcpOption;
+use dhcp::DhcpServer;
 
 const MAC: [u8; 6] = [0xf, 0xf, 0xf, 0xf, 0xe, 0x9];
-pub fn read_tap<W, R>(buf: &mut [u8], read_fn: R, write_fn: W) -> io::Result<usize>
+
+/// tap网卡的二层信息
+pub struct TapState {
+    inner: Mutex<TapInfo>,
+    dhcp: Mutex<Option<DhcpServer>>,
+    // dhcp分配了地址的桥接设备 ip -> mac，发往这些地址的帧使用对应的mac
+    guests: RwLock<HashMap<Ipv4Addr, [u8; 6]>>,
+    vlan: RwLock<vlan::VlanInfo>,
+}
+
+#[derive(Copy, Clone)]
+struct TapInfo {
+    // 对端（虚拟网关）的mac
+    virtual_mac: [u8; 6],
+    // 网卡自身的mac，写入帧的默认目的mac
+    peer_mac: [u8; 6],
+}
+
+impl TapState {
+    pub fn new(peer_mac: [u8; 6]) -> Self {
+        Self {
+            inner: Mutex::new(TapInfo {
+                virtual_mac: MAC,
+                peer_mac,
+            }),
+            dhcp: Mutex::new(None),
+            guests: RwLock::new(HashMap::new()),
+            vlan: RwLock::new(vlan::VlanInfo::default()),
+        }
+    }
+    pub fn set(&self, virtual_mac: [u8; 6], dhcp: Option<DhcpOption>) {
+        self.inner.lock().unwrap().virtual_mac = virtual_mac;
+        let mut guard = self.dhcp.lock().unwrap();
+        match (guard.as_mut(), dhcp) {
+            (Some(server), Some(option)) => server.set_option(option),
+            (_, option) => *guard = option.map(DhcpServer::new),
+        }
+        // 地址池变化后移除不再有效的租约
+        *self.guests.write().unwrap() = match guard.as_ref() {
+            Some(server) => server.leases().map(|(mac, ip)| (*ip, *mac)).collect(),
+            None => HashMap::new(),
+        };
+    }
+    /// 本地所在的vlan，第一个为native vlan，为空时不处理标签
+    pub fn set_vlan(&self, local: Vec<u16>) {
+        self.vlan.write().unwrap().set_local(local);
+    }
+    /// 对端和本地共同所在的vlan
+    pub fn set_vlan_peers(&self, peers: HashMap<Ipv4Addr, Vec<u16>>) {
+        self.vlan.write().unwrap().set_peers(peers);
+    }
+    fn load(&self) -> TapInfo {
+        *self.inner.lock().unwrap()
+    }
+    /// 处理桥接设备的dhcp请求，返回回复的ipv4报文和客户端mac
+    fn dhcp_reply(&self, ipv4: &[u8]) -> Option<(Vec<u8>, [u8; 6])> {
+        let (packet, client_mac, ip) = self.dhcp.lock().unwrap().as_mut()?.reply(ipv4)?;
+        let mut guests = self.guests.write().unwrap();
+        guests.retain(|_, mac| *mac != client_mac);
+        guests.insert(ip, client_mac);
+        Some((packet, client_mac))
+    }
+    fn has_dhcp(&self) -> bool {
+        self.dhcp.lock().unwrap().is_some()
+    }
+    fn is_guest(&self, ip: &Ipv4Addr) -> bool {
+        self.guests.read().unwrap().contains_key(ip)
+    }
+    /// 发往ip的帧的目的mac，dhcp分配的地址使用桥接设备的mac，其他的发给网卡自身
+    fn dest_mac(&self, info: &TapInfo, ip: Ipv4Addr) -> [u8; 6] {
+        let guests = self.guests.read().unwrap();
+        if guests.is_empty() {
+            return info.peer_mac;
+        }
+        guests.get(&ip).copied().unwrap_or(info.peer_mac)
+    }
+}
+
+pub fn read_tap<W, R>(
+    buf: &mut [u8],
+    read_fn: R,
+    write_fn: W,
+    state: &TapState,
+) -> io::Result<usize>
 where
     W: Fn(&[u8]) -> io::Result<usize>,
     R: Fn(&mut [u8]) -> io::Result<usize>,
@@ -13,13 +104,70 @@ where
     let mut eth_buf = [0; 65536];
     loop {
         let len = read_fn(&mut eth_buf)?;
-        if len == 0{
+        if len == 0 {
             return Ok(len);
         }
+        let vlan_info = state.vlan.read().unwrap();
+        // 去掉802.1Q标签，回复的arp和dhcp需要带上原来的标签
+        let (offset, vid) = if vlan_info.is_enable() {
+            let ether = ethernet::packet::EthernetPacket::new(&eth_buf[..len])?;
+            let tag = if ether.protocol() == Protocol::Vlan {
+                match vlan::parse_tag(ether.payload()) {
+                    Some((vid, _)) => Some(vid),
+                    None => continue,
+                }
+            } else {
+                None
+            };
+            let vid = match vlan_info.frame_vlan(tag) {
+                Some(vid) => vid,
+                None => continue,
+            };
+            if tag.is_some() {
+                eth_buf.copy_within(0..12, vlan::TAG_LEN);
+                (vlan::TAG_LEN, Some(vid))
+            } else {
+                (0, Some(vid))
+            }
+        } else {
+            (0, None)
+        };
+        let reply_fn = |frame: &[u8]| match vid {
+            Some(vid) if vlan_info.is_tagged(vid) => write_fn(&vlan::insert_tag(frame, vid)),
+            _ => write_fn(frame),
+        };
         //处理arp包
-        let mut ether = ethernet::packet::EthernetPacket::new(&mut eth_buf[..len])?;
+        let mut ether = ethernet::packet::EthernetPacket::new(&mut eth_buf[offset..len])?;
+        let info = state.load();
         match ether.protocol() {
             Protocol::Ipv4 => {
+                if dhcp::is_dhcp_request(ether.payload()) && state.has_dhcp() {
+                    // 只在native vlan中分配地址
+                    if offset == 0 {
+                        if let Some((packet, client_mac)) = state.dhcp_reply(ether.payload()) {
+                            let mut reply = ethernet::packet::EthernetPacket::unchecked(vec![
+                                    0;
+                                    14 + packet.len()
+                                ]);
+                            reply.set_source(&info.virtual_mac);
+                            reply.set_destination(&client_mac);
+                            reply.set_protocol(Protocol::Ipv4);
+                            reply.payload_mut().copy_from_slice(&packet);
+                            write_fn(&reply.buffer)?;
+                        }
+                        continue;
+                    }
+                }
+                if let Some(vid) = vid {
+                    let payload = ether.payload();
+                    if payload.len() < 20 {
+                        continue;
+                    }
+                    let dest = Ipv4Addr::new(payload[16], payload[17], payload[18], payload[19]);
+                    if !vlan_info.allow_out(vid, dest) {
+                        continue;
+                    }
+                }
                 let len = ether.payload().len();
                 if len > buf.len() {
                     return Err(io::Error::new(io::ErrorKind::Other, "short"));
@@ -35,16 +183,20 @@ where
                 if target_p == [0, 0, 0, 0] || sender_p == [0, 0, 0, 0] || target_p == sender_p {
                     continue;
                 }
+                // 桥接设备自己应答自己的地址
+                if state.is_guest(&Ipv4Addr::from(target_p)) {
+                    continue;
+                }
                 if arp_packet.op_code() == 1 {
                     //回复一个默认的MAC
                     arp_packet.set_op_code(2);
                     arp_packet.set_target_hardware_addr(&sender_h);
                     arp_packet.set_target_protocol_addr(&sender_p);
                     arp_packet.set_sender_protocol_addr(&target_p);
-                    arp_packet.set_sender_hardware_addr(&MAC);
+                    arp_packet.set_sender_hardware_addr(&info.virtual_mac);
                     ether.set_destination(&sender_h);
-                    ether.set_source(&MAC);
-                    write_fn(ether.buffer)?;
+                    ether.set_source(&info.virtual_mac);
+                    reply_fn(ether.buffer)?;
                 }
             }
             _ => {
@@ -53,15 +205,34 @@ where
         }
     }
 }
-pub fn write_tap<W>(buf: &[u8], write_fn: W, mac: &[u8; 6]) -> io::Result<usize>
+pub fn write_tap<W>(buf: &[u8], write_fn: W, state: &TapState) -> io::Result<usize>
 where
     W: Fn(&[u8]) -> io::Result<usize>,
 {
+    let info = state.load();
+    let vlan_info = state.vlan.read().unwrap();
+    let vid = if vlan_info.is_enable() && buf.len() >= 20 {
+        // 和来源没有共同的vlan时丢弃
+        match vlan_info.vlan_in(Ipv4Addr::new(buf[12], buf[13], buf[14], buf[15])) {
+            Some(vid) => Some(vid),
+            None => return Ok(buf.len()),
+        }
+    } else {
+        None
+    };
+    let peer_mac = if buf.len() >= 20 {
+        state.dest_mac(&info, Ipv4Addr::new(buf[16], buf[17], buf[18], buf[19]))
+    } else {
+        info.peer_mac
+    };
     // 封装二层数据
     let mut ether = ethernet::packet::EthernetPacket::unchecked(vec![0; 14 + buf.len()]);
-    ether.set_source(&MAC);
-    ether.set_destination(mac);
+    ether.set_source(&info.virtual_mac);
+    ether.set_destination(&peer_mac);
     ether.set_protocol(Protocol::Ipv4);
     ether.payload_mut().copy_from_slice(buf);
-    write_fn(&ether.buffer)
+    match vid {
+        Some(vid) if vlan_info.is_tagged(vid) => write_fn(&vlan::insert_tag(&ether.buffer, vid)),
+        _ => write_fn(&ether.buffer),
+    }
 }
@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+
+use crate::packet::ethernet::protocol::Protocol;
+
+/// 802.1Q标签的长度
+pub const TAG_LEN: usize = 4;
+
+/// tap网卡上的vlan划分。
+/// 虚拟网络只转发ip包，帧所在的vlan由对端的vlan决定：
+/// 发出的帧只能发给在该vlan中的对端，收到的帧按和来源共同所在的vlan打标签
+#[derive(Default)]
+pub struct VlanInfo {
+    // 本地所在的vlan，第一个为native vlan，收发不带标签，其他的带标签(trunk)
+    local: Vec<u16>,
+    // 对端虚拟ip -> 和本地共同所在的vlan，
+    // 不在表中的地址(网关、广播、组播、对端后面的网段)视为在native vlan中
+    peers: HashMap<Ipv4Addr, Vec<u16>>,
+}
+
+impl VlanInfo {
+    pub fn is_enable(&self) -> bool {
+        !self.local.is_empty()
+    }
+    pub fn set_local(&mut self, local: Vec<u16>) {
+        self.local = local;
+    }
+    pub fn set_peers(&mut self, peers: HashMap<Ipv4Addr, Vec<u16>>) {
+        self.peers = peers;
+    }
+    fn native(&self) -> u16 {
+        self.local[0]
+    }
+    /// 读到的帧所在的vlan，不带标签或者只有优先级时为native vlan，
+    /// 不在本地vlan中时返回None
+    pub fn frame_vlan(&self, tag: Option<u16>) -> Option<u16> {
+        match tag {
+            None | Some(0) => Some(self.native()),
+            Some(vid) => {
+                if self.local.contains(&vid) {
+                    Some(vid)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+    /// vlan中的帧能否发往目的地址
+    pub fn allow_out(&self, vid: u16, dest: Ipv4Addr) -> bool {
+        match self.peers.get(&dest) {
+            Some(vlans) => vlans.contains(&vid),
+            None => vid == self.native(),
+        }
+    }
+    /// 来自源地址的包写入网卡时所在的vlan，没有共同的vlan时返回None
+    pub fn vlan_in(&self, src: Ipv4Addr) -> Option<u16> {
+        let native = self.native();
+        match self.peers.get(&src) {
+            Some(vlans) => {
+                if vlans.contains(&native) {
+                    Some(native)
+                } else {
+                    vlans.first().copied()
+                }
+            }
+            None => Some(native),
+        }
+    }
+    /// 写入网卡时是否需要带标签
+    pub fn is_tagged(&self, vid: u16) -> bool {
+        vid != self.native()
+    }
+}
+
+/// 带标签的帧中的vlan id和内层协议
+pub fn parse_tag(payload: &[u8]) -> Option<(u16, Protocol)> {
+    if payload.len() < TAG_LEN {
+        return None;
+    }
+    let tci = u16::from_be_bytes([payload[0], payload[1]]);
+    let protocol = u16::from_be_bytes([payload[2], payload[3]]);
+    Some((tci & 0x0FFF, protocol.into()))
+}
+
+/// 在以太网帧的源地址后面插入标签
+pub fn insert_tag(frame: &[u8], vid: u16) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(frame.len() + TAG_LEN);
+    buf.extend_from_slice(&frame[..12]);
+    let vlan: u16 = Protocol::Vlan.into();
+    buf.extend_from_slice(&vlan.to_be_bytes());
+    buf.extend_from_slice(&(vid & 0x0FFF).to_be_bytes());
+    buf.extend_from_slice(&frame[12..]);
+    buf
+}
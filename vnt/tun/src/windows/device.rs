@@ -1,91 +1,362 @@
 use crate::device::IFace;
+use crate::packet::DhcpOption;
 use crate::windows::{tap, tun};
+use std::collections::{HashMap, VecDeque};
 use std::io;
 use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
-pub enum Device {
+/// 网卡重建期间最多缓存多久的写入数据
+const RECREATE_BUFFER_TIME: Duration = Duration::from_secs(5);
+/// 网卡重建期间最多缓存的包数量
+const RECREATE_BUFFER_CAPACITY: usize = 4096;
+
+enum Adapter {
     Tap(tap::Device),
     Tun(tun::Device),
 }
 
+/// 设置过的ip、mtu和路由等，重建网卡后恢复
+#[derive(Default)]
+struct State {
+    ip: Option<(Ipv4Addr, Ipv4Addr)>,
+    mtu: Option<u32>,
+    routes: Vec<(Ipv4Addr, Ipv4Addr, u16)>,
+    tap_info: Option<([u8; 6], Option<DhcpOption>)>,
+    tap_vlan: Option<Vec<u16>>,
+    tap_vlan_peers: Option<HashMap<Ipv4Addr, Vec<u16>>>,
+}
+
+/// windows的网卡偶尔会被重置(驱动更新、休眠唤醒等)，
+/// 重建时使用相同的名称并恢复ip和路由，期间写入的数据先缓存，重建后再写入
+pub struct Device {
+    name: String,
+    tap: bool,
+    // 重建期间为None
+    adapter: RwLock<Option<Arc<Adapter>>>,
+    state: Mutex<State>,
+    pending: Mutex<Option<VecDeque<(Instant, Vec<u8>)>>>,
+    recreate_lock: Mutex<()>,
+    closed: AtomicBool,
+}
+
 impl Device {
     pub fn new(name: String, tap: bool) -> io::Result<Self> {
+        let adapter = Self::create(&name, tap)?;
+        Ok(Self {
+            name,
+            tap,
+            adapter: RwLock::new(Some(Arc::new(adapter))),
+            state: Mutex::new(State::default()),
+            pending: Mutex::new(None),
+            recreate_lock: Mutex::new(()),
+            closed: AtomicBool::new(false),
+        })
+    }
+    fn create(name: &str, tap: bool) -> io::Result<Adapter> {
         if tap {
-            Ok(Device::Tap(tap::Device::new(name)?))
+            Ok(Adapter::Tap(tap::Device::new(name.to_string())?))
         } else {
-            Ok(Device::Tun(tun::Device::new(name)?))
+            Ok(Adapter::Tun(tun::Device::new(name.to_string())?))
+        }
+    }
+    fn adapter(&self) -> io::Result<Arc<Adapter>> {
+        match self.adapter.read().unwrap().as_ref() {
+            Some(adapter) => Ok(adapter.clone()),
+            None => Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "device recreating",
+            )),
+        }
+    }
+    /// 是否已经调用过shutdown，此后的读取失败不需要重建网卡
+    pub fn is_shutdown(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+    /// 关闭并重新创建网卡，恢复之前设置的ip、mtu和路由，
+    /// 重建期间写入的数据缓存RECREATE_BUFFER_TIME，重建成功后按顺序写入
+    pub fn recreate(&self) -> io::Result<()> {
+        let _guard = self.recreate_lock.lock().unwrap();
+        if self.is_shutdown() {
+            return Err(io::Error::new(io::ErrorKind::Other, "device shutdown"));
+        }
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.is_none() {
+                *pending = Some(VecDeque::new());
+            }
+        }
+        let old = self.adapter.write().unwrap().take();
+        if let Some(old) = old {
+            // 唤醒阻塞的读取，等待其他线程释放引用后关闭旧网卡
+            if let Err(e) = old.shutdown() {
+                log::warn!("shutdown old adapter {:?}", e);
+            }
+            let start = Instant::now();
+            while Arc::strong_count(&old) > 1 && start.elapsed() < Duration::from_secs(2) {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            drop(old);
+        }
+        let adapter = Self::create(&self.name, self.tap)?;
+        {
+            let state = self.state.lock().unwrap();
+            if let Some(mtu) = state.mtu {
+                adapter.set_mtu(mtu)?;
+            }
+            if let Some((address, mask)) = state.ip {
+                adapter.set_ip(address, mask)?;
+            }
+            for (dest, netmask, metric) in &state.routes {
+                if let Err(e) = adapter.add_route(*dest, *netmask, *metric) {
+                    log::warn!("restore route {}/{} {:?}", dest, netmask, e);
+                }
+            }
+            if let Adapter::Tap(dev) = &adapter {
+                if let Some((virtual_mac, dhcp)) = &state.tap_info {
+                    dev.set_tap_info(*virtual_mac, dhcp.clone());
+                }
+                if let Some(local) = &state.tap_vlan {
+                    dev.set_tap_vlan(local.clone());
+                }
+                if let Some(peers) = &state.tap_vlan_peers {
+                    dev.set_tap_vlan_peers(peers.clone());
+                }
+            }
+        }
+        let adapter = Arc::new(adapter);
+        // 持有pending的锁替换网卡，保证缓存的数据先于新数据写入
+        let mut pending = self.pending.lock().unwrap();
+        *self.adapter.write().unwrap() = Some(adapter.clone());
+        if let Some(list) = pending.take() {
+            let mut count = 0;
+            for (time, buf) in list {
+                if time.elapsed() > RECREATE_BUFFER_TIME {
+                    continue;
+                }
+                if adapter.write(&buf).is_ok() {
+                    count += 1;
+                }
+            }
+            log::info!("网卡重建完成 name={} 写入缓存的包={}", self.name, count);
+        }
+        Ok(())
+    }
+    /// 重建期间缓存写入的数据，返回false表示没有在重建
+    fn buffer(&self, buf: &[u8]) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        let list = match pending.as_mut() {
+            Some(list) => list,
+            None => return false,
+        };
+        while let Some((time, _)) = list.front() {
+            if time.elapsed() > RECREATE_BUFFER_TIME || list.len() >= RECREATE_BUFFER_CAPACITY {
+                list.pop_front();
+            } else {
+                break;
+            }
+        }
+        list.push_back((Instant::now(), buf.to_vec()));
+        true
+    }
+    /// 设置tap模式下的虚拟mac和dhcp信息，tun模式忽略
+    pub fn set_tap_info(&self, virtual_mac: [u8; 6], dhcp: Option<DhcpOption>) {
+        self.state.lock().unwrap().tap_info = Some((virtual_mac, dhcp.clone()));
+        if let Ok(adapter) = self.adapter() {
+            if let Adapter::Tap(dev) = adapter.as_ref() {
+                dev.set_tap_info(virtual_mac, dhcp);
+            }
+        }
+    }
+    /// 设置tap模式下本地所在的vlan，tun模式忽略
+    pub fn set_tap_vlan(&self, local: Vec<u16>) {
+        self.state.lock().unwrap().tap_vlan = Some(local.clone());
+        if let Ok(adapter) = self.adapter() {
+            if let Adapter::Tap(dev) = adapter.as_ref() {
+                dev.set_tap_vlan(local);
+            }
+        }
+    }
+    /// 设置tap模式下对端和本地共同所在的vlan，tun模式忽略
+    pub fn set_tap_vlan_peers(&self, peers: HashMap<Ipv4Addr, Vec<u16>>) {
+        self.state.lock().unwrap().tap_vlan_peers = Some(peers.clone());
+        if let Ok(adapter) = self.adapter() {
+            if let Adapter::Tap(dev) = adapter.as_ref() {
+                dev.set_tap_vlan_peers(peers);
+            }
         }
     }
 }
 
-impl IFace for Device {
+impl IFace for Adapter {
     fn version(&self) -> io::Result<String> {
         match self {
-            Device::Tap(dev) => dev.version(),
-            Device::Tun(dev) => dev.version(),
+            Adapter::Tap(dev) => dev.version(),
+            Adapter::Tun(dev) => dev.version(),
         }
     }
 
     fn name(&self) -> io::Result<String> {
         match self {
-            Device::Tap(dev) => dev.name(),
-            Device::Tun(dev) => dev.name(),
+            Adapter::Tap(dev) => dev.name(),
+            Adapter::Tun(dev) => dev.name(),
         }
     }
 
     fn shutdown(&self) -> io::Result<()> {
         match self {
-            Device::Tap(dev) => dev.shutdown(),
-            Device::Tun(dev) => dev.shutdown(),
+            Adapter::Tap(dev) => dev.shutdown(),
+            Adapter::Tun(dev) => dev.shutdown(),
         }
     }
 
     fn set_ip(&self, address: Ipv4Addr, mask: Ipv4Addr) -> io::Result<()> {
         match self {
-            Device::Tap(dev) => dev.set_ip(address, mask),
-            Device::Tun(dev) => dev.set_ip(address, mask),
+            Adapter::Tap(dev) => dev.set_ip(address, mask),
+            Adapter::Tun(dev) => dev.set_ip(address, mask),
         }
     }
 
     fn mtu(&self) -> io::Result<u32> {
         match self {
-            Device::Tap(dev) => dev.mtu(),
-            Device::Tun(dev) => dev.mtu(),
+            Adapter::Tap(dev) => dev.mtu(),
+            Adapter::Tun(dev) => dev.mtu(),
         }
     }
 
     fn set_mtu(&self, value: u32) -> io::Result<()> {
         match self {
-            Device::Tap(dev) => dev.set_mtu(value),
-            Device::Tun(dev) => dev.set_mtu(value),
+            Adapter::Tap(dev) => dev.set_mtu(value),
+            Adapter::Tun(dev) => dev.set_mtu(value),
         }
     }
 
     fn add_route(&self, dest: Ipv4Addr, netmask: Ipv4Addr, metric: u16) -> io::Result<()> {
         match self {
-            Device::Tap(dev) => dev.add_route(dest, netmask, metric),
-            Device::Tun(dev) => dev.add_route(dest, netmask, metric),
+            Adapter::Tap(dev) => dev.add_route(dest, netmask, metric),
+            Adapter::Tun(dev) => dev.add_route(dest, netmask, metric),
         }
     }
 
     fn delete_route(&self, dest: Ipv4Addr, netmask: Ipv4Addr) -> io::Result<()> {
         match self {
-            Device::Tap(dev) => dev.delete_route(dest, netmask),
-            Device::Tun(dev) => dev.delete_route(dest, netmask),
+            Adapter::Tap(dev) => dev.delete_route(dest, netmask),
+            Adapter::Tun(dev) => dev.delete_route(dest, netmask),
         }
     }
 
     fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
         match self {
-            Device::Tap(dev) => dev.read(buf),
-            Device::Tun(dev) => dev.read(buf),
+            Adapter::Tap(dev) => dev.read(buf),
+            Adapter::Tun(dev) => dev.read(buf),
         }
     }
 
     fn write(&self, buf: &[u8]) -> io::Result<usize> {
         match self {
-            Device::Tap(dev) => dev.write(buf),
-            Device::Tun(dev) => dev.write(buf),
+            Adapter::Tap(dev) => dev.write(buf),
+            Adapter::Tun(dev) => dev.write(buf),
+        }
+    }
+
+    fn read_batch(&self, bufs: &mut [&mut [u8]], sizes: &mut [usize]) -> io::Result<usize> {
+        match self {
+            Adapter::Tap(dev) => dev.read_batch(bufs, sizes),
+            Adapter::Tun(dev) => dev.read_batch(bufs, sizes),
+        }
+    }
+
+    fn write_batch(&self, bufs: &[&[u8]]) -> io::Result<usize> {
+        match self {
+            Adapter::Tap(dev) => dev.write_batch(bufs),
+            Adapter::Tun(dev) => dev.write_batch(bufs),
+        }
+    }
+}
+
+impl IFace for Device {
+    fn version(&self) -> io::Result<String> {
+        self.adapter()?.version()
+    }
+
+    fn name(&self) -> io::Result<String> {
+        self.adapter()?.name()
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.closed.store(true, Ordering::Release);
+        self.adapter()?.shutdown()
+    }
+
+    fn set_ip(&self, address: Ipv4Addr, mask: Ipv4Addr) -> io::Result<()> {
+        self.adapter()?.set_ip(address, mask)?;
+        self.state.lock().unwrap().ip = Some((address, mask));
+        Ok(())
+    }
+
+    fn mtu(&self) -> io::Result<u32> {
+        self.adapter()?.mtu()
+    }
+
+    fn set_mtu(&self, value: u32) -> io::Result<()> {
+        self.adapter()?.set_mtu(value)?;
+        self.state.lock().unwrap().mtu = Some(value);
+        Ok(())
+    }
+
+    fn add_route(&self, dest: Ipv4Addr, netmask: Ipv4Addr, metric: u16) -> io::Result<()> {
+        self.adapter()?.add_route(dest, netmask, metric)?;
+        let mut state = self.state.lock().unwrap();
+        state
+            .routes
+            .retain(|(d, m, _)| !(*d == dest && *m == netmask));
+        state.routes.push((dest, netmask, metric));
+        Ok(())
+    }
+
+    fn delete_route(&self, dest: Ipv4Addr, netmask: Ipv4Addr) -> io::Result<()> {
+        self.state
+            .lock()
+            .unwrap()
+            .routes
+            .retain(|(d, m, _)| !(*d == dest && *m == netmask));
+        self.adapter()?.delete_route(dest, netmask)
+    }
+
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.adapter()?.read(buf)
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        match self.adapter() {
+            Ok(adapter) => adapter.write(buf),
+            Err(e) => {
+                if self.buffer(buf) {
+                    Ok(buf.len())
+                } else {
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    fn read_batch(&self, bufs: &mut [&mut [u8]], sizes: &mut [usize]) -> io::Result<usize> {
+        self.adapter()?.read_batch(bufs, sizes)
+    }
+
+    fn write_batch(&self, bufs: &[&[u8]]) -> io::Result<usize> {
+        match self.adapter() {
+            Ok(adapter) => adapter.write_batch(bufs),
+            Err(e) => {
+                for buf in bufs {
+                    if !self.buffer(buf) {
+                        return Err(e);
+                    }
+                }
+                Ok(bufs.len())
+            }
         }
     }
 }
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::io;
 use std::net::Ipv4Addr;
 use winapi::shared::ifdef::NET_LUID;
@@ -13,6 +14,7 @@ use crate::device::IFace;
 use crate::packet;
 use crate::packet::ethernet::protocol::Protocol;
 use crate::packet::{arp, ethernet};
+use crate::packet::{DhcpOption, TapState};
 use crate::windows::{ctl_code, decode_utf16, encode_utf16, ffi, netsh, route};
 
 /* Present in 8.1 */
@@ -43,7 +45,7 @@ pub struct Device {
     handle: HANDLE,
     index: u32,
     luid: NET_LUID,
-    mac: [u8; 6],
+    tap: TapState,
 }
 
 unsafe impl Send for Device {}
@@ -97,17 +99,29 @@ impl Device {
         let index = ffi::luid_to_index(&luid).map(|index| index as u32)?;
         // 设置网卡跃点
         if let Err(e) = netsh::set_interface_metric(index, 0) {
-            log::warn!("{:?}",e);
+            log::warn!("{:?}", e);
         }
         let device = Self {
             handle,
             index,
             luid,
-            mac,
+            tap: TapState::new(mac),
         };
         device.enabled(true)?;
         Ok(device)
     }
+    /// 设置虚拟mac和dhcp信息
+    pub fn set_tap_info(&self, virtual_mac: [u8; 6], dhcp: Option<DhcpOption>) {
+        self.tap.set(virtual_mac, dhcp);
+    }
+    /// 设置本地所在的vlan
+    pub fn set_tap_vlan(&self, local: Vec<u16>) {
+        self.tap.set_vlan(local);
+    }
+    /// 设置对端和本地共同所在的vlan
+    pub fn set_tap_vlan_peers(&self, peers: HashMap<Ipv4Addr, Vec<u16>>) {
+        self.tap.set_vlan_peers(peers);
+    }
     fn write_tap(&self, buf: &[u8]) -> io::Result<usize> {
         ffi::write_file(self.handle, buf).map(|res| res as _)
     }
@@ -166,6 +180,7 @@ impl IFace for Device {
             buf,
             |eth_buf| ffi::read_file(self.handle, eth_buf).map(|res| res as usize),
             |eth_buf| ffi::write_file(self.handle, eth_buf).map(|res| res as _),
+            &self.tap,
         )
     }
 
@@ -174,7 +189,7 @@ impl IFace for Device {
         packet::write_tap(
             buf,
             |eth_buf| ffi::write_file(self.handle, eth_buf).map(|res| res as _),
-            &self.mac,
+            &self.tap,
         )
     }
 }
@@ -118,7 +118,7 @@ impl Device {
             let index = ffi::luid_to_index(&std::mem::transmute(luid)).map(|index| index as u32)?;
             // 设置网卡跃点
             if let Err(e) = netsh::set_interface_metric(index, 0) {
-                log::warn!("{:?}",e);
+                log::warn!("{:?}", e);
             }
             Ok(Self {
                 luid: std::mem::transmute(luid),
@@ -218,6 +218,58 @@ impl IFace for Device {
         self.send_packet(packet);
         Ok(buf.len())
     }
+
+    fn read_batch(&self, bufs: &mut [&mut [u8]], sizes: &mut [usize]) -> io::Result<usize> {
+        let max = bufs.len().min(sizes.len());
+        let mut num = 0;
+        // 等到第一个包后，直接取出环形缓冲区中已就绪的包，一次事件处理多个包
+        let mut packet = Some(self.receive_blocking()?);
+        while let Some(p) = packet {
+            let bytes = p.bytes();
+            let len = bytes.len();
+            if len > bufs[num].len() {
+                if num == 0 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "data too long"));
+                }
+                log::warn!("data too long {}", len);
+            } else {
+                bufs[num][..len].copy_from_slice(bytes);
+                sizes[num] = len;
+                num += 1;
+            }
+            drop(p);
+            if num == max {
+                break;
+            }
+            // 已经读到数据，出错留给下一次读取处理
+            packet = self.try_receive().unwrap_or_default();
+        }
+        Ok(num)
+    }
+
+    fn write_batch(&self, bufs: &[&[u8]]) -> io::Result<usize> {
+        // 先全部分配再依次提交，减少环形缓冲区的锁竞争和唤醒次数
+        let mut packets = Vec::with_capacity(bufs.len());
+        for buf in bufs {
+            match self.allocate_send_packet(buf.len() as u16) {
+                Ok(mut packet) => {
+                    packet.bytes_mut().copy_from_slice(buf);
+                    packets.push(packet);
+                }
+                Err(e) => {
+                    if packets.is_empty() {
+                        return Err(e);
+                    }
+                    break;
+                }
+            }
+        }
+        let num = packets.len();
+        for packet in packets {
+            self.send_packet(packet);
+        }
+        Ok(num)
+    }
 }
 
 impl Device {